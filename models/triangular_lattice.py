@@ -327,6 +327,23 @@ if os.path.exists(os.path.join(rust_dir, "triangular_lattice_ext.h")):
     _lib = ffi.dlopen(os.path.join(rust_dir, "target", "release",
                                    "libtriangular_lattice_ext.so"))
 
+    # refuse to run against a library whose structs this module would misread
+    _ABI_VERSION = 6
+    if _lib.spinsys_abi_version() != _ABI_VERSION:
+        raise ImportError(
+            "triangular_lattice_ext {} has ABI version {}, expected {}".format(
+                ffi.string(_lib.spinsys_version_string()).decode(),
+                _lib.spinsys_abi_version(), _ABI_VERSION))
+    _struct_names = ["CComplex_f64", "Vector_u32", "CoordMatrix_CComplex_f64",
+                     "DenseMatrix_CComplex_f64", "CTerm", "StateDiagnostics",
+                     "ThermalSums", "BondList", "TriangleList", "ShellList"]
+    _struct_sizes = ffi.new("uint64_t[]", len(_struct_names))
+    _lib.spinsys_struct_sizes(_struct_sizes, len(_struct_names))
+    for _name, _size in zip(_struct_names, _struct_sizes):
+        if ffi.sizeof(_name) != _size:
+            raise ImportError("size of {} differs between the header and "
+                              "triangular_lattice_ext".format(_name))
+
     class CoordMatrix:
         """A class that encapsulates the matrix and provides methods that would
         help memoery management across the FFI boundary
@@ -0,0 +1,61 @@
+//! Version information for external callers, so a wrapper built against one
+//! version of the library can refuse to talk to another instead of silently
+//! misreading its structs.
+use std::mem::size_of;
+
+use common::*;
+
+/// Bumped whenever a #[repr(C)] struct or the signature of an exported function
+/// changes
+pub const ABI_VERSION: u32 = 6;
+
+/// The crate version as a null terminated string
+pub const VERSION: &[u8] = concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes();
+
+/// Sizes of the structs exchanged with external callers in the order reported
+/// by spinsys_struct_sizes
+pub const STRUCT_SIZES: [usize; 10] = [size_of::<CComplex<f64>>(),
+                                       size_of::<Vector<u32>>(),
+                                       size_of::<CoordMatrix<CComplex<f64>>>(),
+                                       size_of::<DenseMatrix<CComplex<f64>>>(),
+                                       size_of::<CTerm>(),
+                                       size_of::<StateDiagnostics>(),
+                                       size_of::<ThermalSums>(),
+                                       size_of::<BondList>(),
+                                       size_of::<TriangleList>(),
+                                       size_of::<ShellList>()];
+
+// The layouts the external callers have been told about. If any of these stops
+// compiling, a struct has changed: update the size here and bump ABI_VERSION.
+const PTR: usize = size_of::<usize>();
+const _: [(); 16] = [(); size_of::<CComplex<f64>>()];
+const _: [(); 2 * PTR] = [(); size_of::<Vector<u32>>()];
+const _: [(); 2 * PTR] = [(); size_of::<Vector<CComplex<f64>>>()];
+const _: [(); 6 * PTR + 8] = [(); size_of::<CoordMatrix<CComplex<f64>>>()];
+const _: [(); 3 * PTR] = [(); size_of::<DenseMatrix<CComplex<f64>>>()];
+const _: [(); 16] = [(); size_of::<CTerm>()];
+const _: [(); 24] = [(); size_of::<StateDiagnostics>()];
+const _: [(); 24] = [(); size_of::<ThermalSums>()];
+const _: [(); 18 * PTR] = [(); size_of::<BondList>()];
+const _: [(); 12 * PTR] = [(); size_of::<TriangleList>()];
+const _: [(); 10 * PTR] = [(); size_of::<ShellList>()];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spinsys_struct_sizes;
+    use spinsys_version_string;
+    use std::ffi::CStr;
+
+    #[test]
+    fn version_and_sizes() {
+        let version = unsafe { CStr::from_ptr(spinsys_version_string()) };
+        assert_eq!(version.to_str().unwrap(), env!("CARGO_PKG_VERSION"));
+
+        // only as many sizes as requested are written
+        let mut sizes = [0_u64; 3];
+        let n = unsafe { spinsys_struct_sizes(sizes.as_mut_ptr(), 3) };
+        assert_eq!(n as usize, STRUCT_SIZES.len());
+        assert_eq!(sizes, [16, 2 * PTR as u64, (6 * PTR + 8) as u64]);
+    }
+}
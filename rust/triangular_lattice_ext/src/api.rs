@@ -0,0 +1,731 @@
+//! The builders as ordinary Rust functions, for Rust callers that would
+//! otherwise go through the same raw pointers a C caller does. The sectors and
+//! the terms are checked as the exported functions check them, failures come
+//! back as an Error, including a panic inside the builders as Error::Panic,
+//! and the matrices are OwnedCoordMatrix values that own their arrays and free
+//! them when dropped. The exported builders of coordinate matrices are thin
+//! wrappers over these functions.
+//!
+//! The functions build on the plain torus with the phases of Convention::Plus,
+//! and their "_in" variants, HamiltonianBuilder::settings and
+//! HamiltonianBuilder::convention on the lattice described by a
+//! LatticeSettings under the convention they are given. The bases keep the
+//! orbits of their Bloch functions (Lookup::Members) unless
+//! HamiltonianBuilder::lookup says otherwise.
+//!
+//! Terms beyond those of TermKind implement OperatorTerm and are built, alone
+//! or together with the terms of the crate as PreparedTerm, by k_operator and
+//! ks_operator.
+//!
+//! The ground state energy of the Heisenberg model on the 4x3 lattice in the Γ
+//! sector with six up spins, diagonalized densely with nalgebra:
+//!
+//! ```
+//! extern crate nalgebra;
+//! extern crate triangular_lattice_ext;
+//!
+//! use nalgebra::{Complex, DMatrix};
+//! use triangular_lattice_ext::api::{self, Dim, Term, TermKind, I, K};
+//!
+//! # fn main() -> triangular_lattice_ext::api::Result<()> {
+//! let terms = [Term::new(TermKind::HSsZ, I(1)), Term::new(TermKind::HSsXy, I(1))];
+//! let h = api::ks_hamiltonian(Dim(4), Dim(3), K(0), K(0), 6, &terms)?;
+//! let n = h.nrows as usize;
+//! let dense = h.to_dense().into_iter().map(|c| Complex::new(c.re, c.im));
+//! let h = DMatrix::from_iterator(n, n, dense);
+//! let energy = h.symmetric_eigenvalues().min();
+//! println!("E0 = {}", energy);
+//! # assert!((energy - api::ks_ground_state(Dim(4), Dim(3), K(0), K(0), 6, &terms,
+//! #                                        1e-12, 300)?.0).abs() < 1e-9);
+//! # Ok(())
+//! # }
+//! ```
+use num_complex::Complex;
+
+pub use blochfunc::{BlochFunc, Convention, Lookup};
+pub use common::{
+    BinaryBasis, CComplex, Dim, LatticeSettings, LatticeTables, OwnedCoordMatrix,
+    Term, TermKind, I, K, MIN_PHASE_NORM
+};
+pub use error::{Error, Result};
+pub use ops::{Basis, ElementSink, OperatorTerm, PreparedTerm};
+pub use sitevector::{LatticeGeometry, Periodicity, SiteOrdering, SitePermutation};
+
+use blochfunc::BlochFuncSet;
+use common::{check_phase_norm, check_sector};
+use consv;
+use error;
+use lanczos;
+use matfree::OpHandle;
+use ops::{self, VecSink};
+use progress::Progress;
+
+// "f", with a panic inside the builders turned into Error::Panic
+fn catch<R, F: FnOnce() -> Result<R>>(f: F) -> Result<R> {
+    error::catch_panic(f).and_then(|r| r)
+}
+
+/// "term" in the (kx, ky) sector of the nx by ny lattice
+pub fn k_term(nx: Dim, ny: Dim, kx: K, ky: K, term: &Term)
+              -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    let torus = LatticeSettings::default();
+    k_term_in(nx, ny, &torus, Convention::Plus, kx, ky, term)
+}
+
+/// Same as k_term on the lattice with "settings" under "convention"
+pub fn k_term_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                 convention: Convention, kx: K, ky: K, term: &Term)
+                 -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    catch(|| consv::k::term_matrix_in(nx, ny, settings, convention, kx, ky, term))
+}
+
+/// "term" in the (kx, ky, nup) sector of the nx by ny lattice. Fails if the
+/// term does not conserve total Sz.
+pub fn ks_term(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term)
+               -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    let torus = LatticeSettings::default();
+    ks_term_in(nx, ny, &torus, Convention::Plus, kx, ky, nup, term)
+}
+
+/// Same as ks_term on the lattice with "settings" under "convention"
+pub fn ks_term_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                  convention: Convention, kx: K, ky: K, nup: u32, term: &Term)
+                  -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    catch(|| {
+        let progress = &mut Progress::none();
+        consv::ks::term_matrix_with_progress(nx, ny, settings, convention, kx, ky,
+                                             nup, term, progress)
+    })
+}
+
+/// The sum of "terms", each scaled by its coefficient, in the (kx, ky) sector
+/// of the nx by ny lattice, built in one pass over the basis
+pub fn k_hamiltonian(nx: Dim, ny: Dim, kx: K, ky: K, terms: &[Term])
+                     -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    catch(|| {
+        check_sector(nx, ny, kx, ky, None)?;
+        for term in terms.iter() {
+            term.check(nx, ny)?;
+        }
+        let bfuncs = consv::k::bloch_states(nx, ny, kx, ky)?;
+        sum(terms, &bfuncs, &mut Progress::none())
+    })
+}
+
+/// The sum of "terms", each scaled by its coefficient, in the (kx, ky, nup)
+/// sector of the nx by ny lattice, built in one pass over the basis. Fails if
+/// any of the terms does not conserve total Sz.
+pub fn ks_hamiltonian(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term])
+                      -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    catch(|| {
+        check_sector(nx, ny, kx, ky, Some(nup))?;
+        for term in terms.iter() {
+            term.check(nx, ny)?;
+        }
+        if let Some(term) = terms.iter().find(|t| !t.kind.conserves_sz()) {
+            return Err(Error::InvalidTerm(term.kind as u32));
+        }
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+        sum(terms, &bfuncs, &mut Progress::none())
+    })
+}
+
+/// The sum of "operators" in the (kx, ky) sector of the nx by ny lattice, built
+/// in one pass over the basis as k_hamiltonian builds the sum of its terms
+pub fn k_operator(nx: Dim, ny: Dim, kx: K, ky: K, operators: &[&dyn OperatorTerm])
+                  -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    catch(|| {
+        check_sector(nx, ny, kx, ky, None)?;
+        let bfuncs = consv::k::bloch_states(nx, ny, kx, ky)?;
+        sum_operators(operators, &bfuncs)
+    })
+}
+
+/// The sum of "operators" in the (kx, ky, nup) sector of the nx by ny lattice.
+/// The elements of a term that does not conserve total Sz are dropped, since
+/// Basis::find finds none of the configurations they lead to.
+pub fn ks_operator(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                   operators: &[&dyn OperatorTerm])
+                   -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    catch(|| {
+        check_sector(nx, ny, kx, ky, Some(nup))?;
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+        sum_operators(operators, &bfuncs)
+    })
+}
+
+// the sum of "operators" on "bfuncs" (see
+// ops::operators_rows_into_with_progress)
+fn sum_operators(operators: &[&dyn OperatorTerm], bfuncs: &BlochFuncSet)
+                 -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    let dims = bfuncs.nonzero;
+    let bound = bfuncs.iter()
+                      .map(|b| {
+                               operators.iter()
+                                        .map(|o| o.row_bound(b))
+                                        .sum::<usize>()
+                           })
+                      .sum();
+    let mut sink = VecSink::with_capacity(bound);
+    ops::operators_rows_into_with_progress(operators, bfuncs, 0..dims, &mut sink,
+                                           &mut Progress::none())?;
+    Ok(sink.into_coord_matrix(dims))
+}
+
+// the sum of "terms" on "bfuncs" (see ops::terms_rows_into_with_progress)
+fn sum(terms: &[Term], bfuncs: &BlochFuncSet, progress: &mut Progress)
+       -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+    let dims = bfuncs.nonzero;
+    let bound = terms.iter()
+                     .map(|t| ops::nnz_bound(t, bfuncs, 0..dims))
+                     .sum();
+    let mut sink = VecSink::with_capacity(bound);
+    ops::terms_rows_into_with_progress(terms, bfuncs, 0..dims, &mut sink, progress)?;
+    Ok(sink.into_coord_matrix(dims))
+}
+
+/// The lowest eigenvalue of the sum of "terms" in the (kx, ky, nup) sector and
+/// its normalized eigenvector, found by the Lanczos method with the operator
+/// applied matrix-free. The iteration stops once the residual norm drops
+/// below tol * max(1, |energy|) and fails with Error::NotConverged if that
+/// does not happen within "max_iter" steps.
+pub fn ks_ground_state(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term],
+                       tol: f64, max_iter: u32)
+                       -> Result<(f64, Vec<Complex<f64>>)> {
+    catch(|| {
+        let op = OpHandle::ks(nx, ny, kx, ky, nup, terms)?;
+        let (energy, vec) = lanczos::ground_state(&op, tol, max_iter, true)?;
+        // asked for
+        Ok((energy, vec.unwrap()))
+    })
+}
+
+/// The lowest eigenvalue of "mat", which has to be square and hermitian, and
+/// its normalized eigenvector, see ks_ground_state
+pub fn ground_state(mat: &OwnedCoordMatrix<CComplex<f64>>, tol: f64, max_iter: u32)
+                    -> Result<(f64, Vec<Complex<f64>>)> {
+    if mat.nrows != mat.ncols {
+        return Err(Error::InvalidArgument("shape"));
+    }
+    catch(|| {
+        let (energy, vec) = lanczos::ground_state(mat, tol, max_iter, true)?;
+        Ok((energy, vec.unwrap()))
+    })
+}
+
+/// A Hamiltonian put together term by term and built in one pass over the
+/// basis, as k_hamiltonian and ks_hamiltonian build it:
+///
+/// ```
+/// # use triangular_lattice_ext::api::{Dim, HamiltonianBuilder, I, K};
+/// # fn main() -> triangular_lattice_ext::api::Result<()> {
+/// let h = HamiltonianBuilder::new(Dim(4), Dim(3)).momentum(K(1), K(0))
+///                                                .nup(6)
+///                                                .add_heisenberg(I(1), 1.)
+///                                                .add_heisenberg(I(2), 0.2)
+///                                                .add_chirality(0.1)
+///                                                .build()?;
+/// # assert_eq!(h.nrows, h.ncols);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The sector is Γ unless given, and total Sz is not fixed unless "nup" is.
+/// Nothing is checked until "build", which fails as ks_hamiltonian does on a
+/// term that does not conserve total Sz together with "nup". The lattice is the
+/// plain torus unless "settings" says otherwise, and its tables are the ones
+/// common::lattice_tables keeps between builds. The phases are those of
+/// Convention::Plus unless "convention" says otherwise, the basis is scanned
+/// with Lookup::Members unless "lookup" does, and its lookups take components
+/// of MIN_PHASE_NORM and above as phases unless "min_phase_norm" says
+/// otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HamiltonianBuilder {
+    nx:         Dim,
+    ny:         Dim,
+    settings:   LatticeSettings,
+    convention: Convention,
+    lookup:     Lookup,
+    phase_norm: f64,
+    kx:         K,
+    ky:         K,
+    nup:        Option<u32>,
+    terms:      Vec<Term>,
+    // the Zeeman field h of -h Σ_i S^z_i
+    field:      f64
+}
+
+impl HamiltonianBuilder {
+    /// An empty Hamiltonian on the nx by ny lattice
+    pub fn new(nx: Dim, ny: Dim) -> HamiltonianBuilder {
+        HamiltonianBuilder { nx,
+                             ny,
+                             settings: LatticeSettings::default(),
+                             convention: Convention::Plus,
+                             lookup: Lookup::Members,
+                             phase_norm: MIN_PHASE_NORM,
+                             kx: K(0),
+                             ky: K(0),
+                             nup: None,
+                             terms: Vec::new(),
+                             field: 0. }
+    }
+
+    /// Build on the lattice with "settings"
+    pub fn settings(mut self, settings: LatticeSettings) -> HamiltonianBuilder {
+        self.settings = settings;
+        self
+    }
+
+    /// Build with the phases of "convention", under which the momentum is
+    /// labelled
+    pub fn convention(mut self, convention: Convention) -> HamiltonianBuilder {
+        self.convention = convention;
+        self
+    }
+
+    /// Scan the basis with "lookup" (see Progress::with_lookup)
+    pub fn lookup(mut self, lookup: Lookup) -> HamiltonianBuilder {
+        self.lookup = lookup;
+        self
+    }
+
+    /// Take components of the Bloch functions of "norm" and above as phases
+    /// (see OrbitTable::min_phase_norm). "build" fails with InvalidArgument
+    /// unless "norm" is finite and not negative.
+    pub fn min_phase_norm(mut self, norm: f64) -> HamiltonianBuilder {
+        self.phase_norm = norm;
+        self
+    }
+
+    /// Build in the (kx, ky) sector
+    pub fn momentum(mut self, kx: K, ky: K) -> HamiltonianBuilder {
+        self.kx = kx;
+        self.ky = ky;
+        self
+    }
+
+    /// Build in the sector with "nup" up spins
+    pub fn nup(mut self, nup: u32) -> HamiltonianBuilder {
+        self.nup = Some(nup);
+        self
+    }
+
+    /// Add "term" as it is
+    pub fn add_term(mut self, term: Term) -> HamiltonianBuilder {
+        self.terms.push(term);
+        self
+    }
+
+    /// Add the Heisenberg exchange j Σ S_i·S_j over the bonds of range "l"
+    pub fn add_heisenberg(self, l: I, j: f64) -> HamiltonianBuilder {
+        self.add_term(Term { kind: TermKind::HSsZ,
+                             l,
+                             coeff: j })
+            .add_term(Term { kind: TermKind::HSsXy,
+                             l,
+                             coeff: j })
+    }
+
+    /// Add the scalar chirality j_chi Σ S_i·(S_j × S_k) over the triangles
+    pub fn add_chirality(self, j_chi: f64) -> HamiltonianBuilder {
+        self.add_term(Term { kind:  TermKind::HSssChi,
+                             l:     I(0),
+                             coeff: j_chi })
+    }
+
+    /// Add the Zeeman term -h Σ_i S^z_i. Adding it twice adds up the fields.
+    pub fn add_zeeman(mut self, h: f64) -> HamiltonianBuilder {
+        self.field += h;
+        self
+    }
+
+    /// The terms added so far, the Zeeman term aside
+    pub fn terms(&self) -> &[Term] { &self.terms }
+
+    /// The matrix of the Hamiltonian, see k_hamiltonian and ks_hamiltonian
+    pub fn build(&self) -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        let (nx, ny, kx, ky) = (self.nx, self.ny, self.kx, self.ky);
+        let (settings, convention) = (&self.settings, self.convention);
+        catch(|| {
+            let phase_norm = check_phase_norm(self.phase_norm)?;
+            let progress = &mut Progress::none().with_lookup(self.lookup)
+                                                .with_min_phase_norm(phase_norm);
+            check_sector(nx, ny, kx, ky, self.nup)?;
+            for term in self.terms.iter() {
+                term.check(nx, ny)?;
+            }
+            let bfuncs = match self.nup {
+                Some(nup) => {
+                    let mut terms = self.terms.iter();
+                    if let Some(term) = terms.find(|t| !t.kind.conserves_sz()) {
+                        return Err(Error::InvalidTerm(term.kind as u32));
+                    }
+                    consv::ks::bloch_states_with_progress(nx, ny, settings,
+                                                          convention, kx, ky, nup,
+                                                          progress)?
+                }
+                None => {
+                    consv::k::bloch_states_with_progress(nx, ny, settings,
+                                                         convention, kx, ky,
+                                                         progress)?
+                }
+            };
+            let mat = sum(&self.terms, &bfuncs, progress)?;
+            if self.field == 0. {
+                return Ok(mat);
+            }
+            // S^z of a state is the same for its whole orbit
+            let half = f64::from((nx * ny).raw_int()) / 2.;
+            let zeeman = bfuncs.iter()
+                               .map(|b| {
+                                   let up = b.lead.raw_int().count_ones();
+                                   -self.field * (f64::from(up) - half)
+                               })
+                               .collect::<Vec<_>>();
+            Ok(add_diagonal(mat, &zeeman))
+        })
+    }
+}
+
+// "mat" plus the diagonal matrix of "diag", with the positions still sorted
+// by column and within a column by row, each once
+fn add_diagonal(mat: OwnedCoordMatrix<CComplex<f64>>, diag: &[f64])
+                -> OwnedCoordMatrix<CComplex<f64>> {
+    let nnz = mat.nnz() + diag.len();
+    let (mut col, mut row) = (Vec::with_capacity(nnz), Vec::with_capacity(nnz));
+    let mut data = Vec::with_capacity(nnz);
+    let mut k = 0;
+    // the element data[k] sits in row col[k] and column row[k]
+    for (j, &d) in (0..).zip(diag.iter()) {
+        while k < mat.nnz() && (mat.row[k], mat.col[k]) < (j, j) {
+            col.push(mat.col[k]);
+            row.push(mat.row[k]);
+            data.push(mat.data[k]);
+            k += 1;
+        }
+        let mut val = CComplex { re: d,
+                                 im: 0. };
+        if k < mat.nnz() && (mat.row[k], mat.col[k]) == (j, j) {
+            val.re += mat.data[k].re;
+            val.im = mat.data[k].im;
+            k += 1;
+        }
+        col.push(j);
+        row.push(j);
+        data.push(val);
+    }
+    col.extend_from_slice(&mat.col[k..]);
+    row.extend_from_slice(&mat.row[k..]);
+    data.extend_from_slice(&mat.data[k..]);
+    OwnedCoordMatrix::new(data, col, row, mat.ncols, mat.nrows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{lattice_tables, BondRange};
+
+    #[test]
+    fn matrices_match_the_builders() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+        let term = Term::new(TermKind::HSsXy, I(1));
+        let mat = ks_term(nx, ny, kx, ky, nup, &term).unwrap();
+        let nearest = BondRange::NearestNeighbor;
+        let built = consv::ks::h_ss_xy(nx, ny, kx, ky, nup, nearest).unwrap();
+        assert_eq!(mat, built);
+
+        let chi = Term { kind:  TermKind::HSssChi,
+                         l:     I(0),
+                         coeff: 0.5 };
+        let k = k_term(nx, ny, kx, ky, &chi).unwrap();
+        let dims = consv::k::bloch_states(nx, ny, kx, ky).unwrap().nonzero;
+        assert_eq!((k.nrows, k.ncols), (dims, dims));
+    }
+
+    #[test]
+    fn hamiltonians_sum_their_terms() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(2), K(1), 5);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term { kind:  TermKind::HSsXy,
+                            l:     I(2),
+                            coeff: 0.3 },
+                     Term { kind:  TermKind::HSssChi,
+                            l:     I(0),
+                            coeff: -0.7 }];
+        let h = ks_hamiltonian(nx, ny, kx, ky, nup, &terms).unwrap();
+        let mut expected = vec![Complex::new(0., 0.); h.to_dense().len()];
+        for term in terms.iter() {
+            let part = ks_term(nx, ny, kx, ky, nup, term).unwrap().to_dense();
+            for (e, p) in expected.iter_mut().zip(part) {
+                *e += p;
+            }
+        }
+        for (a, b) in h.to_dense().iter().zip(expected.iter()) {
+            assert!((*a - *b).norm() < 1e-12);
+        }
+        // the positions come sorted by column and each one once
+        let positions = h.row.iter().zip(h.col.iter()).collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+
+        let (energy, vec) = ground_state(&h, 1e-12, 300).unwrap();
+        let (direct, _) =
+            ks_ground_state(nx, ny, kx, ky, nup, &terms, 1e-12, 300).unwrap();
+        assert!((energy - direct).abs() < 1e-9);
+        let hv = h.mul_vec(&vec).unwrap();
+        for (a, b) in hv.iter().zip(vec.iter()) {
+            assert!((*a - *b * energy).norm() < 1e-6);
+        }
+
+        // repeated positions add up
+        let k = k_hamiltonian(nx, ny, kx, ky, &terms[..2]).unwrap();
+        assert!(k.nrows > h.nrows);
+        let mut twice = k.clone();
+        twice.col.extend_from_slice(&k.col);
+        twice.row.extend_from_slice(&k.row);
+        twice.data.extend_from_slice(&k.data);
+        for (a, b) in twice.to_dense().iter().zip(k.to_dense().iter()) {
+            assert!((*a - *b * 2.).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn builder_sums_its_pieces() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+        let builder = HamiltonianBuilder::new(nx, ny).momentum(kx, ky)
+                                                     .nup(nup)
+                                                     .add_heisenberg(I(1), 1.)
+                                                     .add_heisenberg(I(2), 0.3)
+                                                     .add_chirality(-0.4);
+        assert_eq!(builder.terms().len(), 5);
+        let h = builder.build().unwrap();
+        let mut expected = vec![Complex::new(0., 0.); h.to_dense().len()];
+        for &(kind, l, coeff) in [(TermKind::HSsZ, I(1), 1.),
+                                  (TermKind::HSsXy, I(1), 1.),
+                                  (TermKind::HSsZ, I(2), 0.3),
+                                  (TermKind::HSsXy, I(2), 0.3),
+                                  (TermKind::HSssChi, I(0), -0.4)].iter()
+        {
+            let term = Term::new(kind, l);
+            let part = ks_term(nx, ny, kx, ky, nup, &term).unwrap().to_dense();
+            for (e, p) in expected.iter_mut().zip(part) {
+                *e += p * coeff;
+            }
+        }
+        for (a, b) in h.to_dense().iter().zip(expected.iter()) {
+            assert!((*a - *b).norm() < 1e-12);
+        }
+
+        // with six of twelve spins up the field shifts nothing, with five it
+        // shifts everything by h
+        let field = builder.clone().add_zeeman(0.5).add_zeeman(0.5);
+        assert_eq!(field.build().unwrap(), h);
+        let five = builder.clone().nup(5).build().unwrap();
+        let shifted = builder.nup(5).add_zeeman(1.).build().unwrap();
+        let n = five.nrows as usize;
+        for (i, (a, b)) in shifted.to_dense()
+                                  .iter()
+                                  .zip(five.to_dense().iter())
+                                  .enumerate()
+        {
+            let shift = if i % n == i / n { 1. } else { 0. };
+            assert!((*a - *b - shift).norm() < 1e-12);
+        }
+        let positions = shifted.row
+                               .iter()
+                               .zip(shifted.col.iter())
+                               .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+
+        // without nup the field sees S^z of each Bloch function
+        let k = HamiltonianBuilder::new(nx, ny).momentum(kx, ky)
+                                               .add_zeeman(2.)
+                                               .build()
+                                               .unwrap();
+        let bfuncs = consv::k::bloch_states(nx, ny, kx, ky).unwrap();
+        assert_eq!(k.nnz(), bfuncs.nonzero as usize);
+        for (b, (v, (&i, &j))) in
+            bfuncs.iter().zip(k.data.iter().zip(k.col.iter().zip(k.row.iter())))
+        {
+            assert_eq!(i, j);
+            let up = f64::from(b.lead.raw_int().count_ones());
+            assert_eq!(v.re, -2. * (up - 6.));
+        }
+    }
+
+    // the builder hands its convention to the basis, under which the sector
+    // labelled (kx, ky) is the one labelled (-kx, -ky) under the default
+    #[test]
+    fn builder_takes_the_convention() {
+        let (nx, ny, nup) = (Dim(4), Dim(3), 6);
+        let chi = Term::new(TermKind::HSssChi, I(0));
+        let minus = HamiltonianBuilder::new(nx, ny).convention(Convention::Minus)
+                                                   .momentum(K(1), K(2))
+                                                   .nup(nup)
+                                                   .add_chirality(1.)
+                                                   .build()
+                                                   .unwrap();
+        let torus = LatticeSettings::default();
+        let term = ks_term_in(nx, ny, &torus, Convention::Minus, K(1), K(2), nup,
+                              &chi).unwrap();
+        let mirrored = ks_term(nx, ny, K(3), K(1), nup, &chi).unwrap();
+        assert_eq!(minus.to_dense().len(), mirrored.to_dense().len());
+        for ((a, b), c) in minus.to_dense()
+                                .iter()
+                                .zip(term.to_dense().iter())
+                                .zip(mirrored.to_dense().iter())
+        {
+            assert!((*a - *b).norm() < 1e-12 && (*a - *c).norm() < 1e-12);
+        }
+    }
+
+    // the lookup the builder scans the basis with changes nothing but the
+    // memory used
+    #[test]
+    fn builder_takes_the_lookup() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let builder = HamiltonianBuilder::new(nx, ny).momentum(K(1), K(2))
+                                                     .add_heisenberg(I(1), 1.)
+                                                     .add_chirality(0.5);
+        for builder in [builder.clone(), builder.nup(6)].iter() {
+            let members = builder.build().unwrap();
+            let leads = builder.clone().lookup(Lookup::Leads).build().unwrap();
+            assert_eq!(leads.nnz(), members.nnz());
+            for (a, b) in leads.to_dense().iter().zip(members.to_dense().iter()) {
+                assert!((*a - *b).norm() < 1e-12);
+            }
+        }
+    }
+
+    // a phase norm above the unit components of the orbits of all 12
+    // configurations drops their elements
+    #[test]
+    fn builder_takes_the_phase_norm() {
+        let builder = HamiltonianBuilder::new(Dim(4), Dim(3)).momentum(K(1), K(2))
+                                                             .nup(6)
+                                                             .add_chirality(1.);
+        let plain = builder.build().unwrap();
+        let same = builder.clone().min_phase_norm(0.).build().unwrap();
+        assert_eq!(same.to_dense(), plain.to_dense());
+        let coarse = builder.clone().min_phase_norm(1.5).build().unwrap();
+        assert!(coarse.nnz() < plain.nnz());
+        match builder.min_phase_norm(-1.).build() {
+            Err(Error::InvalidArgument("norm")) => (),
+            r => panic!("{:?}", r.map(|m| m.nnz()))
+        }
+    }
+
+    #[test]
+    fn builder_checks_its_terms() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let ppmm = Term::new(TermKind::HSsPpmm, I(1));
+        let builder = HamiltonianBuilder::new(nx, ny).add_heisenberg(I(1), 1.)
+                                                     .add_term(ppmm);
+        assert!(builder.build().is_ok());
+        match builder.clone().nup(6).build() {
+            Err(Error::InvalidTerm(2)) => (),
+            other => panic!("{:?}", other)
+        }
+        match builder.clone().add_heisenberg(I(4), 1.).build() {
+            Err(Error::InvalidArgument("l")) => (),
+            other => panic!("{:?}", other)
+        }
+        match builder.clone().momentum(K(0), K(3)).build() {
+            Err(Error::InvalidArgument("ky")) => (),
+            other => panic!("{:?}", other)
+        }
+        match HamiltonianBuilder::new(nx, ny).nup(13).build() {
+            Err(Error::InvalidArgument("nup")) => (),
+            other => panic!("{:?}", other)
+        }
+        match HamiltonianBuilder::new(Dim(8), Dim(8)).add_chirality(1.).build() {
+            Err(Error::LatticeTooLarge(64)) => (),
+            other => panic!("{:?}", other)
+        }
+    }
+
+    // h_ss_z on the bonds of range "l" as a crate downstream would write it,
+    // with nothing but what this module exports
+    struct Zz {
+        l: I,
+        j: f64
+    }
+
+    impl OperatorTerm for Zz {
+        fn elements(&self, i: u32, state: &BlochFunc, basis: &Basis,
+                    sink: &mut dyn ElementSink) {
+            let (ref site1, ref site2) = *basis.lattice().bonds(self.l);
+            let (mut same_dir, mut diff_dir) = (0, 0);
+            for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+                let up1 = state.lead.raw_int() & s1.raw_int() != 0;
+                let up2 = state.lead.raw_int() & s2.raw_int() != 0;
+                if up1 == up2 {
+                    same_dir += 1;
+                } else {
+                    diff_dir += 1;
+                }
+            }
+            let element = 0.25 * f64::from(same_dir - diff_dir);
+            sink.push(i, i, Complex::new(self.j * element, 0.));
+        }
+
+        fn is_diagonal(&self) -> bool { true }
+    }
+
+    #[test]
+    fn external_terms_match_the_builtin_ones() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 5);
+        let zz = Zz { l: I(2),
+                      j: 0.3 };
+        let term = Term { kind:  TermKind::HSsZ,
+                          l:     I(2),
+                          coeff: 0.3 };
+        assert_eq!(k_operator(nx, ny, kx, ky, &[&zz]).unwrap(),
+                   k_term(nx, ny, kx, ky, &term).unwrap());
+
+        // together with the terms of the crate, and a diagonal term among
+        // others that look up their columns
+        let xy = Term::new(TermKind::HSsXy, I(1));
+        let chi = Term { kind:  TermKind::HSssChi,
+                         l:     I(0),
+                         coeff: -0.4 };
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default()).unwrap();
+        let prepared = [PreparedTerm::new(xy, &tables),
+                        PreparedTerm::new(chi, &tables)];
+        let operators: [&dyn OperatorTerm; 3] = [&prepared[0], &zz, &prepared[1]];
+        assert_eq!(ks_operator(nx, ny, kx, ky, nup, &operators).unwrap(),
+                   ks_hamiltonian(nx, ny, kx, ky, nup, &[xy, term, chi]).unwrap());
+        assert!(ks_operator(nx, ny, kx, ky, 13, &operators).is_err());
+    }
+
+    #[test]
+    fn failures_are_errors() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let ppmm = Term::new(TermKind::HSsPpmm, I(1));
+        match ks_term(nx, ny, K(0), K(0), 6, &ppmm) {
+            Err(Error::InvalidTerm(2)) => (),
+            other => panic!("{:?}", other)
+        }
+        match ks_hamiltonian(nx, ny, K(0), K(0), 6, &[ppmm]) {
+            Err(Error::InvalidTerm(2)) => (),
+            other => panic!("{:?}", other)
+        }
+        let far = Term::new(TermKind::HSsZ, I(9));
+        assert!(k_term(nx, ny, K(0), K(0), &far).is_err());
+        assert!(k_hamiltonian(nx, ny, K(4), K(0), &[]).is_err());
+        assert!(ks_term(nx, ny, K(0), K(0), 13, &ppmm).is_err());
+        match k_term(Dim(8), Dim(9), K(0), K(0), &ppmm) {
+            Err(Error::LatticeTooLarge(72)) => (),
+            other => panic!("{:?}", other)
+        }
+        // one row, two columns and the element 1 in the second column
+        let one = CComplex { re: 1.,
+                             im: 0. };
+        let rectangular = OwnedCoordMatrix::new(vec![one], vec![0], vec![1], 2, 1);
+        assert!(ground_state(&rectangular, 1e-12, 10).is_err());
+        assert!(rectangular.mul_vec(&[Complex::new(1., 0.)]).is_err());
+        let y = rectangular.mul_vec(&[Complex::new(0., 0.), Complex::new(2., 0.)])
+                           .unwrap();
+        assert_eq!(y, vec![Complex::new(2., 0.)]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Assembly of the coordinate arrays of a matrix on the pool configured in the
+//! pool module: sorting the elements by position and summing the elements that
+//! share one. Both passes split the work independently of the thread count and
+//! never split a run of elements at the same position, so every sum is taken in
+//! the same order and the arrays come out the same for any number of threads.
+use rayon::prelude::*;
+use std::cmp;
+
+use common::*;
+use pool;
+
+/// Number of elements coalesced by a worker thread at a time
+const ELEMENTS_PER_CHUNK: usize = 1 << 16;
+
+/// The order in which the elements with the given index arrays have to be
+/// taken to sort them by (row, col). Elements at the same position keep the
+/// order they are in.
+pub fn row_col_order(col: &[u32], row: &[u32]) -> Vec<usize> {
+    pool::install(|| {
+        // the index makes every key unique, so the unstable sort is
+        // deterministic and leaves equal positions in their original order
+        let mut keys =
+            row.par_iter()
+               .zip(col.par_iter())
+               .enumerate()
+               .map(|(k, (&r, &c))| ((u64::from(r) << 32) | u64::from(c), k))
+               .collect::<Vec<_>>();
+        keys.par_sort_unstable();
+        keys.into_par_iter().map(|(_, k)| k).collect()
+    })
+}
+
+/// Take the elements of "v" in the order given by "perm"
+pub fn permuted<T: Copy + Send + Sync>(v: &[T], perm: &[usize]) -> Vec<T> {
+    pool::install(|| perm.par_iter().map(|&k| v[k]).collect())
+}
+
+/// Sum the elements at the same position of a matrix whose elements are sorted
+/// by (row, col), in the order they appear, and drop the arrays to one element
+/// per position. The chunks are coalesced in place in parallel and then moved
+/// together.
+pub fn coalesce(col: &mut Vec<u32>, row: &mut Vec<u32>,
+                data: &mut Vec<CComplex<f64>>) {
+    let n = data.len();
+    let mut bounds = vec![0];
+    while bounds[bounds.len() - 1] < n {
+        let start = bounds[bounds.len() - 1];
+        let mut end = cmp::min(start + ELEMENTS_PER_CHUNK, n);
+        while end < n && row[end] == row[end - 1] && col[end] == col[end - 1] {
+            end += 1;
+        }
+        bounds.push(end);
+    }
+
+    let lens = {
+        let cols = split_at_bounds(col, &bounds);
+        let rows = split_at_bounds(row, &bounds);
+        let datas = split_at_bounds(data, &bounds);
+        let chunks = cols.into_iter()
+                         .zip(rows)
+                         .zip(datas)
+                         .collect::<Vec<_>>();
+        pool::install(|| {
+            chunks.into_par_iter()
+                  .map(|((col, row), data)| coalesce_chunk(col, row, data))
+                  .collect::<Vec<_>>()
+        })
+    };
+
+    let mut len = 0;
+    for (&start, &chunk_len) in bounds.iter().zip(lens.iter()) {
+        col.copy_within(start..start + chunk_len, len);
+        row.copy_within(start..start + chunk_len, len);
+        data.copy_within(start..start + chunk_len, len);
+        len += chunk_len;
+    }
+    col.truncate(len);
+    row.truncate(len);
+    data.truncate(len);
+}
+
+/// The largest |a_ij - conj(a_ji)| of the square matrix with the given
+/// coordinate arrays, after summing the elements at the same position. A
+/// position whose transpose holds no element is compared with zero. This is 0
+/// for a hermitian matrix and of the order of the roundoff for the builders.
+pub fn max_asymmetry(col: &[u32], row: &[u32], data: &[CComplex<f64>]) -> f64 {
+    let perm = row_col_order(col, row);
+    let mut col = permuted(col, &perm);
+    let mut row = permuted(row, &perm);
+    let mut data = permuted(data, &perm);
+    coalesce(&mut col, &mut row, &mut data);
+    let key = |r: u32, c: u32| (u64::from(r) << 32) | u64::from(c);
+    let keys = row.iter()
+                  .zip(col.iter())
+                  .map(|(&r, &c)| key(r, c))
+                  .collect::<Vec<_>>();
+    (0..keys.len()).map(|k| {
+                       let transposed = key(col[k], row[k]);
+                       let (re, im) = match keys.binary_search(&transposed) {
+                           Ok(t) => (data[t].re, -data[t].im),
+                           Err(_) => (0., 0.)
+                       };
+                       (data[k].re - re).hypot(data[k].im - im)
+                   })
+                   .fold(0., f64::max)
+}
+
+/// The slices of "v" between consecutive "bounds"
+fn split_at_bounds<'a, T>(v: &'a mut [T], bounds: &[usize]) -> Vec<&'a mut [T]> {
+    let mut rest = v;
+    let mut chunks = Vec::with_capacity(bounds.len());
+    for w in bounds.windows(2) {
+        let (chunk, tail) = rest.split_at_mut(w[1] - w[0]);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Coalesce the sorted elements of one chunk into its front. Returns the
+/// number of elements left.
+fn coalesce_chunk(col: &mut [u32], row: &mut [u32], data: &mut [CComplex<f64>])
+                  -> usize {
+    let mut len = 0;
+    for k in 0..data.len() {
+        if len > 0 && row[len - 1] == row[k] && col[len - 1] == col[k] {
+            data[len - 1].re += data[k].re;
+            data[len - 1].im += data[k].im;
+        } else {
+            col[len] = col[k];
+            row[len] = row[k];
+            data[len] = data[k];
+            len += 1;
+        }
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consv;
+    use ops;
+    use test_support::lock_pool;
+
+    type Triplets = (Vec<u32>, Vec<u32>, Vec<CComplex<f64>>);
+
+    // the serial assembly the parallel passes replace: a stable sort of the
+    // positions followed by a single coalescing sweep, kept as a reference
+    fn assemble_reference(col: &[u32], row: &[u32], data: &[CComplex<f64>])
+                          -> Triplets {
+        let mut perm = (0..col.len()).collect::<Vec<usize>>();
+        perm.sort_by_key(|&k| (row[k], col[k]));
+        let mut col = perm.iter().map(|&k| col[k]).collect::<Vec<_>>();
+        let mut row = perm.iter().map(|&k| row[k]).collect::<Vec<_>>();
+        let mut data = perm.iter().map(|&k| data[k]).collect::<Vec<_>>();
+        let len = coalesce_chunk(&mut col, &mut row, &mut data);
+        col.truncate(len);
+        row.truncate(len);
+        data.truncate(len);
+        (col, row, data)
+    }
+
+    fn assemble(col: &[u32], row: &[u32], data: &[CComplex<f64>]) -> Triplets {
+        let perm = row_col_order(col, row);
+        let mut col = permuted(col, &perm);
+        let mut row = permuted(row, &perm);
+        let mut data = permuted(data, &perm);
+        coalesce(&mut col, &mut row, &mut data);
+        (col, row, data)
+    }
+
+    fn bits(t: &Triplets) -> Vec<(u32, u32, u64, u64)> {
+        t.0
+         .iter()
+         .zip(t.1.iter())
+         .zip(t.2.iter())
+         .map(|((&c, &r), d)| (c, r, d.re.to_bits(), d.im.to_bits()))
+         .collect()
+    }
+
+    #[test]
+    fn assembly_matches_serial_4x4() {
+        let _pool = lock_pool();
+        let bfuncs = consv::k::bloch_states(Dim(4), Dim(4), K(1), K(2)).unwrap();
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSsPpmm, I(1)),
+                     Term::new(TermKind::HSsXy, I(2))];
+        let (mut col, mut row, mut data) = (Vec::new(), Vec::new(), Vec::new());
+        for sink in ops::terms_vecs(&terms, &bfuncs).into_iter() {
+            col.extend(sink.cols);
+            row.extend(sink.rows);
+            data.extend(sink.data);
+        }
+        // chunks of several runs of positions shared by the terms
+        assert!(col.len() > 2 * ELEMENTS_PER_CHUNK);
+
+        let reference = bits(&assemble_reference(&col, &row, &data));
+        assert!(reference.len() < col.len());
+        for &threads in [1, 3, 4].iter() {
+            pool::set_threads(threads).unwrap();
+            assert_eq!(bits(&assemble(&col, &row, &data)), reference);
+        }
+        pool::set_threads(0).unwrap();
+    }
+
+    #[test]
+    fn max_asymmetry_test() {
+        let c = |re, im| CComplex { re, im };
+        // [[1, 2 - i], [2 + i, 3]] with the (0, 1) element split in two
+        let (col, row) = (vec![0, 1, 1, 0, 1], vec![0, 0, 0, 1, 1]);
+        let data = vec![c(1., 0.), c(1.5, -1.), c(0.5, 0.), c(2., 1.), c(3., 0.)];
+        assert_eq!(max_asymmetry(&col, &row, &data), 0.);
+        // an imaginary diagonal and an element without its transpose
+        let data = vec![c(1., 0.5), c(1.5, -1.), c(0.5, 0.), c(2., 1.), c(3., 0.)];
+        assert_eq!(max_asymmetry(&col, &row, &data), 1.);
+        assert_eq!(max_asymmetry(&[1, 0], &[0, 0], &[c(3., 4.), c(1., 0.)]), 5.);
+        assert_eq!(max_asymmetry(&[], &[], &[]), 0.);
+    }
+
+    /// Run with --release --ignored --nocapture to time the assembly of 10^8
+    /// triplets arriving in four row-sorted blocks, as from the terms of an
+    /// export, where every row has 64 elements in each block spread over 64
+    /// columns so that many positions repeat
+    #[test]
+    #[ignore]
+    fn assembly_speedup() {
+        use std::time::Instant;
+        let n = 100_000_000;
+        let blocks = 4;
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(2685821657736338717)
+        };
+        let (mut col, mut row, mut data) = (Vec::with_capacity(n),
+                                            Vec::with_capacity(n),
+                                            Vec::with_capacity(n));
+        for _ in 0..blocks {
+            for i in 0..n / blocks {
+                row.push((i / 64) as u32);
+                col.push((random() % 64) as u32);
+                data.push(CComplex { re: 1., im: 0. });
+            }
+        }
+
+        let start = Instant::now();
+        let reference = assemble_reference(&col, &row, &data);
+        let serial = start.elapsed();
+
+        let start = Instant::now();
+        let parallel = assemble(&col, &row, &data);
+        let elapsed = start.elapsed();
+
+        assert_eq!(parallel.0, reference.0);
+        assert_eq!(parallel.1, reference.1);
+        println!("{} triplets into {}, serial: {:?}, parallel: {:?}",
+                 n,
+                 reference.0.len(),
+                 serial,
+                 elapsed);
+    }
+}
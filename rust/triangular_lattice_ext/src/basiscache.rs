@@ -0,0 +1,275 @@
+//! An opt-in cache of the bases built in memory, so that calls for a sector
+//! whose basis was built recently reuse it instead of scanning the sector
+//! again. The cache is off until enable is called with a memory budget. It
+//! keeps the most recently used bases that fit into the budget, as estimated
+//! by BlochFuncSet::heap_bytes, and evicts the least recently used ones first.
+//!
+//! The cache is transparent: a basis is keyed by everything it is built from,
+//! including the lookup and the convention it was built for, and is never
+//! modified once built, so the cached basis is the one a new build would
+//! produce. The lock is not held while a basis is built, so sectors built in
+//! parallel do not wait for each other; two threads asking for the same
+//! uncached sector at once both build it and one of the two copies is kept.
+use std::{
+    mem,
+    sync::{Arc, Mutex}
+};
+
+use blochfunc::{self, BlochFuncSet, Convention, Lookup};
+use common::*;
+use error::Result;
+use sitevector::{LatticeGeometry, Periodicity, SiteOrdering};
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache { max_bytes: 0,
+                                                bytes:     0,
+                                                entries:   Vec::new() });
+
+/// What a basis is built from. "nup" is None for the bases of all
+/// magnetizations. The geometry does not change the Bloch functions, but the
+/// basis keeps the tables of its lattice (see BlochFuncSet::tables), which
+/// it does change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sector {
+    pub nx:          Dim,
+    pub ny:          Dim,
+    /// The shift of the lattice, see common::LatticeSettings
+    pub shift:       u32,
+    /// The periodicity of the lattice, see common::LatticeSettings
+    pub periodicity: Periodicity,
+    /// The ordering of the sites, see common::LatticeSettings
+    pub ordering:    SiteOrdering,
+    pub geometry:    LatticeGeometry,
+    pub kx:          K,
+    pub ky:          K,
+    pub nup:         Option<u32>
+}
+
+impl Sector {
+    /// The sector with momentum (kx, ky) and "nup" up spins of the lattice of
+    /// "tables"
+    pub fn new(tables: &LatticeTables, kx: K, ky: K, nup: Option<u32>) -> Sector {
+        Sector { nx: tables.nx(),
+                 ny: tables.ny(),
+                 shift: tables.shift(),
+                 periodicity: tables.periodicity(),
+                 ordering: tables.ordering(),
+                 geometry: *tables.geometry(),
+                 kx,
+                 ky,
+                 nup }
+    }
+
+    /// The sector of "bfuncs", which fixes the number of up spins if all its
+    /// leading states have the same
+    pub fn of(bfuncs: &BlochFuncSet) -> Sector {
+        let mut counts = bfuncs.iter().map(|b| b.lead.raw_int().count_ones());
+        let first = counts.next();
+        let nup = first.filter(|&n| counts.all(|m| m == n));
+        Sector::new(&bfuncs.tables, bfuncs.kx, bfuncs.ky, nup)
+    }
+
+    /// Whether the orbit of "dec" has the number of up spins of the sector and
+    /// is compatible with its momentum, i.e. belongs in its basis
+    pub fn holds(&self, dec: BinaryBasis) -> bool {
+        let trans = Translations::ordered(self.nx,
+                                          self.ny,
+                                          self.shift,
+                                          self.periodicity,
+                                          self.ordering);
+        self.nup.iter().all(|&nup| dec.raw_int().count_ones() == nup)
+        && blochfunc::fits_momentum(dec, &trans, self.kx, self.ky)
+    }
+}
+
+struct Cache {
+    max_bytes: usize,
+    bytes:     usize,
+    // most recently used last
+    entries:   Vec<Entry>
+}
+
+struct Entry {
+    sector:     Sector,
+    lookup:     Lookup,
+    convention: Convention,
+    bfuncs:     Arc<BlochFuncSet>,
+    bytes:      usize
+}
+
+impl Cache {
+    // the basis of "sector" built for "lookup" and "convention", marked as used
+    // most recently
+    fn get(&mut self, sector: Sector, lookup: Lookup, convention: Convention)
+           -> Option<Arc<BlochFuncSet>> {
+        let pos = self.entries.iter().position(|e| {
+                                         e.sector == sector
+                                         && e.lookup == lookup
+                                         && e.convention == convention
+                                     })?;
+        let entry = self.entries.remove(pos);
+        let bfuncs = entry.bfuncs.clone();
+        self.entries.push(entry);
+        Some(bfuncs)
+    }
+
+    // keep "bfuncs" as the basis of "sector" unless it is already there or
+    // does not fit into the budget at all
+    fn insert(&mut self, sector: Sector, bfuncs: &Arc<BlochFuncSet>) {
+        let (lookup, convention) = (bfuncs.lookup, bfuncs.convention);
+        let present = self.entries.iter().any(|e| {
+                                             e.sector == sector
+                                             && e.lookup == lookup
+                                             && e.convention == convention
+                                         });
+        let bytes = bfuncs.heap_bytes();
+        if present || bytes > self.max_bytes {
+            return;
+        }
+        self.bytes += bytes;
+        self.entries.push(Entry { sector,
+                                  lookup,
+                                  convention,
+                                  bfuncs: bfuncs.clone(),
+                                  bytes });
+        self.evict();
+    }
+
+    // drop the least recently used entries until the rest fit into the budget
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let entry = self.entries.remove(0);
+            self.bytes -= entry.bytes;
+        }
+    }
+}
+
+/// Keep the bases built from now on as long as they fit into "max_bytes"
+/// together, evicting bases already kept if the budget shrinks. 0 turns the
+/// cache off and empties it.
+pub fn enable(max_bytes: usize) {
+    let mut cache = CACHE.lock().unwrap();
+    cache.max_bytes = max_bytes;
+    cache.evict();
+}
+
+/// Drop all bases kept. Handles holding one of them keep it.
+pub fn clear() {
+    let mut cache = CACHE.lock().unwrap();
+    let entries = mem::take(&mut cache.entries);
+    cache.bytes = 0;
+    // the bases are released after the lock
+    drop(cache);
+    drop(entries);
+}
+
+/// The basis of "sector" for "lookup" with the phases of "convention", taken
+/// from the cache if it is there and built otherwise with "build", which must
+/// build it for those
+pub fn get_or_build<F>(sector: Sector, lookup: Lookup, convention: Convention,
+                       build: F)
+                       -> Result<Arc<BlochFuncSet>>
+    where F: FnOnce() -> Result<BlochFuncSet>
+{
+    {
+        let mut cache = CACHE.lock().unwrap();
+        if cache.max_bytes == 0 {
+            drop(cache);
+            return build().map(Arc::new);
+        }
+        if let Some(bfuncs) = cache.get(sector, lookup, convention) {
+            return Ok(bfuncs);
+        }
+    }
+    let bfuncs = Arc::new(build()?);
+    CACHE.lock().unwrap().insert(sector, &bfuncs);
+    Ok(bfuncs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consv;
+
+    fn sector(kx: u32) -> Sector {
+        Sector { nx:          Dim(4),
+                 ny:          Dim(3),
+                 shift:       0,
+                 periodicity: Periodicity::TORUS,
+                 ordering:    SiteOrdering::RowMajor,
+                 geometry:    LatticeGeometry::default(),
+                 kx:          K(kx),
+                 ky:          K(0),
+                 nup:         Some(6) }
+    }
+
+    fn build(kx: u32) -> Result<BlochFuncSet> {
+        let sector = sector(kx);
+        Ok(BlochFuncSet::clone(&consv::ks::bloch_states(sector.nx,
+                                                         sector.ny,
+                                                         sector.kx,
+                                                         sector.ky,
+                                                         6).unwrap()))
+    }
+
+    #[test]
+    fn cache_reuses_and_evicts() {
+        let a = Arc::new(build(0).unwrap());
+        let bytes = a.heap_bytes();
+        let mut cache = Cache { max_bytes: 3 * bytes,
+                                bytes:     0,
+                                entries:   Vec::new() };
+        cache.insert(sector(0), &a);
+        let b = cache.get(sector(0), a.lookup, a.convention).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        let other = match a.lookup {
+            Lookup::Members => Lookup::Leads,
+            Lookup::Leads => Lookup::Members
+        };
+        assert!(cache.get(sector(0), other, a.convention).is_none());
+        // a basis with the phases of the other convention is another basis
+        assert!(cache.get(sector(0), a.lookup, Convention::Minus).is_none());
+        assert!(cache.get(sector(1), a.lookup, a.convention).is_none());
+
+        // sector 0 was used last, so sector 1 goes first once the budget
+        // shrinks
+        cache.insert(sector(1), &Arc::new(build(1).unwrap()));
+        assert!(cache.get(sector(0), a.lookup, a.convention).is_some());
+        cache.max_bytes = bytes * 3 / 2;
+        cache.evict();
+        assert!(cache.get(sector(1), a.lookup, a.convention).is_none());
+        assert!(cache.get(sector(0), a.lookup, a.convention).is_some());
+
+        // a basis larger than the budget is not kept
+        cache.max_bytes = bytes / 2;
+        cache.evict();
+        cache.insert(sector(0), &a);
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.bytes, 0);
+    }
+
+    // the builders go through the process-wide cache; the same builder called
+    // twice, and called again without the cache, gives the same matrices. The
+    // budget only fits the basis of this sector, so that the bases the tests
+    // running alongside build are not kept, and only its matrices are
+    // compared, since they may still push it out of the cache.
+    #[test]
+    fn cached_builds_match_uncached() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(2), K(1), 6);
+        let term = Term::new(TermKind::HSsXy, I(1));
+        let triplets = || {
+            let mat = consv::ks::term_matrix(nx, ny, kx, ky, nup, &term).unwrap();
+            let data = mat.data
+                          .iter()
+                          .map(|c| (c.re.to_bits(), c.im.to_bits()))
+                          .collect::<Vec<_>>();
+            (mat.row.clone(), mat.col.clone(), data)
+        };
+        let uncached = triplets();
+        enable(consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap().heap_bytes());
+        let first = triplets();
+        let second = triplets();
+        enable(0);
+        assert_eq!(first, uncached);
+        assert_eq!(second, uncached);
+    }
+}
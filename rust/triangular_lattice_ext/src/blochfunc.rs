@@ -1,14 +1,368 @@
 use fnv::FnvHashMap;
 use num_complex::Complex;
-use std::cmp::Ordering;
+use std::{
+    borrow::Cow,
+    cmp::{self, Ordering},
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    mem,
+    path::Path,
+    sync::{Arc, Mutex}
+};
 
-use common::{BinaryBasis, Dim};
+use basiscache::Sector;
+use common::{find_leading_state, lattice_tables, BinaryBasis, Dim, LatticeSettings,
+             LatticeTables, SizedTranslations, StateDiagnostics, StateMap, StateWord,
+             Translations, Width, WordTranslations, K, MIN_PHASE_NORM, PI};
+use diskbasis::MappedBasis;
+use error::{Error, Result};
+use pool;
+use progress::{Phase, Progress};
+use rayon::{self, prelude::*};
+#[cfg(feature = "serde")]
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "serde")]
+use serde_json;
+use sitevector::{LatticeGeometry, Periodicity, SiteOrdering};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BlochFunc {
-    pub lead: BinaryBasis,
-    pub decs: FnvHashMap<BinaryBasis, Complex<f64>>,
-    pub norm: f64
+    pub lead:   BinaryBasis,
+    /// The configurations of the orbit in ascending order
+    pub decs:   Box<[BinaryBasis]>,
+    /// For each configuration, the translation tx + nx ty that first takes the
+    /// leading state there, for looking up its phase (see phase)
+    pub shifts: Box<[u8]>,
+    /// The norm itself, not its square, of the unnormalized Bloch function
+    /// Σ_(i, j) e^(i k·(i, j)) T_x^i T_y^j |lead>, summed over all N
+    /// translations: N / sqrt(L) for an orbit of L configurations that is
+    /// compatible with the momentum, where each configuration carries N / L
+    /// of the phases, and 0 otherwise
+    pub norm:   f64
+}
+
+impl BlochFunc {
+    /// Construct the Bloch function with momentum (kx, ky) whose leading state
+    /// is "lead" by translating the leading state across the lattice. The
+    /// norm vanishes if the orbit is incompatible with the momentum, in
+    /// which case the function does not belong in the basis (see is_null).
+    /// The orbit is generated on words of type W.
+    ///
+    /// The phases of the translations that take the leading state to the same
+    /// configuration add up to N / L times a single phase for an orbit of L
+    /// configurations if all the translations that leave the leading state
+    /// unchanged carry a phase of exactly 1 (see keeps_phase), and cancel
+    /// out otherwise. The compatibility is decided on those translations in
+    /// integers, and the norm is then exactly sqrt(N^2 / L), so that no
+    /// tolerance on a sum of floating-point phases decides which orbits make
+    /// up a sector.
+    pub fn new<W: StateWord>(lead: BinaryBasis, trans: &WordTranslations<W>, kx: K,
+                             ky: K)
+                             -> BlochFunc {
+        let nx = trans.nx();
+        let ny = trans.ny();
+        let shift = trans.shift();
+
+        // "members" is a hashtable that holds, for each configuration of the
+        // orbit, the first translation that leads there.
+        let mut members: FnvHashMap<W, u8> = FnvHashMap::default();
+        let mut compatible = true;
+        let lead_word = W::from_basis(lead);
+        // "new_dec" represents the configuration we are currently iterating over.
+        let mut new_dec = lead_word;
+        for j in 0..ny.raw_int() {
+            for i in 0..nx.raw_int() {
+                if new_dec == lead_word {
+                    compatible &= keeps_phase(i, j, nx, ny, shift, kx, ky);
+                }
+                members.entry(new_dec)
+                       .or_insert((i + nx.raw_int() * j) as u8);
+                new_dec = trans.x_word(new_dec);
+            }
+            new_dec = trans.y_word(new_dec);
+        }
+
+        let sites = (nx * ny).raw_int();
+        let norm = if compatible {
+            f64::from(sites * sites / members.len() as u32).sqrt()
+        } else {
+            0.
+        };
+
+        let mut members = members.into_iter().collect::<Vec<_>>();
+        members.sort();
+        let decs = members.iter().map(|&(dec, _)| dec.to_basis()).collect();
+        let shifts = members.iter().map(|&(_, shift)| shift).collect();
+        BlochFunc { lead,
+                    decs,
+                    shifts,
+                    norm }
+    }
+
+    /// The component of the (unnormalized) Bloch function on "dec", the sum of
+    /// the phases of all translations that take the leading state to "dec",
+    /// or None if "dec" is not in the orbit. "phases" is the phase of each
+    /// translation, see BlochFuncSet::phases. Every configuration of an orbit
+    /// of L configurations is reached by N / L translations, which all carry
+    /// the same phase since the orbit is compatible with the momentum.
+    pub fn phase(&self, dec: BinaryBasis, phases: &[Complex<f64>])
+                 -> Option<Complex<f64>> {
+        let n = self.decs.binary_search(&dec).ok()?;
+        let translations = phases.len() as f64 / self.decs.len() as f64;
+        Some(phases[self.shifts[n] as usize] * translations)
+    }
+
+    /// The sign of the phase of the translation that takes the leading state to
+    /// "dec", given the "signs" of BlochFuncSet::signs, or None if "dec" is
+    /// not in the orbit
+    pub fn sign(&self, dec: BinaryBasis, signs: &[i8]) -> Option<i8> {
+        let n = self.decs.binary_search(&dec).ok()?;
+        Some(signs[self.shifts[n] as usize])
+    }
+
+    /// Whether the orbit is incompatible with the momentum, which new records
+    /// as a norm of exactly 0
+    pub fn is_null(&self) -> bool { self.norm == 0. }
+
+    /// Drop the orbit unless it is kept under "lookup"
+    fn with_lookup(mut self, lookup: Lookup) -> BlochFunc {
+        if lookup == Lookup::Leads {
+            self.decs = Box::new([]);
+            self.shifts = Box::new([]);
+        }
+        self
+    }
+
+    /// Whether "dec" is the smallest configuration of its translation orbit,
+    /// which is the leading state of the Bloch functions of the orbit
+    pub fn is_leading<W: StateWord>(dec: BinaryBasis, trans: &WordTranslations<W>)
+                                    -> bool {
+        trans.is_leading_word(W::from_basis(dec))
+    }
+}
+
+/// The phase picked up by the Bloch function with momentum (kx, ky) under i
+/// translations along x and j along y on an nx by ny lattice, e^(+i k·t) with
+/// k·t = 2π (i kx / nx + j ky / ny), as in Convention::Plus. On a lattice
+/// with a shift (see common::LatticeSettings) ny translations along y are the
+/// translation by the shift along -x, and ky / ny becomes
+/// (ky - shift kx / nx) / ny. The angle is reduced in integers (see
+/// phase_turns), so the phase is exact at quarter turns and the phases of
+/// opposite translations are exact conjugates.
+fn bloch_phase(i: u32, j: u32, nx: Dim, ny: Dim, shift: u32, kx: K, ky: K)
+               -> Complex<f64> {
+    let (turns, n) = phase_turns(i, j, nx, ny, shift, kx, ky);
+    root_of_unity(turns % n, n)
+}
+
+/// The angle k·t of bloch_phase as the fraction turns / n of a full turn, with
+/// n = nx ny
+fn phase_turns(i: u32, j: u32, nx: Dim, ny: Dim, shift: u32, kx: K, ky: K)
+               -> (u64, u64) {
+    let (x_turns, y_turns, n) = momentum_turns(nx, ny, shift, kx, ky);
+    (u64::from(i) * x_turns + u64::from(j) * y_turns, n)
+}
+
+/// The angles of a translation along x and of one along y at momentum (kx, ky)
+/// as fractions of a full turn with the common denominator n = nx ny, the
+/// numerators in 0..n
+fn momentum_turns(nx: Dim, ny: Dim, shift: u32, kx: K, ky: K) -> (u64, u64, u64) {
+    let (nx, ny) = (u64::from(nx.raw_int()), u64::from(ny.raw_int()));
+    let (kx, ky, shift) = (u64::from(kx.raw_int()), u64::from(ky.raw_int()),
+                           u64::from(shift));
+    let n = nx * ny;
+    // ky nx - shift kx, taken modulo n
+    let y_turns = (ky * nx + (n - shift % nx) * kx) % n;
+    (kx * ny % n, y_turns, n)
+}
+
+/// e^(2πi t / n) for 0 <= t < n. The angle is folded into the first octant in
+/// integers, by the symmetries of the circle, before a single sin and cos are
+/// taken, so the phase is exactly 1, i, -1 or -i at quarter turns, and
+/// root_of_unity(n - t, n) is exactly the conjugate of root_of_unity(t, n).
+fn root_of_unity(t: u64, n: u64) -> Complex<f64> {
+    // e^(2πi t / m) with m = 8 n, so that the octants start at multiples of n
+    fn fold(t: u64, m: u64) -> Complex<f64> {
+        if 2 * t > m {
+            // e^(-iθ) = conj(e^(iθ))
+            fold(m - t, m).conj()
+        } else if 4 * t > m {
+            // e^(i(π - θ)) = -conj(e^(iθ))
+            let z = fold(m / 2 - t, m);
+            Complex::new(-z.re, z.im)
+        } else if 8 * t > m {
+            // e^(i(π/2 - θ)) = i conj(e^(iθ))
+            let z = fold(m / 4 - t, m);
+            Complex::new(z.im, z.re)
+        } else if t == 0 {
+            Complex::new(1., 0.)
+        } else {
+            Complex::from_polar(&1., &(2. * PI * t as f64 / m as f64))
+        }
+    }
+    fold(8 * t, 8 * n)
+}
+
+/// Whether the translation by i sites along x and j along y carries a phase of
+/// exactly 1 at momentum (kx, ky) on an nx by ny lattice with the given shift,
+/// i.e. whether i kx / nx + j ky / ny is an integer (see bloch_phase),
+/// decided in integers
+pub fn keeps_phase(i: u32, j: u32, nx: Dim, ny: Dim, shift: u32, kx: K, ky: K)
+                   -> bool {
+    let (turns, n) = phase_turns(i, j, nx, ny, shift, kx, ky);
+    turns % n == 0
+}
+
+/// Whether the orbit of "dec" is compatible with momentum (kx, ky), i.e. every
+/// translation that leaves "dec" unchanged keeps the phase, as in BlochFunc::new
+pub fn fits_momentum(dec: BinaryBasis, trans: &Translations, kx: K, ky: K) -> bool {
+    let (nx, ny, shift) = (trans.nx(), trans.ny(), trans.shift());
+    let mut new_dec = dec;
+    for j in 0..ny.raw_int() {
+        for i in 0..nx.raw_int() {
+            if new_dec == dec && !keeps_phase(i, j, nx, ny, shift, kx, ky) {
+                return false;
+            }
+            new_dec = trans.x(new_dec);
+        }
+        new_dec = trans.y(new_dec);
+    }
+    true
+}
+
+/// Whether the Bloch functions with momentum (kx, ky) on an nx by ny lattice
+/// with the given shift only pick up phases of +1 and -1, i.e. 2 kx / nx and
+/// 2 ky / ny are integers (see bloch_phase). That is the case at the center
+/// of the zone and at some points of its boundary, where the basis and the
+/// matrices of the real terms are real.
+pub fn real_momentum(nx: Dim, ny: Dim, shift: u32, kx: K, ky: K) -> bool {
+    let (x_turns, y_turns, n) = momentum_turns(nx, ny, shift, kx, ky);
+    (2 * x_turns) % n == 0 && (2 * y_turns) % n == 0
+}
+
+/// The label of the sum of the momenta (kx, ky) and (qx, qy) on an nx by ny
+/// lattice with the given shift. The phase of ky depends on kx through the
+/// shift (see bloch_phase), so kx + nx is the momentum labelled kx with ky
+/// less the shift, and ky drops by the shift whenever kx + qx wraps around.
+pub fn add_momenta(nx: Dim, ny: Dim, shift: u32, kx: K, ky: K, qx: K, qy: K)
+                   -> (K, K) {
+    let (nx, ny) = (nx.raw_int(), ny.raw_int());
+    let wraps = (kx.raw_int() + qx.raw_int()) / nx;
+    let drop = (wraps * shift) % ny;
+    (K((kx.raw_int() + qx.raw_int()) % nx),
+     K((ky.raw_int() + qy.raw_int() + ny - drop) % ny))
+}
+
+/// The angle q·r of the wavevector q of momentum (kx, ky) at the position
+/// r = x a1 + y a2 of a site, reduced to [0, 2π) under Convention::Plus and
+/// negated under Minus. q is the wavevector that the Fourier component
+/// Σ_r e^(i q·r) O_r of an operator on the sites carries, in the labelling of
+/// the Bloch functions under "convention": q·a1 = 2π kx / nx along +x and,
+/// the translations running along -y, q·a2 = -2π (ky - shift kx / nx) / ny,
+/// which makes e^(i q·r) the same at all the images of a site on a lattice
+/// with a shift (see common::LatticeSettings). The angle is reduced in
+/// integers.
+pub fn momentum_angle(nx: Dim, ny: Dim, shift: u32, convention: Convention, kx: K,
+                      ky: K, x: i32, y: i32)
+                      -> f64 {
+    let (x_turns, y_turns, n) = momentum_turns(nx, ny, shift, kx, ky);
+    let (x_turns, y_turns, n) = (x_turns as i64, y_turns as i64, n as i64);
+    let turns = (i64::from(x) * x_turns - i64::from(y) * y_turns).rem_euclid(n);
+    let angle = 2. * PI * turns as f64 / n as f64;
+    match convention {
+        Convention::Plus => angle,
+        Convention::Minus => -angle
+    }
+}
+
+/// The wavevector q of momentum (kx, ky) (see momentum_angle) in cartesian
+/// coordinates in units of the inverse lattice spacing, q = f1 b1 + f2 b2
+/// with the reciprocal vectors b1 and b2 of "geometry" and f1 = q·a1 / 2π in
+/// [0, 1) and f2 = q·a2 / 2π in (-1, 0] under Convention::Plus, both negated
+/// under Minus. Any q + m b1 + n b2 is the same momentum.
+pub fn momentum_vector(nx: Dim, ny: Dim, shift: u32, convention: Convention,
+                       geometry: &LatticeGeometry, kx: K, ky: K)
+                       -> (f64, f64) {
+    let (x_turns, y_turns, n) = momentum_turns(nx, ny, shift, kx, ky);
+    let sign = match convention {
+        Convention::Plus => 1.,
+        Convention::Minus => -1.
+    };
+    let f1 = sign * x_turns as f64 / n as f64;
+    let f2 = -sign * y_turns as f64 / n as f64;
+    let (b1, b2) = geometry.reciprocal();
+    (f1 * b1.0 + f2 * b2.0, f1 * b1.1 + f2 * b2.1)
+}
+
+/// The wavevectors of all the momenta of the nx by ny lattice (see
+/// momentum_vector), that of (kx, ky) at index kx + nx ky, with the shift and
+/// the geometry of "settings" under "convention"
+pub fn lattice_momenta(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                       convention: Convention)
+                       -> Vec<(f64, f64)> {
+    let shift = settings.shift(nx);
+    let mut momenta = Vec::with_capacity((nx * ny).raw_int() as usize);
+    for ky in 0..ny.raw_int() {
+        for kx in 0..nx.raw_int() {
+            momenta.push(momentum_vector(nx,
+                                         ny,
+                                         shift,
+                                         convention,
+                                         &settings.geometry,
+                                         K(kx),
+                                         K(ky)));
+        }
+    }
+    momenta
+}
+
+/// The momentum (kx, ky) of the nx by ny lattice whose wavevector (see
+/// lattice_momenta) under "convention" is nearest to "q", given in cartesian
+/// coordinates, and the
+/// distance between them, the smallest over all the wavevectors m b1 + n b2
+/// apart that are the same momentum. The distance is 0 up to roundoff when "q"
+/// is allowed on the lattice. Of momenta at the same distance the first in the
+/// order of lattice_momenta is taken.
+pub fn nearest_momentum(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                        convention: Convention, q: (f64, f64))
+                        -> (K, K, f64) {
+    let (a1, a2) = (settings.geometry.a1, settings.geometry.a2);
+    let (b1, b2) = settings.geometry.reciprocal();
+    let mut nearest = (K(0), K(0), f64::INFINITY);
+    let momenta = lattice_momenta(nx, ny, settings, convention);
+    for (k, &p) in momenta.iter().enumerate() {
+        let d = (q.0 - p.0, q.1 - p.1);
+        // the nearest of the images of d, from the reciprocal vectors it is
+        // closest to in the coordinates along b1 and b2
+        let f1 = ((d.0 * a1.0 + d.1 * a1.1) / (2. * PI)).round();
+        let f2 = ((d.0 * a2.0 + d.1 * a2.1) / (2. * PI)).round();
+        for m in -1..2 {
+            for n in -1..2 {
+                let (m, n) = (f1 + f64::from(m), f2 + f64::from(n));
+                let e = (d.0 - m * b1.0 - n * b2.0, d.1 - m * b1.1 - n * b2.1);
+                let distance = e.0.hypot(e.1);
+                if distance < nearest.2 {
+                    let k = k as u32;
+                    nearest = (K(k % nx.raw_int()), K(k / nx.raw_int()), distance);
+                }
+            }
+        }
+    }
+    nearest
+}
+
+/// bloch_phase at a momentum for which real_momentum holds, as a sign
+fn bloch_sign(i: u32, j: u32, nx: Dim, ny: Dim, shift: u32, kx: K, ky: K) -> i8 {
+    let (x_turns, y_turns, n) = momentum_turns(nx, ny, shift, kx, ky);
+    let half_turns =
+        u64::from(i) * (2 * x_turns / n) + u64::from(j) * (2 * y_turns / n);
+    if half_turns % 2 == 0 {
+        1
+    } else {
+        -1
+    }
 }
 
 impl Ord for BlochFunc {
@@ -27,42 +381,884 @@ impl PartialEq for BlochFunc {
 
 impl Eq for BlochFunc {}
 
+impl fmt::Display for BlochFunc {
+    /// The leading state, the length of the orbit if it is kept (see Lookup)
+    /// and the norm
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lead {}", self.lead.raw_int())?;
+        if !self.decs.is_empty() {
+            write!(f, ", {} states", self.decs.len())?;
+        }
+        write!(f, ", norm {}", self.norm)
+    }
+}
+
+/// How the Bloch function whose orbit holds a given configuration is found
+/// when matrix elements are generated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Lookup {
+    /// Every Bloch function keeps the configurations of its orbit, and every
+    /// configuration of the sector is entered in a table. Fast, but the memory
+    /// grows with the number of product states rather than of orbits.
+    Members,
+    /// Only the leading states are kept. A configuration is translated onto the
+    /// leading state of its orbit when it is looked up, which takes up to N
+    /// translations.
+    Leads
+}
+
+impl Lookup {
+    pub fn from_raw(lookup: u32) -> Result<Lookup> {
+        match lookup {
+            0 => Ok(Lookup::Members),
+            1 => Ok(Lookup::Leads),
+            _ => Err(Error::InvalidArgument("lookup"))
+        }
+    }
+}
+
+/// The sign of the phases the translations of the leading state enter a Bloch
+/// function with, i.e. which physical momentum a label (kx, ky) stands for.
+/// With t = (i, j) the translation by i sites along +x and j rows along -y
+/// (see common::translate_x and translate_y), the Bloch function is
+/// Σ_t e^(±i k·t) T_t |lead> with k·t = 2π (i kx / nx + j ky / ny), so that
+/// T_t multiplies it by e^(∓i k·t). The two conventions label the same sector
+/// (kx, ky) and (-kx, -ky) respectively; at momenta where real_momentum holds
+/// they agree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Convention {
+    /// e^(+i k·t), the default
+    Plus,
+    /// e^(-i k·t)
+    Minus
+}
+
+impl Convention {
+    pub fn from_raw(convention: u32) -> Result<Convention> {
+        match convention {
+            0 => Ok(Convention::Plus),
+            1 => Ok(Convention::Minus),
+            _ => Err(Error::InvalidArgument("convention"))
+        }
+    }
+
+    /// e^(+i k·t) as "phase" is taken under the convention
+    pub fn phase(self, phase: Complex<f64>) -> Complex<f64> {
+        match self {
+            Convention::Plus => phase,
+            Convention::Minus => phase.conj()
+        }
+    }
+}
+
+const BASIS_FILE_MAGIC: &[u8; 8] = b"SPNSBAS1";
+
+/// Number of candidate states scanned by a worker thread at a time
+const STATES_PER_CHUNK: usize = 4096;
+/// Number of chunks per thread scanned before progress is reported
+const CHUNKS_PER_THREAD: usize = 4;
+
+/// A basis of Bloch functions. The Bloch functions are in ascending order of
+/// their leading states, and the index of each in "data" is its index in the
+/// basis, i.e. its row and column in every matrix built on the basis. The
+/// order is part of the interface: a sector gets the same basis in the same
+/// order however it is built, scanned on any number of threads, loaded from a
+/// file, taken from the basis cache or mapped from disk, so that matrices and
+/// the vectors computed from them can be kept across runs.
 #[derive(Clone, Debug)]
 pub struct BlochFuncSet {
-    pub data:    Vec<BlochFunc>,
-    pub nonzero: u32,
-    pub nx:      Dim,
-    pub ny:      Dim
+    /// In ascending order of leading state, which create establishes
+    pub data:        Vec<BlochFunc>,
+    pub nonzero:     u32,
+    pub nx:          Dim,
+    pub ny:          Dim,
+    /// The shift of the lattice, see common::LatticeSettings
+    pub shift:       u32,
+    /// The periodicity of the lattice, see common::LatticeSettings
+    pub periodicity: Periodicity,
+    /// The ordering of the sites of the lattice, see common::LatticeSettings
+    pub ordering:    SiteOrdering,
+    /// The tables of the lattice, which the terms built on the basis take
+    /// their bonds and triangles from
+    pub tables:      Arc<LatticeTables>,
+    pub kx:          K,
+    pub ky:          K,
+    /// With Lookup::Leads the Bloch functions do not keep their orbits, i.e.
+    /// "decs" is empty
+    pub lookup:      Lookup,
+    pub convention:  Convention,
+    /// The phase of the Bloch functions under each translation tx + nx ty
+    pub phases:      Vec<Complex<f64>>,
+    /// The same phases as signs if they are all +1 or -1 (see real_momentum),
+    /// empty otherwise
+    pub signs:       Vec<i8>,
+    /// The width of the words the lookup tables are keyed by, the one for the
+    /// lattice unless changed
+    pub width:       Width
 }
 
-impl<'a> BlochFuncSet {
-    pub fn create(nx: Dim, ny: Dim, bfuncs: Vec<BlochFunc>) -> BlochFuncSet {
-        let data = bfuncs;
+impl BlochFuncSet {
+    /// The basis of the Bloch functions "bfuncs", sorted by leading state
+    /// whatever order they come in, with the phases of "convention" on the
+    /// lattice of "tables"
+    pub fn create(tables: Arc<LatticeTables>, kx: K, ky: K, lookup: Lookup,
+                  convention: Convention, bfuncs: Vec<BlochFunc>)
+                  -> BlochFuncSet {
+        let (nx, ny) = (tables.nx(), tables.ny());
+        let (shift, periodicity) = (tables.shift(), tables.periodicity());
+        let ordering = tables.ordering();
+        let mut data = bfuncs;
+        data.sort();
         let nonzero = data.len() as u32;
+        let shifts = 0..(nx * ny).raw_int();
+        let split = |t: u32| (t % nx.raw_int(), t / nx.raw_int());
+        let (phases, signs) = if real_momentum(nx, ny, shift, kx, ky) {
+            let signs = shifts.map(|t| {
+                                  let (i, j) = split(t);
+                                  bloch_sign(i, j, nx, ny, shift, kx, ky)
+                              })
+                              .collect::<Vec<_>>();
+            let phases = signs.iter()
+                              .map(|&s| Complex::new(f64::from(s), 0.))
+                              .collect::<Vec<_>>();
+            (phases, signs)
+        } else {
+            let phases = shifts.map(|t| {
+                                   let (i, j) = split(t);
+                                   let phase =
+                                       bloch_phase(i, j, nx, ny, shift, kx, ky);
+                                   convention.phase(phase)
+                               })
+                               .collect();
+            (phases, Vec::new())
+        };
+        // every member of an orbit carries a unit phase N / L times, so a
+        // component of a Bloch function vanishes only if the whole function
+        // does, and those are never stored
+        debug_assert!(phases.iter().all(|p| (p.norm() - 1.).abs() < 1e-12),
+                      "phases of other than unit modulus");
+        debug_assert!(data.iter().all(|b| !b.is_null()),
+                      "a Bloch function that vanishes at ({}, {})",
+                      kx.raw_int(),
+                      ky.raw_int());
         BlochFuncSet { data,
                        nonzero,
                        nx,
-                       ny }
+                       ny,
+                       shift,
+                       periodicity,
+                       ordering,
+                       tables,
+                       kx,
+                       ky,
+                       lookup,
+                       convention,
+                       phases,
+                       signs,
+                       width: Width::for_lattice(nx, ny) }
+    }
+
+    /// An estimate of the memory the basis takes up on the heap
+    pub fn heap_bytes(&self) -> usize {
+        let orbits = self.data
+                         .iter()
+                         .map(|b| {
+                                  b.decs.len() * mem::size_of::<BinaryBasis>()
+                                  + b.shifts.len()
+                              })
+                         .sum::<usize>();
+        self.data.capacity() * mem::size_of::<BlochFunc>()
+        + orbits
+        + self.phases.capacity() * mem::size_of::<Complex<f64>>()
+        + self.signs.capacity()
     }
 
+    /// The basis with momentum (kx, ky) on the lattice of "tables" spanned by
+    /// the "nstates" candidate configurations state(0), state(1), ..., which
+    /// must be in ascending order and closed under translations. The
+    /// candidates are scanned as described for scan_chunks. The orbits are
+    /// only kept under Lookup::Members, the lookup of "progress", and the
+    /// phases follow "convention". Fails if the scan is cancelled.
+    pub fn scan<F>(tables: Arc<LatticeTables>, kx: K, ky: K, convention: Convention,
+                   nstates: usize, state: F, progress: &mut Progress)
+                   -> Result<BlochFuncSet>
+        where F: Fn(usize) -> BinaryBasis + Sync
+    {
+        let (nx, ny, settings) = (tables.nx(), tables.ny(), tables.settings());
+        let lookup = progress.lookup();
+        let mut bfuncs = Vec::new();
+        BlochFuncSet::scan_chunks(nx, ny, &settings, kx, ky, nstates, state, lookup,
+                                  progress,
+                                  |mut found| {
+                                      bfuncs.append(&mut found);
+                                      Ok(())
+                                  })?;
+        Ok(BlochFuncSet::create(tables, kx, ky, lookup, convention, bfuncs))
+    }
+
+    /// Scan the "nstates" candidate configurations of scan for the Bloch
+    /// functions with momentum (kx, ky) on the nx by ny lattice with
+    /// "settings", passing the ones found to "found" a chunk at a time. The
+    /// candidates are split into chunks that are scanned in parallel on the
+    /// pool configured in the pool module. Every orbit is
+    /// kept by the chunk holding its smallest configuration only, so no orbit
+    /// is found twice and "found" receives the Bloch functions in ascending
+    /// order of their leading states, independent of the thread count. Only
+    /// a few chunks per thread are held in memory at any time. The orbits are
+    /// generated on words of the width for the lattice. The scan is reported to
+    /// "progress" on the calling thread; fails if it is cancelled or if
+    /// "found" fails.
+    pub fn scan_chunks<F, G>(nx: Dim, ny: Dim, settings: &LatticeSettings, kx: K,
+                             ky: K, nstates: usize, state: F, lookup: Lookup,
+                             progress: &mut Progress, mut found: G)
+                             -> Result<()>
+        where F: Fn(usize) -> BinaryBasis + Sync,
+              G: FnMut(Vec<BlochFunc>) -> Result<()>
+    {
+        let width = Width::for_lattice(nx, ny);
+        let (shift, periodicity) = (settings.shift(nx), settings.periodicity);
+        let ordering = settings.ordering(nx, ny);
+        let trans =
+            SizedTranslations::new(nx, ny, shift, periodicity, ordering, width);
+        let chunk = |n: usize| {
+            let start = n * STATES_PER_CHUNK;
+            let end = cmp::min(start + STATES_PER_CHUNK, nstates);
+            match trans {
+                SizedTranslations::U32(ref trans) => {
+                    scan_states(trans, (start..end).map(&state), kx, ky, lookup)
+                }
+                SizedTranslations::U64(ref trans) => {
+                    scan_states(trans, (start..end).map(&state), kx, ky, lookup)
+                }
+            }
+        };
+
+        let nchunks = (nstates + STATES_PER_CHUNK - 1) / STATES_PER_CHUNK;
+        let threads = pool::install(rayon::current_num_threads);
+        let batch_size = CHUNKS_PER_THREAD * threads;
+        let mut done = 0;
+        let mut start = 0;
+        while start < nchunks {
+            progress.check()?;
+            let end = cmp::min(start + batch_size, nchunks);
+            let batch = pool::install(|| {
+                            (start..end).into_par_iter()
+                                        .map(&chunk)
+                                        .collect::<Vec<_>>()
+                        });
+            for bfuncs in batch.into_iter() {
+                found(bfuncs)?;
+            }
+            // the stride of the reports need not divide the chunk size
+            let scanned = cmp::min(end * STATES_PER_CHUNK, nstates);
+            while done < scanned {
+                progress.step(Phase::Basis, done as u64, nstates as u64)?;
+                done += 1;
+            }
+            start = end;
+        }
+        progress.step(Phase::Basis, nstates as u64, nstates as u64)
+    }
+
+    /// Restore the order of the basis (see BlochFuncSet) after "data" has been
+    /// changed
     pub fn sort(&mut self) { self.data.sort(); }
 
-    pub fn iter(&self) -> BlochFuncSetIterator {
+    pub fn iter(&self) -> BlochFuncSetIterator<'_> {
         BlochFuncSetIterator::new(&self.data)
     }
 
-    pub fn build_dict(bfuncs: &BlochFuncSet)
-                      -> FnvHashMap<&BinaryBasis, &BlochFunc> {
-        let mut hashtable = FnvHashMap::default();
-        for bfunc in bfuncs.data.iter() {
-            for dec in bfunc.decs.keys() {
-                hashtable.insert(dec, bfunc);
+    /// Write the leading states of the basis to "path" so the basis can later
+    /// be reconstructed without scanning the whole Hilbert space. The
+    /// sector parameters are recorded alongside and checked on load. "nup"
+    /// is only a label here; u32::MAX is used for bases without Sz
+    /// conservation. The file has no room for a shift, open ends or an
+    /// ordering of the sites, so the basis of a lattice with any of them (see
+    /// common::LatticeSettings) is not saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P, kx: K, ky: K, nup: u32)
+                                -> Result<()> {
+        check_plain(self.shift, self.periodicity, self.ordering)?;
+        let mut f = BufWriter::new(File::create(path)?);
+        f.write_all(BASIS_FILE_MAGIC)?;
+        let header = [self.nx.raw_int(),
+                      self.ny.raw_int(),
+                      kx.raw_int(),
+                      ky.raw_int(),
+                      nup];
+        for x in header.iter() {
+            f.write_all(&x.to_le_bytes())?;
+        }
+        f.write_all(&(self.data.len() as u64).to_le_bytes())?;
+        for bfunc in self.data.iter() {
+            f.write_all(&bfunc.lead.raw_int().to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reconstruct a basis on the plain torus (see common::LatticeSettings) from
+    /// a file written by BlochFuncSet::save, keeping the orbits as chosen by
+    /// "lookup" with the phases of Convention::Plus. Fails if the file lists a
+    /// leading state twice.
+    pub fn load<P: AsRef<Path>>(path: P, nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                                lookup: Lookup)
+                                -> Result<BlochFuncSet> {
+        let mut f = BufReader::new(File::open(path)?);
+        let mut magic = [0_u8; 8];
+        f.read_exact(&mut magic)?;
+        if &magic != BASIS_FILE_MAGIC {
+            return Err(Error::InvalidArgument("basis file"));
+        }
+
+        let mut u32_buf = [0_u8; 4];
+        let header = [nx.raw_int(), ny.raw_int(), kx.raw_int(), ky.raw_int(), nup];
+        for &expected in header.iter() {
+            f.read_exact(&mut u32_buf)?;
+            if u32::from_le_bytes(u32_buf) != expected {
+                return Err(Error::InvalidArgument("basis file"));
+            }
+        }
+
+        let mut u64_buf = [0_u8; 8];
+        f.read_exact(&mut u64_buf)?;
+        let len = u64::from_le_bytes(u64_buf) as usize;
+        let trans = Translations::new(nx, ny);
+        let mut bfuncs = Vec::with_capacity(len);
+        for _ in 0..len {
+            f.read_exact(&mut u64_buf)?;
+            let lead = BinaryBasis(u64::from_le_bytes(u64_buf));
+            let bfunc = BlochFunc::new(lead, &trans, kx, ky);
+            if bfunc.is_null() {
+                return Err(Error::InvalidArgument("basis file"));
+            }
+            bfuncs.push(bfunc.with_lookup(lookup));
+        }
+
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default())?;
+        let table =
+            BlochFuncSet::create(tables, kx, ky, lookup, Convention::Plus, bfuncs);
+        if table.data.windows(2).any(|w| w[0] == w[1]) {
+            return Err(Error::InvalidArgument("basis file"));
+        }
+        Ok(table)
+    }
+
+    /// Expand "psi", given in this basis, into product states. Each Bloch
+    /// function contributes the configurations of its translation orbit with
+    /// amplitude psi_i * phase / norm; orbits are disjoint so every
+    /// configuration appears once. The result is sorted by decimal label.
+    pub fn expand(&self, psi: &[Complex<f64>])
+                  -> Result<Vec<(BinaryBasis, Complex<f64>)>> {
+        if psi.len() != self.data.len() {
+            return Err(Error::InvalidArgument("dim"));
+        }
+        let trans = Translations::ordered(self.nx,
+                                          self.ny,
+                                          self.shift,
+                                          self.periodicity,
+                                          self.ordering);
+        let mut states = Vec::new();
+        for (bfunc, &amp) in self.data.iter().zip(psi.iter()) {
+            for &(dec, phase) in self.orbit(bfunc, &trans).iter() {
+                states.push((dec, amp * phase / bfunc.norm));
+            }
+        }
+        states.sort_by_key(|&(dec, _)| dec);
+        Ok(states)
+    }
+
+    /// The participation ratios of "psi", given in this basis, and its weight
+    /// on the product states "decs". The amplitude of a product state d in the
+    /// orbit of Bloch function b is psi_b * phase_d / norm_b, so the product
+    /// basis ratio is Σ_b |psi_b|^4 Σ_d |phase_d|^4 / norm_b^4, where the
+    /// inner sum is 1 / L_b for an orbit of L_b configurations. Product states
+    /// outside the basis have no weight and repeated ones are counted once.
+    pub fn diagnostics(&self, psi: &[Complex<f64>], decs: &[BinaryBasis])
+                       -> Result<StateDiagnostics> {
+        if psi.len() != self.data.len() {
+            return Err(Error::InvalidArgument("dim"));
+        }
+        let norm = psi.iter().map(|c| c.norm_sqr()).sum::<f64>();
+        if norm == 0. {
+            return Err(Error::InvalidArgument("psi"));
+        }
+
+        let mut diag = StateDiagnostics::default();
+        for (bfunc, amp) in self.data.iter().zip(psi.iter()) {
+            let p = amp.norm_sqr() / norm;
+            let orbit = (bfunc.norm / self.sites() as f64).powi(2);
+            diag.ipr_reduced += p * p;
+            diag.ipr_product += p * p * orbit;
+        }
+
+        let mut decs = decs.to_vec();
+        decs.sort();
+        decs.dedup();
+        let table = OrbitTable::new(self);
+        for &dec in decs.iter() {
+            if let Some((i, _, c)) = table.amplitude(dec) {
+                let amp = psi[i as usize] * c;
+                diag.weight += amp.norm_sqr() / norm;
+            }
+        }
+        Ok(diag)
+    }
+
+    /// The number of sites of the lattice
+    pub fn sites(&self) -> u32 { (self.nx * self.ny).raw_int() }
+
+    /// The configurations of the orbit of "bfunc", a member of this basis, with
+    /// their phases (see BlochFunc::phase), in ascending order. They are
+    /// regenerated if the basis does not keep them.
+    pub fn orbit(&self, bfunc: &BlochFunc, trans: &Translations)
+                 -> Vec<(BinaryBasis, Complex<f64>)> {
+        let members = |bfunc: &BlochFunc| {
+            bfunc.decs
+                 .iter()
+                 .map(|&dec| (dec, bfunc.phase(dec, &self.phases).unwrap()))
+                 .collect()
+        };
+        match self.lookup {
+            Lookup::Members => members(bfunc),
+            Lookup::Leads => {
+                members(&BlochFunc::new(bfunc.lead, trans, self.kx, self.ky))
+            }
+        }
+    }
+
+    /// A table of every configuration of every orbit, giving the index of the
+    /// Bloch function whose orbit holds it. Empty unless the basis keeps its
+    /// orbits (Lookup::Members).
+    pub fn build_dict(bfuncs: &BlochFuncSet) -> StateMap {
+        let mut hashtable = StateMap::new(bfuncs.width);
+        for (i, bfunc) in bfuncs.data.iter().enumerate() {
+            for &dec in bfunc.decs.iter() {
+                hashtable.insert(dec, i as u32);
             }
         }
         hashtable
     }
 }
 
+/// The Bloch functions with momentum (kx, ky) among "states" that are leading
+/// states of their orbits, with the orbits generated on words of type W and
+/// kept as chosen by "lookup"
+fn scan_states<W, S>(trans: &WordTranslations<W>, states: S, kx: K, ky: K,
+                     lookup: Lookup)
+                     -> Vec<BlochFunc>
+    where W: StateWord,
+          S: Iterator<Item = BinaryBasis>
+{
+    states.filter(|&dec| BlochFunc::is_leading(dec, trans))
+          .map(|dec| BlochFunc::new(dec, trans, kx, ky))
+          .filter(|bfunc| !bfunc.is_null())
+          .map(|bfunc| bfunc.with_lookup(lookup))
+          .collect()
+}
+
+/// Finds the Bloch function whose orbit holds a configuration, in the way
+/// chosen by the lookup of the basis. The orbits of a basis on disk are found
+/// as under Lookup::Leads, with the phases read off the mapped file. The tables
+/// map configurations to the position of the Bloch function in the basis,
+/// which is also its index since the Bloch functions are sorted by leading
+/// state. The tables and translations use words of the width of the basis.
+/// Where the basis has signs for phases they are looked up instead of the
+/// complex phases. A strict table checks its misses (see strict).
+pub struct OrbitTable<'a> {
+    orbits:   Orbits<'a>,
+    strict:   bool,
+    min_norm: f64,
+    // the first miss of a strict table that belongs in the basis
+    miss:     Mutex<Option<String>>
+}
+
+// The tables of the lookups. The sector is what a strict table checks its
+// misses against, and is None for the empty table.
+enum Orbits<'a> {
+    Members {
+        bfuncs:  &'a [BlochFunc],
+        phases:  &'a [Complex<f64>],
+        signs:   &'a [i8],
+        members: StateMap,
+        sector:  Option<Sector>
+    },
+    Leads {
+        bfuncs: &'a [BlochFunc],
+        trans:  SizedTranslations,
+        phases: &'a [Complex<f64>],
+        signs:  &'a [i8],
+        leads:  StateMap,
+        sector: Sector
+    },
+    Mapped(Arc<MappedBasis>)
+}
+
+impl<'a> OrbitTable<'a> {
+    pub fn new(bfuncs: &'a BlochFuncSet) -> OrbitTable<'a> {
+        let orbits = match bfuncs.lookup {
+            Lookup::Members => {
+                Orbits::Members { bfuncs:  &bfuncs.data,
+                                      phases:  &bfuncs.phases,
+                                      signs:   &bfuncs.signs,
+                                      members: BlochFuncSet::build_dict(bfuncs),
+                                      sector:  Some(Sector::of(bfuncs)) }
+            }
+            Lookup::Leads => {
+                let mut leads = StateMap::new(bfuncs.width);
+                for (i, b) in bfuncs.iter().enumerate() {
+                    leads.insert(b.lead, i as u32);
+                }
+                let (nx, ny) = (bfuncs.nx, bfuncs.ny);
+                let (shift, width) = (bfuncs.shift, bfuncs.width);
+                let (periodicity, ordering) = (bfuncs.periodicity, bfuncs.ordering);
+                let trans = SizedTranslations::new(nx,
+                                                   ny,
+                                                   shift,
+                                                   periodicity,
+                                                   ordering,
+                                                   width);
+                Orbits::Leads { bfuncs: &bfuncs.data,
+                                    trans,
+                                    phases: &bfuncs.phases,
+                                    signs: &bfuncs.signs,
+                                    leads,
+                                    sector: Sector::of(bfuncs) }
+            }
+        };
+        OrbitTable::with_orbits(orbits)
+    }
+
+    /// A table that finds nothing, for operators that are diagonal
+    pub fn empty() -> OrbitTable<'a> {
+        OrbitTable::with_orbits(Orbits::Members { bfuncs:  &[],
+                                                  phases:  &[],
+                                                  signs:   &[],
+                                                  members: StateMap::new(Width::U64),
+                                                  sector:  None })
+    }
+
+    /// The table of a basis on disk
+    pub fn mapped(basis: Arc<MappedBasis>) -> OrbitTable<'a> {
+        OrbitTable::with_orbits(Orbits::Mapped(basis))
+    }
+
+    fn with_orbits(orbits: Orbits<'a>) -> OrbitTable<'a> {
+        OrbitTable { orbits,
+                     strict: false,
+                     min_norm: MIN_PHASE_NORM,
+                     miss: Mutex::new(None) }
+    }
+
+    /// Check every configuration that find or amplitude does not find. If its
+    /// orbit belongs in the sector of the basis (see basiscache::Sector::holds)
+    /// the basis is missing a Bloch function, which check reports. Off by
+    /// default, since the check walks the orbit of every miss.
+    pub fn strict(self, strict: bool) -> OrbitTable<'a> {
+        OrbitTable { strict, ..self }
+    }
+
+    /// Take the component of a Bloch function on a configuration as a phase
+    /// only if its magnitude is at least "norm" (see common::leading_phase),
+    /// MIN_PHASE_NORM by default. Smaller components are missed as if the
+    /// configuration were in no orbit of the basis. Where the phases are read
+    /// off the translations (Lookup::Leads, or signs) there is no component to
+    /// check.
+    pub fn min_phase_norm(self, norm: f64) -> OrbitTable<'a> {
+        OrbitTable { min_norm: norm,
+                     ..self }
+    }
+
+    /// Fails with Error::Inconsistent if a strict table has missed a
+    /// configuration whose orbit belongs in the basis, naming the first one
+    pub fn check(&self) -> Result<()> {
+        match *self.miss.lock().unwrap() {
+            Some(ref msg) => Err(Error::Inconsistent(msg.clone())),
+            None => Ok(())
+        }
+    }
+
+    /// The index of the Bloch function whose orbit holds "dec", the Bloch
+    /// function and the phase that takes the configuration back to the leading
+    /// state, as in find_leading_state. The Bloch functions of a basis on disk
+    /// are read into a copy, which does not allocate. A strict table keeps a
+    /// miss whose orbit belongs in the basis for check.
+    pub fn find(&self, dec: BinaryBasis)
+                -> Option<(u32, Cow<'_, BlochFunc>, Complex<f64>)> {
+        let found = self.find_unchecked(dec);
+        if found.is_none() && self.strict {
+            self.check_miss(dec);
+        }
+        found
+    }
+
+    // Keep "dec", which the table does not hold, for check if its orbit
+    // belongs in the sector of the basis
+    fn check_miss(&self, dec: BinaryBasis) {
+        let sector = match self.orbits {
+            Orbits::Members { sector, .. } => sector,
+            Orbits::Leads { sector, .. } => Some(sector),
+            Orbits::Mapped(ref basis) => {
+                Some(Sector { nx:          basis.nx,
+                              ny:          basis.ny,
+                              shift:       0,
+                              periodicity: Periodicity::TORUS,
+                              ordering:    SiteOrdering::RowMajor,
+                              geometry:    LatticeGeometry::default(),
+                              kx:          basis.kx,
+                              ky:          basis.ky,
+                              nup:         Some(basis.nup) })
+            }
+        };
+        if let Some(sector) = sector.filter(|sector| sector.holds(dec)) {
+            let mut miss = self.miss.lock().unwrap();
+            if miss.is_none() {
+                *miss = Some(format!("the Bloch function of configuration {:#x} \
+                                      is missing from the basis at momentum \
+                                      ({}, {})",
+                                     dec.raw_int(),
+                                     sector.kx.raw_int(),
+                                     sector.ky.raw_int()));
+            }
+        }
+    }
+
+    fn find_unchecked(&self, dec: BinaryBasis)
+                      -> Option<(u32, Cow<'_, BlochFunc>, Complex<f64>)> {
+        match self.orbits {
+            Orbits::Members { bfuncs,
+                                  phases,
+                                  signs,
+                                  ref members,
+                                  .. } => {
+                if signs.is_empty() {
+                    return find_leading_state(dec, members, bfuncs, phases,
+                                              self.min_norm)
+                        .map(|(i, phase)| {
+                                 (i, Cow::Borrowed(&bfuncs[i as usize]), phase)
+                             });
+                }
+                // the phase is its own conjugate and needs no normalization
+                members.get(dec).and_then(|i| {
+                                    let bfunc = &bfuncs[i as usize];
+                                    bfunc.sign(dec, signs).map(|s| {
+                                        (i,
+                                         Cow::Borrowed(bfunc),
+                                         Complex::new(f64::from(s), 0.))
+                                    })
+                                })
+            }
+            Orbits::Leads { bfuncs,
+                                ref trans,
+                                phases,
+                                signs,
+                                ref leads,
+                                .. } => {
+                let (lead, tx, ty) = trans.leading(dec);
+                let t = (tx + trans.nx().raw_int() * ty) as usize;
+                leads.get(lead).map(|i| {
+                                    let phase = if signs.is_empty() {
+                                        phases[t]
+                                    } else {
+                                        Complex::new(f64::from(signs[t]), 0.)
+                                    };
+                                    (i, Cow::Borrowed(&bfuncs[i as usize]), phase)
+                                })
+            }
+            Orbits::Mapped(ref basis) => {
+                basis.find(dec, self.min_norm)
+                     .map(|(i, bfunc, phase)| (i, Cow::Owned(bfunc), phase))
+            }
+        }
+    }
+
+    /// The index of the Bloch function whose orbit holds "dec", the Bloch
+    /// function and the component of the normalized Bloch function on "dec".
+    /// Checked by a strict table as find is.
+    pub fn amplitude(&self, dec: BinaryBasis)
+                     -> Option<(u32, Cow<'_, BlochFunc>, Complex<f64>)> {
+        let found = self.amplitude_unchecked(dec);
+        if found.is_none() && self.strict {
+            self.check_miss(dec);
+        }
+        found
+    }
+
+    fn amplitude_unchecked(&self, dec: BinaryBasis)
+                           -> Option<(u32, Cow<'_, BlochFunc>, Complex<f64>)> {
+        match self.orbits {
+            Orbits::Members { bfuncs,
+                                  phases,
+                                  ref members,
+                                  .. } => {
+                members.get(dec).map(|i| {
+                                     let bfunc = &bfuncs[i as usize];
+                                     let phase = bfunc.phase(dec, phases).unwrap();
+                                     (i, Cow::Borrowed(bfunc), phase / bfunc.norm)
+                                 })
+            }
+            Orbits::Leads { ref trans, .. } => {
+                // an orbit of L configurations has norm N / sqrt(L) and every
+                // configuration carries a phase of magnitude N / L
+                let n = (trans.nx() * trans.ny()).raw_int() as f64;
+                self.find_unchecked(dec).map(|(i, bfunc, phase)| {
+                                             let amplitude =
+                                                 phase.conj() * bfunc.norm / n;
+                                             (i, bfunc, amplitude)
+                                         })
+            }
+            Orbits::Mapped(ref basis) => {
+                basis.amplitude(dec)
+                     .map(|(i, bfunc, amp)| (i, Cow::Owned(bfunc), amp))
+            }
+        }
+    }
+}
+
+/// Fails with InvalidArgument for a lattice with a shift, open ends or
+/// another ordering of the sites than row-major, which basis files and
+/// records have no fields for
+fn check_plain(shift: u32, periodicity: Periodicity, ordering: SiteOrdering)
+               -> Result<()> {
+    if shift != 0 {
+        return Err(Error::InvalidArgument("shift"));
+    }
+    if !periodicity.is_torus() {
+        return Err(Error::InvalidArgument("periodicity"));
+    }
+    if !ordering.is_row_major() {
+        return Err(Error::InvalidArgument("site ordering"));
+    }
+    Ok(())
+}
+
+/// A basis as serde writes it: the lattice, the momentum, the lookup and the
+/// convention, and the Bloch functions with the orbits the lookup keeps. The
+/// phases and the tables follow from them.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct BasisRecord<'a> {
+    nx:         Dim,
+    ny:         Dim,
+    kx:         K,
+    ky:         K,
+    lookup:     Lookup,
+    convention: Convention,
+    data:       Cow<'a, [BlochFunc]>
+}
+
+#[cfg(feature = "serde")]
+impl BlochFuncSet {
+    /// The basis as JSON, for dumping the bases of small systems and comparing
+    /// them across versions. Fails on a lattice with a shift, open ends or
+    /// another ordering of the sites, as save does.
+    pub fn to_json(&self) -> Result<String> {
+        check_plain(self.shift, self.periodicity, self.ordering)?;
+        // integers, floats and plain strings always serialize
+        Ok(serde_json::to_string(self).unwrap())
+    }
+
+    /// The basis written by to_json, rebuilt on the plain torus (see
+    /// Deserialize)
+    pub fn from_json(json: &str) -> Result<BlochFuncSet> {
+        let record = serde_json::from_str(json)
+            .map_err(|_| Error::InvalidArgument("basis json"))?;
+        BlochFuncSet::from_record(record)
+    }
+
+    /// Each Bloch function of "record" built again from its leading state,
+    /// which has to give back the orbit and the norm recorded
+    fn from_record(record: BasisRecord) -> Result<BlochFuncSet> {
+        let (nx, ny, kx, ky) = (record.nx, record.ny, record.kx, record.ky);
+        ::common::check_sector(nx, ny, kx, ky, None)?;
+        let trans = Translations::new(nx, ny);
+        let sites = (nx * ny).raw_int();
+        let mut data = Vec::with_capacity(record.data.len());
+        for recorded in record.data.iter() {
+            let lead = recorded.lead;
+            let outside = lead.raw_int().checked_shr(sites).unwrap_or(0) != 0;
+            if outside || !BlochFunc::is_leading(lead, &trans) {
+                return Err(Error::InvalidArgument("basis record"));
+            }
+            let bfunc =
+                BlochFunc::new(lead, &trans, kx, ky).with_lookup(record.lookup);
+            if bfunc.is_null()
+               || bfunc.decs != recorded.decs
+               || bfunc.shifts != recorded.shifts
+               || bfunc.norm != recorded.norm
+            {
+                return Err(Error::InvalidArgument("basis record"));
+            }
+            data.push(bfunc);
+        }
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default())?;
+        let set = BlochFuncSet::create(tables, kx, ky, record.lookup,
+                                       record.convention, data);
+        if set.data.windows(2).any(|w| w[0] == w[1]) {
+            return Err(Error::InvalidArgument("basis record"));
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for BlochFuncSet {
+    /// The BasisRecord of the basis. Fails on a lattice with a shift, open
+    /// ends or another ordering of the sites (see to_json).
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        check_plain(self.shift, self.periodicity, self.ordering)
+            .map_err(ser::Error::custom)?;
+        BasisRecord { nx:         self.nx,
+                      ny:         self.ny,
+                      kx:         self.kx,
+                      ky:         self.ky,
+                      lookup:     self.lookup,
+                      convention: self.convention,
+                      data:       Cow::Borrowed(&self.data) }.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for BlochFuncSet {
+    /// The basis of a BasisRecord, each Bloch function built again from its
+    /// leading state on the plain torus. A record whose orbits or norms differ
+    /// from those built, as after a change of the conventions, is refused
+    /// rather than read differently.
+    fn deserialize<D>(deserializer: D)
+                      -> ::std::result::Result<BlochFuncSet, D::Error>
+        where D: Deserializer<'de>
+    {
+        let record = BasisRecord::deserialize(deserializer)?;
+        BlochFuncSet::from_record(record).map_err(de::Error::custom)
+    }
+}
+
+/// The number of Bloch functions the Display of a BlochFuncSet lists
+const DISPLAYED_BLOCH_FUNCS: usize = 8;
+
+impl fmt::Display for BlochFuncSet {
+    /// The lattice, the momentum and the dimension, followed by the first
+    /// DISPLAYED_BLOCH_FUNCS Bloch functions one to a line
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}x{} k = ({}, {}): {} Bloch functions",
+               self.nx.raw_int(),
+               self.ny.raw_int(),
+               self.kx.raw_int(),
+               self.ky.raw_int(),
+               self.data.len())?;
+        for bfunc in self.data.iter().take(DISPLAYED_BLOCH_FUNCS) {
+            write!(f, "\n  {}", bfunc)?;
+        }
+        if self.data.len() > DISPLAYED_BLOCH_FUNCS {
+            write!(f, "\n  ... {} more", self.data.len() - DISPLAYED_BLOCH_FUNCS)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct BlochFuncSetIterator<'a> {
     pub ptr:  usize,
     pub len:  usize,
@@ -89,3 +1285,1040 @@ impl<'a> Iterator for BlochFuncSetIterator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{exchange_spin_flips, interacting_sites, lattice_tables, site_index,
+                 sz_basis, BasisIndex, CComplex, CTerm, Term, TermKind,
+                 Translations32, I};
+    use consv;
+    use error;
+    use k_term_matrix;
+    use k_term_matrix_convention;
+    use ks_term_matrix;
+    use ks_hamiltonian_new_lookup;
+    use ks_term_matrix_convention;
+    use ks_term_matrix_lookup;
+    use ks_term_matrix_phase_norm;
+    use ks_term_matrix_strict;
+    use num_bigint::ToBigUint;
+    use op_free;
+    use ops;
+    use reference;
+    use std::{collections::BTreeSet, ptr::null_mut};
+    use test_support::{elements, thread_allocated};
+
+    fn with_leads(bfuncs: &BlochFuncSet) -> BlochFuncSet {
+        let data = bfuncs.data
+                         .iter()
+                         .map(|b| b.clone().with_lookup(Lookup::Leads))
+                         .collect();
+        BlochFuncSet::create(bfuncs.tables.clone(), bfuncs.kx, bfuncs.ky,
+                             Lookup::Leads, bfuncs.convention, data)
+    }
+
+    fn assert_close(a: &[(BinaryBasis, Complex<f64>)],
+                    b: &[(BinaryBasis, Complex<f64>)]) {
+        assert_eq!(a.len(), b.len());
+        for (&(da, ca), &(db, cb)) in a.iter().zip(b.iter()) {
+            assert_eq!(da, db);
+            assert!((ca - cb).norm() < 1e-12);
+        }
+    }
+
+    fn with_width(bfuncs: &BlochFuncSet, width: Width) -> BlochFuncSet {
+        BlochFuncSet { width,
+                       ..bfuncs.clone() }
+    }
+
+    fn sink_bits(sink: &ops::VecSink) -> Vec<(u32, u32, u64, u64)> {
+        sink.rows
+            .iter()
+            .zip(sink.cols.iter())
+            .zip(sink.data.iter())
+            .map(|((&i, &j), d)| (i, j, d.re.to_bits(), d.im.to_bits()))
+            .collect()
+    }
+
+    #[test]
+    fn narrow_words_match_wide() {
+        // the chiral term only on the smaller lattice, where it is quick
+        let sectors = [(Dim(4), Dim(4), K(1), K(2), 8, 3),
+                       (Dim(5), Dim(4), K(1), K(0), 10, 2),
+                       (Dim(5), Dim(5), K(2), K(1), 5, 2)];
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(2)),
+                     Term::new(TermKind::HSssChi, I(0))];
+        for &(nx, ny, kx, ky, nup, nterms) in sectors.iter() {
+            // scanned on u32 words
+            let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+            assert_eq!(bfuncs.width, Width::U32);
+            let wide = Translations::new(nx, ny);
+            for bfunc in bfuncs.iter() {
+                let b = BlochFunc::new(bfunc.lead, &wide, kx, ky);
+                assert_eq!((&b.decs, &b.shifts), (&bfunc.decs, &bfunc.shifts));
+                assert_eq!(b.norm.to_bits(), bfunc.norm.to_bits());
+            }
+            let narrow = Translations32::new(nx, ny);
+            let step = cmp::max(5, (1 << (nx * ny).raw_int()) >> 18);
+            for dec in (0..1 << (nx * ny).raw_int()).step_by(step).map(BinaryBasis) {
+                assert_eq!(BlochFunc::is_leading(dec, &narrow),
+                           BlochFunc::is_leading(dec, &wide));
+            }
+
+            for narrow in [(*bfuncs).clone(), with_leads(&bfuncs)].iter() {
+                let wide = with_width(narrow, Width::U64);
+                for term in terms[..nterms].iter() {
+                    assert_eq!(sink_bits(&ops::term_vecs(term, narrow)),
+                               sink_bits(&ops::term_vecs(term, &wide)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lattice_beyond_32_sites() {
+        // on 6x6 the orbits of two up spins run past the first 32 sites
+        let (nx, ny, nup) = (Dim(6), Dim(6), 2);
+        let term = Term::new(TermKind::HSsXy, I(1));
+        let states = sz_basis(nx * ny, nup);
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default()).unwrap();
+        let (ref site1, ref site2) = *tables.bonds(I(1));
+        // the trace of the square of the term over the whole Sz sector, 1/4 for
+        // every bond the term flips
+        let flips = states.iter()
+                          .map(|&dec| {
+                              site1.iter()
+                                   .zip(site2.iter())
+                                   .filter(|&(&s1, &s2)| {
+                                       let flips = exchange_spin_flips(dec, s1, s2);
+                                       flips.0 || flips.1
+                                   })
+                                   .count()
+                          })
+                          .sum::<usize>();
+        let (mut dims, mut traces) = (0, [0., 0.]);
+        for (kx, ky) in (0..36).map(|k| (K(k % 6), K(k / 6))) {
+            let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+            assert_eq!(bfuncs.width, Width::U64);
+            let high = |b: &BlochFunc| b.decs.iter().any(|d| d.raw_int() >> 32 > 0);
+            assert!(bfuncs.iter().any(high));
+            dims += bfuncs.nonzero as usize;
+            let members = ops::term_vecs(&term, &bfuncs);
+            let leads = ops::term_vecs(&term, &with_leads(&bfuncs));
+            assert_eq!((&members.rows, &members.cols), (&leads.rows, &leads.cols));
+            for (a, b) in members.data.iter().zip(leads.data.iter()) {
+                assert!((a.re - b.re).abs() < 1e-12 && (a.im - b.im).abs() < 1e-12);
+            }
+            for (trace, sink) in traces.iter_mut().zip([members, leads].iter()) {
+                *trace += sink.data
+                              .iter()
+                              .map(|d| d.re * d.re + d.im * d.im)
+                              .sum::<f64>();
+            }
+        }
+        assert_eq!(dims, states.len());
+        for trace in traces.iter() {
+            assert!((trace - 0.25 * flips as f64).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn narrow_tables_save_memory() {
+        let bfuncs = consv::ks::bloch_states(Dim(5), Dim(4), K(1), K(0), 10)
+                         .unwrap();
+        let bytes = |bfuncs: &BlochFuncSet| {
+            let start = thread_allocated();
+            let table = BlochFuncSet::build_dict(bfuncs);
+            (thread_allocated() - start, table.len())
+        };
+        let (narrow, len) = bytes(&bfuncs);
+        let (wide, wide_len) = bytes(&with_width(&bfuncs, Width::U64));
+        assert_eq!(len, wide_len);
+        // 8 bytes an entry against 16, plus the control bytes
+        assert!(narrow * 5 < wide * 3, "{} {}", narrow, wide);
+    }
+
+    /// Run with --release --ignored --nocapture to time the scan of all
+    /// configurations of a 5x4 lattice for the Bloch functions with k = (1, 2)
+    /// and the lookups of all of them, under both lookups, on u32 words
+    /// against u64 words
+    #[test]
+    #[ignore]
+    fn narrow_words_speedup() {
+        use std::time::{Duration, Instant};
+        let (nx, ny, kx, ky) = (Dim(5), Dim(4), K(1), K(2));
+        let configurations = || (0..1 << 20).map(BinaryBasis);
+        let time = |f: &dyn Fn() -> usize| {
+            let start = Instant::now();
+            let n = f();
+            (start.elapsed(), n)
+        };
+        let scan = |width| -> (Duration, usize) {
+            time(&|| match width {
+                      Width::U32 => {
+                          let trans = Translations32::new(nx, ny);
+                          scan_states(&trans, configurations(), kx, ky,
+                                      Lookup::Members).len()
+                      }
+                      Width::U64 => {
+                          let trans = Translations::new(nx, ny);
+                          scan_states(&trans, configurations(), kx, ky,
+                                      Lookup::Members).len()
+                      }
+                  })
+        };
+        let (narrow, n) = scan(Width::U32);
+        let (wide, wide_n) = scan(Width::U64);
+        assert_eq!(n, wide_n);
+        println!("scan: u32: {:?}, u64: {:?}", narrow, wide);
+
+        let members = consv::k::bloch_states(nx, ny, kx, ky).unwrap();
+        let leads = with_leads(&members);
+        let lookups = [("members", &*members), ("leads", &leads)];
+        for &(name, bfuncs) in lookups.iter() {
+            let find = |width| {
+                let bfuncs = with_width(bfuncs, width);
+                let table = OrbitTable::new(&bfuncs);
+                time(&|| configurations().filter_map(|dec| table.find(dec)).count())
+            };
+            let (narrow, n) = find(Width::U32);
+            let (wide, wide_n) = find(Width::U64);
+            assert_eq!(n, wide_n);
+            println!("{} lookups: u32: {:?}, u64: {:?}", name, narrow, wide);
+        }
+    }
+
+    // the basis with complex phases rather than signs, as at any other momentum
+    fn with_complex_phases(bfuncs: &BlochFuncSet) -> BlochFuncSet {
+        let (nx, ny, kx, ky) = (bfuncs.nx, bfuncs.ny, bfuncs.kx, bfuncs.ky);
+        let split = |t: u32| (t % nx.raw_int(), t / nx.raw_int());
+        let phases = (0..(nx * ny).raw_int())
+            .map(|t| {
+                     let (i, j) = split(t);
+                     bloch_phase(i, j, nx, ny, 0, kx, ky)
+                 })
+            .collect();
+        BlochFuncSet { phases,
+                       signs: Vec::new(),
+                       ..bfuncs.clone() }
+    }
+
+    #[test]
+    fn real_phases_match_complex() {
+        let (nx, ny, nup) = (Dim(4), Dim(3), 6);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSsXy, I(2)),
+                     Term::new(TermKind::SsXy, I(3)),
+                     Term::new(TermKind::HSssChi, I(0))];
+        let close = |a: Complex<f64>, b: Complex<f64>| (a - b).norm() < 1e-12;
+        let c = |z: &CComplex<f64>| Complex::new(z.re, z.im);
+        let bfuncs = consv::ks::bloch_states(nx, ny, K(1), K(0), nup).unwrap();
+        assert!(bfuncs.signs.is_empty());
+        for &(kx, ky) in [(K(0), K(0)), (K(2), K(0))].iter() {
+            let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+            assert!(real_momentum(nx, ny, 0, kx, ky));
+            assert!(bfuncs.signs.contains(&-1) == (kx == K(2)));
+            for specialized in [(*bfuncs).clone(), with_leads(&bfuncs)].iter() {
+                let generic = with_complex_phases(specialized);
+                for (a, b) in specialized.iter().zip(generic.iter()) {
+                    assert_eq!((a.lead, &a.decs, &a.shifts),
+                               (b.lead, &b.decs, &b.shifts));
+                    assert!((a.norm - b.norm).abs() < 1e-12);
+                }
+                let a = OrbitTable::new(specialized);
+                let b = OrbitTable::new(&generic);
+                for dec in (0..1 << 12).map(BinaryBasis) {
+                    match (a.find(dec), b.find(dec)) {
+                        (Some((i, _, p)), Some((j, _, q))) => {
+                            assert_eq!(i, j);
+                            assert!(close(p, q) && p.im == 0.);
+                        }
+                        (a, b) => assert_eq!(a.is_none(), b.is_none())
+                    }
+                }
+
+                for term in terms.iter() {
+                    let a = ops::term_vecs(term, specialized);
+                    let b = ops::term_vecs(term, &generic);
+                    assert_eq!((&a.rows, &a.cols), (&b.rows, &b.cols));
+                    for (x, y) in a.data.iter().zip(b.data.iter()) {
+                        assert!(close(c(x), c(y)));
+                    }
+                    if !term.kind.is_real() {
+                        continue;
+                    }
+                    let mut real = ops::RealVecSink::with_capacity(0);
+                    ops::term_into(term, specialized, &mut real);
+                    assert_eq!((&real.rows, &real.cols), (&b.rows, &b.cols));
+                    for (&x, y) in real.data.iter().zip(b.data.iter()) {
+                        assert!(close(Complex::new(x, 0.), c(y)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run with --release --ignored --nocapture to time the nearest neighbour
+    /// Heisenberg terms of the zero momentum sector of a 6x4 lattice with 12 up
+    /// spins, under both lookups, with signs and real elements against complex
+    /// phases and elements
+    #[test]
+    #[ignore]
+    fn real_phases_speedup() {
+        use std::time::Instant;
+        let (nx, ny, kx, ky) = (Dim(6), Dim(4), K(0), K(0));
+        let members = consv::ks::bloch_states(nx, ny, kx, ky, 12).unwrap();
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1))];
+        let leads = with_leads(&members);
+        for &(name, bfuncs) in [("members", &*members), ("leads", &leads)].iter() {
+            let generic = with_complex_phases(bfuncs);
+            let start = Instant::now();
+            for term in terms.iter() {
+                drop(ops::term_real(term, bfuncs).unwrap());
+            }
+            let real = start.elapsed();
+            let start = Instant::now();
+            for term in terms.iter() {
+                drop(ops::term(term, &generic));
+            }
+            println!("{} terms: real: {:?}, complex: {:?}",
+                     name,
+                     real,
+                     start.elapsed());
+        }
+    }
+
+    #[test]
+    fn compatibility_matches_phase_sums() {
+        // the norm from the summed phases, which cancel to rounding error for
+        // an incompatible orbit
+        for &(nx, ny) in [(4, 4), (6, 2), (3, 4)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let trans = Translations::new(nx, ny);
+            let n = (nx * ny).raw_int();
+            for kx in 0..nx.raw_int() {
+                for ky in 0..ny.raw_int() {
+                    let (kx, ky) = (K(kx), K(ky));
+                    for dec in (0..1 << n).map(BinaryBasis) {
+                        if !BlochFunc::is_leading(dec, &trans) {
+                            continue;
+                        }
+                        let mut sums = FnvHashMap::default();
+                        let mut new_dec = dec;
+                        for j in 0..ny.raw_int() {
+                            for i in 0..nx.raw_int() {
+                                let zero = Complex::new(0., 0.);
+                                *sums.entry(new_dec).or_insert(zero) +=
+                                    bloch_phase(i, j, nx, ny, 0, kx, ky);
+                                new_dec = trans.x(new_dec);
+                            }
+                            new_dec = trans.y(new_dec);
+                        }
+                        let norm = sums.values()
+                                       .map(|p| p.norm_sqr())
+                                       .sum::<f64>()
+                                       .sqrt();
+
+                        let bfunc = BlochFunc::new(dec, &trans, kx, ky);
+                        assert_eq!(bfunc.is_null(), norm < 1e-6);
+                        assert!((bfunc.norm - norm).abs() < 1e-12);
+                        if !bfunc.is_null() {
+                            let l = sums.len() as f64;
+                            assert_eq!(bfunc.norm, (f64::from(n * n) / l).sqrt());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roots_of_unity_are_exact_at_quarter_turns_and_conjugate() {
+        let quarters = [Complex::new(1., 0.),
+                        Complex::new(0., 1.),
+                        Complex::new(-1., 0.),
+                        Complex::new(0., -1.)];
+        for n in 1..65 {
+            for t in 0..n {
+                let z = root_of_unity(t, n);
+                let ang = 2. * PI * t as f64 / n as f64;
+                assert!((z - Complex::from_polar(&1., &ang)).norm() < 1e-14);
+                assert!((z.norm_sqr() - 1.).abs() <= 4. * f64::EPSILON);
+                if 4 * t % n == 0 {
+                    assert_eq!(z, quarters[(4 * t / n) as usize]);
+                }
+                if t > 0 {
+                    assert_eq!(z, root_of_unity(n - t, n).conj());
+                }
+            }
+        }
+    }
+
+    // Whether e^(i ang) = 1
+    fn whole_turns(ang: f64) -> bool {
+        let turns = ang / (2. * PI);
+        (turns - turns.round()).abs() < 1e-12
+    }
+
+    // the wavevector of each label carries the phases of the translations,
+    // q·a1 = 2π kx / nx and q·a2 = -2π (ky - s kx / nx) / ny, the translations
+    // running along -y, and e^(i q·r) is the same at every image of a site,
+    // across a shifted boundary too
+    #[test]
+    fn momenta_carry_the_phases_of_the_translations() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let geometry = LatticeGeometry::default();
+        let dot = |q: (f64, f64), r: (f64, f64)| q.0 * r.0 + q.1 * r.1;
+        for &shift in [0, 1, 3].iter() {
+            let settings = LatticeSettings { shift,
+                                             ..LatticeSettings::default() };
+            let momenta = lattice_momenta(nx, ny, &settings, Convention::Plus);
+            assert_eq!(momenta.len(), 12);
+            let periods = [geometry.cartesian(4, 0),
+                           geometry.cartesian(-(shift as i32), 3)];
+            for (k, &q) in momenta.iter().enumerate() {
+                let (kx, ky) = (K(k as u32 % 4), K(k as u32 / 4));
+                let (fx, fy) = (f64::from(kx.raw_int()), f64::from(ky.raw_int()));
+                let y_phase = 2. * PI * (fy - f64::from(shift) * fx / 4.) / 3.;
+                assert!(whole_turns(dot(q, geometry.a1) - 2. * PI * fx / 4.));
+                assert!(whole_turns(dot(q, geometry.a2) + y_phase));
+                for &period in periods.iter() {
+                    assert!(whole_turns(dot(q, period)));
+                }
+                for x in -4..5 {
+                    for y in -3..4 {
+                        let angle = |convention| {
+                            momentum_angle(nx, ny, shift, convention, kx, ky, x, y)
+                        };
+                        let ang = angle(Convention::Plus);
+                        assert!(whole_turns(ang - dot(q, geometry.cartesian(x, y))));
+                        assert_eq!(angle(Convention::Minus), -ang);
+                    }
+                }
+                let minus = momentum_vector(nx,
+                                            ny,
+                                            shift,
+                                            Convention::Minus,
+                                            &geometry,
+                                            kx,
+                                            ky);
+                assert!((minus.0 + q.0).abs() < 1e-12);
+                assert!((minus.1 + q.1).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_momenta() {
+        // the corner (4π/3, 0) of the zone of the triangular lattice, which
+        // the 6 x 6 lattice has
+        let corner = (4. * PI / 3., 0.);
+        let (torus, plus) = (LatticeSettings::default(), Convention::Plus);
+        let (kx, ky, distance) =
+            nearest_momentum(Dim(6), Dim(6), &torus, plus, corner);
+        assert_eq!((kx, ky), (K(4), K(4)));
+        assert!(distance < 1e-12);
+
+        // each wavevector is found back from any of its images, slightly off
+        let (nx, ny) = (Dim(4), Dim(3));
+        let (b1, b2) = LatticeGeometry::default().reciprocal();
+        let offset = (0.01, -0.02);
+        for &shift in [0, 1].iter() {
+            let settings = LatticeSettings { shift,
+                                             ..torus };
+            let momenta = lattice_momenta(nx, ny, &settings, plus);
+            for (k, &q) in momenta.iter().enumerate() {
+                let image = (q.0 + b1.0 - 2. * b2.0 + offset.0,
+                             q.1 + b1.1 - 2. * b2.1 + offset.1);
+                let (kx, ky, distance) =
+                    nearest_momentum(nx, ny, &settings, plus, image);
+                assert_eq!((kx, ky), (K(k as u32 % 4), K(k as u32 / 4)));
+                assert!((distance - offset.0.hypot(offset.1)).abs() < 1e-12);
+            }
+        }
+        // off the lattice: of the momenta 0 and b1 / 2 = (π, -π / √3) of the
+        // 2 x 1 lattice, (π / 2, 0) is nearest 0
+        let (kx, ky, distance) =
+            nearest_momentum(Dim(2), Dim(1), &torus, plus, (PI / 2., 0.));
+        assert_eq!((kx, ky), (K(0), K(0)));
+        assert!((distance - PI / 2.).abs() < 1e-12);
+    }
+
+    // Whether "norm" is the double nearest to sqrt(n2 / l). With norm = m 2^e
+    // that is (2m - 1)^2 l 2^(2e - 2) <= n2 <= (2m + 1)^2 l 2^(2e - 2), which
+    // is compared exactly in big integers with both sides times 2^(2 - 2e).
+    fn is_rounded_sqrt(norm: f64, n2: u64, l: u64) -> bool {
+        let bits = norm.to_bits();
+        let m = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let e = ((bits >> 52) & 0x7ff) as i64 - 1075;
+        let big = |x: u64| x.to_biguint().unwrap();
+        let target = (0..2 - 2 * e).fold(big(n2), |x, _| x * big(2));
+        let below = big(2 * m - 1) * big(2 * m - 1) * big(l);
+        let above = big(2 * m + 1) * big(2 * m + 1) * big(l);
+        below <= target && target <= above
+    }
+
+    #[test]
+    fn norms_are_correctly_rounded() {
+        for &(nx, ny) in [(4, 4), (6, 2), (3, 4), (5, 3), (6, 3)].iter() {
+            let (n, nx, ny) = (u64::from(nx * ny), Dim(nx), Dim(ny));
+            for kx in 0..nx.raw_int() {
+                for ky in 0..ny.raw_int() {
+                    let (kx, ky) = (K(kx), K(ky));
+                    let bfuncs = consv::k::bloch_states(nx, ny, kx, ky).unwrap();
+                    // the check is slow, so once for each orbit length
+                    let norms = bfuncs.data
+                                      .iter()
+                                      .map(|b| (b.norm.to_bits(), b.decs.len()))
+                                      .collect::<BTreeSet<_>>();
+                    for &(bits, l) in norms.iter() {
+                        let l = l as u64;
+                        let rounded =
+                            |bits| is_rounded_sqrt(f64::from_bits(bits), n * n, l);
+                        assert!(rounded(bits));
+                        assert!(!rounded(bits + 1) && !rounded(bits - 1));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_from_raw() {
+        assert_eq!(Lookup::from_raw(0).unwrap(), Lookup::Members);
+        assert_eq!(Lookup::from_raw(1).unwrap(), Lookup::Leads);
+        assert!(Lookup::from_raw(2).is_err());
+    }
+
+    #[test]
+    fn leads_lookup_matches_members() {
+        let k = consv::k::bloch_states(Dim(4), Dim(4), K(1), K(2)).unwrap();
+        let ks = consv::ks::bloch_states(Dim(6), Dim(3), K(2), K(1), 9).unwrap();
+        let cases = [(&k, TermKind::HSsXy),
+                     (&k, TermKind::HSsPpmm),
+                     (&k, TermKind::HSsPmz),
+                     (&k, TermKind::HSssChi),
+                     (&ks, TermKind::HSsXy),
+                     (&ks, TermKind::HSssChi)];
+        for &(members, kind) in cases.iter() {
+            let leads = with_leads(members);
+            assert!(leads.data.iter().all(|b| b.decs.is_empty()));
+            let term = Term::new(kind, I(1));
+            let a = ops::term_vecs(&term, members);
+            let b = ops::term_vecs(&term, &leads);
+            assert_eq!(a.rows, b.rows);
+            assert_eq!(a.cols, b.cols);
+            for (x, y) in a.data.iter().zip(b.data.iter()) {
+                assert!((x.re - y.re).abs() < 1e-12 && (x.im - y.im).abs() < 1e-12);
+            }
+        }
+
+        let leads = with_leads(&ks);
+        let psi = (0..ks.data.len()).map(|i| Complex::new((i as f64).sin(), 0.1))
+                                    .collect::<Vec<_>>();
+        assert_close(&ks.expand(&psi).unwrap(), &leads.expand(&psi).unwrap());
+        let decs = (0..1 << 10).map(|d| BinaryBasis(d * 173))
+                               .collect::<Vec<_>>();
+        let a = ks.diagnostics(&psi, &decs).unwrap();
+        let b = leads.diagnostics(&psi, &decs).unwrap();
+        assert!((a.ipr_product - b.ipr_product).abs() < 1e-12);
+        assert!((a.weight - b.weight).abs() < 1e-12);
+    }
+
+    #[test]
+    fn leads_lookup_saves_memory() {
+        // the basis and the table a matrix build holds on to, as retained by
+        // a copy made on this thread
+        let held_by = |bfuncs: &BlochFuncSet| {
+            let start = thread_allocated();
+            let copy = bfuncs.clone();
+            let table = OrbitTable::new(&copy);
+            let bytes = thread_allocated() - start;
+            drop(table);
+            bytes
+        };
+        let members = consv::ks::bloch_states(Dim(5), Dim(4), K(0), K(0), 10)
+                          .unwrap();
+        let leads = with_leads(&members);
+        let (a, b) = (held_by(&members), held_by(&leads));
+        assert!(b > 0 && a > 5 * b, "{} bytes against {}", a, b);
+    }
+
+    // the lookup as it used to be done, in a table keyed by references to the
+    // configurations that holds references to the Bloch functions, followed by
+    // a search for the index of the Bloch function found
+    fn find_reference(members: &FnvHashMap<&BinaryBasis, &BlochFunc>,
+                      phases: &[Complex<f64>], index: &BasisIndex,
+                      dec: BinaryBasis)
+                      -> Option<(u32, BinaryBasis, Complex<f64>)> {
+        let &bfunc = members.get(&dec)?;
+        let mut phase = bfunc.phase(dec, phases)?.conj();
+        phase /= phase.norm();
+        Some((index.index_of(bfunc.lead).unwrap(), bfunc.lead, phase))
+    }
+
+    fn reference_dict(bfuncs: &BlochFuncSet)
+                      -> FnvHashMap<&BinaryBasis, &BlochFunc> {
+        let mut members = FnvHashMap::default();
+        for bfunc in bfuncs.data.iter() {
+            for dec in bfunc.decs.iter() {
+                members.insert(dec, bfunc);
+            }
+        }
+        members
+    }
+
+    #[test]
+    fn orbit_table_matches_reference() {
+        let bfuncs = consv::k::bloch_states(Dim(4), Dim(4), K(1), K(2)).unwrap();
+        let leads = with_leads(&bfuncs);
+        let members = reference_dict(&bfuncs);
+        let index = BasisIndex::new(&bfuncs);
+        let table = OrbitTable::new(&bfuncs);
+        let leads_table = OrbitTable::new(&leads);
+        let mut found = 0;
+        for dec in (0..1 << 16).map(BinaryBasis) {
+            let expected = find_reference(&members, &bfuncs.phases, &index, dec);
+            let by_members = table.find(dec)
+                                  .map(|(i, bfunc, phase)| (i, bfunc.lead, phase));
+            assert_eq!(by_members, expected);
+            match (leads_table.find(dec), expected) {
+                (Some((i, bfunc, phase)), Some((j, lead, p))) => {
+                    assert_eq!((i, bfunc.lead), (j, lead));
+                    assert!((phase - p).norm() < 1e-12);
+                    found += 1;
+                }
+                (None, None) => (),
+                _ => panic!("the tables disagree on {:?}", dec)
+            }
+        }
+        assert!(found > 0);
+    }
+
+    /// Run with --release --ignored --nocapture to compare the lookups of all
+    /// configurations of a 5x4 lattice in a table of references followed by a
+    /// search for the index with those in OrbitTable
+    #[test]
+    #[ignore]
+    fn orbit_table_speedup() {
+        use std::time::Instant;
+        let bfuncs = consv::k::bloch_states(Dim(5), Dim(4), K(1), K(2)).unwrap();
+        let members = reference_dict(&bfuncs);
+        let index = BasisIndex::new(&bfuncs);
+        let table = OrbitTable::new(&bfuncs);
+
+        let start = Instant::now();
+        let reference = (0..1 << 20).map(BinaryBasis)
+                                    .filter_map(|dec| {
+                                        find_reference(&members, &bfuncs.phases,
+                                                       &index, dec)
+                                    })
+                                    .map(|(i, _, phase)| i as f64 + phase.re)
+                                    .sum::<f64>();
+        let references = start.elapsed();
+
+        let start = Instant::now();
+        let indexed = (0..1 << 20).map(BinaryBasis)
+                                  .filter_map(|dec| table.find(dec))
+                                  .map(|(i, _, phase)| i as f64 + phase.re)
+                                  .sum::<f64>();
+        let indices = start.elapsed();
+
+        assert_eq!(indexed, reference);
+        println!("references: {:?}, indices: {:?}", references, indices);
+    }
+
+    // the orbit of "lead" as it used to be kept, the phases of the translations
+    // summed in a hash table
+    fn hashed_orbit(lead: BinaryBasis, trans: &Translations, kx: K, ky: K)
+                    -> FnvHashMap<BinaryBasis, Complex<f64>> {
+        let mut decs: FnvHashMap<BinaryBasis, Complex<f64>> = FnvHashMap::default();
+        let mut new_dec = lead;
+        for j in 0..trans.ny().raw_int() {
+            for i in 0..trans.nx().raw_int() {
+                let phase =
+                    bloch_phase(i, j, trans.nx(), trans.ny(), trans.shift(), kx, ky);
+                let new_p = match decs.get(&new_dec) {
+                    Some(&p) => p + phase,
+                    None => phase
+                };
+                decs.insert(new_dec, new_p);
+                new_dec = trans.x(new_dec);
+            }
+            new_dec = trans.y(new_dec);
+        }
+        decs
+    }
+
+    #[test]
+    fn compact_orbits_match_hashed_orbits() {
+        // only orbits of N configurations are compatible with k = (1, 1) on
+        // 5 x 4, and their phases are kept bit for bit. Elsewhere the phases
+        // summed over several translations may differ in the last bits.
+        let sectors = [(5, 4, 1, 1, 10, 0.), (4, 4, 1, 2, 8, 1e-14),
+                       (6, 3, 2, 1, 9, 1e-14), (4, 4, 0, 0, 8, 1e-14)];
+        for &(nx, ny, kx, ky, nup, tol) in sectors.iter() {
+            let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+            let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+            let trans = Translations::new(nx, ny);
+            for bfunc in bfuncs.iter() {
+                let hashed = hashed_orbit(bfunc.lead, &trans, kx, ky);
+                let norm = hashed.values()
+                                 .map(|p| p.norm_sqr())
+                                 .sum::<f64>()
+                                 .sqrt();
+                // the norm is exact (see norms_are_correctly_rounded), the sum of
+                // the squared phases only up to rounding
+                assert!((bfunc.norm - norm).abs() < 1e-14);
+                assert_eq!(bfunc.decs.len(), hashed.len());
+                for (&dec, &p) in hashed.iter() {
+                    let phase = bfunc.phase(dec, &bfuncs.phases).unwrap();
+                    if tol == 0. {
+                        assert_eq!((phase.re.to_bits(), phase.im.to_bits()),
+                                   (p.re.to_bits(), p.im.to_bits()));
+                    } else {
+                        assert!((phase - p).norm() < tol);
+                    }
+                }
+            }
+        }
+    }
+
+    // The elements of z and xy on the 3x2 lattice against those of the dense
+    // reference operator between the Bloch functions written out in the space
+    // of all configurations and normalized by "norm", at every momentum. The
+    // builders read as (data, (col, row)) give <col|H|row> in the orthonormal
+    // basis. A code that projects from other members of the orbits gets the
+    // same elements up to a phase e^(i(θ_col - θ_row)), and the same spectra.
+    #[test]
+    fn elements_match_projected_basis() {
+        let (nx, ny) = (Dim(3), Dim(2));
+        let n = 1 << 6;
+        let trans = Translations::new(nx, ny);
+        let (site1, site2) = interacting_sites(nx, ny, I(1));
+        let bonds = site1.iter()
+                         .zip(site2.iter())
+                         .map(|(&s1, &s2)| {
+                                  (site_index(s1) as u32, site_index(s2) as u32, 1.)
+                              })
+                         .collect::<Vec<_>>();
+        let h = reference::heisenberg(6, &bonds).dense();
+
+        for kx in 0..3 {
+            for ky in 0..2 {
+                let (kx, ky) = (K(kx), K(ky));
+                let bfuncs = consv::k::bloch_states(nx, ny, kx, ky).unwrap();
+                // the Bloch functions as new builds them, over all translations
+                let projected = |b: &BlochFunc| {
+                    let mut v = vec![Complex::new(0., 0.); n];
+                    let mut dec = b.lead;
+                    for j in 0..2 {
+                        for i in 0..3 {
+                            let p = bloch_phase(i, j, nx, ny, 0, kx, ky);
+                            v[dec.raw_int() as usize] += p / b.norm;
+                            dec = trans.x(dec);
+                        }
+                        dec = trans.y(dec);
+                    }
+                    v
+                };
+                let vecs = bfuncs.data.iter().map(projected).collect::<Vec<_>>();
+                let dims = vecs.len();
+                let mut expected = vec![Complex::new(0., 0.); dims * dims];
+                for (a, va) in vecs.iter().enumerate() {
+                    let sq = va.iter().map(|c| c.norm_sqr()).sum::<f64>();
+                    assert!((sq - 1.).abs() < 1e-12);
+                    for (b, vb) in vecs.iter().enumerate() {
+                        for (r, &x) in vb.iter().enumerate() {
+                            for (c, &y) in va.iter().enumerate() {
+                                expected[b * dims + a] +=
+                                    x.conj() * h[r * n + c] * y;
+                            }
+                        }
+                    }
+                }
+
+                let mut found = vec![Complex::new(0., 0.); dims * dims];
+                for &kind in [TermKind::HSsZ, TermKind::HSsXy].iter() {
+                    let term = Term::new(kind, I(1));
+                    let mat = consv::k::term_matrix(nx, ny, kx, ky, &term).unwrap();
+                    for ((&row, &col), d) in
+                        mat.row.iter().zip(mat.col.iter()).zip(mat.data.iter())
+                    {
+                        found[col as usize * dims + row as usize] +=
+                            Complex::new(d.re, d.im);
+                    }
+                }
+                for (&x, &y) in found.iter().zip(expected.iter()) {
+                    assert!((x - y).norm() < 1e-12, "{:?} {:?}", kx, ky);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compact_orbits_save_memory() {
+        // orbits of the 6 x 5, Sz = 0, k = 0 sector, drawn at random
+        let (nx, ny, nup) = (Dim(6), Dim(5), 15);
+        let trans = Translations::new(nx, ny);
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default()).unwrap();
+        let phases = BlochFuncSet::create(tables, K(0), K(0), Lookup::Members,
+                                          Convention::Plus, Vec::new()).phases;
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut bfuncs = Vec::new();
+        while bfuncs.len() < 1000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let dec = BinaryBasis(state & ((1 << 30) - 1));
+            if dec.raw_int().count_ones() != nup {
+                continue;
+            }
+            let (lead, _, _) = trans.leading(dec);
+            let bfunc = BlochFunc::new(lead, &trans, K(0), K(0));
+            if !bfunc.is_null() {
+                bfuncs.push(bfunc);
+            }
+        }
+        // the orbits with their phases in hash tables instead
+        let hashed = bfuncs.iter()
+                           .map(|b| {
+                               let phase = |d| b.phase(d, &phases).unwrap();
+                               let decs = b.decs
+                                           .iter()
+                                           .map(|&d| (d, phase(d)))
+                                           .collect::<FnvHashMap<_, _>>();
+                               (b.lead, decs, b.norm)
+                           })
+                           .collect::<Vec<_>>();
+
+        let start = thread_allocated();
+        let copy = hashed.clone();
+        let a = thread_allocated() - start;
+        drop(copy);
+        let start = thread_allocated();
+        let copy = bfuncs.clone();
+        let b = thread_allocated() - start;
+        drop(copy);
+        assert!(a > 3 * b, "{} bytes against {}", a, b);
+    }
+
+    #[test]
+    fn basis_order_is_independent_of_construction() {
+        use std::{env, fs};
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(4), K(1), K(0), 7);
+        let order = |bfuncs: &BlochFuncSet| {
+            bfuncs.data
+                  .iter()
+                  .map(|b| (b.lead.raw_int(), b.norm.to_bits()))
+                  .collect::<Vec<_>>()
+        };
+        let scanned = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let expected = order(&scanned);
+        assert!(expected.windows(2).all(|w| w[0].0 < w[1].0));
+
+        // Bloch functions handed over in any order
+        let mut reversed = scanned.data.clone();
+        reversed.reverse();
+        let created = BlochFuncSet::create(scanned.tables.clone(), kx, ky,
+                                           scanned.lookup, scanned.convention,
+                                           reversed);
+        assert_eq!(order(&created), expected);
+
+        // a file written out of order
+        let path = env::temp_dir().join("spinsys_basis_order_4x4.bin");
+        let mut unordered = BlochFuncSet::clone(&scanned);
+        unordered.data.reverse();
+        unordered.save(&path, kx, ky, nup).unwrap();
+        let loaded = BlochFuncSet::load(&path, nx, ny, kx, ky, nup, scanned.lookup)
+                         .unwrap();
+        assert_eq!(order(&loaded), expected);
+        // and one listing a leading state twice, which has no order
+        unordered.data.push(unordered.data[0].clone());
+        unordered.save(&path, kx, ky, nup).unwrap();
+        let lookup = scanned.lookup;
+        assert!(BlochFuncSet::load(&path, nx, ny, kx, ky, nup, lookup).is_err());
+        fs::remove_file(&path).unwrap();
+
+        // a basis on disk
+        let path = env::temp_dir().join("spinsys_basis_order_mapped_4x4.bin");
+        let _ = fs::remove_file(&path);
+        let mapped =
+            MappedBasis::create(&path, nx, ny, kx, ky, nup, &mut Progress::none())
+                .unwrap();
+        let leads = expected.iter().map(|&(lead, _)| lead).collect::<Vec<_>>();
+        assert_eq!(mapped.leads(), leads.as_slice());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn basis_json_snapshot() {
+        let (nx, ny, kx, ky) = (Dim(3), Dim(2), K(0), K(0));
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, 3).unwrap();
+        let json = bfuncs.to_json().unwrap();
+        assert_eq!(json,
+                   concat!("{\"nx\":3,\"ny\":2,\"kx\":0,\"ky\":0,",
+                           "\"lookup\":\"Members\",\"convention\":\"Plus\",",
+                           "\"data\":[",
+                           "{\"lead\":7,\"decs\":[7,56],\"shifts\":[0,3],",
+                           "\"norm\":4.242640687119285},",
+                           "{\"lead\":11,\"decs\":[11,22,25,37,44,50],",
+                           "\"shifts\":[0,1,3,2,5,4],\"norm\":2.449489742783178},",
+                           "{\"lead\":13,\"decs\":[13,19,26,38,41,52],",
+                           "\"shifts\":[0,1,4,2,3,5],\"norm\":2.449489742783178},",
+                           "{\"lead\":14,\"decs\":[14,21,28,35,42,49],",
+                           "\"shifts\":[0,1,5,2,4,3],\"norm\":2.449489742783178}",
+                           "]}"));
+
+        let read = BlochFuncSet::from_json(&json).unwrap();
+        assert_eq!(read.data, bfuncs.data);
+        assert_eq!(read.phases, bfuncs.phases);
+        assert_eq!(read.to_json().unwrap(), json);
+
+        // a norm that the leading state does not give, a state that does not
+        // lead its orbit and a basis on a lattice with a shift
+        let norm = json.replace("4.242640687119285", "4.2");
+        assert!(BlochFuncSet::from_json(&norm).is_err());
+        let lead = json.replace("\"lead\":11", "\"lead\":22");
+        assert!(BlochFuncSet::from_json(&lead).is_err());
+        assert!(BlochFuncSet::from_json("{}").is_err());
+        let shifted = LatticeSettings { shift: 1,
+                                        ..LatticeSettings::default() };
+        let plus = Convention::Plus;
+        let shifted =
+            consv::ks::bloch_states_in(nx, ny, &shifted, plus, kx, ky, 3).unwrap();
+        assert!(shifted.to_json().is_err());
+    }
+
+    #[test]
+    fn basis_display() {
+        let (nx, ny, kx, ky) = (Dim(3), Dim(2), K(0), K(0));
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, 3).unwrap();
+        assert_eq!(bfuncs.data[0].to_string(),
+                   "lead 7, 2 states, norm 4.242640687119285");
+        let leads = with_leads(&bfuncs);
+        assert_eq!(leads.data[0].to_string(), "lead 7, norm 4.242640687119285");
+        let shown = bfuncs.to_string();
+        assert_eq!(shown.lines().next(), Some("3x2 k = (0, 0): 4 Bloch functions"));
+        assert_eq!(shown.lines().count(), 5);
+        let larger = consv::ks::bloch_states(Dim(4), Dim(4), kx, ky, 8).unwrap();
+        let shown = larger.to_string();
+        assert_eq!(shown.lines().count(), DISPLAYED_BLOCH_FUNCS + 2);
+        let more = larger.data.len() - DISPLAYED_BLOCH_FUNCS;
+        assert_eq!(shown.lines().last().unwrap(), format!("  ... {} more", more));
+    }
+
+    #[test]
+    fn convention_variants() {
+        let chi = CTerm { kind:  TermKind::HSssChi as u32,
+                          l:     0,
+                          coeff: 1. };
+        let none = null_mut();
+        unsafe {
+            // convention 0 is the default, element for element
+            let plus = k_term_matrix_convention(4, 3, 1, 2, chi, 0, none);
+            assert_eq!(elements(plus),
+                       elements(k_term_matrix(4, 3, 1, 2, chi, none)));
+            // the sector labelled (kx, ky) under the other convention is the one
+            // labelled (-kx, -ky) under the default
+            let minus = elements(ks_term_matrix_convention(4, 3, 1, 2, 6, chi, 1,
+                                                           none));
+            let mirrored = elements(ks_term_matrix(4, 3, 3, 1, 6, chi, none));
+            assert_eq!(minus.len(), mirrored.len());
+            for (a, b) in minus.iter().zip(mirrored.iter()) {
+                assert_eq!((a.0, a.1), (b.0, b.1));
+                assert!((a.2 - b.2).abs() < 1e-12 && (a.3 - b.3).abs() < 1e-12);
+            }
+            let mut status = error::SUCCESS;
+            let invalid = k_term_matrix_convention(4, 3, 1, 2, chi, 2, &mut status);
+            assert!(invalid.is_null());
+            assert_eq!(status, error::ERR_INVALID_ARGUMENT);
+        }
+    }
+
+    #[test]
+    fn strict_variant() {
+        let xy = CTerm { kind:  TermKind::HSsXy as u32,
+                         l:     1,
+                         coeff: 1. };
+        unsafe {
+            // a complete basis passes the check, with or without it
+            for &strict in [0, 1].iter() {
+                let mut status = error::ERR_PANIC;
+                let checked = ks_term_matrix_strict(4, 3, 1, 2, 6, xy, strict,
+                                                    &mut status);
+                assert_eq!(status, error::SUCCESS);
+                assert_eq!(elements(checked),
+                           elements(ks_term_matrix(4, 3, 1, 2, 6, xy, null_mut())));
+            }
+        }
+    }
+
+    // the lookup is chosen per build, and every one gives the same matrix
+    #[test]
+    fn lookup_variants() {
+        let chi = CTerm { kind:  TermKind::HSssChi as u32,
+                          l:     0,
+                          coeff: 1. };
+        unsafe {
+            let plain = elements(ks_term_matrix(4, 3, 1, 2, 6, chi, null_mut()));
+            for &lookup in [0, 1].iter() {
+                let mut status = error::ERR_PANIC;
+                let mat = ks_term_matrix_lookup(4, 3, 1, 2, 6, chi, lookup,
+                                                &mut status);
+                assert_eq!(status, error::SUCCESS);
+                let mat = elements(mat);
+                assert_eq!(mat.len(), plain.len());
+                for (a, b) in mat.iter().zip(plain.iter()) {
+                    assert_eq!((a.0, a.1), (b.0, b.1));
+                    assert!((a.2 - b.2).abs() < 1e-12 && (a.3 - b.3).abs() < 1e-12);
+                }
+            }
+            let mut status = error::SUCCESS;
+            let invalid = ks_term_matrix_lookup(4, 3, 1, 2, 6, chi, 2, &mut status);
+            assert!(invalid.is_null());
+            assert_eq!(status, error::ERR_INVALID_ARGUMENT);
+
+            let terms = [chi];
+            let op = ks_hamiltonian_new_lookup(4, 3, 1, 2, 6, terms.as_ptr(), 1, 1);
+            assert!(!op.is_null());
+            op_free(op);
+            assert!(ks_hamiltonian_new_lookup(4, 3, 1, 2, 6, terms.as_ptr(), 1, 2)
+                        .is_null());
+        }
+    }
+
+    // the phase norm is chosen per build: none at all keeps every element,
+    // one above every component finds no orbit
+    #[test]
+    fn phase_norm_variants() {
+        let chi = CTerm { kind:  TermKind::HSssChi as u32,
+                          l:     0,
+                          coeff: 1. };
+        unsafe {
+            let plain = elements(ks_term_matrix(4, 3, 1, 2, 6, chi, null_mut()));
+            let build = |norm| {
+                let mut status = error::ERR_PANIC;
+                let mat = ks_term_matrix_phase_norm(4, 3, 1, 2, 6, chi, norm,
+                                                    &mut status);
+                (mat, status)
+            };
+            let (mat, status) = build(0.);
+            assert_eq!(status, error::SUCCESS);
+            assert_eq!(elements(mat), plain);
+            let (mat, status) = build(1e9);
+            assert_eq!(status, error::SUCCESS);
+            assert!(elements(mat).is_empty());
+            for &norm in [-1., f64::NAN].iter() {
+                let (mat, status) = build(norm);
+                assert!(mat.is_null());
+                assert_eq!(status, error::ERR_INVALID_ARGUMENT);
+            }
+        }
+    }
+}
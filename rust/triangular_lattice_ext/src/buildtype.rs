@@ -2,6 +2,7 @@
 macro_rules! make_int_type {
     ($n:ident, $t:ty) => {
         #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+        #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
         pub struct $n(pub $t);
 
         impl $n {
@@ -89,3 +90,30 @@ macro_rules! make_int_type {
         }
     };
 }
+
+#[macro_export]
+macro_rules! make_state_word {
+    ($t:ty) => {
+        impl StateWord for $t {
+            const BITS: u32 = (mem::size_of::<$t>() * 8) as u32;
+            const ZERO: Self = 0;
+
+            fn from_basis(dec: BinaryBasis) -> Self {
+                debug_assert!(dec.raw_int() >> (Self::BITS - 1) >> 1 == 0);
+                dec.raw_int() as $t
+            }
+
+            fn to_basis(self) -> BinaryBasis { BinaryBasis(self as u64) }
+
+            fn low_bits(bits: u32) -> Self {
+                if bits >= Self::BITS { !0 } else { (1 << bits) - 1 }
+            }
+
+            fn bit(n: u32) -> Self {
+                (1 as $t).checked_shl(n).expect("bit beyond the word")
+            }
+
+            fn count_ones(self) -> u32 { <$t>::count_ones(self) }
+        }
+    };
+}
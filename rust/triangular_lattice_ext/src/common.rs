@@ -2,22 +2,35 @@ use fnv::FnvHashMap;
 use libc::size_t;
 use num_bigint::*;
 use num_complex::Complex;
+#[cfg(feature = "serde")]
+use serde_json;
 use std::{
-    cmp::Ordering,
-    collections::VecDeque,
+    cmp::{self, Ordering},
+    collections::{HashSet, VecDeque},
     fmt::Debug,
+    hash::Hash,
     iter::FromIterator,
     mem,
     ops::{
-        Add, AddAssign, BitAnd, BitAndAssign, BitOr, BitOrAssign, Div, DivAssign,
-        Mul, MulAssign, Neg, Rem, RemAssign, Sub, SubAssign
-    }
+        Add, AddAssign, BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, Div,
+        DivAssign, Mul, MulAssign, Neg, Not, Rem, RemAssign, Shl, Shr, Sub,
+        SubAssign
+    },
+    ptr,
+    sync::{Arc, Mutex}
 };
+#[cfg(feature = "serde")]
+use std::{fs::File, io::Write, path::Path};
 
-use blochfunc::{BlochFunc, BlochFuncSet};
-use sitevector::SiteVector;
+use assemble;
+use blochfunc::{BlochFunc, BlochFuncSet, Convention};
+use diskbasis::MappedBasis;
+use error::{Error, Result};
+use progress::Progress;
+use sitevector::{self, BondDir, Displacement, LatticeGeometry, Periodicity,
+                 ShellTable, SiteOrdering, SiteVector};
 
-pub const PI: f64 = 3.1415926535897932384626433832795028841971;
+pub const PI: f64 = ::std::f64::consts::PI;
 pub const POW2: [BinaryBasis; 63] = [BinaryBasis(1),
                                      BinaryBasis(2),
                                      BinaryBasis(4),
@@ -82,11 +95,61 @@ pub const POW2: [BinaryBasis; 63] = [BinaryBasis(1),
                                      BinaryBasis(2305843009213693952),
                                      BinaryBasis(4611686018427387904)];
 
+/// The most sites a lattice may have. Configurations are handled in u64 words,
+/// in which site_mask builds the masks of sites 0 to 63; the spare bit keeps
+/// 1 << sites, the number of configurations, representable.
+pub const MAX_SITES: u32 = 63;
+
 make_int_type!(BinaryBasis, u64);
 make_int_type!(Dim, u32);
 make_int_type!(I, i32);
 make_int_type!(K, u32);
 
+/// The unsigned integers a configuration is handled in by the translations and
+/// the tables of the basis. A BinaryBasis is always 64 bits wide, but lattices
+/// of up to 32 sites fit in half of that, which halves the tables keyed by
+/// configurations and speeds up the translations; see Width.
+pub trait StateWord: Copy + Debug + Eq + Hash + Ord + Send + Sync
+                     + BitAnd<Output = Self> + BitOr<Output = Self>
+                     + BitXor<Output = Self> + Not<Output = Self>
+                     + Shl<u32, Output = Self> + Shr<u32, Output = Self> {
+    const BITS: u32;
+    const ZERO: Self;
+
+    /// "dec", which must fit into the word
+    fn from_basis(dec: BinaryBasis) -> Self;
+    fn to_basis(self) -> BinaryBasis;
+    /// The lowest "bits" bits set
+    fn low_bits(bits: u32) -> Self;
+    /// Bit "n" set
+    fn bit(n: u32) -> Self;
+    fn count_ones(self) -> u32;
+}
+
+make_state_word!(u32);
+make_state_word!(u64);
+
+/// The width of the words the configurations of a lattice are handled in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    U32,
+    U64
+}
+
+impl Width {
+    /// The narrowest width that holds the configurations of nx * ny sites.
+    /// Every basis is built with the width for its lattice, so the builders
+    /// behind each entry point take the u32 path for up to 32 sites without
+    /// being told.
+    pub fn for_lattice(nx: Dim, ny: Dim) -> Width {
+        if (nx * ny).raw_int() <= u32::BITS {
+            Width::U32
+        } else {
+            Width::U64
+        }
+    }
+}
+
 impl Neg for I {
     type Output = Self;
 
@@ -127,6 +190,7 @@ impl Div<Dim> for I {
 
 // c compatible complex type for export to numpy at the end
 #[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct CComplex<T> {
     pub re: T,
     pub im: T
@@ -148,8 +212,42 @@ pub struct Vector<T> {
 
 impl<T> Vector<T> {
     fn new(ptr: *mut T, len: size_t) -> Vector<T> { Vector { ptr, len } }
+
+    /// Hand the elements of "data" over to an external caller
+    pub fn from_vec(data: Vec<T>) -> Vector<T> {
+        // a boxed slice has no spare capacity, so it can be rebuilt from the
+        // pointer and the length alone when it is freed
+        let data = data.into_boxed_slice();
+        let len = data.len() as size_t;
+        Vector::new(Box::into_raw(data) as *mut T, len)
+    }
+
+    /// Take back the elements handed over by from_vec. The Vector must not be
+    /// used or freed afterwards. A null Vector gives an empty one.
+    pub unsafe fn into_vec(self) -> Vec<T> {
+        if self.ptr.is_null() {
+            Vec::new()
+        } else {
+            let data = ::std::slice::from_raw_parts_mut(self.ptr, self.len);
+            Box::from_raw(data as *mut [T]).into_vec()
+        }
+    }
+
+    /// View the memory as a slice. Only valid while the memory is still owned by
+    /// the Vector, i.e. before it is handed to request_free.
+    pub unsafe fn as_slice(&self) -> &[T] {
+        if self.len == 0 {
+            &[]
+        } else {
+            ::std::slice::from_raw_parts(self.ptr, self.len)
+        }
+    }
 }
 
+/// A matrix in coordinate format handed over to an external caller. Its arrays
+/// are either all allocated, with the same length, or all null with zero
+/// length, which is what failed builds return. Nothing else is ever handed out,
+/// so a matrix is either complete or has nothing to free.
 #[repr(C)]
 pub struct CoordMatrix<T> {
     pub data:  Vector<T>,
@@ -160,30 +258,495 @@ pub struct CoordMatrix<T> {
 }
 
 impl<T> CoordMatrix<T> {
-    pub fn new(mut data: Vec<T>, mut col: Vec<u32>, mut row: Vec<u32>,
-               ncols: u32, nrows: u32)
+    pub fn new(data: Vec<T>, col: Vec<u32>, row: Vec<u32>, ncols: u32, nrows: u32)
                -> CoordMatrix<T> {
-        let data_ptr = data.as_mut_ptr();
-        let data_len = data.len() as size_t;
-
-        let col_ptr = col.as_mut_ptr();
-        let col_len = col.len() as size_t;
-
-        let row_ptr = row.as_mut_ptr();
-        let row_len = row.len() as size_t;
-
-        mem::forget(data);
-        mem::forget(col);
-        mem::forget(row);
-        let data = Vector::new(data_ptr, data_len);
-        let col = Vector::new(col_ptr, col_len);
-        let row = Vector::new(row_ptr, row_len);
-        CoordMatrix { data,
-                      col,
-                      row,
+        CoordMatrix { data: Vector::from_vec(data),
+                      col: Vector::from_vec(col),
+                      row: Vector::from_vec(row),
                       ncols,
                       nrows }
     }
+
+    /// The matrix returned on failure, with null arrays
+    pub fn empty() -> CoordMatrix<T> {
+        CoordMatrix { data:  Vector::new(ptr::null_mut(), 0),
+                      col:   Vector::new(ptr::null_mut(), 0),
+                      row:   Vector::new(ptr::null_mut(), 0),
+                      ncols: 0,
+                      nrows: 0 }
+    }
+
+    /// Whether the arrays are all allocated or all null, with consistent
+    /// lengths
+    pub fn is_well_formed(&self) -> bool {
+        let nulls = [self.data.ptr.is_null(),
+                     self.col.ptr.is_null(),
+                     self.row.ptr.is_null()];
+        let len = self.data.len;
+        if self.col.len != len || self.row.len != len {
+            false
+        } else if nulls.iter().all(|&n| n) {
+            len == 0
+        } else {
+            !nulls.iter().any(|&n| n)
+        }
+    }
+}
+
+/// The arrays of a CoordMatrix while they are still owned on the Rust side,
+/// laid out the same way: the element data[k] sits in row col[k] and column
+/// row[k] (see IndexLayout). They are freed when the matrix is dropped, so the
+/// builders return this and only the exported functions convert it with
+/// into_ffi, or put it behind a handle (see handle::into_raw), at the
+/// boundary; from_ffi takes a matrix back from there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OwnedCoordMatrix<T> {
+    pub data:  Vec<T>,
+    pub col:   Vec<u32>,
+    pub row:   Vec<u32>,
+    pub ncols: u32,
+    pub nrows: u32
+}
+
+impl<T> OwnedCoordMatrix<T> {
+    pub fn new(data: Vec<T>, col: Vec<u32>, row: Vec<u32>, ncols: u32, nrows: u32)
+               -> OwnedCoordMatrix<T> {
+        debug_assert!(col.len() == data.len() && row.len() == data.len());
+        OwnedCoordMatrix { data,
+                           col,
+                           row,
+                           ncols,
+                           nrows }
+    }
+
+    /// A matrix with no rows, no columns and no elements
+    pub fn empty() -> OwnedCoordMatrix<T> {
+        OwnedCoordMatrix::new(Vec::new(), Vec::new(), Vec::new(), 0, 0)
+    }
+
+    /// The number of stored elements
+    pub fn nnz(&self) -> usize { self.data.len() }
+
+    /// Hand the arrays over to an external caller, who releases them with
+    /// request_free (or real_matrix_free for real elements)
+    pub fn into_ffi(self) -> CoordMatrix<T> {
+        CoordMatrix::new(self.data, self.col, self.row, self.ncols, self.nrows)
+    }
+
+    /// Take back a matrix handed over by into_ffi, which must not be used or
+    /// freed afterwards. A matrix whose arrays are not all allocated or all
+    /// null (see CoordMatrix::is_well_formed) cannot be taken back; its arrays
+    /// are left alone and an empty matrix returned.
+    pub unsafe fn from_ffi(mat: CoordMatrix<T>) -> OwnedCoordMatrix<T> {
+        if !mat.is_well_formed() {
+            return OwnedCoordMatrix::empty();
+        }
+        OwnedCoordMatrix { data:  mat.data.into_vec(),
+                           col:   mat.col.into_vec(),
+                           row:   mat.row.into_vec(),
+                           ncols: mat.ncols,
+                           nrows: mat.nrows }
+    }
+}
+
+impl OwnedCoordMatrix<CComplex<f64>> {
+    /// The matrix as a dense array in column-major order, the element in row i
+    /// and column j at i + j * nrows, as nalgebra and LAPACK take it. Elements
+    /// at the same position add up.
+    pub fn to_dense(&self) -> Vec<Complex<f64>> {
+        let nrows = self.nrows as usize;
+        let mut dense = vec![Complex::new(0., 0.); nrows * self.ncols as usize];
+        let elements = self.data.iter().zip(self.col.iter()).zip(self.row.iter());
+        for ((c, &i), &j) in elements {
+            dense[i as usize + j as usize * nrows] += Complex::new(c.re, c.im);
+        }
+        dense
+    }
+
+    /// The product of the matrix with "x". Fails unless "x" has as many
+    /// elements as the matrix has columns.
+    pub fn mul_vec(&self, x: &[Complex<f64>]) -> Result<Vec<Complex<f64>>> {
+        if x.len() != self.ncols as usize {
+            return Err(Error::InvalidArgument("dim"));
+        }
+        let mut y = vec![Complex::new(0., 0.); self.nrows as usize];
+        let elements = self.data.iter().zip(self.col.iter()).zip(self.row.iter());
+        for ((c, &i), &j) in elements {
+            y[i as usize] += Complex::new(c.re, c.im) * x[j as usize];
+        }
+        Ok(y)
+    }
+}
+
+/// A dense n x n matrix stored in column-major order, i.e. the element in row i
+/// and column j is data[i + j * n]. This is the layout LAPACK and
+/// numpy.asfortranarray expect.
+#[repr(C)]
+pub struct DenseMatrix<T> {
+    pub data: Vector<T>,
+    pub n:    u32
+}
+
+impl<T> DenseMatrix<T> {
+    pub fn new(data: Vec<T>, n: u32) -> DenseMatrix<T> {
+        DenseMatrix { data: Vector::from_vec(data),
+                      n }
+    }
+}
+
+/// The basis states of a sector under "convention", as Metadata records them
+fn representative(convention: Convention) -> &'static str {
+    match convention {
+        Convention::Plus => {
+            "sum_{m,n} exp(2 pi i (m kx / nx + n ky / ny)) Tx^m Ty^n |lead>, \
+             normalized, where lead is the smallest configuration of its \
+             translation orbit; sorted by lead"
+        }
+        Convention::Minus => {
+            "sum_{m,n} exp(-2 pi i (m kx / nx + n ky / ny)) Tx^m Ty^n |lead>, \
+             normalized, where lead is the smallest configuration of its \
+             translation orbit; sorted by lead"
+        }
+    }
+}
+
+/// Description of an exported sector, stored alongside the matrices so the
+/// files remain identifiable. Serialized as JSON with the serde feature.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Metadata {
+    pub lattice:             &'static str,
+    pub boundary_conditions: &'static str,
+    pub nx:                  u32,
+    pub ny:                  u32,
+    pub kx:                  u32,
+    pub ky:                  u32,
+    /// None in sectors that do not conserve total Sz
+    pub nup:                 Option<u32>,
+    pub terms:               Vec<TermMetadata>,
+    pub dimension:           u32,
+    pub nnz:                 Option<u64>,
+    pub index_base:          u32,
+    pub column_major:        bool,
+    pub version:             &'static str,
+    pub representative:      &'static str
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TermMetadata {
+    pub kind:  &'static str,
+    pub l:     u32,
+    pub coeff: f64
+}
+
+impl Metadata {
+    /// Metadata of the sector spanned by "bfuncs", without any terms
+    pub fn new(bfuncs: &BlochFuncSet, kx: K, ky: K, nup: Option<u32>) -> Metadata {
+        Metadata { lattice: "triangular",
+                   boundary_conditions: "periodic",
+                   nx: bfuncs.nx.raw_int(),
+                   ny: bfuncs.ny.raw_int(),
+                   kx: kx.raw_int(),
+                   ky: ky.raw_int(),
+                   nup,
+                   terms: Vec::new(),
+                   dimension: bfuncs.nonzero,
+                   nnz: None,
+                   index_base: 0,
+                   column_major: false,
+                   version: env!("CARGO_PKG_VERSION"),
+                   representative: representative(bfuncs.convention) }
+    }
+
+    pub fn with_terms(mut self, terms: &[Term]) -> Metadata {
+        self.terms = terms.iter()
+                          .map(|t| TermMetadata { kind:  t.kind.name(),
+                                                  l:     t.l.raw_int() as u32,
+                                                  coeff: t.coeff })
+                          .collect();
+        self
+    }
+
+    pub fn with_layout(mut self, layout: IndexLayout) -> Metadata {
+        self.index_base = layout.base();
+        self.column_major = layout.column_major;
+        self
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        // plain strings and numbers always serialize
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Write the metadata as JSON to "path"
+    #[cfg(feature = "serde")]
+    pub fn write_metadata<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(self.to_json().as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Flag for the "flags" argument of the exporters and coord_matrix_set_layout:
+/// indices start at 1 instead of 0
+pub const INDEX_ONE_BASED: u32 = 1;
+/// Flag for the "flags" argument of the exporters and coord_matrix_set_layout:
+/// elements are sorted by column, then by row
+pub const ORDER_COLUMN_MAJOR: u32 = 2;
+
+/// How the index arrays of a coordinate matrix are laid out. The element
+/// data[k] of a coordinate matrix sits in row col[k] and column row[k] (callers
+/// read the arrays as (data, (col, row))). The builders emit the elements
+/// grouped by column, in increasing order of the columns and within a column
+/// in increasing order of the rows (see ops::ElementSink). Column-major order
+/// sorts the elements that way whatever order they are in, so CSC arrays can
+/// be assembled in a single pass.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexLayout {
+    pub one_based:    bool,
+    pub column_major: bool
+}
+
+impl IndexLayout {
+    /// Fails on unknown flags
+    pub fn from_flags(flags: u32) -> Option<IndexLayout> {
+        if flags & !(INDEX_ONE_BASED | ORDER_COLUMN_MAJOR) != 0 {
+            return None;
+        }
+        Some(IndexLayout { one_based:    flags & INDEX_ONE_BASED != 0,
+                           column_major: flags & ORDER_COLUMN_MAJOR != 0 })
+    }
+
+    /// The index of the first row and column
+    pub fn base(&self) -> u32 { self.one_based as u32 }
+
+    /// The order in which the elements of a matrix with the given index arrays
+    /// have to be taken to comply with the layout, or None if they already do
+    pub fn permutation(&self, col: &[u32], row: &[u32]) -> Option<Vec<usize>> {
+        if !self.column_major {
+            return None;
+        }
+        Some(assemble::row_col_order(col, row))
+    }
+
+    /// Move the indices from the base of "from" to the base of this layout
+    pub fn rebase(&self, from: IndexLayout, indices: &mut [u32]) {
+        let (old, new) = (from.base(), self.base());
+        if old != new {
+            for i in indices.iter_mut() {
+                *i = *i - old + new;
+            }
+        }
+    }
+}
+
+/// The operators the builders know how to generate. The discriminants are what
+/// external callers pass in the "kind" field of CTerm.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum TermKind {
+    HSsZ = 0,
+    HSsXy = 1,
+    HSsPpmm = 2,
+    HSsPmz = 3,
+    HSssChi = 4,
+    SsZ = 5,
+    SsXy = 6
+}
+
+impl TermKind {
+    pub fn from_raw(kind: u32) -> Option<TermKind> {
+        match kind {
+            0 => Some(TermKind::HSsZ),
+            1 => Some(TermKind::HSsXy),
+            2 => Some(TermKind::HSsPpmm),
+            3 => Some(TermKind::HSsPmz),
+            4 => Some(TermKind::HSssChi),
+            5 => Some(TermKind::SsZ),
+            6 => Some(TermKind::SsXy),
+            _ => None
+        }
+    }
+
+    /// Whether the operator commutes with total Sz
+    pub fn conserves_sz(self) -> bool {
+        !matches!(self, TermKind::HSsPpmm | TermKind::HSsPmz)
+    }
+
+    /// Whether the elements of the operator are real in a basis with real
+    /// phases (see blochfunc::real_momentum). The other terms carry the
+    /// complex phases of the bonds or a factor of i.
+    pub fn is_real(self) -> bool {
+        matches!(self,
+                 TermKind::HSsZ | TermKind::HSsXy | TermKind::SsZ | TermKind::SsXy)
+    }
+
+    /// The name of the builder generating the operator
+    pub fn name(self) -> &'static str {
+        match self {
+            TermKind::HSsZ => "h_ss_z",
+            TermKind::HSsXy => "h_ss_xy",
+            TermKind::HSsPpmm => "h_ss_ppmm",
+            TermKind::HSsPmz => "h_ss_pmz",
+            TermKind::HSssChi => "h_sss_chi",
+            TermKind::SsZ => "ss_z",
+            TermKind::SsXy => "ss_xy"
+        }
+    }
+}
+
+// c compatible description of a term in a Hamiltonian. "l" is ignored by the
+// chiral term
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CTerm {
+    pub kind:  u32,
+    pub l:     u32,
+    pub coeff: f64
+}
+
+/// Diagnostics of a state in a reduced basis. All of them refer to the
+/// normalized state.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StateDiagnostics {
+    /// Σ_i |psi_i|^4 over the reduced basis
+    pub ipr_reduced: f64,
+    /// Σ_d |<d|psi>|^4 over the product states d
+    pub ipr_product: f64,
+    /// Σ_d |<d|psi>|^2 over the product states d supplied by the caller
+    pub weight:      f64
+}
+
+/// Bonds as parallel arrays of lattice indices: bond i joins site1[i] and
+/// site2[i]. direction[i] is the direction from site1[i] to site2[i] through
+/// the nearest image: 1, 2 or 3 along a1, a2 or a3, -1, -2 or -3 against it
+/// and 0 along none of them. (x1[i], y1[i]) is the position of site1[i] in
+/// the cell and (x2[i], y2[i]) that of the nearest image of site2[i], which
+/// lies outside the cell for a bond across the boundary. wraps_x[i] and
+/// wraps_y[i] are 1 for a bond across the boundary along x or y, 0 otherwise.
+#[repr(C)]
+pub struct BondList {
+    pub site1:     Vector<u32>,
+    pub site2:     Vector<u32>,
+    pub direction: Vector<i32>,
+    pub x1:        Vector<f64>,
+    pub y1:        Vector<f64>,
+    pub x2:        Vector<f64>,
+    pub y2:        Vector<f64>,
+    pub wraps_x:   Vector<u8>,
+    pub wraps_y:   Vector<u8>
+}
+
+/// Triangles as parallel arrays of lattice indices, the corners in the order of
+/// triangular_plaquettes. "inverted" is 0 for upright and 1 for inverted
+/// triangles (see Orientation). (anchor_x[i], anchor_y[i]) is the position of
+/// site1[i], the site triangle i starts at, in the cell.
+#[repr(C)]
+pub struct TriangleList {
+    pub site1:    Vector<u32>,
+    pub site2:    Vector<u32>,
+    pub site3:    Vector<u32>,
+    pub inverted: Vector<u32>,
+    pub anchor_x: Vector<f64>,
+    pub anchor_y: Vector<f64>
+}
+
+/// The shells of neighbors of a site (see lattice_shells) as parallel arrays:
+/// shell i is the shell of range range[i], at distance[i] in units of the
+/// lattice spacing, and holds sites[i] sites, one of them at the displacement
+/// (dx[i], dy[i]) in units of the lattice vectors along x and y.
+#[repr(C)]
+pub struct ShellList {
+    pub range:    Vector<u32>,
+    pub distance: Vector<f64>,
+    pub sites:    Vector<u32>,
+    pub dx:       Vector<i32>,
+    pub dy:       Vector<i32>
+}
+
+/// Partial sums of the finite-temperature Lanczos method at one temperature
+/// over the sectors sampled so far. Dividing "energy" and "observable" by "z"
+/// gives the thermal averages; the sums of different sectors simply add.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThermalSums {
+    /// Σ e^(-E/T), the partition function
+    pub z:          f64,
+    /// Σ E e^(-E/T)
+    pub energy:     f64,
+    /// Σ <O> e^(-E/T)
+    pub observable: f64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Term {
+    pub kind:  TermKind,
+    pub l:     I,
+    pub coeff: f64
+}
+
+impl Term {
+    /// A term with unit coefficient
+    pub fn new(kind: TermKind, l: I) -> Term { Term { kind, l, coeff: 1. } }
+
+    pub fn from_c(term: CTerm) -> Option<Term> {
+        TermKind::from_raw(term.kind).map(|kind| Term { kind,
+                                                        l: I(term.l as i32),
+                                                        coeff: term.coeff })
+    }
+
+    /// Check "l" for the kind of the term on an nx by ny lattice: a bond range
+    /// for the two-site terms of the Hamiltonian, a separation between sites
+    /// below nx * ny for the correlations, and anything for the chiral term,
+    /// which ignores it. The lattice itself is checked first.
+    pub fn check(&self, nx: Dim, ny: Dim) -> Result<()> {
+        check_lattice(nx, ny)?;
+        let l = self.l.raw_int();
+        match self.kind {
+            TermKind::HSssChi => Ok(()),
+            TermKind::SsZ | TermKind::SsXy => {
+                if l >= 0 && (l as u32) < (nx * ny).raw_int() {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidArgument("l"))
+                }
+            }
+            // a negative l wraps to a range far beyond the last
+            _ => BondRange::from_raw(l as u32).map(|_| ())
+        }
+    }
+}
+
+/// Check that the nx by ny lattice has at most MAX_SITES sites. Every entry
+/// point that takes the dimensions of a lattice calls this (directly or through
+/// check_sector) before computing anything from them.
+pub fn check_lattice(nx: Dim, ny: Dim) -> Result<()> {
+    let sites = u64::from(nx.raw_int()) * u64::from(ny.raw_int());
+    if sites > u64::from(MAX_SITES) {
+        Err(Error::LatticeTooLarge(sites))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check the labels of a sector of the nx by ny lattice: the momenta have to be
+/// below nx and ny, and "nup", if given, at most nx * ny. The error names the
+/// first label out of range. A lattice with too many sites fails before any
+/// label is looked at; see check_lattice.
+pub fn check_sector(nx: Dim, ny: Dim, kx: K, ky: K, nup: Option<u32>) -> Result<()> {
+    check_lattice(nx, ny)?;
+    if kx.raw_int() >= nx.raw_int() {
+        return Err(Error::InvalidArgument("kx"));
+    }
+    if ky.raw_int() >= ny.raw_int() {
+        return Err(Error::InvalidArgument("ky"));
+    }
+    match nup {
+        Some(nup) if nup > (nx * ny).raw_int() => Err(Error::InvalidArgument("nup")),
+        _ => Ok(())
+    }
 }
 
 /// A completely recursive implementation of a lexicographical permutation
@@ -216,7 +779,7 @@ fn permute<T>(elements: &[T]) -> Vec<T>
                     // nothing have been done. "rest" should be sorted from greatest
                     // to smallest at this point.
                     None => {
-                        let iter = rest.to_vec().into_iter().rev();
+                        let iter = rest.iter().cloned().rev();
                         let mut nv: VecDeque<T> = VecDeque::from_iter(iter);
 
                         // find the smallest element that is greater than "first"
@@ -237,7 +800,7 @@ fn permute<T>(elements: &[T]) -> Vec<T>
 
     match aux(elements) {
         Some(v) => v.into_iter().collect(),
-        None => elements.to_vec().into_iter().rev().collect()
+        None => elements.iter().cloned().rev().collect()
     }
 }
 
@@ -246,7 +809,7 @@ pub fn vec_to_binarybasis(v: &[bool]) -> BinaryBasis {
     v.iter().rev()
      .enumerate()
      .fold(BinaryBasis(0),
-           |acc, (i, &x)| if x { POW2[i as usize] + acc } else { acc })
+           |acc, (i, &x)| if x { site_mask(i as u32) + acc } else { acc })
 }
 
 pub fn fac(n: BigUint) -> BigUint {
@@ -263,22 +826,26 @@ pub fn choose(n: Dim, c: u32) -> u64 {
     let ncr = fac(n.clone()) / (fac(c.clone()) * fac(n.clone() - c.clone()));
     ncr.to_bytes_le().iter()
        .enumerate()
-       .map(|(i, &x)| x as u64 * POW2[i as usize * 8].raw_int())
+       .map(|(i, &x)| {
+                u64::from(x).checked_shl(i as u32 * 8)
+                            .expect("binomial coefficient wider than 64 bits")
+            })
        .sum()
 }
 
 /// generate the set of all Sz basis states
 pub fn sz_basis(n: Dim, nup: u32) -> Vec<BinaryBasis> {
+    // Progress::none() is never cancelled
+    sz_basis_with_progress(n, nup, &Progress::none()).unwrap()
+}
+
+/// same as sz_basis, giving up if the build is cancelled
+pub fn sz_basis_with_progress(n: Dim, nup: u32, progress: &Progress)
+                              -> Result<Vec<BinaryBasis>> {
     // starting binary representation of a state on the lattice
-    let mut spins = Vec::new();
-    for _ in 0..nup as usize {
-        spins.push(true);
-    }
+    let mut spins = vec![true; nup as usize];
 
-    let mut downs = Vec::new();
-    for _ in 0..(n.raw_int() - nup) as usize {
-        downs.push(false)
-    }
+    let mut downs = vec![false; (n.raw_int() - nup) as usize];
 
     spins.append(&mut downs);
 
@@ -289,6 +856,7 @@ pub fn sz_basis(n: Dim, nup: u32) -> Vec<BinaryBasis> {
 
     // find all possible permutations of the representation
     loop {
+        progress.check()?;
         let v = permute(&curr_perm);
         if v == spins {
             break;
@@ -297,367 +865,3253 @@ pub fn sz_basis(n: Dim, nup: u32) -> Vec<BinaryBasis> {
             curr_perm = v;
         }
     }
-    acc
+    Ok(acc)
 }
 
+/// Move every site by one along +x: each nx-bit row of "dec" is rotated left
+/// by one bit, which leaves "dec" unchanged when nx = 1, on the plain torus
+/// (see LatticeSettings).
 pub fn translate_x(dec: BinaryBasis, nx: Dim, ny: Dim) -> BinaryBasis {
-    let n = (0..ny.raw_int()).map(|x| x * nx.raw_int())
-                             .collect::<Vec<u32>>();
-    let s = n.iter()
-             .map(|&x| dec % POW2[(x + nx.raw_int()) as usize] / POW2[x as usize])
-             .map(|x| {
-                      (x * BinaryBasis(2)) % POW2[nx.raw_int() as usize]
-                      + x / POW2[nx.raw_int() as usize - 1]
-                  });
-
-    n.iter().map(|&x| POW2[x as usize])
-     .zip(s)
-     .map(|(a, b)| a * b)  // basically a dot product here
-     .fold(BinaryBasis(0), |acc, x| x + acc) // sum over vector
+    Translations::new(nx, ny).x(dec)
 }
 
+/// Move every site by one row along -y, a rotation of the whole
+/// configuration by nx bits, which leaves "dec" unchanged when ny = 1, on the
+/// plain torus (see LatticeSettings).
 pub fn translate_y(dec: BinaryBasis, nx: Dim, ny: Dim) -> BinaryBasis {
-    let xdim = POW2[nx.raw_int() as usize];
-    let pred_totdim = POW2[nx.raw_int() as usize * (ny.raw_int() - 1) as usize];
-    let tail = dec % xdim;
-    dec / xdim + tail * pred_totdim
-}
-
-pub fn exchange_spin_flips(dec: BinaryBasis, s1: BinaryBasis, s2: BinaryBasis)
-                           -> (bool, bool) {
-    let updown = (dec | s1 == dec) && (dec | s2 != dec);
-    let downup = (dec | s1 != dec) && (dec | s2 == dec);
-    (updown, downup)
+    Translations::new(nx, ny).y(dec)
 }
 
-pub fn repeated_spins(dec: BinaryBasis, s1: BinaryBasis, s2: BinaryBasis)
-                      -> (bool, bool) {
-    let upup = (dec | s1 == dec) && (dec | s2 == dec);
-    let downdown = (dec | s1 != dec) && (dec | s2 != dec);
-    (upup, downdown)
+/// The first site of each of the ny rows of nx sites
+fn first_column_mask<W: StateWord>(nx: u32, ny: u32) -> W {
+    let mut mask = W::ZERO;
+    for j in 0..ny {
+        mask = mask | W::bit(j * nx);
+    }
+    mask
 }
 
-pub fn generate_bonds(nx: Dim, ny: Dim) -> Vec<Vec<Vec<SiteVector>>> {
-    let n = nx * ny;
-    let mut vec = SiteVector::new((I(0), I(0)), nx, ny);
-    let mut bonds_by_range = vec![Vec::new(); 3];
-    for _ in 0..n.raw_int() {
-        let nearest_neighbor = vec.nearest_neighboring_sites(false);
-        let second_neighbor = vec.second_neighboring_sites(false);
-        let third_neighbor = vec.third_neighboring_sites(false);
-        let neighbors = vec![nearest_neighbor, second_neighbor, third_neighbor];
-        for (leap, bonds) in bonds_by_range.iter_mut().enumerate() {
-            for n in neighbors[leap].iter() {
-                let mut bond = vec![vec.clone(), n.clone()];
-                bond.sort();
-                bonds.push(bond);
-            }
+/// "dec" with the bit of each site i moved to bit to[i]
+fn permute_word<W: StateWord>(dec: W, to: &[u32]) -> W {
+    let mut permuted = W::ZERO;
+    for (i, &bit) in to.iter().enumerate() {
+        if dec & W::bit(i as u32) != W::ZERO {
+            permuted = permuted | W::bit(bit);
         }
-        vec = vec.next_site();
     }
-    bonds_by_range
+    permuted
 }
 
-pub fn gamma(nx: Dim, ny: Dim, s1: BinaryBasis, s2: BinaryBasis) -> Complex<f64> {
-    let m = (s1.raw_int() as f64).log2().round() as i32;
-    let n = (s2.raw_int() as f64).log2().round() as i32;
-    let vec1 = SiteVector::from_index(I(m), nx, ny);
-    let vec2 = SiteVector::from_index(I(n), nx, ny);
-    let ang = vec1.angle_with(&vec2);
-
-    Complex::from_polar(&1.0, &ang)
+/// The translations of configurations on an nx by ny lattice, as shifts and
+/// masks computed once per lattice so that neither divides nor allocates. The
+/// words are of type W; Translations and Translations32 are the two widths,
+/// with the configurations passed in and out as BinaryBasis either way. On a
+/// lattice whose sites are not in row-major order (see LatticeSettings) the
+/// bits are put in row-major order before each translation and back after it.
+#[derive(Clone, Debug)]
+pub struct WordTranslations<W> {
+    nx:           u32,
+    ny:           u32,
+    shift:        u32,
+    periodicity:  Periodicity,
+    ordering:     SiteOrdering,
+    // the row-major index of the site at each bit and the bit of the site of
+    // each row-major index, both empty for RowMajor
+    rows:         Vec<u32>,
+    bits:         Vec<u32>,
+    row_mask:     W,
+    lattice_mask: W,
+    first_column: W
 }
 
-/// Generate all possible pairs of interacting sites on the lattice according to
-/// the stride l
-pub fn interacting_sites(nx: Dim, ny: Dim, l: I)
-                         -> (Vec<BinaryBasis>, Vec<BinaryBasis>) {
-    let mut site1 = Vec::new();
-    let mut site2 = Vec::new();
-    let bonds_by_range = generate_bonds(nx, ny);
-    let bonds = &bonds_by_range[l.raw_int() as usize - 1];
-    for bond in bonds.iter() {
-        site1.push(bond[0].lattice_index());
-        site2.push(bond[1].lattice_index());
+/// Translations on 64-bit words, for any lattice
+pub type Translations = WordTranslations<u64>;
+/// Translations on 32-bit words, for lattices of up to 32 sites
+pub type Translations32 = WordTranslations<u32>;
+
+impl<W: StateWord> WordTranslations<W> {
+    /// The translations of the plain nx by ny torus, see LatticeSettings
+    pub fn new(nx: Dim, ny: Dim) -> WordTranslations<W> {
+        WordTranslations::bounded(nx, ny, 0, Periodicity::TORUS)
     }
 
-    let f = |s: Vec<I>| {
-        s.into_iter().map(|s| POW2[s.raw_int() as usize])
-         .collect::<Vec<BinaryBasis>>()
-    };
+    /// The translations of the nx by ny lattice whose rows are shifted by
+    /// "shift" sites along x across the boundary in y, see LatticeSettings
+    pub fn shifted(nx: Dim, ny: Dim, shift: u32) -> WordTranslations<W> {
+        WordTranslations::bounded(nx, ny, shift, Periodicity::TORUS)
+    }
 
-    (f(site1), f(site2))
-}
+    /// The translations of the nx by ny lattice with the given shift and
+    /// periodicity. A translation along an axis that is not periodic is no
+    /// symmetry of the lattice and is taken as the identity, so that along
+    /// that axis only momentum 0 has states (see LatticeSettings).
+    pub fn bounded(nx: Dim, ny: Dim, shift: u32, periodicity: Periodicity)
+                   -> WordTranslations<W> {
+        WordTranslations::ordered(nx, ny, shift, periodicity, SiteOrdering::RowMajor)
+    }
 
-pub fn triangular_vert_sites(
-    nx: Dim, ny: Dim)
-    -> (Vec<BinaryBasis>, Vec<BinaryBasis>, Vec<BinaryBasis>) {
-    let mut site1 = Vec::new();
-    let mut site2 = Vec::new();
-    let mut site3 = Vec::new();
-    let mut vec = SiteVector::new((I(0), I(0)), nx, ny);
-    let i = I(1);
-    let n = nx * ny;
+    /// The translations of the nx by ny lattice with the given shift and
+    /// periodicity whose sites are in the bits "ordering" puts them in, which
+    /// has to fit the lattice (see LatticeSettings)
+    pub fn ordered(nx: Dim, ny: Dim, shift: u32, periodicity: Periodicity,
+                   ordering: SiteOrdering)
+                   -> WordTranslations<W> {
+        assert!(ordering.fits(nx, ny), "site ordering of another lattice");
+        let (bits, rows) = if ordering.is_row_major() {
+            (Vec::new(), Vec::new())
+        } else {
+            let bits = ordering.positions(nx, ny);
+            let mut rows = vec![0; bits.len()];
+            for (i, &bit) in bits.iter().enumerate() {
+                rows[bit as usize] = i as u32;
+            }
+            (bits, rows)
+        };
+        let nx = nx.raw_int();
+        let ny = ny.raw_int();
+        assert!(nx * ny <= W::BITS, "lattice wider than the words");
+        WordTranslations { nx,
+                           ny,
+                           shift: shift % nx,
+                           periodicity,
+                           ordering,
+                           rows,
+                           bits,
+                           row_mask: W::low_bits(nx),
+                           lattice_mask: W::low_bits(nx * ny),
+                           first_column: first_column_mask(nx, ny) }
+    }
 
-    for _ in 0..n.raw_int() {
-        // For ijk in clockwise direction in upright triangle
-        let s1 = vec.lattice_index();
-        let s2 = vec.xhop(i).lattice_index();
-        let s3 = vec.yhop(i).lattice_index();
-        site1.push(s1);
-        site2.push(s2);
-        site3.push(s3);
+    pub fn nx(&self) -> Dim { Dim(self.nx) }
+
+    pub fn ny(&self) -> Dim { Dim(self.ny) }
+
+    pub fn shift(&self) -> u32 { self.shift }
 
-        // For ijk in clockwise direction in inverted triangle
-        let s4 = vec.lattice_index();
-        let s5 = vec.xhop(i).lattice_index();
-        let s6 = vec.xhop(i).yhop(-i).lattice_index();
-        site1.push(s4);
-        site2.push(s5);
-        site3.push(s6);
+    pub fn periodicity(&self) -> Periodicity { self.periodicity }
 
-        vec = vec.next_site();
+    pub fn ordering(&self) -> SiteOrdering { self.ordering }
+
+    /// Move every site by one along +x, row by row
+    pub fn x(&self, dec: BinaryBasis) -> BinaryBasis {
+        self.x_word(W::from_basis(dec)).to_basis()
     }
 
-    let f = |s: Vec<I>| {
-        s.into_iter().map(|s| POW2[s.raw_int() as usize])
-         .collect::<Vec<BinaryBasis>>()
-    };
+    /// Move every site by one row along -y, a rotation of the whole
+    /// configuration by nx bits, the row that wraps around moved by the shift
+    /// along -x
+    pub fn y(&self, dec: BinaryBasis) -> BinaryBasis {
+        self.y_word(W::from_basis(dec)).to_basis()
+    }
 
-    (f(site1), f(site2), f(site3))
-}
+    /// The leading state of the translation orbit of "dec", i.e. its smallest
+    /// configuration, and the numbers of translations along x and along y that
+    /// take "dec" there
+    pub fn leading(&self, dec: BinaryBasis) -> (BinaryBasis, u32, u32) {
+        let (lead, i, j) = self.leading_word(W::from_basis(dec));
+        (lead.to_basis(), i, j)
+    }
 
-/// Generate all permutations of the combination of any two sites on the lattice
-/// where l = |i - j| for sites i and j
-pub fn all_sites(nx: Dim, ny: Dim, l: I) -> (Vec<BinaryBasis>, Vec<BinaryBasis>) {
-    let mut vec = SiteVector::new((I(0), I(0)), nx, ny);
-    let xstride = l % nx;
-    let ystride = l / nx;
-    let mut site1 = Vec::new();
-    let mut site2 = Vec::new();
-    for _ in 0..ny.raw_int() {
-        for _ in 0..nx.raw_int() {
-            let s1 = vec.lattice_index();
-            let s2 = vec.xhop(xstride).yhop(ystride).lattice_index();
-            site1.push(s1);
-            site2.push(s2);
-            vec = vec.xhop(I(1));
+    /// Same as x on a word
+    pub fn x_word(&self, dec: W) -> W {
+        if self.rows.is_empty() {
+            return self.x_rows(dec);
         }
-        vec = vec.yhop(I(1));
+        permute_word(self.x_rows(permute_word(dec, &self.rows)), &self.bits)
     }
 
-    let f = |s: Vec<I>| {
-        s.into_iter().map(|s| POW2[s.raw_int() as usize])
-         .collect::<Vec<BinaryBasis>>()
-    };
-
-    (f(site1), f(site2))
-}
+    /// Same as y on a word
+    pub fn y_word(&self, dec: W) -> W {
+        if self.rows.is_empty() {
+            return self.y_rows(dec);
+        }
+        permute_word(self.y_rows(permute_word(dec, &self.rows)), &self.bits)
+    }
 
-pub fn find_leading_state<'a>(dec: BinaryBasis,
-                              hashtable: &'a FnvHashMap<&BinaryBasis, &BlochFunc>)
-                              -> Option<(&'a BlochFunc, Complex<f64>)> {
-    match hashtable.get(&dec) {
-        None => None,
-        Some(&cntd_state) => match cntd_state.decs.get(&dec) {
-            None => None,
-            Some(&p) => {
-                let mut phase = p.conj();
-                phase /= phase.norm();
-                Some((cntd_state, phase))
-            }
+    // x_word on a word whose sites are in row-major order
+    fn x_rows(&self, dec: W) -> W {
+        let dec = dec & self.lattice_mask;
+        if !self.periodicity.x {
+            return dec;
         }
+        let shifted = (dec << 1) & !self.first_column & self.lattice_mask;
+        shifted | ((dec >> (self.nx - 1)) & self.first_column)
     }
-}
 
-pub fn gen_ind_dec_conv_dicts<'a>(
-    bfuncs: &'a BlochFuncSet)
-    -> (FnvHashMap<u32, &'a BlochFunc>, FnvHashMap<BinaryBasis, u32>) {
-    let dec = bfuncs.iter().map(|x| x.lead).collect::<Vec<_>>();
-    let nstates = dec.len();
-    let inds = (0..nstates as u32).collect::<Vec<u32>>();
+    // y_word on a word whose sites are in row-major order
+    fn y_rows(&self, dec: W) -> W {
+        if !self.periodicity.y {
+            return dec & self.lattice_mask;
+        }
+        let mut tail = dec & self.row_mask;
+        if self.shift != 0 {
+            tail = ((tail >> self.shift) | (tail << (self.nx - self.shift)))
+                   & self.row_mask;
+        }
+        // shifted in two steps, since a single row of 32 sites on u32 words
+        // would be shifted by the full width
+        (dec >> (self.nx - 1) >> 1) | (tail << (self.nx * (self.ny - 1)))
+    }
 
-    // build the hashtables
-    let dec_to_ind = dec.into_iter()
-                        .zip(inds.clone())
-                        .collect::<FnvHashMap<BinaryBasis, u32>>();
-    let ind_to_dec = inds.into_iter()
-                         .zip(bfuncs.iter())
-                         .collect::<FnvHashMap<u32, &BlochFunc>>();
+    /// Same as leading on a word
+    pub fn leading_word(&self, dec: W) -> (W, u32, u32) {
+        let mut leading = (dec, 0, 0);
+        let mut row = dec;
+        for j in 0..self.ny {
+            let mut new_dec = row;
+            for i in 0..self.nx {
+                if new_dec < leading.0 {
+                    leading = (new_dec, i, j);
+                }
+                new_dec = self.x_word(new_dec);
+            }
+            row = self.y_word(row);
+        }
+        leading
+    }
 
-    (ind_to_dec, dec_to_ind)
+    /// Whether "dec" is the smallest configuration of its translation orbit
+    pub fn is_leading_word(&self, dec: W) -> bool {
+        let mut row = dec;
+        for _ in 0..self.ny {
+            let mut new_dec = row;
+            for _ in 0..self.nx {
+                if new_dec < dec {
+                    return false;
+                }
+                new_dec = self.x_word(new_dec);
+            }
+            row = self.y_word(row);
+        }
+        true
+    }
 }
 
-pub fn coeff(orig_state: &BlochFunc, cntd_state: &BlochFunc) -> f64 {
-    cntd_state.norm / orig_state.norm
+/// The translations of a lattice on words of the width chosen for it
+#[derive(Clone, Debug)]
+pub enum SizedTranslations {
+    U32(Translations32),
+    U64(Translations)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl SizedTranslations {
+    /// The translations of the nx by ny lattice with the given shift,
+    /// periodicity and ordering (see WordTranslations::ordered) on words of the
+    /// given width
+    pub fn new(nx: Dim, ny: Dim, shift: u32, periodicity: Periodicity,
+               ordering: SiteOrdering, width: Width)
+               -> SizedTranslations {
+        match width {
+            Width::U32 => {
+                let trans =
+                    Translations32::ordered(nx, ny, shift, periodicity, ordering);
+                SizedTranslations::U32(trans)
+            }
+            Width::U64 => {
+                let trans =
+                    Translations::ordered(nx, ny, shift, periodicity, ordering);
+                SizedTranslations::U64(trans)
+            }
+        }
+    }
 
-    #[test]
-    fn permute_test1() {
-        let l = vec![false, false, false, true, true, true, true];
-        let ans = vec![false, false, true, false, true, true, true];
-        assert_eq!(permute(&l), ans);
+    pub fn nx(&self) -> Dim {
+        match *self {
+            SizedTranslations::U32(ref trans) => trans.nx(),
+            SizedTranslations::U64(ref trans) => trans.nx()
+        }
     }
 
-    #[test]
-    fn permute_test2() {
-        let l = vec![true, true, false, false, true, true, true, false, false];
-        let ans = vec![true, true, false, true, false, false, false, true, true];
-        assert_eq!(permute(&l), ans);
+    pub fn ny(&self) -> Dim {
+        match *self {
+            SizedTranslations::U32(ref trans) => trans.ny(),
+            SizedTranslations::U64(ref trans) => trans.ny()
+        }
     }
 
-    #[test]
-    fn permute_test3() {
-        let l = vec![true, true, true, false, false];
-        let ans = vec![false, false, true, true, true];
-        assert_eq!(permute(&l), ans);
+    /// See WordTranslations::leading
+    pub fn leading(&self, dec: BinaryBasis) -> (BinaryBasis, u32, u32) {
+        match *self {
+            SizedTranslations::U32(ref trans) => trans.leading(dec),
+            SizedTranslations::U64(ref trans) => trans.leading(dec)
+        }
     }
+}
 
-    #[test]
-    fn compose_test1() {
+/// A hashmap from configurations to indices, keyed by words of the width chosen
+/// for the lattice
+#[derive(Clone, Debug)]
+pub enum StateMap {
+    U32(FnvHashMap<u32, u32>),
+    U64(FnvHashMap<u64, u32>)
+}
+
+impl StateMap {
+    pub fn new(width: Width) -> StateMap {
+        match width {
+            Width::U32 => StateMap::U32(FnvHashMap::default()),
+            Width::U64 => StateMap::U64(FnvHashMap::default())
+        }
+    }
+
+    /// Map "dec" to "i". Panics if "dec" does not fit the width of the map,
+    /// which means the map is narrower than the lattice and could not find
+    /// every configuration.
+    pub fn insert(&mut self, dec: BinaryBasis, i: u32) {
+        if let StateMap::U32(_) = *self {
+            assert!(dec.raw_int() >> 32 == 0, "configuration wider than the map");
+        }
+        match *self {
+            StateMap::U32(ref mut map) => map.insert(u32::from_basis(dec), i),
+            StateMap::U64(ref mut map) => map.insert(dec.raw_int(), i)
+        };
+    }
+
+    /// The index of "dec", None if it is not in the map
+    pub fn get(&self, dec: BinaryBasis) -> Option<u32> {
+        match *self {
+            // a configuration that does not fit is not on the lattice
+            StateMap::U32(ref map) if dec.raw_int() >> 32 == 0 => {
+                map.get(&(dec.raw_int() as u32)).cloned()
+            }
+            StateMap::U32(_) => None,
+            StateMap::U64(ref map) => map.get(&dec.raw_int()).cloned()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            StateMap::U32(ref map) => map.len(),
+            StateMap::U64(ref map) => map.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+/// Whether the spins of the bond (s1, s2) in "dec" are up and down, and down
+/// and up. s1 and s2 are the masks of two different sites (see is_site_pair):
+/// for a single site neither would hold and the bond would silently lose its
+/// weight, so the lists of bonds are checked when a term is prepared (see
+/// check_bond_sites).
+pub fn exchange_spin_flips(dec: BinaryBasis, s1: BinaryBasis, s2: BinaryBasis)
+                           -> (bool, bool) {
+    debug_assert!(is_site_pair(s1, s2),
+                  "{:?} and {:?} are no pair of sites",
+                  s1,
+                  s2);
+    let updown = (dec | s1 == dec) && (dec | s2 != dec);
+    let downup = (dec | s1 != dec) && (dec | s2 == dec);
+    (updown, downup)
+}
+
+/// Whether the spins of the bond (s1, s2) in "dec" are both up, and both down.
+/// s1 and s2 are the masks of two different sites as for exchange_spin_flips:
+/// a single site would always count as a parallel pair.
+pub fn repeated_spins(dec: BinaryBasis, s1: BinaryBasis, s2: BinaryBasis)
+                      -> (bool, bool) {
+    debug_assert!(is_site_pair(s1, s2),
+                  "{:?} and {:?} are no pair of sites",
+                  s1,
+                  s2);
+    let upup = (dec | s1 == dec) && (dec | s2 == dec);
+    let downdown = (dec | s1 != dec) && (dec | s2 != dec);
+    (upup, downdown)
+}
+
+/// A list of bonds packed into masks, so that the number of bonds whose spins
+/// are antiparallel in a configuration takes a few popcounts rather than two
+/// bit tests per bond. The bonds are grouped by the distance d between their
+/// sites on the binary representation: with the first sites of a group
+/// marked in "mask", the second sites of the bonds line up with them in the
+/// configuration shifted by d, and the antiparallel bonds are the set bits of
+/// (dec ^ shifted dec) & mask. A lattice has only a few such distances per
+/// range, counting the bonds that wrap around the boundaries.
+#[derive(Clone, Debug)]
+pub struct BondMasks {
+    nbonds: u32,
+    // the shift that takes the second site of every bond of the group onto its
+    // first site, positive to the right, and the first sites of the group
+    masks:  Vec<(i32, u64)>
+}
+
+impl BondMasks {
+    pub fn new(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>)) -> BondMasks {
+        let (ref site1, ref site2) = *sites;
+        let mut masks: Vec<(i32, u64)> = Vec::new();
+        for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+            let (s1, s2) = (s1.raw_int(), s2.raw_int());
+            let shift = s2.trailing_zeros() as i32 - s1.trailing_zeros() as i32;
+            // a bond listed twice goes into a second group of the same shift
+            let group = masks.iter()
+                             .position(|&(d, mask)| d == shift && mask & s1 == 0);
+            match group {
+                Some(n) => masks[n].1 |= s1,
+                None => masks.push((shift, s1))
+            }
+        }
+        BondMasks { nbonds: site1.len() as u32,
+                    masks }
+    }
+
+    /// The number of bonds
+    pub fn nbonds(&self) -> u32 { self.nbonds }
+
+    /// The number of bonds whose spins are antiparallel in "dec"
+    pub fn antiparallel(&self, dec: BinaryBasis) -> u32 {
+        let dec = dec.raw_int();
+        self.masks
+            .iter()
+            .map(|&(d, mask)| {
+                let shifted = if d >= 0 { dec >> d } else { dec << -d };
+                ((dec ^ shifted) & mask).count_ones()
+            })
+            .sum()
+    }
+}
+
+/// The longest range of bonds there are generators for
+pub const MAX_BOND_RANGE: i32 = 3;
+
+/// The range of the bonds a two-site term of the Hamiltonian acts on. The
+/// discriminants are the "l" external callers pass in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum BondRange {
+    NearestNeighbor = 1,
+    Second = 2,
+    Third = 3
+}
+
+impl BondRange {
+    /// The range numbered "l". Fails unless l is 1, 2 or 3.
+    pub fn from_raw(l: u32) -> Result<BondRange> {
+        match l {
+            1 => Ok(BondRange::NearestNeighbor),
+            2 => Ok(BondRange::Second),
+            3 => Ok(BondRange::Third),
+            _ => Err(Error::InvalidArgument("l"))
+        }
+    }
+
+    /// The number of the range, as Term and LatticeTables take it
+    pub fn l(self) -> I { I(self as i32) }
+}
+
+/// The bonds of range l, the pairs of sites in shell l of each other (see
+/// SiteVector::neighbors_in_shell), each with its two sites in order, grouped
+/// by the site they start from in the order of the site indices. Any range
+/// from 1 up is accepted; there are no bonds of range 0 or of ranges beyond
+/// the lattice.
+///
+/// On a lattice too narrow for the range, two sites can be l-th neighbors
+/// through more than one periodic image: across a lattice two sites wide the
+/// neighbors along +x and -x are the same site, and so are the three second
+/// neighbors along b1, b2 and b3 on a lattice three sites wide. The coupling is
+/// between the two spins rather than between their images, so such a pair is
+/// bonded once, as in most exact diagonalization codes, and only its first
+/// occurrence is kept. For the same reason a pair is bonded at the range of its
+/// nearest images only: on a lattice a single row high the second neighbors
+/// along b1 and b3 are nearest neighbors, and on one three sites wide the third
+/// neighbors are.
+///
+/// Along an axis that "settings" leave open no bond crosses the boundary: the
+/// bonds that would are dropped, and the ranges are those of the images on
+/// this side of the open ends.
+pub fn generate_range_bonds(nx: Dim, ny: Dim, l: I, settings: &LatticeSettings)
+                            -> Vec<Vec<SiteVector>> {
+    let shell = cmp::max(l.raw_int(), 0) as u32;
+    let mut bonds = Vec::new();
+    let mut pairs = HashSet::new();
+    for vec in SiteVector::sites_in(nx, ny, settings) {
+        for partner in vec.neighbors_in_shell(shell, true, settings.periodicity) {
+            let mut bond = vec![vec.clone(), partner];
+            bond.sort();
+            let pair = (bond[0].lattice_index(), bond[1].lattice_index());
+            if pairs.insert(pair) {
+                bonds.push(bond);
+            }
+        }
+    }
+    bonds
+}
+
+/// The bonds of every range from 1 to MAX_BOND_RANGE, those of range l at
+/// l - 1
+pub fn generate_bonds(nx: Dim, ny: Dim, settings: &LatticeSettings)
+                      -> Vec<Vec<Vec<SiteVector>>> {
+    (1..=MAX_BOND_RANGE).map(|l| generate_range_bonds(nx, ny, I(l), settings))
+                        .collect()
+}
+
+/// The mask of the site with index "index". Fails with LatticeTooLarge, rather
+/// than wrapping around, if the site does not fit in a BinaryBasis, which is a
+/// bug in a debug build: every entry point checks the lattice first (see
+/// check_lattice). All masks of single sites are built here or in site_mask.
+pub fn checked_site_mask(index: u32) -> Result<BinaryBasis> {
+    let mask = 1_u64.checked_shl(index).map(BinaryBasis);
+    debug_assert!(mask.is_some(), "site {} beyond the words", index);
+    mask.ok_or_else(|| Error::LatticeTooLarge(u64::from(index) + 1))
+}
+
+/// The mask of the site with index "index", for callers that cannot fail.
+/// Panics if the site does not fit in a BinaryBasis (see checked_site_mask).
+pub fn site_mask(index: u32) -> BinaryBasis {
+    checked_site_mask(index).expect("site beyond the words")
+}
+
+/// The index of the site whose bit is set in "s"
+pub fn site_index(s: BinaryBasis) -> usize {
+    debug_assert!(s.raw_int().is_power_of_two(),
+                  "{:?} is not the mask of a single site",
+                  s);
+    s.raw_int().trailing_zeros() as usize
+}
+
+/// The sites of the nx by ny lattice with "settings" by index
+pub fn site_vectors(nx: Dim, ny: Dim, settings: &LatticeSettings)
+                    -> Vec<SiteVector> {
+    SiteVector::sites_in(nx, ny, settings).collect()
+}
+
+/// The phase γ of the bond between the sites "s1" and "s2", where "sites" are
+/// the sites of the lattice as site_vectors lists them. The direction of the
+/// bond is that of the shortest displacement between the sites on the torus,
+/// which for a bond across the boundary goes through a periodic image. Fails
+/// with InvalidArgument if either mask is not that of a single site of the
+/// lattice.
+pub fn gamma(sites: &[SiteVector], s1: BinaryBasis, s2: BinaryBasis)
+             -> Result<Complex<f64>> {
+    gamma_in(sites, s1, s2, &LatticeGeometry::default())
+}
+
+/// The phase γ of the bond between the sites "s1" and "s2" on a lattice of the
+/// given geometry, whose nearest images can differ from those of the
+/// triangular lattice (see gamma)
+pub fn gamma_in(sites: &[SiteVector], s1: BinaryBasis, s2: BinaryBasis,
+                geometry: &LatticeGeometry)
+                -> Result<Complex<f64>> {
+    gamma_within(sites, s1, s2, geometry, Periodicity::TORUS)
+}
+
+/// The phase γ of the bond between the sites "s1" and "s2" on a lattice of the
+/// given geometry and periodicity, the direction of the bond being that of
+/// the nearest image on this side of the open ends (see gamma)
+pub fn gamma_within(sites: &[SiteVector], s1: BinaryBasis, s2: BinaryBasis,
+                    geometry: &LatticeGeometry, periodicity: Periodicity)
+                    -> Result<Complex<f64>> {
+    let (s1, s2) = (mask_site(sites, s1)?, mask_site(sites, s2)?);
+    let ang = s1.displacement_from_within(s2, geometry, periodicity).angle();
+
+    Ok(Complex::from_polar(&1.0, &ang))
+}
+
+/// The displacement of the second site of each bond from its first through
+/// their nearest image on a lattice of the given geometry and periodicity (see
+/// SiteVector::displacement_from_within), whose wrap counts tell the bonds
+/// across the boundary. Fails as gamma does on a mask that is not that of a
+/// site.
+pub fn bond_displacements_in(sites: &[SiteVector], bonds: &BondSites,
+                             geometry: &LatticeGeometry, periodicity: Periodicity)
+                             -> Result<Vec<Displacement>> {
+    let (ref site1, ref site2) = *bonds;
+    site1.iter()
+         .zip(site2.iter())
+         .map(|(&s1, &s2)| {
+                  let (s1, s2) = (mask_site(sites, s1)?, mask_site(sites, s2)?);
+                  Ok(s2.displacement_from_within(s1, geometry, periodicity))
+              })
+         .collect()
+}
+
+// the site of "sites" whose mask is "s"
+fn mask_site(sites: &[SiteVector], s: BinaryBasis) -> Result<&SiteVector> {
+    if !s.raw_int().is_power_of_two() {
+        return Err(Error::InvalidArgument("site mask"));
+    }
+    sites.get(site_index(s)).ok_or(Error::InvalidArgument("site mask"))
+}
+
+/// Generate all possible pairs of interacting sites on the plain torus (see
+/// LatticeSettings) according to the stride l
+pub fn interacting_sites(nx: Dim, ny: Dim, l: I)
+                         -> (Vec<BinaryBasis>, Vec<BinaryBasis>) {
+    let settings = LatticeSettings::default();
+    bond_sites(&generate_range_bonds(nx, ny, l, &settings))
+}
+
+/// A bond with its direction from its first site to its second (see
+/// SiteVector::bond_direction), None for a bond along none of a1, a2 and a3
+pub type TaggedBond = (Vec<SiteVector>, Option<(BondDir, i32)>);
+
+/// The bonds of range l as interacting_sites lists them, each tagged with its
+/// direction
+pub fn generate_tagged_range_bonds(nx: Dim, ny: Dim, l: I) -> Vec<TaggedBond> {
+    let tag = |bond: Vec<SiteVector>| {
+        let dir = bond[0].bond_direction(&bond[1]).ok();
+        (bond, dir)
+    };
+    let bonds = generate_range_bonds(nx, ny, l, &LatticeSettings::default());
+    bonds.into_iter().map(tag).collect()
+}
+
+/// The pairs of sites of interacting_sites together with the direction of
+/// each bond from its site in the first list to that in the second, coded as
+/// BondDir::code does and 0 for a bond along none of a1, a2 and a3
+pub fn interacting_sites_with_directions(nx: Dim, ny: Dim, l: I)
+                                         -> (Vec<BinaryBasis>,
+                                             Vec<BinaryBasis>,
+                                             Vec<i32>) {
+    let (bonds, dirs): (Vec<_>, Vec<_>) =
+        generate_tagged_range_bonds(nx, ny, l).into_iter().unzip();
+    let (site1, site2) = bond_sites(&bonds);
+    let code = |dir: &Option<(BondDir, i32)>| {
+        dir.map(|(dir, sign)| dir.code(sign)).unwrap_or(0)
+    };
+    let directions = dirs.iter().map(code).collect();
+    (site1, site2, directions)
+}
+
+/// The distance of shell n from a site of the nx by ny lattice at n - 1, in
+/// units of the lattice spacing, with the number of sites in the shell, up to
+/// the farthest shell on the lattice. The bonds of range l are those between
+/// the sites in shell l of each other; a shell the lattice is too small for
+/// has no sites (see SiteVector::neighbors_in_shell). The shells are those of
+/// the torus with the shift of "settings", see lattice_shells.
+pub fn shell_distances(nx: Dim, ny: Dim, settings: &LatticeSettings)
+                       -> Vec<(f64, usize)> {
+    sitevector::shell_table(nx, ny, settings.shift(nx))
+}
+
+/// The shells of neighbors of a site of the nx by ny lattice that hold any
+/// sites, in increasing order of distance, each with its range, its distance,
+/// its number of sites and the displacement of one of them. The distance is
+/// that of the nearest images, so that a site is in one shell only however
+/// many of its images are as near, and the site itself is in none. Shells the
+/// lattice is too small for are left out rather than listed empty, so the
+/// range of a shell, which the bonds of that range join the sites of (see
+/// generate_range_bonds), is not always its position in the table.
+///
+/// Shells are told apart by the squared lengths of the displacements, which are
+/// integers, so sites at the same distance through different displacements
+/// fall in the same shell without a tolerance. The table is that of the torus
+/// with the shift of "settings" and the default geometry, whatever the
+/// periodicity.
+pub fn lattice_shells(nx: Dim, ny: Dim, settings: &LatticeSettings) -> ShellTable {
+    sitevector::shell_list(nx, ny, settings.shift(nx))
+}
+
+fn bond_sites(bonds: &[Vec<SiteVector>]) -> (Vec<BinaryBasis>, Vec<BinaryBasis>) {
+    let mut site1 = Vec::new();
+    let mut site2 = Vec::new();
+    for bond in bonds.iter() {
+        site1.push(bond[0].lattice_index());
+        site2.push(bond[1].lattice_index());
+    }
+
+    let f = |s: Vec<I>| {
+        s.into_iter().map(|s| site_mask(s.raw_int() as u32))
+         .collect::<Vec<BinaryBasis>>()
+    };
+
+    (f(site1), f(site2))
+}
+
+/// The two sites of each of a list of bonds, as interacting_sites returns them
+pub type BondSites = (Vec<BinaryBasis>, Vec<BinaryBasis>);
+
+/// Whether s1 and s2 are the masks of single sites, and of different ones, as
+/// the two sites of a bond have to be
+pub fn is_site_pair(s1: BinaryBasis, s2: BinaryBasis) -> bool {
+    s1 != s2 && s1.raw_int().is_power_of_two() && s2.raw_int().is_power_of_two()
+}
+
+/// Check that every bond of "sites" is a pair of sites (see is_site_pair) and
+/// that no pair of sites is bonded twice, in either order (see
+/// is_canonical_bond_list). The bonds of the lattice always pass; a list that
+/// does not would have the element functions count a single site as a
+/// parallel pair, drop it, or count a bond twice. Fails with
+/// Error::Inconsistent naming the first bad bond.
+pub fn check_bond_sites(sites: &BondSites) -> Result<()> {
+    let mut pairs = FnvHashMap::default();
+    for (n, (&s1, &s2)) in sites.0.iter().zip(sites.1.iter()).enumerate() {
+        if s1 == s2 {
+            return Err(Error::Inconsistent(format!("bond {} pairs the site mask \
+                                                    {:#x} with itself",
+                                                   n,
+                                                   s1.raw_int())));
+        }
+        if !is_site_pair(s1, s2) {
+            return Err(Error::Inconsistent(format!("bond {} joins the masks {:#x} \
+                                                    and {:#x}, which are not \
+                                                    both single sites",
+                                                   n,
+                                                   s1.raw_int(),
+                                                   s2.raw_int())));
+        }
+        if let Some(m) = pairs.insert((cmp::min(s1, s2), cmp::max(s1, s2)), n) {
+            return Err(Error::Inconsistent(format!("bond {} joins the sites \
+                                                    {:#x} and {:#x} of bond {} \
+                                                    again",
+                                                   n,
+                                                   s1.raw_int(),
+                                                   s2.raw_int(),
+                                                   m)));
+        }
+    }
+    Ok(())
+}
+
+/// Whether "sites" pairs no site with itself and lists every unordered pair of
+/// sites at most once, in either order. The terms that generate both orders of
+/// a bond themselves (see ops::ss_pmz_elements) need such a list, and the bonds
+/// of LatticeTables are always one.
+pub fn is_canonical_bond_list(sites: &BondSites) -> bool {
+    let mut pairs = HashSet::new();
+    sites.0
+         .iter()
+         .zip(sites.1.iter())
+         .all(|(&s1, &s2)| {
+                  s1 != s2 && pairs.insert((cmp::min(s1, s2), cmp::max(s1, s2)))
+              })
+}
+
+/// Which way a triangle of the lattice points: an upright triangle has its
+/// corners at r, r + a1 and r - a3, an inverted one at r, r + a1 and r + a1 + a3
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Orientation {
+    Up,
+    Down
+}
+
+/// A triangle of the lattice: the lattice indices of its corners in the order
+/// the chirality term takes them, which way it points and its first corner, the
+/// site it starts at
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plaquette {
+    pub sites:       [I; 3],
+    pub orientation: Orientation,
+    pub anchor:      SiteVector
+}
+
+/// The upright and the inverted triangle starting at each site, in the order of
+/// the sites, 2 N of them on a torus of N sites at least 2 wide and high. The
+/// corners go from the first one along a1 in both, which in the default
+/// geometry is counterclockwise round an upright triangle and clockwise round
+/// an inverted one. A triangle with two corners on the same site, as all are
+/// on a lattice a single site wide or high, is left out, and so is one across
+/// an end that "settings" leave open. A triangle reached again from
+/// another of its corners, which the boundaries of a small lattice can bring
+/// about, is listed once, at the first of them. On the 2x2 torus each three
+/// sites are the corners of two triangles going round them the opposite ways,
+/// with none of their bonds in common, which are not the same and both kept.
+pub fn triangular_plaquettes(nx: Dim, ny: Dim, settings: &LatticeSettings)
+                             -> Vec<Plaquette> {
+    let periodicity = settings.periodicity;
+    let sites = SiteVector::sites_in(nx, ny, settings);
+    let mut plaquettes = Vec::with_capacity(2 * sites.len());
+    let mut seen = HashSet::new();
+    let i = I(1);
+
+    for vec in sites {
+        let up = [vec.lattice_index(),
+                  vec.xhop(i).lattice_index(),
+                  vec.yhop(i).lattice_index()];
+        let down = [vec.lattice_index(),
+                    vec.xhop(i).lattice_index(),
+                    vec.xhop(i).yhop(-i).lattice_index()];
+        let within = |hops: [(i32, i32); 2]| {
+            hops.iter()
+                .all(|&(dx, dy)| vec.hop_allowed(dx, dy, periodicity))
+        };
+        let up = (up, Orientation::Up, within([(1, 0), (0, 1)]));
+        let down = (down, Orientation::Down, within([(1, 0), (1, -1)]));
+
+        for &(sites, orientation, within) in [up, down].iter() {
+            if !within {
+                continue;
+            }
+            // on a lattice a single site wide or high two corners of each
+            // triangle are the same site
+            if sites[0] == sites[1] || sites[1] == sites[2] || sites[2] == sites[0] {
+                continue;
+            }
+            // the same triangle from another corner has its corners rotated
+            let first = (0..3).min_by_key(|&c| sites[c]).unwrap();
+            let corners = [sites[first],
+                           sites[(first + 1) % 3],
+                           sites[(first + 2) % 3]];
+            if seen.insert(corners) {
+                plaquettes.push(Plaquette { sites,
+                                            orientation,
+                                            anchor: vec.clone() });
+            }
+        }
+    }
+    plaquettes
+}
+
+/// The corners of "plaquettes" as three parallel lists of single-site masks
+pub fn plaquette_sites<'a, T>(
+    plaquettes: T)
+    -> (Vec<BinaryBasis>, Vec<BinaryBasis>, Vec<BinaryBasis>)
+    where T: IntoIterator<Item = &'a Plaquette>
+{
+    let mask = |s: I| site_mask(s.raw_int() as u32);
+    let (mut site1, mut site2, mut site3) = (Vec::new(), Vec::new(), Vec::new());
+    for p in plaquettes {
+        site1.push(mask(p.sites[0]));
+        site2.push(mask(p.sites[1]));
+        site3.push(mask(p.sites[2]));
+    }
+    (site1, site2, site3)
+}
+
+/// The corners of the upright and of the inverted triangle starting at each
+/// site, as triangular_plaquettes lists them on the plain torus (see
+/// LatticeSettings)
+pub fn triangular_vert_sites(
+    nx: Dim, ny: Dim)
+    -> (Vec<BinaryBasis>, Vec<BinaryBasis>, Vec<BinaryBasis>) {
+    plaquette_sites(&triangular_plaquettes(nx, ny, &LatticeSettings::default()))
+}
+
+/// The corners of the triangles of one orientation, in the order of
+/// triangular_vert_sites
+pub fn oriented_vert_sites(
+    nx: Dim, ny: Dim, orientation: Orientation)
+    -> (Vec<BinaryBasis>, Vec<BinaryBasis>, Vec<BinaryBasis>) {
+    let plaquettes = triangular_plaquettes(nx, ny, &LatticeSettings::default());
+    plaquette_sites(plaquettes.iter().filter(|p| p.orientation == orientation))
+}
+
+/// Number of lattices whose tables lattice_tables keeps
+const LATTICE_TABLES_CACHED: usize = 8;
+
+static LATTICE_TABLES: Mutex<Vec<Arc<LatticeTables>>> = Mutex::new(Vec::new());
+
+/// The bonds and triangles of an nx by ny lattice together with the geometric
+/// data of the bonds, which all terms on the lattice share. The bonds of each
+/// range are listed in the order of interacting_sites and the triangles in the
+/// order of triangular_plaquettes, both with the periodicity of the tables.
+#[derive(Debug)]
+pub struct LatticeTables {
+    nx:            Dim,
+    ny:            Dim,
+    shift:         u32,
+    periodicity:   Periodicity,
+    ordering:      SiteOrdering,
+    geometry:      LatticeGeometry,
+    // the sites of the bonds of range l at l - 1
+    bonds:         Vec<(Vec<BinaryBasis>, Vec<BinaryBasis>)>,
+    // the phases γ of the same bonds
+    gammas:        Vec<Vec<Complex<f64>>>,
+    // the displacements of the same bonds, see bond_displacements_in
+    displacements: Vec<Vec<Displacement>>,
+    // the same bonds packed for the diagonal terms
+    masks:         Vec<BondMasks>,
+    plaquettes:    Vec<Plaquette>,
+    // the corners of the same triangles
+    triangles:     (Vec<BinaryBasis>, Vec<BinaryBasis>, Vec<BinaryBasis>),
+    // the edges of the triangles, each listed once although it is shared by
+    // two triangles
+    edges:         Vec<(BinaryBasis, BinaryBasis)>
+}
+
+impl LatticeTables {
+    /// The tables of the nx by ny lattice with "settings". Fails with
+    /// LatticeTooLarge beyond MAX_SITES sites, with Inconsistent if a pair of
+    /// sites would be bonded twice (see is_canonical_bond_list), and as gamma
+    /// does if the phase or the displacement of a bond cannot be worked out.
+    pub fn new(nx: Dim, ny: Dim, settings: &LatticeSettings)
+               -> Result<LatticeTables> {
+        check_lattice(nx, ny)?;
+        let (periodicity, geometry) = (settings.periodicity, settings.geometry);
+        let bonds = generate_bonds(nx, ny, settings).iter()
+                                                    .map(|b| bond_sites(b))
+                                                    .collect::<Vec<_>>();
+        if !bonds.iter().all(is_canonical_bond_list) {
+            return Err(Error::Inconsistent(format!("a pair of sites bonded twice \
+                                                    on the {}x{} lattice",
+                                                   nx.raw_int(),
+                                                   ny.raw_int())));
+        }
+        let sites = site_vectors(nx, ny, settings);
+        let gammas = bonds.iter()
+                          .map(|(site1, site2)| {
+                                   site1.iter()
+                                        .zip(site2.iter())
+                                        .map(|(&s1, &s2)| {
+                                                 gamma_within(&sites,
+                                                              s1,
+                                                              s2,
+                                                              &geometry,
+                                                              periodicity)
+                                             })
+                                        .collect::<Result<Vec<_>>>()
+                               })
+                          .collect::<Result<_>>()?;
+        let displacements =
+            bonds.iter()
+                 .map(|bonds| {
+                          bond_displacements_in(&sites,
+                                                bonds,
+                                                &geometry,
+                                                periodicity)
+                      })
+                 .collect::<Result<_>>()?;
+        let masks = bonds.iter().map(BondMasks::new).collect();
+        let plaquettes = triangular_plaquettes(nx, ny, settings);
+        let triangles = plaquette_sites(&plaquettes);
+        let mut edges = Vec::new();
+        {
+            let (ref site1, ref site2, ref site3) = triangles;
+            let zip3 = site1.iter().zip(site2.iter()).zip(site3.iter());
+            for ((&s1, &s2), &s3) in zip3 {
+                for &(a, b) in [(s2, s3), (s3, s1), (s1, s2)].iter() {
+                    edges.push((cmp::min(a, b), cmp::max(a, b)));
+                }
+            }
+        }
+        edges.sort();
+        edges.dedup();
+        Ok(LatticeTables { nx,
+                           ny,
+                           shift: settings.shift(nx),
+                           periodicity,
+                           ordering: settings.ordering(nx, ny),
+                           geometry,
+                           bonds,
+                           gammas,
+                           displacements,
+                           masks,
+                           plaquettes,
+                           triangles,
+                           edges })
+    }
+
+    pub fn nx(&self) -> Dim { self.nx }
+
+    pub fn ny(&self) -> Dim { self.ny }
+
+    /// The shift of the lattice, see LatticeSettings
+    pub fn shift(&self) -> u32 { self.shift }
+
+    /// The periodicity of the lattice, see LatticeSettings
+    pub fn periodicity(&self) -> Periodicity { self.periodicity }
+
+    /// The settings of the lattice
+    pub fn settings(&self) -> LatticeSettings {
+        LatticeSettings { shift:       self.shift,
+                          periodicity: self.periodicity,
+                          geometry:    self.geometry,
+                          ordering:    self.ordering }
+    }
+
+    /// The ordering of the sites of the lattice, see LatticeSettings
+    pub fn ordering(&self) -> SiteOrdering { self.ordering }
+
+    pub fn geometry(&self) -> &LatticeGeometry { &self.geometry }
+
+    /// The two sites of each bond of range l, as interacting_sites lists them
+    pub fn bonds(&self, l: I) -> &(Vec<BinaryBasis>, Vec<BinaryBasis>) {
+        &self.bonds[l.raw_int() as usize - 1]
+    }
+
+    /// The phase γ of each bond of range l, see gamma
+    pub fn gammas(&self, l: I) -> &[Complex<f64>] {
+        &self.gammas[l.raw_int() as usize - 1]
+    }
+
+    /// The displacement of each bond of range l from its first site to its
+    /// second, see bond_displacements_in
+    pub fn displacements(&self, l: I) -> &[Displacement] {
+        &self.displacements[l.raw_int() as usize - 1]
+    }
+
+    /// The bonds of range l packed into masks
+    pub fn masks(&self, l: I) -> &BondMasks { &self.masks[l.raw_int() as usize - 1] }
+
+    /// The triangles of the lattice, as triangular_plaquettes lists them
+    pub fn plaquettes(&self) -> &[Plaquette] { &self.plaquettes }
+
+    /// The three sites of each triangle, in the order of plaquettes
+    pub fn triangles(&self)
+                     -> &(Vec<BinaryBasis>, Vec<BinaryBasis>, Vec<BinaryBasis>) {
+        &self.triangles
+    }
+
+    /// The edges of the triangles, each once and ordered within and between
+    /// them
+    pub fn edges(&self) -> &[(BinaryBasis, BinaryBasis)] { &self.edges }
+}
+
+/// The boundary and the shape of a lattice beyond its size, which the
+/// builders take with nx and ny. The sites (see SiteVector::new_in), the
+/// tables, the translations and the bases built for a lattice keep its
+/// settings with them, so that the worker threads that build the matrices see
+/// them through those. The default is the plain torus of the triangular
+/// lattice.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct LatticeSettings {
+    /// The sites the rows move by along x across the boundary in y: a site
+    /// that leaves the lattice across the top at (x, ny) comes back at
+    /// (x + shift, 0), the shift being taken modulo nx. The momenta are
+    /// quantized on the shifted lattice, which for a label (kx, ky) takes the
+    /// phase of a translation along y to 2π (ky - shift kx / nx) / ny. 0 gives
+    /// the plain torus.
+    pub shift:       u32,
+    /// The axes the lattice closes on itself along. Along an open axis no bond
+    /// or triangle crosses the boundary (see generate_range_bonds and
+    /// triangular_plaquettes), and the translations along it are no symmetry,
+    /// so that they are left out of the Bloch functions and only momentum 0
+    /// along it has states. A lattice with an open end has no shift.
+    pub periodicity: Periodicity,
+    /// The primitive vectors that place the sites in the plane, which decide
+    /// the nearest images of the sites and so the phases of the bonds and
+    /// the wavevectors of the momenta. The bonds themselves follow the
+    /// connectivity of the triangular lattice whatever the geometry.
+    pub geometry:    LatticeGeometry,
+    /// The order the sites are put in the bits of the configurations in (see
+    /// SiteVector::lattice_index) rather than row by row. The bonds, the
+    /// triangles and the masks of the sites follow the ordering, and the
+    /// translations move the sites as on the lattice in row-major order, so
+    /// that the spectra are the same in every ordering while the sites a range
+    /// of bits covers, as in the region of an entanglement cut, are those the
+    /// ordering puts there. A custom ordering applies to the lattices of as
+    /// many sites as it permutes only.
+    pub ordering:    SiteOrdering
+}
+
+impl LatticeSettings {
+    /// The shift on a lattice nx sites wide, in 0..nx, and 0 on a lattice
+    /// with an open end
+    pub fn shift(&self, nx: Dim) -> u32 {
+        if !self.periodicity.is_torus() {
+            return 0;
+        }
+        self.shift % cmp::max(nx.raw_int(), 1)
+    }
+
+    /// The ordering on an nx by ny lattice, RowMajor if it is a custom one of
+    /// another number of sites
+    pub fn ordering(&self, nx: Dim, ny: Dim) -> SiteOrdering {
+        if self.ordering.fits(nx, ny) {
+            self.ordering
+        } else {
+            SiteOrdering::RowMajor
+        }
+    }
+}
+
+/// The tables of the nx by ny lattice with "settings". The tables of the last
+/// few lattices asked for are kept, so that building several terms on the
+/// same lattice generates the bonds only once. The tables are built without
+/// holding the cache, which only takes them once they are complete, so a
+/// lattice whose tables cannot be built leaves the cache as it was.
+pub fn lattice_tables(nx: Dim, ny: Dim, settings: &LatticeSettings)
+                      -> Result<Arc<LatticeTables>> {
+    let shift = settings.shift(nx);
+    let (periodicity, geometry) = (settings.periodicity, settings.geometry);
+    let ordering = settings.ordering(nx, ny);
+    let cached = |t: &Arc<LatticeTables>| {
+        t.nx == nx && t.ny == ny && t.shift == shift && t.geometry == geometry
+        && t.periodicity == periodicity && t.ordering == ordering
+    };
+    // most recently used last
+    let take = |cache: &mut Vec<Arc<LatticeTables>>| {
+        let pos = cache.iter().position(cached)?;
+        let tables = cache.remove(pos);
+        cache.push(tables.clone());
+        Some(tables)
+    };
+    if let Some(tables) = take(&mut LATTICE_TABLES.lock().unwrap()) {
+        return Ok(tables);
+    }
+    let tables = Arc::new(LatticeTables::new(nx, ny, settings)?);
+    let mut cache = LATTICE_TABLES.lock().unwrap();
+    // the same tables may have been built on another thread meanwhile
+    if let Some(tables) = take(&mut cache) {
+        return Ok(tables);
+    }
+    if cache.len() == LATTICE_TABLES_CACHED {
+        cache.remove(0);
+    }
+    cache.push(tables.clone());
+    Ok(tables)
+}
+
+/// The pairs of sites of a correlation function, see all_sites
+#[derive(Clone, Debug)]
+pub struct SitePairs {
+    pub sites:        (Vec<BinaryBasis>, Vec<BinaryBasis>),
+    /// The number of times each pair stands in the sum over the sites i of the
+    /// pairs (i, i + r): 2 when r = -r on the torus, 1 otherwise
+    pub multiplicity: u32
+}
+
+/// The pairs of sites (i, i + r) with r = (l % nx, l / nx), so that the
+/// correlation of the spins "l" sites apart is the sum over the pairs times
+/// the multiplicity. A pair is listed once even when r is its own inverse on
+/// the torus, as for half the length of the lattice, where the pair (i + r, i)
+/// is the same as (i, i + r). A site is never paired with itself: with r = 0
+/// (l = 0) there are no pairs, and the on-site part S_i · S_i = 3/4 is left to
+/// the caller.
+pub fn all_sites(nx: Dim, ny: Dim, l: I) -> SitePairs {
+    all_sites_in(nx, ny, l, &LatticeSettings::default())
+}
+
+/// Same as all_sites on the lattice with "settings"
+pub fn all_sites_in(nx: Dim, ny: Dim, l: I, settings: &LatticeSettings)
+                    -> SitePairs {
+    let xstride = l % nx;
+    let ystride = l / nx;
+    let mut site1 = Vec::new();
+    let mut site2 = Vec::new();
+    let mut pairs = HashSet::new();
+    for vec in SiteVector::sites_in(nx, ny, settings) {
+        let s1 = vec.lattice_index();
+        let s2 = vec.xhop(xstride).yhop(ystride).lattice_index();
+        let (a, b) = (s1.raw_int(), s2.raw_int());
+        if a != b && pairs.insert((cmp::min(a, b), cmp::max(a, b))) {
+            site1.push(s1);
+            site2.push(s2);
+        }
+    }
+    // (i, i + r) and (j, j + r) are the same pair only if j = i + r and
+    // i = j + r, so either every pair repeats or none does
+    let multiplicity = if site1.is_empty() {
+        1
+    } else {
+        (nx * ny).raw_int() / site1.len() as u32
+    };
+
+    let f = |s: Vec<I>| {
+        s.into_iter().map(|s| site_mask(s.raw_int() as u32))
+         .collect::<Vec<BinaryBasis>>()
+    };
+
+    SitePairs { sites: (f(site1), f(site2)),
+                multiplicity }
+}
+
+/// The smallest magnitude of the component of a Bloch function on a
+/// configuration that the lookups take as a phase unless a build chooses
+/// another (see OrbitTable::min_phase_norm). A configuration of an orbit of L
+/// in a basis carries a unit phase N / L times (see BlochFunc::phase), so only
+/// a corrupted orbit comes below it.
+pub const MIN_PHASE_NORM: f64 = 1e-8;
+
+/// "norm" if it can be a min_phase_norm, i.e. it is finite and not negative.
+/// Fails with InvalidArgument otherwise.
+pub fn check_phase_norm(norm: f64) -> Result<f64> {
+    if !norm.is_finite() || norm < 0. {
+        return Err(Error::InvalidArgument("norm"));
+    }
+    Ok(norm)
+}
+
+/// The phase that takes a configuration whose component in a Bloch function is
+/// "component" back to the leading state: the conjugate of the component over
+/// its magnitude. None if the magnitude is below "min_norm", i.e. the component
+/// vanishes, rather than a NaN that would spread over the elements.
+pub fn leading_phase(component: Complex<f64>, min_norm: f64)
+                     -> Option<Complex<f64>> {
+    let norm = component.norm();
+    if norm < min_norm {
+        None
+    } else {
+        Some(component.conj() / norm)
+    }
+}
+
+/// The index in "bfuncs" of the Bloch function that "hashtable" gives for
+/// "dec", together with the phase that takes the configuration back to the
+/// leading state of the Bloch function. None if "dec" is in no orbit, or if
+/// its component is below "min_norm" (see leading_phase).
+pub fn find_leading_state(dec: BinaryBasis, hashtable: &StateMap,
+                          bfuncs: &[BlochFunc], phases: &[Complex<f64>],
+                          min_norm: f64)
+                          -> Option<(u32, Complex<f64>)> {
+    let i = hashtable.get(dec)?;
+    let component = bfuncs[i as usize].phase(dec, phases)?;
+    Some((i, leading_phase(component, min_norm)?))
+}
+
+/// Converts between the leading states of a basis and their indices. The
+/// leading states are kept in a sorted array, so the index of a state is its
+/// position and is found by binary search. For a basis on disk the array is
+/// the one in the mapped file.
+pub struct BasisIndex {
+    leads: Leads
+}
+
+enum Leads {
+    Owned(Vec<BinaryBasis>),
+    Mapped(Arc<MappedBasis>)
+}
+
+impl BasisIndex {
+    /// The index of "bfuncs", which must be sorted by leading state as every
+    /// BlochFuncSet is
+    pub fn new(bfuncs: &BlochFuncSet) -> BasisIndex {
+        let leads = bfuncs.iter().map(|b| b.lead).collect::<Vec<_>>();
+        debug_assert!(leads.windows(2).all(|w| w[0] < w[1]));
+        BasisIndex { leads: Leads::Owned(leads) }
+    }
+
+    /// The index of a basis on disk
+    pub fn mapped(basis: Arc<MappedBasis>) -> BasisIndex {
+        BasisIndex { leads: Leads::Mapped(basis) }
+    }
+
+    /// The index of the basis state whose leading state is "dec", None if "dec"
+    /// is not a leading state of the basis
+    pub fn index_of(&self, dec: BinaryBasis) -> Option<u32> {
+        match self.leads {
+            Leads::Owned(ref leads) => {
+                leads.binary_search(&dec).ok().map(|i| i as u32)
+            }
+            Leads::Mapped(ref basis) => basis.index_of(dec)
+        }
+    }
+
+    /// The leading state of the basis state with index "i"
+    pub fn dec_of(&self, i: u32) -> BinaryBasis {
+        match self.leads {
+            Leads::Owned(ref leads) => leads[i as usize],
+            Leads::Mapped(ref basis) => BinaryBasis(basis.leads()[i as usize])
+        }
+    }
+}
+
+/// The factor norm_b / norm_a that the element of a term between two
+/// configurations picks up in the orthonormal basis of Bloch functions, from
+/// the original state a to the connected state b. With the norms of
+/// BlochFunc::norm this is the sqrt(N_b / N_a) of the usual derivation, where
+/// N_a = norm_a^2, and sqrt(L_a / L_b) for orbits of L_a and L_b
+/// configurations.
+pub fn coeff(orig_state: &BlochFunc, cntd_state: &BlochFunc) -> f64 {
+    cntd_state.norm / orig_state.norm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sitevector::{Shell, SitePermutation};
+    use error;
+    use k_term_matrix;
+    use ks_term_matrix;
+    use std::ptr::null_mut;
+    use test_support::{bond_from_images, elements, two_wide_bonds};
+
+    fn torus() -> LatticeSettings { LatticeSettings::default() }
+
+    #[test]
+    fn permute_test1() {
+        let l = vec![false, false, false, true, true, true, true];
+        let ans = vec![false, false, true, false, true, true, true];
+        assert_eq!(permute(&l), ans);
+    }
+
+    #[test]
+    fn permute_test2() {
+        let l = vec![true, true, false, false, true, true, true, false, false];
+        let ans = vec![true, true, false, true, false, false, false, true, true];
+        assert_eq!(permute(&l), ans);
+    }
+
+    #[test]
+    fn permute_test3() {
+        let l = vec![true, true, true, false, false];
+        let ans = vec![false, false, true, true, true];
+        assert_eq!(permute(&l), ans);
+    }
+
+    #[test]
+    fn compose_test1() {
         let l = vec![true, true, true, true];
         assert_eq!(vec_to_binarybasis(&l), BinaryBasis(15));
     }
 
     #[test]
-    fn compose_test2() {
-        let l = vec![true, false, true, false, true, false];
-        assert_eq!(vec_to_binarybasis(&l), BinaryBasis(42));
+    fn compose_test2() {
+        let l = vec![true, false, true, false, true, false];
+        assert_eq!(vec_to_binarybasis(&l), BinaryBasis(42));
+    }
+
+    #[test]
+    fn choose_test1() {
+        let n = Dim(6);
+        let nup = 3;
+        assert_eq!(choose(n, nup), 20);
+    }
+
+    #[test]
+    fn choose_test2() {
+        let n = Dim(24);
+        let nup = 12;
+        assert_eq!(choose(n, nup), 2704156);
+    }
+
+    #[test]
+    fn sz_basis_test() {
+        let n = Dim(6);
+        let nup = 3;
+        assert_eq!(sz_basis(n, nup).len(), 20);
+    }
+
+    // the division-based translations translate_x and translate_y replace,
+    // kept as a reference
+    fn translate_x_reference(dec: BinaryBasis, nx: Dim, ny: Dim) -> BinaryBasis {
+        let n = (0..ny.raw_int()).map(|x| x * nx.raw_int())
+                                 .collect::<Vec<u32>>();
+        let s = n.iter()
+                 .map(|&x| {
+                          dec % POW2[(x + nx.raw_int()) as usize] / POW2[x as usize]
+                      })
+                 .map(|x| {
+                          (x * BinaryBasis(2)) % POW2[nx.raw_int() as usize]
+                          + x / POW2[nx.raw_int() as usize - 1]
+                      });
+
+        n.iter().map(|&x| POW2[x as usize])
+         .zip(s)
+         .map(|(a, b)| a * b)
+         .fold(BinaryBasis(0), |acc, x| x + acc)
+    }
+
+    fn translate_y_reference(dec: BinaryBasis, nx: Dim, ny: Dim) -> BinaryBasis {
+        let xdim = POW2[nx.raw_int() as usize];
+        let pred_totdim = POW2[nx.raw_int() as usize * (ny.raw_int() - 1) as usize];
+        let tail = dec % xdim;
+        dec / xdim + tail * pred_totdim
+    }
+
+    // xorshift64*, as in lanczos::start_vector
+    fn random_states(seed: u64) -> impl Iterator<Item = u64> {
+        let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        (0..).map(move |_| {
+                 state ^= state >> 12;
+                 state ^= state << 25;
+                 state ^= state >> 27;
+                 state.wrapping_mul(2685821657736338717)
+             })
+    }
+
+    #[test]
+    fn translate_x_test() {
+        let d1 = BinaryBasis(10);
+        let d2 = BinaryBasis(5);
+        let nx = Dim(4);
+        let ny = Dim(6);
+        assert_eq!(translate_x(d1, nx, ny), d2);
+    }
+
+    #[test]
+    fn translate_y_test() {
+        let d1 = BinaryBasis(2);
+        let d2 = BinaryBasis(8192);
+        let nx = Dim(4);
+        let ny = Dim(4);
+        assert_eq!(translate_y(d1, nx, ny), d2);
+    }
+
+    // every shape of up to 16 sites, down to the single rows and columns and
+    // the strongly rectangular 8x2 and 2x8
+    fn small_lattices() -> Vec<(Dim, Dim)> {
+        (1..17).flat_map(|nx| (1..16 / nx + 1).map(move |ny| (Dim(nx), Dim(ny))))
+               .collect()
+    }
+
+    #[test]
+    fn translate_xy_match_reference() {
+        for &(nx, ny) in small_lattices().iter() {
+            for dec in 0..1 << (nx * ny).raw_int() {
+                let dec = BinaryBasis(dec);
+                assert_eq!(translate_x(dec, nx, ny),
+                           translate_x_reference(dec, nx, ny));
+                assert_eq!(translate_y(dec, nx, ny),
+                           translate_y_reference(dec, nx, ny));
+            }
+        }
+    }
+
+    #[test]
+    fn translate_xy_properties() {
+        for &(nx, ny) in small_lattices().iter() {
+            let narrow = Translations32::new(nx, ny);
+            for dec in 0..1 << (nx * ny).raw_int() {
+                let dec = BinaryBasis(dec);
+                let x = translate_x(dec, nx, ny);
+                let y = translate_y(dec, nx, ny);
+                assert_eq!(x.raw_int().count_ones(), dec.raw_int().count_ones());
+                assert_eq!(y.raw_int().count_ones(), dec.raw_int().count_ones());
+                assert_eq!(translate_y(x, nx, ny), translate_x(y, nx, ny));
+                assert_eq!(narrow.x(dec), x);
+                assert_eq!(narrow.y(dec), y);
+                if nx == Dim(1) {
+                    assert_eq!(x, dec);
+                }
+                if ny == Dim(1) {
+                    assert_eq!(y, dec);
+                }
+
+                let mut full_x = dec;
+                for _ in 0..nx.raw_int() {
+                    full_x = translate_x(full_x, nx, ny);
+                }
+                assert_eq!(full_x, dec);
+                let mut full_y = dec;
+                for _ in 0..ny.raw_int() {
+                    full_y = translate_y(full_y, nx, ny);
+                }
+                assert_eq!(full_y, dec);
+            }
+        }
+    }
+
+    // across the boundary in y the rows come back shifted, so that ny steps
+    // along -y are the shift along -x, as the sites of the lattice have it
+    #[test]
+    fn shifted_translations() {
+        for &(nx, ny) in small_lattices().iter() {
+            for shift in 1..nx.raw_int() {
+                let settings = LatticeSettings { shift,
+                                                 ..torus() };
+                let wide = Translations::shifted(nx, ny, shift);
+                let narrow = Translations32::shifted(nx, ny, shift);
+                assert_eq!(wide.shift(), shift);
+                for dec in 0..1 << (nx * ny).raw_int() {
+                    let dec = BinaryBasis(dec);
+                    let y = wide.y(dec);
+                    assert_eq!(narrow.y(dec), y);
+                    assert_eq!(wide.y(wide.x(dec)), wide.x(y));
+                    let mut full_y = dec;
+                    for _ in 0..ny.raw_int() {
+                        full_y = wide.y(full_y);
+                    }
+                    for _ in 0..shift {
+                        full_y = wide.x(full_y);
+                    }
+                    assert_eq!(full_y, dec);
+                }
+                for site in SiteVector::sites_in(nx, ny, &settings) {
+                    let index = site.lattice_index().raw_int() as u32;
+                    let moved = site.yhop(I(-1)).lattice_index().raw_int();
+                    assert_eq!(wide.y(site_mask(index)), site_mask(moved as u32));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn translate_xy_match_reference_random() {
+        let lattices = [(5, 4), (6, 4), (5, 6), (7, 5), (8, 7), (20, 3), (2, 30),
+                        (30, 2), (1, 40), (40, 1)];
+        for &(nx, ny) in lattices.iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let mask = (1 << (nx * ny).raw_int()) - 1;
+            for dec in random_states(u64::from((nx * ny).raw_int())).take(10000) {
+                let dec = BinaryBasis(dec & mask);
+                assert_eq!(translate_x(dec, nx, ny),
+                           translate_x_reference(dec, nx, ny));
+                assert_eq!(translate_y(dec, nx, ny),
+                           translate_y_reference(dec, nx, ny));
+            }
+        }
+    }
+
+    /// Run with --release --ignored --nocapture to time a full 2^24-state scan
+    #[test]
+    #[ignore]
+    fn translate_xy_speedup() {
+        use std::time::Instant;
+        let (nx, ny) = (Dim(6), Dim(4));
+        let states = 1 << 24;
+
+        let start = Instant::now();
+        let mut acc_reference = BinaryBasis(0);
+        for dec in 0..states {
+            let dec = BinaryBasis(dec);
+            let dec = translate_x_reference(dec, nx, ny);
+            acc_reference |= translate_y_reference(dec, nx, ny);
+        }
+        let reference = start.elapsed();
+
+        let start = Instant::now();
+        let mut acc = BinaryBasis(0);
+        for dec in 0..states {
+            acc |= translate_y(translate_x(BinaryBasis(dec), nx, ny), nx, ny);
+        }
+        let masked = start.elapsed();
+
+        assert_eq!(acc, acc_reference);
+        println!("reference: {:?}, shifts and masks: {:?}", reference, masked);
+        assert!(masked < reference);
+    }
+
+    #[test]
+    fn translations_match_reference() {
+        for nx in 1..9 {
+            for ny in 1..(16 / nx + 1) {
+                let (nx, ny) = (Dim(nx), Dim(ny));
+                let trans = Translations::new(nx, ny);
+                for dec in 0..1 << (nx * ny).raw_int() {
+                    let dec = BinaryBasis(dec);
+                    assert_eq!(trans.x(dec), translate_x_reference(dec, nx, ny));
+                    assert_eq!(trans.y(dec), translate_y_reference(dec, nx, ny));
+                }
+            }
+        }
+        // rows wider than the exhaustive sizes
+        let (nx, ny) = (Dim(20), Dim(2));
+        let trans = Translations::new(nx, ny);
+        for &dec in [0, 1, 0x8_0001, 0xf_ffff, 0xa_5a5a_5a5a].iter() {
+            let dec = BinaryBasis(dec);
+            assert_eq!(trans.x(dec), translate_x_reference(dec, nx, ny));
+            assert_eq!(trans.y(dec), translate_y_reference(dec, nx, ny));
+        }
+    }
+
+    #[test]
+    fn narrow_translations_match_wide() {
+        assert_eq!(Width::for_lattice(Dim(8), Dim(4)), Width::U32);
+        assert_eq!(Width::for_lattice(Dim(6), Dim(6)), Width::U64);
+        let lattices = [(Dim(4), Dim(4), 1),
+                        (Dim(5), Dim(4), 7),
+                        (Dim(5), Dim(5), 97)];
+        for &(nx, ny, step) in lattices.iter() {
+            assert_eq!(Width::for_lattice(nx, ny), Width::U32);
+            let narrow = Translations32::new(nx, ny);
+            let wide = Translations::new(nx, ny);
+            for dec in (0..1 << (nx * ny).raw_int()).step_by(step) {
+                assert_eq!(narrow.x_word(dec as u32) as u64, wide.x_word(dec));
+                assert_eq!(narrow.y_word(dec as u32) as u64, wide.y_word(dec));
+                let dec = BinaryBasis(dec);
+                assert_eq!(narrow.leading(dec), wide.leading(dec));
+            }
+        }
+    }
+
+    // a translation under another ordering is the row-major one with the
+    // bits taken to row-major order before it and back after it, and the
+    // bonds are the row-major ones carried to the bits of their sites
+    #[test]
+    fn orderings_conjugate_translations_and_bonds() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let column_major = [0, 3, 6, 9, 1, 4, 7, 10, 2, 5, 8, 11];
+        let custom = SitePermutation::new(&column_major).unwrap();
+        let row_major = Translations::new(nx, ny);
+        let pairs = |l, settings: &LatticeSettings| {
+            let (site1, site2) =
+                bond_sites(&generate_range_bonds(nx, ny, I(l), settings));
+            let mut pairs: Vec<_> =
+                site1.iter().zip(site2.iter()).map(|(&a, &b)| a | b).collect();
+            pairs.sort();
+            pairs
+        };
+        let row_major_pairs: Vec<_> = (1..4).map(|l| pairs(l, &torus())).collect();
+        for &ordering in [SiteOrdering::Snake, SiteOrdering::Custom(custom)].iter() {
+            let to = ordering.positions(nx, ny);
+            let mut from = vec![0; to.len()];
+            for (i, &bit) in to.iter().enumerate() {
+                from[bit as usize] = i as u32;
+            }
+            let settings = LatticeSettings { ordering,
+                                             ..torus() };
+            let trans = Translations::ordered(nx, ny, 0, Periodicity::TORUS,
+                                              settings.ordering(nx, ny));
+            assert_eq!(trans.ordering(), ordering);
+            for dec in 0..1 << 12 {
+                let rows = permute_word(dec, &from);
+                assert_eq!(trans.x_word(dec),
+                           permute_word(row_major.x_word(rows), &to));
+                assert_eq!(trans.y_word(dec),
+                           permute_word(row_major.y_word(rows), &to));
+            }
+            let carry = |p: &BinaryBasis| BinaryBasis(permute_word(p.0, &to));
+            for (l, before) in (1..4).zip(row_major_pairs.iter()) {
+                let mut expected: Vec<_> = before.iter().map(carry).collect();
+                expected.sort();
+                assert_eq!(pairs(l, &settings), expected);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "lattice wider than the words")]
+    fn narrow_translations_refuse_wide_lattices() {
+        Translations32::new(Dim(6), Dim(6));
+    }
+
+    #[test]
+    #[should_panic(expected = "configuration wider than the map")]
+    fn narrow_map_refuses_wide_configurations() {
+        let mut map = StateMap::new(Width::U32);
+        map.insert(BinaryBasis(1 << 35), 0);
+    }
+
+    // the hashtables BasisIndex replaces
+    fn basis_hashtable(bfuncs: &BlochFuncSet) -> FnvHashMap<BinaryBasis, u32> {
+        bfuncs.iter()
+              .enumerate()
+              .map(|(i, b)| (b.lead, i as u32))
+              .collect()
+    }
+
+    #[test]
+    fn basis_index_matches_hashtable() {
+        for &nup in [9, 10].iter() {
+            let bfuncs = ::consv::ks::bloch_states(Dim(5), Dim(4), K(1), K(2), nup)
+                             .unwrap();
+            let index = BasisIndex::new(&bfuncs);
+            let hashtable = basis_hashtable(&bfuncs);
+            for (i, b) in bfuncs.iter().enumerate() {
+                assert_eq!(index.index_of(b.lead), Some(i as u32));
+                assert_eq!(index.dec_of(i as u32), b.lead);
+            }
+            // configurations that are not leading states are not found
+            for dec in (0..1 << 20).step_by(7).map(BinaryBasis) {
+                assert_eq!(index.index_of(dec), hashtable.get(&dec).cloned());
+            }
+        }
+    }
+
+    /// Run with --ignored --nocapture to compare the two implementations
+    #[test]
+    #[ignore]
+    fn basis_index_speedup() {
+        use std::time::Instant;
+        let bfuncs = ::consv::ks::bloch_states(Dim(5), Dim(4), K(0), K(0), 10)
+                         .unwrap();
+        let index = BasisIndex::new(&bfuncs);
+        let hashtable = basis_hashtable(&bfuncs);
+        let leads = bfuncs.iter().map(|b| b.lead).collect::<Vec<_>>();
+        let rounds = 100;
+
+        let start = Instant::now();
+        let mut acc = 0;
+        for _ in 0..rounds {
+            for dec in leads.iter() {
+                acc += hashtable[dec];
+            }
+        }
+        let hashed = start.elapsed();
+
+        let start = Instant::now();
+        let mut acc_index = 0;
+        for _ in 0..rounds {
+            for &dec in leads.iter() {
+                acc_index += index.index_of(dec).unwrap();
+            }
+        }
+        let searched = start.elapsed();
+
+        assert_eq!(acc, acc_index);
+        println!("FnvHashMap: {:?}, BasisIndex: {:?}", hashed, searched);
+    }
+
+    #[test]
+    fn exchange_spin_flips_test1() {
+        let dec = BinaryBasis(10);
+        let s1 = BinaryBasis(2);
+        let s2 = BinaryBasis(8);
+        assert_eq!(exchange_spin_flips(dec, s1, s2), (false, false));
+    }
+
+    #[test]
+    fn exchange_spin_flips_test2() {
+        let dec = BinaryBasis(93);
+        let s1 = BinaryBasis(1);
+        let s2 = BinaryBasis(32);
+        assert_eq!(exchange_spin_flips(dec, s1, s2), (true, false));
+    }
+
+    #[test]
+    fn repeated_spins_test1() {
+        let dec = BinaryBasis(93);
+        let s1 = BinaryBasis(1);
+        let s2 = BinaryBasis(32);
+        assert_eq!(repeated_spins(dec, s1, s2), (false, false));
+    }
+
+    #[test]
+    fn repeated_spins_test2() {
+        let dec = BinaryBasis(93);
+        let s1 = BinaryBasis(4);
+        let s2 = BinaryBasis(64);
+        assert_eq!(repeated_spins(dec, s1, s2), (true, false));
+    }
+
+    #[test]
+    fn repeated_spins_test3() {
+        let dec = BinaryBasis(93);
+        let s1 = BinaryBasis(128);
+        let s2 = BinaryBasis(256);
+        assert_eq!(repeated_spins(dec, s1, s2), (false, true));
+    }
+
+    // the bond-by-bond diagonal element ss_z_elements computed before the
+    // bonds were packed into masks, kept as a reference
+    fn ss_z_elements_reference(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+                               dec: BinaryBasis)
+                               -> f64 {
+        let (ref site1, ref site2) = *sites;
+        let mut same_dir = 0_i32;
+        for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+            let (upup, downdown) = repeated_spins(dec, s1, s2);
+            if upup {
+                same_dir += 1
+            };
+            if downdown {
+                same_dir += 1
+            };
+        }
+        let diff_dir = site1.len() as i32 - same_dir;
+        0.25 * (same_dir - diff_dir) as f64
+    }
+
+    fn ss_z_elements_masked(masks: &BondMasks, dec: BinaryBasis) -> f64 {
+        let state = BlochFunc { lead:   dec,
+                                decs:   Box::new([]),
+                                shifts: Box::new([]),
+                                norm:   1. };
+        ::ops::ss_z_elements(masks, &state)
+    }
+
+    #[test]
+    fn bond_masks_match_reference() {
+        for &(nx, ny) in [(3, 3), (4, 3), (4, 4), (6, 4), (6, 6), (8, 7)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let mask = (1 << (nx * ny).raw_int()) - 1;
+            for l in 1..4 {
+                let bonds = interacting_sites(nx, ny, I(l));
+                let pairs = all_sites(nx, ny, I(l)).sites;
+                for sites in [bonds, pairs].iter() {
+                    let masks = BondMasks::new(sites);
+                    for dec in random_states(u64::from(l as u32)).take(2000) {
+                        let dec = BinaryBasis(dec & mask);
+                        assert_eq!(ss_z_elements_masked(&masks, dec).to_bits(),
+                                   ss_z_elements_reference(sites, dec).to_bits());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run with --release --ignored --nocapture to time the diagonal of the
+    /// nearest neighbor bonds of a 6x6 lattice on 2^22 states
+    #[test]
+    #[ignore]
+    fn bond_masks_speedup() {
+        use std::time::Instant;
+        let (nx, ny) = (Dim(6), Dim(6));
+        let sites = interacting_sites(nx, ny, I(1));
+        let masks = BondMasks::new(&sites);
+        let decs = random_states(36).take(1 << 22)
+                                    .map(|dec| BinaryBasis(dec & ((1 << 36) - 1)))
+                                    .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let reference = decs.iter()
+                            .map(|&dec| ss_z_elements_reference(&sites, dec))
+                            .sum::<f64>();
+        let bitwise = start.elapsed();
+
+        let start = Instant::now();
+        let masked = decs.iter()
+                         .map(|&dec| ss_z_elements_masked(&masks, dec))
+                         .sum::<f64>();
+        let popcounts = start.elapsed();
+
+        assert_eq!(masked, reference);
+        println!("{} masks, bit tests: {:?}, popcounts: {:?}",
+                 masks.masks.len(),
+                 bitwise,
+                 popcounts);
+        assert!(popcounts < bitwise);
+    }
+
+    #[test]
+    fn generate_bonds_test1() {
+        let bonds = generate_bonds(Dim(4), Dim(6), &torus());
+        assert_eq!(bonds[0].len(), 72);
+        assert_eq!(bonds[1].len(), 72);
+        // two sites along x are third neighbors both ways around the 4 sites
+        assert_eq!(bonds[2].len(), 60);
+    }
+
+    #[test]
+    fn generate_bonds_test2() {
+        let bonds = generate_bonds(Dim(6), Dim(6), &torus());
+        assert_eq!(bonds[0].len(), 108);
+        assert_eq!(bonds[1].len(), 108);
+        assert_eq!(bonds[2].len(), 108);
+    }
+
+    // the bonds and triangles of the 4x4 lattice counted by hand for each
+    // periodicity: along an open axis a row of 4 sites has 3 bonds along a1
+    // instead of 4, and so on for the other directions and ranges
+    #[test]
+    fn open_ends_drop_the_bonds_across_them() {
+        let (nx, ny) = (Dim(4), Dim(4));
+        // nearest and second neighbor bonds, triangles
+        let counts = [((true, true), 48, 48, 32),
+                      ((false, true), 40, 32, 24),
+                      ((true, false), 40, 32, 24),
+                      ((false, false), 33, 21, 18)];
+        for &((x, y), nearest, second, triangles) in counts.iter() {
+            let periodicity = Periodicity { x, y };
+            let settings = LatticeSettings { periodicity,
+                                             ..torus() };
+            let bonds = generate_bonds(nx, ny, &settings);
+            assert_eq!(bonds[0].len(), nearest);
+            assert_eq!(bonds[1].len(), second);
+            // each bond joins its sites on this side of the open ends
+            let geometry = LatticeGeometry::default();
+            for (bonds, &length_sqr) in bonds.iter().zip([1, 3].iter()) {
+                for bond in bonds.iter() {
+                    let d = bond[1].displacement_from_within(&bond[0],
+                                                             &geometry,
+                                                             periodicity);
+                    assert_eq!(d.length_sqr(), length_sqr);
+                }
+            }
+            let plaquettes = triangular_plaquettes(nx, ny, &settings);
+            assert_eq!(plaquettes.len(), triangles);
+
+            // the tables follow the periodicity of their settings
+            let tables = lattice_tables(nx, ny, &settings).unwrap();
+            assert_eq!(tables.periodicity(), periodicity);
+            assert_eq!(*tables.bonds(I(1)), bond_sites(&bonds[0]));
+            assert_eq!(tables.bonds(I(2)).0.len(), second);
+            assert_eq!(tables.triangles().0.len(), triangles);
+        }
+        let tables = lattice_tables(nx, ny, &torus()).unwrap();
+        assert_eq!(tables.bonds(I(1)).0.len(), 48);
+    }
+
+    // the bonds of the ranges beyond MAX_BOND_RANGE join the sites of the
+    // shells further out
+    #[test]
+    fn range_bonds_beyond_the_third() {
+        let (nx, ny) = (Dim(8), Dim(8));
+        let shells = shell_distances(nx, ny, &torus());
+        for l in 1..8 {
+            let bonds = generate_range_bonds(nx, ny, I(l), &torus());
+            let (distance, count) = shells[l as usize - 1];
+            assert_eq!(bonds.len(), 64 * count / 2);
+            for bond in bonds.iter() {
+                assert!((bond[0].distance_to(&bond[1]) - distance).abs() < 1e-12);
+            }
+            let (site1, site2) = interacting_sites(nx, ny, I(l));
+            assert_eq!((site1.len(), site2.len()), (bonds.len(), bonds.len()));
+        }
+        assert_eq!(generate_range_bonds(nx, ny, I(4), &torus()).len(), 384);
+        assert!(generate_range_bonds(nx, ny, I(0), &torus()).is_empty());
+        let beyond = I(shells.len() as i32 + 1);
+        assert!(generate_range_bonds(nx, ny, beyond, &torus()).is_empty());
+    }
+
+    #[test]
+    fn shells_hold_every_other_site_once() {
+        for &(nx, ny) in [(2, 2), (3, 3), (4, 3), (1, 5), (8, 2), (6, 6)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let n = (nx * ny).raw_int() as usize;
+            for &shift in [0, 1].iter() {
+                let settings = LatticeSettings { shift,
+                                                 ..torus() };
+                let table = lattice_shells(nx, ny, &settings);
+                let distances = shell_distances(nx, ny, &settings);
+                let sites = table.shells.iter().map(|s| s.sites).sum::<usize>();
+                assert_eq!(sites, n - 1);
+                let shells = &table.shells;
+                let increasing = |w: &[Shell]| w[0].distance < w[1].distance;
+                assert!(shells.windows(2).all(increasing));
+                for shell in shells.iter() {
+                    let l = shell.range;
+                    assert_eq!(table.range(l), Some(shell));
+                    assert_eq!(distances[l.raw_int() as usize - 1],
+                               (shell.distance, shell.sites));
+                    let length = shell.displacement.length_sqr();
+                    assert_eq!(f64::from(length).sqrt(), shell.distance);
+                    let bonds = generate_range_bonds(nx, ny, l, &settings);
+                    assert_eq!(bonds.len(), n * shell.sites / 2);
+                }
+                // the shells the lattice is too small for are left out
+                for (l, &(_, count)) in distances.iter().enumerate() {
+                    let listed = table.range(I(l as i32 + 1)).is_some();
+                    assert_eq!(listed, count > 0);
+                }
+            }
+        }
+
+        // on 2x2 the site at (1, 1) is a nearest neighbor through its image
+        // along a2, so the three other sites make up a single shell
+        let table = lattice_shells(Dim(2), Dim(2), &torus());
+        assert_eq!(table.shells.len(), 1);
+        assert_eq!((table.shells[0].range, table.shells[0].sites), (I(1), 3));
+        let table = lattice_shells(Dim(6), Dim(6), &torus());
+        let first = table.shells[..3].iter()
+                                     .map(|s| (s.range, s.sites))
+                                     .collect::<Vec<_>>();
+        assert_eq!(first, vec![(I(1), 6), (I(2), 6), (I(3), 6)]);
+        let d = table.shells[0].displacement;
+        assert_eq!((d.dx, d.dy), (1, 0));
+    }
+
+    // generate_bonds as it produced all three ranges in one sweep over the
+    // sites, kept as a reference, with the repeated pairs dropped: the ranges
+    // are swept one after the other so that a pair goes to the shortest
+    fn generate_bonds_reference(nx: Dim, ny: Dim) -> Vec<Vec<Vec<SiteVector>>> {
+        let n = nx * ny;
+        let mut bonds_by_range: Vec<Vec<Vec<SiteVector>>> = vec![Vec::new(); 3];
+        for leap in 0..3 {
+            let mut vec = SiteVector::new((I(0), I(0)), nx, ny);
+            for _ in 0..n.raw_int() {
+                // the hops along a1, a2 and a3, b1, b2 and b3 and 2 a1, 2 a2
+                // and 2 a3 that the three ranges were generated by
+                let hops = match leap {
+                    0 => [(1, 0), (-1, 1), (0, -1)],
+                    1 => [(1, 1), (-2, 1), (1, -2)],
+                    _ => [(2, 0), (-2, 2), (0, -2)]
+                };
+                let neighbors = hops.iter()
+                                    .map(|&(dx, dy)| vec.xhop(I(dx)).yhop(I(dy)))
+                                    .filter(|n| *n != vec)
+                                    .collect::<Vec<_>>();
+                for n in neighbors.iter() {
+                    let mut bond = vec![vec.clone(), n.clone()];
+                    bond.sort();
+                    if !bonds_by_range.iter().any(|bonds| bonds.contains(&bond)) {
+                        bonds_by_range[leap].push(bond);
+                    }
+                }
+                vec = vec.next_site();
+            }
+        }
+        bonds_by_range
+    }
+
+    #[test]
+    fn range_bonds_match_reference() {
+        for &(nx, ny) in [(3, 3), (4, 3), (4, 6), (6, 6), (5, 7), (9, 7), (2, 4),
+                          (4, 2)]
+                             .iter()
+        {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let reference = generate_bonds_reference(nx, ny);
+            assert_eq!(generate_bonds(nx, ny, &torus()), reference);
+            for l in 1..=MAX_BOND_RANGE {
+                let bonds = &reference[l as usize - 1];
+                assert_eq!(generate_range_bonds(nx, ny, I(l), &torus()), *bonds);
+                assert_eq!(interacting_sites(nx, ny, I(l)), bond_sites(bonds));
+            }
+        }
+    }
+
+    // the phases of the bonds between all pairs of different sites of the 4x4
+    // lattice as they were before the geometry could be set, row by row: 0 for
+    // 1, + for e^(2πi/3) and - for e^(-2πi/3)
+    const GAMMAS_4X4: &str = "000-+++-+++-+++/000+-+++-+++-++/000++-+++-+++-+/\
+                              000+++-+++-+++-/-+++000-+++-+++/+-++000+-+++-++/\
+                              ++-+000++-+++-+/+++-000+++-+++-/-+++-+++000-+++/\
+                              +-+++-++000+-++/++-+++-+000++-+/+++-+++-000+++-/\
+                              -+++-+++-+++000/+-+++-+++-++000/++-+++-+++-+000/\
+                              +++-+++-+++-000/";
+
+    #[test]
+    fn default_geometry_keeps_the_phases() {
+        let (nx, ny) = (Dim(4), Dim(4));
+        let sites = site_vectors(nx, ny, &torus());
+        let geometry = LatticeGeometry::default();
+        let mut codes = String::new();
+        for m in 0..16 {
+            for n in (0..16).filter(|&n| n != m) {
+                let (s1, s2) = (site_mask(m), site_mask(n));
+                let gamma = gamma(&sites, s1, s2).unwrap();
+                assert_eq!(gamma_in(&sites, s1, s2, &geometry).unwrap(), gamma);
+                codes.push(match gamma.arg() {
+                               a if a.abs() < 1e-9 => '0',
+                               a if a > 0. => '+',
+                               _ => '-'
+                           });
+            }
+            codes.push('/');
+        }
+        assert_eq!(codes, GAMMAS_4X4);
+        // the default geometry is the one of the default settings
+        let settings = LatticeSettings { geometry,
+                                         ..torus() };
+        let tables = lattice_tables(nx, ny, &settings).unwrap();
+        let plain = lattice_tables(nx, ny, &torus()).unwrap();
+        assert!(Arc::ptr_eq(&tables, &plain));
+    }
+
+    #[test]
+    fn tables_follow_the_geometry() {
+        let (nx, ny) = (Dim(4), Dim(4));
+        // a2 a quarter of a1 and a little off it, so that four steps along -y
+        // are shorter than one along x
+        let squashed = LatticeGeometry::new((1., 0.), (0.25, 0.01)).unwrap();
+        let settings = LatticeSettings { geometry: squashed,
+                                         ..torus() };
+        let tables = lattice_tables(nx, ny, &settings).unwrap();
+        let triangular = lattice_tables(nx, ny, &torus()).unwrap();
+        assert_eq!(*tables.geometry(), squashed);
+        assert_eq!(*triangular.geometry(), LatticeGeometry::default());
+        assert_eq!(tables.settings(), settings);
+        // the same bonds, the nearer images changing the phases of some
+        assert_eq!(tables.bonds(I(1)), triangular.bonds(I(1)));
+        assert!(tables.gammas(I(1)) != triangular.gammas(I(1)));
+    }
+
+    // the directions of the bonds are those of their phases, and the bonds of
+    // the second range have none
+    #[test]
+    fn bond_directions_match_phases() {
+        for &(nx, ny) in [(4, 4), (3, 5), (6, 6)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let tables = lattice_tables(nx, ny, &torus()).unwrap();
+            for l in 1..=MAX_BOND_RANGE {
+                let (site1, site2, directions) =
+                    interacting_sites_with_directions(nx, ny, I(l));
+                assert_eq!((site1, site2), *tables.bonds(I(l)));
+                for (&code, &gamma) in directions.iter().zip(tables.gammas(I(l))) {
+                    let arg = match code.abs() {
+                        0 => {
+                            assert_eq!(l, 2);
+                            continue;
+                        }
+                        1 => 0.,
+                        2 => 2. * PI / 3.,
+                        _ => -2. * PI / 3.
+                    };
+                    assert!((gamma - Complex::from_polar(&1., &arg)).norm() < 1e-12);
+                }
+                if l == 2 {
+                    assert!(directions.iter().all(|&code| code == 0));
+                }
+                let tagged = generate_tagged_range_bonds(nx, ny, I(l));
+                assert_eq!(tagged.len(), directions.len());
+            }
+        }
+    }
+
+    // the tracked displacement of each bond leads from its first site to the
+    // image of its second one across the number of boundaries it counts
+    #[test]
+    fn displacements_mark_bonds_across_the_boundary() {
+        for &(nx, ny) in [(4, 4), (3, 5), (6, 6)].iter() {
+            let tables = lattice_tables(Dim(nx), Dim(ny), &torus()).unwrap();
+            let (nx, ny) = (nx as i32, ny as i32);
+            let shells = shell_distances(Dim(nx as u32), Dim(ny as u32), &torus());
+            let xy = |s: BinaryBasis| {
+                let i = site_index(s) as i32;
+                (i % nx, i / nx)
+            };
+            for l in 1..=MAX_BOND_RANGE {
+                let (ref site1, ref site2) = *tables.bonds(I(l));
+                let displacements = tables.displacements(I(l));
+                assert_eq!(displacements.len(), site1.len());
+                let mut wrapped = 0;
+                for ((&s1, &s2), d) in site1.iter().zip(site2).zip(displacements) {
+                    let ((x1, y1), (x2, y2)) = (xy(s1), xy(s2));
+                    assert_eq!(x1 + d.dx + d.wraps_x * nx, x2);
+                    assert_eq!(y1 + d.dy + d.wraps_y * ny, y2);
+                    assert_eq!(d.wraps_x != 0, !(0..nx).contains(&(x1 + d.dx)));
+                    assert_eq!(d.wraps_y != 0, !(0..ny).contains(&(y1 + d.dy)));
+                    let length = f64::from(d.length_sqr()).sqrt();
+                    assert!((length - shells[l as usize - 1].0).abs() < 1e-12);
+                    wrapped += (d.wraps_x != 0 || d.wraps_y != 0) as usize;
+                }
+                assert!(wrapped > 0 && wrapped < site1.len());
+            }
+        }
+    }
+
+    #[test]
+    fn gamma_test() {
+        let nx = Dim(4);
+        let ny = Dim(3);
+        let s1 = BinaryBasis(32);
+        let s2 = BinaryBasis(256);
+        let gamma = gamma(&site_vectors(nx, ny, &torus()), s1, s2).unwrap();
+        println!("{}", gamma);
+        assert!((gamma - Complex::new(-0.5, 0.866025403784)).norm() < 1e-8);
+    }
+
+    // gamma as it recovered the sites from the masks through floating-point
+    // logarithms, kept as a reference
+    fn gamma_reference(nx: Dim, ny: Dim, s1: BinaryBasis, s2: BinaryBasis)
+                       -> Complex<f64> {
+        let m = (s1.raw_int() as f64).log2().round() as i32;
+        let n = (s2.raw_int() as f64).log2().round() as i32;
+        let vec1 = SiteVector::from_index(I(m), nx, ny).unwrap();
+        let vec2 = SiteVector::from_index(I(n), nx, ny).unwrap();
+        let ang = vec1.angle_with(&vec2);
+
+        Complex::from_polar(&1.0, &ang)
+    }
+
+    #[test]
+    fn site_mask_boundaries() {
+        for &index in [0, 31, 32, 62, 63].iter() {
+            let mask = checked_site_mask(index).unwrap();
+            assert_eq!(mask.raw_int(), 1_u64 << index);
+            assert_eq!(site_mask(index), mask);
+            assert_eq!(site_index(mask), index as usize);
+        }
+        assert_eq!(site_mask(MAX_SITES - 1), POW2[MAX_SITES as usize - 1]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "site 64 beyond the words")]
+    fn site_mask_refuses_site_64() { let _ = checked_site_mask(64); }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn site_mask_fails_beyond_the_words() {
+        for &index in [64, 65, u32::MAX].iter() {
+            match checked_site_mask(index) {
+                Err(Error::LatticeTooLarge(n)) => {
+                    assert_eq!(n, u64::from(index) + 1)
+                }
+                other => panic!("site {}: {:?}", index, other)
+            }
+        }
+    }
+
+    #[test]
+    fn site_index_test() {
+        for k in 0..64 {
+            assert_eq!(site_index(BinaryBasis(1 << k)), k);
+        }
+    }
+
+    #[test]
+    fn gamma_at_highest_sites() {
+        // 63 sites, the most POW2 covers. From site 52 on the masks are past
+        // 2^52, beyond which doubles no longer hold every integer.
+        let (nx, ny) = (Dim(9), Dim(7));
+        let sites = site_vectors(nx, ny, &torus());
+        for m in 52..63 {
+            for n in 0..63 {
+                let (s1, s2) = (POW2[m], POW2[n]);
+                let expected =
+                    Complex::from_polar(&1.0,
+                                        &sites[m].angle_with(&sites[n]));
+                assert_eq!(gamma(&sites, s1, s2).unwrap(), expected);
+                assert_eq!(gamma(&sites, s1, s2).unwrap(),
+                           gamma_reference(nx, ny, s1, s2));
+            }
+        }
+    }
+
+    #[test]
+    fn gamma_refuses_malformed_masks() {
+        let sites = site_vectors(Dim(4), Dim(3), &torus());
+        let site = BinaryBasis(1 << 5);
+        // no site, two sites, and sites past the 12 of the lattice
+        for &mask in [0, 3, 1 << 12, 1 << 63, !0].iter() {
+            let mask = BinaryBasis(mask);
+            for &(s1, s2) in [(mask, site), (site, mask)].iter() {
+                match gamma(&sites, s1, s2) {
+                    Err(Error::InvalidArgument("site mask")) => (),
+                    other => panic!("{:?}: {:?}", mask, other)
+                }
+            }
+        }
+        assert!(gamma(&sites, BinaryBasis(1 << 11), site).is_ok());
+    }
+
+    #[test]
+    fn gamma_matches_periodic_images() {
+        for &(nx, ny) in [(Dim(4), Dim(4)), (Dim(3), Dim(3))].iter() {
+            let sites = site_vectors(nx, ny, &torus());
+            let mut wrapped = 0;
+            for l in 1..4 {
+                let (site1, site2) = interacting_sites(nx, ny, I(l));
+                for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+                    let (m, n) = (site_index(s1), site_index(s2));
+                    let d = sites[m].displacement_from(&sites[n]);
+                    if d.wraps_x != 0 || d.wraps_y != 0 {
+                        wrapped += 1;
+                    }
+                    let (length_sqr, phase) = bond_from_images(nx, ny, m, n);
+                    assert!((f64::from(d.length_sqr()) - length_sqr).abs() < 1e-9);
+                    let gamma = gamma(&sites, s1, s2).unwrap();
+                    assert!((gamma - phase).norm() < 1e-12,
+                            "{}x{} bond ({}, {})",
+                            nx.raw_int(),
+                            ny.raw_int(),
+                            m,
+                            n);
+                }
+            }
+            assert!(wrapped > 0);
+        }
+    }
+
+    /// Run with --release --ignored --nocapture to time the phases of all bonds
+    /// of a 6x6 lattice
+    #[test]
+    #[ignore]
+    fn gamma_speedup() {
+        use std::time::Instant;
+        let (nx, ny) = (Dim(6), Dim(6));
+        let bonds = (1..4).map(|l| interacting_sites(nx, ny, I(l)))
+                          .collect::<Vec<_>>();
+        let rounds = 10_000;
+
+        let start = Instant::now();
+        let mut reference = Complex::new(0., 0.);
+        for _ in 0..rounds {
+            for (site1, site2) in bonds.iter().map(|b| (&b.0, &b.1)) {
+                for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+                    reference += gamma_reference(nx, ny, s1, s2);
+                }
+            }
+        }
+        let float = start.elapsed();
+
+        let start = Instant::now();
+        let mut sum = Complex::new(0., 0.);
+        for _ in 0..rounds {
+            let sites = site_vectors(nx, ny, &torus());
+            for (site1, site2) in bonds.iter().map(|b| (&b.0, &b.1)) {
+                for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+                    sum += gamma(&sites, s1, s2).unwrap();
+                }
+            }
+        }
+        let table = start.elapsed();
+
+        assert_eq!(sum, reference);
+        println!("log2 and from_index: {:?}, bits and table: {:?}", float, table);
+    }
+
+    #[test]
+    fn triangular_vert_sites_test1() {
+        let nx = Dim(3);
+        let ny = Dim(3);
+        let (site1, site2, site3) = triangular_vert_sites(nx, ny);
+
+        let site1_target = vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8]
+            .into_iter()
+            .map(|x| POW2[x])
+            .collect::<Vec<BinaryBasis>>();
+
+        let site2_target = vec![1, 1, 2, 2, 0, 0, 4, 4, 5, 5, 3, 3, 7, 7, 8, 8, 6, 6]
+            .into_iter()
+            .map(|x| POW2[x])
+            .collect::<Vec<BinaryBasis>>();
+
+        let site3_target = vec![3, 7, 4, 8, 5, 6, 6, 1, 7, 2, 8, 0, 0, 4, 1, 5, 2, 3]
+            .into_iter()
+            .map(|x| POW2[x])
+            .collect::<Vec<BinaryBasis>>();
+
+        assert_eq!(site1, site1_target);
+        assert_eq!(site2, site2_target);
+        assert_eq!(site3, site3_target);
+    }
+
+    #[test]
+    fn triangular_plaquettes_test() {
+        for &(nx, ny) in [(3, 3), (4, 3), (3, 4), (6, 5), (2, 2), (6, 1)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let plaquettes = triangular_plaquettes(nx, ny, &torus());
+            assert_eq!(plaquette_sites(&plaquettes), triangular_vert_sites(nx, ny));
+
+            let up = oriented_vert_sites(nx, ny, Orientation::Up);
+            let down = oriented_vert_sites(nx, ny, Orientation::Down);
+            assert_eq!(up.0.len() + down.0.len(), plaquettes.len());
+            let (mut next_up, mut next_down) = (0, 0);
+            for p in plaquettes.iter() {
+                let masks = plaquette_sites(Some(p));
+                match p.orientation {
+                    Orientation::Up => {
+                        assert_eq!(masks.0[0], up.0[next_up]);
+                        assert_eq!(masks.2[0], up.2[next_up]);
+                        next_up += 1;
+                    }
+                    Orientation::Down => {
+                        assert_eq!(masks.0[0], down.0[next_down]);
+                        assert_eq!(masks.2[0], down.2[next_down]);
+                        next_down += 1;
+                    }
+                }
+            }
+            if nx.raw_int() < 3 || ny.raw_int() < 3 {
+                continue;
+            }
+
+            // unit triangles, upright ones going round counterclockwise from
+            // their anchor and inverted ones clockwise
+            assert_eq!(plaquettes.len(), 2 * (nx * ny).raw_int() as usize);
+            let sites = site_vectors(nx, ny, &torus());
+            for p in plaquettes.iter() {
+                assert_eq!(p.anchor.lattice_index(), p.sites[0]);
+                let corner = |c: usize| &sites[p.sites[c].raw_int() as usize];
+                let d1 = p.anchor.displacement_to(corner(1));
+                let d2 = p.anchor.displacement_to(corner(2));
+                let d3 = corner(1).displacement_to(corner(2));
+                for d in [d1, d2, d3].iter() {
+                    assert!((d.0.hypot(d.1) - 1.).abs() < 1e-12);
+                }
+                let turn = d1.0 * d2.1 - d1.1 * d2.0;
+                match p.orientation {
+                    Orientation::Up => assert!(turn > 0.),
+                    Orientation::Down => assert!(turn < 0.)
+                }
+            }
+        }
     }
 
+    // every site is a corner of six triangles and, away from the 2x2 torus,
+    // every nearest neighbor bond an edge of two; on the 2x2 torus each three
+    // sites are the corners of an upright and of an inverted triangle, going
+    // round them the opposite ways
     #[test]
-    fn choose_test1() {
-        let n = Dim(6);
-        let nup = 3;
-        assert_eq!(choose(n, nup), 20);
+    fn plaquettes_counted_once() {
+        use std::collections::HashMap;
+
+        for &(nx, ny) in [(3, 3), (4, 4), (2, 2)].iter() {
+            let n = nx * ny;
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let plaquettes = triangular_plaquettes(nx, ny, &torus());
+            assert_eq!(plaquettes.len(), 2 * n as usize);
+            let tables = lattice_tables(nx, ny, &torus()).unwrap();
+            assert_eq!(tables.plaquettes(), &plaquettes[..]);
+            assert_eq!(::lattice_triangle_count(nx.raw_int(), ny.raw_int()),
+                       2 * n);
+
+            let mut corners = vec![0; n as usize];
+            let mut edges = HashMap::new();
+            let mut sets = HashMap::new();
+            for p in plaquettes.iter() {
+                let mut set = p.sites.to_vec();
+                set.sort();
+                sets.entry(set).or_insert_with(Vec::new).push(p.sites);
+                for c in 0..3 {
+                    corners[p.sites[c].raw_int() as usize] += 1;
+                    let (a, b) = (p.sites[c], p.sites[(c + 1) % 3]);
+                    *edges.entry((cmp::min(a, b), cmp::max(a, b))).or_insert(0) += 1;
+                }
+            }
+            assert!(corners.iter().all(|&c| c == 6));
+            if n == 4 {
+                assert_eq!(sets.len(), 4);
+                for triangles in sets.values() {
+                    let [a, b, c] = triangles[0];
+                    let reversed = [[a, c, b], [c, b, a], [b, a, c]];
+                    assert_eq!(triangles.len(), 2);
+                    assert!(reversed.contains(&triangles[1]));
+                }
+                continue;
+            }
+            assert_eq!(sets.len(), 2 * n as usize);
+            let (site1, site2) = interacting_sites(nx, ny, I(1));
+            assert_eq!(edges.len(), site1.len());
+            for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+                let (a, b) = (I(site_index(s1) as i32), I(site_index(s2) as i32));
+                assert_eq!(edges[&(cmp::min(a, b), cmp::max(a, b))], 2);
+            }
+        }
     }
 
     #[test]
-    fn choose_test2() {
-        let n = Dim(24);
-        let nup = 12;
-        assert_eq!(choose(n, nup), 2704156);
+    fn two_wide_bonds_test() {
+        for &(nx, ny) in [(2, 4), (4, 2)].iter() {
+            let (site1, site2) = interacting_sites(Dim(nx), Dim(ny), I(1));
+            let mut bonds = site1.iter()
+                                 .zip(site2.iter())
+                                 .map(|(&s1, &s2)| {
+                                     let (m1, m2) = (site_index(s1), site_index(s2));
+                                     (m1.min(m2), m1.max(m2))
+                                 })
+                                 .collect::<Vec<_>>();
+            let mut expected = two_wide_bonds(nx, ny);
+            bonds.sort();
+            expected.sort();
+            assert_eq!(bonds, expected);
+        }
+        // no pair is bonded twice within a range, however narrow the lattice
+        for &(nx, ny) in [(2, 2), (2, 3), (3, 2), (2, 5), (3, 3), (4, 4)].iter() {
+            for l in 1..=MAX_BOND_RANGE {
+                let bonds = generate_range_bonds(Dim(nx), Dim(ny), I(l), &torus());
+                let mut pairs =
+                    bonds.iter()
+                         .map(|b| (b[0].lattice_index(), b[1].lattice_index()))
+                         .map(|(a, b)| (a.min(b), a.max(b)))
+                         .collect::<Vec<_>>();
+                let len = pairs.len();
+                pairs.sort();
+                pairs.dedup();
+                assert_eq!(pairs.len(), len, "{}x{} l = {}", nx, ny, l);
+            }
+        }
     }
 
     #[test]
-    fn sz_basis_test() {
-        let n = Dim(6);
-        let nup = 3;
-        assert_eq!(sz_basis(n, nup).len(), 20);
+    fn degenerate_lattice_bonds_test() {
+        // a chain of six sites either way: the nearest neighbors along the
+        // chain, then the sites two apart, a second neighbor through a periodic
+        // image, and no third neighbors that are not already bonded
+        let chain = vec![vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (0, 5)],
+                         vec![(0, 2), (1, 3), (2, 4), (3, 5), (0, 4), (1, 5)],
+                         vec![]];
+        // on the 2x2 lattice every two sites are nearest neighbors
+        let square = vec![vec![(0, 1), (2, 3), (0, 3), (1, 2), (0, 2), (1, 3)],
+                          vec![],
+                          vec![]];
+        for &(nx, ny, expected) in
+            [(6, 1, &chain), (1, 6, &chain), (2, 2, &square)].iter()
+        {
+            for l in 1..=MAX_BOND_RANGE {
+                let (site1, site2) = interacting_sites(Dim(nx), Dim(ny), I(l));
+                let mut bonds =
+                    site1.iter()
+                         .zip(site2.iter())
+                         .map(|(&s1, &s2)| (site_index(s1), site_index(s2)))
+                         .map(|(m1, m2)| (m1.min(m2), m1.max(m2)))
+                         .collect::<Vec<_>>();
+                let mut expected = expected[l as usize - 1].clone();
+                bonds.sort();
+                expected.sort();
+                assert_eq!(bonds, expected, "{}x{} l = {}", nx, ny, l);
+            }
+        }
+
+        for &(nx, ny) in [(6, 1), (1, 6)].iter() {
+            let (site1, site2, site3) = triangular_vert_sites(Dim(nx), Dim(ny));
+            assert!(site1.is_empty() && site2.is_empty() && site3.is_empty());
+        }
+        let target = |s: Vec<usize>| s.into_iter().map(|x| POW2[x]).collect();
+        let triangles = (target(vec![0, 0, 1, 1, 2, 2, 3, 3]),
+                         target(vec![1, 1, 0, 0, 3, 3, 2, 2]),
+                         target(vec![2, 3, 3, 2, 0, 1, 1, 0]));
+        assert_eq!(triangular_vert_sites(Dim(2), Dim(2)), triangles);
     }
 
+    // the triangles and the pairs of all_sites come site by site in the order of
+    // the site indices, as they did when the sites were walked by hand
     #[test]
-    fn translate_x_test() {
-        let d1 = BinaryBasis(10);
-        let d2 = BinaryBasis(5);
-        let nx = Dim(4);
-        let ny = Dim(6);
-        assert_eq!(translate_x(d1, nx, ny), d2);
+    fn enumerations_keep_their_order() {
+        let lattices = [(3, 3), (4, 3), (2, 4), (4, 2), (1, 5), (5, 1), (6, 5)];
+        for &(nx, ny) in lattices.iter() {
+            let n = nx * ny;
+            let site = |i: u32, dx: u32, dy: u32| {
+                let (x, y) = ((i % nx + dx) % nx, (i / nx + dy) % ny);
+                POW2[(x + y * nx) as usize]
+            };
+            let mut triangles = (Vec::new(), Vec::new(), Vec::new());
+            for i in 0..n {
+                // the upright triangle and the inverted one, y - 1 being
+                // ny - 1 rows up
+                let upright = (site(i, 1, 0), site(i, 0, 1));
+                let inverted = (site(i, 1, 0), site(i, 1, ny - 1));
+                for &c in [upright, inverted].iter() {
+                    let corners = (site(i, 0, 0), c.0, c.1);
+                    if corners.0 != corners.1 && corners.1 != corners.2
+                       && corners.2 != corners.0
+                    {
+                        triangles.0.push(corners.0);
+                        triangles.1.push(corners.1);
+                        triangles.2.push(corners.2);
+                    }
+                }
+            }
+            assert_eq!(triangular_vert_sites(Dim(nx), Dim(ny)), triangles);
+
+            for l in 0..n {
+                let (dx, dy) = (l % nx, l / nx);
+                let mut pairs = (Vec::new(), Vec::new());
+                for i in 0..n {
+                    let (s1, s2) = (site(i, 0, 0), site(i, dx, dy));
+                    let (a, b) = (cmp::min(s1, s2), cmp::max(s1, s2));
+                    let seen = pairs.0.iter().zip(pairs.1.iter()).any(|(&p, &q)| {
+                                   (cmp::min(p, q), cmp::max(p, q)) == (a, b)
+                               });
+                    if s1 != s2 && !seen {
+                        pairs.0.push(s1);
+                        pairs.1.push(s2);
+                    }
+                }
+                assert_eq!(all_sites(Dim(nx), Dim(ny), I(l as i32)).sites, pairs);
+            }
+        }
     }
 
     #[test]
-    fn translate_y_test() {
-        let d1 = BinaryBasis(2);
-        let d2 = BinaryBasis(8192);
-        let nx = Dim(4);
-        let ny = Dim(4);
-        assert_eq!(translate_y(d1, nx, ny), d2);
+    fn all_sites_pairs() {
+        for &(nx, ny) in [(3, 3), (4, 4)].iter() {
+            let n = nx * ny;
+            for l in 0..n {
+                let (dx, dy) = (l % nx, l / nx);
+                let mut expected = HashSet::new();
+                for i in 0..n {
+                    let j = (i % nx + dx) % nx + (i / nx + dy) % ny * nx;
+                    if i != j {
+                        let (i, j) = (i as usize, j as usize);
+                        expected.insert((cmp::min(i, j), cmp::max(i, j)));
+                    }
+                }
+                let mut expected = expected.into_iter().collect::<Vec<_>>();
+                expected.sort();
+
+                let pairs = all_sites(Dim(nx), Dim(ny), I(l as i32));
+                let (ref site1, ref site2) = pairs.sites;
+                let mut found =
+                    site1.iter()
+                         .zip(site2.iter())
+                         .map(|(&s1, &s2)| (site_index(s1), site_index(s2)))
+                         .map(|(i, j)| (cmp::min(i, j), cmp::max(i, j)))
+                         .collect::<Vec<_>>();
+                found.sort();
+                let len = found.len();
+                found.dedup();
+                assert_eq!(found.len(), len, "{}x{} l = {}", nx, ny, l);
+                assert_eq!(found, expected, "{}x{} l = {}", nx, ny, l);
+                if l == 0 {
+                    assert!(found.is_empty());
+                } else {
+                    let half = (2 * dx) % nx == 0 && (2 * dy) % ny == 0;
+                    assert_eq!(pairs.multiplicity, if half { 2 } else { 1 });
+                    assert_eq!(pairs.multiplicity * len as u32, n);
+                }
+            }
+        }
     }
 
     #[test]
-    fn exchange_spin_flips_test1() {
-        let dec = BinaryBasis(10);
-        let s1 = BinaryBasis(2);
-        let s2 = BinaryBasis(8);
-        assert_eq!(exchange_spin_flips(dec, s1, s2), (false, false));
+    fn corrupted_phase_is_not_found() {
+        use blochfunc::BlochFuncSet;
+        let (nx, ny) = (Dim(3), Dim(3));
+        let bfuncs = ::consv::k::bloch_states(nx, ny, K(1), K(2)).unwrap();
+        let members = BlochFuncSet::build_dict(&bfuncs);
+        let trans = Translations::new(nx, ny);
+        let (i, bfunc) = bfuncs.data
+                               .iter()
+                               .enumerate()
+                               .find(|&(_, b)| b.decs.len() == 9)
+                               .unwrap();
+        let dec = trans.y(trans.x(bfunc.lead));
+        let (j, phase) = find_leading_state(dec, &members, &bfuncs.data,
+                                            &bfuncs.phases, MIN_PHASE_NORM)
+                             .unwrap();
+        assert_eq!(j as usize, i);
+        assert!((phase.norm() - 1.).abs() < 1e-12);
+
+        // the phase of the translation by (1, 1), the only one that takes the
+        // leading state to "dec", lost
+        let mut phases = bfuncs.phases.clone();
+        phases[1 + 3] = Complex::new(0., 0.);
+        let find = |dec| {
+            find_leading_state(dec, &members, &bfuncs.data, &phases, MIN_PHASE_NORM)
+        };
+        assert!(find(dec).is_none());
+        assert!(find(bfunc.lead).is_some());
+        let phase = |re, im| leading_phase(Complex::new(re, im), MIN_PHASE_NORM);
+        assert_eq!(phase(0., 0.), None);
+        assert_eq!(phase(1e-12, -1e-12), None);
+        assert_eq!(phase(0., -3.), Some(Complex::new(0., 1.)));
     }
 
     #[test]
-    fn exchange_spin_flips_test2() {
-        let dec = BinaryBasis(93);
-        let s1 = BinaryBasis(1);
-        let s2 = BinaryBasis(32);
-        assert_eq!(exchange_spin_flips(dec, s1, s2), (true, false));
+    fn min_phase_norm_is_checked() {
+        for &norm in [-1e-8, f64::NAN, f64::INFINITY].iter() {
+            match check_phase_norm(norm) {
+                Err(Error::InvalidArgument("norm")) => {}
+                r => panic!("{} accepted: {:?}", norm, r)
+            }
+        }
+        assert_eq!(check_phase_norm(0.).unwrap(), 0.);
+        assert_eq!(check_phase_norm(MIN_PHASE_NORM).unwrap(), MIN_PHASE_NORM);
     }
 
+    // the threshold is the one passed in: a component of the default's size
+    // is a phase under a lower one, and a unit component is not under a
+    // higher one
     #[test]
-    fn repeated_spins_test1() {
-        let dec = BinaryBasis(93);
-        let s1 = BinaryBasis(1);
-        let s2 = BinaryBasis(32);
-        assert_eq!(repeated_spins(dec, s1, s2), (false, false));
+    fn min_phase_norm_changes_the_lookup() {
+        let small = Complex::new(MIN_PHASE_NORM / 2., 0.);
+        assert_eq!(leading_phase(small, MIN_PHASE_NORM), None);
+        assert_eq!(leading_phase(small, 0.), Some(Complex::new(1., 0.)));
+
+        let (nx, ny) = (Dim(3), Dim(3));
+        let bfuncs = ::consv::k::bloch_states(nx, ny, K(1), K(2)).unwrap();
+        let members = BlochFuncSet::build_dict(&bfuncs);
+        let bfunc = bfuncs.data.iter().find(|b| b.decs.len() == 9).unwrap();
+        let find = |norm| {
+            find_leading_state(bfunc.lead, &members, &bfuncs.data, &bfuncs.phases,
+                               norm)
+        };
+        assert!(find(MIN_PHASE_NORM).is_some());
+        assert!(find(2.).is_none());
     }
 
     #[test]
-    fn repeated_spins_test2() {
-        let dec = BinaryBasis(93);
-        let s1 = BinaryBasis(4);
-        let s2 = BinaryBasis(64);
-        assert_eq!(repeated_spins(dec, s1, s2), (true, false));
+    fn interacting_sites_order() {
+        // external callers rely on this order through lattice_bonds
+        let (site1, site2) = interacting_sites(Dim(3), Dim(3), I(1));
+        let site1_target = vec![0, 0, 0, 1, 3, 1, 0, 4, 2, 3, 3, 0, 4, 6, 1, 3, 7,
+                                2, 6, 6, 3, 7, 0, 4, 6, 1, 5]
+            .into_iter()
+            .map(|x| POW2[x])
+            .collect::<Vec<BinaryBasis>>();
+        let site2_target = vec![1, 5, 6, 2, 1, 7, 2, 2, 8, 4, 8, 3, 5, 4, 4, 5, 5,
+                                5, 7, 2, 6, 8, 7, 7, 8, 8, 8]
+            .into_iter()
+            .map(|x| POW2[x])
+            .collect::<Vec<BinaryBasis>>();
+        assert_eq!(site1, site1_target);
+        assert_eq!(site2, site2_target);
     }
 
     #[test]
-    fn repeated_spins_test3() {
-        let dec = BinaryBasis(93);
-        let s1 = BinaryBasis(128);
-        let s2 = BinaryBasis(256);
-        assert_eq!(repeated_spins(dec, s1, s2), (false, true));
+    fn lattice_tables_fail_without_touching_the_cache() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let tables = lattice_tables(nx, ny, &torus()).unwrap();
+        match lattice_tables(Dim(9), Dim(8), &torus()) {
+            Err(Error::LatticeTooLarge(72)) => (),
+            other => panic!("{:?}", other.map(|_| ()))
+        }
+        let again = lattice_tables(nx, ny, &torus()).unwrap();
+        assert!(Arc::ptr_eq(&tables, &again));
     }
 
     #[test]
-    fn generate_bonds_test1() {
-        let bonds = generate_bonds(Dim(4), Dim(6));
-        assert_eq!(bonds[0].len(), 72);
-        assert_eq!(bonds[1].len(), 72);
-        assert_eq!(bonds[2].len(), 72);
+    fn lattice_tables_match_sites() {
+        for &(nx, ny) in [(3, 3), (4, 3), (6, 4), (5, 6)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let tables = lattice_tables(nx, ny, &torus()).unwrap();
+            let again = lattice_tables(nx, ny, &torus()).unwrap();
+            assert!(Arc::ptr_eq(&tables, &again));
+            for l in 1..4 {
+                let (site1, site2) = interacting_sites(nx, ny, I(l));
+                let sites = site_vectors(nx, ny, &torus());
+                let gammas = site1.iter()
+                                  .zip(site2.iter())
+                                  .map(|(&s1, &s2)| gamma(&sites, s1, s2))
+                                  .collect::<Result<Vec<_>>>()
+                                  .unwrap();
+                assert_eq!(*tables.bonds(I(l)), (site1, site2));
+                assert_eq!(tables.gammas(I(l)), gammas.as_slice());
+            }
+            let triangles = triangular_vert_sites(nx, ny);
+            assert_eq!(*tables.triangles(), triangles);
+            // three edges per triangle, each shared by two triangles
+            assert_eq!(tables.edges().len(), 3 * triangles.0.len() / 2);
+        }
     }
 
+    /// Run with --release --ignored --nocapture to time the setup of five
+    /// operators on the same lattice
     #[test]
-    fn generate_bonds_test2() {
-        let bonds = generate_bonds(Dim(6), Dim(6));
-        assert_eq!(bonds[0].len(), 108);
-        assert_eq!(bonds[1].len(), 108);
-        assert_eq!(bonds[2].len(), 108);
+    #[ignore]
+    fn lattice_tables_speedup() {
+        use ops::PreparedTerm;
+        use std::time::Instant;
+        let (nx, ny) = (Dim(6), Dim(6));
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSsPpmm, I(1)),
+                     Term::new(TermKind::HSsPmz, I(1)),
+                     Term::new(TermKind::HSssChi, I(0))];
+        let rounds = 100;
+
+        // what every builder computed for itself before the tables were shared
+        let start = Instant::now();
+        for _ in 0..rounds {
+            for term in terms.iter() {
+                if term.kind == TermKind::HSssChi {
+                    triangular_vert_sites(nx, ny);
+                    continue;
+                }
+                let (site1, site2) = interacting_sites(nx, ny, term.l);
+                if term.kind == TermKind::HSsPpmm || term.kind == TermKind::HSsPmz {
+                    let sites = site_vectors(nx, ny, &torus());
+                    let _gammas = site1.iter()
+                                       .zip(site2.iter())
+                                       .map(|(&s1, &s2)| gamma(&sites, s1, s2))
+                                       .collect::<Result<Vec<_>>>()
+                                       .unwrap();
+                }
+            }
+        }
+        let separate = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..rounds {
+            for &term in terms.iter() {
+                let tables = lattice_tables(nx, ny, &torus()).unwrap();
+                PreparedTerm::new(term, &tables);
+            }
+        }
+        let shared = start.elapsed();
+
+        println!("separate: {:?}, shared: {:?}", separate, shared);
+        assert!(shared < separate);
     }
 
     #[test]
-    fn gamma_test() {
-        let nx = Dim(4);
-        let ny = Dim(3);
-        let s1 = BinaryBasis(32);
-        let s2 = BinaryBasis(256);
-        let gamma = gamma(nx, ny, s1, s2);
-        println!("{}", gamma);
-        assert!((gamma - Complex::new(-0.5, 0.866025403784)).norm() < 1e-8);
+    fn lattice_bonds_test() {
+        use {bond_list_free, lattice_bonds, lattice_shells, lattice_triangles,
+             shell_list_free, triangle_list_free};
+
+        let mut status = -1;
+        for l in 1..4 {
+            let bonds = unsafe { lattice_bonds(4, 3, l, &mut status) };
+            assert_eq!(status, 0);
+            let (site1, site2, directions) =
+                interacting_sites_with_directions(Dim(4), Dim(3), I(l as i32));
+            unsafe {
+                assert_eq!(bonds.direction.as_slice(), &directions[..]);
+                let tables = lattice_tables(Dim(4), Dim(3), &torus()).unwrap();
+                let sites = site_vectors(Dim(4), Dim(3), &torus());
+                let shells = shell_distances(Dim(4), Dim(3), &torus());
+                let length = shells[l as usize - 1].0;
+                for (i, d) in tables.displacements(I(l as i32)).iter().enumerate() {
+                    let at = |x: &Vector<f64>, y: &Vector<f64>| {
+                        (x.as_slice()[i], y.as_slice()[i])
+                    };
+                    let p1 = at(&bonds.x1, &bonds.y1);
+                    let p2 = at(&bonds.x2, &bonds.y2);
+                    let s2 = &sites[bonds.site2.as_slice()[i] as usize];
+                    let q2 = s2.position_in(&LatticeGeometry::default());
+                    let d12 = (p2.0 - p1.0).hypot(p2.1 - p1.1);
+                    assert!((d12 - length).abs() < 1e-12);
+                    assert_eq!(bonds.wraps_x.as_slice()[i], (d.wraps_x != 0) as u8);
+                    assert_eq!(bonds.wraps_y.as_slice()[i], (d.wraps_y != 0) as u8);
+                    let wraps = d.wraps_x != 0 || d.wraps_y != 0;
+                    assert_eq!(wraps, (p2.0 - q2.0).hypot(p2.1 - q2.1) > 1e-12);
+                }
+                assert_eq!(bonds.site1.as_slice().iter().map(|&i| POW2[i as usize])
+                                .collect::<Vec<_>>(),
+                           site1);
+                assert_eq!(bonds.site2.as_slice().iter().map(|&i| POW2[i as usize])
+                                .collect::<Vec<_>>(),
+                           site2);
+                bond_list_free(bonds);
+            }
+        }
+        let bonds = unsafe { lattice_bonds(4, 3, 4, &mut status) };
+        assert_eq!(status, ::error::ERR_INVALID_ARGUMENT);
+        assert!(bonds.site1.ptr.is_null());
+        assert!(bonds.direction.ptr.is_null());
+        assert!(bonds.wraps_y.ptr.is_null());
+        unsafe { bond_list_free(bonds) };
+
+        let tris = lattice_triangles(3, 3);
+        let (site1, site2, site3) = triangular_vert_sites(Dim(3), Dim(3));
+        let sites = site_vectors(Dim(3), Dim(3), &torus());
+        unsafe {
+            for (i, &inverted) in tris.inverted.as_slice().iter().enumerate() {
+                assert_eq!(inverted, i as u32 % 2);
+                let anchor = &sites[tris.site1.as_slice()[i] as usize];
+                let (x, y) = anchor.position_in(&LatticeGeometry::default());
+                assert_eq!(tris.anchor_x.as_slice()[i], x);
+                assert_eq!(tris.anchor_y.as_slice()[i], y);
+                assert_eq!(POW2[tris.site1.as_slice()[i] as usize], site1[i]);
+                assert_eq!(POW2[tris.site2.as_slice()[i] as usize], site2[i]);
+                assert_eq!(POW2[tris.site3.as_slice()[i] as usize], site3[i]);
+            }
+            assert_eq!(tris.inverted.len, 18);
+            triangle_list_free(tris);
+        }
+
+        let shells = lattice_shells(4, 3);
+        let table = super::lattice_shells(Dim(4), Dim(3), &torus());
+        unsafe {
+            assert_eq!(shells.range.len, table.shells.len());
+            for (i, shell) in table.shells.iter().enumerate() {
+                assert_eq!(shells.range.as_slice()[i], shell.range.raw_int() as u32);
+                assert_eq!(shells.distance.as_slice()[i], shell.distance);
+                assert_eq!(shells.sites.as_slice()[i], shell.sites as u32);
+                assert_eq!(shells.dx.as_slice()[i], shell.displacement.dx);
+                assert_eq!(shells.dy.as_slice()[i], shell.displacement.dy);
+            }
+            shell_list_free(shells);
+            assert!(lattice_shells(8, 8).range.ptr.is_null());
+        }
     }
 
     #[test]
-    fn triangular_vert_sites_test1() {
-        let nx = Dim(3);
-        let ny = Dim(3);
-        let (site1, site2, site3) = triangular_vert_sites(nx, ny);
+    fn lattice_momenta_test() {
+        use {lattice_momenta, nearest_lattice_momentum, vector_f64_free};
 
-        let site1_target = vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8]
-            .into_iter()
-            .map(|x| POW2[x])
-            .collect::<Vec<BinaryBasis>>();
+        let momenta = lattice_momenta(4, 3);
+        let plus = ::blochfunc::Convention::Plus;
+        let expected = ::blochfunc::lattice_momenta(Dim(4), Dim(3), &torus(), plus);
+        unsafe {
+            let q = momenta.as_slice();
+            assert_eq!(q.len(), 24);
+            for (k, &(qx, qy)) in expected.iter().enumerate() {
+                assert_eq!((q[2 * k], q[2 * k + 1]), (qx, qy));
+                let (mut kx, mut ky, mut status) = (9, 9, -1);
+                let distance = nearest_lattice_momentum(4, 3, qx, qy, &mut kx,
+                                                        &mut ky, &mut status);
+                assert_eq!(status, ::error::SUCCESS);
+                assert_eq!((kx, ky), (k as u32 % 4, k as u32 / 4));
+                assert!(distance < 1e-12);
+            }
+            vector_f64_free(momenta);
 
-        let site2_target = vec![1, 1, 2, 2, 0, 0, 4, 4, 5, 5, 3, 3, 7, 7, 8, 8, 6, 6]
-            .into_iter()
-            .map(|x| POW2[x])
-            .collect::<Vec<BinaryBasis>>();
+            assert!(lattice_momenta(0, 3).ptr.is_null());
+            assert!(lattice_momenta(8, 8).ptr.is_null());
+            let (mut kx, mut ky, mut status) = (9, 9, 0);
+            let distance = nearest_lattice_momentum(4,
+                                                    3,
+                                                    f64::NAN,
+                                                    0.,
+                                                    &mut kx,
+                                                    &mut ky,
+                                                    &mut status);
+            assert!(distance.is_nan());
+            assert_eq!((kx, ky, status), (9, 9, ::error::ERR_INVALID_ARGUMENT));
+            let distance = nearest_lattice_momentum(8, 8, 0., 0., &mut kx, &mut ky,
+                                                    &mut status);
+            assert!(distance.is_nan());
+            assert_eq!(status, ::error::ERR_LATTICE_TOO_LARGE);
+        }
+    }
 
-        let site3_target = vec![3, 7, 4, 8, 5, 6, 6, 1, 7, 2, 8, 0, 0, 4, 1, 5, 2, 3]
-            .into_iter()
-            .map(|x| POW2[x])
-            .collect::<Vec<BinaryBasis>>();
+    #[test]
+    fn panic_at_ffi_boundary() {
+        use std::ffi::CStr;
+        use {guard, guard_status, spinsys_last_error};
 
-        assert_eq!(site1, site1_target);
-        assert_eq!(site2, site2_target);
-        assert_eq!(site3, site3_target);
+        // every entry point runs its body through one of the guards, and the
+        // parameters are checked before anything can panic on them, so the
+        // guards are exercised directly
+        let mut status = 0;
+        let mat = unsafe {
+            guard_status(&mut status, CoordMatrix::empty(), || {
+                if status == 0 {
+                    panic!("builder failed");
+                }
+                CoordMatrix::<CComplex<f64>>::empty()
+            })
+        };
+        assert_eq!(status, ::error::ERR_PANIC);
+        assert_eq!(mat.data.len, 0);
+        let msg = spinsys_last_error();
+        assert!(!msg.is_null());
+        assert_eq!(unsafe { CStr::from_ptr(msg) }.to_str().unwrap(),
+                   "builder failed");
+
+        let n = guard(0, || -> u32 { panic!("no status") });
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn lattice_size_boundaries() {
+        use std::ptr;
+        use {k_h_ss_z, ks_h_ss_z_rows, ks_term_nnz, lattice_bonds,
+             lattice_triangles};
+
+        let accepted = [(31, 2), (2, 31), (9, 7), (7, 9), (21, 3), (63, 1)];
+        let rejected = [(8, 8), (32, 2), (64, 1), (16, 4), (9, 8),
+                        (u32::MAX, u32::MAX)];
+        for &(nx, ny) in accepted.iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let n = (nx * ny).raw_int();
+            assert!(check_lattice(nx, ny).is_ok());
+            assert!(check_sector(nx, ny, K(0), K(0), Some(n)).is_ok());
+
+            // the highest site survives a full round of translations
+            let trans = Translations::new(nx, ny);
+            for &dec in [site_mask(n - 1), site_mask(0) | site_mask(n - 1),
+                         BinaryBasis((1 << n) - 1)]
+                            .iter()
+            {
+                let mut x = dec;
+                for _ in 0..nx.raw_int() {
+                    x = trans.x(x);
+                    assert_eq!(x.raw_int() >> n, 0);
+                }
+                let mut y = dec;
+                for _ in 0..ny.raw_int() {
+                    y = trans.y(y);
+                    assert_eq!(y.raw_int() >> n, 0);
+                }
+                assert_eq!((x, y), (dec, dec));
+            }
+
+            let (site1, site2) = all_sites(nx, ny, I(n as i32 - 1)).sites;
+            let (t1, t2, t3) = triangular_vert_sites(nx, ny);
+            let masks = site1.iter().chain(&site2).chain(&t1).chain(&t2).chain(&t3);
+            assert!(masks.fold(BinaryBasis(0), |acc, &s| acc | s)
+                    == BinaryBasis((1 << n) - 1));
+
+            let mut status = 1;
+            let bonds = unsafe {
+                lattice_bonds(nx.raw_int(), ny.raw_int(), 1, &mut status)
+            };
+            assert_eq!(status, 0);
+            assert!(unsafe { bonds.site2.as_slice() }.iter().all(|&s| s < n));
+            unsafe { ::bond_list_free(bonds) };
+        }
+
+        // a single spin up leaves one state per momentum
+        for &(nx, ny) in [(31, 2), (9, 7)].iter() {
+            let mut status = 1;
+            let mat = unsafe {
+                ks_h_ss_z_rows(nx, ny, 1, 1, 1, 1, 0, 1, ptr::null(), &mut status)
+            };
+            assert_eq!(status, 0);
+            assert_eq!((mat.nrows, mat.ncols, mat.data.len), (1, 1, 1));
+            unsafe { ::request_free(mat) };
+        }
+
+        for &(nx, ny) in rejected.iter() {
+            let sites = u64::from(nx) * u64::from(ny);
+            match check_lattice(Dim(nx), Dim(ny)) {
+                Err(Error::LatticeTooLarge(n)) => assert_eq!(n, sites),
+                other => panic!("{}x{}: {:?}", nx, ny, other)
+            }
+            let term = Term::new(TermKind::HSsZ, I(1));
+            match ::consv::ks::term_nnz(Dim(nx), Dim(ny), K(0), K(0), 1, &term) {
+                Err(Error::LatticeTooLarge(n)) => assert_eq!(n, sites),
+                other => panic!("{}x{}: {:?}", nx, ny, other)
+            }
+            // the labels are not looked at
+            match check_sector(Dim(nx), Dim(ny), K(nx), K(0), Some(0)) {
+                Err(Error::LatticeTooLarge(_)) => {}
+                other => panic!("{}x{}: {:?}", nx, ny, other)
+            }
+
+            let mut status = 0;
+            let mat = unsafe {
+                ks_h_ss_z_rows(nx, ny, 0, 0, 1, 1, 0, 1, ptr::null(), &mut status)
+            };
+            assert_eq!(status, ::error::ERR_LATTICE_TOO_LARGE);
+            assert!(mat.is_well_formed() && mat.data.ptr.is_null());
+            let mut status = 0;
+            let cterm = CTerm { kind:  TermKind::HSsXy as u32,
+                                l:     1,
+                                coeff: 1. };
+            let nnz = unsafe { ks_term_nnz(nx, ny, 0, 0, 1, cterm, &mut status) };
+            assert_eq!((nnz, status), (0, ::error::ERR_LATTICE_TOO_LARGE));
+            let mut status = 0;
+            let bonds = unsafe { lattice_bonds(nx, ny, 1, &mut status) };
+            assert_eq!(status, ::error::ERR_LATTICE_TOO_LARGE);
+            assert_eq!(bonds.site1.len, 0);
+            assert_eq!(lattice_triangles(nx, ny).site1.len, 0);
+            let mat = k_h_ss_z(nx, ny, 0, 0, 1);
+            assert_eq!((mat.nrows, mat.ncols, mat.data.len), (0, 0, 0));
+        }
+        let msg = Error::LatticeTooLarge(64).to_string();
+        assert_eq!(msg, "system too large: 64 sites, at most 63 are supported");
+    }
+
+    #[test]
+    fn full_width_rows_on_u32_words() {
+        // a single row of 32 sites fills the u32 words, and translating it
+        // along y used to shift by the full width
+        let (nx, ny) = (Dim(32), Dim(1));
+        assert_eq!(Width::for_lattice(nx, ny), Width::U32);
+        let trans = Translations32::new(nx, ny);
+        let wide = Translations::new(nx, ny);
+        for &dec in [1_u64, 1 << 31, 0xdead_beef, 0xffff_ffff].iter() {
+            let dec = BinaryBasis(dec);
+            assert_eq!(trans.y(dec), dec);
+            assert_eq!(trans.x(dec), wide.x(dec));
+            assert_eq!(trans.leading(dec), wide.leading(dec));
+        }
+    }
+
+    #[test]
+    fn bond_range_test() {
+        assert_eq!(BondRange::from_raw(1).unwrap(), BondRange::NearestNeighbor);
+        assert_eq!(BondRange::from_raw(2).unwrap(), BondRange::Second);
+        assert_eq!(BondRange::from_raw(3).unwrap(), BondRange::Third);
+        for &l in [0, 4, u32::MAX].iter() {
+            match BondRange::from_raw(l) {
+                Err(Error::InvalidArgument("l")) => {}
+                other => panic!("l = {}: {:?}", l, other)
+            }
+        }
+        assert_eq!(BondRange::Third.l(), I(3));
+    }
+
+    #[test]
+    fn term_check_test() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let term = |kind, l| Term::new(kind, I(l));
+        for &l in [1, 2, 3].iter() {
+            for &kind in [TermKind::HSsZ, TermKind::HSsXy, TermKind::HSsPpmm,
+                          TermKind::HSsPmz].iter()
+            {
+                assert!(term(kind, l).check(nx, ny).is_ok());
+            }
+        }
+        // the chirality has no range
+        assert!(term(TermKind::HSssChi, 0).check(nx, ny).is_ok());
+        assert!(term(TermKind::HSssChi, -7).check(nx, ny).is_ok());
+        // the correlations are between sites l apart along the lattice
+        assert!(term(TermKind::SsZ, 0).check(nx, ny).is_ok());
+        assert!(term(TermKind::SsXy, 11).check(nx, ny).is_ok());
+
+        let invalid = [term(TermKind::HSsZ, 0),
+                       term(TermKind::HSsXy, 4),
+                       term(TermKind::HSsPmz, -1),
+                       term(TermKind::SsZ, 12),
+                       term(TermKind::SsXy, -1)];
+        for t in invalid.iter() {
+            match t.check(nx, ny) {
+                Err(Error::InvalidArgument("l")) => {}
+                other => panic!("{:?}: {:?}", t, other)
+            }
+        }
+    }
+
+    #[test]
+    fn check_sector_test() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        assert!(check_sector(nx, ny, K(3), K(2), None).is_ok());
+        assert!(check_sector(nx, ny, K(0), K(0), Some(0)).is_ok());
+        assert!(check_sector(nx, ny, K(0), K(0), Some(12)).is_ok());
+        let cases = [((K(4), K(0), None), "kx"),
+                     ((K(0), K(3), Some(6)), "ky"),
+                     ((K(0), K(0), Some(13)), "nup"),
+                     // the momenta are checked first
+                     ((K(5), K(7), Some(99)), "kx")];
+        for &((kx, ky, nup), name) in cases.iter() {
+            match check_sector(nx, ny, kx, ky, nup) {
+                Err(Error::InvalidArgument(arg)) => assert_eq!(arg, name),
+                other => panic!("{:?}: {:?}", (kx, ky, nup), other)
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_parameters_at_ffi_boundary() {
+        use std::ptr;
+        use {k_h_ss_z, k_ss_xy, k_term_nnz, ks_h_ss_xy, ks_h_ss_z_rows,
+             ks_term_nnz};
+
+        let term = |kind: TermKind, l| {
+            CTerm { kind: kind as u32,
+                    l,
+                    coeff: 1. }
+        };
+        unsafe {
+            // (kx, ky, nup, l), every one out of range for the 4 by 3 lattice
+            for &(kx, ky, nup, l) in [(4, 0, 6, 1), (0, 3, 6, 1), (0, 0, 13, 1),
+                                      (0, 0, 6, 0), (0, 0, 6, 4)]
+                                         .iter()
+            {
+                let mut status = 0;
+                let mat = ks_h_ss_z_rows(4, 3, kx, ky, nup, l, 0, 10, ptr::null(),
+                                         &mut status);
+                assert_eq!(status, ::error::ERR_INVALID_ARGUMENT);
+                assert!(mat.is_well_formed() && mat.data.ptr.is_null());
+
+                let mut status = 0;
+                let nnz = ks_term_nnz(4, 3, kx, ky, nup, term(TermKind::HSsXy, l),
+                                      &mut status);
+                assert_eq!((nnz, status), (0, ::error::ERR_INVALID_ARGUMENT));
+
+                let mat = ks_h_ss_xy(4, 3, kx, ky, nup, l);
+                assert_eq!((mat.nrows, mat.ncols, mat.data.len), (0, 0, 0));
+            }
+
+            for &(kx, ky, l) in [(4, 0, 1), (0, 3, 1), (0, 0, 0), (0, 0, 4)].iter() {
+                let mut status = 0;
+                let nnz = k_term_nnz(4, 3, kx, ky, term(TermKind::HSsZ, l),
+                                     &mut status);
+                assert_eq!((nnz, status), (0, ::error::ERR_INVALID_ARGUMENT));
+                let mat = k_h_ss_z(4, 3, kx, ky, l);
+                assert_eq!((mat.nrows, mat.ncols, mat.data.len), (0, 0, 0));
+            }
+            // the correlations take any separation on the lattice
+            let mut status = 0;
+            let term = term(TermKind::SsXy, 11);
+            assert!(k_term_nnz(4, 3, 0, 0, term, &mut status) > 0);
+            assert_eq!(status, 0);
+            assert_eq!(k_ss_xy(4, 3, 0, 0, 12).data.len, 0);
+        }
+    }
+
+    #[test]
+    fn free_empty_and_failed_outputs() {
+        use std::{mem, ptr};
+        use {bond_list_free, dense_matrix_free, k_h_ss_z, ks_h_ss_z,
+             ks_h_ss_z_dense, ks_h_ss_z_rows, op_free, real_matrix_free,
+             request_free, shell_list_free, spinsys_string_free,
+             triangle_list_free, vector_f64_free};
+
+        unsafe {
+            // zeroed structs and null pointers
+            request_free(mem::zeroed());
+            real_matrix_free(mem::zeroed());
+            dense_matrix_free(mem::zeroed());
+            vector_f64_free(mem::zeroed());
+            bond_list_free(mem::zeroed());
+            triangle_list_free(mem::zeroed());
+            shell_list_free(mem::zeroed());
+            op_free(ptr::null_mut());
+            spinsys_string_free(ptr::null_mut());
+
+            // the only product state without up spins has zero momentum
+            let mat = ks_h_ss_z(3, 3, 1, 0, 0, 1);
+            assert!(mat.is_well_formed());
+            assert_eq!(mat.data.len, 0);
+            request_free(mat);
+
+            // failed builds own nothing
+            let mut status = 0;
+            let rows = (5, 2);
+            let mat = ks_h_ss_z_rows(3, 3, 0, 0, 4, 1, rows.0, rows.1, ptr::null(),
+                                     &mut status);
+            assert_eq!(status, ::error::ERR_INVALID_ARGUMENT);
+            assert!(mat.is_well_formed() && mat.data.ptr.is_null());
+            request_free(mat);
+            let dense = ks_h_ss_z_dense(3, 3, 0, 0, 4, 0, &mut status);
+            assert!(status < 0);
+            assert!(dense.data.ptr.is_null());
+            dense_matrix_free(dense);
+
+            let mut mat = k_h_ss_z(3, 3, 0, 0, 1);
+            assert!(mat.is_well_formed());
+            let data = mem::replace(&mut mat.data, Vector::new(ptr::null_mut(), 0));
+            assert!(!mat.is_well_formed());
+            mat.data = data;
+            request_free(mat);
+        }
+    }
+
+    #[test]
+    fn owned_matrices_free_their_arrays() {
+        use blochfunc::OrbitTable;
+        use consv;
+        use ops::{PreparedTerm, RowElements, VecSink};
+        use test_support::thread_allocated;
+        use request_free;
+
+        let (nx, ny) = (Dim(3), Dim(3));
+        let bfuncs = consv::ks::bloch_states(nx, ny, K(1), K(0), 4).unwrap();
+        let terms = [Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSssChi, I(0))];
+        // row by row on this thread, whose allocations alone are counted
+        let build = |term: Term| {
+            let tables = lattice_tables(nx, ny, &torus()).unwrap();
+            let prepared = PreparedTerm::new(term, &tables);
+            let table = OrbitTable::new(&bfuncs);
+            let mut sink = VecSink::with_capacity(0);
+            let mut elements = RowElements::new();
+            for (i, b) in bfuncs.iter().enumerate() {
+                prepared.row_into(i as u32, b, &table, &mut elements, &mut sink);
+            }
+            sink.into_coord_matrix(bfuncs.nonzero)
+        };
+        // the tables of the lattice are cached by the first builds
+        let chi = build(terms[1]);
+        drop(build(terms[0]));
+
+        let start = thread_allocated();
+        for n in 0..4000 {
+            let mat = build(terms[n % 2]);
+            assert!(mat.nnz() > 0);
+            match n % 4 {
+                0 | 1 => drop(mat),
+                2 => unsafe { request_free(mat.into_ffi()) },
+                _ => {
+                    let back = unsafe { OwnedCoordMatrix::from_ffi(mat.into_ffi()) };
+                    assert_eq!((back.col.clone(), back.row.clone()),
+                               (chi.col.clone(), chi.row.clone()));
+                    assert!(back.data
+                                .iter()
+                                .zip(chi.data.iter())
+                                .all(|(a, b)| (a.re, a.im) == (b.re, b.im)));
+                }
+            }
+        }
+        assert_eq!(thread_allocated(), start);
+
+        unsafe {
+            let empty = OwnedCoordMatrix::<f64>::from_ffi(CoordMatrix::empty());
+            assert_eq!((empty.nnz(), empty.nrows), (0, 0));
+            // a malformed matrix is not taken apart
+            let mut mat = chi.clone().into_ffi();
+            let data = mem::replace(&mut mat.data, Vector::new(ptr::null_mut(), 0));
+            let col = Vector::new(mat.col.ptr, mat.col.len);
+            let row = Vector::new(mat.row.ptr, mat.row.len);
+            assert_eq!(OwnedCoordMatrix::from_ffi(mat).nnz(), 0);
+            drop((data.into_vec(), col.into_vec(), row.into_vec()));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn metadata_test() {
+        use consv;
+        use std::{env, ffi::CStr, fs};
+        use {ks_sector_metadata_json, spinsys_string_free};
+
+        let ptr = ks_sector_metadata_json(4, 3, 1, 0, 6);
+        let json = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { spinsys_string_free(ptr) };
+        let sector = consv::ks::bloch_states(Dim(4), Dim(3), K(1), K(0), 6);
+        let dims = sector.unwrap().nonzero;
+        for field in ["\"nx\":4", "\"ny\":3", "\"kx\":1", "\"ky\":0", "\"nup\":6",
+                      "\"nnz\":null", "\"terms\":[]"].iter()
+        {
+            assert!(json.contains(field), "{} not in {}", field, json);
+        }
+        assert!(json.contains(&format!("\"dimension\":{}", dims)));
+        assert!(json.contains(env!("CARGO_PKG_VERSION")));
+
+        let bfuncs = consv::k::bloch_states(Dim(3), Dim(3), K(0), K(0)).unwrap();
+        let term = Term { kind:  TermKind::HSsXy,
+                          l:     I(2),
+                          coeff: 0.5 };
+        let metadata = Metadata::new(&bfuncs, K(0), K(0), None).with_terms(&[term]);
+        let path = env::temp_dir().join("spinsys_metadata_test.json");
+        metadata.write_metadata(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(written, metadata.to_json());
+        let terms = "\"terms\":[{\"kind\":\"h_ss_xy\",\"l\":2,\"coeff\":0.5}]";
+        assert!(written.contains(terms));
+        assert!(written.contains("\"nup\":null"));
+    }
+
+    #[test]
+    fn empty_ranges_give_zero_matrices() {
+        let none = null_mut();
+        unsafe {
+            // every pair of sites range l reaches is bonded at a shorter range
+            let empty = [(3, 3, 3), (6, 1, 3), (2, 2, 2), (2, 2, 3), (2, 3, 2)];
+            for &(nx, ny, l) in empty.iter() {
+                for &kind in [TermKind::HSsZ, TermKind::HSsXy, TermKind::HSsPpmm,
+                              TermKind::HSsPmz].iter()
+                {
+                    let term = CTerm { kind: kind as u32,
+                                       l,
+                                       coeff: 1. };
+                    let mut status = error::ERR_PANIC;
+                    let mat = k_term_matrix(nx, ny, 0, 0, term, &mut status);
+                    assert_eq!(status, error::SUCCESS);
+                    let zero = |&(_, _, re, im): &(u32, u32, f64, f64)| {
+                        re == 0. && im == 0.
+                    };
+                    assert!(elements(mat).iter().all(zero),
+                            "{}x{} l = {} {:?}",
+                            nx,
+                            ny,
+                            l,
+                            kind);
+                }
+                let xy = CTerm { kind:  TermKind::HSsXy as u32,
+                                 l,
+                                 coeff: 1. };
+                let mat = ks_term_matrix(nx, ny, 0, 0, nx * ny / 2, xy, none);
+                assert!(elements(mat).is_empty());
+            }
+        }
     }
 }
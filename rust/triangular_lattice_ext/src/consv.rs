@@ -1,125 +1,150 @@
-/// This module contains the following sub-modules:
-///     k
-///     ks
-///     ksl
+//! This module contains the following sub-modules:
+//!     k
+//!     ks
+//!     ksl
 
 /// This module contains functions that work under the assumption that lattice
-/// momentum is conserved.
+/// momentum is conserved. Every function checks the labels of the sector (see
+/// common::check_sector) and the "l" of its terms (see Term::check) before it
+/// builds anything, and fails with an error naming the first one out of range.
+/// The functions build on the plain torus with the phases of
+/// Convention::Plus, those ending in "_in" on the lattice with the settings
+/// they are given (see common::LatticeSettings) under the convention they are
+/// given (see blochfunc::Convention).
 pub mod k {
-    use fnv::FnvHashMap;
-    use num_complex::Complex;
+    use std::sync::Arc;
 
-    use blochfunc::{BlochFunc, BlochFuncSet};
+    use basiscache::{self, Sector};
+    use blochfunc::{BlochFuncSet, Convention};
     use common::*;
+    use error::Result;
     use ops;
+    use progress::Progress;
 
-    fn bloch_states<'a>(nx: Dim, ny: Dim, kx: K, ky: K) -> BlochFuncSet {
-        let n = nx * ny;
-        let mut sieve = vec![true; 2_usize.pow(n.raw_int())];
-        let mut bfuncs: Vec<BlochFunc> = Vec::new();
-        let phase = |i, j| {
-            let r = 1.;
-            let ang1 = 2. * PI * (i * kx.raw_int()) as f64 / nx.raw_int() as f64;
-            let ang2 = 2. * PI * (j * ky.raw_int()) as f64 / ny.raw_int() as f64;
-            Complex::from_polar(&r, &(ang1 + ang2))
-        };
-
-        for dec in 0..2_usize.pow(n.raw_int()) {
-            if sieve[dec] {
-                // if the corresponding entry of dec in "sieve" is not false,
-                // we find all translations of dec and put them in a BlochFunc
-                // then mark all corresponding entries in "sieve" as false.
-
-                // "decs" is a hashtable that holds vectors whose entries
-                // correspond to Bloch function constituent configurations which
-                // are mapped to single decimals that represent the leading states.
-                let mut decs: FnvHashMap<BinaryBasis, Complex<f64>> =
-                    FnvHashMap::default();
-                // "new_dec" represents the configuration we are currently iterating
-                // over.
-                let mut new_dec = BinaryBasis(dec as u64);
-                for j in 0..ny.raw_int() {
-                    for i in 0..nx.raw_int() {
-                        sieve[new_dec.raw_int() as usize] = false;
-                        let new_p = match decs.get(&new_dec) {
-                            Some(&p) => p + phase(i, j),
-                            None => phase(i, j)
-                        };
-                        decs.insert(new_dec, new_p);
-                        new_dec = translate_x(new_dec, nx, ny);
-                    }
-                    new_dec = translate_y(new_dec, nx, ny);
-                }
+    /// The basis of the (kx, ky) sector, taken from the basis cache if it is
+    /// enabled and holds it
+    pub fn bloch_states(nx: Dim, ny: Dim, kx: K, ky: K)
+                        -> Result<Arc<BlochFuncSet>> {
+        let torus = LatticeSettings::default();
+        bloch_states_in(nx, ny, &torus, Convention::Plus, kx, ky)
+    }
 
-                let lead = BinaryBasis(dec as u64);
-                let norm = decs.values()
-                               .into_iter()
-                               .map(|&x| x.norm_sqr())
-                               .sum::<f64>()
-                               .sqrt();
+    /// Same as bloch_states on the lattice with "settings" under "convention"
+    pub fn bloch_states_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                           convention: Convention, kx: K, ky: K)
+                           -> Result<Arc<BlochFuncSet>> {
+        let progress = &mut Progress::none();
+        bloch_states_with_progress(nx, ny, settings, convention, kx, ky, progress)
+    }
 
-                if norm > 1e-8 {
-                    let mut bfunc = BlochFunc { lead, decs, norm };
-                    bfuncs.push(bfunc);
-                }
-            }
-        }
+    /// Same as bloch_states_in, reporting the progress of the scan to
+    /// "progress" and scanning with its lookup. Fails if the build is
+    /// cancelled. Nothing is reported for a basis taken from the cache.
+    pub fn bloch_states_with_progress(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                                      convention: Convention, kx: K, ky: K,
+                                      progress: &mut Progress)
+                                      -> Result<Arc<BlochFuncSet>> {
+        check_sector(nx, ny, kx, ky, None)?;
+        let tables = lattice_tables(nx, ny, settings)?;
+        let sector = Sector::new(&tables, kx, ky, None);
+        basiscache::get_or_build(sector, progress.lookup(), convention, || {
+            let n = nx * ny;
+            let nstates = 1_usize.checked_shl(n.raw_int())
+                                 .expect("more configurations than addresses");
+            let state = |dec| BinaryBasis(dec as u64);
+            BlochFuncSet::scan(tables.clone(), kx, ky, convention, nstates, state,
+                               progress)
+        })
+    }
 
-        let mut table = BlochFuncSet::create(nx, ny, bfuncs);
-        table.sort();
-        table
+    fn build(nx: Dim, ny: Dim, kx: K, ky: K, term: Term)
+             -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        term_matrix(nx, ny, kx, ky, &term)
     }
 
-    pub fn h_ss_z(nx: Dim, ny: Dim, kx: K, ky: K, l: I)
-                  -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky);
-        let sites = interacting_sites(nx, ny, l);
-        ops::ss_z(&sites, &bfuncs)
+    pub fn h_ss_z(nx: Dim, ny: Dim, kx: K, ky: K, l: BondRange)
+                  -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, Term::new(TermKind::HSsZ, l.l()))
     }
 
-    pub fn h_ss_xy(nx: Dim, ny: Dim, kx: K, ky: K, l: I)
-                   -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky);
-        let sites = interacting_sites(nx, ny, l);
-        ops::ss_xy(&sites, &bfuncs)
+    pub fn h_ss_xy(nx: Dim, ny: Dim, kx: K, ky: K, l: BondRange)
+                   -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, Term::new(TermKind::HSsXy, l.l()))
     }
 
-    pub fn h_ss_ppmm(nx: Dim, ny: Dim, kx: K, ky: K, l: I)
-                     -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky);
-        let sites = interacting_sites(nx, ny, l);
-        ops::ss_ppmm(&sites, &bfuncs)
+    pub fn h_ss_ppmm(nx: Dim, ny: Dim, kx: K, ky: K, l: BondRange)
+                     -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, Term::new(TermKind::HSsPpmm, l.l()))
     }
 
-    pub fn h_ss_pmz(nx: Dim, ny: Dim, kx: K, ky: K, l: I)
-                    -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky);
-        let sites = interacting_sites(nx, ny, l);
-        ops::ss_pmz(&sites, &bfuncs)
+    pub fn h_ss_pmz(nx: Dim, ny: Dim, kx: K, ky: K, l: BondRange)
+                    -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, Term::new(TermKind::HSsPmz, l.l()))
     }
 
-    pub fn h_sss_chi(nx: Dim, ny: Dim, kx: K, ky: K) -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky);
-        let sites = triangular_vert_sites(nx, ny);
-        ops::sss_chi(&sites, &bfuncs)
+    pub fn h_sss_chi(nx: Dim, ny: Dim, kx: K, ky: K)
+                     -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, Term::new(TermKind::HSssChi, I(0)))
     }
 
-    pub fn ss_z(nx: Dim, ny: Dim, kx: K, ky: K, l: I) -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky);
-        let sites = all_sites(nx, ny, l);
-        ops::ss_z(&sites, &bfuncs)
+    /// The correlation of the spins "l" sites apart (see common::all_sites)
+    pub fn ss_z(nx: Dim, ny: Dim, kx: K, ky: K, l: I)
+                -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, Term::new(TermKind::SsZ, l))
     }
 
     pub fn ss_xy(nx: Dim, ny: Dim, kx: K, ky: K, l: I)
-                 -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky);
-        let sites = all_sites(nx, ny, l);
-        ops::ss_xy(&sites, &bfuncs)
+                 -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, Term::new(TermKind::SsXy, l))
+    }
+
+    pub fn term_matrix(nx: Dim, ny: Dim, kx: K, ky: K, term: &Term)
+                       -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        let torus = LatticeSettings::default();
+        term_matrix_in(nx, ny, &torus, Convention::Plus, kx, ky, term)
+    }
+
+    /// Same as term_matrix on the lattice with "settings" under "convention"
+    pub fn term_matrix_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                          convention: Convention, kx: K, ky: K, term: &Term)
+                          -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        check_sector(nx, ny, kx, ky, None)?;
+        term.check(nx, ny)?;
+        let bfuncs = bloch_states_in(nx, ny, settings, convention, kx, ky)?;
+        Ok(ops::term(term, &bfuncs))
+    }
+
+    /// Build each of "terms" as a matrix of its own, in one pass over the basis
+    /// (see ops::terms_vecs)
+    pub fn terms_matrices(nx: Dim, ny: Dim, kx: K, ky: K, terms: &[Term])
+                          -> Result<Vec<OwnedCoordMatrix<CComplex<f64>>>> {
+        check_sector(nx, ny, kx, ky, None)?;
+        for term in terms.iter() {
+            term.check(nx, ny)?;
+        }
+        let bfuncs = bloch_states(nx, ny, kx, ky)?;
+        let sinks = ops::terms_vecs(terms, &bfuncs);
+        Ok(sinks.into_iter()
+                .map(|sink| sink.into_coord_matrix(bfuncs.nonzero))
+                .collect())
+    }
+
+    /// The number of stored elements of "term", without building it
+    pub fn term_nnz(nx: Dim, ny: Dim, kx: K, ky: K, term: &Term) -> Result<u64> {
+        check_sector(nx, ny, kx, ky, None)?;
+        term.check(nx, ny)?;
+        let bfuncs = bloch_states(nx, ny, kx, ky)?;
+        Ok(ops::term_nnz(term, &bfuncs))
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
+        use error::Error;
+        use lanczos::hermitian_eigvals;
+        use num_complex::Complex;
+        use reference;
+        use validation::tridiagonal_eigvals;
 
         #[test]
         fn bloch_states_test() {
@@ -127,123 +152,1307 @@ pub mod k {
             let ny = Dim(4);
             let kx = K(1);
             let ky = K(3);
-            let bfuncs = bloch_states(nx, ny, kx, ky);
+            let bfuncs = bloch_states(nx, ny, kx, ky).unwrap();
             assert_eq!(bfuncs.nonzero, 4080);
         }
+
+        // H_pmz of the whole 3x3 space written out densely from its definition
+        // in the basis of the spin configurations, with the phases of the bonds
+        // from the nearest periodic images, against the union of the spectra
+        // of the momentum sectors
+        #[test]
+        fn h_ss_pmz_spectrum_test() {
+            use test_support::bond_from_images;
+            let (nx, ny) = (Dim(3), Dim(3));
+            let n = 1 << 9;
+            let (site1, site2) = interacting_sites(nx, ny, I(1));
+            let mut h = vec![Complex::new(0., 0.); n * n];
+            for (&s_1, &s_2) in site1.iter().zip(site2.iter()) {
+                let (m1, m2) = (site_index(s_1), site_index(s_2));
+                for &(a, b) in [(m1, m2), (m2, m1)].iter() {
+                    let gamma = bond_from_images(nx, ny, a, b).1;
+                    for dec in 0..n {
+                        let z = if dec >> a & 1 == 1 { 0.5 } else { -0.5 };
+                        let (new_dec, phase) = if dec >> b & 1 == 1 {
+                            (dec - (1 << b), gamma.conj())
+                        } else {
+                            (dec + (1 << b), -gamma)
+                        };
+                        h[dec * n + new_dec] += Complex::new(0., z) * phase;
+                    }
+                }
+            }
+            let expected = tridiagonal_eigvals(h, n).unwrap();
+
+            let mut spectrum = Vec::new();
+            for kx in 0..3 {
+                for ky in 0..3 {
+                    let term = Term::new(TermKind::HSsPmz, I(1));
+                    let mat = term_matrix(nx, ny, K(kx), K(ky), &term).unwrap();
+                    let dims = mat.ncols as usize;
+                    let mut block = vec![Complex::new(0., 0.); dims * dims];
+                    for ((&i, &j), c) in
+                        mat.row.iter().zip(mat.col.iter()).zip(mat.data.iter())
+                    {
+                        block[i as usize * dims + j as usize] +=
+                            Complex::new(c.re, c.im);
+                    }
+                    // hermitian to the last bit, as the Jacobi sweeps need
+                    let block =
+                        (0..dims * dims).map(|k| {
+                                            let t = k % dims * dims + k / dims;
+                                            (block[k] + block[t].conj()) / 2.
+                                        })
+                                        .collect::<Vec<_>>();
+                    spectrum.extend(hermitian_eigvals(&block, dims).unwrap());
+                }
+            }
+            spectrum.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(spectrum.len(), n);
+            for (x, y) in spectrum.iter().zip(expected.iter()) {
+                assert!((x - y).abs() < 1e-10);
+            }
+        }
+
+        /// The union of the spectra of the sum of "terms" in every momentum
+        /// sector of the nx by ny lattice, in ascending order
+        fn sector_spectrum(nx: u32, ny: u32, terms: &[Term]) -> Vec<f64> {
+            let (dx, dy) = (Dim(nx), Dim(ny));
+            let mut spectrum = Vec::new();
+            for kx in 0..nx {
+                for ky in 0..ny {
+                    let mats = terms_matrices(dx, dy, K(kx), K(ky), terms).unwrap();
+                    let dims = mats[0].ncols as usize;
+                    let mut block = vec![Complex::new(0., 0.); dims * dims];
+                    for mat in mats.iter() {
+                        for ((&i, &j), c) in mat.row
+                                                .iter()
+                                                .zip(mat.col.iter())
+                                                .zip(mat.data.iter())
+                        {
+                            block[i as usize * dims + j as usize] +=
+                                Complex::new(c.re, c.im);
+                        }
+                    }
+                    let block =
+                        (0..dims * dims).map(|k| {
+                                            let t = k % dims * dims + k / dims;
+                                            (block[k] + block[t].conj()) / 2.
+                                        })
+                                        .collect::<Vec<_>>();
+                    spectrum.extend(hermitian_eigvals(&block, dims).unwrap());
+                }
+            }
+            spectrum.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            spectrum
+        }
+
+        // The nearest neighbor Heisenberg model on the 2x4 and 4x2 lattices,
+        // written out densely from the bond list checked by hand, against the
+        // union of the spectra of the momentum sectors. Across the axis two
+        // sites wide each pair of sites is bonded once.
+        #[test]
+        fn heisenberg_two_wide_spectrum_test() {
+            use test_support::two_wide_bonds;
+            for &(nx, ny) in [(2, 4), (4, 2)].iter() {
+                let bonds = two_wide_bonds(nx, ny).into_iter()
+                                                  .map(|(a, b)| {
+                                                      (a as u32, b as u32, 1.)
+                                                  })
+                                                  .collect::<Vec<_>>();
+                let h = reference::heisenberg(nx * ny, &bonds).dense();
+                let expected = tridiagonal_eigvals(h, 1 << (nx * ny)).unwrap();
+
+                let terms = [Term::new(TermKind::HSsZ, I(1)),
+                             Term::new(TermKind::HSsXy, I(1))];
+                let spectrum = sector_spectrum(nx, ny, &terms);
+                assert_eq!(spectrum.len(), expected.len());
+                for (x, y) in spectrum.iter().zip(expected.iter()) {
+                    assert!((x - y).abs() < 1e-10, "{}x{}", nx, ny);
+                }
+            }
+        }
+
+        // The J1-J2 Heisenberg chain of six sites against the 6x1 and 1x6
+        // lattices, on which the second neighbors are the sites two apart along
+        // the chain and the third neighbors are all bonded at a shorter range
+        #[test]
+        fn heisenberg_chain_spectrum_test() {
+            let j2 = 0.37;
+            let mut bonds = Vec::new();
+            for i in 0..6 {
+                bonds.push((i, (i + 1) % 6, 1.));
+                bonds.push((i, (i + 2) % 6, j2));
+            }
+            let h = reference::heisenberg(6, &bonds).dense();
+            let expected = tridiagonal_eigvals(h, 1 << 6).unwrap();
+
+            let term = |kind, l, coeff| Term { kind, l: I(l), coeff };
+            let terms = [term(TermKind::HSsZ, 1, 1.),
+                         term(TermKind::HSsXy, 1, 1.),
+                         term(TermKind::HSsZ, 2, j2),
+                         term(TermKind::HSsXy, 2, j2),
+                         term(TermKind::HSsZ, 3, 1.),
+                         term(TermKind::HSsXy, 3, 1.)];
+            for &(nx, ny) in [(6, 1), (1, 6)].iter() {
+                let spectrum = sector_spectrum(nx, ny, &terms);
+                assert_eq!(spectrum.len(), expected.len());
+                for (x, y) in spectrum.iter().zip(expected.iter()) {
+                    assert!((x - y).abs() < 1e-10, "{}x{}", nx, ny);
+                }
+            }
+        }
+
+        #[test]
+        fn builders_are_hermitian() {
+            use test_support::assert_hermitian;
+            use {k_h_ss_pmz, k_h_ss_ppmm, k_h_ss_xy, k_h_ss_z, k_h_sss_chi, k_ss_xy,
+                 k_ss_z};
+            for &(nx, ny) in [(3, 3), (4, 3)].iter() {
+                for kx in 0..nx {
+                    for ky in 0..ny {
+                        let at = |name| format!("{} {}x{} k = ({}, {})", name, nx,
+                                                ny, kx, ky);
+                        assert_hermitian(k_h_sss_chi(nx, ny, kx, ky), &at("chi"));
+                        for l in 1..4 {
+                            let at = |name| format!("{} l = {}", at(name), l);
+                            assert_hermitian(k_h_ss_z(nx, ny, kx, ky, l), &at("z"));
+                            assert_hermitian(k_h_ss_xy(nx, ny, kx, ky, l),
+                                             &at("xy"));
+                            assert_hermitian(k_h_ss_ppmm(nx, ny, kx, ky, l),
+                                             &at("ppmm"));
+                            assert_hermitian(k_h_ss_pmz(nx, ny, kx, ky, l),
+                                             &at("pmz"));
+                        }
+                        for l in 0..nx * ny {
+                            let at = |name| format!("{} l = {}", at(name), l);
+                            assert_hermitian(k_ss_z(nx, ny, kx, ky, l), &at("ss_z"));
+                            assert_hermitian(k_ss_xy(nx, ny, kx, ky, l),
+                                             &at("ss_xy"));
+                        }
+                    }
+                }
+            }
+
+            // a matrix that is not there or not square is refused
+            let mut status = 0;
+            let null = ::std::ptr::null();
+            let asymmetry = unsafe { ::check_hermiticity(null, &mut status) };
+            assert!(asymmetry.is_nan());
+            assert_eq!(status, ::error::ERR_INVALID_ARGUMENT);
+            let mut mat = k_h_ss_xy(3, 3, 0, 0, 1);
+            mat.ncols += 1;
+            status = 0;
+            let asymmetry = unsafe { ::check_hermiticity(&mat, &mut status) };
+            assert!(asymmetry.is_nan());
+            assert_eq!(status, ::error::ERR_INVALID_ARGUMENT);
+            mat.ncols -= 1;
+            unsafe { ::request_free(mat) };
+        }
+
+        /// Run with --ignored --nocapture to time the build
+        #[test]
+        #[ignore]
+        fn h_ss_ppmm_bench() {
+            use std::time::Instant;
+            let bfuncs = bloch_states(Dim(5), Dim(4), K(0), K(0)).unwrap();
+            let term = Term::new(TermKind::HSsPpmm, I(1));
+            let start = Instant::now();
+            let vecs = ops::term_vecs(&term, &bfuncs);
+            println!("5x4 ppmm: {} elements in {:?}",
+                     vecs.data.len(),
+                     start.elapsed());
+        }
+
+        fn same_matrices(a: &OwnedCoordMatrix<CComplex<f64>>,
+                         b: &OwnedCoordMatrix<CComplex<f64>>)
+                         -> bool {
+            let bits = |m: &OwnedCoordMatrix<CComplex<f64>>| {
+                m.data
+                 .iter()
+                 .map(|d| (d.re.to_bits(), d.im.to_bits()))
+                 .collect::<Vec<_>>()
+            };
+            a.row == b.row && a.col == b.col && bits(a) == bits(b) &&
+            a.nrows == b.nrows && a.ncols == b.ncols
+        }
+
+        fn assert_invalid<T>(result: Result<T>, name: &str) {
+            match result {
+                Err(Error::InvalidArgument(arg)) => assert_eq!(arg, name),
+                Err(e) => panic!("expected an invalid {}, got {}", name, e),
+                Ok(_) => panic!("expected an invalid {}", name)
+            }
+        }
+
+        #[test]
+        fn invalid_parameters_test() {
+            let (nx, ny) = (Dim(4), Dim(3));
+            let nn = BondRange::NearestNeighbor;
+            assert_invalid(bloch_states(nx, ny, K(4), K(0)), "kx");
+            assert_invalid(bloch_states(nx, ny, K(0), K(3)), "ky");
+            assert_invalid(h_ss_z(nx, ny, K(4), K(0), nn), "kx");
+            assert_invalid(h_ss_ppmm(nx, ny, K(0), K(3), nn), "ky");
+            assert_invalid(h_sss_chi(nx, ny, K(9), K(0)), "kx");
+            assert_invalid(ss_z(nx, ny, K(0), K(0), I(12)), "l");
+            assert_invalid(ss_xy(nx, ny, K(0), K(0), I(-1)), "l");
+            let term = Term::new(TermKind::HSsPmz, I(0));
+            assert_invalid(term_matrix(nx, ny, K(0), K(0), &term), "l");
+            assert_invalid(term_nnz(nx, ny, K(0), K(0), &term), "l");
+            let terms = [Term::new(TermKind::HSsZ, I(1)), term];
+            assert_invalid(terms_matrices(nx, ny, K(0), K(0), &terms), "l");
+            assert_invalid(terms_matrices(nx, ny, K(0), K(3), &terms[..1]), "ky");
+        }
+
+        #[test]
+        fn terms_matrices_test() {
+            let (nx, ny, kx, ky) = (Dim(4), Dim(3), K(1), K(2));
+            let terms = [Term::new(TermKind::HSsZ, I(1)),
+                         Term::new(TermKind::HSsXy, I(1)),
+                         Term::new(TermKind::HSsPpmm, I(1)),
+                         Term::new(TermKind::HSsPmz, I(1)),
+                         Term::new(TermKind::HSssChi, I(1)),
+                         Term::new(TermKind::HSsZ, I(2)),
+                         Term::new(TermKind::HSsPpmm, I(2)),
+                         Term { kind: TermKind::HSsXy, l: I(1), coeff: 0.5 },
+                         Term::new(TermKind::SsZ, I(1))];
+            let mats = terms_matrices(nx, ny, kx, ky, &terms).unwrap();
+            assert_eq!(mats.len(), terms.len());
+            for (term, mat) in terms.iter().zip(mats.iter()) {
+                let single = term_matrix(nx, ny, kx, ky, term).unwrap();
+                assert!(same_matrices(mat, &single), "{:?}", term.kind);
+            }
+        }
+
+        /// Run with --ignored --nocapture to compare the batch build with
+        /// separate builds
+        #[test]
+        #[ignore]
+        fn terms_matrices_bench() {
+            use std::time::Instant;
+            let bfuncs = bloch_states(Dim(6), Dim(4), K(0), K(0)).unwrap();
+            let terms = [Term::new(TermKind::HSsZ, I(1)),
+                         Term::new(TermKind::HSsXy, I(1)),
+                         Term::new(TermKind::HSsPpmm, I(1))];
+            let start = Instant::now();
+            for term in terms.iter() {
+                ops::term_vecs(term, &bfuncs);
+            }
+            println!("6x4 separate: {:?}", start.elapsed());
+            let start = Instant::now();
+            ops::terms_vecs(&terms, &bfuncs);
+            println!("6x4 batch: {:?}", start.elapsed());
+        }
     }
 }
 
 /// This module contains functions that work under the assumption that lattice
-/// momentum and total Sz are conserved.
+/// momentum and total Sz are conserved. The parameters are checked as in the k
+/// module, "nup" included, and the lattice is the plain torus with the phases
+/// of Convention::Plus unless the function takes settings and a convention, as
+/// there.
 pub mod ks {
-    use fnv::FnvHashMap;
     use num_complex::Complex;
+    use std::{cmp, ops::Range, path::Path, sync::Arc};
 
-    use blochfunc::{BlochFunc, BlochFuncSet};
+    use basiscache::{self, Sector};
+    use blochfunc::{self, BlochFuncSet, Convention, Lookup};
     use common::*;
-    use ops;
+    use error::{Error, Result};
+    use ops::{self, SliceSink, VecSink};
+    use progress::Progress;
+    use spill::SpillSink;
+
+    /// The basis of the (kx, ky, nup) sector, taken from the basis cache if it
+    /// is enabled and holds it
+    pub fn bloch_states(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32)
+                        -> Result<Arc<BlochFuncSet>> {
+        let torus = LatticeSettings::default();
+        bloch_states_in(nx, ny, &torus, Convention::Plus, kx, ky, nup)
+    }
+
+    /// Same as bloch_states on the lattice with "settings" under "convention"
+    pub fn bloch_states_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                           convention: Convention, kx: K, ky: K, nup: u32)
+                           -> Result<Arc<BlochFuncSet>> {
+        let progress = &mut Progress::none();
+        bloch_states_with_progress(nx, ny, settings, convention, kx, ky, nup,
+                                   progress)
+    }
+
+    /// Same as bloch_states_in, reporting the progress of the scan through the
+    /// Sz basis to "progress" and scanning with its lookup. Fails if the build
+    /// is cancelled. Nothing is reported for a basis taken from the cache.
+    pub fn bloch_states_with_progress(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                                      convention: Convention, kx: K, ky: K,
+                                      nup: u32, progress: &mut Progress)
+                                      -> Result<Arc<BlochFuncSet>> {
+        check_sector(nx, ny, kx, ky, Some(nup))?;
+        let tables = lattice_tables(nx, ny, settings)?;
+        let sector = Sector::new(&tables, kx, ky, Some(nup));
+        basiscache::get_or_build(sector, progress.lookup(), convention, || {
+            let n = nx * ny;
+
+            // the orbit scan needs the configurations in ascending order
+            let mut sz_basis_states = sz_basis_with_progress(n, nup, progress)?;
+            sz_basis_states.sort_unstable();
+            let nstates = sz_basis_states.len();
+            let state = |i| sz_basis_states[i];
+            BlochFuncSet::scan(tables.clone(), kx, ky, convention, nstates, state,
+                               progress)
+        })
+    }
+
+    // the parameters of "term" in the (kx, ky, nup) sector, in the order they
+    // are passed
+    fn check(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term) -> Result<()> {
+        check_sector(nx, ny, kx, ky, Some(nup))?;
+        term.check(nx, ny)
+    }
+
+    fn build(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: Term)
+             -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        check(nx, ny, kx, ky, nup, &term)?;
+        let bfuncs = bloch_states(nx, ny, kx, ky, nup)?;
+        Ok(ops::term(&term, &bfuncs))
+    }
+
+    pub fn h_ss_z(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: BondRange)
+                  -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, nup, Term::new(TermKind::HSsZ, l.l()))
+    }
 
-    fn bloch_states<'a>(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32) -> BlochFuncSet {
-        let n = nx * ny;
+    pub fn h_ss_xy(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: BondRange)
+                   -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, nup, Term::new(TermKind::HSsXy, l.l()))
+    }
+
+    pub fn h_sss_chi(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32)
+                     -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, nup, Term::new(TermKind::HSssChi, I(0)))
+    }
+
+    /// The correlation of the spins "l" sites apart (see common::all_sites)
+    pub fn ss_z(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: I)
+                -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, nup, Term::new(TermKind::SsZ, l))
+    }
+
+    pub fn ss_xy(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: I)
+                 -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        build(nx, ny, kx, ky, nup, Term::new(TermKind::SsXy, l))
+    }
+
+    /// Build "term". Fails if the term does not conserve total Sz.
+    pub fn term_matrix(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term)
+                       -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        let torus = LatticeSettings::default();
+        term_matrix_with_progress(nx, ny, &torus, Convention::Plus, kx, ky, nup,
+                                  term, &mut Progress::none())
+    }
 
-        let sz_basis_states = sz_basis(n, nup);
-        let mut szdec_to_ind: FnvHashMap<BinaryBasis, usize> = FnvHashMap::default();
-        let mut ind_to_szdec: FnvHashMap<usize, BinaryBasis> = FnvHashMap::default();
-        for (i, &bs) in sz_basis_states.iter().enumerate() {
-            ind_to_szdec.insert(i, bs);
-            szdec_to_ind.insert(bs, i);
+    /// Same as term_matrix on the lattice with "settings" under "convention",
+    /// reporting the progress of the basis construction and of the element
+    /// generation to "progress". Fails if the build is cancelled.
+    pub fn term_matrix_with_progress(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                                     convention: Convention, kx: K, ky: K,
+                                     nup: u32, term: &Term, progress: &mut Progress)
+                                     -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        check(nx, ny, kx, ky, nup, term)?;
+        if !term.kind.conserves_sz() {
+            return Err(Error::InvalidTerm(term.kind as u32));
         }
+        let bfuncs = bloch_states_with_progress(nx, ny, settings, convention, kx,
+                                                ky, nup, progress)?;
+        let sink = ops::term_vecs_with_progress(term, &bfuncs, progress)?;
+        Ok(sink.into_coord_matrix(bfuncs.nonzero))
+    }
 
-        let mut sieve = vec![true; sz_basis_states.len()];
-        let mut bfuncs: Vec<BlochFunc> = Vec::new();
-        let phase = |i, j| {
-            let r = 1.;
-            let ang1 = 2. * PI * (i * kx.raw_int()) as f64 / nx.raw_int() as f64;
-            let ang2 = 2. * PI * (j * ky.raw_int()) as f64 / ny.raw_int() as f64;
-            Complex::from_polar(&r, &(ang1 + ang2))
+    /// Same as term_matrix, holding at most "max_bytes" of elements in memory
+    /// while the basis is around and spilling the rest to a scratch file in
+    /// "dir" (see the spill module). The basis is released before the result
+    /// is read back, unless the basis cache keeps it.
+    pub fn term_matrix_spilled(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                               term: &Term, max_bytes: usize, dir: &Path)
+                               -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        check(nx, ny, kx, ky, nup, term)?;
+        if !term.kind.conserves_sz() {
+            return Err(Error::InvalidTerm(term.kind as u32));
+        }
+        let mut sink = SpillSink::new(max_bytes, dir);
+        let dims = {
+            let bfuncs = bloch_states(nx, ny, kx, ky, nup)?;
+            ops::term_into(term, &bfuncs, &mut sink);
+            bfuncs.nonzero
         };
+        sink.into_coord_matrix(dims)
+    }
 
-        for ind in 0..sieve.len() {
-            if sieve[ind] {
-                // if the corresponding entry of dec in "sieve" is not false,
-                // we find all translations of dec and put them in a BlochFunc
-                // then mark all corresponding entries in "sieve" as false.
-
-                // "decs" is a hashtable that holds vectors whose entries
-                // correspond to Bloch function constituent configurations which
-                // are mapped to single decimals that represent the leading states.
-                let mut decs: FnvHashMap<BinaryBasis, Complex<f64>> =
-                    FnvHashMap::default();
-                // "new_dec" represents the configuration we are currently iterating
-                // over.
-                let dec = *ind_to_szdec.get(&ind).unwrap();
-                let mut new_dec = dec;
-                let mut new_ind = ind;
-                for j in 0..ny.raw_int() {
-                    for i in 0..nx.raw_int() {
-                        sieve[new_ind as usize] = false;
-                        let new_p = match decs.get(&new_dec) {
-                            Some(&p) => p + phase(i, j),
-                            None => phase(i, j)
-                        };
-                        decs.insert(new_dec, new_p);
-                        new_dec = translate_x(new_dec, nx, ny);
-                        new_ind = *szdec_to_ind.get(&new_dec).unwrap() as usize;
-                    }
-                    new_dec = translate_y(new_dec, nx, ny);
-                }
+    /// Build "term" with real elements (see ops::term_real). Fails without
+    /// building the basis unless the term is real and (kx, ky) has real
+    /// phases.
+    pub fn term_real(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term)
+                     -> Result<OwnedCoordMatrix<f64>> {
+        check(nx, ny, kx, ky, nup, term)?;
+        if !term.kind.is_real() {
+            return Err(Error::InvalidTerm(term.kind as u32));
+        }
+        if !blochfunc::real_momentum(nx, ny, 0, kx, ky) {
+            return Err(Error::InvalidArgument("kx, ky"));
+        }
+        let bfuncs = bloch_states(nx, ny, kx, ky, nup)?;
+        ops::term_real(term, &bfuncs)
+    }
 
-                let lead = dec;
-                let norm = decs.values()
-                               .into_iter()
-                               .map(|&x| x.norm_sqr())
-                               .sum::<f64>()
-                               .sqrt();
+    /// Build each of "terms" as a matrix of its own, in one pass over the basis
+    /// (see ops::terms_vecs). Fails if any of the terms does not conserve total
+    /// Sz.
+    pub fn terms_matrices(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term])
+                          -> Result<Vec<OwnedCoordMatrix<CComplex<f64>>>> {
+        let torus = LatticeSettings::default();
+        terms_matrices_in(nx, ny, &torus, Convention::Plus, kx, ky, nup, terms)
+    }
 
-                if norm > 1e-8 {
-                    let mut bfunc = BlochFunc { lead, decs, norm };
-                    bfuncs.push(bfunc);
-                }
-            }
+    /// Same as terms_matrices on the lattice with "settings" under "convention"
+    pub fn terms_matrices_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                             convention: Convention, kx: K, ky: K, nup: u32,
+                             terms: &[Term])
+                             -> Result<Vec<OwnedCoordMatrix<CComplex<f64>>>> {
+        check_sector(nx, ny, kx, ky, Some(nup))?;
+        for term in terms.iter() {
+            term.check(nx, ny)?;
+        }
+        if let Some(term) = terms.iter().find(|t| !t.kind.conserves_sz()) {
+            return Err(Error::InvalidTerm(term.kind as u32));
         }
+        let bfuncs = bloch_states_in(nx, ny, settings, convention, kx, ky, nup)?;
+        let sinks = ops::terms_vecs(terms, &bfuncs);
+        Ok(sinks.into_iter()
+                .map(|sink| sink.into_coord_matrix(bfuncs.nonzero))
+                .collect())
+    }
 
-        let mut table = BlochFuncSet::create(nx, ny, bfuncs);
-        table.sort();
-        table
+    /// The number of stored elements of "term", without building it. Fails if
+    /// the term does not conserve total Sz.
+    pub fn term_nnz(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term)
+                    -> Result<u64> {
+        check(nx, ny, kx, ky, nup, term)?;
+        if !term.kind.conserves_sz() {
+            return Err(Error::InvalidTerm(term.kind as u32));
+        }
+        let bfuncs = bloch_states(nx, ny, kx, ky, nup)?;
+        Ok(ops::term_nnz(term, &bfuncs))
     }
 
-    pub fn h_ss_z(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: I)
-                  -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky, nup);
-        let sites = interacting_sites(nx, ny, l);
-        ops::ss_z(&sites, &bfuncs)
+    /// Generate "term" directly into the arrays of a coordinate matrix provided
+    /// by the caller, in the order ops::term would store the elements, and
+    /// return the number of elements. Fails if the term does not conserve
+    /// total Sz, or if the arrays are shorter than term_nnz, in which case they
+    /// hold only the elements that fit.
+    pub fn term_into_slices(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                            term: &Term, data: &mut [CComplex<f64>],
+                            cols: &mut [u32], rows: &mut [u32])
+                            -> Result<usize> {
+        check(nx, ny, kx, ky, nup, term)?;
+        if !term.kind.conserves_sz() {
+            return Err(Error::InvalidTerm(term.kind as u32));
+        }
+        let bfuncs = bloch_states(nx, ny, kx, ky, nup)?;
+        let mut sink = SliceSink::new(data, cols, rows);
+        ops::term_into(term, &bfuncs, &mut sink);
+        sink.finish()
     }
 
-    pub fn h_ss_xy(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: I)
-                   -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky, nup);
-        let sites = interacting_sites(nx, ny, l);
-        ops::ss_xy(&sites, &bfuncs)
+    /// Build "term" as a dense matrix. Fails if the dimension of the sector
+    /// exceeds ops::dense_max_dim().
+    pub fn term_dense(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term)
+                      -> Result<DenseMatrix<CComplex<f64>>> {
+        check(nx, ny, kx, ky, nup, term)?;
+        let bfuncs = bloch_states(nx, ny, kx, ky, nup)?;
+        ops::term_dense(term, &bfuncs, ops::dense_max_dim())
     }
 
-    pub fn h_sss_chi(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32)
-                     -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky, nup);
-        let sites = triangular_vert_sites(nx, ny);
-        ops::sss_chi(&sites, &bfuncs)
+    /// Build only the rows in "rows" of "term". Row and column indices are
+    /// global so the pieces of a matrix built on different ranks simply
+    /// concatenate. If "basis_path" is given the basis is read from a file
+    /// written by BlochFuncSet::save instead of being constructed from
+    /// scratch.
+    pub fn term_rows(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term,
+                     rows: Range<u32>, basis_path: Option<&str>)
+                     -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        check(nx, ny, kx, ky, nup, term)?;
+        let bfuncs = match basis_path {
+            Some(path) => {
+                let lookup = Lookup::Members;
+                Arc::new(BlochFuncSet::load(path, nx, ny, kx, ky, nup, lookup)?)
+            }
+            None => bloch_states(nx, ny, kx, ky, nup)?
+        };
+        let dims = bfuncs.nonzero;
+        if rows.start > rows.end {
+            return Err(Error::InvalidArgument("rows"));
+        }
+        let rows = cmp::min(rows.start, dims)..cmp::min(rows.end, dims);
+
+        let mut sink =
+            VecSink::with_capacity(ops::nnz_bound(term, &bfuncs, rows.clone()));
+        ops::term_rows_into(term, &bfuncs, rows, &mut sink)?;
+        Ok(sink.into_coord_matrix(dims))
     }
 
-    pub fn ss_z(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: I)
-                -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky, nup);
-        let sites = all_sites(nx, ny, l);
-        ops::ss_z(&sites, &bfuncs)
+    /// Expand "psi", given in the reduced basis of the sector, into the product
+    /// states it is made of, sorted by decimal label. Configurations whose
+    /// orbit is incompatible with (kx, ky) are left out, so there are at most
+    /// choose(nx * ny, nup) of them.
+    pub fn expand_state(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                        psi: &[Complex<f64>])
+                        -> Result<Vec<(BinaryBasis, Complex<f64>)>> {
+        bloch_states(nx, ny, kx, ky, nup)?.expand(psi)
     }
 
-    pub fn ss_xy(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, l: I)
-                 -> CoordMatrix<CComplex<f64>> {
-        let bfuncs = bloch_states(nx, ny, kx, ky, nup);
-        let sites = all_sites(nx, ny, l);
-        ops::ss_xy(&sites, &bfuncs)
+    /// Expand "psi" into the basis of all choose(nx * ny, nup) configurations
+    /// with "nup" up spins, ordered by ascending decimal label. Configurations
+    /// left out by expand_state get a zero amplitude.
+    pub fn expand_state_sz(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                           psi: &[Complex<f64>])
+                           -> Result<Vec<Complex<f64>>> {
+        let states = expand_state(nx, ny, kx, ky, nup, psi)?;
+        let mut decs = sz_basis(nx * ny, nup);
+        decs.sort();
+        let mut full = vec![Complex::new(0., 0.); decs.len()];
+        for (dec, amp) in states.into_iter() {
+            // every configuration of an orbit has the same number of up spins
+            let i = decs.binary_search(&dec).unwrap();
+            full[i] = amp;
+        }
+        Ok(full)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use fnv::FnvHashMap;
+        use lanczos::LinearOperator;
+        use std::{env, ptr};
+
+        fn triplets(mat: OwnedCoordMatrix<CComplex<f64>>)
+                    -> Vec<(u32, u32, f64, f64)> {
+            mat.row
+               .iter()
+               .zip(mat.col.iter())
+               .zip(mat.data.iter())
+               .map(|((&r, &c), d)| (r, c, d.re, d.im))
+               .collect()
+        }
+
+        #[test]
+        fn builders_are_hermitian() {
+            use test_support::assert_hermitian;
+            use {ks_h_ss_xy, ks_h_ss_z, ks_h_sss_chi, ks_ss_xy, ks_ss_z};
+            for &(nx, ny) in [(3, 3), (4, 3)].iter() {
+                for kx in 0..nx {
+                    for ky in 0..ny {
+                        for nup in 0..=nx * ny {
+                            let at = |name| format!("{} {}x{} k = ({}, {}) nup = {}",
+                                                    name, nx, ny, kx, ky, nup);
+                            assert_hermitian(ks_h_sss_chi(nx, ny, kx, ky, nup),
+                                             &at("chi"));
+                            for l in 1..4 {
+                                let at = |name| format!("{} l = {}", at(name), l);
+                                assert_hermitian(ks_h_ss_z(nx, ny, kx, ky, nup, l),
+                                                 &at("z"));
+                                assert_hermitian(ks_h_ss_xy(nx, ny, kx, ky, nup, l),
+                                                 &at("xy"));
+                                assert_hermitian(ks_ss_z(nx, ny, kx, ky, nup, l),
+                                                 &at("ss_z"));
+                                assert_hermitian(ks_ss_xy(nx, ny, kx, ky, nup, l),
+                                                 &at("ss_xy"));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        /// The dimension of the (kx, ky, nup) sector by Burnside's lemma
+        /// weighted with the characters of the momentum: N^-1 Σ_g e^(-ik·g)
+        /// |Fix(g)|, where a translation g of period m splits the lattice into
+        /// N / m cycles and fixes the configurations of nup / m of those up
+        fn burnside_dim(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32) -> u64 {
+            fn gcd(a: u32, b: u32) -> u32 {
+                if b == 0 {
+                    a
+                } else {
+                    gcd(b, a % b)
+                }
+            }
+            let n = nx * ny;
+            let mut sum = 0.;
+            for i in 0..nx {
+                for j in 0..ny {
+                    let (px, py) = (nx / gcd(i, nx), ny / gcd(j, ny));
+                    let period = px * py / gcd(px, py);
+                    if nup % period > 0 {
+                        continue;
+                    }
+                    let fixed = binomial(n / period, nup / period) as f64;
+                    let ang = 2. * PI * (f64::from(i * kx) / f64::from(nx)
+                                         + f64::from(j * ky) / f64::from(ny));
+                    sum += fixed * ang.cos();
+                }
+            }
+            (sum / f64::from(n)).round() as u64
+        }
+
+        fn binomial(n: u32, k: u32) -> u64 {
+            (0..k).fold(1, |acc, i| acc * u64::from(n - i) / u64::from(i + 1))
+        }
+
+        #[test]
+        fn sector_dims_match_burnside() {
+            for &(nx, ny) in [(4, 4), (6, 2)].iter() {
+                let n = nx * ny;
+                for nup in 0..=n {
+                    let mut total = 0;
+                    for kx in 0..nx {
+                        for ky in 0..ny {
+                            let bfuncs =
+                                bloch_states(Dim(nx), Dim(ny), K(kx), K(ky), nup)
+                                    .unwrap();
+                            let dim = bfuncs.data.len() as u64;
+                            assert_eq!(dim,
+                                       burnside_dim(nx, ny, kx, ky, nup),
+                                       "{}x{} k = ({}, {}) nup = {}",
+                                       nx,
+                                       ny,
+                                       kx,
+                                       ky,
+                                       nup);
+                            total += dim;
+                        }
+                    }
+                    let states = binomial(n, nup);
+                    assert_eq!(total, states, "{}x{} nup = {}", nx, ny, nup);
+                }
+            }
+        }
+
+        /// A single flipped spin in the ferromagnet hops to each nearest
+        /// neighbor with amplitude J / 2 under the exchange, and under the
+        /// chirality with amplitude 3i / 2 along the corners of the triangles
+        /// in the order triangular_vert_sites lists them, i.e. along +x, y - x
+        /// and -y, and -3i / 2 back. With the amplitude e^(i p·r) on the
+        /// site r = (x, y) the magnon has the energy
+        /// J (3N / 4 - 3) + Σ_d (J cos p·d + 3 chi sin p·d) over those three
+        /// steps d, where p = ±2π (kx / nx, -ky / ny) under Convention::Plus
+        /// and Minus respectively since the translations run along -y. The
+        /// chirality makes the dispersion odd in p and tells the two apart.
+        #[test]
+        fn single_magnon_dispersion_test() {
+            let (nx, ny) = (4, 4);
+            let n = nx * ny;
+            let (j, chi) = (1., 0.3);
+            let terms = [Term { kind:  TermKind::HSsZ,
+                                l:     I(1),
+                                coeff: j },
+                         Term { kind:  TermKind::HSsXy,
+                                l:     I(1),
+                                coeff: j },
+                         Term { kind:  TermKind::HSssChi,
+                                l:     I(0),
+                                coeff: chi }];
+            let steps = [(1., 0.), (-1., 1.), (0., -1.)];
+            let conventions = [(Convention::Plus, 1.), (Convention::Minus, -1.)];
+            let torus = LatticeSettings::default();
+            for &(convention, sign) in conventions.iter() {
+                for kx in 0..nx {
+                    for ky in 0..ny {
+                        let bfuncs = bloch_states_in(Dim(nx), Dim(ny), &torus,
+                                                     convention, K(kx), K(ky),
+                                                     n - 1).unwrap();
+                        assert_eq!(bfuncs.data.len(), 1);
+                        let energy =
+                            terms.iter()
+                                 .flat_map(|t| ops::term_vecs(t, &bfuncs).data)
+                                 .fold(Complex::new(0., 0.), |a, b| {
+                                     a + Complex::new(b.re, b.im)
+                                 });
+
+                        let px = sign * 2. * PI * f64::from(kx) / f64::from(nx);
+                        let py = -sign * 2. * PI * f64::from(ky) / f64::from(ny);
+                        let expected = steps.iter()
+                                            .map(|&(dx, dy)| {
+                                                let pd = px * dx + py * dy;
+                                                j * pd.cos() + 3. * chi * pd.sin()
+                                            })
+                                            .sum::<f64>()
+                                       + j * (0.75 * f64::from(n) - 3.);
+                        assert!((energy.re - expected).abs() < 1e-12
+                                && energy.im.abs() < 1e-12,
+                                "{:?} k = ({}, {}): {} against {}",
+                                convention,
+                                kx,
+                                ky,
+                                energy,
+                                expected);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn terms_matrices_test() {
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+            let terms = [Term::new(TermKind::HSsZ, I(1)),
+                         Term::new(TermKind::HSsXy, I(1)),
+                         Term::new(TermKind::HSssChi, I(1)),
+                         Term::new(TermKind::HSsXy, I(2)),
+                         Term::new(TermKind::SsZ, I(2))];
+            let mats = terms_matrices(nx, ny, kx, ky, nup, &terms).unwrap();
+            for (term, mat) in terms.iter().zip(mats.iter()) {
+                let single = term_matrix(nx, ny, kx, ky, nup, term).unwrap();
+                assert_eq!(mat.row, single.row);
+                assert_eq!(mat.col, single.col);
+                assert!(mat.data
+                           .iter()
+                           .zip(single.data.iter())
+                           .all(|(a, b)| a.re.to_bits() == b.re.to_bits() &&
+                                         a.im.to_bits() == b.im.to_bits()));
+            }
+            let ppmm = [Term::new(TermKind::HSsZ, I(1)),
+                        Term::new(TermKind::HSsPpmm, I(1))];
+            assert!(terms_matrices(nx, ny, kx, ky, nup, &ppmm).is_err());
+        }
+
+        #[test]
+        fn term_dense_test() {
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+            for &kind in [TermKind::HSsZ, TermKind::HSsXy, TermKind::HSssChi].iter()
+            {
+                let term = Term::new(kind, I(1));
+                let dense = term_dense(nx, ny, kx, ky, nup, &term).unwrap();
+                let n = dense.n as usize;
+                let data = unsafe { dense.data.as_slice() };
+                let mut expected = vec![(0., 0.); n * n];
+                let bfuncs = bloch_states(nx, ny, kx, ky, nup).unwrap();
+                let mat = ops::term(&term, &bfuncs);
+                for (r, c, re, im) in triplets(mat) {
+                    // (data, (col, row)), column-major
+                    let elem = &mut expected[c as usize + r as usize * n];
+                    elem.0 += re;
+                    elem.1 += im;
+                }
+                for (d, e) in data.iter().zip(expected.iter()) {
+                    assert!((d.re - e.0).abs() < 1e-12
+                            && (d.im - e.1).abs() < 1e-12);
+                }
+                unsafe { ::dense_matrix_free(dense) };
+            }
+        }
+
+        #[test]
+        fn term_dense_size_guard_test() {
+            let bfuncs = bloch_states(Dim(4), Dim(3), K(0), K(0), 6).unwrap();
+            let term = Term::new(TermKind::HSsXy, I(1));
+            match ops::term_dense(&term, &bfuncs, bfuncs.nonzero - 1) {
+                Err(Error::TooLarge(n)) => assert_eq!(n, bfuncs.nonzero),
+                _ => panic!("expected the size guard to trip")
+            }
+        }
+
+        #[test]
+        fn term_real_test() {
+            let (nx, ny, nup) = (4, 3, 6);
+            let c_term = |kind: TermKind| {
+                CTerm { kind:  kind as u32,
+                        l:     1,
+                        coeff: 1. }
+            };
+            let mut status = 1;
+            for &kx in [0, 2].iter() {
+                let mat = unsafe {
+                    let term = c_term(TermKind::HSsXy);
+                    ::ks_term_matrix_real(nx, ny, kx, 0, nup, term, &mut status)
+                };
+                assert_eq!(status, ::error::SUCCESS);
+                let mat = unsafe { OwnedCoordMatrix::from_ffi(mat) };
+                let found = mat.row
+                               .iter()
+                               .zip(mat.col.iter())
+                               .zip(mat.data.iter())
+                               .map(|((&r, &c), &d)| (r, c, d, 0.))
+                               .collect::<Vec<_>>();
+                let complex = ::ks_h_ss_xy(nx, ny, kx, 0, nup, 1);
+                let complex = unsafe { OwnedCoordMatrix::from_ffi(complex) };
+                assert_eq!(found, triplets(complex));
+            }
+
+            // complex phases or elements
+            for &(kx, kind, code) in
+                [(1, TermKind::HSsXy, ::error::ERR_INVALID_ARGUMENT),
+                 (0, TermKind::HSssChi, ::error::ERR_INVALID_TERM)].iter()
+            {
+                let term = c_term(kind);
+                let mat = unsafe {
+                    ::ks_term_matrix_real(nx, ny, kx, 0, nup, term, &mut status)
+                };
+                assert_eq!(status, code);
+                assert!(mat.is_well_formed() && mat.data.ptr.is_null());
+                unsafe { ::real_matrix_free(mat) };
+            }
+        }
+
+        #[test]
+        fn term_into_slices_test() {
+            let (nx, ny, kx, ky, nup) = (4, 3, 1, 2, 6);
+            let term = Term::new(TermKind::HSsXy, I(1));
+            let nnz = term_nnz(Dim(nx), Dim(ny), K(kx), K(ky), nup, &term).unwrap();
+            let nnz = nnz as usize;
+            let zero = CComplex { re: 0., im: 0. };
+            let (mut data, mut row, mut col) =
+                (vec![zero; nnz], vec![0; nnz], vec![0; nnz]);
+            let written = unsafe {
+                ::ks_h_ss_xy_into(nx, ny, kx, ky, nup, 1, data.as_mut_ptr(),
+                                  row.as_mut_ptr(), col.as_mut_ptr(), nnz as u64)
+            };
+            assert_eq!(written, nnz as i64);
+            let mat = ::ks_h_ss_xy(nx, ny, kx, ky, nup, 1);
+            let expected = triplets(unsafe { OwnedCoordMatrix::from_ffi(mat) });
+            let found = row.iter()
+                           .zip(col.iter())
+                           .zip(data.iter())
+                           .map(|((&r, &c), d)| (r, c, d.re, d.im))
+                           .collect::<Vec<_>>();
+            assert_eq!(found, expected);
+
+            // one short: nothing is written past "cap"
+            let sentinel = CComplex { re: 7., im: 7. };
+            let (mut data, mut row, mut col) =
+                (vec![zero; nnz], vec![0; nnz], vec![0; nnz]);
+            data[nnz - 1] = sentinel;
+            row[nnz - 1] = u32::MAX;
+            col[nnz - 1] = u32::MAX;
+            let written = unsafe {
+                ::ks_h_ss_xy_into(nx, ny, kx, ky, nup, 1, data.as_mut_ptr(),
+                                  row.as_mut_ptr(), col.as_mut_ptr(),
+                                  nnz as u64 - 1)
+            };
+            assert_eq!(written, i64::from(::error::ERR_INVALID_ARGUMENT));
+            assert_eq!((data[nnz - 1].re, data[nnz - 1].im), (7., 7.));
+            assert_eq!((row[nnz - 1], col[nnz - 1]),
+                       (u32::MAX, u32::MAX));
+
+            let written = unsafe {
+                ::ks_h_sss_chi_into(nx, ny, kx, ky, nup, ptr::null_mut(),
+                                    ptr::null_mut(), ptr::null_mut(), 0)
+            };
+            assert_eq!(written, i64::from(::error::ERR_INVALID_ARGUMENT));
+        }
+
+        #[test]
+        fn term_rows_chunks_test() {
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+            for &kind in [TermKind::HSsZ, TermKind::HSsXy, TermKind::HSssChi].iter()
+            {
+                let term = Term { kind,
+                                  l: I(1),
+                                  coeff: 1. };
+                let full =
+                    term_rows(nx, ny, kx, ky, nup, &term, 0..u32::MAX, None);
+                let full = triplets(full.unwrap());
+
+                let dims = bloch_states(nx, ny, kx, ky, nup).unwrap().nonzero;
+                let bounds = [0, dims / 4, dims / 2, 3 * dims / 4, dims];
+                let mut chunked = Vec::new();
+                for w in bounds.windows(2) {
+                    let chunk =
+                        term_rows(nx, ny, kx, ky, nup, &term, w[0]..w[1], None);
+                    chunked.append(&mut triplets(chunk.unwrap()));
+                }
+                assert_eq!(full, chunked);
+            }
+        }
+
+        #[test]
+        fn term_rows_basis_file_test() {
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(0), K(1), 5);
+            let path = env::temp_dir().join("spinsys_term_rows_basis_file_test.bin");
+            bloch_states(nx, ny, kx, ky, nup).unwrap().save(&path, kx, ky, nup)
+                                             .unwrap();
+
+            let term = Term { kind:  TermKind::HSsXy,
+                              l:     I(2),
+                              coeff: 1. };
+            let path_str = path.to_str();
+            let from_file = term_rows(nx, ny, kx, ky, nup, &term, 3..40, path_str);
+            let from_scratch = term_rows(nx, ny, kx, ky, nup, &term, 3..40, None);
+            assert_eq!(triplets(from_file.unwrap()),
+                       triplets(from_scratch.unwrap()));
+
+            // parameters that do not match the file are rejected
+            let res = term_rows(nx, ny, kx, ky, nup + 1, &term, 3..40, path_str);
+            assert!(res.is_err());
+        }
+
+        #[test]
+        fn term_nnz_test() {
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(4), K(1), K(3), 8);
+            for &kind in [TermKind::HSsZ, TermKind::HSsXy, TermKind::HSssChi].iter()
+            {
+                let term = Term::new(kind, I(1));
+                let nnz = term_nnz(nx, ny, kx, ky, nup, &term).unwrap();
+                let bfuncs = bloch_states(nx, ny, kx, ky, nup).unwrap();
+                let mat = ops::term(&term, &bfuncs);
+                assert_eq!(nnz, mat.nnz() as u64);
+                // no (row, col) pair is stored twice
+                let mut pairs = triplets(mat).into_iter()
+                                             .map(|(r, c, _, _)| (r, c))
+                                             .collect::<Vec<_>>();
+                pairs.sort();
+                pairs.dedup();
+                assert_eq!(nnz, pairs.len() as u64);
+            }
+            let term = Term::new(TermKind::HSsPpmm, I(1));
+            assert!(term_nnz(nx, ny, kx, ky, nup, &term).is_err());
+        }
+
+        #[test]
+        fn nnz_bound_test() {
+            use test_support::thread_allocations;
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(4), K(1), K(3), 8);
+            let bfuncs = bloch_states(nx, ny, kx, ky, nup).unwrap();
+            let rows = 0..bfuncs.nonzero;
+            for &kind in [TermKind::HSsZ, TermKind::HSsXy, TermKind::HSssChi].iter()
+            {
+                let term = Term::new(kind, I(1));
+                let nnz = ops::term_nnz(&term, &bfuncs) as usize;
+                let bound = ops::nnz_bound(&term, &bfuncs, rows.clone());
+                assert!(nnz <= bound && bound <= nnz + nnz / 10);
+
+                // the arrays are allocated once instead of growing
+                let allocations = |mut sink: VecSink| {
+                    let start = thread_allocations();
+                    ops::term_rows_into(&term, &bfuncs, rows.clone(), &mut sink)
+                        .unwrap();
+                    thread_allocations() - start
+                };
+                let grown = allocations(VecSink::with_capacity(0));
+                let reserved = allocations(VecSink::with_capacity(bound));
+                assert!(reserved + 20 < grown, "{} against {}", reserved, grown);
+
+                let mat = ops::term(&term, &bfuncs);
+                assert_eq!(mat.data.capacity(), nnz);
+                assert_eq!(mat.row.capacity(), nnz);
+            }
+        }
+
+        #[test]
+        fn term_matrix_cancel_test() {
+            use error::ERR_CANCELLED;
+            use libc::{c_char, c_void};
+            use std::{
+                ffi::CStr,
+                sync::atomic::{AtomicU8, Ordering}
+            };
+            use test_support::{lock_pool, tracked_allocated, tracked_pool};
+
+            struct Cancel {
+                flag:  AtomicU8,
+                // the fraction of the rows done when the flag was set, and
+                // the reports that came after
+                at:    Option<f64>,
+                later: usize
+            }
+
+            // sets the flag at the first report of rows done, when the blocks
+            // of the rest of the batch have been generated on the workers
+            extern "C" fn cancel_on_rows(fraction: f64, phase: *const c_char,
+                                         ctx: *mut c_void) {
+                let cancel = unsafe { &mut *(ctx as *mut Cancel) };
+                let phase = unsafe { CStr::from_ptr(phase) };
+                if cancel.at.is_some() {
+                    cancel.later += 1;
+                } else if phase.to_bytes() == b"elements" && fraction > 0. {
+                    cancel.at = Some(fraction);
+                    cancel.flag.store(1, Ordering::Relaxed);
+                }
+            }
+
+            // several batches of rows, see ops::blocks_in_order
+            let (nx, ny, kx, ky, nup) = (Dim(5), Dim(4), K(0), K(0), 10);
+            let term = Term::new(TermKind::HSsXy, I(1));
+            let torus = LatticeSettings::default();
+            let _pool = lock_pool();
+            let pool = tracked_pool(4);
+            let build = || {
+                let mut cancel = Cancel { flag:  AtomicU8::new(0),
+                                          at:    None,
+                                          later: 0 };
+                // the build runs on a thread of the pool, which is handed
+                // the address of "cancel" since a Progress stays on its thread
+                let ctx = &mut cancel as *mut Cancel as usize;
+                let result = pool.install(|| {
+                    let cancel = ctx as *mut Cancel;
+                    let flag = unsafe { &(*cancel).flag } as *const _ as *const u8;
+                    let progress = Progress::new(Some(cancel_on_rows),
+                                                 cancel as *mut c_void);
+                    let mut progress = unsafe { progress.with_cancel(flag) };
+                    term_matrix_with_progress(nx, ny, &torus, Convention::Plus,
+                                              kx, ky, nup, &term, &mut progress)
+                });
+                assert_eq!(result.err().map(|e| e.status()), Some(ERR_CANCELLED));
+                // stopped at the next row, well before the end
+                assert!(cancel.at.unwrap() < 0.5);
+                assert_eq!(cancel.later, 0);
+            };
+
+            // the tables of the lattice are cached for good, so they are built
+            // before counting
+            build();
+            let before = tracked_allocated();
+            build();
+            // everything allocated by the build, on any of the threads, has
+            // been freed again
+            assert_eq!(tracked_allocated(), before);
+        }
+
+        #[test]
+        fn term_matrix_progress_test() {
+            use libc::c_void;
+            use test_support::{collect, Reports};
+
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+            let term = Term::new(TermKind::HSsXy, I(1));
+            let mut reports: Reports = Vec::new();
+            let ctx = &mut reports as *mut Reports as *mut c_void;
+            let mut progress = Progress::new(Some(collect), ctx);
+            let torus = LatticeSettings::default();
+            let mat = term_matrix_with_progress(nx, ny, &torus, Convention::Plus,
+                                                kx, ky, nup, &term, &mut progress);
+            let plain = term_matrix(nx, ny, kx, ky, nup, &term).unwrap();
+            assert_eq!(mat.unwrap().data.len(), plain.data.len());
+
+            // every phase is reported in order and ends at exactly 1
+            let split = reports.iter().position(|r| r.1 == "elements").unwrap();
+            let (basis, elements) = reports.split_at(split);
+            assert!(basis.iter().all(|r| r.1 == "basis"));
+            assert!(elements.iter().all(|r| r.1 == "elements"));
+            for phase in [basis, elements].iter() {
+                assert!(phase.len() > 2 && phase.len() <= 102);
+                assert!(phase.windows(2).all(|w| w[0].0 <= w[1].0));
+                assert_eq!(phase.last().unwrap().0, 1.);
+            }
+        }
+
+        #[test]
+        fn invalid_parameters_test() {
+            let (nx, ny) = (Dim(4), Dim(3));
+            let term = Term::new(TermKind::HSsXy, I(1));
+            let cases = [((K(4), K(0), 6, term), "kx"),
+                         ((K(0), K(3), 6, term), "ky"),
+                         ((K(0), K(0), 13, term), "nup"),
+                         ((K(0), K(0), 6, Term::new(TermKind::HSsZ, I(4))), "l"),
+                         ((K(0), K(0), 6, Term::new(TermKind::SsZ, I(-2))), "l")];
+            for &((kx, ky, nup, term), name) in cases.iter() {
+                let results = [term_matrix(nx, ny, kx, ky, nup, &term).err(),
+                               term_real(nx, ny, kx, ky, nup, &term).err(),
+                               term_nnz(nx, ny, kx, ky, nup, &term).err(),
+                               term_dense(nx, ny, kx, ky, nup, &term).err(),
+                               terms_matrices(nx, ny, kx, ky, nup, &[term]).err()];
+                for result in results.iter() {
+                    match *result {
+                        Some(Error::InvalidArgument(arg)) => assert_eq!(arg, name),
+                        ref other => panic!("{:?}: {:?}", (kx, ky, nup, term), other)
+                    }
+                }
+            }
+            match bloch_states(nx, ny, K(0), K(0), 13) {
+                Err(Error::InvalidArgument("nup")) => {}
+                _ => panic!("nup = 13 accepted on 12 sites")
+            }
+            let l = BondRange::Second;
+            assert!(h_ss_xy(nx, ny, K(0), K(3), 6, l).is_err());
+            assert!(ss_xy(nx, ny, K(0), K(0), 6, I(12)).is_err());
+        }
+
+        #[test]
+        fn expand_state_test() {
+            use lanczos::ground_state;
+            use matfree::OpHandle;
+
+            let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+            let terms = [Term::new(TermKind::HSsZ, I(1)),
+                         Term::new(TermKind::HSsXy, I(1)),
+                         Term { kind:  TermKind::HSsZ,
+                                l:     I(2),
+                                coeff: 0.3 },
+                         Term { kind:  TermKind::HSsXy,
+                                l:     I(2),
+                                coeff: 0.3 }];
+            let op = OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap();
+            let (energy, psi) = ground_state(&op, 1e-12, 300, true).unwrap();
+            let psi = psi.unwrap();
+
+            let states = expand_state(nx, ny, kx, ky, nup, &psi).unwrap();
+            assert!(states.windows(2).all(|w| w[0].0 < w[1].0));
+            let norm = states.iter().map(|s| s.1.norm_sqr()).sum::<f64>();
+            assert!((norm - 1.).abs() < 1e-10);
+
+            // apply the same Hamiltonian directly in the product basis
+            let amps = states.iter().cloned().collect::<FnvHashMap<_, _>>();
+            let mut h_psi: FnvHashMap<BinaryBasis, Complex<f64>> =
+                FnvHashMap::default();
+            for (&dec, &amp) in amps.iter() {
+                for &(l, j) in [(1, 1.), (2, 0.3)].iter() {
+                    let (site1, site2) = interacting_sites(nx, ny, I(l));
+                    for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+                        let (upup, downdown) = repeated_spins(dec, s1, s2);
+                        let sz = if upup || downdown { 0.25 } else { -0.25 };
+                        *h_psi.entry(dec).or_insert(Complex::new(0., 0.)) +=
+                            amp * j * sz;
+                        let (updown, downup) = exchange_spin_flips(dec, s1, s2);
+                        let flipped = match (updown, downup) {
+                            (true, false) => dec - s1 + s2,
+                            (false, true) => dec + s1 - s2,
+                            _ => continue
+                        };
+                        *h_psi.entry(flipped).or_insert(Complex::new(0., 0.)) +=
+                            amp * j * 0.5;
+                    }
+                }
+            }
+            for (dec, val) in h_psi.iter() {
+                let amp = amps.get(dec).cloned().unwrap_or(Complex::new(0., 0.));
+                assert!((*val - amp * energy).norm() < 1e-8);
+            }
+
+            // the same amplitudes in the fixed-nup basis
+            let full = expand_state_sz(nx, ny, kx, ky, nup, &psi).unwrap();
+            assert_eq!(full.len() as u64, choose(nx * ny, nup));
+            let mut decs = sz_basis(nx * ny, nup);
+            decs.sort();
+            for (dec, amp) in decs.iter().zip(full.iter()) {
+                let expected =
+                    amps.get(dec).cloned().unwrap_or(Complex::new(0., 0.));
+                assert_eq!(*amp, expected);
+            }
+
+            assert!(expand_state(nx, ny, kx, ky, nup, &psi[1..]).is_err());
+        }
+
+        // every orbit fits as many momenta as it holds configurations, so the
+        // sectors of a shifted lattice still share out the whole Sz basis
+        #[test]
+        fn shifted_sectors_partition_the_sz_basis() {
+            let (nx, ny, nup) = (Dim(4), Dim(3), 6);
+            for shift in 1..4 {
+                let settings = LatticeSettings { shift,
+                                                 ..LatticeSettings::default() };
+                let mut dim = 0;
+                for kx in 0..4 {
+                    for ky in 0..3 {
+                        let plus = Convention::Plus;
+                        dim += bloch_states_in(nx, ny, &settings, plus, K(kx), K(ky),
+                                               nup).unwrap()
+                                                   .nonzero;
+                    }
+                }
+                assert_eq!(dim as u64, choose(nx * ny, nup));
+            }
+        }
+
+        // the Sz = 0 Hamiltonian of the shifted lattice written out from a
+        // bond list made by hand
+        struct ShiftedHeisenberg {
+            diag: Vec<f64>,
+            flips: Vec<Vec<usize>>
+        }
+
+        impl LinearOperator for ShiftedHeisenberg {
+            fn dim(&self) -> usize { self.diag.len() }
+
+            fn apply(&self, x: &[Complex<f64>], y: &mut [Complex<f64>])
+                     -> Result<()> {
+                for (i, yi) in y.iter_mut().enumerate() {
+                    *yi = x[i] * self.diag[i];
+                    for &j in self.flips[i].iter() {
+                        *yi += x[j] * 0.5;
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn shifted_ground_state_matches_explicit_bonds() {
+            use lanczos::ground_state;
+
+            let (nx, ny, shift, nup) = (6i64, 3i64, 1i64, 9);
+            // going once around y moves x by the shift
+            let site = |x: i64, y: i64| {
+                let wraps = y.div_euclid(ny);
+                let x = (x + wraps * shift).rem_euclid(nx);
+                1u64 << (x + nx * (y - wraps * ny))
+            };
+            let mut bonds = vec![];
+            for y in 0..ny {
+                for x in 0..nx {
+                    for &(dx, dy) in [(1, 0), (-1, 1), (0, -1)].iter() {
+                        bonds.push((site(x, y), site(x + dx, y + dy)));
+                    }
+                }
+            }
+            let mut pairs = bonds.iter()
+                                 .map(|&(a, b)| (a.min(b), a.max(b)))
+                                 .collect::<Vec<_>>();
+            pairs.sort();
+            pairs.dedup();
+            assert_eq!(pairs.len(), 54);
+
+            let decs = (0..1u64 << 18).filter(|d| d.count_ones() == nup)
+                                      .collect::<Vec<_>>();
+            let mut op = ShiftedHeisenberg { diag:  vec![0.; decs.len()],
+                                             flips: vec![vec![]; decs.len()] };
+            for (i, &dec) in decs.iter().enumerate() {
+                for &(a, b) in bonds.iter() {
+                    if (dec & a == 0) == (dec & b == 0) {
+                        op.diag[i] += 0.25;
+                    } else {
+                        op.diag[i] -= 0.25;
+                        let j = decs.binary_search(&(dec ^ a ^ b)).unwrap();
+                        op.flips[i].push(j);
+                    }
+                }
+            }
+            let (expected, _) = ground_state(&op, 1e-8, 300, false).unwrap();
+
+            // the coordinate arrays of both parts of each sector taken together
+            // are its Hamiltonian, repeated positions adding up
+            let settings = LatticeSettings { shift: shift as u32,
+                                             ..LatticeSettings::default() };
+            let terms = [Term::new(TermKind::HSsZ, I(1)),
+                         Term::new(TermKind::HSsXy, I(1))];
+            let mut lowest = f64::INFINITY;
+            for kx in 0..6 {
+                for ky in 0..3 {
+                    let (kx, ky) = (K(kx), K(ky));
+                    let parts = terms_matrices_in(Dim(6), Dim(3), &settings,
+                                                  Convention::Plus, kx, ky, nup,
+                                                  &terms).unwrap();
+                    let (mut data, mut col, mut row) = (vec![], vec![], vec![]);
+                    let mut dim = 0;
+                    for part in parts.iter() {
+                        data.extend_from_slice(&part.data);
+                        col.extend_from_slice(&part.col);
+                        row.extend_from_slice(&part.row);
+                        dim = part.nrows;
+                    }
+                    let mat = OwnedCoordMatrix::new(data, col, row, dim, dim);
+                    let (energy, _) = ground_state(&mat, 1e-8, 300, false).unwrap();
+                    lowest = lowest.min(energy);
+                }
+            }
+            assert!((lowest - expected).abs() < 1e-9);
+        }
     }
 }
@@ -0,0 +1,448 @@
+//! Bases kept on disk. The basis of a sector too large for memory is scanned a
+//! few chunks at a time into a file holding the sorted leading states, the
+//! norms and the translation orbits of its Bloch functions as flat arrays, and
+//! the file is then memory-mapped read-only. The operating system pages the
+//! arrays in as the matrix elements are generated, so once the file outgrows
+//! the page cache every lookup may cost a read from disk.
+use memmap::Mmap;
+use num_complex::Complex;
+use std::{
+    cmp,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    slice,
+    sync::Arc
+};
+
+use blochfunc::{BlochFunc, BlochFuncSet, Convention, Lookup};
+use common::*;
+use error::{Error, Result};
+use progress::Progress;
+use sitevector::{Periodicity, SiteOrdering};
+
+const MAPPED_BASIS_MAGIC: &[u8; 8] = b"SPNSMAP1";
+/// The magic, the sector (nx, ny, kx, ky, nup), four bytes of padding and the
+/// numbers of Bloch functions and of orbit configurations
+const HEADER_LEN: usize = 48;
+
+/// The basis of a (kx, ky, nup) sector in a memory-mapped file. After the
+/// header the file holds, all little-endian,
+///
+///  * the leading states in ascending order,
+///  * the norms of the Bloch functions,
+///  * the offsets of their orbits into the arena, one more than there are
+///    Bloch functions,
+///  * the arena: the configurations of every orbit in ascending order, and
+///  * the shifts (see BlochFunc) of the configurations of the arena.
+///
+/// The file must not be modified while it is mapped.
+pub struct MappedBasis {
+    map:            Mmap,
+    pub nx:         Dim,
+    pub ny:         Dim,
+    pub kx:         K,
+    pub ky:         K,
+    pub nup:        u32,
+    /// The convention of the phases, Convention::Plus as on the plain torus
+    pub convention: Convention,
+    /// The tables of the plain torus the basis is on
+    pub tables:     Arc<LatticeTables>,
+    len:            usize,
+    total:          usize,
+    trans:          SizedTranslations,
+    /// The phase of the Bloch functions under each translation tx + nx ty
+    phases:         Vec<Complex<f64>>
+}
+
+/// The exported basis handle. The operators built on it share the mapping,
+/// so the handle may be freed while they are still in use.
+pub struct BasisHandle {
+    pub basis: Arc<MappedBasis>
+}
+
+impl MappedBasis {
+    /// Open the basis at "path" if the file exists, and scan the sector into it
+    /// otherwise (see create)
+    pub fn open_or_create<P: AsRef<Path>>(path: P, nx: Dim, ny: Dim, kx: K, ky: K,
+                                          nup: u32)
+                                          -> Result<MappedBasis> {
+        let path = path.as_ref();
+        if path.exists() {
+            MappedBasis::open(path, nx, ny, kx, ky, nup)
+        } else {
+            MappedBasis::create(path, nx, ny, kx, ky, nup, &mut Progress::none())
+        }
+    }
+
+    /// Scan the (kx, ky, nup) sector into a file at "path" and map it. The
+    /// configurations with "nup" spins up are enumerated in ascending order
+    /// without ever being stored, and the Bloch functions found are spilled to
+    /// a scratch file next to "path" as they come in, so the memory used does
+    /// not grow with the sector. The arrays are then written out section by
+    /// section in a single pass over the scratch file. The file only appears
+    /// at "path" once it is complete; fails if the scan is cancelled. The
+    /// file has no room for the settings of the lattice (see
+    /// common::LatticeSettings), so the basis is that of the plain torus.
+    pub fn create<P: AsRef<Path>>(path: P, nx: Dim, ny: Dim, kx: K, ky: K,
+                                  nup: u32, progress: &mut Progress)
+                                  -> Result<MappedBasis> {
+        check_sector(nx, ny, kx, ky, Some(nup))?;
+        let path = path.as_ref();
+        let spill = sibling(path, ".spill");
+        let part = sibling(path, ".part");
+        let result = write_basis(&spill, &part, nx, ny, kx, ky, nup, progress)
+            .and_then(|()| fs::rename(&part, path).map_err(Error::from));
+        let _ = fs::remove_file(&spill);
+        if result.is_err() {
+            let _ = fs::remove_file(&part);
+        }
+        result?;
+        MappedBasis::open(path, nx, ny, kx, ky, nup)
+    }
+
+    /// Map a file written by MappedBasis::create. Fails unless the file was
+    /// written for the same sector and is complete. The basis is that of the
+    /// plain torus.
+    pub fn open<P: AsRef<Path>>(path: P, nx: Dim, ny: Dim, kx: K, ky: K, nup: u32)
+                                -> Result<MappedBasis> {
+        check_sector(nx, ny, kx, ky, Some(nup))?;
+        // the arrays are read in place
+        if cfg!(target_endian = "big") {
+            return Err(Error::InvalidArgument("basis file"));
+        }
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_LEN || &map[..8] != MAPPED_BASIS_MAGIC {
+            return Err(Error::InvalidArgument("basis file"));
+        }
+
+        let u32_at = |n: usize| {
+            let mut buf = [0_u8; 4];
+            buf.copy_from_slice(&map[n..n + 4]);
+            u32::from_le_bytes(buf)
+        };
+        let header = [nx.raw_int(), ny.raw_int(), kx.raw_int(), ky.raw_int(), nup];
+        for (n, &expected) in header.iter().enumerate() {
+            if u32_at(8 + 4 * n) != expected {
+                return Err(Error::InvalidArgument("basis file"));
+            }
+        }
+        let u64_at = |n: usize| {
+            let mut buf = [0_u8; 8];
+            buf.copy_from_slice(&map[n..n + 8]);
+            u64::from_le_bytes(buf) as usize
+        };
+        let (len, total) = (u64_at(32), u64_at(40));
+        if map.len() != file_len(len, total) {
+            return Err(Error::InvalidArgument("basis file"));
+        }
+
+        let convention = Convention::Plus;
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default())?;
+        let phases = BlochFuncSet::create(tables.clone(), kx, ky, Lookup::Leads,
+                                          convention, Vec::new()).phases;
+        let width = Width::for_lattice(nx, ny);
+        let (torus, ordering) = (Periodicity::TORUS, SiteOrdering::RowMajor);
+        let trans = SizedTranslations::new(nx, ny, 0, torus, ordering, width);
+        let basis = MappedBasis { map,
+                                  nx,
+                                  ny,
+                                  kx,
+                                  ky,
+                                  nup,
+                                  convention,
+                                  tables,
+                                  len,
+                                  total,
+                                  trans,
+                                  phases };
+        // the indices are those of the basis in memory (see BlochFuncSet)
+        debug_assert!(basis.leads().windows(2).all(|w| w[0] < w[1]),
+                      "leading states out of order");
+        Ok(basis)
+    }
+
+    pub fn dim(&self) -> u32 { self.len as u32 }
+
+    /// The leading states in ascending order
+    pub fn leads(&self) -> &[u64] { self.array(HEADER_LEN, self.len) }
+
+    fn norms(&self) -> &[f64] { self.array(HEADER_LEN + 8 * self.len, self.len) }
+
+    fn offsets(&self) -> &[u64] {
+        self.array(HEADER_LEN + 16 * self.len, self.len + 1)
+    }
+
+    fn decs(&self) -> &[u64] {
+        self.array(HEADER_LEN + 8 * (3 * self.len + 1), self.total)
+    }
+
+    fn shifts(&self) -> &[u8] {
+        self.array(HEADER_LEN + 8 * (3 * self.len + 1 + self.total), self.total)
+    }
+
+    fn array<T>(&self, start: usize, len: usize) -> &[T] {
+        let bytes = &self.map[start..start + len * mem::size_of::<T>()];
+        // the map starts on a page boundary and every array but the last, which
+        // holds bytes, has 8-byte elements, so the arrays are aligned
+        debug_assert_eq!(bytes.as_ptr() as usize % mem::align_of::<T>(), 0);
+        unsafe { slice::from_raw_parts(bytes.as_ptr() as *const T, len) }
+    }
+
+    /// The Bloch function with index "i", holding its leading state and norm
+    /// only as under Lookup::Leads
+    pub fn get(&self, i: u32) -> BlochFunc {
+        BlochFunc { lead:   BinaryBasis(self.leads()[i as usize]),
+                    decs:   Box::new([]),
+                    shifts: Box::new([]),
+                    norm:   self.norms()[i as usize] }
+    }
+
+    /// The index of the Bloch function whose leading state is "dec", None if
+    /// "dec" is not a leading state of the basis
+    pub fn index_of(&self, dec: BinaryBasis) -> Option<u32> {
+        self.leads()
+            .binary_search(&dec.raw_int())
+            .ok()
+            .map(|i| i as u32)
+    }
+
+    /// The component of the unnormalized Bloch function with index "i" on
+    /// "dec", as in BlochFunc::phase
+    fn phase(&self, i: u32, dec: BinaryBasis) -> Option<Complex<f64>> {
+        let offsets = self.offsets();
+        let start = offsets[i as usize] as usize;
+        let decs = &self.decs()[start..offsets[i as usize + 1] as usize];
+        let n = decs.binary_search(&dec.raw_int()).ok()?;
+        let translations = self.phases.len() as f64 / decs.len() as f64;
+        Some(self.phases[self.shifts()[start + n] as usize] * translations)
+    }
+
+    /// Same as OrbitTable::find. The orbit of "dec" is found by translating it
+    /// onto its leading state, and the phase is read off the arena as in
+    /// find_leading_state, with components below "min_norm" taken as missing.
+    pub fn find(&self, dec: BinaryBasis, min_norm: f64)
+                -> Option<(u32, BlochFunc, Complex<f64>)> {
+        let i = self.index_of(self.trans.leading(dec).0)?;
+        let phase = leading_phase(self.phase(i, dec)?, min_norm)?;
+        Some((i, self.get(i), phase))
+    }
+
+    /// Same as OrbitTable::amplitude
+    pub fn amplitude(&self, dec: BinaryBasis)
+                     -> Option<(u32, BlochFunc, Complex<f64>)> {
+        let i = self.index_of(self.trans.leading(dec).0)?;
+        let bfunc = self.get(i);
+        let amplitude = self.phase(i, dec)? / bfunc.norm;
+        Some((i, bfunc, amplitude))
+    }
+}
+
+/// The size of a basis file with "len" Bloch functions whose orbits hold
+/// "total" configurations
+fn file_len(len: usize, total: usize) -> usize {
+    HEADER_LEN + 8 * (3 * len + 1 + total) + total
+}
+
+/// "path" with "suffix" appended to the file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// C(m, k) for m up to "n" and k up to "nup", indexed [m][k]
+fn binomials(n: u32, nup: u32) -> Vec<Vec<u64>> {
+    let mut table = vec![vec![0_u64; nup as usize + 1]; n as usize + 1];
+    for m in 0..=n as usize {
+        table[m][0] = 1;
+        for k in 1..=cmp::min(m, nup as usize) {
+            table[m][k] = table[m - 1][k - 1] + table[m - 1][k];
+        }
+    }
+    table
+}
+
+/// The "rank"-th smallest configuration of "n" sites with "nup" spins up.
+/// "binomials" is binomials(n, nup).
+fn unrank(n: u32, nup: u32, rank: u64, binomials: &[Vec<u64>]) -> BinaryBasis {
+    let mut dec = 0_u64;
+    let mut rank = rank;
+    let mut up = nup as usize;
+    for site in (0..n as usize).rev() {
+        if up == 0 {
+            break;
+        }
+        // the configurations with this spin down come first
+        let below = binomials[site][up];
+        if rank >= below {
+            dec |= site_mask(site as u32).raw_int();
+            rank -= below;
+            up -= 1;
+        }
+    }
+    BinaryBasis(dec)
+}
+
+fn write_basis(spill: &Path, part: &Path, nx: Dim, ny: Dim, kx: K, ky: K,
+               nup: u32, progress: &mut Progress)
+               -> Result<()> {
+    // every Bloch function is spilled as its leading state, norm and orbit
+    // length followed by the configurations and shifts of its orbit
+    let n = (nx * ny).raw_int();
+    let binomials = binomials(n, nup);
+    let nstates = binomials[n as usize][nup as usize] as usize;
+    let state = |i: usize| unrank(n, nup, i as u64, &binomials);
+    let (mut len, mut total) = (0, 0);
+    {
+        let mut f = BufWriter::new(File::create(spill)?);
+        let spill_chunk = |bfuncs: Vec<BlochFunc>| -> Result<()> {
+            for bfunc in bfuncs.iter() {
+                f.write_all(&bfunc.lead.raw_int().to_le_bytes())?;
+                f.write_all(&bfunc.norm.to_bits().to_le_bytes())?;
+                f.write_all(&(bfunc.decs.len() as u64).to_le_bytes())?;
+                for dec in bfunc.decs.iter() {
+                    f.write_all(&dec.raw_int().to_le_bytes())?;
+                }
+                f.write_all(&bfunc.shifts)?;
+                len += 1;
+                total += bfunc.decs.len();
+            }
+            Ok(())
+        };
+        let torus = LatticeSettings::default();
+        BlochFuncSet::scan_chunks(nx, ny, &torus, kx, ky, nstates, state,
+                                  Lookup::Members, progress, spill_chunk)?;
+        f.flush()?;
+    }
+
+    let file = File::create(part)?;
+    file.set_len(file_len(len, total) as u64)?;
+    let mut header = BufWriter::new(file);
+    header.write_all(MAPPED_BASIS_MAGIC)?;
+    // the sector, padded to 8 bytes
+    let sector = [nx.raw_int(), ny.raw_int(), kx.raw_int(), ky.raw_int(), nup, 0];
+    for x in sector.iter() {
+        header.write_all(&x.to_le_bytes())?;
+    }
+    header.write_all(&(len as u64).to_le_bytes())?;
+    header.write_all(&(total as u64).to_le_bytes())?;
+    header.flush()?;
+
+    let section = |start: usize| -> Result<BufWriter<File>> {
+        let mut f = OpenOptions::new().write(true).open(part)?;
+        f.seek(SeekFrom::Start(start as u64))?;
+        Ok(BufWriter::new(f))
+    };
+    let mut leads = section(HEADER_LEN)?;
+    let mut norms = section(HEADER_LEN + 8 * len)?;
+    let mut offsets = section(HEADER_LEN + 16 * len)?;
+    let mut decs = section(HEADER_LEN + 8 * (3 * len + 1))?;
+    let mut shifts = section(HEADER_LEN + 8 * (3 * len + 1 + total))?;
+
+    let mut f = BufReader::new(File::open(spill)?);
+    let mut u64_buf = [0_u8; 8];
+    let mut shift_buf = Vec::new();
+    let mut offset = 0_u64;
+    offsets.write_all(&offset.to_le_bytes())?;
+    for _ in 0..len {
+        f.read_exact(&mut u64_buf)?;
+        leads.write_all(&u64_buf)?;
+        f.read_exact(&mut u64_buf)?;
+        norms.write_all(&u64_buf)?;
+        f.read_exact(&mut u64_buf)?;
+        let orbit = u64::from_le_bytes(u64_buf);
+        offset += orbit;
+        offsets.write_all(&offset.to_le_bytes())?;
+        for _ in 0..orbit {
+            f.read_exact(&mut u64_buf)?;
+            decs.write_all(&u64_buf)?;
+        }
+        shift_buf.resize(orbit as usize, 0);
+        f.read_exact(&mut shift_buf)?;
+        shifts.write_all(&shift_buf)?;
+    }
+    for section in [leads, norms, offsets, decs, shifts].iter_mut() {
+        section.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consv;
+    use matfree::OpHandle;
+    use std::env;
+
+    #[test]
+    fn unrank_matches_sz_basis() {
+        let (n, nup) = (Dim(10), 4);
+        let mut expected = sz_basis(n, nup);
+        expected.sort();
+        let binomials = binomials(n.raw_int(), nup);
+        let states = (0..expected.len() as u64)
+            .map(|rank| unrank(n.raw_int(), nup, rank, &binomials))
+            .collect::<Vec<_>>();
+        assert_eq!(states, expected);
+    }
+
+    #[test]
+    fn mapped_basis_matches_memory_4x4() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(4), K(1), K(0), 7);
+        let path = env::temp_dir().join("spinsys_mapped_basis_4x4.bin");
+        let _ = fs::remove_file(&path);
+        let created =
+            MappedBasis::create(&path, nx, ny, kx, ky, nup, &mut Progress::none())
+                .unwrap();
+        assert!(!sibling(&path, ".spill").exists());
+        assert!(!sibling(&path, ".part").exists());
+
+        // the arrays hold the basis built in memory
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let basis = MappedBasis::open_or_create(&path, nx, ny, kx, ky, nup).unwrap();
+        assert_eq!(basis.dim(), bfuncs.nonzero);
+        assert_eq!(basis.leads(), created.leads());
+        let trans = Translations::new(nx, ny);
+        for (i, bfunc) in bfuncs.iter().enumerate() {
+            let mapped = basis.get(i as u32);
+            assert_eq!(mapped.lead, bfunc.lead);
+            assert_eq!(mapped.norm.to_bits(), bfunc.norm.to_bits());
+            for &(dec, phase) in bfuncs.orbit(bfunc, &trans).iter() {
+                let (j, found, amp) = basis.amplitude(dec).unwrap();
+                assert_eq!(j, i as u32);
+                assert_eq!(found.lead, bfunc.lead);
+                assert_eq!(amp, phase / bfunc.norm);
+            }
+        }
+
+        // and so do the operators built on it
+        let terms = [Term { kind:  TermKind::HSsZ,
+                            l:     I(1),
+                            coeff: 1. },
+                     Term { kind:  TermKind::HSsXy,
+                            l:     I(1),
+                            coeff: 0.5 },
+                     Term { kind:  TermKind::HSssChi,
+                            l:     I(1),
+                            coeff: 0.25 }];
+        let dims = bfuncs.nonzero as usize;
+        let x = (0..dims).map(|i| Complex::new(i as f64, 1. / (1. + i as f64)))
+                         .collect::<Vec<_>>();
+        let apply = |op: &OpHandle| {
+            let mut y = vec![Complex::new(0., 0.); dims];
+            op.apply(&x, &mut y).unwrap();
+            y
+        };
+        let in_memory = OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap();
+        let mapped = OpHandle::mapped(Arc::new(basis), &terms).unwrap();
+        assert_eq!(apply(&mapped), apply(&in_memory));
+
+        // a file written for another sector is rejected
+        assert!(MappedBasis::open(&path, nx, ny, kx, ky, nup + 1).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
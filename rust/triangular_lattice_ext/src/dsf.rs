@@ -0,0 +1,369 @@
+//! Dynamical structure factors by the continued-fraction method. The ground
+//! state is excited by a spin operator carrying momentum q, which maps it into
+//! the sector of momentum k + q, and the Lanczos recursion of the Hamiltonian
+//! of that sector is run from the excited state. The spectral function
+//! follows from the coefficients of the recursion as a continued fraction.
+use fnv::FnvHashMap;
+use num_complex::Complex;
+use std::cmp;
+
+use blochfunc::{add_momenta, momentum_angle, BlochFuncSet, Convention, OrbitTable};
+use common::*;
+use consv;
+use error::{Error, Result};
+use lanczos::{krylov, norm, Krylov};
+use matfree::OpHandle;
+
+/// The spin operator applied to the ground state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Sz,
+    SPlus,
+    SMinus
+}
+
+impl Channel {
+    pub fn from_raw(channel: u32) -> Result<Channel> {
+        match channel {
+            0 => Ok(Channel::Sz),
+            1 => Ok(Channel::SPlus),
+            2 => Ok(Channel::SMinus),
+            _ => Err(Error::InvalidArgument("channel"))
+        }
+    }
+
+    /// The number of up spins after the operator is applied to a state with
+    /// "nup" up spins, None if the result vanishes identically
+    fn target_nup(self, n: u32, nup: u32) -> Option<u32> {
+        match self {
+            Channel::Sz => Some(nup),
+            Channel::SPlus if nup < n => Some(nup + 1),
+            Channel::SMinus if nup > 0 => Some(nup - 1),
+            _ => None
+        }
+    }
+}
+
+/// The coefficients of the Lanczos recursion started from the normalized
+/// excited state. "beta[i]" couples the i-th and (i+1)-th Lanczos vectors;
+/// both are padded with zeros if the recursion terminates early. "norm" is
+/// the norm of the excited state, so the spectral function integrates to
+/// norm^2.
+#[derive(Clone, Debug)]
+pub struct ContinuedFraction {
+    pub alpha: Vec<f64>,
+    pub beta:  Vec<f64>,
+    pub norm:  f64
+}
+
+/// O(q)|psi> in product states for "psi" given in the basis "bfuncs", where
+/// O(q) = N^(-1/2) Σ_j e^(i q·r_j) O_j with the angles blochfunc::momentum_angle
+/// gives under the convention of "bfuncs": e^(2πi (qx x_j / nx - qy y_j / ny))
+/// under Convention::Plus on a lattice without a shift, and the conjugate
+/// phases under Convention::Minus. translate_x moves the sites along +x but
+/// translate_y along -y, hence the opposite signs, which give the result the
+/// momentum k + q in the labelling of bloch_states under either convention.
+fn excite(bfuncs: &BlochFuncSet, psi: &[Complex<f64>], channel: Channel, qx: u32,
+          qy: u32)
+          -> Result<FnvHashMap<BinaryBasis, Complex<f64>>> {
+    let (nx, ny) = (bfuncs.nx.raw_int(), bfuncs.ny.raw_int());
+    let n = nx * ny;
+    let phases = (0..n).map(|bit| {
+                           // the row-major index of the site
+                           let j = bfuncs.ordering.row_major_index(bit, bfuncs.nx);
+                           let ang = momentum_angle(bfuncs.nx,
+                                                    bfuncs.ny,
+                                                    bfuncs.shift,
+                                                    bfuncs.convention,
+                                                    K(qx),
+                                                    K(qy),
+                                                    (j % nx) as i32,
+                                                    (j / nx) as i32);
+                           Complex::from_polar(&(1. / (n as f64).sqrt()), &ang)
+                       })
+                       .collect::<Vec<_>>();
+
+    let mut excited = FnvHashMap::default();
+    for (dec, amp) in bfuncs.expand(psi)? {
+        for (j, &phase) in phases.iter().enumerate() {
+            let bit = checked_site_mask(j as u32)?;
+            let up = dec & bit != BinaryBasis(0);
+            let (new_dec, val) = match channel {
+                Channel::Sz if up => (dec, 0.5 * phase * amp),
+                Channel::Sz => (dec, -0.5 * phase * amp),
+                Channel::SPlus if !up => (dec | bit, phase * amp),
+                Channel::SMinus if up => (dec - bit, phase * amp),
+                _ => continue
+            };
+            *excited.entry(new_dec)
+                    .or_insert_with(|| Complex::new(0., 0.)) += val;
+        }
+    }
+    Ok(excited)
+}
+
+/// The components of a momentum eigenstate, given in product states, on the
+/// basis "bfuncs" of its sector
+fn project(bfuncs: &BlochFuncSet, states: &FnvHashMap<BinaryBasis, Complex<f64>>)
+           -> Vec<Complex<f64>> {
+    let table = OrbitTable::new(bfuncs);
+    let mut v = vec![Complex::new(0., 0.); bfuncs.data.len()];
+    for (&dec, &amp) in states.iter() {
+        if let Some((i, _, c)) = table.amplitude(dec) {
+            v[i as usize] += c.conj() * amp;
+        }
+    }
+    v
+}
+
+/// The continued fraction of the dynamical structure factor of "channel" at
+/// momentum (qx, qy) in the state "psi" of the (kx, ky, nup) sector, usually
+/// its ground state, under the sum of "terms". "psi" is normalized first and
+/// "m" Lanczos steps are taken in the sector of k + q (see
+/// blochfunc::add_momenta). Both momenta are labelled under Convention::Plus.
+pub fn dsf_lanczos(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, qx: u32, qy: u32,
+                   channel: Channel, terms: &[Term], psi: &[Complex<f64>], m: u32)
+                   -> Result<ContinuedFraction> {
+    let (torus, plus) = (LatticeSettings::default(), Convention::Plus);
+    dsf_lanczos_in(nx, ny, &torus, plus, kx, ky, nup, qx, qy, channel, terms, psi,
+                   m)
+}
+
+/// Same as dsf_lanczos on the lattice with "settings", both momenta being
+/// labelled under "convention"
+pub fn dsf_lanczos_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                      convention: Convention, kx: K, ky: K, nup: u32, qx: u32,
+                      qy: u32, channel: Channel, terms: &[Term],
+                      psi: &[Complex<f64>], m: u32)
+                      -> Result<ContinuedFraction> {
+    if qx >= nx.raw_int() || qy >= ny.raw_int() {
+        return Err(Error::InvalidArgument("q"));
+    }
+    let bfuncs = consv::ks::bloch_states_in(nx, ny, settings, convention, kx, ky,
+                                            nup)?;
+    if psi.len() != bfuncs.nonzero as usize {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    let n0 = norm(psi);
+    if n0 == 0. {
+        return Err(Error::InvalidArgument("psi"));
+    }
+
+    let m = m as usize;
+    let mut cf = ContinuedFraction { alpha: vec![0.; m],
+                                     beta:  vec![0.; m],
+                                     norm:  0. };
+    let target_nup = match channel.target_nup(nx.raw_int() * ny.raw_int(), nup) {
+        Some(target_nup) => target_nup,
+        None => return Ok(cf)
+    };
+    let (kx, ky) = add_momenta(nx, ny, bfuncs.shift, kx, ky, K(qx), K(qy));
+    let op =
+        OpHandle::ks_in(nx, ny, settings, convention, kx, ky, target_nup, terms)?;
+
+    let psi = psi.iter().map(|&x| x / n0).collect::<Vec<_>>();
+    let excited = excite(&bfuncs, &psi, channel, qx, qy)?;
+    let v = project(op.bfuncs(), &excited);
+    cf.norm = norm(&v);
+    if cf.norm == 0. || m == 0 {
+        return Ok(cf);
+    }
+
+    let mut matvecs = 0;
+    let m = cmp::min(m, v.len());
+    let Krylov { alpha, beta, .. } = krylov(&op, &v, m, &mut matvecs)?;
+    cf.alpha[..alpha.len()].copy_from_slice(&alpha);
+    cf.beta[..beta.len()].copy_from_slice(&beta);
+    Ok(cf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lanczos::{ground_state, tridiagonal_eigh};
+    use observables::structure_factor;
+
+    fn heisenberg() -> Vec<Term> {
+        vec![Term { kind:  TermKind::HSsZ,
+                    l:     I(1),
+                    coeff: 1. },
+             Term { kind:  TermKind::HSsXy,
+                    l:     I(1),
+                    coeff: 1. }]
+    }
+
+    // <psi|O(q)^† O(q)|psi> evaluated directly on the product states of psi
+    fn static_weight(nx: u32, ny: u32, states: &[(BinaryBasis, Complex<f64>)],
+                     channel: Channel, qx: u32, qy: u32)
+                     -> f64 {
+        let n = nx * ny;
+        let amps = states.iter()
+                         .cloned()
+                         .collect::<FnvHashMap<BinaryBasis, Complex<f64>>>();
+        let amp = |dec| amps.get(&dec).cloned().unwrap_or(Complex::new(0., 0.));
+        let mut sum = Complex::new(0., 0.);
+        for i in 0..n {
+            for j in 0..n {
+                let (bi, bj) = (site_mask(i), site_mask(j));
+                let dx = (j % nx) as f64 - (i % nx) as f64;
+                let dy = (j / nx) as f64 - (i / nx) as f64;
+                let ang = 2.
+                          * PI
+                          * (qx as f64 * dx / nx as f64
+                             - qy as f64 * dy / ny as f64);
+                let phase = Complex::from_polar(&(1. / n as f64), &ang);
+                for &(dec, a) in states.iter() {
+                    let up_i = dec & bi != BinaryBasis(0);
+                    let up_j = dec & bj != BinaryBasis(0);
+                    // <psi|O_i^† O_j|psi>
+                    let val = match channel {
+                        Channel::Sz => {
+                            let si = if up_i { 0.5 } else { -0.5 };
+                            let sj = if up_j { 0.5 } else { -0.5 };
+                            Complex::new(a.norm_sqr() * si * sj, 0.)
+                        }
+                        Channel::SPlus if i == j && !up_j => {
+                            Complex::new(a.norm_sqr(), 0.)
+                        }
+                        Channel::SPlus if i != j && !up_j && up_i => {
+                            amp((dec | bj) - bi).conj() * a
+                        }
+                        Channel::SMinus if i == j && up_j => {
+                            Complex::new(a.norm_sqr(), 0.)
+                        }
+                        Channel::SMinus if i != j && up_j && !up_i => {
+                            amp((dec - bj) | bi).conj() * a
+                        }
+                        _ => Complex::new(0., 0.)
+                    };
+                    sum += phase * val;
+                }
+            }
+        }
+        sum.re
+    }
+
+    #[test]
+    fn sum_rule_3x3() {
+        let (nx, ny, nup) = (Dim(3), Dim(3), 4);
+        let terms = heisenberg();
+        let op = OpHandle::ks(nx, ny, K(0), K(0), nup, &terms).unwrap();
+        let (_, psi) = ground_state(&op, 1e-12, 300, true).unwrap();
+        let psi = psi.unwrap();
+        let states = op.bfuncs().expand(&psi).unwrap();
+        for &channel in [Channel::Sz, Channel::SPlus, Channel::SMinus].iter() {
+            for &(qx, qy) in [(0, 0), (1, 0), (0, 1), (1, 2), (2, 1)].iter() {
+                let cf = dsf_lanczos(nx,
+                                     ny,
+                                     K(0),
+                                     K(0),
+                                     nup,
+                                     qx,
+                                     qy,
+                                     channel,
+                                     &terms,
+                                     &psi,
+                                     20).unwrap();
+                let expected = static_weight(3, 3, &states, channel, qx, qy);
+                assert!((cf.norm.powi(2) - expected).abs() < 1e-10);
+
+                // the spectral weights of the continued fraction, which
+                // terminates within the 14 dimensional target sector
+                let len = cf.beta.iter().position(|b| b.abs() < 1e-10).unwrap() + 1;
+                let (_, evecs) =
+                    tridiagonal_eigh(&cf.alpha[..len], &cf.beta[..len - 1]).unwrap();
+                let integrated = (0..len).map(|j| evecs[j].powi(2)).sum::<f64>()
+                                 * cf.norm.powi(2);
+                assert!((integrated - expected).abs() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn excitation_momentum_follows_convention() {
+        // S-(q) takes the ferromagnet into the magnon labelled q in the
+        // convention of the bases, which is -q in the other one
+        let (nx, ny, n) = (Dim(4), Dim(3), 12);
+        let torus = LatticeSettings::default();
+        let basis = |kx, ky, nup, convention| {
+            consv::ks::bloch_states_in(nx, ny, &torus, convention, K(kx), K(ky), nup)
+                .unwrap()
+        };
+        let conventions = [(Convention::Plus, Convention::Minus),
+                           (Convention::Minus, Convention::Plus)];
+        for &(convention, other) in conventions.iter() {
+            let ferro = basis(0, 0, n, convention);
+            let psi = [Complex::new(1., 0.)];
+            for &(qx, qy) in [(1, 0), (0, 1), (1, 2), (3, 1)].iter() {
+                let excited = excite(&ferro, &psi, Channel::SMinus, qx, qy).unwrap();
+                let v = project(&basis(qx, qy, n - 1, convention), &excited);
+                assert!((norm(&v) - 1.).abs() < 1e-12);
+                let v = project(&basis(qx, qy, n - 1, other), &excited);
+                assert!(norm(&v) < 1e-12);
+            }
+        }
+    }
+
+    // S(q) = <S_-q · S_q> is the weight of Sz(q) plus half those of S+(q) and
+    // S-(q) at the same label, as both take their phases from momentum_angle,
+    // on a shifted lattice too
+    #[test]
+    fn structure_factor_is_the_sum_of_the_channels() {
+        let (nx, ny, nup) = (Dim(4), Dim(3), 6);
+        let terms = heisenberg();
+        for &shift in [0, 1].iter() {
+            let settings = LatticeSettings { shift,
+                                             ..LatticeSettings::default() };
+            let op = OpHandle::ks_in(nx, ny, &settings, Convention::Plus, K(1),
+                                     K(0), nup, &terms).unwrap();
+            let (_, psi) = ground_state(&op, 1e-12, 300, true).unwrap();
+            let psi = psi.unwrap();
+            let sq = structure_factor(op.bfuncs(), &psi).unwrap();
+            for (q, &s) in sq.iter().enumerate() {
+                let (qx, qy) = (q as u32 % 4, q as u32 / 4);
+                let weight = |channel| {
+                    dsf_lanczos_in(nx, ny, &settings, Convention::Plus, K(1), K(0),
+                                   nup, qx, qy, channel, &terms, &psi, 0).unwrap()
+                                                                         .norm
+                                                                         .powi(2)
+                };
+                let expected = weight(Channel::Sz)
+                               + 0.5 * (weight(Channel::SPlus)
+                                        + weight(Channel::SMinus));
+                assert!((s - expected).abs() < 1e-10, "{} {}", shift, q);
+            }
+        }
+    }
+
+    #[test]
+    fn vanishing_excitation() {
+        let (nx, ny) = (Dim(3), Dim(2));
+        let terms = heisenberg();
+        let psi = vec![Complex::new(1., 0.)];
+        let cf = dsf_lanczos(nx,
+                             ny,
+                             K(0),
+                             K(0),
+                             6,
+                             1,
+                             0,
+                             Channel::SPlus,
+                             &terms,
+                             &psi,
+                             4).unwrap();
+        assert_eq!(cf.norm, 0.);
+        assert_eq!(cf.alpha, vec![0.; 4]);
+        assert!(dsf_lanczos(nx,
+                            ny,
+                            K(0),
+                            K(0),
+                            6,
+                            3,
+                            0,
+                            Channel::Sz,
+                            &terms,
+                            &psi,
+                            4).is_err());
+        assert!(Channel::from_raw(3).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use libc::c_char;
+use std::{
+    any::Any,
+    cell::RefCell,
+    ffi::CString,
+    fmt, io, mem,
+    panic::{self, AssertUnwindSafe},
+    process, ptr, thread
+};
+
+/// Status codes handed back to callers across the FFI. Zero means success and
+/// every failure is negative so callers can simply test for `< 0`.
+pub const SUCCESS: i32 = 0;
+pub const ERR_INVALID_ARGUMENT: i32 = -1;
+pub const ERR_INVALID_TERM: i32 = -2;
+pub const ERR_IO: i32 = -3;
+pub const ERR_HDF5: i32 = -4;
+pub const ERR_GROUP_EXISTS: i32 = -5;
+pub const ERR_PANIC: i32 = -6;
+pub const ERR_NOT_CONVERGED: i32 = -7;
+pub const ERR_TOO_LARGE: i32 = -8;
+pub const ERR_ALREADY_FREED: i32 = -9;
+pub const ERR_CANCELLED: i32 = -10;
+pub const ERR_LATTICE_TOO_LARGE: i32 = -11;
+pub const ERR_INCONSISTENT: i32 = -12;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidArgument(&'static str),
+    InvalidTerm(u32),
+    Io(io::Error),
+    Hdf5(String),
+    GroupExists(String),
+    Panic,
+    NotConverged,
+    TooLarge(u32),
+    Cancelled,
+    LatticeTooLarge(u64),
+    /// A check of a strict build failed, see OrbitTable::strict
+    Inconsistent(String)
+}
+
+impl Error {
+    /// The status code corresponding to the error
+    pub fn status(&self) -> i32 {
+        match *self {
+            Error::InvalidArgument(_) => ERR_INVALID_ARGUMENT,
+            Error::InvalidTerm(_) => ERR_INVALID_TERM,
+            Error::Io(_) => ERR_IO,
+            Error::Hdf5(_) => ERR_HDF5,
+            Error::GroupExists(_) => ERR_GROUP_EXISTS,
+            Error::Panic => ERR_PANIC,
+            Error::NotConverged => ERR_NOT_CONVERGED,
+            Error::TooLarge(_) => ERR_TOO_LARGE,
+            Error::Cancelled => ERR_CANCELLED,
+            Error::LatticeTooLarge(_) => ERR_LATTICE_TOO_LARGE,
+            Error::Inconsistent(_) => ERR_INCONSISTENT
+        }
+    }
+
+    /// The status code of the error, keeping its message for
+    /// last_error_message so that a caller handed back only the code, or an
+    /// empty result, can still tell what went wrong. Error::Panic leaves the
+    /// message of the panic kept by catch_panic in place.
+    pub fn report(&self) -> i32 {
+        if let Error::Panic = *self {
+            return self.status();
+        }
+        set_last_error(&self.to_string());
+        self.status()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidArgument(arg) => write!(f, "invalid argument: {}", arg),
+            Error::InvalidTerm(kind) => write!(f, "invalid term kind {}", kind),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Hdf5(ref msg) => write!(f, "HDF5 error: {}", msg),
+            Error::GroupExists(ref name) => {
+                write!(f, "group \"{}\" already exists", name)
+            }
+            Error::Panic => write!(f, "internal error"),
+            Error::NotConverged => write!(f, "eigensolver did not converge"),
+            Error::TooLarge(dims) => {
+                write!(f, "dimension {} is too large for a dense matrix", dims)
+            }
+            Error::Cancelled => write!(f, "cancelled by the caller"),
+            Error::LatticeTooLarge(sites) => {
+                write!(f,
+                       "system too large: {} sites, at most {} are supported",
+                       sites,
+                       ::common::MAX_SITES)
+            }
+            Error::Inconsistent(ref msg) => {
+                write!(f, "internal inconsistency: {}", msg)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error { Error::Io(e) }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Collapse a result into a status code for the FFI, keeping the message of an
+/// error (see Error::report)
+pub fn status<T>(result: Result<T>) -> i32 {
+    match result {
+        Ok(_) => SUCCESS,
+        Err(e) => e.report()
+    }
+}
+
+thread_local! {
+    // the message of the last error reported or panic caught on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(msg: &str) {
+    let msg = msg.replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).ok());
+}
+
+// Aborts the process if dropped while the thread unwinds. Used where a panic
+// cannot be turned into an error, since unwinding into the frames of a foreign
+// caller is undefined behavior.
+struct AbortOnUnwind;
+
+impl Drop for AbortOnUnwind {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            process::abort();
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Run "f", turning a panic into Error::Panic so that it never unwinds
+/// through the frames of a foreign caller. The panic message is kept for
+/// last_error_message. Whatever "f" was working on when it panicked is
+/// abandoned, so it must not leave shared state half updated.
+pub fn catch_panic<R, F: FnOnce() -> R>(f: F) -> Result<R> {
+    let guard = AbortOnUnwind;
+    let result =
+        panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+            set_last_error(&panic_message(&*payload));
+            Error::Panic
+        });
+    mem::forget(guard);
+    result
+}
+
+/// The message of the last error reported (see Error::report) or panic caught
+/// by catch_panic on the calling thread, or null if there was none. The string
+/// is owned by the library and stays valid until the next error on the same
+/// thread.
+pub fn last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+                  last.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr())
+              })
+}
@@ -0,0 +1,238 @@
+//! Real-time evolution exp(-iHt)|psi> by the Lanczos method. Each step builds
+//! a small Krylov space from the current state and exponentiates the
+//! tridiagonal projection of the operator exactly. The same Krylov space serves
+//! any step length, so a step that turns out too long for the error estimate is
+//! shortened without further applications of the operator.
+use num_complex::Complex;
+use std::cmp;
+
+use error::{Error, Result};
+use lanczos::{axpy, krylov, norm, tridiagonal_eigh, Krylov, LinearOperator};
+
+/// Steps are never split further than this, relative to the whole evolution
+const MIN_STEP: f64 = 1e-12;
+
+/// exp(-i T dt) e_1 for the tridiagonal T with eigenvalues "evals" and
+/// eigenvectors in the columns of the row-major "evecs"
+fn exp_e1(evals: &[f64], evecs: &[f64], dt: f64) -> Vec<Complex<f64>> {
+    let m = evals.len();
+    (0..m).map(|k| {
+              (0..m).fold(Complex::new(0., 0.), |acc, j| {
+                        acc + Complex::from_polar(&(evecs[k * m + j] * evecs[j]),
+                                                  &(-evals[j] * dt))
+                    })
+          })
+          .collect()
+}
+
+/// exp(-i op t)|psi> for a hermitian "op", using Krylov spaces of at most
+/// "krylov_dim" vectors. Steps are shortened until the estimated error of
+/// each is below tol * |step / t| relative to the norm of "psi", so the total
+/// error, including that of the norm, stays below "tol". Returns the evolved
+/// state and the number of applications of "op".
+pub fn time_evolve<A: LinearOperator>(op: &A, psi: &[Complex<f64>], t: f64,
+                                      krylov_dim: u32, tol: f64)
+                                      -> Result<(Vec<Complex<f64>>, u64)> {
+    if psi.len() != op.dim() {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    if krylov_dim == 0 {
+        return Err(Error::InvalidArgument("krylov_dim"));
+    }
+    if tol.is_nan() || tol <= 0. {
+        return Err(Error::InvalidArgument("tol"));
+    }
+    if !t.is_finite() {
+        return Err(Error::InvalidArgument("t"));
+    }
+
+    let mut psi = psi.to_vec();
+    let mut matvecs = 0;
+    let n0 = norm(&psi);
+    if n0 == 0. || t == 0. {
+        return Ok((psi, matvecs));
+    }
+    let m = cmp::min(krylov_dim as usize, psi.len());
+    let mut done = 0.;
+    while done < t.abs() {
+        let Krylov { basis, alpha, beta } = krylov(op, &psi, m, &mut matvecs)?;
+        let (evals, evecs) = tridiagonal_eigh(&alpha, &beta[..alpha.len() - 1])?;
+        let residual = beta[alpha.len() - 1];
+
+        let mut dt = t.abs() - done;
+        let coeffs = loop {
+            let coeffs = exp_e1(&evals, &evecs, dt * t.signum());
+            // the part of the exact result outside the Krylov space is
+            // approximately the residual times the last coefficient
+            let err = residual * coeffs[coeffs.len() - 1].norm();
+            if err <= tol * dt / t.abs() {
+                break coeffs;
+            }
+            dt /= 2.;
+            if dt < MIN_STEP * t.abs() {
+                return Err(Error::NotConverged);
+            }
+        };
+
+        let n = norm(&psi);
+        for x in psi.iter_mut() {
+            *x = Complex::new(0., 0.);
+        }
+        for (u, &c) in basis.iter().zip(coeffs.iter()) {
+            axpy(-c * n, u, &mut psi);
+        }
+        done += dt;
+    }
+    Ok((psi, matvecs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::*;
+    use lanczos::{dot, start_vector};
+    use matfree::OpHandle;
+
+    fn heisenberg(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32) -> OpHandle {
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term { kind:  TermKind::HSsXy,
+                            l:     I(2),
+                            coeff: 0.4 }];
+        OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap()
+    }
+
+    // a row-major dense copy of an operator, which is much cheaper to apply
+    // than the matrix-free original
+    struct Dense {
+        n: usize,
+        a: Vec<Complex<f64>>
+    }
+
+    impl Dense {
+        fn new(op: &OpHandle) -> Dense {
+            let n = op.dim() as usize;
+            let mut a = vec![Complex::new(0., 0.); n * n];
+            let mut e = vec![Complex::new(0., 0.); n];
+            let mut col = vec![Complex::new(0., 0.); n];
+            for j in 0..n {
+                e[j] = Complex::new(1., 0.);
+                op.apply(&e, &mut col).unwrap();
+                e[j] = Complex::new(0., 0.);
+                for i in 0..n {
+                    a[i * n + j] = col[i];
+                }
+            }
+            Dense { n, a }
+        }
+    }
+
+    impl LinearOperator for Dense {
+        fn dim(&self) -> usize { self.n }
+
+        fn apply(&self, x: &[Complex<f64>], y: &mut [Complex<f64>]) -> Result<()> {
+            for (i, yi) in y.iter_mut().enumerate() {
+                let row = &self.a[i * self.n..(i + 1) * self.n];
+                *yi = row.iter().zip(x.iter()).map(|(a, b)| a * b)
+                     .fold(Complex::new(0., 0.), |acc, x| acc + x);
+            }
+            Ok(())
+        }
+    }
+
+    fn matmul(a: &[Complex<f64>], b: &[Complex<f64>], n: usize)
+              -> Vec<Complex<f64>> {
+        let mut c = vec![Complex::new(0., 0.); n * n];
+        for i in 0..n {
+            for k in 0..n {
+                for j in 0..n {
+                    c[i * n + j] += a[i * n + k] * b[k * n + j];
+                }
+            }
+        }
+        c
+    }
+
+    // exp(-i h t) by scaling and squaring a Taylor series
+    fn dense_expm(h: &Dense, t: f64) -> Vec<Complex<f64>> {
+        let n = h.n;
+        let mut a = h.a
+                     .iter()
+                     .map(|&x| x * Complex::new(0., -t))
+                     .collect::<Vec<_>>();
+        let scale = a.iter().map(|x| x.norm()).sum::<f64>();
+        let squarings = scale.log2().max(0.).ceil() as i32 + 1;
+        for x in a.iter_mut() {
+            *x /= 2_f64.powi(squarings);
+        }
+
+        let mut exp = vec![Complex::new(0., 0.); n * n];
+        let mut term = exp.clone();
+        for i in 0..n {
+            exp[i * n + i] = Complex::new(1., 0.);
+            term[i * n + i] = Complex::new(1., 0.);
+        }
+        for k in 1..30 {
+            term = matmul(&term, &a, n);
+            for x in term.iter_mut() {
+                *x /= k as f64;
+            }
+            for (x, y) in exp.iter_mut().zip(term.iter()) {
+                *x += *y;
+            }
+        }
+        for _ in 0..squarings {
+            exp = matmul(&exp, &exp, n);
+        }
+        exp
+    }
+
+    #[test]
+    fn matches_dense_expm() {
+        // the whole 3 x 2 sector fits in one Krylov space, the 3 x 3 one does
+        // not, so longer evolutions there have to be split
+        let cases = [(Dim(3), Dim(2), K(0), K(0), 3, 4),
+                     (Dim(3), Dim(3), K(1), K(0), 4, 8)];
+        for &(nx, ny, kx, ky, nup, krylov_dim) in cases.iter() {
+            let op = heisenberg(nx, ny, kx, ky, nup);
+            let dense = Dense::new(&op);
+            let n = dense.n;
+            let psi = start_vector(n, 3);
+            for &t in [0.1, 1., 4.].iter() {
+                let (evolved, matvecs) =
+                    time_evolve(&op, &psi, t, krylov_dim, 1e-10).unwrap();
+                let exp = dense_expm(&dense, t);
+                for i in 0..n {
+                    let exact = (0..n).map(|j| exp[i * n + j] * psi[j])
+                                      .fold(Complex::new(0., 0.), |acc, x| acc + x);
+                    assert!((evolved[i] - exact).norm() < 1e-9);
+                }
+                assert!((norm(&evolved) - 1.).abs() < 1e-10);
+                if t > 1. && n > krylov_dim as usize {
+                    assert!(matvecs > krylov_dim as u64);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conserves_energy() {
+        let op = Dense::new(&heisenberg(Dim(4), Dim(3), K(1), K(2), 6));
+        let psi = start_vector(op.n, 5);
+        let energy = |v: &[Complex<f64>]| {
+            let mut w = vec![Complex::new(0., 0.); op.n];
+            op.apply(v, &mut w).unwrap();
+            dot(v, &w).re
+        };
+
+        let (evolved, _) = time_evolve(&op, &psi, 50., 20, 1e-10).unwrap();
+        assert!((energy(&evolved) - energy(&psi)).abs() < 1e-9);
+        assert!((norm(&evolved) - 1.).abs() < 1e-10);
+
+        // and back to the start
+        let (back, _) = time_evolve(&op, &evolved, -50., 20, 1e-10).unwrap();
+        for (a, b) in back.iter().zip(psi.iter()) {
+            assert!((*a - *b).norm() < 1e-9);
+        }
+    }
+}
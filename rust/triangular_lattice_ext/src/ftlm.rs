@@ -0,0 +1,287 @@
+//! Thermal averages by the finite-temperature Lanczos method. The trace over a
+//! sector is sampled with random vectors and the Boltzmann factors are
+//! evaluated on the Krylov space of each, so the thermodynamics of sectors far
+//! too large to diagonalize follow from a few hundred applications of the
+//! Hamiltonian. Only the partial sums are returned: the sums of different
+//! sectors add, and the averages are taken once all sectors are in.
+use num_complex::Complex;
+use std::{cmp, sync::Arc};
+
+use common::*;
+use error::{Error, Result};
+use lanczos::{dot, krylov, start_vector, tridiagonal_eigh, Krylov, LinearOperator};
+use matfree::OpHandle;
+
+/// Add the contributions of the normalized start vector "v" to "sums",
+/// multiplied by "weight". The Boltzmann factors are not shifted, so sums from
+/// different sectors and different calls can be added directly.
+fn accumulate<A, B>(ham: &A, obs: &B, v: &[Complex<f64>], m: usize, temps: &[f64],
+                    weight: f64, sums: &mut [ThermalSums])
+                    -> Result<()>
+    where A: LinearOperator,
+          B: LinearOperator
+{
+    let mut matvecs = 0;
+    let Krylov { basis, alpha, beta } = krylov(ham, v, m, &mut matvecs)?;
+    let k = alpha.len();
+    let (evals, evecs) = tridiagonal_eigh(&alpha, &beta[..k - 1])?;
+
+    let mut ov = vec![Complex::new(0., 0.); v.len()];
+    obs.apply(v, &mut ov)?;
+    // <v_i|O|v> for the Lanczos vectors v_i
+    let proj = basis.iter().map(|u| dot(u, &ov)).collect::<Vec<_>>();
+
+    for (j, &e) in evals.iter().enumerate() {
+        // <v|psi_j> and <psi_j|O|v> for the Ritz vector psi_j
+        let overlap = evecs[j];
+        let o = (0..k).fold(Complex::new(0., 0.), |acc, i| {
+                          acc + evecs[i * k + j] * proj[i]
+                      });
+        for (s, &t) in sums.iter_mut().zip(temps.iter()) {
+            let boltzmann = weight * (-e / t).exp();
+            s.z += boltzmann * overlap * overlap;
+            s.energy += boltzmann * overlap * overlap * e;
+            s.observable += boltzmann * (overlap * o).re;
+        }
+    }
+    Ok(())
+}
+
+/// The partial sums of the partition function, the energy and the observable
+/// "obs" at each of the temperatures "temps", estimated from "r" random
+/// vectors with Krylov spaces of at most "m" vectors under "ham". The random
+/// vectors are drawn from "seed", so equal arguments give equal results.
+pub fn ftlm<A, B>(ham: &A, obs: &B, r: u32, m: u32, temps: &[f64], seed: u64)
+                  -> Result<Vec<ThermalSums>>
+    where A: LinearOperator,
+          B: LinearOperator
+{
+    if obs.dim() != ham.dim() {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    if r == 0 {
+        return Err(Error::InvalidArgument("r"));
+    }
+    if m == 0 {
+        return Err(Error::InvalidArgument("m"));
+    }
+    if temps.iter().any(|&t| !(t > 0. && t.is_finite())) {
+        return Err(Error::InvalidArgument("temps"));
+    }
+
+    let mut sums = vec![ThermalSums::default(); temps.len()];
+    let dim = ham.dim();
+    if dim == 0 {
+        return Ok(sums);
+    }
+    let m = cmp::min(m as usize, dim);
+    let weight = dim as f64 / r as f64;
+    for i in 0..r as u64 {
+        let v = start_vector(dim, seed.wrapping_add(i));
+        accumulate(ham, obs, &v, m, temps, weight, &mut sums)?;
+    }
+    Ok(sums)
+}
+
+/// ftlm in the (kx, ky, nup) sector with the Hamiltonian and the observable
+/// given as terms
+pub fn ks_ftlm(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, ham_terms: &[Term],
+               obs_term: &Term, r: u32, m: u32, temps: &[f64], seed: u64)
+               -> Result<Vec<ThermalSums>> {
+    if !obs_term.kind.conserves_sz() {
+        return Err(Error::InvalidTerm(obs_term.kind as u32));
+    }
+    let ham = OpHandle::ks(nx, ny, kx, ky, nup, ham_terms)?;
+    let obs = OpHandle::new(Arc::new(ham.bfuncs().clone()), &[*obs_term]);
+    ftlm(&ham, &obs, r, m, temps, seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lanczos::symmetric_eigh;
+
+    fn hamiltonian() -> Vec<Term> {
+        vec![Term { kind:  TermKind::HSsZ,
+                    l:     I(1),
+                    coeff: 1. },
+             Term { kind:  TermKind::HSsXy,
+                    l:     I(1),
+                    coeff: 1. },
+             Term { kind:  TermKind::HSsXy,
+                    l:     I(2),
+                    coeff: 0.3 }]
+    }
+
+    fn observable() -> Term {
+        Term { kind:  TermKind::SsXy,
+               l:     I(1),
+               coeff: 1. }
+    }
+
+    // the real matrix [[Re A, -Im A], [Im A, Re A]] of an operator, which is
+    // symmetric up to rounding
+    fn real_embedding<A: LinearOperator>(op: &A) -> Vec<f64> {
+        let n = op.dim();
+        let mut a = vec![0.; 4 * n * n];
+        let mut e = vec![Complex::new(0., 0.); n];
+        let mut col = vec![Complex::new(0., 0.); n];
+        for j in 0..n {
+            e[j] = Complex::new(1., 0.);
+            op.apply(&e, &mut col).unwrap();
+            e[j] = Complex::new(0., 0.);
+            for (i, c) in col.iter().enumerate() {
+                a[i * 2 * n + j] = c.re;
+                a[i * 2 * n + j + n] = -c.im;
+                a[(i + n) * 2 * n + j] = c.im;
+                a[(i + n) * 2 * n + j + n] = c.re;
+            }
+        }
+        a
+    }
+
+    // the exact sums by dense diagonalization. Every eigenvalue of the real
+    // embedding appears twice, hence the factors of 1/2.
+    fn exact(ham: &OpHandle, obs: &OpHandle, temps: &[f64]) -> Vec<ThermalSums> {
+        let n = 2 * ham.dim() as usize;
+        let mut sums = vec![ThermalSums::default(); temps.len()];
+        if n == 0 {
+            return sums;
+        }
+        let h = real_embedding(ham);
+        let h = (0..n * n).map(|x| 0.5 * (h[x] + h[(x % n) * n + x / n]))
+                          .collect::<Vec<_>>();
+        let (evals, evecs) = symmetric_eigh(&h, n).unwrap();
+        let o = real_embedding(obs);
+        for (k, &e) in evals.iter().enumerate() {
+            let mut ok = 0.;
+            for i in 0..n {
+                for j in 0..n {
+                    ok += evecs[i * n + k] * o[i * n + j] * evecs[j * n + k];
+                }
+            }
+            for (s, &t) in sums.iter_mut().zip(temps.iter()) {
+                let boltzmann = 0.5 * (-e / t).exp();
+                s.z += boltzmann;
+                s.energy += boltzmann * e;
+                s.observable += boltzmann * ok;
+            }
+        }
+        sums
+    }
+
+    fn sectors() -> Vec<(K, K, u32)> {
+        let mut sectors = Vec::new();
+        for kx in 0..3 {
+            for ky in 0..2 {
+                for nup in 0..7 {
+                    sectors.push((K(kx), K(ky), nup));
+                }
+            }
+        }
+        sectors
+    }
+
+    fn close(a: f64, b: f64, tol: f64) -> bool {
+        (a - b).abs() <= tol * b.abs().max(1.)
+    }
+
+    #[test]
+    fn exact_with_complete_start_vectors() {
+        // the unit vectors sample the trace exactly and their Krylov spaces
+        // are exhausted, so the estimators reproduce the exact sums
+        let temps = [0.1, 1., 10.];
+        for &(kx, ky, nup) in sectors().iter() {
+            let ham =
+                OpHandle::ks(Dim(3), Dim(2), kx, ky, nup, &hamiltonian()).unwrap();
+            let obs = OpHandle::new(Arc::new(ham.bfuncs().clone()), &[observable()]);
+            let dim = ham.dim() as usize;
+            let mut sums = vec![ThermalSums::default(); temps.len()];
+            for i in 0..dim {
+                let mut v = vec![Complex::new(0., 0.); dim];
+                v[i] = Complex::new(1., 0.);
+                accumulate(&ham, &obs, &v, dim, &temps, 1., &mut sums).unwrap();
+            }
+            for (s, x) in sums.iter().zip(exact(&ham, &obs, &temps).iter()) {
+                assert!(close(s.z, x.z, 1e-10));
+                assert!(close(s.energy, x.energy, 1e-10));
+                assert!(close(s.observable, x.observable, 1e-10));
+            }
+        }
+    }
+
+    #[test]
+    fn random_vectors_3x2() {
+        let temps = [2., 10.];
+        let mut total = vec![ThermalSums::default(); temps.len()];
+        let mut expected = vec![ThermalSums::default(); temps.len()];
+        for &(kx, ky, nup) in sectors().iter() {
+            let sums = ks_ftlm(Dim(3),
+                               Dim(2),
+                               kx,
+                               ky,
+                               nup,
+                               &hamiltonian(),
+                               &observable(),
+                               400,
+                               10,
+                               &temps,
+                               7).unwrap();
+            let again = ks_ftlm(Dim(3),
+                                Dim(2),
+                                kx,
+                                ky,
+                                nup,
+                                &hamiltonian(),
+                                &observable(),
+                                400,
+                                10,
+                                &temps,
+                                7).unwrap();
+            let ham =
+                OpHandle::ks(Dim(3), Dim(2), kx, ky, nup, &hamiltonian()).unwrap();
+            let obs = OpHandle::new(Arc::new(ham.bfuncs().clone()), &[observable()]);
+            let x = exact(&ham, &obs, &temps);
+            for i in 0..temps.len() {
+                assert_eq!(sums[i].z, again[i].z);
+                total[i].z += sums[i].z;
+                total[i].energy += sums[i].energy;
+                total[i].observable += sums[i].observable;
+                expected[i].z += x[i].z;
+                expected[i].energy += x[i].energy;
+                expected[i].observable += x[i].observable;
+            }
+        }
+        // the thermal averages are only sampled, but closely enough to tell
+        // a wrong estimator from a right one
+        for (s, x) in total.iter().zip(expected.iter()) {
+            assert!(close(s.z, x.z, 0.05));
+            assert!(close(s.energy / s.z, x.energy / x.z, 0.05));
+            assert!(close(s.observable / s.z, x.observable / x.z, 0.05));
+        }
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let ham =
+            OpHandle::ks(Dim(3), Dim(2), K(0), K(0), 3, &hamiltonian()).unwrap();
+        let obs = OpHandle::new(Arc::new(ham.bfuncs().clone()), &[observable()]);
+        assert!(ftlm(&ham, &obs, 0, 4, &[1.], 0).is_err());
+        assert!(ftlm(&ham, &obs, 4, 0, &[1.], 0).is_err());
+        assert!(ftlm(&ham, &obs, 4, 4, &[0.], 0).is_err());
+        let flip = Term { kind:  TermKind::HSsPpmm,
+                          l:     I(1),
+                          coeff: 1. };
+        assert!(ks_ftlm(Dim(3),
+                        Dim(2),
+                        K(0),
+                        K(0),
+                        3,
+                        &hamiltonian(),
+                        &flip,
+                        4,
+                        4,
+                        &[1.],
+                        0).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Export of operators and sector metadata into HDF5 files. Every sector lives
+//! in its own group so a whole momentum scan can be collected into one file:
+//!
+//! ```text
+//! /<group_name>/nx, ny, kx, ky, nup, dimension     (scalars)
+//! /<group_name>/index_base, column_major           (scalars)
+//! /<group_name>/metadata                           (JSON as UTF-8 bytes)
+//! /<group_name>/term_kind, term_l, term_coeff      (one entry per term)
+//! /<group_name>/row, col, data_re, data_im         (COO arrays)
+//! /<group_name>/leads                              (optional)
+//! ```
+//!
+//! The COO arrays hold the sum of all the terms weighted by their coefficients.
+//! The entries of different terms at the same (row, col) are summed, so every
+//! pair appears once, and the entries come out sorted by column and then by
+//! row. The indices start at "index_base" (0 or 1). If "column_major" is 1
+//! the entries are sorted that way in any case; see IndexLayout.
+//!
+//! The terms are summed row by row as they are generated, so besides the basis
+//! and the COO arrays written out only the rows in flight are held (see
+//! ops::blocks_in_order for the formula), never a copy per term.
+use hdf5;
+
+use assemble::permuted;
+use blochfunc::BlochFuncSet;
+use common::*;
+use consv;
+use error::{Error, Result};
+use num_complex::Complex;
+use ops::{self, ElementSink};
+
+impl From<hdf5::Error> for Error {
+    fn from(e: hdf5::Error) -> Error { Error::Hdf5(format!("{}", e)) }
+}
+
+struct Coo {
+    row:    Vec<u32>,
+    col:    Vec<u32>,
+    data:   Vec<CComplex<f64>>,
+    sorted: bool
+}
+
+impl Coo {
+    fn with_capacity(n: usize) -> Coo {
+        Coo { row:    Vec::with_capacity(n),
+              col:    Vec::with_capacity(n),
+              data:   Vec::with_capacity(n),
+              sorted: false }
+    }
+
+    fn set_layout(&mut self, layout: IndexLayout) {
+        layout.rebase(IndexLayout::default(), &mut self.row);
+        layout.rebase(IndexLayout::default(), &mut self.col);
+        if self.sorted {
+            return;
+        }
+        if let Some(perm) = layout.permutation(&self.col, &self.row) {
+            self.row = permuted(&self.row, &perm);
+            self.col = permuted(&self.col, &perm);
+            self.data = permuted(&self.data, &perm);
+        }
+    }
+}
+
+impl ElementSink for Coo {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        self.row.push(row);
+        self.col.push(col);
+        self.data.push(CComplex::from_num_complex(val));
+    }
+}
+
+fn write_scalar<T: hdf5::H5Type>(group: &hdf5::Group, name: &str, val: T)
+                                 -> Result<()> {
+    group.new_dataset::<T>()
+         .create(name, ())?
+         .write_scalar(&val)?;
+    Ok(())
+}
+
+fn write_vec<T: hdf5::H5Type>(group: &hdf5::Group, name: &str, v: &[T])
+                              -> Result<()> {
+    group.new_dataset::<T>()
+         .create(name, v.len())?
+         .write_raw(v)?;
+    Ok(())
+}
+
+fn write_sector(group: &hdf5::Group, bfuncs: &BlochFuncSet, kx: K, ky: K,
+                nup: u32, terms: &[Term], coo: &Coo, layout: IndexLayout,
+                with_basis: bool)
+                -> Result<()> {
+    write_scalar(group, "nx", bfuncs.nx.raw_int())?;
+    write_scalar(group, "ny", bfuncs.ny.raw_int())?;
+    write_scalar(group, "kx", kx.raw_int())?;
+    write_scalar(group, "ky", ky.raw_int())?;
+    write_scalar(group, "nup", nup)?;
+    write_scalar(group, "dimension", bfuncs.nonzero)?;
+    write_scalar(group, "index_base", layout.base())?;
+    write_scalar(group, "column_major", layout.column_major as u32)?;
+    let mut metadata = Metadata::new(bfuncs, kx, ky, Some(nup)).with_terms(terms)
+                                                               .with_layout(layout);
+    metadata.nnz = Some(coo.row.len() as u64);
+    write_vec(group, "metadata", metadata.to_json().as_bytes())?;
+
+    let kinds = terms.iter().map(|t| t.kind as u32).collect::<Vec<u32>>();
+    let ls = terms.iter()
+                  .map(|t| t.l.raw_int() as u32)
+                  .collect::<Vec<u32>>();
+    let coeffs = terms.iter().map(|t| t.coeff).collect::<Vec<f64>>();
+    write_vec(group, "term_kind", &kinds)?;
+    write_vec(group, "term_l", &ls)?;
+    write_vec(group, "term_coeff", &coeffs)?;
+
+    write_vec(group, "row", &coo.row)?;
+    write_vec(group, "col", &coo.col)?;
+    let data_re = coo.data.iter().map(|c| c.re).collect::<Vec<f64>>();
+    write_vec(group, "data_re", &data_re)?;
+    let data_im = coo.data.iter().map(|c| c.im).collect::<Vec<f64>>();
+    write_vec(group, "data_im", &data_im)?;
+
+    if with_basis {
+        let leads = bfuncs.iter()
+                          .map(|b| b.lead.raw_int())
+                          .collect::<Vec<u64>>();
+        write_vec(group, "leads", &leads)?;
+    }
+    Ok(())
+}
+
+/// Build the given terms in the (kx, ky, nup) sector and write them into the
+/// group "group_name" of the file at "path". The file is created if it does not
+/// exist and appended to otherwise. An existing group is never overwritten. The
+/// COO arrays are laid out according to "layout".
+pub fn export_ks(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term],
+                 path: &str, group_name: &str, layout: IndexLayout,
+                 with_basis: bool)
+                 -> Result<()> {
+    for term in terms.iter() {
+        if !term.kind.conserves_sz() {
+            return Err(Error::InvalidTerm(term.kind as u32));
+        }
+    }
+
+    // open the file before the (potentially long) build so a locked file
+    // fails early
+    let file = hdf5::File::open(path, "a")?;
+    if file.link_exists(group_name) {
+        return Err(Error::GroupExists(group_name.to_string()));
+    }
+
+    let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+    let dims = bfuncs.nonzero;
+    let nnz = terms.iter()
+                   .map(|t| ops::nnz_bound(t, &bfuncs, 0..dims))
+                   .sum();
+    let mut coo = Coo::with_capacity(nnz);
+    ops::terms_into(terms, &bfuncs, &mut coo);
+    // the rows come merged and in order (see ops::terms_rows_into_with_progress),
+    // which is column-major whatever the number of terms
+    coo.sorted = true;
+    coo.set_layout(layout);
+
+    let group = file.create_group(group_name)?;
+    write_sector(&group, &bfuncs, kx, ky, nup, terms, &coo, layout, with_basis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn export_round_trip() {
+        let path = env::temp_dir().join("spinsys_export_ks_test.h5");
+        let _ = fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+        let xy = Term { kind:  TermKind::HSsXy,
+                        l:     I(1),
+                        coeff: 1.0 };
+        let z = Term { kind:  TermKind::HSsZ,
+                       l:     I(2),
+                       coeff: 0.5 };
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let one_based = IndexLayout { one_based:    true,
+                                      column_major: true };
+        for (n, terms) in [vec![xy], vec![xy, z]].iter().enumerate() {
+            let mut expected = Coo::with_capacity(0);
+            ops::terms_into(terms, &bfuncs, &mut expected);
+            for &layout in [IndexLayout::default(), one_based].iter() {
+                let name = format!("sector_{}_{}", n, layout.base());
+                export_ks(nx, ny, kx, ky, nup, terms, path, &name, layout, true)
+                    .unwrap();
+                let file = hdf5::File::open(path, "r").unwrap();
+                let group = file.group(&name).unwrap();
+                let scalar = |name| {
+                    group.dataset(name).unwrap().read_scalar::<u32>().unwrap()
+                };
+                assert_eq!([scalar("nx"), scalar("ny"), scalar("kx"),
+                            scalar("ky"), scalar("nup")],
+                           [4, 3, 1, 2, 6]);
+                assert_eq!(scalar("dimension"), bfuncs.nonzero);
+                assert_eq!(scalar("index_base"), layout.base());
+                assert_eq!(scalar("column_major"), layout.column_major as u32);
+                let indices = |name| {
+                    group.dataset(name).unwrap().read_raw::<u32>().unwrap()
+                };
+                let (row, col) = (indices("row"), indices("col"));
+                // sorted by column, then by row, with every position once
+                assert!(row.iter()
+                           .zip(col.iter())
+                           .zip(row.iter().zip(col.iter()).skip(1))
+                           .all(|(a, b)| a < b));
+                let base = layout.base();
+                assert!(row.iter().eq(expected.row.iter().map(|&i| i + base)));
+                assert!(col.iter().eq(expected.col.iter().map(|&i| i + base)));
+                let values = |name| {
+                    group.dataset(name).unwrap().read_raw::<f64>().unwrap()
+                };
+                assert!(values("data_re").iter()
+                                         .eq(expected.data.iter().map(|c| &c.re)));
+                assert!(values("data_im").iter()
+                                         .eq(expected.data.iter().map(|c| &c.im)));
+                assert_eq!(indices("term_kind"),
+                           terms.iter().map(|t| t.kind as u32).collect::<Vec<_>>());
+                assert_eq!(values("term_coeff"),
+                           terms.iter().map(|t| t.coeff).collect::<Vec<_>>());
+                let leads = group.dataset("leads").unwrap().read_raw::<u64>();
+                assert_eq!(leads.unwrap().len(), bfuncs.nonzero as usize);
+
+                let metadata = group.dataset("metadata")
+                                    .unwrap()
+                                    .read_raw::<u8>()
+                                    .unwrap();
+                let mut written = Metadata::new(&bfuncs, kx, ky, Some(nup))
+                    .with_terms(terms)
+                    .with_layout(layout);
+                written.nnz = Some(row.len() as u64);
+                assert_eq!(String::from_utf8(metadata).unwrap(), written.to_json());
+            }
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
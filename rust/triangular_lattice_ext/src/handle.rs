@@ -0,0 +1,242 @@
+//! Matrices handed to external callers behind an opaque handle. The arrays are
+//! read through accessor functions and released with coord_matrix_free. A
+//! handle is not the address of its matrix but an id drawn from a counter that
+//! never repeats, under which the matrix is kept in a registry until it is
+//! freed. coord_matrix_free deallocates the matrix at once, and since no later
+//! matrix gets the same id, any further free of the handle, or access through
+//! it, finds nothing and is reported instead of reaching another matrix.
+use assemble::permuted;
+use common::*;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex
+    }
+};
+
+// the id of the next handle, never 0 so that no handle is null
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+// the matrices handed out and not yet freed, by the id of their handle. They
+// are boxed so they stay in place while the map changes.
+static LIVE: Mutex<BTreeMap<usize, Box<CoordMatrixHandle>>> =
+    Mutex::new(BTreeMap::new());
+
+/// A matrix built on the Rust side, owned by an external caller until it is
+/// released, with the layout its index arrays are in
+pub struct CoordMatrixHandle {
+    pub matrix: OwnedCoordMatrix<CComplex<f64>>,
+    pub layout: IndexLayout
+}
+
+/// Hand "matrix" over to an external caller behind a handle, to be released
+/// with coord_matrix_free
+pub fn into_raw(matrix: OwnedCoordMatrix<CComplex<f64>>) -> *mut CoordMatrixHandle {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let handle = CoordMatrixHandle { matrix,
+                                     layout: IndexLayout::default() };
+    LIVE.lock().unwrap().insert(id, Box::new(handle));
+    id as *mut CoordMatrixHandle
+}
+
+/// The matrix behind "handle", or None if it is null or has been freed. The
+/// reference must not be used once the handle is freed.
+pub unsafe fn live<'a>(handle: *const CoordMatrixHandle)
+                       -> Option<&'a CoordMatrixHandle> {
+    let live = LIVE.lock().unwrap();
+    live.get(&(handle as usize))
+        .map(|mat| &*(&**mat as *const CoordMatrixHandle))
+}
+
+/// Same as live for a handle to be modified
+pub unsafe fn live_mut<'a>(handle: *mut CoordMatrixHandle)
+                           -> Option<&'a mut CoordMatrixHandle> {
+    let mut live = LIVE.lock().unwrap();
+    live.get_mut(&(handle as usize))
+        .map(|mat| &mut *(&mut **mat as *mut CoordMatrixHandle))
+}
+
+/// Deallocate the matrix behind "handle". Returns false if it is not a live
+/// handle, in which case nothing is touched.
+pub fn free(handle: *mut CoordMatrixHandle) -> bool {
+    let mat = LIVE.lock().unwrap().remove(&(handle as usize));
+    mat.is_some()
+}
+
+impl CoordMatrixHandle {
+    /// Rearrange the arrays according to "layout". Once sorted in column-major
+    /// order the elements stay sorted even if a layout without that flag is
+    /// requested later, which is still a valid order.
+    pub fn set_layout(&mut self, layout: IndexLayout) {
+        let mat = &mut self.matrix;
+        layout.rebase(self.layout, &mut mat.col);
+        layout.rebase(self.layout, &mut mat.row);
+        if let Some(perm) = layout.permutation(&mat.col, &mat.row) {
+            mat.data = permuted(&mat.data, &perm);
+            mat.col = permuted(&mat.col, &perm);
+            mat.row = permuted(&mat.row, &perm);
+        }
+        self.layout = IndexLayout { column_major: layout.column_major
+                                                  || self.layout.column_major,
+                                    ..layout };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coord_matrix_col;
+    use coord_matrix_data;
+    use coord_matrix_free;
+    use coord_matrix_nnz;
+    use coord_matrix_row;
+    use coord_matrix_set_layout;
+    use error;
+    use k_term_matrix;
+    use k_term_matrix_shifted;
+    use ks_h_ss_xy;
+    use ks_term_matrix;
+    use request_free;
+    use spinsys_last_error;
+    use std::{ffi::CStr, mem, ptr::null_mut};
+    use test_support::thread_allocated;
+
+    #[test]
+    fn double_free_is_reported() {
+        let term = CTerm { kind:  TermKind::HSsXy as u32,
+                           l:     1,
+                           coeff: 1. };
+        unsafe {
+            let handle = ks_term_matrix(4, 3, 1, 0, 6, term, null_mut());
+            assert!(!handle.is_null());
+            let mat = ks_h_ss_xy(4, 3, 1, 0, 6, 1);
+            assert_eq!(coord_matrix_nnz(handle), mat.data.len as u64);
+            let col =
+                ::std::slice::from_raw_parts(coord_matrix_col(handle), mat.col.len);
+            assert_eq!(col, mat.col.as_slice());
+            request_free(mat);
+
+            assert_eq!(coord_matrix_free(handle), error::SUCCESS);
+            assert_eq!(coord_matrix_free(handle), error::ERR_ALREADY_FREED);
+            assert!(coord_matrix_data(handle).is_null());
+            assert_eq!(coord_matrix_nnz(handle), 0);
+            assert_eq!(coord_matrix_free(::std::ptr::null_mut()),
+                       error::ERR_INVALID_ARGUMENT);
+        }
+    }
+
+    #[test]
+    fn free_gives_the_handle_back() {
+        let cycle = || unsafe {
+            let handle = into_raw(OwnedCoordMatrix::empty());
+            assert!(live(handle).is_some());
+            assert_eq!(coord_matrix_free(handle), error::SUCCESS);
+            assert!(live(handle).is_none());
+        };
+        // the registry keeps a node around once it has held a handle
+        cycle();
+        let start = thread_allocated();
+        for _ in 0..1000 {
+            cycle();
+        }
+        let leaked = thread_allocated() - start;
+        assert!(leaked < 10 * mem::size_of::<CoordMatrixHandle>() as isize);
+    }
+
+    #[test]
+    fn freed_handles_are_never_reused() {
+        let freed = into_raw(OwnedCoordMatrix::empty());
+        assert!(free(freed));
+        // the matrices handed out afterwards, which may well be allocated where
+        // the freed one was, all get handles of their own
+        let later = (0..100).map(|_| into_raw(OwnedCoordMatrix::empty()))
+                            .collect::<Vec<_>>();
+        assert!(later.iter().all(|&handle| handle != freed));
+        assert_eq!(unsafe { coord_matrix_free(freed) }, error::ERR_ALREADY_FREED);
+        for handle in later {
+            assert!(unsafe { live(handle) }.is_some());
+            assert!(free(handle));
+        }
+    }
+
+    #[test]
+    fn layouts() {
+        let term = CTerm { kind:  TermKind::HSsXy as u32,
+                           l:     1,
+                           coeff: 1. };
+        let handle = unsafe { ks_term_matrix(3, 3, 1, 1, 4, term, null_mut()) };
+        let triplets = |base: u32| unsafe {
+            let mat = live(handle).unwrap();
+            let nnz = coord_matrix_nnz(handle) as usize;
+            let col = ::std::slice::from_raw_parts(coord_matrix_col(handle), nnz);
+            let row = ::std::slice::from_raw_parts(coord_matrix_row(handle), nnz);
+            (0..nnz).map(|k| (row[k] - base, col[k] - base, mat.matrix.data[k].re))
+                    .collect::<Vec<_>>()
+        };
+        let zero_based = triplets(0);
+        // the builders emit the elements in column-major order already
+        assert!(zero_based.windows(2)
+                          .all(|w| (w[0].0, w[0].1) < (w[1].0, w[1].1)));
+
+        unsafe {
+            assert_eq!(coord_matrix_set_layout(handle, INDEX_ONE_BASED),
+                       error::SUCCESS);
+            assert_eq!(triplets(1), zero_based);
+            assert!(triplets(0).iter().all(|t| t.0 >= 1 && t.1 >= 1));
+
+            assert_eq!(coord_matrix_set_layout(handle,
+                                               INDEX_ONE_BASED
+                                               | ORDER_COLUMN_MAJOR),
+                       error::SUCCESS);
+            let sorted = triplets(1);
+            assert!(sorted.windows(2)
+                          .all(|w| (w[0].0, w[0].1) < (w[1].0, w[1].1)));
+            let mut expected = zero_based.clone();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(sorted, expected);
+
+            // back to zero-based indices, staying sorted
+            assert_eq!(coord_matrix_set_layout(handle, 0), error::SUCCESS);
+            assert_eq!(triplets(0), expected);
+
+            assert_eq!(coord_matrix_set_layout(handle, 4),
+                       error::ERR_INVALID_ARGUMENT);
+            coord_matrix_free(handle);
+        }
+    }
+
+    fn last_error() -> String {
+        let msg = unsafe { CStr::from_ptr(spinsys_last_error()) };
+        msg.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn failures_are_reported() {
+        let xy = CTerm { kind:  TermKind::HSsXy as u32,
+                         l:     1,
+                         coeff: 1. };
+        let invalid = CTerm { kind:  99,
+                              l:     1,
+                              coeff: 1. };
+        let mut status = error::SUCCESS;
+        unsafe {
+            assert!(k_term_matrix(4, 3, 1, 2, invalid, &mut status).is_null());
+            assert_eq!(status, error::ERR_INVALID_TERM);
+            assert!(last_error().contains("invalid term kind 99"));
+            assert!(ks_term_matrix(4, 3, 0, 0, 13, xy, &mut status).is_null());
+            assert_eq!(status, error::ERR_INVALID_ARGUMENT);
+            assert!(last_error().contains("nup"));
+            assert!(k_term_matrix_shifted(4, 3, 1, 2, xy, 4, &mut status).is_null());
+            assert_eq!(status, error::ERR_INVALID_ARGUMENT);
+            assert!(last_error().contains("shift"));
+
+            let handle = k_term_matrix(4, 3, 1, 2, xy, &mut status);
+            assert_eq!(status, error::SUCCESS);
+            coord_matrix_free(handle);
+        }
+        // the builders without a status still leave the reason behind
+        let mat = ks_h_ss_xy(4, 3, 0, 3, 6, 1);
+        assert!(mat.data.ptr.is_null());
+        assert!(last_error().contains("ky"));
+    }
+}
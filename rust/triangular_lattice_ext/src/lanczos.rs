@@ -0,0 +1,739 @@
+//! Lanczos eigensolver for hermitian operators. The Krylov vectors are fully
+//! reorthogonalized at every step, which keeps spurious copies of converged
+//! eigenvalues ("ghosts") out of the spectrum at the cost of storing the whole
+//! Krylov basis.
+use num_complex::Complex;
+use std::{cmp, f64};
+
+use common::*;
+use error::{Error, Result};
+use matfree::OpHandle;
+
+/// Anything that can compute y = A x for a hermitian A
+pub trait LinearOperator {
+    fn dim(&self) -> usize;
+    fn apply(&self, x: &[Complex<f64>], y: &mut [Complex<f64>]) -> Result<()>;
+}
+
+impl LinearOperator for OpHandle {
+    fn dim(&self) -> usize { OpHandle::dim(self) as usize }
+
+    fn apply(&self, x: &[Complex<f64>], y: &mut [Complex<f64>]) -> Result<()> {
+        OpHandle::apply(self, x, y)
+    }
+}
+
+// the arrays are read as (data, (col, row)) like the callers of the builders do
+impl LinearOperator for CoordMatrix<CComplex<f64>> {
+    fn dim(&self) -> usize { self.nrows as usize }
+
+    fn apply(&self, x: &[Complex<f64>], y: &mut [Complex<f64>]) -> Result<()> {
+        let (data, col, row) = unsafe {
+            (self.data.as_slice(), self.col.as_slice(), self.row.as_slice())
+        };
+        coord_apply(data, col, row, self.dim(), x, y)
+    }
+}
+
+impl LinearOperator for OwnedCoordMatrix<CComplex<f64>> {
+    fn dim(&self) -> usize { self.nrows as usize }
+
+    fn apply(&self, x: &[Complex<f64>], y: &mut [Complex<f64>]) -> Result<()> {
+        coord_apply(&self.data, &self.col, &self.row, self.dim(), x, y)
+    }
+}
+
+// y = A x for the dim x dim matrix A with the arrays (data, (col, row))
+fn coord_apply(data: &[CComplex<f64>], col: &[u32], row: &[u32], dim: usize,
+               x: &[Complex<f64>], y: &mut [Complex<f64>])
+               -> Result<()> {
+    if x.len() != dim || y.len() != dim {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    for yi in y.iter_mut() {
+        *yi = Complex::new(0., 0.);
+    }
+    for ((c, &j), &i) in data.iter().zip(col.iter()).zip(row.iter()) {
+        y[j as usize] += Complex::new(c.re, c.im) * x[i as usize];
+    }
+    Ok(())
+}
+
+/// <u|v>
+pub fn dot(u: &[Complex<f64>], v: &[Complex<f64>]) -> Complex<f64> {
+    u.iter()
+     .zip(v.iter())
+     .fold(Complex::new(0., 0.), |acc, (a, b)| acc + a.conj() * *b)
+}
+
+pub fn norm(v: &[Complex<f64>]) -> f64 { dot(v, v).re.sqrt() }
+
+/// v = v - c u
+pub fn axpy(c: Complex<f64>, u: &[Complex<f64>], v: &mut [Complex<f64>]) {
+    for (a, b) in u.iter().zip(v.iter_mut()) {
+        *b -= c * *a;
+    }
+}
+
+/// Remove the components of "v" along the (orthonormal) "basis". Done twice
+/// since a single pass of Gram-Schmidt is not enough once "v" has lost most of
+/// its norm to the basis.
+pub fn orthogonalize(basis: &[Vec<Complex<f64>>], v: &mut [Complex<f64>]) {
+    for _ in 0..2 {
+        for u in basis.iter() {
+            let c = dot(u, v);
+            axpy(c, u, v);
+        }
+    }
+}
+
+/// A fixed pseudo-random starting vector. A random start makes it unlikely
+/// for the vector to be orthogonal to the eigenvectors sought by symmetry,
+/// while a fixed seed keeps the results reproducible.
+pub fn start_vector(dim: usize, seed: u64) -> Vec<Complex<f64>> {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    let mut next = || {
+        // xorshift64*
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let r = state.wrapping_mul(2685821657736338717);
+        (r >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+    };
+    let mut v = (0..dim).map(|_| Complex::new(next(), next()))
+                        .collect::<Vec<_>>();
+    let n = norm(&v);
+    for x in v.iter_mut() {
+        *x /= n;
+    }
+    v
+}
+
+/// Eigenvalues and eigenvectors of the real symmetric tridiagonal matrix with
+/// "diag" on the diagonal and "offdiag" on the sub- and super-diagonal, by the
+/// implicit QL method. The eigenvalues are sorted in ascending order and the
+/// eigenvectors are returned in the columns of a row-major n x n array.
+pub fn tridiagonal_eigh(diag: &[f64], offdiag: &[f64])
+                        -> Result<(Vec<f64>, Vec<f64>)> {
+    let n = diag.len();
+    let mut d = diag.to_vec();
+    let mut e = offdiag.to_vec();
+    e.resize(n, 0.);
+    let mut z = vec![0.; n * n];
+    for i in 0..n {
+        z[i * n + i] = 1.;
+    }
+
+    let eps = 2f64.powi(-52);
+    let mut f = 0.;
+    let mut tst1 = 0f64;
+    for l in 0..n {
+        tst1 = tst1.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while m < n - 1 && e[m].abs() > eps * tst1 {
+            m += 1;
+        }
+
+        if m > l {
+            let mut iter = 0;
+            loop {
+                iter += 1;
+                if iter > 30 {
+                    return Err(Error::NotConverged);
+                }
+                // compute the implicit shift
+                let g = d[l];
+                let mut p = (d[l + 1] - g) / (2. * e[l]);
+                let mut r = p.hypot(1.);
+                if p < 0. {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let h = g - d[l];
+                for di in d[l + 2..].iter_mut() {
+                    *di -= h;
+                }
+                f += h;
+
+                // implicit QL transformation
+                p = d[m];
+                let (mut c, mut c2, mut c3) = (1., 1., 1.);
+                let el1 = e[l + 1];
+                let (mut s, mut s2) = (0., 0.);
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    let g = c * e[i];
+                    let h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    for k in 0..n {
+                        let h = z[k * n + i + 1];
+                        z[k * n + i + 1] = s * z[k * n + i] + c * h;
+                        z[k * n + i] = c * z[k * n + i] - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+                if e[l].abs() <= eps * tst1 {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.;
+    }
+
+    // sort the eigenpairs
+    let mut order = (0..n).collect::<Vec<usize>>();
+    order.sort_by(|&a, &b| d[a].partial_cmp(&d[b]).unwrap());
+    let evals = order.iter().map(|&i| d[i]).collect::<Vec<f64>>();
+    let mut evecs = vec![0.; n * n];
+    for (col, &i) in order.iter().enumerate() {
+        for k in 0..n {
+            evecs[k * n + col] = z[k * n + i];
+        }
+    }
+    Ok((evals, evecs))
+}
+
+/// Eigenvalues and eigenvectors of the real symmetric n x n matrix "a"
+/// (row-major) by the cyclic Jacobi method, in the same layout as
+/// tridiagonal_eigh. Meant for the small projected matrices of the restarted
+/// solver below.
+pub fn symmetric_eigh(a: &[f64], n: usize) -> Result<(Vec<f64>, Vec<f64>)> {
+    let mut a = a.to_vec();
+    let mut z = vec![0.; n * n];
+    for i in 0..n {
+        z[i * n + i] = 1.;
+    }
+
+    let scale = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let mut converged = false;
+    for _ in 0..100 {
+        let off = (0..n).flat_map(|i| (0..n).map(move |j| (i, j)))
+                        .filter(|&(i, j)| i != j)
+                        .map(|(i, j)| a[i * n + j] * a[i * n + j])
+                        .sum::<f64>()
+                        .sqrt();
+        if off <= 1e-15 * scale {
+            converged = true;
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p * n + q];
+                if apq == 0. {
+                    continue;
+                }
+                // rotate in the (p, q) plane to zero out a[p][q]
+                let theta = (a[q * n + q] - a[p * n + p]) / (2. * apq);
+                let t = theta.signum() / (theta.abs() + theta.hypot(1.));
+                let c = 1. / t.hypot(1.);
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k * n + p], a[k * n + q]);
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p * n + k], a[q * n + k]);
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (zkp, zkq) = (z[k * n + p], z[k * n + q]);
+                    z[k * n + p] = c * zkp - s * zkq;
+                    z[k * n + q] = s * zkp + c * zkq;
+                }
+            }
+        }
+    }
+    if !converged {
+        return Err(Error::NotConverged);
+    }
+
+    let mut order = (0..n).collect::<Vec<usize>>();
+    order.sort_by(|&i, &j| a[i * n + i].partial_cmp(&a[j * n + j]).unwrap());
+    let evals = order.iter().map(|&i| a[i * n + i]).collect::<Vec<f64>>();
+    let mut evecs = vec![0.; n * n];
+    for (col, &i) in order.iter().enumerate() {
+        for k in 0..n {
+            evecs[k * n + col] = z[k * n + i];
+        }
+    }
+    Ok((evals, evecs))
+}
+
+/// Eigenvalues of the hermitian n x n matrix "a" (row-major) in ascending
+/// order. The real symmetric matrix [[Re a, -Im a], [Im a, Re a]] has the same
+/// eigenvalues, each twice, so symmetric_eigh does the work.
+pub fn hermitian_eigvals(a: &[Complex<f64>], n: usize) -> Result<Vec<f64>> {
+    let mut real = vec![0.; 4 * n * n];
+    for i in 0..n {
+        for j in 0..n {
+            let c = a[i * n + j];
+            real[i * 2 * n + j] = c.re;
+            real[i * 2 * n + j + n] = -c.im;
+            real[(i + n) * 2 * n + j] = c.im;
+            real[(i + n) * 2 * n + j + n] = c.re;
+        }
+    }
+    let (evals, _) = symmetric_eigh(&real, 2 * n)?;
+    Ok(evals.into_iter().step_by(2).collect())
+}
+
+/// An orthonormal Krylov basis together with the diagonal and the
+/// off-diagonal of the projection of the operator onto it. The off-diagonal
+/// has one element more than the diagonal, the norm of the residual left after
+/// the last vector, which is zero if the space is invariant.
+pub struct Krylov {
+    pub basis: Vec<Vec<Complex<f64>>>,
+    pub alpha: Vec<f64>,
+    pub beta:  Vec<f64>
+}
+
+/// The Krylov space of "v" under "op" with at most "m" vectors
+pub fn krylov<A: LinearOperator>(op: &A, v: &[Complex<f64>], m: usize,
+                                 matvecs: &mut u64)
+                                 -> Result<Krylov> {
+    let n0 = norm(v);
+    let mut basis = vec![v.iter().map(|&x| x / n0).collect::<Vec<_>>()];
+    let mut alpha = Vec::new();
+    let mut beta: Vec<f64> = Vec::new();
+    let mut w = vec![Complex::new(0., 0.); v.len()];
+    for j in 0..m {
+        op.apply(&basis[j], &mut w)?;
+        *matvecs += 1;
+        let wn = norm(&w);
+        let a = dot(&basis[j], &w).re;
+        axpy(Complex::new(a, 0.), &basis[j], &mut w);
+        if j > 0 {
+            axpy(Complex::new(beta[j - 1], 0.), &basis[j - 1], &mut w);
+        }
+        orthogonalize(&basis, &mut w);
+        let b = norm(&w);
+        alpha.push(a);
+        if b <= 1e-12 * wn.max(1.) {
+            beta.push(0.);
+            break;
+        }
+        beta.push(b);
+        if j + 1 < m {
+            basis.push(w.iter().map(|&x| x / b).collect());
+        }
+    }
+    Ok(Krylov { basis, alpha, beta })
+}
+
+/// The normalized linear combination of "basis" given by column "col" of the
+/// row-major array "coeffs" with "ncols" columns
+fn ritz_vector(basis: &[Vec<Complex<f64>>], coeffs: &[f64], ncols: usize,
+               col: usize)
+               -> Vec<Complex<f64>> {
+    let mut v = vec![Complex::new(0., 0.); basis[0].len()];
+    for (k, u) in basis.iter().enumerate() {
+        let c = coeffs[k * ncols + col];
+        for (x, y) in v.iter_mut().zip(u.iter()) {
+            *x += *y * c;
+        }
+    }
+    let n = norm(&v);
+    for x in v.iter_mut() {
+        *x /= n;
+    }
+    v
+}
+
+/// Normalize "v" after removing its components along "locked" and "basis".
+/// Returns false if nothing is left of it.
+fn orthonormalize(locked: &[Vec<Complex<f64>>], basis: &[Vec<Complex<f64>>],
+                  v: &mut [Complex<f64>])
+                  -> bool {
+    let n0 = norm(v);
+    orthogonalize(locked, v);
+    orthogonalize(basis, v);
+    let n = norm(v);
+    if n <= 1e-10 * n0 {
+        return false;
+    }
+    for x in v.iter_mut() {
+        *x /= n;
+    }
+    true
+}
+
+/// The lowest eigenvalue of "op" and, if "want_vector" is set, the
+/// corresponding normalized eigenvector. The iteration stops once the residual
+/// norm of the lowest Ritz pair drops below tol * max(1, |eigenvalue|), and an
+/// error is returned if that does not happen within "max_iter" steps.
+pub fn ground_state<A: LinearOperator>(
+    op: &A, tol: f64, max_iter: u32, want_vector: bool)
+    -> Result<(f64, Option<Vec<Complex<f64>>>)> {
+    ground_state_from(op, &start_vector(op.dim(), 1), tol, max_iter, want_vector)
+}
+
+/// Same as ground_state, starting the iteration from "start" instead of a
+/// fixed random vector. A start close to the ground state, such as that of a
+/// slightly different operator, cuts the number of iterations.
+pub fn ground_state_from<A: LinearOperator>(
+    op: &A, start: &[Complex<f64>], tol: f64, max_iter: u32, want_vector: bool)
+    -> Result<(f64, Option<Vec<Complex<f64>>>)> {
+    let dim = op.dim();
+    if dim == 0 {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    if start.len() != dim {
+        return Err(Error::InvalidArgument("start"));
+    }
+    if tol.is_nan() || tol <= 0. {
+        return Err(Error::InvalidArgument("tol"));
+    }
+    let n0 = norm(start);
+    if n0 == 0. {
+        return Err(Error::InvalidArgument("start"));
+    }
+
+    let mut basis = vec![start.iter().map(|&x| x / n0).collect::<Vec<_>>()];
+    let mut alpha: Vec<f64> = Vec::new();
+    let mut beta: Vec<f64> = Vec::new();
+    let mut w = vec![Complex::new(0., 0.); dim];
+    for j in 0..max_iter as usize {
+        op.apply(&basis[j], &mut w)?;
+        let a = dot(&basis[j], &w).re;
+        axpy(Complex::new(a, 0.), &basis[j], &mut w);
+        if j > 0 {
+            axpy(Complex::new(beta[j - 1], 0.), &basis[j - 1], &mut w);
+        }
+        orthogonalize(&basis, &mut w);
+        let b = norm(&w);
+        alpha.push(a);
+
+        let (evals, evecs) = tridiagonal_eigh(&alpha, &beta)?;
+        let m = alpha.len();
+        let theta = evals[0];
+        let residual = b * evecs[(m - 1) * m].abs();
+        // the Krylov space is exhausted once the basis spans the whole space
+        if residual <= tol * theta.abs().max(1.) || m == dim {
+            let vector = if want_vector {
+                Some(ritz_vector(&basis, &evecs, m, 0))
+            } else {
+                None
+            };
+            return Ok((theta, vector));
+        }
+
+        beta.push(b);
+        basis.push(w.iter().map(|&x| x / b).collect());
+    }
+    Err(Error::NotConverged)
+}
+
+/// Thick-restart Lanczos for the "nev" lowest eigenpairs of "op" restricted to
+/// the orthogonal complement of "locked", which must consist of eigenvectors of
+/// "op". At most "ncv" Krylov vectors are kept. On restart the Ritz vectors
+/// of the lowest (nev + ncv) / 2 Ritz values are kept along with the residual
+/// and the Krylov space is expanded again from there.
+fn thick_restart<A: LinearOperator>(op: &A, locked: &[Vec<Complex<f64>>],
+                                    nev: usize, ncv: usize, tol: f64,
+                                    restarts: &mut u32, max_restarts: u32,
+                                    seed: u64)
+                                    -> Result<Vec<(f64, Vec<Complex<f64>>)>> {
+    let dim = op.dim();
+    let avail = dim - locked.len();
+    let ncv = cmp::min(ncv, avail);
+    let nev = cmp::min(nev, ncv);
+
+    let mut v = start_vector(dim, seed);
+    if !orthonormalize(locked, &[], &mut v) {
+        return Ok(Vec::new());
+    }
+    let mut basis = vec![v];
+    // the projection of the operator onto the basis
+    let mut t = vec![0.; ncv * ncv];
+    let mut w = vec![Complex::new(0., 0.); dim];
+    let mut seed = seed;
+    loop {
+        let mut residual = 0.;
+        for j in basis.len() - 1..ncv {
+            op.apply(&basis[j], &mut w)?;
+            let wn = norm(&w);
+            orthogonalize(locked, &mut w);
+            let mut coeffs = vec![0.; j + 1];
+            for _ in 0..2 {
+                for (i, u) in basis.iter().enumerate() {
+                    let c = dot(u, &w);
+                    coeffs[i] += c.re;
+                    axpy(c, u, &mut w);
+                }
+            }
+            for (i, &c) in coeffs.iter().enumerate() {
+                t[i * ncv + j] = c;
+                t[j * ncv + i] = c;
+            }
+            let b = norm(&w);
+            if j + 1 == ncv {
+                residual = b;
+                break;
+            }
+            if b > 1e-10 * wn {
+                t[(j + 1) * ncv + j] = b;
+                t[j * ncv + j + 1] = b;
+                basis.push(w.iter().map(|&x| x / b).collect());
+            } else {
+                // invariant subspace found, continue with a fresh direction
+                seed += 1;
+                let mut v = start_vector(dim, seed);
+                orthonormalize(locked, &basis, &mut v);
+                basis.push(v);
+            }
+        }
+
+        let (theta, y) = symmetric_eigh(&t, ncv)?;
+        let converged = (0..nev).all(|i| {
+                                    let r = residual * y[(ncv - 1) * ncv + i].abs();
+                                    r <= tol * theta[i].abs().max(1.)
+                                });
+        // the basis spans the whole space once ncv == avail
+        if converged || ncv == avail {
+            return Ok((0..nev).map(|i| {
+                                  (theta[i], ritz_vector(&basis, &y, ncv, i))
+                              })
+                              .collect());
+        }
+        if *restarts >= max_restarts {
+            return Err(Error::NotConverged);
+        }
+        *restarts += 1;
+
+        let keep = cmp::min((nev + ncv) / 2, ncv - 1);
+        let mut new_basis = (0..keep).map(|i| ritz_vector(&basis, &y, ncv, i))
+                                     .collect::<Vec<_>>();
+        for x in t.iter_mut() {
+            *x = 0.;
+        }
+        for i in 0..keep {
+            t[i * ncv + i] = theta[i];
+        }
+        let mut r = w.clone();
+        if orthonormalize(locked, &new_basis, &mut r) {
+            for i in 0..keep {
+                let s = residual * y[(ncv - 1) * ncv + i];
+                t[keep * ncv + i] = s;
+                t[i * ncv + keep] = s;
+            }
+        } else {
+            seed += 1;
+            r = start_vector(dim, seed);
+            orthonormalize(locked, &new_basis, &mut r);
+        }
+        new_basis.push(r);
+        basis = new_basis;
+    }
+}
+
+/// Eigenvalues in ascending order and the eigenvectors that go with them
+pub type Eigenpairs = (Vec<f64>, Vec<Vec<Complex<f64>>>);
+
+/// The "nev" lowest eigenvalues of "op" in ascending order along with their
+/// eigenvectors, found by thick-restart Lanczos with at most "ncv" Krylov
+/// vectors. A Krylov space grown from a single vector only ever holds one
+/// vector of each degenerate eigenspace, so the solver is rerun on the
+/// orthogonal complement of the eigenvectors found so far, and the copies it
+/// turns up are merged in, until a run no longer finds anything below the
+/// highest eigenvalue wanted. "max_restarts" bounds the number of restarts
+/// over all runs.
+pub fn lowest_eigenpairs<A: LinearOperator>(
+    op: &A, nev: usize, ncv: usize, tol: f64, max_restarts: u32)
+    -> Result<Eigenpairs> {
+    let dim = op.dim();
+    if nev == 0 || nev > dim {
+        return Err(Error::InvalidArgument("nev"));
+    }
+    if ncv <= nev {
+        return Err(Error::InvalidArgument("ncv"));
+    }
+    if tol.is_nan() || tol <= 0. {
+        return Err(Error::InvalidArgument("tol"));
+    }
+
+    let mut energies: Vec<f64> = Vec::new();
+    let mut vectors: Vec<Vec<Complex<f64>>> = Vec::new();
+    let mut restarts = 0;
+    let mut seed = 1;
+    while vectors.len() < dim {
+        let found = thick_restart(op,
+                                  &vectors,
+                                  nev,
+                                  ncv,
+                                  tol,
+                                  &mut restarts,
+                                  max_restarts,
+                                  seed)?;
+        seed += ncv as u64;
+
+        let threshold = if energies.len() < nev {
+            f64::INFINITY
+        } else {
+            let last = energies[nev - 1];
+            last - tol * last.abs().max(1.)
+        };
+        let mut pairs = energies.into_iter()
+                                .zip(vectors)
+                                .collect::<Vec<_>>();
+        let nfound = pairs.len();
+        pairs.extend(found.into_iter().filter(|&(e, _)| e < threshold));
+        if pairs.len() == nfound {
+            let (e, v) = pairs.into_iter().unzip();
+            energies = e;
+            vectors = v;
+            break;
+        }
+        pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        pairs.truncate(nev);
+        let (e, v) = pairs.into_iter().unzip();
+        energies = e;
+        vectors = v;
+    }
+    Ok((energies, vectors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consv;
+
+    /// Eigenvalues of a hermitian operator by dense diagonalization, for
+    /// checking the Lanczos results on small sectors. The complex n x n matrix
+    /// A + iB is embedded in the real symmetric matrix [[A, -B], [B, A]],
+    /// which has the same eigenvalues, each appearing twice.
+    fn dense_eigenvalues<A: LinearOperator>(op: &A) -> Vec<f64> {
+        let n = op.dim();
+        let m = 2 * n;
+        let mut a = vec![0.; m * m];
+        let mut e = vec![Complex::new(0., 0.); n];
+        let mut col = vec![Complex::new(0., 0.); n];
+        for j in 0..n {
+            e[j] = Complex::new(1., 0.);
+            op.apply(&e, &mut col).unwrap();
+            e[j] = Complex::new(0., 0.);
+            for i in 0..n {
+                a[i * m + j] = col[i].re;
+                a[(i + n) * m + j + n] = col[i].re;
+                a[(i + n) * m + j] = col[i].im;
+                a[i * m + j + n] = -col[i].im;
+            }
+        }
+
+        let (evals, _) = symmetric_eigh(&a, m).unwrap();
+        evals.into_iter().step_by(2).collect()
+    }
+
+    fn heisenberg(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32) -> OpHandle {
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1))];
+        OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap()
+    }
+
+    #[test]
+    fn tridiagonal_eigh_test() {
+        // the second difference matrix has eigenvalues 2 - 2cos(kπ/(n + 1))
+        let n = 12;
+        let (evals, evecs) =
+            tridiagonal_eigh(&vec![2.; n], &vec![-1.; n - 1]).unwrap();
+        for k in 0..n {
+            let expected = 2. - 2. * ((k + 1) as f64 * PI / (n + 1) as f64).cos();
+            assert!((evals[k] - expected).abs() < 1e-12);
+            let v = (0..n).map(|i| evecs[i * n + k]).collect::<Vec<f64>>();
+            assert!((v.iter().map(|x| x * x).sum::<f64>() - 1.).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn ground_state_matches_dense_3x3() {
+        for &(kx, ky) in [(0, 0), (1, 2)].iter() {
+            let op = heisenberg(Dim(3), Dim(3), K(kx), K(ky), 4);
+            let dense = dense_eigenvalues(&op);
+            let (e0, v) = ground_state(&op, 1e-12, 300, true).unwrap();
+            assert!((e0 - dense[0]).abs() < 1e-10);
+
+            // residual of the eigenvector
+            let v = v.unwrap();
+            let mut hv = vec![Complex::new(0., 0.); v.len()];
+            op.apply(&v, &mut hv).unwrap();
+            axpy(Complex::new(e0, 0.), &v, &mut hv);
+            assert!(norm(&hv) < 1e-8);
+        }
+    }
+
+    #[test]
+    fn ground_state_4x4() {
+        // the ground state energy of the 16 site cluster, E0/N = -0.53472,
+        // from B. Bernu, P. Lecheminant, C. Lhuillier and L. Pierre, Phys.
+        // Rev. B 50, 10048 (1994)
+        let op = heisenberg(Dim(4), Dim(4), K(0), K(0), 8);
+        let (e0, _) = ground_state(&op, 1e-12, 300, false).unwrap();
+        assert!((e0 - -8.5555149175).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ground_state_on_coord_matrix() {
+        let (nx, ny, kx, ky, nup) = (Dim(3), Dim(3), K(0), K(0), 4);
+        let op = heisenberg(nx, ny, kx, ky, nup);
+        let mat = consv::ks::h_ss_z(nx, ny, kx, ky, nup,
+                                    BondRange::NearestNeighbor)
+                      .unwrap();
+        let (e_op, _) = ground_state(&op, 1e-12, 300, false).unwrap();
+        let (e_z, _) = ground_state(&mat, 1e-12, 300, false).unwrap();
+        assert!(e_op < e_z);
+        assert!((dense_eigenvalues(&mat)[0] - e_z).abs() < 1e-10);
+    }
+
+    #[test]
+    fn ground_state_reports_non_convergence() {
+        let op = heisenberg(Dim(4), Dim(3), K(0), K(0), 6);
+        match ground_state(&op, 1e-12, 3, false) {
+            Err(Error::NotConverged) => (),
+            _ => panic!("expected a convergence failure")
+        }
+    }
+
+    #[test]
+    fn lowest_eigenpairs_match_dense_4x3() {
+        for &(kx, ky) in [(0, 0), (2, 0), (1, 1)].iter() {
+            let op = heisenberg(Dim(4), Dim(3), K(kx), K(ky), 6);
+            let dense = dense_eigenvalues(&op);
+            let (energies, vectors) =
+                lowest_eigenpairs(&op, 10, 24, 1e-10, 500).unwrap();
+            assert_eq!(energies.len(), 10);
+            for (e, d) in energies.iter().zip(dense.iter()) {
+                assert!((e - d).abs() < 1e-10);
+            }
+            for (i, u) in vectors.iter().enumerate() {
+                for (j, v) in vectors.iter().enumerate() {
+                    let expected = if i == j { 1. } else { 0. };
+                    assert!((dot(u, v) - Complex::new(expected, 0.)).norm() < 1e-8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lowest_eigenpairs_find_degenerate_copies() {
+        // the spectrum of this sector is full of degenerate pairs
+        let op = heisenberg(Dim(3), Dim(3), K(0), K(0), 4);
+        let dense = dense_eigenvalues(&op);
+        let (energies, _) = lowest_eigenpairs(&op, 10, 12, 1e-10, 500).unwrap();
+        for (e, d) in energies.iter().zip(dense.iter()) {
+            assert!((e - d).abs() < 1e-10);
+        }
+    }
+}
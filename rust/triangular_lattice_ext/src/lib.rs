@@ -1,100 +1,3005 @@
+// the C entry points take their arguments flat and leave the pointer contracts
+// to the generated header; the other lints ask for std items newer than the
+// compilers this crate still builds with
+#![allow(clippy::too_many_arguments,
+         clippy::missing_safety_doc,
+         clippy::manual_div_ceil,
+         clippy::manual_is_multiple_of,
+         clippy::missing_const_for_thread_local,
+         clippy::derivable_impls)]
+
 extern crate fnv;
+#[cfg(feature = "hdf5")]
+extern crate hdf5;
 extern crate libc;
+extern crate memmap;
 extern crate num_bigint;
 extern crate num_complex;
 extern crate num_traits;
+#[cfg(feature = "python")]
+extern crate numpy;
+#[cfg(feature = "python")]
+extern crate pyo3;
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
 #[macro_use]
 mod buildtype;
 
+mod abi;
+pub mod api;
+pub mod assemble;
+mod basiscache;
 mod blochfunc;
 pub mod common;
 pub mod consv;
+mod diskbasis;
+mod dsf;
+pub mod error;
+mod evolve;
+mod ftlm;
+#[cfg(feature = "hdf5")]
+mod h5;
+mod handle;
+mod lanczos;
+mod matfree;
+mod observables;
 mod ops;
+mod pool;
+mod progress;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(test)]
+mod reference;
+mod rows;
 mod sitevector;
+mod spill;
+mod stiffness;
+mod stream;
+mod sweep;
+#[cfg(test)]
+mod test_support;
+#[cfg(any(test, feature = "validation"))]
+pub mod validation;
+
+use blochfunc::{Convention, Lookup};
+use common::{
+    BinaryBasis, BondList, BondRange, CComplex, CTerm, CoordMatrix, DenseMatrix,
+    Dim, IndexLayout, LatticeSettings, Orientation, OwnedCoordMatrix,
+    StateDiagnostics, Term, TermKind, ShellList, ThermalSums, TriangleList, Vector,
+    I, K
+};
+use diskbasis::{BasisHandle, MappedBasis};
+use error::{Error, Result};
+use handle::CoordMatrixHandle;
+use lanczos::LinearOperator;
+use libc::{c_char, c_void};
+use matfree::OpHandle;
+use num_complex::Complex;
+use progress::{Progress, ProgressCallback};
+use rows::{HamiltonianRows, RowCursor};
+use sitevector::{LatticeGeometry, Periodicity, SiteOrdering};
+use std::{
+    env,
+    ffi::{CStr, CString},
+    path::Path,
+    ptr, slice,
+    sync::Arc
+};
+use stream::ElementCallback;
+
+// helpers to convert raw arguments passed in from external callers
+unsafe fn terms_from_raw(terms: *const CTerm, nterms: u32) -> Result<Vec<Term>> {
+    if terms.is_null() {
+        return Err(Error::InvalidArgument("terms"));
+    }
+    slice::from_raw_parts(terms, nterms as usize)
+        .iter()
+        .map(|&t| Term::from_c(t).ok_or(Error::InvalidTerm(t.kind)))
+        .collect()
+}
+
+unsafe fn str_from_raw<'a>(s: *const c_char, name: &'static str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(Error::InvalidArgument(name));
+    }
+    CStr::from_ptr(s).to_str()
+                     .map_err(|_| Error::InvalidArgument(name))
+}
+
+unsafe fn optional_str_from_raw<'a>(s: *const c_char, name: &'static str)
+                                    -> Result<Option<&'a str>> {
+    if s.is_null() {
+        Ok(None)
+    } else {
+        str_from_raw(s, name).map(Some)
+    }
+}
+
+unsafe fn write_status(status: *mut i32, code: i32) {
+    if !status.is_null() {
+        *status = code;
+    }
+}
+
+// Every exported function runs its body through one of these so that a panic
+// is reported as ERR_PANIC, or the failure value of the function, instead of
+// unwinding into the caller. The panic message is kept for spinsys_last_error.
+fn guard<R, F: FnOnce() -> R>(on_panic: R, f: F) -> R {
+    error::catch_panic(f).unwrap_or(on_panic)
+}
 
-use common::{CComplex, CoordMatrix, Dim, I, K};
+// same as guard for functions that write their status code to "status"
+unsafe fn guard_status<R, F: FnOnce() -> R>(status: *mut i32, on_panic: R, f: F)
+                                            -> R {
+    error::catch_panic(f).unwrap_or_else(|e| {
+                             write_status(status, e.status());
+                             on_panic
+                         })
+}
+
+/// The message of the last error on the calling thread, or null if there was
+/// none: a failure reported as a status code or an empty or null result, or a
+/// panic, reported as ERR_PANIC or the failure value of the function. The
+/// string belongs to the library and stays valid until the next error on the
+/// same thread; it must not be freed.
+#[no_mangle]
+pub extern "C" fn spinsys_last_error() -> *const c_char {
+    error::last_error_message()
+}
+
+/// The version of the binary interface. Callers should refuse to use the
+/// library if it differs from the version they were written against.
+#[no_mangle]
+pub extern "C" fn spinsys_abi_version() -> u32 { guard(0, || abi::ABI_VERSION) }
+
+/// The crate version as a static string that must not be freed
+#[no_mangle]
+pub extern "C" fn spinsys_version_string() -> *const c_char {
+    guard(ptr::null(), || abi::VERSION.as_ptr() as *const c_char)
+}
+
+/// Write the sizes in bytes of CComplex_f64, Vector_u32,
+/// CoordMatrix_CComplex_f64, DenseMatrix_CComplex_f64, CTerm, StateDiagnostics,
+/// ThermalSums, BondList, TriangleList and ShellList, in that order, to "out",
+/// which has room for "len" numbers. Returns the number of sizes available,
+/// which may exceed "len" if structs are added later.
+#[no_mangle]
+pub unsafe extern "C" fn spinsys_struct_sizes(out: *mut u64, len: u32) -> u32 {
+    guard(0, || {
+        if !out.is_null() {
+            let out = slice::from_raw_parts_mut(out, len as usize);
+            for (o, &size) in out.iter_mut().zip(abi::STRUCT_SIZES.iter()) {
+                *o = size as u64;
+            }
+        }
+        abi::STRUCT_SIZES.len() as u32
+    })
+}
 
 // The following functions wrap functions in child modules so they could be
 // exported via the FFI without namespace collisions (the FFI follows C
-// convention so namespace doesn't exist.)
+// convention so namespace doesn't exist.) They have no status to report, so
+// parameters out of range give an empty matrix, with the reason in
+// spinsys_last_error; the variants taking a CTerm also return a status code.
 #[no_mangle]
 pub extern "C" fn k_h_ss_z(nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
                            -> CoordMatrix<CComplex<f64>> {
-    consv::k::h_ss_z(Dim(nx), Dim(ny), K(kx), K(ky), I(l as i32))
+    guard(empty_coord_matrix(), || k_matrix(nx, ny, kx, ky, TermKind::HSsZ, l))
 }
 
 #[no_mangle]
 pub extern "C" fn k_h_ss_xy(nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
                             -> CoordMatrix<CComplex<f64>> {
-    consv::k::h_ss_xy(Dim(nx), Dim(ny), K(kx), K(ky), I(l as i32))
+    guard(empty_coord_matrix(), || k_matrix(nx, ny, kx, ky, TermKind::HSsXy, l))
 }
 
 #[no_mangle]
 pub extern "C" fn k_h_ss_ppmm(nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
                               -> CoordMatrix<CComplex<f64>> {
-    consv::k::h_ss_ppmm(Dim(nx), Dim(ny), K(kx), K(ky), I(l as i32))
+    guard(empty_coord_matrix(), || {
+        k_matrix(nx, ny, kx, ky, TermKind::HSsPpmm, l)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn k_h_ss_pmz(nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
                              -> CoordMatrix<CComplex<f64>> {
-    consv::k::h_ss_pmz(Dim(nx), Dim(ny), K(kx), K(ky), I(l as i32))
+    guard(empty_coord_matrix(), || {
+        k_matrix(nx, ny, kx, ky, TermKind::HSsPmz, l)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn k_h_sss_chi(nx: u32, ny: u32, kx: u32, ky: u32)
                               -> CoordMatrix<CComplex<f64>> {
-    consv::k::h_sss_chi(Dim(nx), Dim(ny), K(kx), K(ky))
+    guard(empty_coord_matrix(), || {
+        k_matrix(nx, ny, kx, ky, TermKind::HSssChi, 0)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn k_ss_z(nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
                          -> CoordMatrix<CComplex<f64>> {
-    consv::k::ss_z(Dim(nx), Dim(ny), K(kx), K(ky), I(l as i32))
+    guard(empty_coord_matrix(), || k_matrix(nx, ny, kx, ky, TermKind::SsZ, l))
 }
 
 #[no_mangle]
 pub extern "C" fn k_ss_xy(nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
                           -> CoordMatrix<CComplex<f64>> {
-    consv::k::ss_xy(Dim(nx), Dim(ny), K(kx), K(ky), I(l as i32))
+    guard(empty_coord_matrix(), || k_matrix(nx, ny, kx, ky, TermKind::SsXy, l))
 }
 
 #[no_mangle]
 pub extern "C" fn ks_h_ss_z(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
                             -> CoordMatrix<CComplex<f64>> {
-    consv::ks::h_ss_z(Dim(nx), Dim(ny), K(kx), K(ky), nup, I(l as i32))
+    guard(empty_coord_matrix(), || {
+        ks_matrix(nx, ny, kx, ky, nup, TermKind::HSsZ, l)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ks_h_ss_xy(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
                              -> CoordMatrix<CComplex<f64>> {
-    consv::ks::h_ss_xy(Dim(nx), Dim(ny), K(kx), K(ky), nup, I(l as i32))
+    guard(empty_coord_matrix(), || {
+        ks_matrix(nx, ny, kx, ky, nup, TermKind::HSsXy, l)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ks_h_sss_chi(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32)
                                -> CoordMatrix<CComplex<f64>> {
-    consv::ks::h_sss_chi(Dim(nx), Dim(ny), K(kx), K(ky), nup)
+    guard(empty_coord_matrix(), || {
+        ks_matrix(nx, ny, kx, ky, nup, TermKind::HSssChi, 0)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ks_ss_z(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
                           -> CoordMatrix<CComplex<f64>> {
-    consv::ks::ss_z(Dim(nx), Dim(ny), K(kx), K(ky), nup, I(l as i32))
+    guard(empty_coord_matrix(), || {
+        ks_matrix(nx, ny, kx, ky, nup, TermKind::SsZ, l)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ks_ss_xy(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
                            -> CoordMatrix<CComplex<f64>> {
-    consv::ks::ss_xy(Dim(nx), Dim(ny), K(kx), K(ky), nup, I(l as i32))
+    guard(empty_coord_matrix(), || {
+        ks_matrix(nx, ny, kx, ky, nup, TermKind::SsXy, l)
+    })
+}
+
+// Streaming variants of the ks builders. Every element is handed to "cb" along
+// with "ctx" instead of being collected. Rows arrive in increasing order, the
+// columns of a row in increasing order and every (row, col) pair exactly once;
+// see the stream module for details.
+// Returns a status code.
+fn ks_stream(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, kind: TermKind,
+             l: u32, cb: Option<ElementCallback>, ctx: *mut c_void)
+             -> i32 {
+    let term = Term { kind,
+                      l: I(l as i32),
+                      coeff: 1. };
+    error::status(stream::ks_term(Dim(nx),
+                                  Dim(ny),
+                                  K(kx),
+                                  K(ky),
+                                  nup,
+                                  term,
+                                  cb,
+                                  ctx))
+}
+
+#[no_mangle]
+pub extern "C" fn ks_h_ss_z_stream(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32,
+                                   l: u32, cb: Option<ElementCallback>,
+                                   ctx: *mut c_void)
+                                   -> i32 {
+    guard(error::ERR_PANIC, || {
+        ks_stream(nx, ny, kx, ky, nup, TermKind::HSsZ, l, cb, ctx)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn ks_h_ss_xy_stream(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32,
+                                    l: u32, cb: Option<ElementCallback>,
+                                    ctx: *mut c_void)
+                                    -> i32 {
+    guard(error::ERR_PANIC, || {
+        ks_stream(nx, ny, kx, ky, nup, TermKind::HSsXy, l, cb, ctx)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn ks_h_sss_chi_stream(nx: u32, ny: u32, kx: u32, ky: u32,
+                                      nup: u32, cb: Option<ElementCallback>,
+                                      ctx: *mut c_void)
+                                      -> i32 {
+    guard(error::ERR_PANIC, || {
+        ks_stream(nx, ny, kx, ky, nup, TermKind::HSssChi, 0, cb, ctx)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn ks_ss_z_stream(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32,
+                                 l: u32, cb: Option<ElementCallback>,
+                                 ctx: *mut c_void)
+                                 -> i32 {
+    guard(error::ERR_PANIC, || {
+        ks_stream(nx, ny, kx, ky, nup, TermKind::SsZ, l, cb, ctx)
+    })
 }
 
-// accepts a pointer from external callers so Rust can dispose of the objects
-// passed to the caller
+#[no_mangle]
+pub extern "C" fn ks_ss_xy_stream(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32,
+                                  l: u32, cb: Option<ElementCallback>,
+                                  ctx: *mut c_void)
+                                  -> i32 {
+    guard(error::ERR_PANIC, || {
+        ks_stream(nx, ny, kx, ky, nup, TermKind::SsXy, l, cb, ctx)
+    })
+}
+
+/// Stream the sum of the "nterms" terms in the (kx, ky, nup) sector, each
+/// scaled by its coefficient, into "cb" as the streaming builders above do. The
+/// contributions of all terms to a (row, col) pair are merged. Returns a status
+/// code.
+#[no_mangle]
+pub unsafe extern "C" fn ks_terms_stream(nx: u32, ny: u32, kx: u32, ky: u32,
+                                         nup: u32, terms: *const CTerm,
+                                         nterms: u32, cb: Option<ElementCallback>,
+                                         ctx: *mut c_void)
+                                         -> i32 {
+    guard(error::ERR_PANIC, || {
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                         stream::ks_terms(Dim(nx), Dim(ny), K(kx), K(ky), nup,
+                                          &terms, cb, ctx)
+                     });
+        error::status(result)
+    })
+}
+
+/// Write the leading states of the (kx, ky, nup) basis to "path" so the row
+/// range builders below can skip the basis construction. Returns a status code.
+#[no_mangle]
+pub unsafe extern "C" fn ks_save_basis(nx: u32, ny: u32, kx: u32, ky: u32,
+                                       nup: u32, path: *const c_char)
+                                       -> i32 {
+    guard(error::ERR_PANIC, || {
+        let result =
+            str_from_raw(path, "path").and_then(|path| {
+                                          let bfuncs =
+                                              consv::ks::bloch_states(Dim(nx),
+                                                                      Dim(ny),
+                                                                      K(kx),
+                                                                      K(ky),
+                                                                      nup)?;
+                                          bfuncs.save(path, K(kx), K(ky), nup)
+                                      });
+        error::status(result)
+    })
+}
+
+// Row range variants of the ks builders for distributed builds. Only the rows
+// in [row_start, row_end) are generated, with global row and column indices. If
+// "basis_path" is not null the basis is read from a file written by
+// ks_save_basis. The status code is written to "status" if it is not null; on
+// failure the returned matrix is empty.
+unsafe fn ks_rows(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, kind: TermKind,
+                  l: u32, row_start: u32, row_end: u32,
+                  basis_path: *const c_char, status: *mut i32)
+                  -> CoordMatrix<CComplex<f64>> {
+    let term = Term { kind,
+                      l: I(l as i32),
+                      coeff: 1. };
+    let result = optional_str_from_raw(basis_path, "basis_path").and_then(|path| {
+                     consv::ks::term_rows(Dim(nx),
+                                          Dim(ny),
+                                          K(kx),
+                                          K(ky),
+                                          nup,
+                                          &term,
+                                          row_start..row_end,
+                                          path)
+                 });
+    match result {
+        Ok(mat) => {
+            write_status(status, error::SUCCESS);
+            mat.into_ffi()
+        }
+        Err(e) => {
+            write_status(status, e.report());
+            empty_coord_matrix()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_ss_z_rows(nx: u32, ny: u32, kx: u32, ky: u32,
+                                        nup: u32, l: u32, row_start: u32,
+                                        row_end: u32, basis_path: *const c_char,
+                                        status: *mut i32)
+                                        -> CoordMatrix<CComplex<f64>> {
+    guard_status(status, empty_coord_matrix(), || {
+        ks_rows(nx,
+                ny,
+                kx,
+                ky,
+                nup,
+                TermKind::HSsZ,
+                l,
+                row_start,
+                row_end,
+                basis_path,
+                status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_ss_xy_rows(nx: u32, ny: u32, kx: u32, ky: u32,
+                                         nup: u32, l: u32, row_start: u32,
+                                         row_end: u32, basis_path: *const c_char,
+                                         status: *mut i32)
+                                         -> CoordMatrix<CComplex<f64>> {
+    guard_status(status, empty_coord_matrix(), || {
+        ks_rows(nx,
+                ny,
+                kx,
+                ky,
+                nup,
+                TermKind::HSsXy,
+                l,
+                row_start,
+                row_end,
+                basis_path,
+                status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_sss_chi_rows(nx: u32, ny: u32, kx: u32, ky: u32,
+                                           nup: u32, row_start: u32,
+                                           row_end: u32,
+                                           basis_path: *const c_char,
+                                           status: *mut i32)
+                                           -> CoordMatrix<CComplex<f64>> {
+    guard_status(status, empty_coord_matrix(), || {
+        ks_rows(nx,
+                ny,
+                kx,
+                ky,
+                nup,
+                TermKind::HSssChi,
+                0,
+                row_start,
+                row_end,
+                basis_path,
+                status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_ss_z_rows(nx: u32, ny: u32, kx: u32, ky: u32,
+                                      nup: u32, l: u32, row_start: u32,
+                                      row_end: u32, basis_path: *const c_char,
+                                      status: *mut i32)
+                                      -> CoordMatrix<CComplex<f64>> {
+    guard_status(status, empty_coord_matrix(), || {
+        ks_rows(nx,
+                ny,
+                kx,
+                ky,
+                nup,
+                TermKind::SsZ,
+                l,
+                row_start,
+                row_end,
+                basis_path,
+                status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_ss_xy_rows(nx: u32, ny: u32, kx: u32, ky: u32,
+                                       nup: u32, l: u32, row_start: u32,
+                                       row_end: u32, basis_path: *const c_char,
+                                       status: *mut i32)
+                                       -> CoordMatrix<CComplex<f64>> {
+    guard_status(status, empty_coord_matrix(), || {
+        ks_rows(nx,
+                ny,
+                kx,
+                ky,
+                nup,
+                TermKind::SsXy,
+                l,
+                row_start,
+                row_end,
+                basis_path,
+                status)
+    })
+}
+
+// Variants of the ks builders that write the elements straight into arrays of
+// "cap" entries each provided by the caller, in the order of the matrices the
+// plain builders return, without allocating any arrays of their own. The
+// number of elements written is returned, or a negative status code on
+// failure. ks_term_nnz gives the capacity needed; with less the call fails
+// with ERR_INVALID_ARGUMENT and the contents of the arrays are unspecified, but
+// nothing is written past "cap". The pointers may be null if "cap" is zero.
+unsafe fn ks_into(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, kind: TermKind,
+                  l: u32, data: *mut CComplex<f64>, row: *mut u32, col: *mut u32,
+                  cap: u64)
+                  -> i64 {
+    let term = Term::new(kind, I(l as i32));
+    let result = out_slice(data, cap, "data").and_then(|data| {
+                     let rows = out_slice(row, cap, "row")?;
+                     let cols = out_slice(col, cap, "col")?;
+                     consv::ks::term_into_slices(Dim(nx),
+                                                 Dim(ny),
+                                                 K(kx),
+                                                 K(ky),
+                                                 nup,
+                                                 &term,
+                                                 data,
+                                                 cols,
+                                                 rows)
+                 });
+    match result {
+        Ok(nnz) => nnz as i64,
+        Err(e) => i64::from(e.report())
+    }
+}
+
+// an output array of "len" entries provided by the caller, null if empty
+unsafe fn out_slice<'a, T>(ptr: *mut T, len: u64, name: &'static str)
+                           -> Result<&'a mut [T]> {
+    if len == 0 {
+        Ok(&mut [])
+    } else if ptr.is_null() {
+        Err(Error::InvalidArgument(name))
+    } else {
+        Ok(slice::from_raw_parts_mut(ptr, len as usize))
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_ss_z_into(nx: u32, ny: u32, kx: u32, ky: u32,
+                                        nup: u32, l: u32,
+                                        data: *mut CComplex<f64>, row: *mut u32,
+                                        col: *mut u32, cap: u64)
+                                        -> i64 {
+    guard(i64::from(error::ERR_PANIC), || {
+        ks_into(nx, ny, kx, ky, nup, TermKind::HSsZ, l, data, row, col, cap)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_ss_xy_into(nx: u32, ny: u32, kx: u32, ky: u32,
+                                         nup: u32, l: u32,
+                                         data: *mut CComplex<f64>, row: *mut u32,
+                                         col: *mut u32, cap: u64)
+                                         -> i64 {
+    guard(i64::from(error::ERR_PANIC), || {
+        ks_into(nx, ny, kx, ky, nup, TermKind::HSsXy, l, data, row, col, cap)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_sss_chi_into(nx: u32, ny: u32, kx: u32, ky: u32,
+                                           nup: u32, data: *mut CComplex<f64>,
+                                           row: *mut u32, col: *mut u32,
+                                           cap: u64)
+                                           -> i64 {
+    guard(i64::from(error::ERR_PANIC), || {
+        ks_into(nx, ny, kx, ky, nup, TermKind::HSssChi, 0, data, row, col, cap)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_ss_z_into(nx: u32, ny: u32, kx: u32, ky: u32,
+                                      nup: u32, l: u32, data: *mut CComplex<f64>,
+                                      row: *mut u32, col: *mut u32, cap: u64)
+                                      -> i64 {
+    guard(i64::from(error::ERR_PANIC), || {
+        ks_into(nx, ny, kx, ky, nup, TermKind::SsZ, l, data, row, col, cap)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_ss_xy_into(nx: u32, ny: u32, kx: u32, ky: u32,
+                                       nup: u32, l: u32, data: *mut CComplex<f64>,
+                                       row: *mut u32, col: *mut u32, cap: u64)
+                                       -> i64 {
+    guard(i64::from(error::ERR_PANIC), || {
+        ks_into(nx, ny, kx, ky, nup, TermKind::SsXy, l, data, row, col, cap)
+    })
+}
+
+// Dense variants of the ks builders for small sectors. The matrix is stored in
+// column-major order and must be released with dense_matrix_free. If the
+// dimension of the sector exceeds the limit set with
+// spinsys_set_dense_max_dim (20000 by default) nothing is allocated and
+// ERR_TOO_LARGE is written to "status" (if not null) along with an empty
+// matrix.
+unsafe fn ks_dense(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, kind: TermKind,
+                   l: u32, status: *mut i32)
+                   -> DenseMatrix<CComplex<f64>> {
+    let term = Term::new(kind, I(l as i32));
+    let result = consv::ks::term_dense(Dim(nx), Dim(ny), K(kx), K(ky), nup, &term);
+    match result {
+        Ok(mat) => {
+            write_status(status, error::SUCCESS);
+            mat
+        }
+        Err(e) => {
+            write_status(status, e.report());
+            empty_dense_matrix()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_ss_z_dense(nx: u32, ny: u32, kx: u32, ky: u32,
+                                         nup: u32, l: u32, status: *mut i32)
+                                         -> DenseMatrix<CComplex<f64>> {
+    guard_status(status, empty_dense_matrix(), || {
+        ks_dense(nx, ny, kx, ky, nup, TermKind::HSsZ, l, status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_ss_xy_dense(nx: u32, ny: u32, kx: u32, ky: u32,
+                                          nup: u32, l: u32, status: *mut i32)
+                                          -> DenseMatrix<CComplex<f64>> {
+    guard_status(status, empty_dense_matrix(), || {
+        ks_dense(nx, ny, kx, ky, nup, TermKind::HSsXy, l, status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_h_sss_chi_dense(nx: u32, ny: u32, kx: u32, ky: u32,
+                                            nup: u32, status: *mut i32)
+                                            -> DenseMatrix<CComplex<f64>> {
+    guard_status(status, empty_dense_matrix(), || {
+        ks_dense(nx, ny, kx, ky, nup, TermKind::HSssChi, 0, status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_ss_z_dense(nx: u32, ny: u32, kx: u32, ky: u32,
+                                       nup: u32, l: u32, status: *mut i32)
+                                       -> DenseMatrix<CComplex<f64>> {
+    guard_status(status, empty_dense_matrix(), || {
+        ks_dense(nx, ny, kx, ky, nup, TermKind::SsZ, l, status)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ks_ss_xy_dense(nx: u32, ny: u32, kx: u32, ky: u32,
+                                        nup: u32, l: u32, status: *mut i32)
+                                        -> DenseMatrix<CComplex<f64>> {
+    guard_status(status, empty_dense_matrix(), || {
+        ks_dense(nx, ny, kx, ky, nup, TermKind::SsXy, l, status)
+    })
+}
+
+/// Set the largest dimension for which the dense builders allocate a matrix
+#[no_mangle]
+pub extern "C" fn spinsys_set_dense_max_dim(n: u32) {
+    guard((), || ops::set_dense_max_dim(n))
+}
+
+/// Run all parallel work on "n" threads from now on, 0 meaning all cores.
+/// Calls already in progress keep the thread count they started with. Returns
+/// a status code.
+#[no_mangle]
+pub extern "C" fn spinsys_set_threads(n: u32) -> i32 {
+    guard(error::ERR_PANIC, || error::status(pool::set_threads(n)))
+}
+
+/// Check that the spectrum of the XXZ model on the bonds of range "l" plus the
+/// chiral term in the (kx, ky) sector equals the union of its spectra in the
+/// (kx, ky, nup) sectors over all nup, diagonalizing both sides densely. Meant
+/// as a smoke test of a build on clusters whose k sector has at most 1024
+/// states. Returns SUCCESS if the spectra agree to 1e-10, ERR_INCONSISTENT
+/// with the largest deviation in the message of spinsys_last_error if they do
+/// not, and ERR_TOO_LARGE for a larger sector. Only built with the
+/// "validation" feature.
+#[cfg(any(test, feature = "validation"))]
+#[no_mangle]
+pub extern "C" fn ks_validate_sector_decomposition(nx: u32, ny: u32, kx: u32,
+                                                   ky: u32, l: u32)
+                                                   -> i32 {
+    guard(error::ERR_PANIC, || {
+        let (nx, ny, kx, ky, l) = (Dim(nx), Dim(ny), K(kx), K(ky), I(l as i32));
+        error::status(validation::sector_decomposition(nx, ny, kx, ky, l))
+    })
+}
+
+/// Keep the bases built in memory from now on for reuse by later calls on the
+/// same sector, as long as they take up no more than "max_bytes" together. The
+/// least recently used bases are dropped first. A basis is only reused for the
+/// lookup it was built for (see ks_term_matrix_lookup), so the results are the
+/// same as without the cache. 0, the default, turns the cache off and
+/// empties it.
+#[no_mangle]
+pub extern "C" fn spinsys_enable_basis_cache(max_bytes: u64) {
+    guard((), || basiscache::enable(max_bytes as usize))
+}
+
+/// Drop all bases kept by the basis cache, leaving it enabled. Handles built on
+/// one of them are unaffected.
+#[no_mangle]
+pub extern "C" fn spinsys_clear_basis_cache() { guard((), basiscache::clear) }
+
+/// Write the sum of the given terms in the (kx, ky, nup) sector into the group
+/// "group_name" of the HDF5 file at "path" along with the sector metadata, and
+/// the leading states of the basis if "with_basis" is set. "flags" selects the
+/// layout of the index arrays as in coord_matrix_set_layout. Returns a status
+/// code.
+#[cfg(feature = "hdf5")]
+#[no_mangle]
+pub unsafe extern "C" fn ks_export_h5(nx: u32, ny: u32, kx: u32, ky: u32,
+                                      nup: u32, terms: *const CTerm, nterms: u32,
+                                      with_basis: bool, flags: u32,
+                                      path: *const c_char,
+                                      group_name: *const c_char)
+                                      -> i32 {
+    guard(error::ERR_PANIC, || {
+        let layout = match IndexLayout::from_flags(flags) {
+            Some(layout) => layout,
+            None => return error::ERR_INVALID_ARGUMENT
+        };
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                                                      let path =
+                                                          str_from_raw(path,
+                                                                       "path")?;
+                                                      let group_name =
+                                             str_from_raw(group_name, "group_name")?;
+                                                      h5::export_ks(Dim(nx),
+                                                                    Dim(ny),
+                                                                    K(kx),
+                                                                    K(ky),
+                                                                    nup,
+                                                                    &terms,
+                                                                    path,
+                                                                    group_name,
+                                                                    layout,
+                                                                    with_basis)
+                                                  });
+        error::status(result)
+    })
+}
+
+/// A JSON description of the (kx, ky, nup) sector, as stored by the exporters,
+/// for workflows that keep the matrices in memory. Returns a null pointer if
+/// the labels are out of range or the string cannot be represented. The string
+/// must be released with spinsys_string_free.
+#[cfg(feature = "serde")]
+#[no_mangle]
+pub extern "C" fn ks_sector_metadata_json(nx: u32, ny: u32, kx: u32, ky: u32,
+                                          nup: u32)
+                                          -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        let bfuncs =
+            match consv::ks::bloch_states(Dim(nx), Dim(ny), K(kx), K(ky), nup) {
+                Ok(bfuncs) => bfuncs,
+                Err(_) => return ptr::null_mut()
+            };
+        let metadata = common::Metadata::new(&bfuncs, K(kx), K(ky), Some(nup));
+        match CString::new(metadata.to_json()) {
+            Ok(s) => s.into_raw(),
+            Err(_) => ptr::null_mut()
+        }
+    })
+}
+
+/// Release a string returned by the library. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn spinsys_string_free(s: *mut c_char) {
+    guard((), || {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    })
+}
+
+/// Build a matrix-free operator equal to the sum of the given terms in the
+/// (kx, ky, nup) sector. Returns a null pointer on failure. The handle must be
+/// released with op_free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_hamiltonian_new(nx: u32, ny: u32, kx: u32, ky: u32,
+                                            nup: u32, terms: *const CTerm,
+                                            nterms: u32)
+                                            -> *mut OpHandle {
+    guard(ptr::null_mut(), || {
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                                                      OpHandle::ks(Dim(nx),
+                                                                   Dim(ny),
+                                                                   K(kx),
+                                                                   K(ky),
+                                                                   nup,
+                                                                   &terms)
+                                                  });
+        match result {
+            Ok(op) => Box::into_raw(Box::new(op)),
+            Err(_) => ptr::null_mut()
+        }
+    })
+}
+
+/// Same as ks_hamiltonian_new with the basis finding the orbits of the
+/// configurations as coded by "lookup", see ks_term_matrix_lookup. Returns a
+/// null pointer on failure.
+#[no_mangle]
+pub unsafe extern "C" fn ks_hamiltonian_new_lookup(nx: u32, ny: u32, kx: u32,
+                                                   ky: u32, nup: u32,
+                                                   terms: *const CTerm,
+                                                   nterms: u32, lookup: u32)
+                                                   -> *mut OpHandle {
+    guard(ptr::null_mut(), || {
+        let result = Lookup::from_raw(lookup).and_then(|lookup| {
+            let terms = terms_from_raw(terms, nterms)?;
+            OpHandle::ks_with_lookup(Dim(nx), Dim(ny), K(kx), K(ky), nup, &terms,
+                                     lookup)
+        });
+        match result {
+            Ok(op) => Box::into_raw(Box::new(op)),
+            Err(_) => ptr::null_mut()
+        }
+    })
+}
+
+/// The dimension of the operator, or 0 if the handle is null
+#[no_mangle]
+pub unsafe extern "C" fn op_dim(handle: *const OpHandle) -> u32 {
+    guard(0, || handle.as_ref().map_or(0, |op| op.dim()))
+}
+
+/// y = H x where x and y are arrays of "dim" complex numbers. "dim" must equal
+/// the dimension of the operator. Returns a status code.
+#[no_mangle]
+pub unsafe extern "C" fn op_apply(handle: *const OpHandle,
+                                  x: *const CComplex<f64>, y: *mut CComplex<f64>,
+                                  dim: u64)
+                                  -> i32 {
+    guard(error::ERR_PANIC, || {
+        let op = match handle.as_ref() {
+            Some(op) => op,
+            None => return error::ERR_INVALID_ARGUMENT
+        };
+        if x.is_null() || y.is_null() || dim != op.dim() as u64 {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        // CComplex and Complex are both #[repr(C)] pairs of (re, im)
+        let x = slice::from_raw_parts(x as *const Complex<f64>, dim as usize);
+        let y = slice::from_raw_parts_mut(y as *mut Complex<f64>, dim as usize);
+        error::status(op.apply(x, y))
+    })
+}
+
+/// Release an operator created by ks_hamiltonian_new. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn op_free(handle: *mut OpHandle) {
+    guard((), || {
+        if !handle.is_null() {
+            drop(Box::from_raw(handle));
+        }
+    })
+}
+
+/// Map the basis of the (kx, ky, nup) sector kept in the file at "path",
+/// scanning the sector into the file first if it does not exist yet. The file
+/// holds the flat arrays of the basis and only parts of it are in memory at
+/// any time, so it may be far larger than the available memory. Returns a null
+/// pointer if the file cannot be written or was written for another sector.
+/// The handle must be released with basis_free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_basis_new_mmap(nx: u32, ny: u32, kx: u32, ky: u32,
+                                           nup: u32, path: *const c_char)
+                                           -> *mut BasisHandle {
+    guard(ptr::null_mut(), || {
+        let result = str_from_raw(path, "path").and_then(|path| {
+                         MappedBasis::open_or_create(path,
+                                                     Dim(nx),
+                                                     Dim(ny),
+                                                     K(kx),
+                                                     K(ky),
+                                                     nup)
+                     });
+        match result {
+            Ok(basis) => {
+                Box::into_raw(Box::new(BasisHandle { basis: Arc::new(basis) }))
+            }
+            Err(_) => ptr::null_mut()
+        }
+    })
+}
+
+/// The dimension of the basis, or 0 if the handle is null
+#[no_mangle]
+pub unsafe extern "C" fn basis_dim(handle: *const BasisHandle) -> u32 {
+    guard(0, || handle.as_ref().map_or(0, |h| h.basis.dim()))
+}
+
+/// Build a matrix-free operator equal to the sum of the given terms on a basis
+/// created by ks_basis_new_mmap. Returns a null pointer on failure. The
+/// operator keeps the mapping alive, so the basis may be freed before it. The
+/// handle must be released with op_free.
+#[no_mangle]
+pub unsafe extern "C" fn basis_hamiltonian_new(handle: *const BasisHandle,
+                                               terms: *const CTerm, nterms: u32)
+                                               -> *mut OpHandle {
+    guard(ptr::null_mut(), || {
+        let handle = match handle.as_ref() {
+            Some(handle) => handle,
+            None => return ptr::null_mut()
+        };
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                                                      OpHandle::mapped(
+                                                          handle.basis.clone(),
+                                                          &terms)
+                                                  });
+        match result {
+            Ok(op) => Box::into_raw(Box::new(op)),
+            Err(_) => ptr::null_mut()
+        }
+    })
+}
+
+/// Release a basis created by ks_basis_new_mmap. The file is kept. Null is
+/// ignored.
+#[no_mangle]
+pub unsafe extern "C" fn basis_free(handle: *mut BasisHandle) {
+    guard((), || {
+        if !handle.is_null() {
+            drop(Box::from_raw(handle));
+        }
+    })
+}
+
+/// A cursor over the rows of the sum of the given terms on a basis created by
+/// ks_basis_new_mmap, generated one at a time as they are read with rows_next.
+/// Row i holds the elements of column i of the operator, as in the coordinate
+/// matrices. Returns a null pointer on failure. The cursor keeps the mapping
+/// alive, so the basis may be freed before it. The cursor must be released
+/// with rows_free.
+#[no_mangle]
+pub unsafe extern "C" fn rows_new(handle: *const BasisHandle, terms: *const CTerm,
+                                  nterms: u32)
+                                  -> *mut RowCursor {
+    guard(ptr::null_mut(), || {
+        let handle = match handle.as_ref() {
+            Some(handle) => handle,
+            None => return ptr::null_mut()
+        };
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                         HamiltonianRows::mapped(handle.basis.clone(), &terms)
+                     });
+        match result {
+            Ok(rows) => Box::into_raw(Box::new(RowCursor::new(rows))),
+            Err(_) => ptr::null_mut()
+        }
+    })
+}
+
+/// Copy the next row of "cursor" into "cols" and "vals", which hold "cap"
+/// entries each, write its index to "row" if it is not null and return its
+/// number of elements. Returns -1 with SUCCESS once all rows have been read.
+/// On failure -1 is returned and the status code is written to "status" if it
+/// is not null; a row with more than "cap" elements fails with
+/// ERR_INVALID_ARGUMENT and is returned by the next call. A buffer of
+/// basis_dim entries always suffices.
+#[no_mangle]
+pub unsafe extern "C" fn rows_next(cursor: *mut RowCursor, row: *mut u64,
+                                   cols: *mut u32, vals: *mut CComplex<f64>,
+                                   cap: u64, status: *mut i32)
+                                   -> i64 {
+    guard_status(status, -1, || {
+        let result = match cursor.as_mut() {
+            Some(_) if cols.is_null() => Err(Error::InvalidArgument("cols")),
+            Some(_) if vals.is_null() => Err(Error::InvalidArgument("vals")),
+            Some(cursor) => {
+                let cols = slice::from_raw_parts_mut(cols, cap as usize);
+                // CComplex and Complex are both #[repr(C)] pairs of (re, im)
+                let vals = slice::from_raw_parts_mut(vals as *mut Complex<f64>,
+                                                     cap as usize);
+                cursor.next_into(cols, vals)
+            }
+            None => Err(Error::InvalidArgument("cursor"))
+        };
+        match result {
+            Ok(Some((i, len))) => {
+                write_status(status, error::SUCCESS);
+                if !row.is_null() {
+                    *row = u64::from(i);
+                }
+                len as i64
+            }
+            Ok(None) => {
+                write_status(status, error::SUCCESS);
+                -1
+            }
+            Err(e) => {
+                write_status(status, e.report());
+                -1
+            }
+        }
+    })
+}
+
+/// Release a cursor created by rows_new. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rows_free(cursor: *mut RowCursor) {
+    guard((), || {
+        if !cursor.is_null() {
+            drop(Box::from_raw(cursor));
+        }
+    })
+}
+
+/// <psi|O|psi> for the operator O of kind "term_id" with the given "l" in the
+/// (kx, ky, nup) sector, where "psi" holds "dim" amplitudes in the reduced
+/// basis. The elements of O are generated on the fly and never stored. The
+/// status code is written to "status" if it is not null; on failure zero is
+/// returned.
+#[no_mangle]
+pub unsafe extern "C" fn ks_expectation(nx: u32, ny: u32, kx: u32, ky: u32,
+                                        nup: u32, term_id: u32, l: u32,
+                                        psi: *const CComplex<f64>, dim: u64,
+                                        status: *mut i32)
+                                        -> CComplex<f64> {
+    guard_status(status, CComplex { re: 0., im: 0. }, || {
+        if psi.is_null() {
+            write_status(status, error::ERR_INVALID_ARGUMENT);
+            return CComplex { re: 0., im: 0. };
+        }
+        // CComplex and Complex are both #[repr(C)] pairs of (re, im)
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        let term = match TermKind::from_raw(term_id) {
+            Some(kind) => Term::new(kind, I(l as i32)),
+            None => {
+                write_status(status, error::ERR_INVALID_TERM);
+                return CComplex { re: 0., im: 0. };
+            }
+        };
+        let result = observables::ks_expectation(Dim(nx),
+                                                 Dim(ny),
+                                                 K(kx),
+                                                 K(ky),
+                                                 nup,
+                                                 &term,
+                                                 psi);
+        match result {
+            Ok(val) => {
+                write_status(status, error::SUCCESS);
+                CComplex::from_num_complex(val)
+            }
+            Err(e) => {
+                write_status(status, e.report());
+                CComplex { re: 0., im: 0. }
+            }
+        }
+    })
+}
+
+/// The static structure factor S(q) = <psi|S_-q · S_q|psi> of "psi", holding
+/// "dim" amplitudes in the reduced basis of the (kx, ky, nup) sector, at every
+/// momentum q = (m, n) of the cluster, whose wavevector lattice_momenta lists at
+/// the same index. S(q) is written to out[m + n * nx], so "out" must hold
+/// nx * ny elements. The real-space
+/// correlations are computed once from streamed elements and then Fourier
+/// transformed.
+#[no_mangle]
+pub unsafe extern "C" fn ks_structure_factor_scan(nx: u32, ny: u32, kx: u32,
+                                                  ky: u32, nup: u32,
+                                                  psi: *const CComplex<f64>,
+                                                  dim: u64, out: *mut f64)
+                                                  -> i32 {
+    guard(error::ERR_PANIC, || {
+        if psi.is_null() || out.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        match observables::ks_structure_factor(nx, ny, kx, ky, nup, psi) {
+            Ok(sq) => {
+                slice::from_raw_parts_mut(out, sq.len()).copy_from_slice(&sq);
+                error::SUCCESS
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+// Hand a vector of doubles over to the caller, writing the status code to
+// "status" if it is not null. On failure the vector is empty with a null
+// pointer.
+unsafe fn vector_or_status(result: Result<Vec<f64>>, status: *mut i32)
+                           -> Vector<f64> {
+    match result {
+        Ok(v) => {
+            write_status(status, error::SUCCESS);
+            Vector::from_vec(v)
+        }
+        Err(e) => {
+            write_status(status, e.report());
+            empty_vector()
+        }
+    }
+}
+
+fn empty_vector<T>() -> Vector<T> {
+    Vector { ptr: ptr::null_mut(),
+             len: 0 }
+}
+
+fn empty_coord_matrix() -> CoordMatrix<CComplex<f64>> { CoordMatrix::empty() }
+
+fn matrix_or_empty(result: Result<OwnedCoordMatrix<CComplex<f64>>>)
+                   -> CoordMatrix<CComplex<f64>> {
+    match result {
+        Ok(mat) => mat.into_ffi(),
+        Err(e) => {
+            e.report();
+            empty_coord_matrix()
+        }
+    }
+}
+
+// the handle of the matrix in "result", or a null pointer with the error in
+// "status" and spinsys_last_error
+unsafe fn handle_or_null(result: Result<OwnedCoordMatrix<CComplex<f64>>>,
+                         status: *mut i32)
+                         -> *mut CoordMatrixHandle {
+    match result {
+        Ok(mat) => {
+            write_status(status, error::SUCCESS);
+            handle::into_raw(mat)
+        }
+        Err(e) => {
+            write_status(status, e.report());
+            ptr::null_mut()
+        }
+    }
+}
+
+// "kind" with range or separation "l" and a unit coefficient in the (kx, ky)
+// sector, built by the api module
+fn k_matrix(nx: u32, ny: u32, kx: u32, ky: u32, kind: TermKind, l: u32)
+            -> CoordMatrix<CComplex<f64>> {
+    let term = Term::new(kind, I(l as i32));
+    matrix_or_empty(api::k_term(Dim(nx), Dim(ny), K(kx), K(ky), &term))
+}
+
+// same as k_matrix in the (kx, ky, nup) sector
+fn ks_matrix(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, kind: TermKind, l: u32)
+             -> CoordMatrix<CComplex<f64>> {
+    let term = Term::new(kind, I(l as i32));
+    matrix_or_empty(api::ks_term(Dim(nx), Dim(ny), K(kx), K(ky), nup, &term))
+}
+
+fn empty_dense_matrix() -> DenseMatrix<CComplex<f64>> {
+    DenseMatrix { data: empty_vector(),
+                  n:    0 }
+}
+
+/// The N x N matrix of <psi|S_i · S_j|psi> for "psi", holding "dim" amplitudes
+/// in the reduced basis of the (kx, ky, nup) sector, where N = nx * ny. The
+/// element for sites i and j is at index i * N + j; the diagonal is exactly
+/// 3/4. Release the result with vector_f64_free. The status code is written
+/// to "status" if it is not null; on failure the vector is empty with a null
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ks_correlation_matrix(nx: u32, ny: u32, kx: u32,
+                                               ky: u32, nup: u32,
+                                               psi: *const CComplex<f64>,
+                                               dim: u64, status: *mut i32)
+                                               -> Vector<f64> {
+    guard_status(status, empty_vector(), || {
+        if psi.is_null() {
+            return vector_or_status(Err(Error::InvalidArgument("psi")), status);
+        }
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        let result = observables::ks_correlation_matrix(nx, ny, kx, ky, nup, psi);
+        vector_or_status(result, status)
+    })
+}
+
+/// The von Neumann entropy -Σ p ln p of the reduced density matrix of "psi",
+/// holding "dim" amplitudes in the reduced basis of the (kx, ky, nup) sector,
+/// on the sites whose bits are set in "region_mask". Regions of more than
+/// observables::MAX_REGION_SITES sites are refused with ERR_TOO_LARGE. The
+/// status code is written to "status" if it is not null; on failure zero is
+/// returned.
+#[no_mangle]
+pub unsafe extern "C" fn ks_entanglement_entropy(nx: u32, ny: u32, kx: u32,
+                                                 ky: u32, nup: u32,
+                                                 psi: *const CComplex<f64>,
+                                                 dim: u64, region_mask: u64,
+                                                 status: *mut i32)
+                                                 -> f64 {
+    guard_status(status, 0., || {
+        let torus = LatticeSettings::default();
+        ks_entanglement_entropy_in(nx, ny, &torus, kx, ky, nup, psi, dim,
+                                   region_mask, status)
+    })
+}
+
+// ks_entanglement_entropy on the lattice with "settings"
+unsafe fn ks_entanglement_entropy_in(nx: u32, ny: u32, settings: &LatticeSettings,
+                                     kx: u32, ky: u32, nup: u32,
+                                     psi: *const CComplex<f64>, dim: u64,
+                                     region_mask: u64, status: *mut i32)
+                                     -> f64 {
+    if psi.is_null() {
+        write_status(status, error::ERR_INVALID_ARGUMENT);
+        return 0.;
+    }
+    let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+    let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+    match observables::ks_entanglement_entropy(nx,
+                                               ny,
+                                               settings,
+                                               kx,
+                                               ky,
+                                               nup,
+                                               psi,
+                                               region_mask)
+    {
+        Ok(entropy) => {
+            write_status(status, error::SUCCESS);
+            entropy
+        }
+        Err(e) => {
+            write_status(status, e.report());
+            0.
+        }
+    }
+}
+
+/// The eigenvalues of the reduced density matrix used by
+/// ks_entanglement_entropy, 2^|A| of them in descending order. Release the
+/// result with vector_f64_free. On failure the vector is empty with a null
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ks_entanglement_spectrum(nx: u32, ny: u32, kx: u32,
+                                                  ky: u32, nup: u32,
+                                                  psi: *const CComplex<f64>,
+                                                  dim: u64, region_mask: u64,
+                                                  status: *mut i32)
+                                                  -> Vector<f64> {
+    guard_status(status, empty_vector(), || {
+        let torus = LatticeSettings::default();
+        ks_entanglement_spectrum_in(nx, ny, &torus, kx, ky, nup, psi, dim,
+                                    region_mask, status)
+    })
+}
+
+// ks_entanglement_spectrum on the lattice with "settings"
+unsafe fn ks_entanglement_spectrum_in(nx: u32, ny: u32,
+                                      settings: &LatticeSettings, kx: u32,
+                                      ky: u32, nup: u32,
+                                      psi: *const CComplex<f64>, dim: u64,
+                                      region_mask: u64, status: *mut i32)
+                                      -> Vector<f64> {
+    if psi.is_null() {
+        return vector_or_status(Err(Error::InvalidArgument("psi")), status);
+    }
+    let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+    let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+    let result = observables::ks_entanglement_spectrum(nx,
+                                                       ny,
+                                                       settings,
+                                                       kx,
+                                                       ky,
+                                                       nup,
+                                                       psi,
+                                                       region_mask);
+    vector_or_status(result, status)
+}
+
+/// Same as ks_entanglement_entropy for "psi" in the basis of a lattice with
+/// another ordering of the sites (see k_term_matrix_ordered), the bits of
+/// "region_mask" being those of the sites in that ordering. A region of
+/// consecutive bits is a different cut in each ordering.
+#[no_mangle]
+pub unsafe extern "C" fn ks_entanglement_entropy_ordered(
+    nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, psi: *const CComplex<f64>,
+    dim: u64, region_mask: u64, ordering: u32, permutation: *const u32,
+    status: *mut i32)
+    -> f64 {
+    guard_status(status, 0., || {
+        match site_ordering(nx, ny, ordering, permutation) {
+            Ok(ordering) => {
+                let settings = LatticeSettings { ordering,
+                                                 ..LatticeSettings::default() };
+                ks_entanglement_entropy_in(nx, ny, &settings, kx, ky, nup, psi,
+                                           dim, region_mask, status)
+            }
+            Err(e) => {
+                write_status(status, e.report());
+                0.
+            }
+        }
+    })
+}
+
+/// Same as ks_entanglement_spectrum on a lattice with another ordering of the
+/// sites, see ks_entanglement_entropy_ordered
+#[no_mangle]
+pub unsafe extern "C" fn ks_entanglement_spectrum_ordered(
+    nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, psi: *const CComplex<f64>,
+    dim: u64, region_mask: u64, ordering: u32, permutation: *const u32,
+    status: *mut i32)
+    -> Vector<f64> {
+    guard_status(status, empty_vector(), || {
+        match site_ordering(nx, ny, ordering, permutation) {
+            Ok(ordering) => {
+                let settings = LatticeSettings { ordering,
+                                                 ..LatticeSettings::default() };
+                ks_entanglement_spectrum_in(nx, ny, &settings, kx, ky, nup, psi,
+                                            dim, region_mask, status)
+            }
+            Err(e) => vector_or_status(Err(e), status)
+        }
+    })
+}
+
+/// <psi|Sz_i|psi> for every site i, where "psi" holds the "dim" amplitudes of
+/// all configurations with "nup" up spins in ascending order of their decimal
+/// labels, as written by ks_expand_state_sz. Release the result with
+/// vector_f64_free.
+#[no_mangle]
+pub unsafe extern "C" fn s_local_sz(nx: u32, ny: u32, nup: u32,
+                                    psi: *const CComplex<f64>, dim: u64,
+                                    status: *mut i32)
+                                    -> Vector<f64> {
+    guard_status(status, empty_vector(), || {
+        if psi.is_null() {
+            return vector_or_status(Err(Error::InvalidArgument("psi")), status);
+        }
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        let result = observables::s_local_sz(Dim(nx), Dim(ny), nup, psi);
+        vector_or_status(result, status)
+    })
+}
+
+/// <psi|Sz_i|psi> for every site i of "psi", holding "dim" amplitudes in the
+/// reduced basis of the (kx, ky, nup) sector. All of them are equal by
+/// symmetry. Release the result with vector_f64_free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_local_sz(nx: u32, ny: u32, kx: u32, ky: u32,
+                                     nup: u32, psi: *const CComplex<f64>,
+                                     dim: u64, status: *mut i32)
+                                     -> Vector<f64> {
+    guard_status(status, empty_vector(), || {
+        if psi.is_null() {
+            return vector_or_status(Err(Error::InvalidArgument("psi")), status);
+        }
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        vector_or_status(observables::ks_local_sz(nx, ny, kx, ky, nup, psi), status)
+    })
+}
+
+/// Diagnostics of "psi", holding "dim" amplitudes in the reduced basis of the
+/// (kx, ky, nup) sector: its inverse participation ratios in the reduced and
+/// in the product basis, and its weight on the "ndecs" product states in
+/// "decs" (which may be null if "ndecs" is zero). The results are written to
+/// "out".
+#[no_mangle]
+pub unsafe extern "C" fn ks_state_diagnostics(nx: u32, ny: u32, kx: u32, ky: u32,
+                                              nup: u32,
+                                              psi: *const CComplex<f64>,
+                                              dim: u64, decs: *const u64,
+                                              ndecs: u64,
+                                              out: *mut StateDiagnostics)
+                                              -> i32 {
+    guard(error::ERR_PANIC, || {
+        if psi.is_null() || out.is_null() || (decs.is_null() && ndecs > 0) {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        let decs = if ndecs == 0 {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(decs, ndecs as usize).iter()
+                                                       .map(|&d| BinaryBasis(d))
+                                                       .collect()
+        };
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        match observables::ks_state_diagnostics(nx, ny, kx, ky, nup, psi, &decs) {
+            Ok(diag) => {
+                *out = diag;
+                error::SUCCESS
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// Expand "psi", holding "dim" amplitudes in the reduced basis of the
+/// (kx, ky, nup) sector, into product states. The decimal labels of the
+/// configurations are written to "out_dec" in ascending order and their
+/// amplitudes to "out_full". Both must hold choose(nx * ny, nup) elements,
+/// although configurations whose orbit is incompatible with the momentum are
+/// left out so fewer may be written. Returns the number of states written, or
+/// a negative status code on failure.
+#[no_mangle]
+pub unsafe extern "C" fn ks_expand_state(nx: u32, ny: u32, kx: u32, ky: u32,
+                                         nup: u32, psi: *const CComplex<f64>,
+                                         dim: u64, out_full: *mut CComplex<f64>,
+                                         out_dec: *mut u64)
+                                         -> i64 {
+    guard(error::ERR_PANIC as i64, || {
+        if psi.is_null() || out_full.is_null() || out_dec.is_null() {
+            return error::ERR_INVALID_ARGUMENT as i64;
+        }
+        // CComplex and Complex are both #[repr(C)] pairs of (re, im)
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        match consv::ks::expand_state(Dim(nx), Dim(ny), K(kx), K(ky), nup, psi) {
+            Ok(states) => {
+                let full = slice::from_raw_parts_mut(out_full, states.len());
+                let decs = slice::from_raw_parts_mut(out_dec, states.len());
+                for (k, (dec, amp)) in states.iter().enumerate() {
+                    decs[k] = dec.raw_int();
+                    full[k] = CComplex::from_num_complex(*amp);
+                }
+                states.len() as i64
+            }
+            Err(e) => e.report() as i64
+        }
+    })
+}
+
+/// Same as ks_expand_state, writing the amplitudes of all choose(nx * ny, nup)
+/// configurations with "nup" up spins to "out" in ascending order of their
+/// decimal labels, so no labels need to be passed around. Returns the number
+/// of amplitudes written, or a negative status code on failure.
+#[no_mangle]
+pub unsafe extern "C" fn ks_expand_state_sz(nx: u32, ny: u32, kx: u32, ky: u32,
+                                            nup: u32, psi: *const CComplex<f64>,
+                                            dim: u64, out: *mut CComplex<f64>)
+                                            -> i64 {
+    guard(error::ERR_PANIC as i64, || {
+        if psi.is_null() || out.is_null() {
+            return error::ERR_INVALID_ARGUMENT as i64;
+        }
+        let psi = slice::from_raw_parts(psi as *const Complex<f64>, dim as usize);
+        match consv::ks::expand_state_sz(Dim(nx), Dim(ny), K(kx), K(ky), nup, psi) {
+            Ok(full) => {
+                let out = slice::from_raw_parts_mut(out, full.len());
+                for (o, c) in out.iter_mut().zip(full.iter()) {
+                    *o = CComplex::from_num_complex(*c);
+                }
+                full.len() as i64
+            }
+            Err(e) => e.report() as i64
+        }
+    })
+}
+
+// Ground state of a hermitian operator by the Lanczos method. The lowest
+// eigenvalue is written to "out_energy" and, if "out_vec" is not null, the
+// normalized eigenvector is written to "out_vec", which must hold as many
+// elements as the dimension of the operator. The iteration stops once the
+// residual norm drops below tol * max(1, |energy|); failure to get there within
+// "max_iter" iterations is reported as ERR_NOT_CONVERGED.
+unsafe fn ground_state<A: LinearOperator>(op: &A, tol: f64, max_iter: u32,
+                                          out_energy: *mut f64,
+                                          out_vec: *mut CComplex<f64>)
+                                          -> i32 {
+    if out_energy.is_null() {
+        return error::ERR_INVALID_ARGUMENT;
+    }
+    match lanczos::ground_state(op, tol, max_iter, !out_vec.is_null()) {
+        Ok((energy, vec)) => {
+            *out_energy = energy;
+            if let Some(vec) = vec {
+                let out = slice::from_raw_parts_mut(out_vec, vec.len());
+                for (o, c) in out.iter_mut().zip(vec) {
+                    *o = CComplex::from_num_complex(c);
+                }
+            }
+            error::SUCCESS
+        }
+        Err(e) => e.report()
+    }
+}
+
+/// Ground state of the sum of the given terms in the (kx, ky, nup) sector. The
+/// operator is applied matrix-free. Use ks_hamiltonian_new and op_ground_state
+/// instead if the eigenvector is wanted, since the dimension of the sector is
+/// needed to allocate "out_vec".
+#[no_mangle]
+pub unsafe extern "C" fn ks_ground_state(nx: u32, ny: u32, kx: u32, ky: u32,
+                                         nup: u32, terms: *const CTerm,
+                                         nterms: u32, tol: f64, max_iter: u32,
+                                         out_energy: *mut f64,
+                                         out_vec: *mut CComplex<f64>)
+                                         -> i32 {
+    guard(error::ERR_PANIC, || {
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                                                      OpHandle::ks(Dim(nx),
+                                                                   Dim(ny),
+                                                                   K(kx),
+                                                                   K(ky),
+                                                                   nup,
+                                                                   &terms)
+                                                  });
+        match result {
+            Ok(op) => ground_state(&op, tol, max_iter, out_energy, out_vec),
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// Lowest energies of the sum of the given terms in every momentum sector with
+/// "nup" up spins, or over all numbers of up spins if "nup" is u32::MAX. The
+/// energies are written to "out_energies", which holds nx * ny elements, at
+/// index kx + ky * nx, with +inf for empty sectors. The (kx, ky, nup) of the
+/// sector with the lowest energy are written to "out_best_sector", which
+/// holds 3 elements. The sectors are solved in parallel.
+#[no_mangle]
+pub unsafe extern "C" fn ks_ground_state_sweep(nx: u32, ny: u32, nup: u32,
+                                               terms: *const CTerm, nterms: u32,
+                                               tol: f64, max_iter: u32,
+                                               out_energies: *mut f64,
+                                               out_best_sector: *mut u32)
+                                               -> i32 {
+    guard(error::ERR_PANIC, || {
+        if out_energies.is_null() || out_best_sector.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let nup = if nup == u32::MAX { None } else { Some(nup) };
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                         sweep::ground_state_sweep(Dim(nx),
+                                                   Dim(ny),
+                                                   &LatticeSettings::default(),
+                                                   nup,
+                                                   &terms,
+                                                   tol,
+                                                   max_iter)
+                     });
+        match result {
+            Ok(sweep) => {
+                slice::from_raw_parts_mut(out_energies, sweep.energies.len())
+                .copy_from_slice(&sweep.energies);
+                let (kx, ky, nup) = sweep.best;
+                let best = slice::from_raw_parts_mut(out_best_sector, 3);
+                best.copy_from_slice(&[kx.raw_int(), ky.raw_int(), nup]);
+                error::SUCCESS
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// The lowest energy of the sum of the given terms over all momenta for each
+/// number of up spins from 0 to nx * ny, written to "out_energies", which
+/// holds nx * ny + 1 elements. Leave any Zeeman term out of "terms": it only
+/// shifts the energy with nup up spins by -h (nup - nx * ny / 2), which is
+/// applied afterwards when the magnetization curve is taken.
+#[no_mangle]
+pub unsafe extern "C" fn ks_magnetization_curve(nx: u32, ny: u32,
+                                                terms: *const CTerm, nterms: u32,
+                                                tol: f64, max_iter: u32,
+                                                out_energies: *mut f64)
+                                                -> i32 {
+    guard(error::ERR_PANIC, || {
+        if out_energies.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                         sweep::magnetization_curve(Dim(nx),
+                                                    Dim(ny),
+                                                    &LatticeSettings::default(),
+                                                    &terms,
+                                                    tol,
+                                                    max_iter)
+                     });
+        match result {
+            Ok(energies) => {
+                slice::from_raw_parts_mut(out_energies, energies.len())
+                .copy_from_slice(&energies);
+                error::SUCCESS
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// Ground-state energies of the sum of the given terms in the (kx, ky, nup)
+/// sector under a twist θ of the spins across the boundary in x, for "ntheta"
+/// values of θ evenly spaced from 0 to "theta_max", written to "out_energies".
+/// The stiffness ρ_s, defined by E(θ) - E(0) = N ρ_s (θ / nx)^2 / 2 and
+/// estimated from the first three energies, is written to "out_stiffness".
+/// Only the Ising and xy terms can be twisted. The basis is built once for the
+/// whole sweep.
+#[no_mangle]
+pub unsafe extern "C" fn ks_spin_stiffness(nx: u32, ny: u32, kx: u32, ky: u32,
+                                           nup: u32, terms: *const CTerm,
+                                           nterms: u32, ntheta: u32,
+                                           theta_max: f64, tol: f64,
+                                           max_iter: u32, out_energies: *mut f64,
+                                           out_stiffness: *mut f64)
+                                           -> i32 {
+    guard(error::ERR_PANIC, || {
+        if out_energies.is_null() || out_stiffness.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let terms = match terms_from_raw(terms, nterms) {
+            Ok(terms) => terms,
+            Err(e) => return e.report()
+        };
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        match stiffness::spin_stiffness(nx, ny, kx, ky, nup, &terms, ntheta,
+                                        theta_max, tol, max_iter)
+        {
+            Ok(s) => {
+                slice::from_raw_parts_mut(out_energies, s.energies.len())
+                .copy_from_slice(&s.energies);
+                *out_stiffness = s.stiffness;
+                error::SUCCESS
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// Ground state of an operator created by ks_hamiltonian_new
+#[no_mangle]
+pub unsafe extern "C" fn op_ground_state(handle: *const OpHandle, tol: f64,
+                                         max_iter: u32, out_energy: *mut f64,
+                                         out_vec: *mut CComplex<f64>)
+                                         -> i32 {
+    guard(error::ERR_PANIC, || match handle.as_ref() {
+        Some(op) => ground_state(op, tol, max_iter, out_energy, out_vec),
+        None => error::ERR_INVALID_ARGUMENT
+    })
+}
+
+/// Ground state of a matrix returned by any of the builders
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_ground_state(
+    mat: *const CoordMatrix<CComplex<f64>>, tol: f64, max_iter: u32,
+    out_energy: *mut f64, out_vec: *mut CComplex<f64>)
+    -> i32 {
+    guard(error::ERR_PANIC, || match mat.as_ref() {
+        Some(mat) => ground_state(mat, tol, max_iter, out_energy, out_vec),
+        None => error::ERR_INVALID_ARGUMENT
+    })
+}
+
+// exp(-i op t) applied to "psi_in", which holds "dim" elements, by the Krylov
+// method with Krylov spaces of at most "krylov_dim" vectors. The result is
+// written to "psi_out", which may not alias "psi_in", and the number of
+// applications of the operator to "matvecs" if it is not null. The steps are
+// split as needed to keep the error, including that of the norm, below "tol".
+unsafe fn time_evolve<A: LinearOperator>(op: &A, psi_in: *const CComplex<f64>,
+                                         psi_out: *mut CComplex<f64>, dim: u64,
+                                         t: f64, krylov_dim: u32, tol: f64,
+                                         matvecs: *mut u64)
+                                         -> i32 {
+    if psi_in.is_null() || psi_out.is_null() {
+        return error::ERR_INVALID_ARGUMENT;
+    }
+    let psi = slice::from_raw_parts(psi_in as *const Complex<f64>, dim as usize);
+    match evolve::time_evolve(op, psi, t, krylov_dim, tol) {
+        Ok((evolved, count)) => {
+            let out = slice::from_raw_parts_mut(psi_out, evolved.len());
+            for (o, c) in out.iter_mut().zip(evolved) {
+                *o = CComplex::from_num_complex(c);
+            }
+            if !matvecs.is_null() {
+                *matvecs = count;
+            }
+            error::SUCCESS
+        }
+        Err(e) => e.report()
+    }
+}
+
+/// Real-time evolution under the sum of the given terms in the (kx, ky, nup)
+/// sector. The operator is applied matrix-free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_time_evolve(nx: u32, ny: u32, kx: u32, ky: u32,
+                                        nup: u32, terms: *const CTerm,
+                                        nterms: u32,
+                                        psi_in: *const CComplex<f64>,
+                                        psi_out: *mut CComplex<f64>, dim: u64,
+                                        t: f64, krylov_dim: u32, tol: f64,
+                                        matvecs: *mut u64)
+                                        -> i32 {
+    guard(error::ERR_PANIC, || {
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                                                      OpHandle::ks(nx, ny, kx, ky,
+                                                                   nup, &terms)
+                                                  });
+        match result {
+            Ok(op) => {
+                time_evolve(&op, psi_in, psi_out, dim, t, krylov_dim, tol, matvecs)
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// Real-time evolution under an operator created by ks_hamiltonian_new
+#[no_mangle]
+pub unsafe extern "C" fn op_time_evolve(handle: *const OpHandle,
+                                        psi_in: *const CComplex<f64>,
+                                        psi_out: *mut CComplex<f64>, dim: u64,
+                                        t: f64, krylov_dim: u32, tol: f64,
+                                        matvecs: *mut u64)
+                                        -> i32 {
+    guard(error::ERR_PANIC, || match handle.as_ref() {
+        Some(op) => {
+            time_evolve(op, psi_in, psi_out, dim, t, krylov_dim, tol, matvecs)
+        }
+        None => error::ERR_INVALID_ARGUMENT
+    })
+}
+
+/// The continued fraction of the dynamical structure factor of the state
+/// "psi0" of the (kx, ky, nup) sector, usually its ground state, at momentum
+/// (qx, qy), whose wavevector lattice_momenta lists. "channel" selects the
+/// operator applied to the state: 0 for Sz(q), 1 for S+(q) and 2 for S-(q).
+/// "m" Lanczos steps of the sum of the given terms are taken in the sector the
+/// excited state belongs to and the diagonal and off-diagonal coefficients
+/// written to "out_alpha" and "out_beta", which hold "m" elements each and are
+/// padded with zeros if the recursion terminates early. The norm of the
+/// excited state, relative to a normalized "psi0", is written to "out_norm".
+#[no_mangle]
+pub unsafe extern "C" fn ks_dsf_lanczos(nx: u32, ny: u32, kx: u32, ky: u32,
+                                        nup: u32, qx: u32, qy: u32, channel: u32,
+                                        terms: *const CTerm, nterms: u32,
+                                        psi0: *const CComplex<f64>, dim: u64,
+                                        m: u32, out_alpha: *mut f64,
+                                        out_beta: *mut f64, out_norm: *mut f64)
+                                        -> i32 {
+    guard(error::ERR_PANIC, || {
+        if psi0.is_null()
+           || out_alpha.is_null()
+           || out_beta.is_null()
+           || out_norm.is_null()
+        {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        let psi = slice::from_raw_parts(psi0 as *const Complex<f64>, dim as usize);
+        let channel = match dsf::Channel::from_raw(channel) {
+            Ok(channel) => channel,
+            Err(e) => return e.report()
+        };
+        let terms = match terms_from_raw(terms, nterms) {
+            Ok(terms) => terms,
+            Err(e) => return e.report()
+        };
+        let result =
+            dsf::dsf_lanczos(nx, ny, kx, ky, nup, qx, qy, channel, &terms, psi, m);
+        match result {
+            Ok(cf) => {
+                let m = m as usize;
+                slice::from_raw_parts_mut(out_alpha, m).copy_from_slice(&cf.alpha);
+                slice::from_raw_parts_mut(out_beta, m).copy_from_slice(&cf.beta);
+                *out_norm = cf.norm;
+                error::SUCCESS
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// Finite-temperature Lanczos estimates in the (kx, ky, nup) sector for the
+/// Hamiltonian given by "ham_terms" and the observable "obs_term", from "r"
+/// random vectors with Krylov spaces of at most "m" vectors. One set of partial
+/// sums is written to "out" for each of the "ntemps" temperatures in "temps".
+/// The sums of all sectors add up to Z, Z <E> and Z <O>; the random vectors
+/// are drawn from "seed".
+#[no_mangle]
+pub unsafe extern "C" fn ks_ftlm(nx: u32, ny: u32, kx: u32, ky: u32, nup: u32,
+                                 ham_terms: *const CTerm, nham_terms: u32,
+                                 obs_term: *const CTerm, r: u32, m: u32,
+                                 temps: *const f64, ntemps: u32, seed: u64,
+                                 out: *mut ThermalSums)
+                                 -> i32 {
+    guard(error::ERR_PANIC, || {
+        if temps.is_null() || out.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let ham_terms = match terms_from_raw(ham_terms, nham_terms) {
+            Ok(terms) => terms,
+            Err(e) => return e.report()
+        };
+        let obs_term = match terms_from_raw(obs_term, 1) {
+            Ok(terms) => terms[0],
+            Err(e) => return e.report()
+        };
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        let temps = slice::from_raw_parts(temps, ntemps as usize);
+        match ftlm::ks_ftlm(nx, ny, kx, ky, nup, &ham_terms, &obs_term, r, m, temps,
+                            seed)
+        {
+            Ok(sums) => {
+                slice::from_raw_parts_mut(out, sums.len()).copy_from_slice(&sums);
+                error::SUCCESS
+            }
+            Err(e) => e.report()
+        }
+    })
+}
+
+// The "nev" lowest eigenvalues of a hermitian operator in ascending order by
+// thick-restart Lanczos with at most "ncv" Krylov vectors (ncv > nev). The
+// eigenvalues are written to "out_energies" and, if "out_vectors" is not null,
+// the normalized eigenvectors are written one after another to "out_vectors",
+// which must hold nev times the dimension of the operator elements. Degenerate
+// eigenvalues are reported as many times as they occur. Failure to converge
+// within "max_restarts" restarts is reported as ERR_NOT_CONVERGED.
+unsafe fn lowest_eigenpairs<A: LinearOperator>(op: &A, nev: u32, ncv: u32,
+                                               tol: f64, max_restarts: u32,
+                                               out_energies: *mut f64,
+                                               out_vectors: *mut CComplex<f64>)
+                                               -> i32 {
+    if out_energies.is_null() {
+        return error::ERR_INVALID_ARGUMENT;
+    }
+    let result = lanczos::lowest_eigenpairs(op,
+                                            nev as usize,
+                                            ncv as usize,
+                                            tol,
+                                            max_restarts);
+    match result {
+        Ok((energies, vectors)) => {
+            let out = slice::from_raw_parts_mut(out_energies, energies.len());
+            out.copy_from_slice(&energies);
+            if !out_vectors.is_null() {
+                let len = vectors.len() * op.dim();
+                let out = slice::from_raw_parts_mut(out_vectors, len);
+                let elements = vectors.iter().flat_map(|v| v.iter());
+                for (o, &c) in out.iter_mut().zip(elements) {
+                    *o = CComplex::from_num_complex(c);
+                }
+            }
+            error::SUCCESS
+        }
+        Err(e) => e.report()
+    }
+}
+
+/// The lowest eigenpairs of the sum of the given terms in the (kx, ky, nup)
+/// sector. The operator is applied matrix-free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_lowest_eigenpairs(nx: u32, ny: u32, kx: u32, ky: u32,
+                                              nup: u32, terms: *const CTerm,
+                                              nterms: u32, nev: u32, ncv: u32,
+                                              tol: f64, max_restarts: u32,
+                                              out_energies: *mut f64,
+                                              out_vectors: *mut CComplex<f64>)
+                                              -> i32 {
+    guard(error::ERR_PANIC, || {
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                                                      OpHandle::ks(Dim(nx),
+                                                                   Dim(ny),
+                                                                   K(kx),
+                                                                   K(ky),
+                                                                   nup,
+                                                                   &terms)
+                                                  });
+        match result {
+            Ok(op) => lowest_eigenpairs(&op,
+                                        nev,
+                                        ncv,
+                                        tol,
+                                        max_restarts,
+                                        out_energies,
+                                        out_vectors),
+            Err(e) => e.report()
+        }
+    })
+}
+
+/// The lowest eigenpairs of an operator created by ks_hamiltonian_new
+#[no_mangle]
+pub unsafe extern "C" fn op_lowest_eigenpairs(handle: *const OpHandle, nev: u32,
+                                              ncv: u32, tol: f64,
+                                              max_restarts: u32,
+                                              out_energies: *mut f64,
+                                              out_vectors: *mut CComplex<f64>)
+                                              -> i32 {
+    guard(error::ERR_PANIC, || match handle.as_ref() {
+        Some(op) => lowest_eigenpairs(op,
+                                      nev,
+                                      ncv,
+                                      tol,
+                                      max_restarts,
+                                      out_energies,
+                                      out_vectors),
+        None => error::ERR_INVALID_ARGUMENT
+    })
+}
+
+/// The lowest eigenpairs of a matrix returned by any of the builders
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_lowest_eigenpairs(
+    mat: *const CoordMatrix<CComplex<f64>>, nev: u32, ncv: u32, tol: f64,
+    max_restarts: u32, out_energies: *mut f64, out_vectors: *mut CComplex<f64>)
+    -> i32 {
+    guard(error::ERR_PANIC, || match mat.as_ref() {
+        Some(mat) => lowest_eigenpairs(mat,
+                                       nev,
+                                       ncv,
+                                       tol,
+                                       max_restarts,
+                                       out_energies,
+                                       out_vectors),
+        None => error::ERR_INVALID_ARGUMENT
+    })
+}
+
+/// Build the operator described by "term" in the (kx, ky) sector. Returns a
+/// null pointer if the term or the parameters are invalid. The status code is
+/// written to "status" if it is not null. The arrays are read through the
+/// coord_matrix_* accessors and the handle must be released with
+/// coord_matrix_free.
+///
+/// A lattice too small for the range of a two-site term to reach a pair of
+/// sites that no shorter range bonds, as range 3 on 3x3 and on the chains and
+/// ranges 2 and 3 on 2x2, 2x3 and 3x2, has no bonds of that range. The term is
+/// valid there and its matrix has no nonzero elements.
+#[no_mangle]
+pub unsafe extern "C" fn k_term_matrix(nx: u32, ny: u32, kx: u32, ky: u32,
+                                       term: CTerm, status: *mut i32)
+                                       -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        let torus = LatticeSettings::default();
+        k_term_matrix_in(nx, ny, &torus, Convention::Plus, kx, ky, term, status)
+    })
+}
+
+// k_term_matrix on the lattice with "settings" under "convention"
+unsafe fn k_term_matrix_in(nx: u32, ny: u32, settings: &LatticeSettings,
+                           convention: Convention, kx: u32, ky: u32, term: CTerm,
+                           status: *mut i32)
+                           -> *mut CoordMatrixHandle {
+    let result = Term::from_c(term).ok_or(Error::InvalidTerm(term.kind))
+                                   .and_then(|term| {
+                                       api::k_term_in(Dim(nx),
+                                                      Dim(ny),
+                                                      settings,
+                                                      convention,
+                                                      K(kx),
+                                                      K(ky),
+                                                      &term)
+                                   });
+    handle_or_null(result, status)
+}
+
+/// Same as k_term_matrix on a lattice whose primitive vectors along x and y
+/// are (a1x, a1y) and (a2x, a2y) in cartesian coordinates rather than those of
+/// the triangular lattice, see LatticeGeometry. Also fails if the vectors are
+/// not finite or do not span the plane.
+#[no_mangle]
+pub unsafe extern "C" fn k_term_matrix_geometry(nx: u32, ny: u32, kx: u32,
+                                                ky: u32, term: CTerm, a1x: f64,
+                                                a1y: f64, a2x: f64, a2y: f64,
+                                                status: *mut i32)
+                                                -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match LatticeGeometry::new((a1x, a1y), (a2x, a2y)) {
+            Ok(geometry) => {
+                let settings = LatticeSettings { geometry,
+                                                 ..LatticeSettings::default() };
+                k_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, term,
+                                 status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Build the operator described by "term" in the (kx, ky, nup) sector. Returns
+/// a null pointer if the term or the parameters are invalid or the term does
+/// not conserve total Sz. The status code is written to "status" if it is not
+/// null. A range without bonds on the lattice gives a matrix without nonzero
+/// elements, see k_term_matrix.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix(nx: u32, ny: u32, kx: u32, ky: u32,
+                                        nup: u32, term: CTerm, status: *mut i32)
+                                        -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        ks_term_matrix_progress(nx, ny, kx, ky, nup, term, None, ptr::null_mut(),
+                                status)
+    })
+}
+
+/// Same as ks_term_matrix on a lattice with the primitive vectors (a1x, a1y)
+/// and (a2x, a2y), see k_term_matrix_geometry
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_geometry(nx: u32, ny: u32, kx: u32,
+                                                 ky: u32, nup: u32, term: CTerm,
+                                                 a1x: f64, a1y: f64, a2x: f64,
+                                                 a2y: f64, status: *mut i32)
+                                                 -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match LatticeGeometry::new((a1x, a1y), (a2x, a2y)) {
+            Ok(geometry) => {
+                let settings = LatticeSettings { geometry,
+                                                 ..LatticeSettings::default() };
+                ks_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, nup,
+                                  term,
+                                  &mut Progress::none(), status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as k_term_matrix on a lattice whose boundary in y is shifted by "shift"
+/// sites along x, see LatticeSettings: a site that leaves the lattice across
+/// the top at (x, ny) comes back at (x + shift, 0). The momenta are quantized on
+/// the shifted lattice, which for a label (kx, ky) takes the phase of a
+/// translation along y to 2π (ky - shift kx / nx) / ny. Also fails unless
+/// "shift" is less than nx.
+#[no_mangle]
+pub unsafe extern "C" fn k_term_matrix_shifted(nx: u32, ny: u32, kx: u32, ky: u32,
+                                               term: CTerm, shift: u32,
+                                               status: *mut i32)
+                                               -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        if shift >= nx {
+            return handle_or_null(Err(Error::InvalidArgument("shift")), status);
+        }
+        let settings = LatticeSettings { shift,
+                                         ..LatticeSettings::default() };
+        k_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, term,
+                                 status)
+    })
+}
+
+/// Same as ks_term_matrix on a lattice shifted by "shift" across the boundary
+/// in y, see k_term_matrix_shifted
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_shifted(nx: u32, ny: u32, kx: u32,
+                                                ky: u32, nup: u32, term: CTerm,
+                                                shift: u32, status: *mut i32)
+                                                -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        if shift >= nx {
+            return handle_or_null(Err(Error::InvalidArgument("shift")), status);
+        }
+        let settings = LatticeSettings { shift,
+                                         ..LatticeSettings::default() };
+        ks_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, nup,
+                                  term,
+                          &mut Progress::none(), status)
+    })
+}
+
+// the periodicity coded by the flags of k_term_matrix_periodicity, failing for
+// a momentum other than 0 along an open axis
+fn periodicity(kx: u32, ky: u32, periodic_x: u32, periodic_y: u32)
+               -> Result<Periodicity> {
+    let periodicity = Periodicity { x: periodic_x != 0,
+                                    y: periodic_y != 0 };
+    if (!periodicity.x && kx != 0) || (!periodicity.y && ky != 0) {
+        return Err(Error::InvalidArgument("momentum along an open axis"));
+    }
+    Ok(periodicity)
+}
+
+/// Same as k_term_matrix on a lattice that is periodic along x only if
+/// "periodic_x" is nonzero and along y only if "periodic_y" is, see
+/// LatticeSettings: no bond or triangle crosses an open end, and along an open
+/// axis only momentum 0 has states. A lattice with an open end has no shift.
+/// Also fails for a momentum other than 0 along an open axis.
+#[no_mangle]
+pub unsafe extern "C" fn k_term_matrix_periodicity(nx: u32, ny: u32, kx: u32,
+                                                   ky: u32, term: CTerm,
+                                                   periodic_x: u32,
+                                                   periodic_y: u32,
+                                                   status: *mut i32)
+                                                   -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match periodicity(kx, ky, periodic_x, periodic_y) {
+            Ok(periodicity) => {
+                let settings = LatticeSettings { periodicity,
+                                                 ..LatticeSettings::default() };
+                k_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, term,
+                                 status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as ks_term_matrix on a lattice with open ends along the axes whose flag
+/// is 0, see k_term_matrix_periodicity
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_periodicity(nx: u32, ny: u32, kx: u32,
+                                                    ky: u32, nup: u32,
+                                                    term: CTerm,
+                                                    periodic_x: u32,
+                                                    periodic_y: u32,
+                                                    status: *mut i32)
+                                                    -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match periodicity(kx, ky, periodic_x, periodic_y) {
+            Ok(periodicity) => {
+                let settings = LatticeSettings { periodicity,
+                                                 ..LatticeSettings::default() };
+                ks_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, nup,
+                                  term,
+                                  &mut Progress::none(), status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// The ordering of the sites of the nx by ny lattice coded by "ordering" and
+/// "permutation" (see k_term_matrix_ordered), of which nx * ny entries are read
+/// for a custom ordering
+unsafe fn site_ordering(nx: u32, ny: u32, ordering: u32, permutation: *const u32)
+                        -> Result<SiteOrdering> {
+    let (nx, ny) = (Dim(nx), Dim(ny));
+    common::check_lattice(nx, ny)?;
+    let permutation: &[u32] = if ordering == 2 && !permutation.is_null() {
+        slice::from_raw_parts(permutation, (nx * ny).raw_int() as usize)
+    } else {
+        &[]
+    };
+    let ordering = SiteOrdering::from_raw(ordering, permutation)?;
+    if !ordering.fits(nx, ny) {
+        return Err(Error::InvalidArgument("site ordering"));
+    }
+    Ok(ordering)
+}
+
+/// Same as k_term_matrix on a lattice whose sites are put in the bits of the
+/// configurations in another order than x + nx y, see LatticeSettings: 0 is that
+/// row-major order, 1 the snake that runs the odd rows right to left and 2
+/// the custom order that puts site x + nx y at bit permutation[x + nx y], the
+/// nx * ny entries of "permutation" having to be a permutation of the sites.
+/// The spectrum is the same in every order, the basis and its leading states
+/// are not. Also fails for an invalid ordering.
+#[no_mangle]
+pub unsafe extern "C" fn k_term_matrix_ordered(nx: u32, ny: u32, kx: u32, ky: u32,
+                                               term: CTerm, ordering: u32,
+                                               permutation: *const u32,
+                                               status: *mut i32)
+                                               -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match site_ordering(nx, ny, ordering, permutation) {
+            Ok(ordering) => {
+                let settings = LatticeSettings { ordering,
+                                                 ..LatticeSettings::default() };
+                k_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, term,
+                                 status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as ks_term_matrix on a lattice with another ordering of the sites, see
+/// k_term_matrix_ordered
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_ordered(nx: u32, ny: u32, kx: u32,
+                                                ky: u32, nup: u32, term: CTerm,
+                                                ordering: u32,
+                                                permutation: *const u32,
+                                                status: *mut i32)
+                                                -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match site_ordering(nx, ny, ordering, permutation) {
+            Ok(ordering) => {
+                let settings = LatticeSettings { ordering,
+                                                 ..LatticeSettings::default() };
+                ks_term_matrix_in(nx, ny, &settings, Convention::Plus, kx, ky, nup,
+                                  term,
+                                  &mut Progress::none(), status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as k_term_matrix with the Bloch functions built under the sign
+/// convention coded by "convention": 0 for Σ_t e^(+i k·t) T_t |lead>, the
+/// default, and 1 for Σ_t e^(-i k·t) T_t |lead>, where T_t translates by
+/// t = (i, j), i sites along +x and j rows along -y, and k·t = 2π (i kx / nx +
+/// j ky / ny). A sector labelled (kx, ky) under one convention is the one
+/// labelled (-kx, -ky) under the other. Also fails for an invalid convention.
+#[no_mangle]
+pub unsafe extern "C" fn k_term_matrix_convention(nx: u32, ny: u32, kx: u32,
+                                                  ky: u32, term: CTerm,
+                                                  convention: u32,
+                                                  status: *mut i32)
+                                                  -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match Convention::from_raw(convention) {
+            Ok(convention) => {
+                let torus = LatticeSettings::default();
+                k_term_matrix_in(nx, ny, &torus, convention, kx, ky, term, status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as ks_term_matrix under the sign convention coded by "convention", see
+/// k_term_matrix_convention
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_convention(nx: u32, ny: u32, kx: u32,
+                                                   ky: u32, nup: u32,
+                                                   term: CTerm, convention: u32,
+                                                   status: *mut i32)
+                                                   -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match Convention::from_raw(convention) {
+            Ok(convention) => {
+                let torus = LatticeSettings::default();
+                ks_term_matrix_in(nx, ny, &torus, convention, kx, ky, nup, term,
+                                  &mut Progress::none(), status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as ks_term_matrix, checking the configurations that the terms lead to
+/// and that are not found in the basis if "strict" is 1: the build fails with
+/// ERR_INCONSISTENT if one of them belongs in the sector of the basis, which
+/// means the basis is missing a Bloch function. With 0, as in ks_term_matrix,
+/// their matrix elements are dropped along with those of the configurations of
+/// other sectors. The check costs a walk over the orbit of every such
+/// configuration.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_strict(nx: u32, ny: u32, kx: u32, ky: u32,
+                                               nup: u32, term: CTerm, strict: u32,
+                                               status: *mut i32)
+                                               -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        let mut progress = Progress::none().strict(strict != 0);
+        let torus = LatticeSettings::default();
+        ks_term_matrix_in(nx, ny, &torus, Convention::Plus, kx, ky, nup, term,
+                          &mut progress, status)
+    })
+}
+
+/// Same as ks_term_matrix, finding the orbit of a configuration as coded by
+/// "lookup": 0, as in ks_term_matrix, keeps the configurations of every orbit
+/// of the basis in memory, 1 only the leading states, translating
+/// configurations onto them as they are looked up. The latter needs far less
+/// memory but builds the matrix more slowly.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_lookup(nx: u32, ny: u32, kx: u32, ky: u32,
+                                               nup: u32, term: CTerm, lookup: u32,
+                                               status: *mut i32)
+                                               -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match Lookup::from_raw(lookup) {
+            Ok(lookup) => {
+                let mut progress = Progress::none().with_lookup(lookup);
+                let torus = LatticeSettings::default();
+                ks_term_matrix_in(nx, ny, &torus, Convention::Plus, kx, ky, nup,
+                                  term, &mut progress, status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as ks_term_matrix, taking the component of a Bloch function on a
+/// configuration as a phase only if its magnitude is at least "norm" rather
+/// than 1e-8. Smaller components are treated as if the configuration were in
+/// no orbit of the basis. Fails with ERR_INVALID_ARGUMENT unless "norm" is
+/// finite and not negative.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_phase_norm(nx: u32, ny: u32, kx: u32,
+                                                   ky: u32, nup: u32, term: CTerm,
+                                                   norm: f64, status: *mut i32)
+                                                   -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        match common::check_phase_norm(norm) {
+            Ok(norm) => {
+                let mut progress = Progress::none().with_min_phase_norm(norm);
+                let torus = LatticeSettings::default();
+                ks_term_matrix_in(nx, ny, &torus, Convention::Plus, kx, ky, nup,
+                                  term, &mut progress, status)
+            }
+            Err(e) => handle_or_null(Err(e), status)
+        }
+    })
+}
+
+/// Same as ks_term_matrix, calling "progress" with the fraction done, the name
+/// of the phase ("basis" or "elements") and "ctx" at roughly every percent of
+/// each phase. The callback is invoked on the calling thread only. A null
+/// callback reports nothing.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_progress(nx: u32, ny: u32, kx: u32,
+                                                 ky: u32, nup: u32, term: CTerm,
+                                                 progress: Option<ProgressCallback>,
+                                                 ctx: *mut c_void,
+                                                 status: *mut i32)
+                                                 -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        ks_term_matrix_cancellable(nx,
+                                   ny,
+                                   kx,
+                                   ky,
+                                   nup,
+                                   term,
+                                   progress,
+                                   ctx,
+                                   ptr::null(),
+                                   status)
+    })
+}
+
+/// Same as ks_term_matrix_progress with the progress callback passed as "cb",
+/// additionally polling the byte at "cancel" between rows and basis states if
+/// it is not null. Once another thread sets it to a nonzero value the build
+/// stops, frees everything it has allocated and returns a null pointer with
+/// ERR_CANCELLED written to "status".
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_cancellable(nx: u32, ny: u32, kx: u32,
+                                                    ky: u32, nup: u32,
+                                                    term: CTerm,
+                                                    cb: Option<ProgressCallback>,
+                                                    ctx: *mut c_void,
+                                                    cancel: *const u8,
+                                                    status: *mut i32)
+                                                    -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        let mut progress = Progress::new(cb, ctx).with_cancel(cancel);
+        let torus = LatticeSettings::default();
+        ks_term_matrix_in(nx, ny, &torus, Convention::Plus, kx, ky, nup, term,
+                          &mut progress, status)
+    })
+}
+
+// ks_term_matrix_cancellable on the lattice with "settings" under "convention"
+unsafe fn ks_term_matrix_in(nx: u32, ny: u32, settings: &LatticeSettings,
+                            convention: Convention, kx: u32, ky: u32, nup: u32,
+                            term: CTerm, progress: &mut Progress,
+                            status: *mut i32)
+                            -> *mut CoordMatrixHandle {
+    let result = Term::from_c(term).ok_or(Error::InvalidTerm(term.kind))
+                                   .and_then(|term| {
+                                       consv::ks::term_matrix_with_progress(
+                                           Dim(nx),
+                                           Dim(ny),
+                                           settings,
+                                           convention,
+                                           K(kx),
+                                           K(ky),
+                                           nup,
+                                           &term,
+                                           progress)
+                                   });
+    handle_or_null(result, status)
+}
+
+/// Same as ks_term_matrix, holding at most "max_bytes" of matrix elements in
+/// memory while the basis is around and spilling the rest to a scratch file in
+/// the directory "spill_dir" (the system's temporary directory if null). The
+/// spilled elements are read back into arrays of the final size once the basis
+/// is released, so the basis and the whole matrix are never held together.
+/// ERR_IO means the scratch file could not be written or read.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_spilled(nx: u32, ny: u32, kx: u32,
+                                                ky: u32, nup: u32, term: CTerm,
+                                                max_bytes: u64,
+                                                spill_dir: *const c_char,
+                                                status: *mut i32)
+                                                -> *mut CoordMatrixHandle {
+    guard_status(status, ptr::null_mut(), || {
+        let result = Term::from_c(term)
+            .ok_or(Error::InvalidTerm(term.kind))
+            .and_then(|term| {
+                let dir = match optional_str_from_raw(spill_dir, "spill_dir")? {
+                    Some(dir) => Path::new(dir).to_path_buf(),
+                    None => env::temp_dir()
+                };
+                consv::ks::term_matrix_spilled(Dim(nx),
+                                               Dim(ny),
+                                               K(kx),
+                                               K(ky),
+                                               nup,
+                                               &term,
+                                               max_bytes as usize,
+                                               &dir)
+            });
+        handle_or_null(result, status)
+    })
+}
+
+/// Same as ks_term_matrix for the real terms (HSsZ, HSsXy, SsZ and SsXy) in a
+/// sector with real phases, i.e. where 2 kx / nx and 2 ky / ny are integers,
+/// returning the matrix with real elements. The status code is written to
+/// "status" if it is not null; on failure the returned matrix is empty. The
+/// matrix must be released with real_matrix_free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_matrix_real(nx: u32, ny: u32, kx: u32, ky: u32,
+                                             nup: u32, term: CTerm,
+                                             status: *mut i32)
+                                             -> CoordMatrix<f64> {
+    guard_status(status, CoordMatrix::empty(), || {
+        let result = Term::from_c(term).ok_or(Error::InvalidTerm(term.kind))
+                                       .and_then(|term| {
+                                           consv::ks::term_real(Dim(nx),
+                                                                Dim(ny),
+                                                                K(kx),
+                                                                K(ky),
+                                                                nup,
+                                                                &term)
+                                       });
+        match result {
+            Ok(mat) => {
+                write_status(status, error::SUCCESS);
+                mat.into_ffi()
+            }
+            Err(e) => {
+                write_status(status, e.report());
+                CoordMatrix::empty()
+            }
+        }
+    })
+}
+
+/// Build each of the "nterms" terms in the (kx, ky, nup) sector, writing one
+/// handle per term to "out", which must have room for "nterms" pointers. The
+/// bond terms that share a range are generated together in a single pass over
+/// the basis. Returns a status code; on failure nothing is written to "out".
+/// Every handle must be released with coord_matrix_free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_terms_matrices(nx: u32, ny: u32, kx: u32, ky: u32,
+                                           nup: u32, terms: *const CTerm,
+                                           nterms: u32,
+                                           out: *mut *mut CoordMatrixHandle)
+                                           -> i32 {
+    guard(error::ERR_PANIC, || {
+        if out.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                                                      consv::ks::terms_matrices(
+                                                          Dim(nx),
+                                                          Dim(ny),
+                                                          K(kx),
+                                                          K(ky),
+                                                          nup,
+                                                          &terms)
+                                                  });
+        let mats = match result {
+            Ok(mats) => mats,
+            Err(e) => return e.report()
+        };
+        let out = slice::from_raw_parts_mut(out, mats.len());
+        for (slot, mat) in out.iter_mut().zip(mats) {
+            *slot = handle::into_raw(mat);
+        }
+        error::SUCCESS
+    })
+}
+
+/// Build each of the "nterms" terms in every momentum sector with "nup" up
+/// spins, writing one handle per term and sector to "out", which must have room
+/// for nx * ny * nterms pointers. The handle of term t in the (kx, ky) sector
+/// is at index (kx + ky * nx) * nterms + t, as ks_terms_matrices would write it
+/// for that sector. The sectors are built in parallel on the threads set with
+/// spinsys_set_threads. Returns a status code; on failure nothing is written
+/// to "out". Every handle must be released with coord_matrix_free.
+#[no_mangle]
+pub unsafe extern "C" fn ks_build_all_sectors(nx: u32, ny: u32, nup: u32,
+                                              terms: *const CTerm, nterms: u32,
+                                              out: *mut *mut CoordMatrixHandle)
+                                              -> i32 {
+    guard(error::ERR_PANIC, || {
+        if out.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        let result = terms_from_raw(terms, nterms).and_then(|terms| {
+                         let torus = LatticeSettings::default();
+                         sweep::build_all_sectors(Dim(nx), Dim(ny), &torus, nup,
+                                                  &terms)
+                     });
+        let sectors = match result {
+            Ok(sectors) => sectors,
+            Err(e) => return e.report()
+        };
+        let len = (nx * ny * nterms) as usize;
+        let out = slice::from_raw_parts_mut(out, len);
+        for (slot, mat) in out.iter_mut().zip(sectors.into_iter().flatten()) {
+            *slot = handle::into_raw(mat);
+        }
+        error::SUCCESS
+    })
+}
+
+/// The number of stored elements k_term_matrix would return for "term",
+/// computed without storing any of them. The status code is written to
+/// "status" if it is not null; on failure 0 is returned.
+#[no_mangle]
+pub unsafe extern "C" fn k_term_nnz(nx: u32, ny: u32, kx: u32, ky: u32,
+                                    term: CTerm, status: *mut i32)
+                                    -> u64 {
+    guard_status(status, 0, || {
+        let result = Term::from_c(term).ok_or(Error::InvalidTerm(term.kind))
+                                       .and_then(|term| {
+                                           consv::k::term_nnz(Dim(nx),
+                                                              Dim(ny),
+                                                              K(kx),
+                                                              K(ky),
+                                                              &term)
+                                       });
+        match result {
+            Ok(nnz) => {
+                write_status(status, error::SUCCESS);
+                nnz
+            }
+            Err(e) => {
+                write_status(status, e.report());
+                0
+            }
+        }
+    })
+}
+
+/// The number of stored elements ks_term_matrix would return for "term",
+/// computed without storing any of them. The status code is written to
+/// "status" if it is not null; on failure 0 is returned.
+#[no_mangle]
+pub unsafe extern "C" fn ks_term_nnz(nx: u32, ny: u32, kx: u32, ky: u32,
+                                     nup: u32, term: CTerm, status: *mut i32)
+                                     -> u64 {
+    guard_status(status, 0, || {
+        let result = Term::from_c(term).ok_or(Error::InvalidTerm(term.kind))
+                                       .and_then(|term| {
+                                           consv::ks::term_nnz(Dim(nx),
+                                                               Dim(ny),
+                                                               K(kx),
+                                                               K(ky),
+                                                               nup,
+                                                               &term)
+                                       });
+        match result {
+            Ok(nnz) => {
+                write_status(status, error::SUCCESS);
+                nnz
+            }
+            Err(e) => {
+                write_status(status, e.report());
+                0
+            }
+        }
+    })
+}
+
+/// The number of stored elements, or 0 if the handle is null or freed
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_nnz(handle: *const CoordMatrixHandle) -> u64 {
+    guard(0, || {
+        handle::live(handle).map_or(0, |mat| mat.matrix.nnz() as u64)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_nrows(handle: *const CoordMatrixHandle)
+                                            -> u32 {
+    guard(0, || handle::live(handle).map_or(0, |mat| mat.matrix.nrows))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_ncols(handle: *const CoordMatrixHandle)
+                                            -> u32 {
+    guard(0, || handle::live(handle).map_or(0, |mat| mat.matrix.ncols))
+}
+
+/// The "nnz" matrix elements, or null if the handle is null or freed. The
+/// pointer is valid until the handle is freed.
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_data(handle: *const CoordMatrixHandle)
+                                           -> *const CComplex<f64> {
+    guard(ptr::null(), || {
+        handle::live(handle).map_or(ptr::null(), |mat| mat.matrix.data.as_ptr())
+    })
+}
+
+/// The "nnz" column indices, or null if the handle is null or freed
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_col(handle: *const CoordMatrixHandle)
+                                          -> *const u32 {
+    guard(ptr::null(), || {
+        handle::live(handle).map_or(ptr::null(), |mat| mat.matrix.col.as_ptr())
+    })
+}
+
+/// The "nnz" row indices, or null if the handle is null or freed
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_row(handle: *const CoordMatrixHandle)
+                                          -> *const u32 {
+    guard(ptr::null(), || {
+        handle::live(handle).map_or(ptr::null(), |mat| mat.matrix.row.as_ptr())
+    })
+}
+
+/// Switch the index arrays to the layout selected by "flags", a combination of
+/// INDEX_ONE_BASED and ORDER_COLUMN_MAJOR. Without INDEX_ONE_BASED the indices
+/// are zero-based. Pointers obtained from the accessors stay valid. Returns a
+/// status code.
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_set_layout(handle: *mut CoordMatrixHandle,
+                                                 flags: u32)
+                                                 -> i32 {
+    guard(error::ERR_PANIC, || {
+        let layout = match IndexLayout::from_flags(flags) {
+            Some(layout) => layout,
+            None => return error::ERR_INVALID_ARGUMENT
+        };
+        if handle.is_null() {
+            return error::ERR_INVALID_ARGUMENT;
+        }
+        match handle::live_mut(handle) {
+            Some(mat) => {
+                mat.set_layout(layout);
+                error::SUCCESS
+            }
+            None => error::ERR_ALREADY_FREED
+        }
+    })
+}
+
+/// Release a matrix returned by k_term_matrix or ks_term_matrix together with
+/// its handle. Freeing the same handle twice returns ERR_ALREADY_FREED instead
+/// of corrupting memory (see the handle module); a null handle returns
+/// ERR_INVALID_ARGUMENT.
+#[no_mangle]
+pub unsafe extern "C" fn coord_matrix_free(handle: *mut CoordMatrixHandle) -> i32 {
+    guard(error::ERR_PANIC, || {
+        if handle.is_null() {
+            error::ERR_INVALID_ARGUMENT
+        } else if handle::free(handle) {
+            error::SUCCESS
+        } else {
+            error::ERR_ALREADY_FREED
+        }
+    })
+}
+
+// release the memory of a vector handed over with Vector::from_vec. Empty
+// vectors returned on failure have a null pointer and own nothing.
+unsafe fn drop_vector<T>(vec: Vector<T>) { drop(vec.into_vec()) }
+
+/// The largest |a_ij - conj(a_ji)| of a matrix returned by any of the builders,
+/// summing the elements at the same position first (see
+/// assemble::max_asymmetry). For a hermitian operator it is of the order of the
+/// roundoff. The status code is written to "status" if it is not null; a null,
+/// malformed or non-square matrix is refused with ERR_INVALID_ARGUMENT and NaN
+/// is returned.
+#[no_mangle]
+pub unsafe extern "C" fn check_hermiticity(mat: *const CoordMatrix<CComplex<f64>>,
+                                           status: *mut i32)
+                                           -> f64 {
+    guard_status(status, f64::NAN, || match mat.as_ref() {
+        Some(mat) if mat.is_well_formed() && mat.nrows == mat.ncols => {
+            write_status(status, error::SUCCESS);
+            assemble::max_asymmetry(mat.col.as_slice(),
+                                    mat.row.as_slice(),
+                                    mat.data.as_slice())
+        }
+        _ => {
+            write_status(status, error::ERR_INVALID_ARGUMENT);
+            f64::NAN
+        }
+    })
+}
+
+/// Release a matrix returned by any of the builders. Matrices returned on
+/// failure, and zeroed ones, own nothing and are ignored. So is a matrix whose
+/// arrays are neither all allocated nor all null, which the library never
+/// returns.
 #[no_mangle]
 pub unsafe extern "C" fn request_free(mat: CoordMatrix<CComplex<f64>>) {
-    Box::from_raw(mat.data.ptr);
-    Box::from_raw(mat.col.ptr);
-    Box::from_raw(mat.row.ptr);
+    guard((), || drop(OwnedCoordMatrix::from_ffi(mat)))
+}
+
+/// Release a matrix returned by ks_term_matrix_real, as request_free does
+#[no_mangle]
+pub unsafe extern "C" fn real_matrix_free(mat: CoordMatrix<f64>) {
+    guard((), || drop(OwnedCoordMatrix::from_ffi(mat)))
+}
+
+/// Release a matrix returned by any of the dense builders. Matrices returned
+/// on failure, and zeroed ones, are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn dense_matrix_free(mat: DenseMatrix<CComplex<f64>>) {
+    guard((), || drop_vector(mat.data))
+}
+
+/// Release a vector of doubles returned by any of the functions above
+#[no_mangle]
+pub unsafe extern "C" fn vector_f64_free(vec: Vector<f64>) {
+    guard((), || drop_vector(vec))
+}
+
+fn empty_bond_list() -> BondList {
+    BondList { site1:     empty_vector(),
+               site2:     empty_vector(),
+               direction: empty_vector(),
+               x1:        empty_vector(),
+               y1:        empty_vector(),
+               x2:        empty_vector(),
+               y2:        empty_vector(),
+               wraps_x:   empty_vector(),
+               wraps_y:   empty_vector() }
+}
+
+fn empty_triangle_list() -> TriangleList {
+    TriangleList { site1:    empty_vector(),
+                   site2:    empty_vector(),
+                   site3:    empty_vector(),
+                   inverted: empty_vector(),
+                   anchor_x: empty_vector(),
+                   anchor_y: empty_vector() }
+}
+
+fn empty_shell_list() -> ShellList {
+    ShellList { range:    empty_vector(),
+                distance: empty_vector(),
+                sites:    empty_vector(),
+                dx:       empty_vector(),
+                dy:       empty_vector() }
+}
+
+// the lattice index of each single-site mask
+fn site_indices(sites: Vec<BinaryBasis>) -> Vector<u32> {
+    Vector::from_vec(sites.into_iter()
+                          .map(|s| s.raw_int().trailing_zeros())
+                          .collect())
+}
+
+/// The bonds between l-th neighbors (l = 1, 2 or 3) as pairs of lattice
+/// indices, in the order the builders visit them, with the direction, the
+/// coordinates of the sites and the boundary crossings of each bond (see
+/// BondList). An invalid "l" gives ERR_INVALID_ARGUMENT in "status", if not
+/// null, and a lattice of more than MAX_SITES sites ERR_LATTICE_TOO_LARGE, both
+/// with empty lists. Release the result with bond_list_free.
+#[no_mangle]
+pub unsafe extern "C" fn lattice_bonds(nx: u32, ny: u32, l: u32, status: *mut i32)
+                                       -> BondList {
+    guard_status(status, empty_bond_list(), || {
+        let checked = common::check_lattice(Dim(nx), Dim(ny))
+            .and_then(|()| BondRange::from_raw(l));
+        let l = match checked {
+            Ok(l) => l,
+            Err(e) => {
+                write_status(status, e.report());
+                return empty_bond_list();
+            }
+        };
+        let (site1, site2, direction) =
+            common::interacting_sites_with_directions(Dim(nx), Dim(ny), l.l());
+        let torus = LatticeSettings::default();
+        let tables = match common::lattice_tables(Dim(nx), Dim(ny), &torus) {
+            Ok(tables) => tables,
+            Err(e) => {
+                write_status(status, e.report());
+                return empty_bond_list();
+            }
+        };
+        let sites = common::site_vectors(Dim(nx), Dim(ny), &torus);
+        let (mut x1, mut y1, mut x2, mut y2) = (vec![], vec![], vec![], vec![]);
+        let (mut wraps_x, mut wraps_y) = (vec![], vec![]);
+        for (&s, d) in site1.iter().zip(tables.displacements(l.l())) {
+            let (x, y) = sites[common::site_index(s)].position_in(tables.geometry());
+            let (dx, dy) = d.cartesian_in(tables.geometry());
+            x1.push(x);
+            y1.push(y);
+            x2.push(x + dx);
+            y2.push(y + dy);
+            wraps_x.push((d.wraps_x != 0) as u8);
+            wraps_y.push((d.wraps_y != 0) as u8);
+        }
+        write_status(status, error::SUCCESS);
+        BondList { site1:     site_indices(site1),
+                   site2:     site_indices(site2),
+                   direction: Vector::from_vec(direction),
+                   x1:        Vector::from_vec(x1),
+                   y1:        Vector::from_vec(y1),
+                   x2:        Vector::from_vec(x2),
+                   y2:        Vector::from_vec(y2),
+                   wraps_x:   Vector::from_vec(wraps_x),
+                   wraps_y:   Vector::from_vec(wraps_y) }
+    })
+}
+
+/// Release a list returned by lattice_bonds
+#[no_mangle]
+pub unsafe extern "C" fn bond_list_free(bonds: BondList) {
+    guard((), || {
+        drop_vector(bonds.site1);
+        drop_vector(bonds.site2);
+        drop_vector(bonds.direction);
+        drop_vector(bonds.x1);
+        drop_vector(bonds.y1);
+        drop_vector(bonds.x2);
+        drop_vector(bonds.y2);
+        drop_vector(bonds.wraps_x);
+        drop_vector(bonds.wraps_y);
+    })
+}
+
+/// The triangles of the lattice as triplets of lattice indices in the order
+/// the chirality term visits them, an upright and an inverted one per site,
+/// with the orientation and the position of the first site of each (see
+/// TriangleList), each triangle once. The lists are empty for a lattice of more
+/// than MAX_SITES sites. Release the result with triangle_list_free.
+#[no_mangle]
+pub extern "C" fn lattice_triangles(nx: u32, ny: u32) -> TriangleList {
+    guard(empty_triangle_list(), || {
+        if common::check_lattice(Dim(nx), Dim(ny)).is_err() {
+            return empty_triangle_list();
+        }
+        let torus = LatticeSettings::default();
+        let tables = match common::lattice_tables(Dim(nx), Dim(ny), &torus) {
+            Ok(tables) => tables,
+            Err(_) => return empty_triangle_list()
+        };
+        let plaquettes = tables.plaquettes();
+        let index = |c: usize| {
+            Vector::from_vec(plaquettes.iter()
+                                       .map(|p| p.sites[c].raw_int() as u32)
+                                       .collect())
+        };
+        let (mut inverted, mut anchor_x, mut anchor_y) = (vec![], vec![], vec![]);
+        for p in plaquettes.iter() {
+            let (x, y) = p.anchor.position_in(tables.geometry());
+            inverted.push((p.orientation == Orientation::Down) as u32);
+            anchor_x.push(x);
+            anchor_y.push(y);
+        }
+        TriangleList { site1:    index(0),
+                       site2:    index(1),
+                       site3:    index(2),
+                       inverted: Vector::from_vec(inverted),
+                       anchor_x: Vector::from_vec(anchor_x),
+                       anchor_y: Vector::from_vec(anchor_y) }
+    })
+}
+
+/// The number of triangles lattice_triangles lists, 0 for a lattice of more
+/// than MAX_SITES sites
+#[no_mangle]
+pub extern "C" fn lattice_triangle_count(nx: u32, ny: u32) -> u32 {
+    guard(0, || {
+        if common::check_lattice(Dim(nx), Dim(ny)).is_err() {
+            return 0;
+        }
+        let torus = LatticeSettings::default();
+        common::lattice_tables(Dim(nx), Dim(ny), &torus)
+            .map_or(0, |tables| tables.plaquettes().len() as u32)
+    })
+}
+
+/// The wavevectors of the momenta of the plain torus in cartesian coordinates,
+/// in units of the inverse lattice spacing, under Convention::Plus (see
+/// blochfunc::lattice_momenta): qx and qy of the momentum (kx, ky) at
+/// 2 (kx + nx ky) and 2 (kx + nx ky) + 1. Under the other convention of
+/// k_term_matrix_convention the wavevectors are negated. The vector is
+/// empty for an empty lattice or one of more than MAX_SITES sites. Release it
+/// with vector_f64_free.
+#[no_mangle]
+pub extern "C" fn lattice_momenta(nx: u32, ny: u32) -> Vector<f64> {
+    guard(empty_vector(), || {
+        if nx == 0 || ny == 0 || common::check_lattice(Dim(nx), Dim(ny)).is_err() {
+            return empty_vector();
+        }
+        let torus = LatticeSettings::default();
+        let momenta =
+            blochfunc::lattice_momenta(Dim(nx), Dim(ny), &torus, Convention::Plus);
+        Vector::from_vec(momenta.iter().flat_map(|&(qx, qy)| vec![qx, qy]).collect())
+    })
+}
+
+/// The momentum whose wavevector (see lattice_momenta) is nearest to (qx, qy),
+/// written to "kx" and "ky", returning the distance between them, which is 0 up
+/// to roundoff for a wavevector allowed on the lattice. The status code is
+/// written to "status" if it is not null; on failure NaN is returned and "kx"
+/// and "ky" are left alone.
+#[no_mangle]
+pub unsafe extern "C" fn nearest_lattice_momentum(nx: u32, ny: u32, qx: f64,
+                                                  qy: f64, kx: *mut u32,
+                                                  ky: *mut u32,
+                                                  status: *mut i32)
+                                                  -> f64 {
+    guard_status(status, f64::NAN, || {
+        if let Err(e) = common::check_lattice(Dim(nx), Dim(ny)) {
+            write_status(status, e.report());
+            return f64::NAN;
+        }
+        let finite = qx.is_finite() && qy.is_finite();
+        if nx == 0 || ny == 0 || kx.is_null() || ky.is_null() || !finite {
+            write_status(status, error::ERR_INVALID_ARGUMENT);
+            return f64::NAN;
+        }
+        let torus = LatticeSettings::default();
+        let (mx, my, distance) =
+            blochfunc::nearest_momentum(Dim(nx), Dim(ny), &torus, Convention::Plus,
+                                        (qx, qy));
+        *kx = mx.raw_int();
+        *ky = my.raw_int();
+        write_status(status, error::SUCCESS);
+        distance
+    })
+}
+
+/// Release a list returned by lattice_triangles
+#[no_mangle]
+pub unsafe extern "C" fn triangle_list_free(triangles: TriangleList) {
+    guard((), || {
+        drop_vector(triangles.site1);
+        drop_vector(triangles.site2);
+        drop_vector(triangles.site3);
+        drop_vector(triangles.inverted);
+        drop_vector(triangles.anchor_x);
+        drop_vector(triangles.anchor_y);
+    })
+}
+
+/// The shells of neighbors of a site that hold any sites, in increasing order
+/// of distance, with the range of each, which is the "l" the builders take for
+/// the bonds between the sites in the shell of each other (see ShellList and
+/// common::lattice_shells). The lists are empty for a lattice of more than
+/// MAX_SITES sites. Release the result with shell_list_free.
+#[no_mangle]
+pub extern "C" fn lattice_shells(nx: u32, ny: u32) -> ShellList {
+    guard(empty_shell_list(), || {
+        if common::check_lattice(Dim(nx), Dim(ny)).is_err() {
+            return empty_shell_list();
+        }
+        let table =
+            common::lattice_shells(Dim(nx), Dim(ny), &LatticeSettings::default());
+        let (mut range, mut distance, mut sites) = (vec![], vec![], vec![]);
+        let (mut dx, mut dy) = (vec![], vec![]);
+        for shell in table.shells.iter() {
+            range.push(shell.range.raw_int() as u32);
+            distance.push(shell.distance);
+            sites.push(shell.sites as u32);
+            dx.push(shell.displacement.dx);
+            dy.push(shell.displacement.dy);
+        }
+        ShellList { range:    Vector::from_vec(range),
+                    distance: Vector::from_vec(distance),
+                    sites:    Vector::from_vec(sites),
+                    dx:       Vector::from_vec(dx),
+                    dy:       Vector::from_vec(dy) }
+    })
+}
+
+/// Release a list returned by lattice_shells
+#[no_mangle]
+pub unsafe extern "C" fn shell_list_free(shells: ShellList) {
+    guard((), || {
+        drop_vector(shells.range);
+        drop_vector(shells.distance);
+        drop_vector(shells.sites);
+        drop_vector(shells.dx);
+        drop_vector(shells.dy);
+    })
 }
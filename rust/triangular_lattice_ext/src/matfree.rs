@@ -0,0 +1,279 @@
+//! Matrix-free operators. An OpHandle owns the basis of a sector together with
+//! the lookup tables and site tables of its terms, and regenerates the matrix
+//! elements row by row every time it is applied to a vector. Nothing but the
+//! basis is ever stored, so sectors whose coordinate matrices would not fit in
+//! memory can still be handed to an iterative eigensolver. The basis itself
+//! may be kept on disk (see the diskbasis module).
+use num_complex::Complex;
+use rayon::{self, prelude::*};
+use std::{borrow::Cow, cmp, mem, sync::Arc};
+
+use blochfunc::{BlochFunc, BlochFuncSet, Convention, Lookup, OrbitTable};
+use common::*;
+use consv;
+use diskbasis::MappedBasis;
+use error::{Error, Result};
+use ops::{
+    ElementSink, PreparedTerm, RowElements, BLOCKS_PER_THREAD, ROWS_PER_BLOCK
+};
+use pool;
+use progress::Progress;
+
+pub struct OpHandle {
+    // borrows from "basis" below and must therefore be dropped before it.
+    // Fields are dropped in the order of declaration.
+    table: OrbitTable<'static>,
+    terms: Vec<PreparedTerm>,
+    basis: Basis
+}
+
+enum Basis {
+    Memory(Arc<BlochFuncSet>),
+    Mapped(Arc<MappedBasis>)
+}
+
+/// Collects the contributions of a block of rows to the output vector
+struct Contributions<'a> {
+    x:   &'a [Complex<f64>],
+    out: Vec<(u32, Complex<f64>)>
+}
+
+impl<'a> ElementSink for Contributions<'a> {
+    // the elements are read as (data, (col, row)) by the callers of the
+    // coordinate matrix builders, so the element generated in row i and
+    // column j lands in row j of the output
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        self.out.push((col, val * self.x[row as usize]));
+    }
+}
+
+impl OpHandle {
+    /// An operator on the given basis equal to the sum of "terms" weighted by
+    /// their coefficients
+    pub fn new(bfuncs: Arc<BlochFuncSet>, terms: &[Term]) -> OpHandle {
+        let terms = terms.iter()
+                         .map(|&t| PreparedTerm::new(t, &bfuncs.tables))
+                         .collect::<Vec<_>>();
+        let table = if terms.iter().all(|t| t.is_diagonal()) {
+            OrbitTable::empty()
+        } else {
+            // the Bloch functions and phases the table refers to live on the
+            // heap inside "bfuncs", which the handle holds a reference to and
+            // which is never modified, so they stay valid for as long as the
+            // handle exists
+            let table = OrbitTable::new(&bfuncs);
+            unsafe { mem::transmute::<OrbitTable, OrbitTable<'static>>(table) }
+        };
+        OpHandle { table,
+                   terms,
+                   basis: Basis::Memory(bfuncs) }
+    }
+
+    /// The sum of "terms" in the (kx, ky, nup) sector
+    pub fn ks(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term])
+              -> Result<OpHandle> {
+        let torus = LatticeSettings::default();
+        OpHandle::ks_in(nx, ny, &torus, Convention::Plus, kx, ky, nup, terms)
+    }
+
+    /// Same as ks with the basis scanned with "lookup" (see
+    /// Progress::with_lookup)
+    pub fn ks_with_lookup(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term],
+                          lookup: Lookup)
+                          -> Result<OpHandle> {
+        check_sz(terms)?;
+        for term in terms.iter() {
+            term.check(nx, ny)?;
+        }
+        let torus = LatticeSettings::default();
+        let mut progress = Progress::none().with_lookup(lookup);
+        let bfuncs =
+            consv::ks::bloch_states_with_progress(nx, ny, &torus, Convention::Plus,
+                                                  kx, ky, nup, &mut progress)?;
+        Ok(OpHandle::new(bfuncs, terms))
+    }
+
+    /// Same as ks on the lattice with "settings" under "convention"
+    pub fn ks_in(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                 convention: Convention, kx: K, ky: K, nup: u32, terms: &[Term])
+                 -> Result<OpHandle> {
+        check_sz(terms)?;
+        for term in terms.iter() {
+            term.check(nx, ny)?;
+        }
+        let bfuncs =
+            consv::ks::bloch_states_in(nx, ny, settings, convention, kx, ky, nup)?;
+        Ok(OpHandle::new(bfuncs, terms))
+    }
+
+    /// The sum of "terms" on a basis on disk. The lookup tables are the mapped
+    /// arrays themselves, so the operator adds next to nothing to the memory
+    /// used.
+    pub fn mapped(basis: Arc<MappedBasis>, terms: &[Term]) -> Result<OpHandle> {
+        check_sz(terms)?;
+        let terms = terms.iter()
+                         .map(|&t| PreparedTerm::new(t, &basis.tables))
+                         .collect::<Vec<_>>();
+        let table = if terms.iter().all(|t| t.is_diagonal()) {
+            OrbitTable::empty()
+        } else {
+            OrbitTable::mapped(basis.clone())
+        };
+        Ok(OpHandle { table,
+                      terms,
+                      basis: Basis::Mapped(basis) })
+    }
+
+    /// Twist the terms by "theta" across the boundary in x as described for
+    /// PreparedTerm::twisted, replacing any earlier twist. The basis and its
+    /// lookup tables are kept, so only the bond phases are regenerated.
+    pub fn set_twist(&mut self, theta: f64) -> Result<()> {
+        let tables = match self.basis {
+            Basis::Memory(ref bfuncs) => &bfuncs.tables,
+            Basis::Mapped(ref basis) => &basis.tables
+        };
+        self.terms = self.terms
+                         .iter()
+                         .map(|t| PreparedTerm::twisted(t.term, tables, theta))
+                         .collect::<Result<Vec<_>>>()?;
+        Ok(())
+    }
+
+    pub fn dim(&self) -> u32 {
+        match self.basis {
+            Basis::Memory(ref bfuncs) => bfuncs.nonzero,
+            Basis::Mapped(ref basis) => basis.dim()
+        }
+    }
+
+    /// The basis the operator acts on. Panics if the basis is on disk.
+    pub fn bfuncs(&self) -> &BlochFuncSet {
+        match self.basis {
+            Basis::Memory(ref bfuncs) => bfuncs,
+            Basis::Mapped(_) => panic!("the basis of the operator is on disk")
+        }
+    }
+
+    /// The basis state with index "i"
+    fn state(&self, i: u32) -> Cow<'_, BlochFunc> {
+        match self.basis {
+            Basis::Memory(ref bfuncs) => Cow::Borrowed(&bfuncs.data[i as usize]),
+            Basis::Mapped(ref basis) => Cow::Owned(basis.get(i))
+        }
+    }
+
+    /// y = H x. Blocks of rows are generated in parallel on the pool
+    /// configured in the pool module and their contributions are added to y on
+    /// the calling thread, so the result does not depend on the thread count.
+    pub fn apply(&self, x: &[Complex<f64>], y: &mut [Complex<f64>]) -> Result<()> {
+        let dims = self.dim() as usize;
+        if x.len() != dims || y.len() != dims {
+            return Err(Error::InvalidArgument("dim"));
+        }
+        pool::install(|| self.apply_unchecked(x, y));
+        Ok(())
+    }
+
+    fn apply_unchecked(&self, x: &[Complex<f64>], y: &mut [Complex<f64>]) {
+        let dims = self.dim() as usize;
+        for yi in y.iter_mut() {
+            *yi = Complex::new(0., 0.);
+        }
+
+        // only a few blocks per thread are held in memory at any time
+        let nblocks = (dims + ROWS_PER_BLOCK - 1) / ROWS_PER_BLOCK;
+        let batch_size = BLOCKS_PER_THREAD * rayon::current_num_threads();
+        let mut start = 0;
+        while start < nblocks {
+            let end = cmp::min(start + batch_size, nblocks);
+            let batch = (start..end).into_par_iter()
+                                    .map(|n| self.block_contributions(n, x))
+                                    .collect::<Vec<_>>();
+            for contribs in batch.into_iter() {
+                for (j, val) in contribs.into_iter() {
+                    y[j as usize] += val;
+                }
+            }
+            start = end;
+        }
+    }
+
+    fn block_contributions(&self, n: usize, x: &[Complex<f64>])
+                           -> Vec<(u32, Complex<f64>)> {
+        let row_start = n * ROWS_PER_BLOCK;
+        let row_end = cmp::min(row_start + ROWS_PER_BLOCK, self.dim() as usize);
+        let mut sink = Contributions { x, out: Vec::new() };
+        let mut elements = RowElements::new();
+        for i in row_start as u32..row_end as u32 {
+            let orig_state = self.state(i);
+            for term in self.terms.iter() {
+                term.row_into(i,
+                              &orig_state,
+                              &self.table,
+                              &mut elements,
+                              &mut sink);
+            }
+        }
+        sink.out
+    }
+}
+
+/// Fails unless all of "terms" conserve Sz, as the terms of an operator on the
+/// basis of a (kx, ky, nup) sector must
+pub fn check_sz(terms: &[Term]) -> Result<()> {
+    match terms.iter().find(|t| !t.kind.conserves_sz()) {
+        Some(term) => Err(Error::InvalidTerm(term.kind as u32)),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ops;
+
+    #[test]
+    fn apply_matches_coord_matrix() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+        let terms = [Term { kind:  TermKind::HSsZ,
+                            l:     I(1),
+                            coeff: 1. },
+                     Term { kind:  TermKind::HSsXy,
+                            l:     I(2),
+                            coeff: 0.5 },
+                     Term { kind:  TermKind::HSssChi,
+                            l:     I(0),
+                            coeff: -0.3 }];
+        let op = OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap();
+        let dims = op.dim() as usize;
+        let x = (0..dims).map(|i| Complex::new(1. + i as f64, 0.5 - i as f64))
+                         .collect::<Vec<_>>();
+        let mut y = vec![Complex::new(0., 0.); dims];
+        op.apply(&x, &mut y).unwrap();
+
+        // the coordinate matrices are read as (data, (col, row)) by the callers
+        let mut expected = vec![Complex::new(0., 0.); dims];
+        for term in terms.iter() {
+            let mat = ops::term(term, op.bfuncs());
+            let (data, col, row) = (&mat.data, &mat.col, &mat.row);
+            for k in 0..data.len() {
+                let val = Complex::new(data[k].re, data[k].im);
+                expected[col[k] as usize] += val * x[row[k] as usize];
+            }
+        }
+        for (a, b) in y.iter().zip(expected.iter()) {
+            assert!((*a - *b).norm() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn apply_rejects_wrong_dim() {
+        let terms = [Term { kind:  TermKind::HSsZ,
+                            l:     I(1),
+                            coeff: 1. }];
+        let op = OpHandle::ks(Dim(4), Dim(3), K(0), K(0), 6, &terms).unwrap();
+        let x = vec![Complex::new(0., 0.); op.dim() as usize + 1];
+        let mut y = x.clone();
+        assert!(op.apply(&x, &mut y).is_err());
+    }
+}
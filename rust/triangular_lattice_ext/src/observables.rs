@@ -0,0 +1,639 @@
+//! Quantities evaluated on a state in the symmetry-reduced basis of a sector
+//! without materializing the operators involved. The matrix elements are
+//! generated row by row exactly as for the builders and consumed on the fly.
+use fnv::FnvHashMap;
+use num_complex::Complex;
+
+use blochfunc::{momentum_angle, BlochFuncSet, Convention};
+use common::*;
+use consv;
+use error::{Error, Result};
+use lanczos::hermitian_eigvals;
+use ops::{self, ElementSink};
+
+/// The largest region whose reduced density matrix is diagonalized. The
+/// matrix has 2^|A| rows and is diagonalized densely.
+pub const MAX_REGION_SITES: u32 = 10;
+
+/// Accumulates <psi|O|psi> from the elements of O
+struct ExpectationSink<'a> {
+    psi: &'a [Complex<f64>],
+    sum: Complex<f64>
+}
+
+impl<'a> ElementSink for ExpectationSink<'a> {
+    // the element generated in row i and column j sits in row j and column i
+    // of the matrix (the arrays are read as (data, (col, row)))
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        let (i, j) = (row as usize, col as usize);
+        self.sum += self.psi[j].conj() * val * self.psi[i];
+    }
+}
+
+/// <psi|term|psi> on the basis "bfuncs". "psi" need not be normalized.
+pub fn expectation(term: &Term, bfuncs: &BlochFuncSet, psi: &[Complex<f64>])
+                   -> Result<Complex<f64>> {
+    if psi.len() != bfuncs.nonzero as usize {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    let mut sink = ExpectationSink { psi,
+                                     sum: Complex::new(0., 0.) };
+    ops::term_into(term, bfuncs, &mut sink);
+    Ok(sink.sum)
+}
+
+/// <psi|term|psi> in the (kx, ky, nup) sector
+pub fn ks_expectation(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: &Term,
+                      psi: &[Complex<f64>])
+                      -> Result<Complex<f64>> {
+    if !term.kind.conserves_sz() {
+        return Err(Error::InvalidTerm(term.kind as u32));
+    }
+    term.check(nx, ny)?;
+    let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+    expectation(term, &bfuncs, psi)
+}
+
+/// The correlations C(r) = 1/N Σ_i <psi|S_i · S_{i+r}|psi> / <psi|psi> for
+/// every separation r = (l % nx, l / nx), at index l. In a momentum sector
+/// every site is equivalent, so C(r) is also <S_i · S_{i+r}> for any single i.
+/// Each separation takes one streamed pass over the elements of ss_z and ss_xy.
+pub fn correlations(bfuncs: &BlochFuncSet, psi: &[Complex<f64>])
+                    -> Result<Vec<f64>> {
+    let n = (bfuncs.nx * bfuncs.ny).raw_int() as usize;
+    let norm = psi.iter().map(|c| c.norm_sqr()).sum::<f64>();
+    if norm == 0. {
+        return Err(Error::InvalidArgument("psi"));
+    }
+
+    // S_i · S_i = 3/4 for spin 1/2; SsXy does not generate the on-site part
+    let mut corr = vec![0.75];
+    for l in 1..n {
+        let zz = expectation(&Term::new(TermKind::SsZ, I(l as i32)), bfuncs, psi)?;
+        let xy = expectation(&Term::new(TermKind::SsXy, I(l as i32)), bfuncs, psi)?;
+        corr.push((zz + xy).re / (n as f64 * norm));
+    }
+    Ok(corr)
+}
+
+/// The full N x N matrix of <psi|S_i · S_j|psi> / <psi|psi>, with the element
+/// for the sites at bits i and j at index i * N + j. Only the N distinct
+/// separations are evaluated; the rest follows from translation symmetry.
+pub fn correlation_matrix(bfuncs: &BlochFuncSet, psi: &[Complex<f64>])
+                          -> Result<Vec<f64>> {
+    let (nx, ny) = (bfuncs.nx.raw_int(), bfuncs.ny.raw_int());
+    let corr = correlations(bfuncs, psi)?;
+    let n = corr.len();
+    // the row-major index of the site at each bit
+    let index = |bit: u32| bfuncs.ordering.row_major_index(bit, bfuncs.nx);
+    let mut mat = Vec::with_capacity(n * n);
+    for i in (0..n as u32).map(index) {
+        for j in (0..n as u32).map(index) {
+            let x = (j % nx + nx - i % nx) % nx;
+            let y = (j / nx + ny - i / nx) % ny;
+            mat.push(corr[(x + y * nx) as usize]);
+        }
+    }
+    Ok(mat)
+}
+
+/// The static structure factor S(q) = <psi|S_-q · S_q|psi> / <psi|psi> at every
+/// momentum q = (m, n) allowed on the cluster, stored at index m + n * nx,
+/// with the wavevector blochfunc::momentum_angle gives the label, as
+/// blochfunc::lattice_momenta lists them. This is S(q) = Σ_r exp(-i q·r) C(r)
+/// with the correlations C(r) computed once by correlations().
+pub fn structure_factor(bfuncs: &BlochFuncSet, psi: &[Complex<f64>])
+                        -> Result<Vec<f64>> {
+    let (nx, ny) = (bfuncs.nx.raw_int(), bfuncs.ny.raw_int());
+    let corr = correlations(bfuncs, psi)?;
+
+    let mut sq = Vec::with_capacity(corr.len());
+    for qy in 0..ny {
+        for qx in 0..nx {
+            // C(r) is real and C(r) = C(-r), so the sine part cancels
+            let mut s = 0.;
+            for (l, c) in corr.iter().enumerate() {
+                let (x, y) = ((l as u32 % nx) as i32, (l as u32 / nx) as i32);
+                let ang = momentum_angle(bfuncs.nx,
+                                         bfuncs.ny,
+                                         bfuncs.shift,
+                                         bfuncs.convention,
+                                         K(qx),
+                                         K(qy),
+                                         x,
+                                         y);
+                s += c * ang.cos();
+            }
+            sq.push(s);
+        }
+    }
+    Ok(sq)
+}
+
+/// S(q) at every allowed q for a state in the (kx, ky, nup) sector
+pub fn ks_structure_factor(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                           psi: &[Complex<f64>])
+                           -> Result<Vec<f64>> {
+    let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+    if psi.len() != bfuncs.nonzero as usize {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    structure_factor(&bfuncs, psi)
+}
+
+/// The correlation matrix of a state in the (kx, ky, nup) sector
+pub fn ks_correlation_matrix(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                             psi: &[Complex<f64>])
+                             -> Result<Vec<f64>> {
+    let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+    if psi.len() != bfuncs.nonzero as usize {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    correlation_matrix(&bfuncs, psi)
+}
+
+/// The eigenvalues of the reduced density matrix of "psi" on the sites set in
+/// "region_mask" (bit i for site i), in descending order. There are
+/// 2^|A| of them, including the zeros.
+pub fn entanglement_spectrum(bfuncs: &BlochFuncSet, psi: &[Complex<f64>],
+                             region_mask: u64)
+                             -> Result<Vec<f64>> {
+    let n = (bfuncs.nx * bfuncs.ny).raw_int();
+    if n < 64 && region_mask >> n != 0 {
+        return Err(Error::InvalidArgument("region_mask"));
+    }
+    let sites = region_mask.count_ones();
+    if sites > MAX_REGION_SITES {
+        return Err(Error::TooLarge(1 << sites));
+    }
+    let states = bfuncs.expand(psi)?;
+    let norm = states.iter().map(|s| s.1.norm_sqr()).sum::<f64>();
+    if norm == 0. {
+        return Err(Error::InvalidArgument("psi"));
+    }
+
+    // the configurations of the region that appear alongside each
+    // configuration of the rest of the lattice
+    let mut groups: FnvHashMap<u64, Vec<(u64, Complex<f64>)>> =
+        FnvHashMap::default();
+    for (dec, amp) in states.into_iter() {
+        let dec = dec.raw_int();
+        groups.entry(dec & !region_mask)
+              .or_default()
+              .push((dec & region_mask, amp));
+    }
+
+    // total Sz is conserved, so the reduced density matrix is block diagonal
+    // in the number of up spins in the region
+    let mut blocks: Vec<Vec<u64>> = vec![Vec::new(); sites as usize + 1];
+    let mut index: FnvHashMap<u64, (usize, usize)> = FnvHashMap::default();
+    for group in groups.values() {
+        for &(a, _) in group.iter() {
+            let k = a.count_ones() as usize;
+            index.entry(a).or_insert_with(|| {
+                              blocks[k].push(a);
+                              (k, blocks[k].len() - 1)
+                          });
+        }
+    }
+    let mut rho = blocks.iter()
+                        .map(|b| vec![Complex::new(0., 0.); b.len() * b.len()])
+                        .collect::<Vec<_>>();
+    for group in groups.values() {
+        for &(a, amp) in group.iter() {
+            let (k, i) = index[&a];
+            let m = blocks[k].len();
+            for &(a2, amp2) in group.iter() {
+                let (k2, j) = index[&a2];
+                if k2 == k {
+                    rho[k][i * m + j] += amp * amp2.conj() / norm;
+                }
+            }
+        }
+    }
+
+    let mut spectrum = Vec::with_capacity(1 << sites);
+    for (block, configs) in rho.iter().zip(blocks.iter()) {
+        if !configs.is_empty() {
+            spectrum.extend(hermitian_eigvals(block, configs.len())?);
+        }
+    }
+    spectrum.resize(1 << sites, 0.);
+    spectrum.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    Ok(spectrum)
+}
+
+/// The von Neumann entropy -Σ p ln p of the reduced density matrix of "psi" on
+/// the sites set in "region_mask"
+pub fn entanglement_entropy(bfuncs: &BlochFuncSet, psi: &[Complex<f64>],
+                            region_mask: u64)
+                            -> Result<f64> {
+    let spectrum = entanglement_spectrum(bfuncs, psi, region_mask)?;
+    // eigenvalues that vanish up to rounding contribute nothing
+    Ok(-spectrum.iter()
+                .filter(|&&p| p > 1e-14)
+                .map(|&p| p * p.ln())
+                .sum::<f64>())
+}
+
+/// The entanglement spectrum of a state in the (kx, ky, nup) sector of the
+/// lattice with "settings", labelled under Convention::Plus, the bits of
+/// "region_mask" being those of the sites in its ordering
+pub fn ks_entanglement_spectrum(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                                kx: K, ky: K, nup: u32, psi: &[Complex<f64>],
+                                region_mask: u64)
+                                -> Result<Vec<f64>> {
+    let plus = Convention::Plus;
+    let bfuncs = consv::ks::bloch_states_in(nx, ny, settings, plus, kx, ky, nup)?;
+    entanglement_spectrum(&bfuncs, psi, region_mask)
+}
+
+/// The entanglement entropy of a state in the (kx, ky, nup) sector of the
+/// lattice with "settings", see ks_entanglement_spectrum
+pub fn ks_entanglement_entropy(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                               kx: K, ky: K, nup: u32, psi: &[Complex<f64>],
+                               region_mask: u64)
+                               -> Result<f64> {
+    let plus = Convention::Plus;
+    let bfuncs = consv::ks::bloch_states_in(nx, ny, settings, plus, kx, ky, nup)?;
+    entanglement_entropy(&bfuncs, psi, region_mask)
+}
+
+// <Sz_i> for every site from the amplitudes of product states
+fn local_sz<I>(n: u32, states: I) -> Result<Vec<f64>>
+    where I: Iterator<Item = (BinaryBasis, Complex<f64>)>
+{
+    let mut sz = vec![0.; n as usize];
+    let mut norm = 0.;
+    for (dec, amp) in states {
+        let w = amp.norm_sqr();
+        norm += w;
+        for (i, s) in sz.iter_mut().enumerate() {
+            let mask = checked_site_mask(i as u32)?;
+            if dec & mask == mask {
+                *s += 0.5 * w;
+            } else {
+                *s -= 0.5 * w;
+            }
+        }
+    }
+    if norm == 0. {
+        return Err(Error::InvalidArgument("psi"));
+    }
+    Ok(sz.into_iter().map(|s| s / norm).collect())
+}
+
+/// <psi|Sz_i|psi> / <psi|psi> for every site i, where "psi" is given in the
+/// basis of all choose(nx * ny, nup) configurations with "nup" up spins,
+/// ordered by ascending decimal label (the order ks_expand_state_sz writes)
+pub fn s_local_sz(nx: Dim, ny: Dim, nup: u32, psi: &[Complex<f64>])
+                  -> Result<Vec<f64>> {
+    check_lattice(nx, ny)?;
+    if nup > (nx * ny).raw_int() {
+        return Err(Error::InvalidArgument("nup"));
+    }
+    let n = nx * ny;
+    let mut decs = sz_basis(n, nup);
+    if psi.len() != decs.len() {
+        return Err(Error::InvalidArgument("dim"));
+    }
+    decs.sort();
+    local_sz(n.raw_int(), decs.into_iter().zip(psi.iter().cloned()))
+}
+
+/// <psi|Sz_i|psi> / <psi|psi> for every site i of a state in the (kx, ky, nup)
+/// sector. Translation symmetry makes all of them equal to nup / N - 1/2; they
+/// are computed from the expanded state nonetheless, as a consistency check.
+pub fn ks_local_sz(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, psi: &[Complex<f64>])
+                   -> Result<Vec<f64>> {
+    let states = consv::ks::expand_state(nx, ny, kx, ky, nup, psi)?;
+    local_sz((nx * ny).raw_int(), states.into_iter())
+}
+
+/// Participation ratios of a state in the (kx, ky, nup) sector and its weight
+/// on the product states "decs"
+pub fn ks_state_diagnostics(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32,
+                            psi: &[Complex<f64>], decs: &[BinaryBasis])
+                            -> Result<StateDiagnostics> {
+    consv::ks::bloch_states(nx, ny, kx, ky, nup)?.diagnostics(psi, decs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blochfunc::BlochFunc;
+    use lanczos::ground_state;
+    use matfree::OpHandle;
+    use sitevector::{SiteOrdering, SitePermutation, SiteVector};
+
+    #[test]
+    fn expectation_matches_sandwich() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term { kind:  TermKind::HSssChi,
+                            l:     I(0),
+                            coeff: 0.4 }];
+        let op = OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap();
+        let (_, psi) = ground_state(&op, 1e-12, 300, true).unwrap();
+        let psi = psi.unwrap();
+
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        for &(kind, l) in [(TermKind::SsZ, 1),
+                           (TermKind::SsXy, 2),
+                           (TermKind::HSssChi, 0)].iter()
+        {
+            let term = Term::new(kind, I(l));
+            let mat = ops::term(&term, &bfuncs);
+            let mut expected = Complex::new(0., 0.);
+            for k in 0..mat.nnz() {
+                let val = Complex::new(mat.data[k].re, mat.data[k].im);
+                let (i, j) = (mat.col[k] as usize, mat.row[k] as usize);
+                expected += psi[i].conj() * val * psi[j];
+            }
+            let val = ks_expectation(nx, ny, kx, ky, nup, &term, &psi).unwrap();
+            assert!((val - expected).norm() < 1e-12);
+        }
+
+        let term = Term::new(TermKind::SsZ, I(1));
+        let res = ks_expectation(nx, ny, kx, ky, nup, &term, &psi[1..]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn structure_factor_matches_operators() {
+        let (nx, ny, kx, ky, nup) = (Dim(3), Dim(3), K(0), K(0), 4);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1))];
+        let op = OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap();
+        let (_, psi) = ground_state(&op, 1e-12, 300, true).unwrap();
+        let psi = psi.unwrap();
+        let sq = ks_structure_factor(nx, ny, kx, ky, nup, &psi).unwrap();
+        assert_eq!(sq.len(), 9);
+
+        // S(q) = 1/N Σ_r exp(-i q·r) Σ_i S_i · S_{i+r}, with every operator
+        // built explicitly
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let mut ops_r = Vec::new();
+        for l in 1..9 {
+            let mut elems = Vec::new();
+            for &kind in [TermKind::SsZ, TermKind::SsXy].iter() {
+                let mat = ops::term(&Term::new(kind, I(l)), &bfuncs);
+                for k in 0..mat.nnz() {
+                    let val = Complex::new(mat.data[k].re, mat.data[k].im);
+                    elems.push((mat.col[k] as usize, mat.row[k] as usize, val));
+                }
+            }
+            ops_r.push(elems);
+        }
+        for (q, &s) in sq.iter().enumerate() {
+            // the translations run along -y, and so does the wavevector of
+            // the label qy (see momentum_angle)
+            let (qx, qy) = ((q % 3) as f64, -((q / 3) as f64));
+            // the on-site term contributes N * 3/4
+            let mut expected = Complex::new(9. * 0.75, 0.);
+            for (l, elems) in ops_r.iter().enumerate() {
+                let (x, y) = (((l + 1) % 3) as f64, ((l + 1) / 3) as f64);
+                let phase =
+                    Complex::from_polar(&1., &(-2. * PI * (qx * x + qy * y) / 3.));
+                for &(c, r, val) in elems.iter() {
+                    expected += phase * psi[c].conj() * val * psi[r];
+                }
+            }
+            expected /= 9.;
+            assert!(expected.im.abs() < 1e-10);
+            assert!((s - expected.re).abs() < 1e-10);
+        }
+
+        assert!(ks_structure_factor(nx, ny, kx, ky, nup, &psi[1..]).is_err());
+    }
+
+    // N Σ_r C(r) = <S_tot²>, which weighs the pairs at the separations that are
+    // their own inverse on the 4x4 torus, such as (2, 0), as much as the rest
+    #[test]
+    fn correlations_sum_to_total_spin() {
+        let (nx, ny) = (Dim(4), Dim(4));
+        // a single spin down at zero momentum is the polarized state lowered by
+        // S_tot-, with S = N / 2 = 8
+        let bfuncs = consv::ks::bloch_states(nx, ny, K(0), K(0), 15).unwrap();
+        assert_eq!(bfuncs.nonzero, 1);
+        let corr = correlations(&bfuncs, &[Complex::new(1., 0.)]).unwrap();
+        let total = corr.iter().sum::<f64>() * 16.;
+        assert!((total - 8. * 9.).abs() < 1e-10);
+    }
+
+    #[test]
+    fn correlation_matrix_matches_product_basis() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1))];
+        let op = OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap();
+        let (_, psi) = ground_state(&op, 1e-12, 300, true).unwrap();
+        let psi = psi.unwrap();
+        let mat = ks_correlation_matrix(nx, ny, kx, ky, nup, &psi).unwrap();
+        assert_eq!(mat.len(), 144);
+
+        // <S_i · S_j> evaluated directly on the expanded state
+        let states = consv::ks::expand_state(nx, ny, kx, ky, nup, &psi).unwrap();
+        let amps = states.iter().cloned().collect::<FnvHashMap<_, _>>();
+        for i in 0..12 {
+            assert_eq!(mat[i * 12 + i], 0.75);
+            for j in 0..12 {
+                if i == j {
+                    continue;
+                }
+                let (s1, s2) = (site_mask(i as u32), site_mask(j as u32));
+                let mut expected = Complex::new(0., 0.);
+                for (&dec, &amp) in amps.iter() {
+                    let (upup, downdown) = repeated_spins(dec, s1, s2);
+                    let sz = if upup || downdown { 0.25 } else { -0.25 };
+                    expected += amp.conj() * amp * sz;
+                    let flipped = match exchange_spin_flips(dec, s1, s2) {
+                        (true, false) => dec - s1 + s2,
+                        (false, true) => dec + s1 - s2,
+                        _ => continue
+                    };
+                    if let Some(&other) = amps.get(&flipped) {
+                        expected += other.conj() * amp * 0.5;
+                    }
+                }
+                assert!((mat[i * 12 + j] - expected.re).abs() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn entanglement_of_singlet_pairs() {
+        // singlets on the bonds (0, 1) and (2, 3) of a 2 x 2 cluster, which is
+        // symmetric under both translations
+        let (nx, ny, kx, ky, nup) = (Dim(2), Dim(2), K(0), K(0), 2);
+        let s = 0.5;
+        let product = [(0b0101, s), (0b0110, -s), (0b1001, -s), (0b1010, s)];
+
+        // project onto the reduced basis
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let psi = bfuncs.iter()
+                        .map(|b| {
+                            product.iter()
+                                   .filter_map(|&(dec, amp)| {
+                                       b.phase(BinaryBasis(dec), &bfuncs.phases)
+                                        .map(|c| (c / b.norm).conj() * amp)
+                                   })
+                                   .fold(Complex::new(0., 0.), |acc, x| acc + x)
+                        })
+                        .collect::<Vec<_>>();
+
+        let ln2 = 2_f64.ln();
+        for &(mask, expected) in
+            [(0b0001, ln2), (0b0011, 0.), (0b0101, 2. * ln2)].iter()
+        {
+            let entropy = entanglement_entropy(&bfuncs, &psi, mask).unwrap();
+            assert!((entropy - expected).abs() < 1e-12);
+        }
+        let spectrum = entanglement_spectrum(&bfuncs, &psi, 0b0101).unwrap();
+        assert_eq!(spectrum.len(), 4);
+        assert!(spectrum.iter().all(|&p| (p - 0.25).abs() < 1e-12));
+        let spectrum = entanglement_spectrum(&bfuncs, &psi, 0b0011).unwrap();
+        assert!((spectrum[0] - 1.).abs() < 1e-12);
+        assert!(spectrum[1..].iter().all(|&p| p.abs() < 1e-12));
+
+        assert!(entanglement_entropy(&bfuncs, &psi, 0b10000).is_err());
+        let bfuncs = consv::ks::bloch_states(Dim(4), Dim(3), kx, ky, 6).unwrap();
+        let psi = vec![Complex::new(1., 0.); bfuncs.nonzero as usize];
+        match entanglement_entropy(&bfuncs, &psi, 0b111_1111_1111) {
+            Err(Error::TooLarge(n)) => assert_eq!(n, 2048),
+            _ => panic!("expected the region size guard to trip")
+        }
+    }
+
+    // singlets along the rows of a 2 x 2 cluster, cut off at the first two
+    // bits: in row-major order those are the sites of a row, which holds a
+    // singlet, while in column-major order they are a column, which cuts both
+    #[test]
+    fn entanglement_cuts_follow_the_ordering() {
+        let (nx, ny, kx, ky, nup) = (Dim(2), Dim(2), K(0), K(0), 2);
+        let column_major = SitePermutation::new(&[0, 2, 1, 3]).unwrap();
+        let ln2 = 2_f64.ln();
+        let cuts = [(SiteOrdering::RowMajor, 0.),
+                    (SiteOrdering::Snake, 0.),
+                    (SiteOrdering::Custom(column_major), 2. * ln2)];
+        for &(ordering, expected) in cuts.iter() {
+            let settings = LatticeSettings { ordering,
+                                             ..LatticeSettings::default() };
+            let bit = |x, y| {
+                let site = SiteVector::new_in((I(x), I(y)), nx, ny, &settings);
+                1 << site.lattice_index().raw_int()
+            };
+            // (|ud> - |du>) / √2 on each row, the up spins in the words
+            let singlet = |y| [(bit(0, y), 1.), (bit(1, y), -1.)];
+            let mut product = Vec::new();
+            for &(a, sa) in singlet(0).iter() {
+                for &(b, sb) in singlet(1).iter() {
+                    product.push((a | b, 0.5 * sa * sb));
+                }
+            }
+            let plus = Convention::Plus;
+            let bfuncs = consv::ks::bloch_states_in(nx, ny, &settings, plus, kx, ky,
+                                                    nup).unwrap();
+            let amplitude = |b: &BlochFunc| {
+                product.iter()
+                       .filter_map(|&(dec, amp)| {
+                           b.phase(BinaryBasis(dec), &bfuncs.phases)
+                            .map(|c| (c / b.norm).conj() * amp)
+                       })
+                       .fold(Complex::new(0., 0.), |acc, x| acc + x)
+            };
+            let psi = bfuncs.iter().map(amplitude).collect::<Vec<_>>();
+            let entropy = entanglement_entropy(&bfuncs, &psi, 0b0011).unwrap();
+            assert!((entropy - expected).abs() < 1e-12, "{:?}", ordering);
+        }
+    }
+
+    #[test]
+    fn local_sz_test() {
+        let (nx, ny, nup) = (Dim(3), Dim(2), 2);
+        let mut decs = sz_basis(nx * ny, nup);
+        decs.sort();
+
+        // a single configuration has <Sz_i> = +-1/2
+        let mut psi = vec![Complex::new(0., 0.); decs.len()];
+        let k = decs.iter()
+                    .position(|&d| d == BinaryBasis(0b100001))
+                    .unwrap();
+        psi[k] = Complex::new(0., 2.);
+        let sz = s_local_sz(nx, ny, nup, &psi).unwrap();
+        assert_eq!(sz, vec![0.5, -0.5, -0.5, -0.5, -0.5, 0.5]);
+
+        // against diag(Sz_i) applied to a generic vector
+        let psi = (0..decs.len()).map(|i| Complex::new((i as f64).cos(), 0.3))
+                                 .collect::<Vec<_>>();
+        let sz = s_local_sz(nx, ny, nup, &psi).unwrap();
+        let norm = psi.iter().map(|c| c.norm_sqr()).sum::<f64>();
+        for (site, &s) in sz.iter().enumerate() {
+            let diag = decs.iter().map(|&d| {
+                                      if (d.raw_int() >> site) & 1 == 1 {
+                                          0.5
+                                      } else {
+                                          -0.5
+                                      }
+                                  });
+            let expected = diag.zip(psi.iter())
+                               .map(|(s, c)| s * c.norm_sqr())
+                               .sum::<f64>()
+                           / norm;
+            assert!((s - expected).abs() < 1e-14);
+        }
+        assert!(s_local_sz(nx, ny, nup, &psi[1..]).is_err());
+
+        // uniform in a momentum sector, and the same as for the expanded state
+        let (kx, ky) = (K(1), K(1));
+        let dim = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap().nonzero;
+        let dim = dim as usize;
+        let psi = (0..dim).map(|i| Complex::new(1., i as f64))
+                          .collect::<Vec<_>>();
+        let sz = ks_local_sz(nx, ny, kx, ky, nup, &psi).unwrap();
+        assert!(sz.iter().all(|s| (s - (2. / 6. - 0.5)).abs() < 1e-14));
+        let full = consv::ks::expand_state_sz(nx, ny, kx, ky, nup, &psi).unwrap();
+        let plain = s_local_sz(nx, ny, nup, &full).unwrap();
+        for (a, b) in sz.iter().zip(plain.iter()) {
+            assert!((a - b).abs() < 1e-14);
+        }
+    }
+
+    #[test]
+    fn state_diagnostics_match_expanded_state() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1))];
+        let op = OpHandle::ks(nx, ny, kx, ky, nup, &terms).unwrap();
+        let (_, psi) = ground_state(&op, 1e-12, 300, true).unwrap();
+        // not normalized on purpose
+        let psi = psi.unwrap().into_iter().map(|c| c * 3.).collect::<Vec<_>>();
+
+        // two configurations in the sector, one repeated, and one with the
+        // wrong number of up spins
+        let decs = [BinaryBasis(0b0011_0011_0011),
+                    BinaryBasis(0b0001_0111_0011),
+                    BinaryBasis(0b0001_0111_0011),
+                    BinaryBasis(0b1)];
+        let diag = ks_state_diagnostics(nx, ny, kx, ky, nup, &psi, &decs).unwrap();
+
+        let states = consv::ks::expand_state(nx, ny, kx, ky, nup, &psi).unwrap();
+        let norm = psi.iter().map(|c| c.norm_sqr()).sum::<f64>();
+        let ipr_reduced = psi.iter()
+                             .map(|c| (c.norm_sqr() / norm).powi(2))
+                             .sum::<f64>();
+        let ipr_product = states.iter()
+                                .map(|s| (s.1.norm_sqr() / norm).powi(2))
+                                .sum::<f64>();
+        let weight = states.iter()
+                           .filter(|s| decs[..2].contains(&s.0))
+                           .map(|s| s.1.norm_sqr() / norm)
+                           .sum::<f64>();
+        assert!((diag.ipr_reduced - ipr_reduced).abs() < 1e-14);
+        assert!((diag.ipr_product - ipr_product).abs() < 1e-14);
+        assert!((diag.weight - weight).abs() < 1e-14);
+        assert!(diag.weight > 0.);
+        assert!(diag.ipr_product < diag.ipr_reduced);
+    }
+}
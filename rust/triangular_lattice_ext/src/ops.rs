@@ -1,121 +1,203 @@
-use blochfunc::{BlochFunc, BlochFuncSet};
+use blochfunc::{BlochFunc, BlochFuncSet, OrbitTable};
 use common::*;
+use error::{Error, Result};
 use fnv::FnvHashMap;
 /// Operators generated by functions in this module assume translational
 /// symmetry and will work with systems regardless of whether total Sz is a good
 /// quantum number.
 use num_complex::Complex;
+use pool;
+use progress::{Phase, Progress};
+use rayon::{self, prelude::*};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp,
+    collections::hash_map::Entry,
+    ops::Range,
+    slice,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc}
+};
 
-pub fn ss_z_elements(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
-                     orig_state: &BlochFunc)
-                     -> f64 {
-    let (ref site1, ref site2) = *sites;
-    let mut same_dir = 0_i32;
-    for (&s1, &s2) in site1.iter().zip(site2.iter()) {
-        let (upup, downdown) = repeated_spins(orig_state.lead, s1, s2);
-        if upup {
-            same_dir += 1
-        };
-        if downdown {
-            same_dir += 1
-        };
-    }
-    let diff_dir = site1.len() as i32 - same_dir;
+/// Number of rows handed to a worker thread at a time
+pub const ROWS_PER_BLOCK: usize = 256;
+/// Number of blocks per thread generated before they are passed on
+pub const BLOCKS_PER_THREAD: usize = 4;
+/// Number of columns of a row up to which RowElements searches them linearly
+const LINEAR_SEARCH_MAX: usize = 64;
+
+/// Generate the diagonal element of Σ sz_i sz_j over the bonds packed in
+/// "masks"
+pub fn ss_z_elements(masks: &BondMasks, orig_state: &BlochFunc) -> f64 {
+    let diff_dir = masks.antiparallel(orig_state.lead) as i32;
+    let same_dir = masks.nbonds() as i32 - diff_dir;
     0.25 * (same_dir - diff_dir) as f64
 }
 
 /// Generate the xy-elements of an XXZ chain. Note: the matrix generated here
 /// corresponds to Σ(sx_i * sx_j + sy_i + sy_j), so if you are thinking in terms
-/// of s+ and s-, the 1/2 is already included in the output
+/// of s+ and s-, the 1/2 is already included in the output. Unless
+/// "bond_phases" is empty, s+_1 s-_2 on the n-th bond is multiplied by
+/// bond_phases[n] and s-_1 s+_2 by its conjugate. The elements are left in
+/// "j_element", which is cleared first.
 #[allow(non_snake_case)]
 #[allow(unused)]
 pub fn ss_xy_elements(nx: Dim, ny: Dim,
                       sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+                      bond_phases: &[Complex<f64>],
                       orig_state: &BlochFunc,
-                      dec_to_ind: &FnvHashMap<BinaryBasis, u32>,
-                      hashtable: &FnvHashMap<&BinaryBasis, &BlochFunc>)
-                      -> FnvHashMap<u32, Complex<f64>> {
+                      table: &OrbitTable,
+                      j_element: &mut RowElements) {
     let J = Complex::new(0.5, 0.);
-    let mut j_element = FnvHashMap::default();
+    j_element.clear();
     let (ref site1, ref site2) = *sites;
-    for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+    for (n, (&s1, &s2)) in site1.iter().zip(site2.iter()).enumerate() {
         let (updown, downup) = exchange_spin_flips(orig_state.lead, s1, s2);
         let mut new_dec: BinaryBasis;
+        let mut twist = Complex::new(1., 0.);
         match (updown, downup) {
-            (true, false) => new_dec = orig_state.lead - s1 + s2,
-            (false, true) => new_dec = orig_state.lead + s1 - s2,
+            (true, false) => {
+                new_dec = orig_state.lead - s1 + s2;
+                if let Some(p) = bond_phases.get(n) {
+                    twist = p.conj();
+                }
+            }
+            (false, true) => {
+                new_dec = orig_state.lead + s1 - s2;
+                if let Some(&p) = bond_phases.get(n) {
+                    twist = p;
+                }
+            }
             _ => continue
         }
-        match find_leading_state(new_dec, &hashtable) {
+        match table.find(new_dec) {
             None => (),
-            Some((cntd_state, phase)) => {
-                let j = *(dec_to_ind.get(&(cntd_state.lead)).unwrap());
-                let coeff = phase * coeff(&orig_state, &cntd_state);
-
-                let element = match j_element.get(&j) {
-                    Some(&c) => c + J * coeff,
-                    None => J * coeff
-                };
-                j_element.insert(j, element);
+            Some((j, cntd_state, phase)) => {
+                let coeff = twist * phase * coeff(orig_state, &cntd_state);
+                j_element.add(j, J * coeff);
             }
         }
     }
-    j_element
 }
 
+/// Generate the elements of Σ(γ_ij s+_i s+_j + γ*_ij s-_i s-_j), where
+/// bond_gammas[n] is the phase γ of the n-th bond. The elements are left in
+/// "j_element", which is cleared first.
 #[allow(non_snake_case)]
-pub fn ss_ppmm_elements(nx: Dim, ny: Dim,
-                        sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+pub fn ss_ppmm_elements(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+                        bond_gammas: &[Complex<f64>],
                         orig_state: &BlochFunc,
-                        dec_to_ind: &FnvHashMap<BinaryBasis, u32>,
-                        hashtable: &FnvHashMap<&BinaryBasis, &BlochFunc>)
-                        -> FnvHashMap<u32, Complex<f64>> {
+                        table: &OrbitTable,
+                        j_element: &mut RowElements) {
     let J = Complex::new(1., 0.);
-    let mut j_element = FnvHashMap::default();
+    j_element.clear();
     let (ref site1, ref site2) = *sites;
-    for (&s1, &s2) in site1.iter().zip(site2.iter()) {
+    for (n, (&s1, &s2)) in site1.iter().zip(site2.iter()).enumerate() {
         let (upup, downdown) = repeated_spins(orig_state.lead, s1, s2);
-        let mut new_dec: BinaryBasis;
+        let new_dec: BinaryBasis;
         let mut _gamma = Complex::new(0., 0.);
         match (upup, downdown) {
             (true, false) => {
                 new_dec = orig_state.lead - s1 - s2;
-                _gamma += gamma(nx, ny, s1, s2).conj();
+                _gamma += bond_gammas[n].conj();
             }
             (false, true) => {
                 new_dec = orig_state.lead + s1 + s2;
-                _gamma += gamma(nx, ny, s1, s2);
+                _gamma += bond_gammas[n];
             }
             _ => continue
         }
-        match find_leading_state(new_dec, &hashtable) {
+        match table.find(new_dec) {
             None => (),
-            Some((cntd_state, phase)) => {
-                let j = *(dec_to_ind.get(&(cntd_state.lead)).unwrap());
-                let coeff = phase * coeff(&orig_state, &cntd_state);
+            Some((j, cntd_state, phase)) => {
+                let coeff = phase * coeff(orig_state, &cntd_state);
+                j_element.add(j, J * coeff * _gamma);
+            }
+        }
+    }
+}
 
-                let element = match j_element.get(&j) {
-                    Some(&c) => c + J * coeff * _gamma,
-                    None => J * coeff * _gamma
-                };
-                j_element.insert(j, element);
+/// Generate the elements of ss_z_elements, of ss_xy_elements without a twist
+/// if "xy" is set and of ss_ppmm_elements if "ppmm" is set, all on the bonds
+/// "sites", in one pass over the bonds. The orientations of the spins of each
+/// bond are tested once for all three: antiparallel spins are flipped by the
+/// xy term and parallel ones by the ppmm term, and either kind counts towards
+/// the diagonal. Returns the diagonal element and leaves the others in
+/// "xy_element" and "ppmm_element", which are cleared first and left empty for
+/// a term that is not asked for.
+#[allow(non_snake_case)]
+pub fn ss_bond_elements(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+                        bond_gammas: &[Complex<f64>],
+                        xy: bool, ppmm: bool,
+                        orig_state: &BlochFunc,
+                        table: &OrbitTable,
+                        xy_element: &mut RowElements,
+                        ppmm_element: &mut RowElements)
+                        -> f64 {
+    let J_xy = Complex::new(0.5, 0.);
+    let J_ppmm = Complex::new(1., 0.);
+    let twist = Complex::new(1., 0.);
+    let mut same_dir = 0_i32;
+    xy_element.clear();
+    ppmm_element.clear();
+    let lead = orig_state.lead;
+    let (ref site1, ref site2) = *sites;
+    for (n, (&s1, &s2)) in site1.iter().zip(site2.iter()).enumerate() {
+        let up1 = lead | s1 == lead;
+        let up2 = lead | s2 == lead;
+        if up1 == up2 {
+            same_dir += 1;
+            if !ppmm {
+                continue;
+            }
+            let new_dec;
+            let mut _gamma = Complex::new(0., 0.);
+            if up1 {
+                new_dec = lead - s1 - s2;
+                _gamma += bond_gammas[n].conj();
+            } else {
+                new_dec = lead + s1 + s2;
+                _gamma += bond_gammas[n];
+            }
+            if let Some((j, cntd_state, phase)) = table.find(new_dec) {
+                let coeff = phase * coeff(orig_state, &cntd_state);
+                ppmm_element.add(j, J_ppmm * coeff * _gamma);
+            }
+        } else if xy {
+            let new_dec = if up1 {
+                lead - s1 + s2
+            } else {
+                lead + s1 - s2
+            };
+            if let Some((j, cntd_state, phase)) = table.find(new_dec) {
+                let coeff = twist * phase * coeff(orig_state, &cntd_state);
+                xy_element.add(j, J_xy * coeff);
             }
         }
     }
-    j_element
+    let diff_dir = site1.len() as i32 - same_dir;
+    0.25 * (same_dir - diff_dir) as f64
 }
 
+/// Generate the elements of the s+ sz / s- sz term, where bond_gammas[n] is the
+/// phase γ of the n-th bond. γ does not depend on the order of the sites of a
+/// bond, so both orders share it. The elements are left in "j_element", which
+/// is cleared first.
+///
+/// Both orders of every bond are generated here, so "sites" has to list each
+/// unordered pair of sites once (see is_canonical_bond_list): a pair listed in
+/// both orders would be counted twice. PreparedTerm checks the bonds of its
+/// terms for that once (see check_bond_sites).
 #[allow(non_snake_case)]
-pub fn ss_pmz_elements(nx: Dim, ny: Dim,
-                       sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+pub fn ss_pmz_elements(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+                       bond_gammas: &[Complex<f64>],
                        orig_state: &BlochFunc,
-                       dec_to_ind: &FnvHashMap<BinaryBasis, u32>,
-                       hashtable: &FnvHashMap<&BinaryBasis, &BlochFunc>)
-                       -> FnvHashMap<u32, Complex<f64>> {
+                       table: &OrbitTable,
+                       j_element: &mut RowElements) {
     let J = Complex::new(0., 1.); // the entire operator was multiplied by i
-    let mut j_element = FnvHashMap::default();
+    j_element.clear();
     let (ref site1, ref site2) = *sites;
-    for (&s_1, &s_2) in site1.iter().zip(site2.iter()) {
+    for (n, (&s_1, &s_2)) in site1.iter().zip(site2.iter()).enumerate() {
         for &(s1, s2) in [(s_1, s_2), (s_2, s_1)].iter() {
             let z_contrib = if orig_state.lead | s1 == orig_state.lead {
                 0.5
@@ -123,37 +205,32 @@ pub fn ss_pmz_elements(nx: Dim, ny: Dim,
                 -0.5
             };
 
-            let mut new_dec: BinaryBasis;
+            let new_dec: BinaryBasis;
             let mut _gamma = Complex::new(0., 0.);
             if orig_state.lead | s2 == orig_state.lead {
                 new_dec = orig_state.lead - s2;
-                _gamma += gamma(nx, ny, s1, s2).conj();
+                _gamma += bond_gammas[n].conj();
             } else {
                 new_dec = orig_state.lead + s2;
-                _gamma -= gamma(nx, ny, s1, s2);
+                _gamma -= bond_gammas[n];
             }
 
-            match find_leading_state(new_dec, &hashtable) {
+            match table.find(new_dec) {
                 None => (),
-                Some((cntd_state, phase)) => {
-                    let j = *(dec_to_ind.get(&(cntd_state.lead)).unwrap());
-                    let coeff = phase * coeff(&orig_state, &cntd_state);
-
-                    let element = match j_element.get(&j) {
-                        Some(&c) => c + J * z_contrib * coeff * _gamma,
-                        None => J * z_contrib * coeff * _gamma
-                    };
-                    j_element.insert(j, element);
+                Some((j, cntd_state, phase)) => {
+                    let coeff = phase * coeff(orig_state, &cntd_state);
+                    j_element.add(j, J * z_contrib * coeff * _gamma);
                 }
             }
         }
     }
-    j_element
 }
 
 /// Generate the elements of the chiral term (\vec{S_1} \times \vec{S_2}) \cdot
 /// \vec{S_3} which could be written as 1/2 i Σ_{ijk} S^z_i (S^+_j S^-_k - S^-_j
-/// S^+_k). The factor of 1/2 is already included in the output
+/// S^+_k). The factor of 1/2 is already included in the output, which is left
+/// in "j_element" after clearing it. The two flips of a pair of spins carry
+/// opposite signs, which keeps the term hermitian.
 #[allow(unused)]
 #[allow(non_snake_case)]
 pub fn sss_chi_elements(nx: Dim, ny: Dim,
@@ -161,11 +238,10 @@ pub fn sss_chi_elements(nx: Dim, ny: Dim,
                          Vec<BinaryBasis>,
                          Vec<BinaryBasis>),
                         orig_state: &BlochFunc,
-                        dec_to_ind: &FnvHashMap<BinaryBasis, u32>,
-                        hashtable: &FnvHashMap<&BinaryBasis, &BlochFunc>)
-                        -> FnvHashMap<u32, Complex<f64>> {
+                        table: &OrbitTable,
+                        j_element: &mut RowElements) {
     let J = Complex::new(0., 0.5);
-    let mut j_element = FnvHashMap::default();
+    j_element.clear();
     let (ref site1, ref site2, ref site3) = *sites;
 
     let zip3 = site1.iter()
@@ -185,108 +261,1525 @@ pub fn sss_chi_elements(nx: Dim, ny: Dim,
                 sk = s_tmp;
 
                 let (updown, downup) = exchange_spin_flips(orig_state.lead, sj, sk);
-                let mut new_dec: BinaryBasis;
-                match (updown, downup) {
-                    (true, false) => new_dec = orig_state.lead - sj + sk,
-                    (false, true) => new_dec = orig_state.lead + sj - sk,
+                // S^-_j S^+_k enters with a minus sign, S^+_j S^-_k with a plus
+                let (new_dec, sign) = match (updown, downup) {
+                    (true, false) => (orig_state.lead - sj + sk, -1.),
+                    (false, true) => (orig_state.lead + sj - sk, 1.),
                     _ => continue
-                }
-                match find_leading_state(new_dec, &hashtable) {
+                };
+                match table.find(new_dec) {
                     None => (),
-                    Some((cntd_state, phase)) => {
-                        let j = *(dec_to_ind.get(&(cntd_state.lead)).unwrap());
-                        let coeff = phase * coeff(&orig_state, &cntd_state);
+                    Some((j, cntd_state, phase)) => {
+                        let coeff = phase * coeff(orig_state, &cntd_state);
 
                         let z_contrib = if orig_state.lead | si == orig_state.lead {
                             0.5
                         } else {
                             -0.5
                         };
+                        j_element.add(j, J * sign * z_contrib * coeff);
+                    }
+                }
+            }
+        }
+    }
+}
 
-                        let element = match j_element.get(&j) {
-                            Some(&c) => c + J * z_contrib * coeff,
-                            None => J * z_contrib * coeff
-                        };
-                        j_element.insert(j, element);
+/// The elements of one row as the contributions of the bonds are added up, in
+/// the order their columns first appear. The columns of a row are searched
+/// linearly while there are only a few of them and through a hashmap of their
+/// positions once there are more. The builders keep one per thread and reuse it
+/// from row to row, so the buffers keep their capacity.
+#[derive(Default)]
+pub struct RowElements {
+    elements:  Vec<(u32, Complex<f64>)>,
+    // the position of each column in "elements", filled in once the row has
+    // LINEAR_SEARCH_MAX columns
+    positions: FnvHashMap<u32, usize>
+}
+
+impl RowElements {
+    pub fn new() -> RowElements { RowElements::default() }
+
+    /// Drop the elements of the previous row
+    pub fn clear(&mut self) {
+        self.elements.clear();
+        self.positions.clear();
+    }
+
+    /// Add "val" to the element in column "j"
+    pub fn add(&mut self, j: u32, val: Complex<f64>) {
+        let len = self.elements.len();
+        if len < LINEAR_SEARCH_MAX {
+            match self.elements.iter_mut().find(|e| e.0 == j) {
+                Some(e) => e.1 += val,
+                None => self.elements.push((j, val))
+            }
+            return;
+        }
+        if self.positions.is_empty() {
+            for (k, &(j, _)) in self.elements.iter().enumerate() {
+                self.positions.insert(j, k);
+            }
+        }
+        match self.positions.entry(j) {
+            Entry::Occupied(e) => self.elements[*e.get()].1 += val,
+            Entry::Vacant(e) => {
+                e.insert(len);
+                self.elements.push((j, val));
+            }
+        }
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, (u32, Complex<f64>)> {
+        self.elements.iter()
+    }
+
+    pub fn as_slice(&self) -> &[(u32, Complex<f64>)] { &self.elements }
+
+    /// Put the elements in order of their columns
+    pub fn sort_by_column(&mut self) {
+        self.elements.sort_unstable_by_key(|e| e.0);
+        // the positions would be stale
+        self.positions.clear();
+    }
+}
+
+// sums the rows of several terms; the row index is the caller's to keep track of
+impl ElementSink for RowElements {
+    fn push(&mut self, _row: u32, col: u32, val: Complex<f64>) {
+        self.add(col, val);
+    }
+}
+
+/// Receives matrix elements as they are generated. Rows are generated in
+/// increasing order, and within a row every column appears at most once (the
+/// contributions of different bonds are merged before they reach the sink) and
+/// the columns come in increasing order. Every builder keeps to this order,
+/// whatever the thread count; callers may rely on it.
+pub trait ElementSink {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>);
+}
+
+/// Collects the elements into the arrays of a coordinate matrix
+pub struct VecSink {
+    pub data: Vec<CComplex<f64>>,
+    pub cols: Vec<u32>,
+    pub rows: Vec<u32>
+}
+
+impl VecSink {
+    pub fn with_capacity(n: usize) -> VecSink {
+        VecSink { data: Vec::with_capacity(n),
+                  cols: Vec::with_capacity(n),
+                  rows: Vec::with_capacity(n) }
+    }
+
+    /// The arrays as a matrix, with their spare capacity released
+    pub fn into_coord_matrix(mut self, dims: u32)
+                             -> OwnedCoordMatrix<CComplex<f64>> {
+        self.data.shrink_to_fit();
+        self.cols.shrink_to_fit();
+        self.rows.shrink_to_fit();
+        OwnedCoordMatrix::new(self.data, self.cols, self.rows, dims, dims)
+    }
+}
+
+impl ElementSink for VecSink {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        self.rows.push(row);
+        self.cols.push(col);
+        self.data.push(CComplex::from_num_complex(val));
+    }
+}
+
+/// Collects the real parts of the elements into the arrays of a real coordinate
+/// matrix, for operators whose elements are real (see term_real)
+pub struct RealVecSink {
+    pub data: Vec<f64>,
+    pub cols: Vec<u32>,
+    pub rows: Vec<u32>
+}
+
+impl RealVecSink {
+    pub fn with_capacity(n: usize) -> RealVecSink {
+        RealVecSink { data: Vec::with_capacity(n),
+                      cols: Vec::with_capacity(n),
+                      rows: Vec::with_capacity(n) }
+    }
+
+    /// The arrays as a matrix, with their spare capacity released
+    pub fn into_coord_matrix(mut self, dims: u32) -> OwnedCoordMatrix<f64> {
+        self.data.shrink_to_fit();
+        self.cols.shrink_to_fit();
+        self.rows.shrink_to_fit();
+        OwnedCoordMatrix::new(self.data, self.cols, self.rows, dims, dims)
+    }
+}
+
+impl ElementSink for RealVecSink {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        debug_assert!(val.im == 0.);
+        self.rows.push(row);
+        self.cols.push(col);
+        self.data.push(val.re);
+    }
+}
+
+/// Accumulates the elements into a dense column-major matrix
+pub struct DenseSink {
+    pub data: Vec<CComplex<f64>>,
+    pub n:    usize
+}
+
+impl DenseSink {
+    pub fn new(n: usize) -> DenseSink {
+        let data = (0..n * n).map(|_| CComplex { re: 0., im: 0. })
+                             .collect();
+        DenseSink { data, n }
+    }
+
+    pub fn into_dense_matrix(self) -> DenseMatrix<CComplex<f64>> {
+        DenseMatrix::new(self.data, self.n as u32)
+    }
+}
+
+impl ElementSink for DenseSink {
+    // elements are generated as (data, (col, row)) for the coordinate matrices,
+    // i.e. the element generated in row i and column j belongs to row j and
+    // column i of the matrix
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        let elem = &mut self.data[col as usize + row as usize * self.n];
+        elem.re += val.re;
+        elem.im += val.im;
+    }
+}
+
+// Buffers the elements of a block of rows generated on a worker thread
+struct BlockSink {
+    elements: Vec<(u32, u32, Complex<f64>)>
+}
+
+impl ElementSink for BlockSink {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        self.elements.push((row, col, val));
+    }
+}
+
+/// Counts the elements without storing them. Since the element functions merge
+/// all contributions to the same column of a row before handing them over, the
+/// count equals the number of stored elements of the coordinate matrix.
+#[derive(Default)]
+pub struct CountSink {
+    pub count: u64
+}
+
+impl ElementSink for CountSink {
+    fn push(&mut self, _row: u32, _col: u32, _val: Complex<f64>) { self.count += 1; }
+}
+
+/// Writes the elements into arrays provided by the caller, storing as many as
+/// fit and counting the rest
+pub struct SliceSink<'a> {
+    data:  &'a mut [CComplex<f64>],
+    cols:  &'a mut [u32],
+    rows:  &'a mut [u32],
+    count: usize
+}
+
+impl<'a> SliceSink<'a> {
+    pub fn new(data: &'a mut [CComplex<f64>], cols: &'a mut [u32],
+               rows: &'a mut [u32])
+               -> SliceSink<'a> {
+        SliceSink { data,
+                    cols,
+                    rows,
+                    count: 0 }
+    }
+
+    fn cap(&self) -> usize {
+        cmp::min(self.data.len(), cmp::min(self.cols.len(), self.rows.len()))
+    }
+
+    /// The number of elements written. Fails if they did not all fit, in which
+    /// case the arrays hold only the first of them.
+    pub fn finish(self) -> Result<usize> {
+        if self.count > self.cap() {
+            return Err(Error::InvalidArgument("cap"));
+        }
+        Ok(self.count)
+    }
+}
+
+impl<'a> ElementSink for SliceSink<'a> {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        let k = self.count;
+        if k < self.cap() {
+            self.rows[k] = row;
+            self.cols[k] = col;
+            self.data[k] = CComplex::from_num_complex(val);
+        }
+        self.count += 1;
+    }
+}
+
+static DENSE_MAX_DIM: AtomicUsize = AtomicUsize::new(20000);
+
+/// The largest dimension for which dense matrices are built
+pub fn dense_max_dim() -> u32 { DENSE_MAX_DIM.load(Ordering::Relaxed) as u32 }
+
+pub fn set_dense_max_dim(n: u32) {
+    DENSE_MAX_DIM.store(n as usize, Ordering::Relaxed)
+}
+
+/// A term together with the sites it acts on, so that the matrix elements can be
+/// generated one row at a time
+pub struct PreparedTerm {
+    pub term:    Term,
+    nx:          Dim,
+    ny:          Dim,
+    // the bonds and triangles of the lattice and their phases, shared by all
+    // terms on it
+    tables:      Arc<LatticeTables>,
+    // the pairs of sites of the correlation functions, or the bonds given to
+    // with_bonds, None for the terms that act on the bonds or triangles of the
+    // lattice
+    pairs:       Option<(Vec<BinaryBasis>, Vec<BinaryBasis>)>,
+    // the phases of the bonds given to with_bonds, None for the bonds of the
+    // lattice
+    pair_gammas: Option<Vec<Complex<f64>>>,
+    // the number of times each of those pairs counts, see SitePairs
+    pair_weight: f64,
+    // the same pairs packed for the diagonal correlation function
+    pair_masks:  Option<BondMasks>,
+    // the phases of the bonds under a boundary twist, empty without one
+    bond_phases: Vec<Complex<f64>>
+}
+
+impl PreparedTerm {
+    /// Prepare "term" on the lattice of "tables", acting on its bonds of range
+    /// l, which are checked when the tables are built (see LatticeTables::new)
+    pub fn new(term: Term, tables: &Arc<LatticeTables>) -> PreparedTerm {
+        let (nx, ny) = (tables.nx(), tables.ny());
+        let (pairs, pair_weight) = match term.kind {
+            TermKind::SsZ | TermKind::SsXy => {
+                let pairs = all_sites_in(nx, ny, term.l, &tables.settings());
+                (Some(pairs.sites), f64::from(pairs.multiplicity))
+            }
+            _ => (None, 1.)
+        };
+        PreparedTerm::on_pairs(term, tables, pairs, pair_weight)
+    }
+
+    /// Prepare "term" on the lattice of "tables" acting on "bonds" rather than
+    /// on the bonds of range l of the lattice. Fails with InvalidArgument for
+    /// the chiral term and the correlation functions, which do not act on
+    /// bonds, and with Inconsistent unless the bonds are all pairs of
+    /// different sites, each listed once (see check_bond_sites), as the
+    /// element functions assume.
+    pub fn with_bonds(term: Term, tables: &Arc<LatticeTables>, bonds: BondSites)
+                      -> Result<PreparedTerm> {
+        match term.kind {
+            TermKind::HSssChi | TermKind::SsZ | TermKind::SsXy => {
+                return Err(Error::InvalidArgument("term without bonds"));
+            }
+            _ => ()
+        }
+        check_bond_sites(&bonds)?;
+        let sites = site_vectors(tables.nx(), tables.ny(), &tables.settings());
+        let gammas = bonds.0
+                          .iter()
+                          .zip(bonds.1.iter())
+                          .map(|(&s1, &s2)| {
+                                   gamma_within(&sites, s1, s2, tables.geometry(),
+                                                tables.periodicity())
+                               })
+                          .collect::<Result<Vec<_>>>()?;
+        let mut prepared = PreparedTerm::on_pairs(term, tables, Some(bonds), 1.);
+        prepared.pair_gammas = Some(gammas);
+        Ok(prepared)
+    }
+
+    fn on_pairs(term: Term, tables: &Arc<LatticeTables>, pairs: Option<BondSites>,
+                pair_weight: f64)
+                -> PreparedTerm {
+        let pair_masks = match term.kind {
+            TermKind::SsZ | TermKind::HSsZ => pairs.as_ref().map(BondMasks::new),
+            _ => None
+        };
+        PreparedTerm { term,
+                       nx: tables.nx(),
+                       ny: tables.ny(),
+                       tables: tables.clone(),
+                       pairs,
+                       pair_weight,
+                       pair_gammas: None,
+                       pair_masks,
+                       bond_phases: Vec::new() }
+    }
+
+    // the pairs of sites the term acts on, unless it is the chiral term
+    fn pairs(&self) -> &(Vec<BinaryBasis>, Vec<BinaryBasis>) {
+        match self.pairs {
+            Some(ref pairs) => pairs,
+            None => self.tables.bonds(self.term.l)
+        }
+    }
+
+    // the phases of the pairs the term acts on
+    fn gammas(&self) -> &[Complex<f64>] {
+        match self.pair_gammas {
+            Some(ref gammas) => gammas,
+            None => self.tables.gammas(self.term.l)
+        }
+    }
+
+    // the pairs of the diagonal terms packed into masks
+    fn masks(&self) -> &BondMasks {
+        match self.pair_masks {
+            Some(ref masks) => masks,
+            None => self.tables.masks(self.term.l)
+        }
+    }
+
+    /// The term with the spins twisted about the z axis by "theta" across the
+    /// boundary in x. The twist is spread evenly over the lattice, which keeps
+    /// the translational symmetry: s+_i s-_j picks up e^(i theta dx / nx), where
+    /// dx is the distance from i to j along x in the nearest periodic image,
+    /// as the lattice tables track it for each bond (see
+    /// LatticeTables::displacements), taken the shorter way around the lattice
+    /// where two images are equally near. Only terms symmetric under rotations
+    /// about z can be twisted, and none of their bonds may span exactly half of
+    /// the lattice along x.
+    pub fn twisted(term: Term, tables: &Arc<LatticeTables>, theta: f64)
+                   -> Result<PreparedTerm> {
+        let (nx, ny) = (tables.nx(), tables.ny());
+        let mut prepared = PreparedTerm::new(term, tables);
+        if theta == 0. {
+            return Ok(prepared);
+        }
+        match term.kind {
+            TermKind::HSsZ => (),
+            TermKind::HSsXy => {
+                let displacements = match prepared.pairs {
+                    Some(ref pairs) => {
+                        let sites = site_vectors(nx, ny, &tables.settings());
+                        bond_displacements_in(&sites,
+                                              pairs,
+                                              tables.geometry(),
+                                              tables.periodicity())?
                     }
+                    None => prepared.tables.displacements(term.l).to_vec()
+                };
+                let nx = nx.raw_int() as i32;
+                let mut bond_phases = Vec::new();
+                for d in displacements {
+                    let mut dx = d.dx.rem_euclid(nx);
+                    if 2 * dx == nx {
+                        return Err(Error::InvalidArgument("theta"));
+                    } else if 2 * dx > nx {
+                        dx -= nx;
+                    }
+                    let ang = theta * f64::from(dx) / f64::from(nx);
+                    bond_phases.push(Complex::from_polar(&1., &ang));
                 }
+                prepared.bond_phases = bond_phases;
             }
+            _ => return Err(Error::InvalidTerm(term.kind as u32))
+        }
+        Ok(prepared)
+    }
+
+    /// An upper bound on the number of elements in the row of "orig_state": the
+    /// number of configurations the term connects it to. The bound is exact
+    /// unless several of them share an orbit or have no place in the basis.
+    pub fn row_bound(&self, orig_state: &BlochFunc) -> usize {
+        let lead = orig_state.lead;
+        let flips = |s1, s2| {
+            let (updown, downup) = exchange_spin_flips(lead, s1, s2);
+            updown || downup
+        };
+        match self.term.kind {
+            TermKind::HSsZ | TermKind::SsZ => 1,
+            TermKind::HSsXy | TermKind::SsXy => {
+                let (ref site1, ref site2) = *self.pairs();
+                site1.iter()
+                     .zip(site2.iter())
+                     .filter(|&(&s1, &s2)| flips(s1, s2))
+                     .count()
+            }
+            TermKind::HSsPpmm => {
+                let (ref site1, ref site2) = *self.pairs();
+                site1.iter()
+                     .zip(site2.iter())
+                     .filter(|&(&s1, &s2)| {
+                         let (upup, downdown) = repeated_spins(lead, s1, s2);
+                         upup || downdown
+                     })
+                     .count()
+            }
+            TermKind::HSsPmz => 2 * self.pairs().0.len(),
+            TermKind::HSssChi => {
+                let edges = self.tables.edges();
+                edges.iter().filter(|&&(s1, s2)| flips(s1, s2)).count()
+            }
+        }
+    }
+
+    /// Whether the term only has diagonal elements, in which case the lookup
+    /// tables passed to row_into are not consulted
+    pub fn is_diagonal(&self) -> bool {
+        matches!(self.term.kind, TermKind::HSsZ | TermKind::SsZ)
+    }
+
+    /// Generate row i of the term, scaled by the coefficient of the term, into
+    /// "sink" in increasing order of the columns. "orig_state" is the basis
+    /// state with index i of the basis "table" is built from. "elements" is
+    /// scratch space for the row, passed in so that it can be reused by the
+    /// caller.
+    pub fn row_into<S: ElementSink + ?Sized>(&self, i: u32, orig_state: &BlochFunc,
+                                             table: &OrbitTable,
+                                             elements: &mut RowElements,
+                                             sink: &mut S) {
+        let (nx, ny) = (self.nx, self.ny);
+        let coeff = self.term.coeff * self.pair_weight;
+        match self.term.kind {
+            TermKind::HSsZ | TermKind::SsZ => {
+                let element = ss_z_elements(self.masks(), orig_state);
+                sink.push(i, i, Complex::new(coeff * element, 0.));
+                return;
+            }
+            TermKind::HSsXy | TermKind::SsXy => {
+                ss_xy_elements(nx, ny, self.pairs(), &self.bond_phases, orig_state,
+                               table, elements)
+            }
+            TermKind::HSsPpmm => {
+                ss_ppmm_elements(self.pairs(), self.gammas(),
+                                 orig_state, table, elements)
+            }
+            TermKind::HSsPmz => {
+                ss_pmz_elements(self.pairs(), self.gammas(),
+                                orig_state, table, elements)
+            }
+            TermKind::HSssChi => {
+                sss_chi_elements(nx, ny, self.tables.triangles(), orig_state, table,
+                                 elements)
+            }
+        }
+        elements.sort_by_column();
+        for &(j, entry) in elements.iter() {
+            sink.push(i, j, entry * coeff);
         }
     }
-    j_element
 }
 
-pub fn ss_z(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>), bfuncs: &BlochFuncSet)
-            -> CoordMatrix<CComplex<f64>> {
-    let dims = bfuncs.nonzero;
-    let (ind_to_dec, _) = gen_ind_dec_conv_dicts(&bfuncs);
-
-    let mut data: Vec<CComplex<f64>> = Vec::with_capacity(dims as usize);
-    let cols = (0..dims as u32).collect::<Vec<u32>>();
-    let rows = (0..dims as u32).collect::<Vec<u32>>();
-    for i in 0..dims as u32 {
-        let orig_state = ind_to_dec.get(&i).unwrap();
-        let i_element = ss_z_elements(&sites, &orig_state);
-        let re = i_element;
-        let im = 0.;
-        data.push(CComplex { re, im });
-    }
-    CoordMatrix::new(data, cols, rows, dims, dims)
-}
-
-fn off_diag_ops<T>(element_f: fn(nx: Dim,
-                    ny: Dim,
-                    sites: &T,
-                    orig_state: &BlochFunc,
-                    dec_to_ind: &FnvHashMap<BinaryBasis, u32>,
-                    hashtable: &FnvHashMap<&BinaryBasis,
-                                &BlochFunc>)
-                    -> FnvHashMap<u32, Complex<f64>>,
-                   sites: &T, bfuncs: &BlochFuncSet)
-                   -> CoordMatrix<CComplex<f64>> {
+/// What a term sees of the basis its rows are generated on: the Bloch
+/// functions, the lookup of the Bloch function a configuration belongs to and
+/// the tables of the lattice. The builders hand one to OperatorTerm::elements
+/// with every row.
+pub struct Basis<'a> {
+    bfuncs: &'a BlochFuncSet,
+    table:  &'a OrbitTable<'a>,
+    tables: Arc<LatticeTables>
+}
+
+impl<'a> Basis<'a> {
+    /// The context of "bfuncs", looked up in "table", which is built from it
+    /// unless all terms are diagonal
+    pub fn new(bfuncs: &'a BlochFuncSet, table: &'a OrbitTable<'a>) -> Basis<'a> {
+        Basis { bfuncs,
+                table,
+                tables: bfuncs.tables.clone() }
+    }
+
+    pub fn nx(&self) -> Dim { self.bfuncs.nx }
+
+    pub fn ny(&self) -> Dim { self.bfuncs.ny }
+
+    /// The momentum of the basis
+    pub fn k(&self) -> (K, K) { (self.bfuncs.kx, self.bfuncs.ky) }
+
+    /// The number of Bloch functions, the dimension of the matrices
+    pub fn dim(&self) -> u32 { self.bfuncs.nonzero }
+
+    /// The Bloch function with index "i"
+    pub fn state(&self, i: u32) -> &BlochFunc { &self.bfuncs.data[i as usize] }
+
+    /// The index of the Bloch function whose orbit holds the configuration
+    /// "dec", the Bloch function and the phase that takes the configuration
+    /// back to its leading state (see common::find_leading_state). None if the
+    /// orbit is not in the basis, as it is not for a term that changes total
+    /// Sz in a basis that fixes it, or if the term is diagonal.
+    pub fn find(&self, dec: BinaryBasis)
+                -> Option<(u32, Cow<'_, BlochFunc>, Complex<f64>)> {
+        self.table.find(dec)
+    }
+
+    /// The factor the element from "orig_state" to "cntd_state" picks up from
+    /// the norms of the Bloch functions, see common::coeff
+    pub fn coeff(&self, orig_state: &BlochFunc, cntd_state: &BlochFunc) -> f64 {
+        coeff(orig_state, cntd_state)
+    }
+
+    /// The bonds, their phases γ and the triangles of the lattice
+    pub fn lattice(&self) -> &LatticeTables { &self.tables }
+}
+
+/// A term of an operator that generates its own matrix elements one row at a
+/// time, to be summed with others by operators_rows_into_with_progress. The
+/// terms of the crate implement it through PreparedTerm; a term of another
+/// crate implements it with what Basis offers. For a term that moves spins,
+/// an element from the Bloch function "state" to the configuration "dec" is
+///
+/// ```text
+/// <dec|O|state.lead> * phase * basis.coeff(state, cntd)
+/// ```
+///
+/// in column j, where (j, cntd, phase) = basis.find(dec).
+pub trait OperatorTerm: Sync {
+    /// Generate the elements of row "i", the row of "state", into "sink",
+    /// scaled by the coefficient of the term. The elements may come in any
+    /// order and a column more than once: the builders add up the elements of
+    /// a row in the same column and sort the row by column before passing it
+    /// on.
+    fn elements(&self, i: u32, state: &BlochFunc, basis: &Basis,
+                sink: &mut dyn ElementSink);
+
+    /// An upper bound on the number of elements in the row of "state", for
+    /// reserving space; a bad bound costs memory or time but nothing else
+    fn row_bound(&self, _state: &BlochFunc) -> usize { 1 }
+
+    /// Whether the term only has diagonal elements. If all terms of a build
+    /// are diagonal, the lookup of Basis::find is not built and finds nothing.
+    fn is_diagonal(&self) -> bool { false }
+}
+
+thread_local! {
+    // the scratch space of PreparedTerm::row_into for the rows generated on
+    // this thread through OperatorTerm
+    static ROW_SCRATCH: RefCell<RowElements> = RefCell::new(RowElements::new());
+}
+
+impl OperatorTerm for PreparedTerm {
+    fn elements(&self, i: u32, state: &BlochFunc, basis: &Basis,
+                sink: &mut dyn ElementSink) {
+        ROW_SCRATCH.with(|elements| {
+                           let elements = &mut *elements.borrow_mut();
+                           self.row_into(i, state, basis.table, elements, sink)
+                       })
+    }
+
+    fn row_bound(&self, state: &BlochFunc) -> usize {
+        PreparedTerm::row_bound(self, state)
+    }
+
+    fn is_diagonal(&self) -> bool { PreparedTerm::is_diagonal(self) }
+}
+
+/// Generate the rows in "rows" of the operator described by "term" on the given
+/// basis, scaled by the coefficient of the term, into "sink". Fails if "rows"
+/// extends past the end of the basis.
+pub fn term_rows_into<S: ElementSink>(term: &Term, bfuncs: &BlochFuncSet,
+                                      rows: Range<u32>, sink: &mut S)
+                                      -> Result<()> {
+    term_rows_into_with_progress(term, bfuncs, rows, sink, &mut Progress::none())
+}
+
+/// Same as term_rows_into, reporting the fraction of rows done to "progress".
+/// Fails if the build is cancelled, in which case only part of the rows have
+/// been passed to "sink", or if a strict "progress" (see Progress::strict)
+/// finds a Bloch function missing from the basis.
+///
+/// Blocks of rows are generated in parallel on the pool configured in the pool
+/// module, reading the basis and its lookup tables only, and passed on to
+/// "sink" on the calling thread in row order. The output therefore does not
+/// depend on the thread count, and neither "sink" nor "progress" is touched by
+/// any other thread.
+pub fn term_rows_into_with_progress<S: ElementSink>(term: &Term,
+                                                    bfuncs: &BlochFuncSet,
+                                                    rows: Range<u32>, sink: &mut S,
+                                                    progress: &mut Progress)
+                                                    -> Result<()> {
+    if rows.end > bfuncs.nonzero {
+        return Err(Error::InvalidArgument("rows"));
+    }
+    let prepared = PreparedTerm::new(*term, &bfuncs.tables);
+    let table = if prepared.is_diagonal() {
+        OrbitTable::empty()
+    } else {
+        OrbitTable::new(bfuncs).strict(progress.is_strict())
+                               .min_phase_norm(progress.min_phase_norm())
+    };
+    let block = |rows: Range<u32>| {
+        let states = &bfuncs.data[rows.start as usize..rows.end as usize];
+        let bound = states.iter().map(|b| prepared.row_bound(b)).sum();
+        let mut block = BlockSink { elements: Vec::with_capacity(bound) };
+        let mut elements = RowElements::new();
+        for (i, orig_state) in rows.zip(states.iter()) {
+            prepared.row_into(i, orig_state, &table, &mut elements, &mut block);
+        }
+        block.elements
+    };
+
+    let total = rows.len() as u64;
+    let first = rows.start;
+    let emit = |elements: Vec<(u32, u32, Complex<f64>)>, rows: Range<u32>,
+                progress: &mut Progress| {
+        table.check()?;
+        let mut elements = elements.into_iter().peekable();
+        for row in rows {
+            progress.step(Phase::Elements, (row - first) as u64, total)?;
+            while let Some(&(i, j, val)) = elements.peek() {
+                if i != row {
+                    break;
+                }
+                sink.push(i, j, val);
+                elements.next();
+            }
+        }
+        Ok(())
+    };
+    blocks_in_order(rows, progress, block, emit)?;
+    progress.step(Phase::Elements, total, total)
+}
+
+/// Generate the sum of "terms" on the given basis, each scaled by its
+/// coefficient, into "sink" one row at a time: the contributions of all terms to
+/// a row are merged before the row is passed on, so every (row, col) pair
+/// reaches "sink" once. The columns of each row come in increasing order,
+/// which reproduces the order of sorting the elements of all terms by (row,
+/// col) and summing the ones at the same position, with the sums taken in the
+/// order of "terms". Fails if "rows" extends past the end of
+/// the basis, the build is cancelled or a strict "progress" finds a Bloch
+/// function missing from the basis.
+///
+/// Nothing but the rows in flight is held besides the basis, so the peak
+/// memory is that of blocks_in_order regardless of the size of the operator.
+pub fn terms_rows_into_with_progress<S: ElementSink>(terms: &[Term],
+                                                     bfuncs: &BlochFuncSet,
+                                                     rows: Range<u32>,
+                                                     sink: &mut S,
+                                                     progress: &mut Progress)
+                                                     -> Result<()> {
+    if rows.end > bfuncs.nonzero {
+        return Err(Error::InvalidArgument("rows"));
+    }
+    let prepared = terms.iter()
+                        .map(|&t| PreparedTerm::new(t, &bfuncs.tables))
+                        .collect::<Vec<_>>();
+    let operators = prepared.iter()
+                            .map(|p| p as &dyn OperatorTerm)
+                            .collect::<Vec<_>>();
+    operators_rows_into_with_progress(&operators, bfuncs, rows, sink, progress)
+}
+
+/// Same as terms_rows_into_with_progress for terms given as OperatorTerm,
+/// which need not be terms of the crate. The rows of all terms are merged and
+/// emitted as described there, in the order of "operators".
+pub fn operators_rows_into_with_progress<S>(operators: &[&dyn OperatorTerm],
+                                            bfuncs: &BlochFuncSet, rows: Range<u32>,
+                                            sink: &mut S, progress: &mut Progress)
+                                            -> Result<()>
+    where S: ElementSink
+{
+    if rows.end > bfuncs.nonzero {
+        return Err(Error::InvalidArgument("rows"));
+    }
+    let table = if operators.iter().all(|o| o.is_diagonal()) {
+        OrbitTable::empty()
+    } else {
+        OrbitTable::new(bfuncs).strict(progress.is_strict())
+                               .min_phase_norm(progress.min_phase_norm())
+    };
+    let basis = Basis::new(bfuncs, &table);
+    let block = |rows: Range<u32>| {
+        let states = &bfuncs.data[rows.start as usize..rows.end as usize];
+        let bound = states.iter()
+                          .map(|b| {
+                                   operators.iter()
+                                            .map(|o| o.row_bound(b))
+                                            .sum::<usize>()
+                               })
+                          .sum();
+        let mut block = BlockSink { elements: Vec::with_capacity(bound) };
+        let mut sum = RowElements::new();
+        for (i, orig_state) in rows.zip(states.iter()) {
+            sum.clear();
+            for o in operators.iter() {
+                o.elements(i, orig_state, &basis, &mut sum);
+            }
+            sum.sort_by_column();
+            for &(j, val) in sum.iter() {
+                block.push(i, j, val);
+            }
+        }
+        block.elements
+    };
+
+    let total = rows.len() as u64;
+    let first = rows.start;
+    let emit = |elements: Vec<(u32, u32, Complex<f64>)>, rows: Range<u32>,
+                progress: &mut Progress| {
+        table.check()?;
+        let mut elements = elements.into_iter().peekable();
+        for row in rows {
+            progress.step(Phase::Elements, (row - first) as u64, total)?;
+            while let Some(&(i, j, val)) = elements.peek() {
+                if i != row {
+                    break;
+                }
+                sink.push(i, j, val);
+                elements.next();
+            }
+        }
+        Ok(())
+    };
+    blocks_in_order(rows, progress, block, emit)?;
+    progress.step(Phase::Elements, total, total)
+}
+
+/// Generate the sum of "terms" on the given basis into "sink" as described for
+/// terms_rows_into_with_progress
+pub fn terms_into<S: ElementSink>(terms: &[Term], bfuncs: &BlochFuncSet,
+                                  sink: &mut S) {
+    // the rows cover the basis exactly and Progress::none() is never cancelled
+    terms_rows_into_with_progress(terms, bfuncs, 0..bfuncs.nonzero, sink,
+                                  &mut Progress::none()).unwrap()
+}
+
+/// Generate the blocks of ROWS_PER_BLOCK rows that "rows" splits into with
+/// "block" in parallel on the pool configured in the pool module, and hand
+/// them to "emit" together with their rows on the calling thread in row
+/// order. Only a few blocks per thread are held in memory at any time. Fails
+/// as soon as "emit" does or the build is cancelled.
+///
+/// Peak memory: a builder that passes the elements on as they are emitted
+/// (to a callback, a counter or a file) holds, besides the basis B as given by
+/// BlochFuncSet::heap_bytes,
+///
+/// ```text
+/// T + 24 * BLOCKS_PER_THREAD * threads * ROWS_PER_BLOCK * r
+/// ```
+///
+/// bytes, where T is the orbit table (one hashmap entry of 12 bytes plus
+/// overhead, 20 to 40 bytes in all, per configuration of the sector under
+/// Lookup::Members and per Bloch function under Lookup::Leads, nothing for
+/// diagonal operators) and r the number of elements of a row as bounded by
+/// PreparedTerm::row_bound, 24 bytes being the size of a buffered element. A
+/// builder that collects the elements adds the 24 * nnz bytes of the result.
+fn blocks_in_order<B, F, E>(rows: Range<u32>, progress: &mut Progress, block: F,
+                            mut emit: E)
+                            -> Result<()>
+    where B: Send,
+          F: Fn(Range<u32>) -> B + Sync,
+          E: FnMut(B, Range<u32>, &mut Progress) -> Result<()>
+{
+    let block_rows = |n: usize| {
+        let start = rows.start + (n * ROWS_PER_BLOCK) as u32;
+        start..cmp::min(start + ROWS_PER_BLOCK as u32, rows.end)
+    };
+    let nblocks = (rows.len() + ROWS_PER_BLOCK - 1) / ROWS_PER_BLOCK;
+    let batch_size = BLOCKS_PER_THREAD * pool::install(rayon::current_num_threads);
+    let mut start = 0;
+    while start < nblocks {
+        progress.check()?;
+        let end = cmp::min(start + batch_size, nblocks);
+        let batch = pool::install(|| {
+                        (start..end).into_par_iter()
+                                    .map(|n| block(block_rows(n)))
+                                    .collect::<Vec<_>>()
+                    });
+        for (n, b) in (start..end).zip(batch) {
+            emit(b, block_rows(n), progress)?;
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Generate the operator described by "term" on the given basis, scaled by the
+/// coefficient of the term, into "sink"
+pub fn term_into<S: ElementSink>(term: &Term, bfuncs: &BlochFuncSet, sink: &mut S) {
+    // the rows cover the basis exactly and Progress::none() is never cancelled
+    term_rows_into(term, bfuncs, 0..bfuncs.nonzero, sink).unwrap()
+}
+
+/// Collect the elements of the operator described by "term" on the given basis,
+/// scaled by the coefficient of the term
+pub fn term_vecs(term: &Term, bfuncs: &BlochFuncSet) -> VecSink {
+    // Progress::none() is never cancelled
+    term_vecs_with_progress(term, bfuncs, &mut Progress::none()).unwrap()
+}
+
+/// Same as term_vecs, reporting the fraction of rows done to "progress". Fails
+/// if the build is cancelled.
+pub fn term_vecs_with_progress(term: &Term, bfuncs: &BlochFuncSet,
+                               progress: &mut Progress)
+                               -> Result<VecSink> {
     let dims = bfuncs.nonzero;
-    let hashtable = BlochFuncSet::build_dict(&bfuncs);
-    let (ind_to_dec, dec_to_ind) = gen_ind_dec_conv_dicts(&bfuncs);
+    let mut sink = VecSink::with_capacity(nnz_bound(term, bfuncs, 0..dims));
+    term_rows_into_with_progress(term, bfuncs, 0..dims, &mut sink, progress)?;
+    Ok(sink)
+}
+
+// The terms of a batch generated together by ss_bond_elements, by their
+// position in the batch: HSsZ, HSsXy without a twist and HSsPpmm on the bonds
+// of the same range
+struct BondTerms {
+    l:    I,
+    z:    Option<usize>,
+    xy:   Option<usize>,
+    ppmm: Option<usize>
+}
 
-    let alloc_size = dims * (1 + 8 * (bfuncs.nx * bfuncs.ny).raw_int());
-    let mut data: Vec<CComplex<f64>> = Vec::with_capacity(alloc_size as usize);
-    let mut cols: Vec<u32> = Vec::with_capacity(alloc_size as usize);
-    let mut rows: Vec<u32> = Vec::with_capacity(alloc_size as usize);
-    for i in 0..dims as u32 {
-        let orig_state = ind_to_dec.get(&i).unwrap();
-        let ij_elements = element_f(bfuncs.nx,
-                                    bfuncs.ny,
-                                    sites,
-                                    &orig_state,
-                                    &dec_to_ind,
-                                    &hashtable);
-        for (j, entry) in ij_elements.into_iter() {
-            rows.push(i);
-            cols.push(j);
-            data.push(CComplex::from_num_complex(entry));
+impl BondTerms {
+    fn new(l: I) -> BondTerms {
+        BondTerms { l,
+                    z: None,
+                    xy: None,
+                    ppmm: None }
+    }
+
+    // take on "term", the n-th of the batch, unless it acts on other bonds or
+    // a term of its kind is already there
+    fn join(&mut self, n: usize, term: &Term) -> bool {
+        if term.l != self.l {
+            return false;
+        }
+        let slot = match term.kind {
+            TermKind::HSsZ => &mut self.z,
+            TermKind::HSsXy => &mut self.xy,
+            TermKind::HSsPpmm => &mut self.ppmm,
+            _ => return false
+        };
+        if slot.is_some() {
+            return false;
         }
+        *slot = Some(n);
+        true
     }
-    CoordMatrix::new(data, cols, rows, dims, dims)
 }
 
-pub fn ss_xy(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>), bfuncs: &BlochFuncSet)
-             -> CoordMatrix<CComplex<f64>> {
-    off_diag_ops(ss_xy_elements, &sites, &bfuncs)
+// How the terms of a batch are generated: together with the other terms on the
+// same bonds, or on their own
+enum Pass {
+    Bonds(BondTerms),
+    Single(usize)
+}
+
+impl Pass {
+    fn plan(terms: &[Term]) -> Vec<Pass> {
+        let mut passes: Vec<Pass> = Vec::new();
+        for (n, term) in terms.iter().enumerate() {
+            let joined = passes.iter_mut().any(|pass| match *pass {
+                                                   Pass::Bonds(ref mut bonds) => {
+                                                       bonds.join(n, term)
+                                                   }
+                                                   Pass::Single(_) => false
+                                               });
+            if joined {
+                continue;
+            }
+            let mut bonds = BondTerms::new(term.l);
+            if bonds.join(n, term) {
+                passes.push(Pass::Bonds(bonds));
+            } else {
+                passes.push(Pass::Single(n));
+            }
+        }
+        passes
+    }
+}
+
+/// Collect the elements of each of "terms" on the given basis, scaled by the
+/// coefficients of the terms, in the order of "terms". Each result equals what
+/// term_vecs returns for the term, but the basis is traversed once for all of
+/// them, and h_ss_z, h_ss_xy and h_ss_ppmm on bonds of the same range are
+/// generated together by ss_bond_elements.
+pub fn terms_vecs(terms: &[Term], bfuncs: &BlochFuncSet) -> Vec<VecSink> {
+    // Progress::none() is never cancelled
+    terms_vecs_with_progress(terms, bfuncs, &mut Progress::none()).unwrap()
+}
+
+/// Same as terms_vecs, reporting the fraction of rows done to "progress".
+/// Fails if the build is cancelled or a strict "progress" finds a Bloch
+/// function missing from the basis.
+pub fn terms_vecs_with_progress(terms: &[Term], bfuncs: &BlochFuncSet,
+                                progress: &mut Progress)
+                                -> Result<Vec<VecSink>> {
+    let dims = bfuncs.nonzero;
+    let tables = &bfuncs.tables;
+    let prepared = terms.iter()
+                        .map(|&t| PreparedTerm::new(t, tables))
+                        .collect::<Vec<_>>();
+    let passes = Pass::plan(terms);
+    let table = if prepared.iter().all(|p| p.is_diagonal()) {
+        OrbitTable::empty()
+    } else {
+        OrbitTable::new(bfuncs).strict(progress.is_strict())
+                               .min_phase_norm(progress.min_phase_norm())
+    };
+    let block = |rows: Range<u32>| {
+        let states = &bfuncs.data[rows.start as usize..rows.end as usize];
+        let mut blocks =
+            prepared.iter()
+                    .map(|p| {
+                             let bound = states.iter().map(|b| p.row_bound(b)).sum();
+                             BlockSink { elements: Vec::with_capacity(bound) }
+                         })
+                    .collect::<Vec<_>>();
+        let (mut xy, mut ppmm) = (RowElements::new(), RowElements::new());
+        for (i, orig_state) in rows.zip(states.iter()) {
+            for pass in passes.iter() {
+                let bonds = match *pass {
+                    Pass::Bonds(ref bonds) => bonds,
+                    Pass::Single(n) => {
+                        prepared[n].row_into(i, orig_state, &table, &mut xy,
+                                             &mut blocks[n]);
+                        continue;
+                    }
+                };
+                let z = ss_bond_elements(tables.bonds(bonds.l),
+                                         tables.gammas(bonds.l),
+                                         bonds.xy.is_some(),
+                                         bonds.ppmm.is_some(),
+                                         orig_state,
+                                         &table,
+                                         &mut xy,
+                                         &mut ppmm);
+                xy.sort_by_column();
+                ppmm.sort_by_column();
+                if let Some(n) = bonds.z {
+                    let coeff = terms[n].coeff;
+                    blocks[n].push(i, i, Complex::new(coeff * z, 0.));
+                }
+                for &(n, elements) in [(bonds.xy, &xy), (bonds.ppmm, &ppmm)].iter() {
+                    if let Some(n) = n {
+                        for &(j, entry) in elements.iter() {
+                            blocks[n].push(i, j, entry * terms[n].coeff);
+                        }
+                    }
+                }
+            }
+        }
+        blocks.into_iter().map(|b| b.elements).collect::<Vec<_>>()
+    };
+
+    let mut sinks = terms.iter()
+                         .map(|t| nnz_bound(t, bfuncs, 0..dims))
+                         .map(VecSink::with_capacity)
+                         .collect::<Vec<_>>();
+    let total = dims as u64;
+    let emit = |blocks: Vec<Vec<(u32, u32, Complex<f64>)>>, rows: Range<u32>,
+                progress: &mut Progress| {
+        table.check()?;
+        for row in rows {
+            progress.step(Phase::Elements, row as u64, total)?;
+        }
+        for (elements, sink) in blocks.into_iter().zip(sinks.iter_mut()) {
+            for (i, j, val) in elements.into_iter() {
+                sink.push(i, j, val);
+            }
+        }
+        Ok(())
+    };
+    blocks_in_order(0..dims, progress, block, emit)?;
+    progress.step(Phase::Elements, total, total)?;
+    Ok(sinks)
+}
+
+/// The capacity the builders reserve for the elements of the rows "rows" of
+/// "term" on the given basis, which must not extend past its end. This is the
+/// sum of PreparedTerm::row_bound over the rows and is cheap to compute since
+/// no lookups are involved. Reserving it up front keeps the arrays from
+/// growing, which would briefly need up to twice their final size.
+pub fn nnz_bound(term: &Term, bfuncs: &BlochFuncSet, rows: Range<u32>) -> usize {
+    let prepared = PreparedTerm::new(*term, &bfuncs.tables);
+    let data = &bfuncs.data[rows.start as usize..rows.end as usize];
+    pool::install(|| {
+        data.par_iter()
+            .map(|b| prepared.row_bound(b))
+            .sum::<usize>()
+    })
+}
+
+/// The number of elements term_vecs would generate for "term" on the given
+/// basis
+pub fn term_nnz(term: &Term, bfuncs: &BlochFuncSet) -> u64 {
+    let mut sink = CountSink::default();
+    term_into(term, bfuncs, &mut sink);
+    sink.count
+}
+
+/// Build the operator described by "term" on the given basis, scaled by the
+/// coefficient of the term
+pub fn term(term: &Term, bfuncs: &BlochFuncSet) -> OwnedCoordMatrix<CComplex<f64>> {
+    term_vecs(term, bfuncs).into_coord_matrix(bfuncs.nonzero)
 }
 
-pub fn ss_ppmm(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
-               bfuncs: &BlochFuncSet)
-               -> CoordMatrix<CComplex<f64>> {
-    off_diag_ops(ss_ppmm_elements, &sites, &bfuncs)
+/// Build the operator described by "term" on the given basis with real
+/// elements, in the order of term. Fails unless the term is real (see
+/// TermKind::is_real) and the basis has real phases, in which case the phases
+/// are signs and the imaginary parts of all elements vanish exactly.
+pub fn term_real(term: &Term, bfuncs: &BlochFuncSet)
+                 -> Result<OwnedCoordMatrix<f64>> {
+    if !term.kind.is_real() {
+        return Err(Error::InvalidTerm(term.kind as u32));
+    }
+    if bfuncs.signs.is_empty() {
+        return Err(Error::InvalidArgument("kx, ky"));
+    }
+    let dims = bfuncs.nonzero;
+    let mut sink = RealVecSink::with_capacity(nnz_bound(term, bfuncs, 0..dims));
+    term_into(term, bfuncs, &mut sink);
+    Ok(sink.into_coord_matrix(dims))
 }
 
-pub fn ss_pmz(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>), bfuncs: &BlochFuncSet)
-              -> CoordMatrix<CComplex<f64>> {
-    off_diag_ops(ss_pmz_elements, &sites, &bfuncs)
+/// Build the operator described by "term" on the given basis as a dense
+/// matrix, scaled by the coefficient of the term. Refuses to do so if the
+/// dimension exceeds "max_dim".
+pub fn term_dense(term: &Term, bfuncs: &BlochFuncSet, max_dim: u32)
+                  -> Result<DenseMatrix<CComplex<f64>>> {
+    let dims = bfuncs.nonzero;
+    if dims > max_dim {
+        return Err(Error::TooLarge(dims));
+    }
+    let mut sink = DenseSink::new(dims as usize);
+    term_into(term, bfuncs, &mut sink);
+    Ok(sink.into_dense_matrix())
 }
 
-pub fn sss_chi(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>, Vec<BinaryBasis>),
-               bfuncs: &BlochFuncSet)
-               -> CoordMatrix<CComplex<f64>> {
-    off_diag_ops(sss_chi_elements, &sites, &bfuncs)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consv;
+    use libc::c_void;
+
+    // ss_pmz_elements as it was written before RowElements, with a fresh
+    // hashmap per row, kept as a reference
+    #[allow(non_snake_case)]
+    fn ss_pmz_elements_reference(sites: &(Vec<BinaryBasis>, Vec<BinaryBasis>),
+                                 bond_gammas: &[Complex<f64>],
+                                 orig_state: &BlochFunc,
+                                 table: &OrbitTable)
+                                 -> FnvHashMap<u32, Complex<f64>> {
+        let J = Complex::new(0., 1.);
+        let mut j_element = FnvHashMap::default();
+        let (ref site1, ref site2) = *sites;
+        for (n, (&s_1, &s_2)) in site1.iter().zip(site2.iter()).enumerate() {
+            for &(s1, s2) in [(s_1, s_2), (s_2, s_1)].iter() {
+                let z_contrib = if orig_state.lead | s1 == orig_state.lead {
+                    0.5
+                } else {
+                    -0.5
+                };
+                let new_dec;
+                let mut _gamma = Complex::new(0., 0.);
+                if orig_state.lead | s2 == orig_state.lead {
+                    new_dec = orig_state.lead - s2;
+                    _gamma += bond_gammas[n].conj();
+                } else {
+                    new_dec = orig_state.lead + s2;
+                    _gamma -= bond_gammas[n];
+                }
+                if let Some((j, cntd_state, phase)) = table.find(new_dec) {
+                    let coeff = phase * coeff(orig_state, &cntd_state);
+                    let element = match j_element.get(&j) {
+                        Some(&c) => c + J * z_contrib * coeff * _gamma,
+                        None => J * z_contrib * coeff * _gamma
+                    };
+                    j_element.insert(j, element);
+                }
+            }
+        }
+        j_element
+    }
+
+    fn sorted_bits<'a, I>(elements: I) -> Vec<(u32, u64, u64)>
+        where I: Iterator<Item = (&'a u32, &'a Complex<f64>)>
+    {
+        let mut bits = elements.map(|(&j, c)| (j, c.re.to_bits(), c.im.to_bits()))
+                               .collect::<Vec<_>>();
+        bits.sort();
+        bits
+    }
+
+    #[test]
+    fn row_elements_match_hashmap() {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut elements = RowElements::new();
+        for &ncols in [3, LINEAR_SEARCH_MAX, 5 * LINEAR_SEARCH_MAX].iter() {
+            elements.clear();
+            let mut reference = FnvHashMap::default();
+            for _ in 0..4 * ncols {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let j = (state % ncols as u64) as u32;
+                let val = Complex::new((state >> 40) as f64, j as f64 / 7.);
+                elements.add(j, val);
+                *reference.entry(j).or_insert(Complex::new(0., 0.)) += val;
+            }
+            let found = elements.iter().map(|(j, c)| (j, c));
+            assert_eq!(sorted_bits(found), sorted_bits(reference.iter()));
+        }
+    }
+
+    #[test]
+    fn ss_pmz_elements_match_reference() {
+        let (nx, ny) = (Dim(4), Dim(4));
+        let bfuncs = consv::k::bloch_states(nx, ny, K(1), K(2)).unwrap();
+        let table = OrbitTable::new(&bfuncs);
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default()).unwrap();
+        let mut elements = RowElements::new();
+        for &l in [I(1), I(2)].iter() {
+            let (sites, gammas) = (tables.bonds(l), tables.gammas(l));
+            for orig_state in bfuncs.data.iter() {
+                ss_pmz_elements(sites, gammas, orig_state, &table, &mut elements);
+                let reference =
+                    ss_pmz_elements_reference(sites, gammas, orig_state, &table);
+                let found = elements.iter().map(|(j, c)| (j, c));
+                assert_eq!(sorted_bits(found), sorted_bits(reference.iter()));
+            }
+        }
+    }
+
+    // the nearest neighbor bonds of "tables" listed in both orders, the first
+    // few once more and the first site bonded to itself
+    fn redundant_bonds(tables: &LatticeTables) -> BondSites {
+        let (site1, site2) = tables.bonds(I(1));
+        let mut sites = (site1.clone(), site2.clone());
+        sites.0.extend(site2.iter().chain(site1.iter().take(3)).chain(&site1[..1]));
+        sites.1.extend(site1.iter().chain(site2.iter().take(3)).chain(&site1[..1]));
+        sites
+    }
+
+    #[test]
+    fn redundant_bonds_are_refused() {
+        let tables = lattice_tables(Dim(3), Dim(3), &LatticeSettings::default())
+            .unwrap();
+        let sites = tables.bonds(I(1));
+        assert!(is_canonical_bond_list(sites));
+        assert!(check_bond_sites(sites).is_ok());
+        let redundant = redundant_bonds(&tables);
+        assert!(!is_canonical_bond_list(&redundant));
+        // the first bond listed in the other order is the first one refused
+        let expected = format!("bond {} joins the sites", sites.0.len());
+        match check_bond_sites(&redundant) {
+            Err(Error::Inconsistent(ref msg)) => {
+                assert!(msg.starts_with(&expected), "{}", msg);
+                assert!(msg.ends_with("of bond 0 again"), "{}", msg);
+            }
+            other => panic!("{:?}", other)
+        }
+    }
+
+    fn sink_bits(sink: &VecSink) -> Vec<(u32, u32, u64, u64)> {
+        sink.rows
+            .iter()
+            .zip(sink.cols.iter())
+            .zip(sink.data.iter())
+            .map(|((&i, &j), d)| (i, j, d.re.to_bits(), d.im.to_bits()))
+            .collect()
+    }
+
+    #[test]
+    fn summed_rows_match_coalesced_terms() {
+        use assemble::{self, permuted};
+        let bfuncs = consv::k::bloch_states(Dim(4), Dim(4), K(1), K(2)).unwrap();
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSsPmz, I(1)),
+                     Term::new(TermKind::HSsXy, I(2))];
+        let mut all = VecSink::with_capacity(0);
+        for term in terms.iter() {
+            let sink = term_vecs(term, &bfuncs);
+            all.rows.extend(sink.rows);
+            all.cols.extend(sink.cols);
+            all.data.extend(sink.data);
+        }
+        let perm = assemble::row_col_order(&all.cols, &all.rows);
+        let mut reference = VecSink { data: permuted(&all.data, &perm),
+                                      cols: permuted(&all.cols, &perm),
+                                      rows: permuted(&all.rows, &perm) };
+        assemble::coalesce(&mut reference.cols, &mut reference.rows,
+                           &mut reference.data);
+
+        let mut summed = VecSink::with_capacity(0);
+        terms_into(&terms, &bfuncs, &mut summed);
+        assert_eq!(sink_bits(&summed), sink_bits(&reference));
+
+        // a single term keeps the order term_into generates it in
+        let mut single = VecSink::with_capacity(0);
+        terms_into(&terms[2..3], &bfuncs, &mut single);
+        assert_eq!(sink_bits(&single), sink_bits(&term_vecs(&terms[2], &bfuncs)));
+    }
+
+    /// Run with --release --ignored --nocapture to compare the rows of the
+    /// s+ sz term on a 5x4 lattice accumulated in a fresh hashmap each with
+    /// those accumulated in a reused RowElements
+    #[test]
+    #[ignore]
+    fn row_elements_speedup() {
+        use std::time::Instant;
+        let (nx, ny) = (Dim(5), Dim(4));
+        let bfuncs = consv::k::bloch_states(nx, ny, K(1), K(2)).unwrap();
+        let table = OrbitTable::new(&bfuncs);
+        let tables = lattice_tables(nx, ny, &LatticeSettings::default()).unwrap();
+        let (sites, gammas) = (tables.bonds(I(1)), tables.gammas(I(1)));
+
+        let start = Instant::now();
+        let mut reference = 0;
+        for orig_state in bfuncs.data.iter() {
+            reference +=
+                ss_pmz_elements_reference(sites, gammas, orig_state, &table).len();
+        }
+        let hashmaps = start.elapsed();
+
+        let start = Instant::now();
+        let mut found = 0;
+        let mut elements = RowElements::new();
+        for orig_state in bfuncs.data.iter() {
+            ss_pmz_elements(sites, gammas, orig_state, &table, &mut elements);
+            found += elements.iter().count();
+        }
+        let reused = start.elapsed();
+
+        assert_eq!(found, reference);
+        println!("{} elements, hashmaps: {:?}, reused: {:?}",
+                 found,
+                 hashmaps,
+                 reused);
+    }
+
+    #[test]
+    fn strict_mode_catches_missing_bloch_functions() {
+        use blochfunc::Lookup;
+        let (nx, ny, kx, ky) = (Dim(4), Dim(4), K(1), K(2));
+        let full = consv::ks::bloch_states(nx, ny, kx, ky, 8).unwrap();
+        let term = Term::new(TermKind::HSsXy, I(1));
+        let removed = full.nonzero / 2;
+        // the elements of "full" outside the row and column of the removed
+        // Bloch function, numbered as in the truncated basis
+        let renumber = |i: u32| if i > removed { i - 1 } else { i };
+        let expected = {
+            let sink = term_vecs(&term, &full);
+            let mut bits = sink_bits(&sink).into_iter()
+                                           .filter(|&(i, j, _, _)| {
+                                               i != removed && j != removed
+                                           })
+                                           .map(|(i, j, re, im)| {
+                                               (renumber(i), renumber(j), re, im)
+                                           })
+                                           .collect::<Vec<_>>();
+            bits.sort();
+            bits
+        };
+        for &lookup in [Lookup::Members, Lookup::Leads].iter() {
+            let mut data = full.data.clone();
+            data.remove(removed as usize);
+            let truncated = BlochFuncSet::create(full.tables.clone(), kx, ky, lookup,
+                                                 full.convention, data);
+            let mut strict = Progress::none().strict(true);
+            match terms_vecs_with_progress(&[term], &truncated, &mut strict) {
+                Err(Error::Inconsistent(ref msg)) => {
+                    assert!(msg.contains("missing"))
+                }
+                Err(e) => panic!("unexpected error {}", e),
+                Ok(_) => panic!("the missing Bloch function went unnoticed")
+            }
+
+            let mut lenient = Progress::none().strict(false);
+            let sink = terms_vecs_with_progress(&[term], &truncated, &mut lenient)
+                .unwrap()
+                .remove(0);
+            let mut bits = sink_bits(&sink);
+            bits.sort();
+            // the same elements, summed in another order
+            assert_eq!(bits.len(), expected.len());
+            for (a, b) in bits.iter().zip(expected.iter()) {
+                assert_eq!((a.0, a.1), (b.0, b.1));
+                let (a_re, b_re) = (f64::from_bits(a.2), f64::from_bits(b.2));
+                let (a_im, b_im) = (f64::from_bits(a.3), f64::from_bits(b.3));
+                assert!((a_re - b_re).abs() < 1e-12 && (a_im - b_im).abs() < 1e-12);
+            }
+        }
+    }
+
+    // records the positions of the elements in the order they arrive
+    struct OrderSink(Vec<(u32, u32)>);
+
+    impl ElementSink for OrderSink {
+        fn push(&mut self, row: u32, col: u32, _val: Complex<f64>) {
+            self.0.push((row, col));
+        }
+    }
+
+    extern "C" fn record(row: u64, col: u64, _re: f64, _im: f64, ctx: *mut c_void) {
+        let order = unsafe { &mut *(ctx as *mut Vec<(u32, u32)>) };
+        order.push((row as u32, col as u32));
+    }
+
+    fn assert_in_order(order: &[(u32, u32)], at: &str) {
+        assert!(order.windows(2).all(|w| w[0] < w[1]), "{} out of order", at);
+    }
+
+    fn vec_order(sink: &VecSink) -> Vec<(u32, u32)> {
+        sink.rows.iter().cloned().zip(sink.cols.iter().cloned()).collect()
+    }
+
+    // The order of the elements is part of the interface (see ElementSink):
+    // rows in increasing order and the columns of each row in increasing
+    // order, from every builder and every term in every sector
+    #[test]
+    fn builders_emit_rows_then_columns_in_order() {
+        use rows::HamiltonianRows;
+        use stream;
+        let (nx, ny) = (Dim(4), Dim(3));
+        let mut all = vec![Term::new(TermKind::HSssChi, I(0))];
+        for l in 1..4 {
+            for &kind in [TermKind::HSsZ,
+                          TermKind::HSsXy,
+                          TermKind::HSsPpmm,
+                          TermKind::HSsPmz,
+                          TermKind::SsZ,
+                          TermKind::SsXy].iter()
+            {
+                all.push(Term::new(kind, I(l)));
+            }
+        }
+        let conserving = all.iter()
+                            .cloned()
+                            .filter(|t| t.kind.conserves_sz())
+                            .collect::<Vec<_>>();
+        for kx in 0..4 {
+            for ky in 0..3 {
+                let (kx, ky) = (K(kx), K(ky));
+                let k = consv::k::bloch_states(nx, ny, kx, ky).unwrap();
+                let mut bases = vec![(k, &all[..], "all nup".to_string())];
+                for nup in 0..13 {
+                    let ks = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+                    bases.push((ks, &conserving[..], format!("nup = {}", nup)));
+                }
+                for (bfuncs, terms, sector) in bases.into_iter() {
+                    let at = |builder: &str| {
+                        format!("{} at k = ({}, {}), {}",
+                                builder,
+                                kx.raw_int(),
+                                ky.raw_int(),
+                                sector)
+                    };
+                    for term in terms.iter() {
+                        let name =
+                            format!("{:?} l = {}", term.kind, term.l.raw_int());
+                        let sink = term_vecs(term, &bfuncs);
+                        assert_in_order(&vec_order(&sink), &at(&name));
+                        let middle = bfuncs.nonzero / 3..2 * bfuncs.nonzero / 3;
+                        let mut part = OrderSink(Vec::new());
+                        term_rows_into(term, &bfuncs, middle, &mut part).unwrap();
+                        assert_in_order(&part.0, &at(&format!("rows of {}", name)));
+                    }
+                    for sink in terms_vecs(terms, &bfuncs).iter() {
+                        assert_in_order(&vec_order(sink), &at("terms_vecs"));
+                    }
+                    let mut summed = OrderSink(Vec::new());
+                    terms_into(terms, &bfuncs, &mut summed);
+                    assert_in_order(&summed.0, &at("terms_into"));
+                    let mut rows = HamiltonianRows::new(&bfuncs, terms);
+                    let mut pulled = Vec::new();
+                    while let Some((i, row)) = rows.next_row() {
+                        pulled.extend(row.iter().map(|&(j, _)| (i, j)));
+                    }
+                    assert_in_order(&pulled, &at("HamiltonianRows"));
+                }
+
+                for nup in 0..13 {
+                    let mut streamed: Vec<(u32, u32)> = Vec::new();
+                    let ctx = &mut streamed as *mut Vec<(u32, u32)> as *mut c_void;
+                    stream::ks_terms(nx, ny, kx, ky, nup, &conserving, Some(record),
+                                     ctx).unwrap();
+                    assert_in_order(&streamed,
+                                    &format!("ks_terms at nup = {}", nup));
+                    streamed.clear();
+                    let ctx = &mut streamed as *mut Vec<(u32, u32)> as *mut c_void;
+                    stream::ks_term(nx, ny, kx, ky, nup, conserving[1], Some(record),
+                                    ctx).unwrap();
+                    assert_in_order(&streamed, &format!("ks_term at nup = {}", nup));
+                }
+            }
+        }
+    }
+
+    // A bond list with a site paired with itself, with a mask of two sites,
+    // or with a pair of sites twice, is refused by PreparedTerm::with_bonds
+    // with a message naming the bond, while the bonds of the lattice itself
+    // build the same matrix as PreparedTerm::new
+    #[test]
+    fn with_bonds_rejects_degenerate_bonds() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let bfuncs = consv::k::bloch_states(nx, ny, K(1), K(1)).unwrap();
+        let bonds = bfuncs.tables.bonds(I(1)).clone();
+        let mut same = bonds.clone();
+        same.1[2] = same.0[2];
+        let mut wide = bonds.clone();
+        wide.1[3] = wide.1[3] | wide.0[0];
+        let redundant = redundant_bonds(&bfuncs.tables);
+        let broken = [(same, "bond 2 pairs the site mask"),
+                      (wide, "bond 3 joins the masks"),
+                      (redundant, "of bond 0 again")];
+        let kinds = [TermKind::HSsZ,
+                     TermKind::HSsXy,
+                     TermKind::HSsPpmm,
+                     TermKind::HSsPmz];
+        for &(ref sites, expected) in broken.iter() {
+            for &kind in kinds.iter() {
+                let term = Term::new(kind, I(1));
+                match PreparedTerm::with_bonds(term, &bfuncs.tables, sites.clone()) {
+                    Err(Error::Inconsistent(ref msg)) => {
+                        assert!(msg.contains(expected), "{}", msg)
+                    }
+                    Err(e) => panic!("{:?}: {:?}", kind, e),
+                    Ok(_) => panic!("{:?}: bonds accepted", kind)
+                }
+            }
+        }
+        // the chiral term and the correlation functions have no bonds
+        for &(kind, l) in [(TermKind::HSssChi, I(0)),
+                           (TermKind::SsZ, I(1)),
+                           (TermKind::SsXy, I(1))].iter()
+        {
+            let term = Term::new(kind, l);
+            match PreparedTerm::with_bonds(term, &bfuncs.tables, bonds.clone()) {
+                Err(Error::InvalidArgument(_)) => (),
+                Err(e) => panic!("{:?}: {:?}", kind, e),
+                Ok(_) => panic!("{:?}: bonds accepted", kind)
+            }
+        }
+        for &kind in kinds.iter() {
+            let term = Term::new(kind, I(1));
+            let tables = &bfuncs.tables;
+            let prepared = PreparedTerm::with_bonds(term, tables, bonds.clone());
+            let prepared = prepared.unwrap();
+            let mut sink = VecSink::with_capacity(0);
+            operators_rows_into_with_progress(&[&prepared], &bfuncs,
+                                              0..bfuncs.nonzero, &mut sink,
+                                              &mut Progress::none()).unwrap();
+            let expected = term_vecs(&term, &bfuncs);
+            assert_eq!(sink.rows, expected.rows, "{:?}", kind);
+            assert_eq!(sink.cols, expected.cols, "{:?}", kind);
+            assert_eq!(sink.data, expected.data, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn spin_pair_helpers() {
+        let (s1, s2) = (BinaryBasis(0b10), BinaryBasis(0b1000));
+        assert!(is_site_pair(s1, s2));
+        assert!(!is_site_pair(s1, s1));
+        assert!(!is_site_pair(s1, BinaryBasis(0b1100)));
+        assert!(!is_site_pair(BinaryBasis(0), s2));
+        let dec = BinaryBasis(0b1010);
+        assert_eq!(repeated_spins(dec, s1, s2), (true, false));
+        assert_eq!(exchange_spin_flips(dec, s1, s2), (false, false));
+    }
 }
@@ -0,0 +1,105 @@
+//! The thread pool all parallel work in the crate runs on. By default rayon's
+//! global pool is used, which spans all cores. set_threads replaces it with a
+//! pool of the requested size. Work that has already started keeps running on
+//! the pool it started on, so the setting can safely be changed between calls
+//! and only affects the calls that start afterwards.
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::{Arc, Mutex};
+
+use error::{Error, Result};
+
+static POOL: Mutex<Option<Arc<ThreadPool>>> = Mutex::new(None);
+
+/// Run all parallel work on "n" threads from now on. 0 means all cores.
+pub fn set_threads(n: u32) -> Result<()> {
+    let pool = if n == 0 {
+        None
+    } else {
+        let pool =
+            ThreadPoolBuilder::new().num_threads(n as usize)
+                                    .build()
+                                    .map_err(|_| Error::InvalidArgument("n"))?;
+        Some(Arc::new(pool))
+    };
+    *POOL.lock().unwrap() = pool;
+    Ok(())
+}
+
+/// Run "op" on the configured pool
+pub fn install<OP, R>(op: OP) -> R
+    where OP: FnOnce() -> R + Send,
+          R: Send
+{
+    // the lock is released before "op" starts so the setting can be changed
+    // while it runs
+    let pool = POOL.lock().unwrap().clone();
+    match pool {
+        Some(pool) => pool.install(op),
+        None => op()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::*;
+    use consv;
+    use matfree::OpHandle;
+    use num_complex::Complex;
+    use rayon;
+    use test_support::lock_pool;
+
+    fn apply_4x4() -> Vec<(f64, f64)> {
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSssChi, I(0))];
+        let op = OpHandle::ks(Dim(4), Dim(4), K(1), K(0), 8, &terms).unwrap();
+        let x = (0..op.dim()).map(|i| {
+                                 Complex::new((i as f64).sin(), 1. / (1. + i as f64))
+                             })
+                             .collect::<Vec<_>>();
+        let mut y = vec![Complex::new(0., 0.); x.len()];
+        op.apply(&x, &mut y).unwrap();
+        y.iter().map(|c| (c.re, c.im)).collect()
+    }
+
+    fn build_4x4() -> Vec<(u32, u32, f64, f64)> {
+        let term = Term::new(TermKind::HSsXy, I(1));
+        let ks =
+            consv::ks::term_matrix(Dim(4), Dim(4), K(1), K(0), 8, &term).unwrap();
+        let k = consv::k::term_matrix(Dim(4), Dim(4), K(1), K(0), &term).unwrap();
+        ks.row
+          .iter()
+          .zip(ks.col.iter())
+          .zip(ks.data.iter())
+          .chain(k.row.iter().zip(k.col.iter()).zip(k.data.iter()))
+          .map(|((&i, &j), c)| (i, j, c.re, c.im))
+          .collect()
+    }
+
+    fn bases() -> Vec<(u64, f64)> {
+        let k = consv::k::bloch_states(Dim(4), Dim(4), K(1), K(0)).unwrap();
+        let ks = consv::ks::bloch_states(Dim(6), Dim(3), K(2), K(1), 9).unwrap();
+        k.data
+         .iter()
+         .chain(ks.data.iter())
+         .map(|b| (b.lead.raw_int(), b.norm))
+         .collect()
+    }
+
+    #[test]
+    fn thread_count_does_not_change_output() {
+        let _pool = lock_pool();
+        set_threads(1).unwrap();
+        assert_eq!(install(rayon::current_num_threads), 1);
+        let serial = (apply_4x4(), build_4x4(), bases());
+
+        set_threads(4).unwrap();
+        assert_eq!(install(rayon::current_num_threads), 4);
+        let parallel = (apply_4x4(), build_4x4(), bases());
+
+        set_threads(0).unwrap();
+        // bit for bit, not just within rounding
+        assert_eq!(serial, parallel);
+    }
+}
@@ -0,0 +1,157 @@
+//! Coarse progress reporting for long-running builds. The callback supplied by
+//! the caller is invoked with the fraction of the current phase that is done,
+//! the name of the phase as a C string and the caller's context pointer. Each
+//! phase is reported in steps of roughly one percent and always ends with a
+//! fraction of exactly 1. The callback is only ever invoked from the calling
+//! thread and never after the builder returns.
+//!
+//! The same hooks poll an optional cancellation flag owned by the caller. Once
+//! the flag is set to a nonzero value the build stops at the next row or basis
+//! state, drops everything allocated so far and fails with Error::Cancelled.
+//!
+//! A build can also be asked to check its lookups in the basis (see
+//! Progress::strict), in which case it fails with Error::Inconsistent at the
+//! next row once a lookup has missed a Bloch function of the sector, told how
+//! the basis it scans finds the orbits of the configurations (see
+//! Progress::with_lookup) and which components it takes as phases (see
+//! Progress::with_min_phase_norm).
+use libc::{c_char, c_void};
+use std::{
+    ptr,
+    sync::atomic::{AtomicU8, Ordering}
+};
+
+use blochfunc::Lookup;
+use common::MIN_PHASE_NORM;
+use error::{Error, Result};
+
+pub type ProgressCallback =
+    extern "C" fn(fraction: f64, phase: *const c_char, ctx: *mut c_void);
+
+/// Number of reports per phase
+const STEPS: u64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Scan of the Sz basis for Bloch states
+    Basis,
+    /// Generation of the matrix elements, row by row
+    Elements
+}
+
+impl Phase {
+    fn name(self) -> &'static [u8] {
+        match self {
+            Phase::Basis => b"basis\0",
+            Phase::Elements => b"elements\0"
+        }
+    }
+}
+
+pub struct Progress {
+    cb:             Option<ProgressCallback>,
+    ctx:            *mut c_void,
+    cancel:         *const AtomicU8,
+    strict:         bool,
+    lookup:         Lookup,
+    min_phase_norm: f64
+}
+
+impl Progress {
+    pub fn new(cb: Option<ProgressCallback>, ctx: *mut c_void) -> Progress {
+        Progress { cb,
+                   ctx,
+                   cancel: ptr::null(),
+                   strict: false,
+                   lookup: Lookup::Members,
+                   min_phase_norm: MIN_PHASE_NORM }
+    }
+
+    /// Reports nothing and is never cancelled
+    pub fn none() -> Progress { Progress::new(None, ptr::null_mut()) }
+
+    /// Poll the byte at "cancel" as well. A null pointer disables cancellation.
+    /// The byte must stay valid until the build returns and may only be
+    /// written atomically by other threads.
+    pub unsafe fn with_cancel(self, cancel: *const u8) -> Progress {
+        Progress { cancel: cancel as *const AtomicU8,
+                   ..self }
+    }
+
+    /// Build the lookup tables of the basis strict (see OrbitTable::strict).
+    /// Off by default.
+    pub fn strict(self, strict: bool) -> Progress { Progress { strict, ..self } }
+
+    /// Whether the build checks its lookups
+    pub fn is_strict(&self) -> bool { self.strict }
+
+    /// Scan the basis of the build with "lookup". Lookup::Members by default.
+    /// A basis taken from the basis cache is one built with the same lookup.
+    pub fn with_lookup(self, lookup: Lookup) -> Progress {
+        Progress { lookup, ..self }
+    }
+
+    /// The lookup the basis of the build is scanned with
+    pub fn lookup(&self) -> Lookup { self.lookup }
+
+    /// Build the lookup tables of the basis with "norm" as the smallest
+    /// component taken as a phase (see OrbitTable::min_phase_norm).
+    /// MIN_PHASE_NORM by default. "norm" must have passed
+    /// common::check_phase_norm.
+    pub fn with_min_phase_norm(self, norm: f64) -> Progress {
+        Progress { min_phase_norm: norm,
+                   ..self }
+    }
+
+    /// The smallest component the lookups of the build take as a phase
+    pub fn min_phase_norm(&self) -> f64 { self.min_phase_norm }
+
+    /// Fails with Error::Cancelled if the caller has asked to stop
+    pub fn check(&self) -> Result<()> {
+        let cancel = unsafe { self.cancel.as_ref() };
+        match cancel {
+            Some(flag) if flag.load(Ordering::Relaxed) != 0 => Err(Error::Cancelled),
+            _ => Ok(())
+        }
+    }
+
+    /// Report that "done" out of "total" units of "phase" are complete. Only
+    /// about every hundredth unit and the last one are passed on to the
+    /// callback. Fails if the build has been cancelled.
+    pub fn step(&mut self, phase: Phase, done: u64, total: u64) -> Result<()> {
+        self.check()?;
+        if let Some(cb) = self.cb {
+            let stride = ((total + STEPS - 1) / STEPS).max(1);
+            if done % stride == 0 || done >= total {
+                let fraction = if total == 0 {
+                    1.
+                } else {
+                    done.min(total) as f64 / total as f64
+                };
+                cb(fraction, phase.name().as_ptr() as *const c_char, self.ctx);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{collect, Reports};
+
+    #[test]
+    fn step_reports_coarsely() {
+        let mut reports: Reports = Vec::new();
+        {
+            let mut progress =
+                Progress::new(Some(collect), &mut reports as *mut Reports as *mut _);
+            for i in 1..1001 {
+                progress.step(Phase::Elements, i, 1000).unwrap();
+            }
+        }
+        assert_eq!(reports.len(), 100);
+        assert_eq!(reports.last().unwrap(), &(1., "elements".to_string()));
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+}
@@ -0,0 +1,223 @@
+//! Native Python module exposing the builders. Every builder returns a
+//! (data, row, col, shape) tuple of numpy arrays that can be passed straight to
+//! scipy:
+//!
+//! ```text
+//! data, row, col, shape = triangular_lattice_ext.ks_h_ss_z(4, 3, 0, 0, 6, 1)
+//! H = scipy.sparse.csr_matrix((data, (row, col)), shape=shape)
+//! ```
+//!
+//! The arrays are owned by Python, so unlike the matrices returned through the
+//! C interface they need not be freed by hand. Build with the
+//! "extension-module" feature to produce a library Python can import.
+use numpy::{c64, IntoPyArray, PyArray1};
+use pyo3::{exceptions::PyValueError, prelude::*, wrap_pyfunction};
+use std::sync::Arc;
+
+use blochfunc::BlochFuncSet;
+use common::*;
+use consv;
+use error::{Error, Result};
+use ops;
+
+type Coo<'py> = (&'py PyArray1<c64>,
+                 &'py PyArray1<u32>,
+                 &'py PyArray1<u32>,
+                 (u32, u32));
+
+/// Invalid parameters surface in Python as a ValueError
+fn value_error(e: Error) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+/// Generate "term" on the basis returned by "basis" with the GIL released
+fn coo<'py, F>(py: Python<'py>, nx: u32, ny: u32, basis: F, kind: TermKind,
+               l: u32)
+               -> PyResult<Coo<'py>>
+    where F: Send + FnOnce() -> Result<Arc<BlochFuncSet>>
+{
+    let term = Term::new(kind, I(l as i32));
+    term.check(Dim(nx), Dim(ny)).map_err(value_error)?;
+    let build = move || -> Result<_> {
+        let bfuncs = basis()?;
+        Ok((ops::term_vecs(&term, &bfuncs), bfuncs.nonzero))
+    };
+    let (sink, dims) = py.allow_threads(build).map_err(value_error)?;
+    let data = sink.data
+                   .iter()
+                   .map(|c| c64::new(c.re, c.im))
+                   .collect::<Vec<c64>>();
+    // the builders generate the elements in (data, (col, row)) order, so the
+    // arrays are swapped to read (data, (row, col)) like scipy expects
+    Ok((data.into_pyarray(py),
+        sink.cols.into_pyarray(py),
+        sink.rows.into_pyarray(py),
+        (dims, dims)))
+}
+
+fn k_coo(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, kind: TermKind, l: u32)
+         -> PyResult<Coo> {
+    let basis = move || consv::k::bloch_states(Dim(nx), Dim(ny), K(kx), K(ky));
+    coo(py, nx, ny, basis, kind, l)
+}
+
+fn ks_coo(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, nup: u32,
+          kind: TermKind, l: u32)
+          -> PyResult<Coo> {
+    let basis = move || {
+        consv::ks::bloch_states(Dim(nx), Dim(ny), K(kx), K(ky), nup)
+    };
+    coo(py, nx, ny, basis, kind, l)
+}
+
+#[pyfunction]
+fn k_h_ss_z(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
+            -> PyResult<Coo> {
+    k_coo(py, nx, ny, kx, ky, TermKind::HSsZ, l)
+}
+
+#[pyfunction]
+fn k_h_ss_xy(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
+             -> PyResult<Coo> {
+    k_coo(py, nx, ny, kx, ky, TermKind::HSsXy, l)
+}
+
+#[pyfunction]
+fn k_h_ss_ppmm(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
+               -> PyResult<Coo> {
+    k_coo(py, nx, ny, kx, ky, TermKind::HSsPpmm, l)
+}
+
+#[pyfunction]
+fn k_h_ss_pmz(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
+              -> PyResult<Coo> {
+    k_coo(py, nx, ny, kx, ky, TermKind::HSsPmz, l)
+}
+
+#[pyfunction]
+fn k_h_sss_chi(py: Python, nx: u32, ny: u32, kx: u32, ky: u32)
+               -> PyResult<Coo> {
+    k_coo(py, nx, ny, kx, ky, TermKind::HSssChi, 0)
+}
+
+#[pyfunction]
+fn k_ss_z(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
+          -> PyResult<Coo> {
+    k_coo(py, nx, ny, kx, ky, TermKind::SsZ, l)
+}
+
+#[pyfunction]
+fn k_ss_xy(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, l: u32)
+           -> PyResult<Coo> {
+    k_coo(py, nx, ny, kx, ky, TermKind::SsXy, l)
+}
+
+#[pyfunction]
+fn ks_h_ss_z(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
+             -> PyResult<Coo> {
+    ks_coo(py, nx, ny, kx, ky, nup, TermKind::HSsZ, l)
+}
+
+#[pyfunction]
+fn ks_h_ss_xy(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
+              -> PyResult<Coo> {
+    ks_coo(py, nx, ny, kx, ky, nup, TermKind::HSsXy, l)
+}
+
+#[pyfunction]
+fn ks_h_sss_chi(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, nup: u32)
+                -> PyResult<Coo> {
+    ks_coo(py, nx, ny, kx, ky, nup, TermKind::HSssChi, 0)
+}
+
+#[pyfunction]
+fn ks_ss_z(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
+           -> PyResult<Coo> {
+    ks_coo(py, nx, ny, kx, ky, nup, TermKind::SsZ, l)
+}
+
+#[pyfunction]
+fn ks_ss_xy(py: Python, nx: u32, ny: u32, kx: u32, ky: u32, nup: u32, l: u32)
+            -> PyResult<Coo> {
+    ks_coo(py, nx, ny, kx, ky, nup, TermKind::SsXy, l)
+}
+
+#[pymodule]
+fn triangular_lattice_ext(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(k_h_ss_z, m)?)?;
+    m.add_function(wrap_pyfunction!(k_h_ss_xy, m)?)?;
+    m.add_function(wrap_pyfunction!(k_h_ss_ppmm, m)?)?;
+    m.add_function(wrap_pyfunction!(k_h_ss_pmz, m)?)?;
+    m.add_function(wrap_pyfunction!(k_h_sss_chi, m)?)?;
+    m.add_function(wrap_pyfunction!(k_ss_z, m)?)?;
+    m.add_function(wrap_pyfunction!(k_ss_xy, m)?)?;
+    m.add_function(wrap_pyfunction!(ks_h_ss_z, m)?)?;
+    m.add_function(wrap_pyfunction!(ks_h_ss_xy, m)?)?;
+    m.add_function(wrap_pyfunction!(ks_h_sss_chi, m)?)?;
+    m.add_function(wrap_pyfunction!(ks_ss_z, m)?)?;
+    m.add_function(wrap_pyfunction!(ks_ss_xy, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::IntoPyDict;
+
+    // runs against an embedded interpreter, so numpy has to be importable
+    #[test]
+    fn builders_from_python() {
+        Python::with_gil(|py| {
+            let m = PyModule::new(py, "triangular_lattice_ext").unwrap();
+            triangular_lattice_ext(py, m).unwrap();
+            let locals = [("ext", m)].into_py_dict(py);
+            py.run(r#"
+import numpy as np
+
+data, row, col, shape = ext.ks_h_ss_z(4, 3, 0, 0, 6, 1)
+assert shape[0] == shape[1] == len(data)
+assert data.dtype == np.complex128
+assert (row == col).all() and (row == np.arange(shape[0])).all()
+
+data, row, col, shape = ext.k_h_ss_xy(4, 3, 1, 2, 1)
+assert len(data) == len(row) == len(col)
+assert row.max() < shape[0] and col.max() < shape[1]
+
+# hermiticity of the assembled matrix
+H = np.zeros(shape, dtype=np.complex128)
+np.add.at(H, (row, col), data)
+assert np.allclose(H, H.conj().T)
+
+for args in [(4, 3, 4, 0, 6, 1), (4, 3, 0, 3, 6, 1), (4, 3, 0, 0, 13, 1),
+             (4, 3, 0, 0, 6, 4)]:
+    try:
+        ext.ks_h_ss_z(*args)
+    except ValueError:
+        pass
+    else:
+        raise AssertionError(args)
+"#,
+                   None,
+                   Some(locals))
+              .unwrap();
+        });
+    }
+
+    #[test]
+    fn arrays_match_coord_matrix() {
+        Python::with_gil(|py| {
+            let (data, row, col, shape) = ks_h_ss_xy(py, 4, 3, 1, 0, 6, 1).unwrap();
+            let mat = consv::ks::h_ss_xy(Dim(4), Dim(3), K(1), K(0), 6,
+                                         BondRange::NearestNeighbor)
+                          .unwrap();
+            let (mdata, mcol, mrow) = (&mat.data, &mat.col, &mat.row);
+            assert_eq!(shape, (mat.nrows, mat.ncols));
+            assert_eq!(&row.to_vec().unwrap(), mcol);
+            assert_eq!(&col.to_vec().unwrap(), mrow);
+            let data = data.to_vec().unwrap();
+            for (a, b) in data.iter().zip(mdata.iter()) {
+                assert_eq!((a.re, a.im), (b.re, b.im));
+            }
+        });
+    }
+}
@@ -0,0 +1,832 @@
+//! A dense reference implementation of the operators for the tests, written
+//! from the definitions of the model rather than from the builders: every term
+//! is a sum of Kronecker products of 2x2 Pauli matrices acting on the whole
+//! 2^N dimensional space of an N site cluster, and a sector is the image of
+//! an explicitly constructed projector onto total Sz and momentum. Nothing in
+//! here calls the production code, so that the two cannot share a bug; the
+//! tests at the bottom compare them.
+//!
+//! The space of 2^N amplitudes is held in full, which is why the clusters are
+//! limited to MAX_SITES sites. A site is up if its bit in the index of a basis
+//! state is set, and site (x, y) has bit x + nx y.
+use num_complex::Complex;
+use std::f64::consts::PI;
+
+type C = Complex<f64>;
+
+/// The largest cluster the reference handles
+pub const MAX_SITES: u32 = 14;
+
+/// Elements and eigenvalues are compared to this absolute tolerance
+pub const TOL: f64 = 1e-10;
+
+const ZERO: C = C { re: 0., im: 0. };
+const ONE: C = C { re: 1., im: 0. };
+const IM: C = C { re: 0., im: 1. };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pauli {
+    X,
+    Y,
+    Z
+}
+
+impl Pauli {
+    /// The matrix in the basis (up, down), indexed by [row][column]
+    pub fn matrix(self) -> [[C; 2]; 2] {
+        match self {
+            Pauli::X => [[ZERO, ONE], [ONE, ZERO]],
+            Pauli::Y => [[ZERO, -IM], [IM, ZERO]],
+            Pauli::Z => [[ONE, ZERO], [ZERO, -ONE]]
+        }
+    }
+}
+
+/// A Kronecker product of Pauli matrices on distinct sites, the identity on
+/// all others, times a coefficient
+#[derive(Clone, Debug)]
+pub struct PauliString {
+    pub coeff:   C,
+    pub factors: Vec<(u32, Pauli)>
+}
+
+impl PauliString {
+    /// The image of the basis state "state" as a single basis state and its
+    /// amplitude: every Pauli matrix has a single nonzero element per column
+    fn apply_to(&self, state: usize) -> (usize, C) {
+        let mut out = state;
+        let mut amp = self.coeff;
+        for &(site, pauli) in self.factors.iter() {
+            let bit = 1 << site;
+            let col = if state & bit != 0 { 0 } else { 1 };
+            let m = pauli.matrix();
+            let row = if m[0][col] != ZERO { 0 } else { 1 };
+            amp *= m[row][col];
+            if row == 0 {
+                out |= bit;
+            } else {
+                out &= !bit;
+            }
+        }
+        (out, amp)
+    }
+}
+
+/// An operator on the 2^n dimensional space of n sites as a sum of Pauli
+/// strings
+#[derive(Clone, Debug)]
+pub struct Operator {
+    pub n:       u32,
+    pub strings: Vec<PauliString>
+}
+
+impl Operator {
+    pub fn new(n: u32) -> Operator {
+        assert!(n <= MAX_SITES, "{} sites are too many for the reference", n);
+        Operator { n,
+                   strings: Vec::new() }
+    }
+
+    pub fn add(&mut self, coeff: C, factors: &[(u32, Pauli)]) {
+        for (m, &(site, _)) in factors.iter().enumerate() {
+            assert!(site < self.n);
+            assert!(factors[..m].iter().all(|&(s, _)| s != site));
+        }
+        self.strings.push(PauliString { coeff,
+                                        factors: factors.to_vec() });
+    }
+
+    pub fn scale(mut self, c: f64) -> Operator {
+        for s in self.strings.iter_mut() {
+            s.coeff *= c;
+        }
+        self
+    }
+
+    pub fn plus(mut self, other: Operator) -> Operator {
+        assert_eq!(self.n, other.n);
+        self.strings.extend(other.strings);
+        self
+    }
+
+    /// The operator applied to the vector of 2^n amplitudes "v"
+    pub fn apply(&self, v: &[C]) -> Vec<C> {
+        assert_eq!(v.len(), 1 << self.n);
+        let mut w = vec![ZERO; v.len()];
+        for (state, &a) in v.iter().enumerate() {
+            if a == ZERO {
+                continue;
+            }
+            for s in self.strings.iter() {
+                let (out, amp) = s.apply_to(state);
+                w[out] += amp * a;
+            }
+        }
+        w
+    }
+
+    /// The matrix on all 2^n basis states, row-major
+    pub fn dense(&self) -> Vec<C> {
+        let d = 1 << self.n;
+        let mut a = vec![ZERO; d * d];
+        for state in 0..d {
+            for s in self.strings.iter() {
+                let (out, amp) = s.apply_to(state);
+                a[out * d + state] += amp;
+            }
+        }
+        a
+    }
+
+    /// The matrix <b_i|O|b_j> on the orthonormal vectors "basis", row-major
+    pub fn matrix_in(&self, basis: &[Vec<C>]) -> Vec<C> {
+        let d = basis.len();
+        let mut a = vec![ZERO; d * d];
+        for (j, bj) in basis.iter().enumerate() {
+            let w = self.apply(bj);
+            for (i, bi) in basis.iter().enumerate() {
+                a[i * d + j] = dot(bi, &w);
+            }
+        }
+        a
+    }
+}
+
+/// <u|v>
+fn dot(u: &[C], v: &[C]) -> C {
+    u.iter().zip(v.iter()).map(|(a, b)| a.conj() * b)
+     .fold(C::new(0., 0.), |acc, x| acc + x)
+}
+
+fn norm(v: &[C]) -> f64 { v.iter().map(|a| a.norm_sqr()).sum::<f64>().sqrt() }
+
+/// The spin operators on a site in terms of the Pauli matrices: S^a = σ^a / 2,
+/// S^± = (σ^x ± i σ^y) / 2
+fn s_plus() -> Vec<(C, Pauli)> {
+    vec![(C::new(0.5, 0.), Pauli::X), (C::new(0., 0.5), Pauli::Y)]
+}
+
+fn s_minus() -> Vec<(C, Pauli)> {
+    vec![(C::new(0.5, 0.), Pauli::X), (C::new(0., -0.5), Pauli::Y)]
+}
+
+fn s_z() -> Vec<(C, Pauli)> { vec![(C::new(0.5, 0.), Pauli::Z)] }
+
+/// c times the product of the sums of Pauli matrices "ops" on "sites",
+/// expanded into Pauli strings
+fn add_product(op: &mut Operator, c: C, factors: &[(u32, Vec<(C, Pauli)>)]) {
+    let mut strings = vec![(c, Vec::new())];
+    for &(site, ref sum) in factors.iter() {
+        strings = strings.into_iter()
+                         .flat_map(|(c, f): (C, Vec<(u32, Pauli)>)| {
+                                       sum.iter().map(move |&(a, p)| {
+                                                     let mut f = f.clone();
+                                                     f.push((site, p));
+                                                     (c * a, f)
+                                                 })
+                                   })
+                         .collect();
+    }
+    for (c, f) in strings {
+        op.add(c, &f);
+    }
+}
+
+/// An nx by ny cluster with periodic boundaries
+#[derive(Clone, Copy, Debug)]
+pub struct Cluster {
+    pub nx: u32,
+    pub ny: u32
+}
+
+impl Cluster {
+    pub fn new(nx: u32, ny: u32) -> Cluster {
+        assert!(nx * ny <= MAX_SITES);
+        Cluster { nx, ny }
+    }
+
+    pub fn sites(&self) -> u32 { self.nx * self.ny }
+
+    /// The index of the site at (x, y), taken modulo the cluster
+    pub fn site(&self, x: i64, y: i64) -> u32 {
+        let x = x.rem_euclid(i64::from(self.nx)) as u32;
+        let y = y.rem_euclid(i64::from(self.ny)) as u32;
+        x + self.nx * y
+    }
+
+    /// The displacements from a site to its neighbours at range l, one of each
+    /// pair of opposite ones: the primitive vectors a1 = (1, 0), a2 = (-1, 1)
+    /// and a3 = (0, -1) for l = 1, their differences a1 - a2, a2 - a3 and
+    /// a3 - a1 for l = 2 and twice the primitive vectors for l = 3
+    pub fn shell(l: u32) -> Vec<(i64, i64)> {
+        let a = [(1, 0), (-1, 1), (0, -1)];
+        match l {
+            1 => a.to_vec(),
+            2 => (0..3).map(|m| {
+                            let (p, q) = (a[m], a[(m + 1) % 3]);
+                            (p.0 - q.0, p.1 - q.1)
+                        })
+                       .collect(),
+            3 => a.iter().map(|&(x, y)| (2 * x, 2 * y)).collect(),
+            _ => panic!("no bonds of range {}", l)
+        }
+    }
+
+    /// The bonds of range l as pairs of sites with the phase γ of the bond. A
+    /// pair of sites is bonded once, at the shortest range that joins them on
+    /// the cluster, and a site is not bonded to itself. γ is 1 along a1,
+    /// e^(2πi/3) along a2 and e^(-2πi/3) along a3, and e^(2πi/3) for the bonds
+    /// of range 2, which lie along none of them.
+    pub fn bonds(&self, l: u32) -> Vec<(u32, u32, C)> {
+        let mut bonds = Vec::new();
+        let mut shorter = Vec::new();
+        for m in 1..l {
+            shorter.extend(self.bonds(m).into_iter().map(|(i, j, _)| (i, j)));
+        }
+        for y in 0..i64::from(self.ny) {
+            for x in 0..i64::from(self.nx) {
+                for &(dx, dy) in Cluster::shell(l).iter() {
+                    let i = self.site(x, y);
+                    let j = self.site(x + dx, y + dy);
+                    let pair = (i.min(j), i.max(j));
+                    if i == j || shorter.contains(&pair)
+                       || bonds.iter().any(|&(a, b, _)| (a, b) == pair)
+                    {
+                        continue;
+                    }
+                    let angle = if dy == 0 {
+                        0.
+                    } else if dx == 0 {
+                        -2. * PI / 3.
+                    } else {
+                        2. * PI / 3.
+                    };
+                    bonds.push((pair.0, pair.1, C::from_polar(&1., &angle)));
+                }
+            }
+        }
+        bonds
+    }
+
+    /// The upright and the inverted triangle at each site, with their corners
+    /// clockwise: (r, r + a1, r - a3) and (r, r + a1, r + a1 + a3). Triangles
+    /// with a repeated corner are left out.
+    pub fn triangles(&self) -> Vec<(u32, u32, u32)> {
+        let mut triangles = Vec::new();
+        for y in 0..i64::from(self.ny) {
+            for x in 0..i64::from(self.nx) {
+                let up = (self.site(x, y), self.site(x + 1, y), self.site(x, y + 1));
+                let down = (self.site(x, y),
+                            self.site(x + 1, y),
+                            self.site(x + 1, y - 1));
+                for &(i, j, k) in [up, down].iter() {
+                    if i != j && j != k && k != i {
+                        triangles.push((i, j, k));
+                    }
+                }
+            }
+        }
+        triangles
+    }
+
+    /// The operator translating every spin by (tx, ty)
+    pub fn translate(&self, state: usize, tx: i64, ty: i64) -> usize {
+        let mut out = 0;
+        for y in 0..i64::from(self.ny) {
+            for x in 0..i64::from(self.nx) {
+                if state & 1 << self.site(x, y) != 0 {
+                    out |= 1 << self.site(x + tx, y + ty);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// The terms of the model, each written out from its definition. "l" is the
+/// range of the bonds and is ignored by the chiral term.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// Σ S^z_i S^z_j
+    Zz,
+    /// Σ S^x_i S^x_j + S^y_i S^y_j
+    Xy,
+    /// Σ γ_ij S^+_i S^+_j + γ*_ij S^-_i S^-_j
+    Ppmm,
+    /// i Σ over both orders of each bond of S^z_i (γ*_ij S^-_j - γ_ij S^+_j)
+    Pmz,
+    /// Σ over the triangles, their corners i, j, k clockwise, of
+    /// (S_i x S_j) . S_k
+    Chi
+}
+
+pub fn operator(cluster: Cluster, model: Model, l: u32) -> Operator {
+    let mut op = Operator::new(cluster.sites());
+    let half = C::new(0.5, 0.);
+    match model {
+        Model::Zz => {
+            for (i, j, _) in cluster.bonds(l) {
+                add_product(&mut op, ONE, &[(i, s_z()), (j, s_z())]);
+            }
+        }
+        Model::Xy => {
+            // (S^+ S^- + S^- S^+) / 2
+            for (i, j, _) in cluster.bonds(l) {
+                add_product(&mut op, half, &[(i, s_plus()), (j, s_minus())]);
+                add_product(&mut op, half, &[(i, s_minus()), (j, s_plus())]);
+            }
+        }
+        Model::Ppmm => {
+            for (i, j, g) in cluster.bonds(l) {
+                add_product(&mut op, g, &[(i, s_plus()), (j, s_plus())]);
+                add_product(&mut op, g.conj(), &[(i, s_minus()), (j, s_minus())]);
+            }
+        }
+        Model::Pmz => {
+            for (i, j, g) in cluster.bonds(l) {
+                for &(i, j) in [(i, j), (j, i)].iter() {
+                    let (g, g_conj) = (IM * g, IM * g.conj());
+                    add_product(&mut op, g_conj, &[(i, s_z()), (j, s_minus())]);
+                    add_product(&mut op, -g, &[(i, s_z()), (j, s_plus())]);
+                }
+            }
+        }
+        Model::Chi => {
+            // ε_abc S^a_i S^b_j S^c_k
+            let paulis = [Pauli::X, Pauli::Y, Pauli::Z];
+            for (i, j, k) in cluster.triangles() {
+                for a in 0..3 {
+                    for &(b, c, sign) in [((a + 1) % 3, (a + 2) % 3, 1.),
+                                          ((a + 2) % 3, (a + 1) % 3, -1.)]
+                                             .iter()
+                    {
+                        op.add(C::new(sign / 8., 0.),
+                               &[(i, paulis[a]), (j, paulis[b]), (k, paulis[c])]);
+                    }
+                }
+            }
+        }
+    }
+    op
+}
+
+/// The Heisenberg model Σ J S_i . S_j on the bonds (i, j, J) of "n" sites,
+/// for lattices whose bonds the tests list by hand
+pub fn heisenberg(n: u32, bonds: &[(u32, u32, f64)]) -> Operator {
+    let mut op = Operator::new(n);
+    for &(i, j, coupling) in bonds.iter() {
+        let (c, half) = (C::new(coupling, 0.), C::new(coupling / 2., 0.));
+        add_product(&mut op, c, &[(i, s_z()), (j, s_z())]);
+        add_product(&mut op, half, &[(i, s_plus()), (j, s_minus())]);
+        add_product(&mut op, half, &[(i, s_minus()), (j, s_plus())]);
+    }
+    op
+}
+
+/// An orthonormal basis of the sector of momentum (kx, ky) and, unless "nup"
+/// is None, of nup up spins: the images of the basis states under
+///   1 / N Σ_t e^(i k.t) T_t
+/// orthonormalized by Gram-Schmidt, where T_t moves every spin by tx sites
+/// along x and ty rows along -y and k.t = 2π (kx tx / nx + ky ty / ny). These
+/// are the signs the builders define their sectors with. The images are those
+/// of the basis states in "states", each of which has to have one, or of all
+/// basis states in ascending order if it is None, and are kept as long as they
+/// are independent of the ones before them.
+pub fn sector_basis(cluster: Cluster, kx: u32, ky: u32, nup: Option<u32>,
+                    states: Option<&[usize]>)
+                    -> Vec<Vec<C>> {
+    let dim = 1_usize << cluster.sites();
+    let all = (0..dim).filter(|s| nup.map(|n| s.count_ones() == n).unwrap_or(true))
+                      .collect::<Vec<_>>();
+    let states = states.unwrap_or(&all);
+    let n = f64::from(cluster.sites());
+    let mut basis: Vec<Vec<C>> = Vec::new();
+    for &s in states.iter() {
+        let mut v = vec![ZERO; dim];
+        for ty in 0..cluster.ny {
+            for tx in 0..cluster.nx {
+                let turns = f64::from(kx * tx) / f64::from(cluster.nx)
+                            + f64::from(ky * ty) / f64::from(cluster.ny);
+                let phase = C::from_polar(&1., &(2. * PI * turns));
+                v[cluster.translate(s, i64::from(tx), -i64::from(ty))] += phase / n;
+            }
+        }
+        for b in basis.iter() {
+            let overlap = dot(b, &v);
+            for (x, y) in v.iter_mut().zip(b.iter()) {
+                *x -= overlap * y;
+            }
+        }
+        let vnorm = norm(&v);
+        if vnorm > 1e-8 {
+            for x in v.iter_mut() {
+                *x /= vnorm;
+            }
+            basis.push(v);
+        } else {
+            assert!(states.len() == all.len(),
+                    "the state {:#x} has no component in the sector",
+                    s);
+        }
+    }
+    basis
+}
+
+/// The eigenvalues of the hermitian d x d matrix "a" (row-major) in ascending
+/// order, from cyclic Jacobi rotations of the real symmetric matrix
+/// [[re a, -im a], [im a, re a]], whose spectrum is that of "a" twice over
+pub fn eigvals(a: &[C], d: usize) -> Vec<f64> {
+    assert_eq!(a.len(), d * d);
+    let n = 2 * d;
+    let mut m = vec![0.; n * n];
+    for i in 0..d {
+        for j in 0..d {
+            let x = a[i * d + j];
+            m[i * n + j] = x.re;
+            m[(i + d) * n + j + d] = x.re;
+            m[(i + d) * n + j] = x.im;
+            m[i * n + j + d] = -x.im;
+        }
+    }
+    for _ in 0..100 {
+        let off = (0..n * n).filter(|k| k / n != k % n)
+                            .map(|k| m[k] * m[k])
+                            .sum::<f64>();
+        if off < 1e-26 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = m[p * n + q];
+                if apq == 0. {
+                    continue;
+                }
+                let theta = (m[q * n + q] - m[p * n + p]) / (2. * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+                let c = 1. / (t * t + 1.).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (mkp, mkq) = (m[k * n + p], m[k * n + q]);
+                    m[k * n + p] = c * mkp - s * mkq;
+                    m[k * n + q] = s * mkp + c * mkq;
+                }
+                for k in 0..n {
+                    let (mpk, mqk) = (m[p * n + k], m[q * n + k]);
+                    m[p * n + k] = c * mpk - s * mqk;
+                    m[q * n + k] = s * mpk + c * mqk;
+                }
+            }
+        }
+    }
+    let mut evals = (0..n).map(|i| m[i * n + i]).collect::<Vec<_>>();
+    evals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    evals.into_iter().step_by(2).collect()
+}
+
+/// Assert that the hermitian d x d matrices "a" and "b" (row-major) have the
+/// same eigenvalues to TOL
+pub fn assert_same_spectrum(a: &[C], b: &[C], d: usize) {
+    let (ea, eb) = (eigvals(a, d), eigvals(b, d));
+    for (n, (x, y)) in ea.iter().zip(eb.iter()).enumerate() {
+        assert!((x - y).abs() < TOL,
+                "eigenvalue {} is {} on one side and {} on the other",
+                n,
+                x,
+                y);
+    }
+}
+
+/// Assert that the d x d matrices "a" and "b" (row-major) are the same operator
+/// in two bases whose vectors differ only by a phase each, that is that
+/// a_ij = e^(-iφ_i) b_ij e^(iφ_j) for some phases φ, to TOL. The phases are
+/// fixed along the nonzero elements starting from the first vector of each
+/// connected block, and then every element is compared.
+pub fn assert_same_matrix_up_to_basis_phase(a: &[C], b: &[C], d: usize) {
+    assert_eq!(a.len(), d * d);
+    assert_eq!(b.len(), d * d);
+    let mut phases: Vec<Option<C>> = vec![None; d];
+    for start in 0..d {
+        if phases[start].is_some() {
+            continue;
+        }
+        phases[start] = Some(ONE);
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            let pi = phases[i].unwrap();
+            for j in 0..d {
+                let (x, y) = (a[i * d + j], b[i * d + j]);
+                if phases[j].is_some() || x.norm() < TOL || y.norm() < TOL {
+                    continue;
+                }
+                // x = pi* y pj
+                let pj = pi * x / y;
+                phases[j] = Some(pj / pj.norm());
+                stack.push(j);
+            }
+        }
+    }
+    for i in 0..d {
+        for j in 0..d {
+            let (pi, pj) = (phases[i].unwrap(), phases[j].unwrap());
+            let (x, y) = (a[i * d + j], pi.conj() * b[i * d + j] * pj);
+            assert!((x - y).norm() < TOL,
+                    "element ({}, {}) is {} on one side and {} on the other",
+                    i,
+                    j,
+                    x,
+                    y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blochfunc::Convention;
+    use common::*;
+    use consv;
+    use ops::{self, DenseSink};
+    use sitevector::{Periodicity, SiteOrdering, SitePermutation};
+
+    fn model(kind: TermKind) -> Model {
+        match kind {
+            TermKind::HSsZ | TermKind::SsZ => Model::Zz,
+            TermKind::HSsXy | TermKind::SsXy => Model::Xy,
+            TermKind::HSsPpmm => Model::Ppmm,
+            TermKind::HSsPmz => Model::Pmz,
+            TermKind::HSssChi => Model::Chi
+        }
+    }
+
+    /// The matrix the builders generate for "kind" on the basis of the sector
+    /// and the leading states of the basis
+    fn production(nx: u32, ny: u32, kx: u32, ky: u32, nup: Option<u32>,
+                  kind: TermKind, l: u32)
+                  -> (Vec<C>, Vec<usize>) {
+        let torus = LatticeSettings::default();
+        production_in(nx, ny, &torus, kx, ky, nup, kind, l)
+    }
+
+    // production on the lattice with "settings"
+    fn production_in(nx: u32, ny: u32, settings: &LatticeSettings, kx: u32,
+                     ky: u32, nup: Option<u32>, kind: TermKind, l: u32)
+                     -> (Vec<C>, Vec<usize>) {
+        let (nx, ny, kx, ky) = (Dim(nx), Dim(ny), K(kx), K(ky));
+        let plus = Convention::Plus;
+        let bfuncs = match nup {
+            Some(nup) => {
+                consv::ks::bloch_states_in(nx, ny, settings, plus, kx, ky, nup)
+                    .unwrap()
+            }
+            None => {
+                consv::k::bloch_states_in(nx, ny, settings, plus, kx, ky).unwrap()
+            }
+        };
+        let d = bfuncs.nonzero as usize;
+        let mut sink = DenseSink::new(d);
+        ops::term_into(&Term::new(kind, I(l as i32)), &bfuncs, &mut sink);
+        // the element generated in row i and column j is that of row j and
+        // column i (see DenseSink)
+        let a = (0..d * d).map(|n| sink.data[n % d * d + n / d])
+                          .map(|c| C::new(c.re, c.im))
+                          .collect();
+        let leads = bfuncs.data.iter().map(|b| b.lead.raw_int() as usize).collect();
+        (a, leads)
+    }
+
+    fn hermitian(a: &[C], d: usize) -> bool {
+        (0..d * d).all(|k| (a[k] - a[k % d * d + k / d].conj()).norm() < TOL)
+    }
+
+    #[test]
+    fn pauli_strings_are_kronecker_products() {
+        // σ^x σ^y σ^z on sites 0, 1, 2 from the Kronecker product of the
+        // matrices, with site 0 the least significant bit
+        let mut op = Operator::new(3);
+        op.add(ONE, &[(0, Pauli::X), (1, Pauli::Y), (2, Pauli::Z)]);
+        let bit = |s: usize, site: usize| 1 - (s >> site & 1);
+        for col in 0..8 {
+            let mut v = vec![ZERO; 8];
+            v[col] = ONE;
+            let w = op.apply(&v);
+            for (row, &x) in w.iter().enumerate() {
+                let paulis = [Pauli::X, Pauli::Y, Pauli::Z];
+                let kron = paulis.iter()
+                                 .enumerate()
+                                 .map(|(site, p)| {
+                                          p.matrix()[bit(row, site)][bit(col, site)]
+                                      })
+                                 .fold(ONE, |a, b| a * b);
+                assert_eq!(x, kron);
+            }
+        }
+    }
+
+    #[test]
+    fn spin_algebra() {
+        // S_1 . S_2 = 1/4 on the triplet of two spins and -3/4 on the
+        // singlet
+        let cluster = Cluster::new(2, 1);
+        let xy = operator(cluster, Model::Xy, 1);
+        let dot = operator(cluster, Model::Zz, 1).plus(xy);
+        let mut singlet = vec![ZERO; 4];
+        singlet[1] = C::new(0.5_f64.sqrt(), 0.);
+        singlet[2] = -singlet[1];
+        let triplet = [1, 0, 0, 0].iter()
+                                  .map(|&x| C::new(f64::from(x), 0.))
+                                  .collect::<Vec<_>>();
+        // on two sites every bond joins the same pair, which is bonded once
+        let w = dot.apply(&singlet);
+        for (x, y) in w.iter().zip(singlet.iter()) {
+            assert!((*x + *y * 0.75).norm() < TOL);
+        }
+        let w = dot.apply(&triplet);
+        for (x, y) in w.iter().zip(triplet.iter()) {
+            assert!((*x - *y * 0.25).norm() < TOL);
+        }
+        // the same on the bond listed by hand, written out densely
+        let listed = heisenberg(2, &[(0, 1, 1.)]).dense();
+        for (x, y) in listed.iter().zip(dot.dense().iter()) {
+            assert!((x - y).norm() < TOL);
+        }
+    }
+
+    #[test]
+    fn sector_bases_span_the_space() {
+        let cluster = Cluster::new(3, 2);
+        let mut total = 0;
+        for kx in 0..3 {
+            for ky in 0..2 {
+                for nup in 0..7 {
+                    total += sector_basis(cluster, kx, ky, Some(nup), None).len();
+                }
+            }
+        }
+        assert_eq!(total, 64);
+    }
+
+    #[test]
+    fn eigvals_of_known_matrices() {
+        // the Pauli matrices and a 3x3 with eigenvalues 0, 1, 3
+        for p in [Pauli::X, Pauli::Y, Pauli::Z].iter() {
+            let m = p.matrix();
+            let a = [m[0][0], m[0][1], m[1][0], m[1][1]];
+            let e = eigvals(&a, 2);
+            assert!((e[0] + 1.).abs() < TOL && (e[1] - 1.).abs() < TOL);
+        }
+        let a = [2., -1., 0., -1., 1., 0., 0., 0., 1.].iter()
+                                                   .map(|&x| C::new(x, 0.))
+                                                   .collect::<Vec<_>>();
+        let e = eigvals(&a, 3);
+        let s = 5_f64.sqrt();
+        for (x, y) in e.iter().zip([(3. - s) / 2., 1., (3. + s) / 2.].iter()) {
+            assert!((x - y).abs() < TOL);
+        }
+    }
+
+    #[test]
+    fn basis_phases_are_found() {
+        let a = vec![ONE, IM * 2., -IM * 2., ONE * 3.];
+        let p = C::from_polar(&1., &0.7);
+        let b = vec![a[0], a[1] * p, a[2] * p.conj(), a[3]];
+        assert_same_matrix_up_to_basis_phase(&a, &b, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "element")]
+    fn basis_phases_do_not_hide_a_conjugation() {
+        // the product of the elements around the loop 0 -> 1 -> 2 -> 0 is i,
+        // which no change of the phases of the basis vectors changes
+        let (o, l) = (ZERO, ONE);
+        let a = vec![o, l, l, l, o, IM, l, -IM, o];
+        let b = a.iter().map(|x| x.conj()).collect::<Vec<_>>();
+        assert_same_matrix_up_to_basis_phase(&a, &b, 3);
+    }
+
+    /// Compare the matrix of "kind" from the builders with the reference on
+    /// the basis of the production sector, whose vectors are those of the
+    /// reference up to a phase each
+    fn compare(nx: u32, ny: u32, kx: u32, ky: u32, nup: Option<u32>, kind: TermKind,
+               l: u32) {
+        let cluster = Cluster::new(nx, ny);
+        let (a, leads) = production(nx, ny, kx, ky, nup, kind, l);
+        let d = leads.len();
+        let basis = sector_basis(cluster, kx, ky, nup, Some(&leads));
+        assert_eq!(basis.len(), d);
+        let mut op = operator(cluster, model(kind), l);
+        if kind == TermKind::HSssChi {
+            // sss_chi_elements goes through the three cyclic orders of the
+            // corners of each triangle three times over
+            op = op.scale(3.);
+        }
+        let b = op.matrix_in(&basis);
+        assert!(hermitian(&b, d));
+        assert_same_spectrum(&a, &b, d);
+        assert_same_matrix_up_to_basis_phase(&a, &b, d);
+    }
+
+    const KINDS: [TermKind; 5] = [TermKind::HSsZ,
+                                  TermKind::HSsXy,
+                                  TermKind::HSsPpmm,
+                                  TermKind::HSsPmz,
+                                  TermKind::HSssChi];
+
+    fn ranges(kind: TermKind) -> Vec<u32> {
+        match kind {
+            TermKind::HSssChi => vec![0],
+            _ => vec![1, 2, 3]
+        }
+    }
+
+    #[test]
+    fn builders_match_reference_in_k_sectors() {
+        let sectors = [(3, 3, 1, 2), (3, 3, 0, 0), (4, 2, 1, 1), (2, 3, 1, 2)];
+        for &(nx, ny, kx, ky) in sectors.iter() {
+            for &kind in KINDS.iter() {
+                for l in ranges(kind) {
+                    compare(nx, ny, kx, ky, None, kind, l);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn builders_match_reference_in_ks_sectors() {
+        let sectors = [(3, 3, 1, 2, 4), (3, 3, 0, 0, 3), (4, 3, 1, 2, 5)];
+        for &(nx, ny, kx, ky, nup) in sectors.iter() {
+            for &kind in KINDS.iter().filter(|k| k.conserves_sz()) {
+                for l in ranges(kind) {
+                    compare(nx, ny, kx, ky, Some(nup), kind, l);
+                }
+            }
+        }
+    }
+
+    // the sectors are also built from every basis state, independently of the
+    // leading states the builders chose
+    #[test]
+    fn sector_dimensions_and_spectra_match() {
+        let (nx, ny, kx, ky) = (3, 3, 2, 1);
+        let cluster = Cluster::new(nx, ny);
+        for nup in 0..10 {
+            let (a, leads) =
+                production(nx, ny, kx, ky, Some(nup), TermKind::HSsXy, 1);
+            let basis = sector_basis(cluster, kx, ky, Some(nup), None);
+            assert_eq!(basis.len(), leads.len());
+            let xy = operator(cluster, Model::Xy, 1);
+            assert_same_spectrum(&a, &xy.matrix_in(&basis), leads.len());
+        }
+    }
+
+    // the order of the sites in the bits relabels the basis states and
+    // leaves the spectrum of every term in every sector as it was
+    #[test]
+    fn spectra_agree_across_orderings() {
+        let (nx, ny, nup) = (4, 3, 6);
+        let column_major = [0, 3, 6, 9, 1, 4, 7, 10, 2, 5, 8, 11];
+        let custom = SitePermutation::new(&column_major).unwrap();
+        let orderings = [SiteOrdering::Snake, SiteOrdering::Custom(custom)];
+        let terms = [(TermKind::HSsZ, 1),
+                     (TermKind::HSsXy, 1),
+                     (TermKind::HSsXy, 2),
+                     (TermKind::HSssChi, 0)];
+        for &(kx, ky) in [(0, 0), (1, 0), (2, 1)].iter() {
+            for &(kind, l) in terms.iter() {
+                let (a, leads) = production(nx, ny, kx, ky, Some(nup), kind, l);
+                let d = leads.len();
+                for &ordering in orderings.iter() {
+                    let settings = LatticeSettings { ordering,
+                                                     ..LatticeSettings::default() };
+                    let (b, others) =
+                        production_in(nx, ny, &settings, kx, ky, Some(nup), kind, l);
+                    assert_eq!(others.len(), d);
+                    assert!(hermitian(&b, d));
+                    assert_same_spectrum(&a, &b, d);
+                }
+            }
+        }
+    }
+
+    // the open 2x2 lattice is a rhombus of two triangles, whose five bonds
+    // give the Heisenberg model the levels (S (S + 1) - s (s + 1)) / 2 - 3/4,
+    // S the total spin and s that of the two corners off the shared bond
+    #[test]
+    fn open_two_by_two_spectrum() {
+        let open = LatticeSettings { periodicity: Periodicity { x: false,
+                                                                y: false },
+                                     ..LatticeSettings::default() };
+        let (zz, leads) = production_in(2, 2, &open, 0, 0, None, TermKind::HSsZ, 1);
+        let (xy, _) = production_in(2, 2, &open, 0, 0, None, TermKind::HSsXy, 1);
+        let d = leads.len();
+        assert_eq!(d, 16);
+        let h = zz.iter().zip(xy.iter()).map(|(a, b)| a + b).collect::<Vec<_>>();
+        assert!(hermitian(&h, d));
+        let mut expected = vec![-1.75];
+        for &(level, degeneracy) in [(-0.75, 7), (0.25, 3), (1.25, 5)].iter() {
+            expected.extend(vec![level; degeneracy]);
+        }
+        for (x, y) in eigvals(&h, d).iter().zip(expected.iter()) {
+            assert!((x - y).abs() < TOL);
+        }
+    }
+}
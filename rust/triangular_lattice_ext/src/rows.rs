@@ -0,0 +1,312 @@
+//! Rows of an operator pulled one at a time. A HamiltonianRows generates the
+//! elements of a row only when it is asked for the next one, into buffers it
+//! reuses from row to row. It sits between the builders that return a whole
+//! coordinate matrix and the ones that hand every element to a callback: only
+//! the basis and its lookup tables are held, and the basis may be kept on disk
+//! (see the diskbasis module).
+//!
+//! The rows are the ones the builders generate. As with the coordinate
+//! matrices, which are read as (data, (col, row)), the row of basis state i
+//! holds the elements of column i of the operator, which for a Hermitian
+//! operator are the conjugates of those of row i. Rows come in increasing
+//! order, and the contributions of all terms and bonds to the same column of a
+//! row are summed, with the columns in increasing order.
+use num_complex::Complex;
+use std::{borrow::Cow, sync::Arc};
+
+use blochfunc::{BlochFuncSet, OrbitTable};
+use common::*;
+use diskbasis::MappedBasis;
+use error::{Error, Result};
+use matfree::check_sz;
+use ops::{ElementSink, PreparedTerm, RowElements};
+
+/// The index of a row and its elements as (column, value) pairs
+pub type Row<'r> = (u32, &'r [(u32, Complex<f64>)]);
+
+pub struct HamiltonianRows<'a> {
+    table:   OrbitTable<'a>,
+    basis:   Basis<'a>,
+    terms:   Vec<PreparedTerm>,
+    // the index of the row in "row", None before the first one
+    current: Option<u32>,
+    // scratch space for the element functions
+    scratch: RowElements,
+    // the elements of the current row, summed over the terms
+    row:     RowElements
+}
+
+enum Basis<'a> {
+    Memory(&'a BlochFuncSet),
+    Mapped(Arc<MappedBasis>)
+}
+
+// Sums the elements the terms generate for a row
+struct RowSink<'b>(&'b mut RowElements);
+
+impl<'b> ElementSink for RowSink<'b> {
+    fn push(&mut self, _row: u32, col: u32, val: Complex<f64>) {
+        self.0.add(col, val);
+    }
+}
+
+impl<'a> HamiltonianRows<'a> {
+    /// The rows of the sum of "terms", weighted by their coefficients, on the
+    /// basis "bfuncs"
+    pub fn new(bfuncs: &'a BlochFuncSet, terms: &[Term]) -> HamiltonianRows<'a> {
+        let terms = terms.iter()
+                         .map(|&t| PreparedTerm::new(t, &bfuncs.tables))
+                         .collect::<Vec<_>>();
+        let table = if terms.iter().all(|t| t.is_diagonal()) {
+            OrbitTable::empty()
+        } else {
+            OrbitTable::new(bfuncs)
+        };
+        HamiltonianRows::with_table(table, Basis::Memory(bfuncs), terms)
+    }
+
+    fn with_table(table: OrbitTable<'a>, basis: Basis<'a>, terms: Vec<PreparedTerm>)
+                  -> HamiltonianRows<'a> {
+        HamiltonianRows { table,
+                          basis,
+                          terms,
+                          current: None,
+                          scratch: RowElements::new(),
+                          row: RowElements::new() }
+    }
+
+    /// The number of rows
+    pub fn dim(&self) -> u32 {
+        match self.basis {
+            Basis::Memory(bfuncs) => bfuncs.nonzero,
+            Basis::Mapped(ref basis) => basis.dim()
+        }
+    }
+
+    /// The next row, or None once all rows have been generated. The elements
+    /// are overwritten by the next call.
+    pub fn next_row(&mut self) -> Option<Row<'_>> {
+        let i = self.current.map_or(0, |i| i + 1);
+        if i >= self.dim() {
+            return None;
+        }
+        let orig_state = match self.basis {
+            Basis::Memory(bfuncs) => Cow::Borrowed(&bfuncs.data[i as usize]),
+            Basis::Mapped(ref basis) => Cow::Owned(basis.get(i))
+        };
+        self.row.clear();
+        for term in self.terms.iter() {
+            term.row_into(i,
+                          &orig_state,
+                          &self.table,
+                          &mut self.scratch,
+                          &mut RowSink(&mut self.row));
+        }
+        self.row.sort_by_column();
+        self.current = Some(i);
+        self.current_row()
+    }
+
+    /// The row last returned by next_row, None before the first call
+    pub fn current_row(&self) -> Option<Row<'_>> {
+        self.current.map(|i| (i, self.row.as_slice()))
+    }
+}
+
+impl HamiltonianRows<'static> {
+    /// The rows of the sum of "terms", weighted by their coefficients, on a
+    /// basis on disk. The rows keep the mapping alive.
+    pub fn mapped(basis: Arc<MappedBasis>, terms: &[Term])
+                  -> Result<HamiltonianRows<'static>> {
+        check_sz(terms)?;
+        let terms = terms.iter()
+                         .map(|&t| PreparedTerm::new(t, &basis.tables))
+                         .collect::<Vec<_>>();
+        let table = if terms.iter().all(|t| t.is_diagonal()) {
+            OrbitTable::empty()
+        } else {
+            OrbitTable::mapped(basis.clone())
+        };
+        Ok(HamiltonianRows::with_table(table, Basis::Mapped(basis), terms))
+    }
+}
+
+/// The exported row cursor. A row that does not fit into the buffers of the
+/// caller is kept until it has been read.
+pub struct RowCursor {
+    rows:    HamiltonianRows<'static>,
+    pending: bool
+}
+
+impl RowCursor {
+    pub fn new(rows: HamiltonianRows<'static>) -> RowCursor {
+        RowCursor { rows,
+                    pending: false }
+    }
+
+    /// Copy the next row into "cols" and "vals" and return its index and its
+    /// number of elements, or None once all rows have been read. Fails without
+    /// moving on if the row has more elements than "cols" or "vals" can hold.
+    pub fn next_into(&mut self, cols: &mut [u32], vals: &mut [Complex<f64>])
+                     -> Result<Option<(u32, usize)>> {
+        if !self.pending {
+            if self.rows.next_row().is_none() {
+                return Ok(None);
+            }
+            self.pending = true;
+        }
+        let (i, row) = self.rows.current_row().unwrap();
+        if row.len() > cols.len() || row.len() > vals.len() {
+            return Err(Error::InvalidArgument("cap"));
+        }
+        for (k, &(j, val)) in row.iter().enumerate() {
+            cols[k] = j;
+            vals[k] = val;
+        }
+        self.pending = false;
+        Ok(Some((i, row.len())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consv;
+    use error::{ERR_INVALID_ARGUMENT, SUCCESS};
+    use fnv::FnvHashMap;
+    use libc::c_char;
+    use std::{env, ffi::CString, fs, ptr};
+    use {basis_free, ks_basis_new_mmap, rows_free, rows_new, rows_next};
+
+    fn terms() -> [Term; 3] {
+        [Term { kind:  TermKind::HSsZ,
+                l:     I(1),
+                coeff: 1. },
+         Term { kind:  TermKind::HSsXy,
+                l:     I(1),
+                coeff: 0.5 },
+         Term { kind:  TermKind::HSssChi,
+                l:     I(1),
+                coeff: -0.3 }]
+    }
+
+    type Rows = Vec<(u32, Vec<(u32, Complex<f64>)>)>;
+
+    fn collect(rows: &mut HamiltonianRows) -> Rows {
+        let mut collected = Vec::new();
+        while let Some((i, row)) = rows.next_row() {
+            collected.push((i, row.to_vec()));
+        }
+        collected
+    }
+
+    #[test]
+    fn rows_match_coord_matrices() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let terms = terms();
+        let mut expected = FnvHashMap::default();
+        for term in terms.iter() {
+            let v = ::ops::term_vecs(term, &bfuncs);
+            for k in 0..v.data.len() {
+                let val = Complex::new(v.data[k].re, v.data[k].im);
+                *expected.entry((v.rows[k], v.cols[k]))
+                         .or_insert(Complex::new(0., 0.)) += val;
+            }
+        }
+
+        let mut rows = HamiltonianRows::new(&bfuncs, &terms);
+        assert!(rows.current_row().is_none());
+        let collected = collect(&mut rows);
+        assert!(rows.next_row().is_none());
+        assert_eq!(collected.len(), bfuncs.nonzero as usize);
+        let mut found = 0;
+        for (n, &(i, ref row)) in collected.iter().enumerate() {
+            assert_eq!(i, n as u32);
+            for &(j, val) in row.iter() {
+                assert!((val - expected[&(i, j)]).norm() < 1e-12);
+            }
+            found += row.len();
+        }
+        assert_eq!(found, expected.len());
+    }
+
+    #[test]
+    fn mapped_rows_match_memory() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(2), 6);
+        let path = env::temp_dir().join("spinsys_mapped_rows_4x3.bin");
+        let _ = fs::remove_file(&path);
+        let basis = MappedBasis::open_or_create(&path, nx, ny, kx, ky, nup).unwrap();
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let terms = terms();
+
+        let memory = collect(&mut HamiltonianRows::new(&bfuncs, &terms));
+        let mut rows = HamiltonianRows::mapped(Arc::new(basis), &terms).unwrap();
+        assert_eq!(collect(&mut rows), memory);
+
+        let ppmm = [Term::new(TermKind::HSsPpmm, I(1))];
+        let basis = MappedBasis::open_or_create(&path, nx, ny, kx, ky, nup).unwrap();
+        match HamiltonianRows::mapped(Arc::new(basis), &ppmm) {
+            Err(Error::InvalidTerm(_)) => (),
+            _ => panic!("expected a term that does not conserve Sz to be rejected")
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn row_cursor_over_ffi() {
+        let (nx, ny, kx, ky, nup) = (4, 3, 1, 2, 6);
+        let path = env::temp_dir().join("spinsys_row_cursor_4x3.bin");
+        let _ = fs::remove_file(&path);
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let bfuncs = consv::ks::bloch_states(Dim(nx), Dim(ny), K(kx), K(ky), nup)
+                         .unwrap();
+        let expected = collect(&mut HamiltonianRows::new(&bfuncs, &terms()));
+        let c_terms = [CTerm { kind:  0,
+                               l:     1,
+                               coeff: 1. },
+                       CTerm { kind:  1,
+                               l:     1,
+                               coeff: 0.5 },
+                       CTerm { kind:  4,
+                               l:     1,
+                               coeff: -0.3 }];
+
+        unsafe {
+            let basis = ks_basis_new_mmap(nx, ny, kx, ky, nup,
+                                          c_path.as_ptr() as *const c_char);
+            assert!(!basis.is_null());
+            let cursor = rows_new(basis, c_terms.as_ptr(), c_terms.len() as u32);
+            assert!(!cursor.is_null());
+            // the cursor keeps the mapping alive
+            basis_free(basis);
+
+            let dim = bfuncs.nonzero as usize;
+            let mut cols = vec![0; dim];
+            let mut vals = vec![CComplex { re: 0., im: 0. }; dim];
+            let (mut row, mut status) = (0_u64, 1);
+            for &(i, ref elements) in expected.iter() {
+                if elements.len() > 1 {
+                    // a row that does not fit is kept for the next call
+                    let n = rows_next(cursor, &mut row, cols.as_mut_ptr(),
+                                      vals.as_mut_ptr(), 1, &mut status);
+                    assert_eq!((n, status), (-1, ERR_INVALID_ARGUMENT));
+                }
+                let n = rows_next(cursor, &mut row, cols.as_mut_ptr(),
+                                  vals.as_mut_ptr(), dim as u64, &mut status);
+                assert_eq!((n, status, row), (elements.len() as i64, SUCCESS,
+                                              i as u64));
+                for (k, &(j, val)) in elements.iter().enumerate() {
+                    assert_eq!(cols[k], j);
+                    assert_eq!((vals[k].re, vals[k].im), (val.re, val.im));
+                }
+            }
+            status = 1;
+            let n = rows_next(cursor, ptr::null_mut(), cols.as_mut_ptr(),
+                              vals.as_mut_ptr(), dim as u64, &mut status);
+            assert_eq!((n, status), (-1, SUCCESS));
+            rows_free(cursor);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
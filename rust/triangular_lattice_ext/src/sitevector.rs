@@ -1,73 +1,818 @@
-use common::{Dim, I, PI};
+use common::{Dim, LatticeSettings, I, MAX_SITES, PI};
+use error::{Error, Result};
+use std::{
+    cmp::Ordering,
+    fmt,
+    mem,
+    sync::{Arc, Mutex}
+};
 
 #[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
 pub struct SiteVector {
-    x:  I,
-    y:  I,
-    nx: Dim,
-    ny: Dim
+    x:     I,
+    y:     I,
+    nx:    Dim,
+    ny:    Dim,
+    // the sites the rows move by along x across the boundary in y, see
+    // common::LatticeSettings
+    shift:    I,
+    // the bits of the sites in the configurations, see common::LatticeSettings
+    ordering: SiteOrdering
 }
 
 impl SiteVector {
-    pub fn lattice_index(&self) -> I { self.x + self.y * self.nx }
+    /// The bit of the site in the configurations, x + nx y unless the lattice
+    /// has another ordering (see common::LatticeSettings)
+    pub fn lattice_index(&self) -> I {
+        let (x, y) = (self.x.raw_int() as u32, self.y.raw_int() as u32);
+        I(self.ordering.position(x, y, self.nx) as i32)
+    }
 
+    /// The site after this one in the order of lattice_index, the first one
+    /// after the last
     pub fn next_site(&self) -> SiteVector {
-        let new_index = self.lattice_index() + I(1);
-        let new_x = new_index % self.nx;
-        let new_y = new_index / self.nx;
-        SiteVector { x: new_x,
-                     y: new_y,
+        let n = (self.nx * self.ny).raw_int();
+        self.site_at((self.lattice_index().raw_int() as u32 + 1) % n)
+    }
+
+    /// The site of this lattice with lattice index "index", which has to be
+    /// on the lattice
+    fn site_at(&self, index: u32) -> SiteVector {
+        let i = self.ordering.row_major_index(index, self.nx) as i32;
+        let nx = self.nx.raw_int() as i32;
+        SiteVector { x: I(i % nx),
+                     y: I(i / nx),
                      ..*self }
     }
 
+    /// The site at (x, y), taken modulo the lattice along each axis, on the
+    /// plain torus, see common::LatticeSettings
     pub fn new(ordered_pair: (I, I), nx: Dim, ny: Dim) -> SiteVector {
-        let x = ordered_pair.0;
-        let y = ordered_pair.1;
-        SiteVector { x, y, nx, ny }
+        SiteVector::new_in(ordered_pair, nx, ny, &LatticeSettings::default())
+    }
+
+    /// The site at (x, y) on the lattice with "settings", see new: each time y
+    /// is taken back across the boundary x moves by the shift
+    pub fn new_in(ordered_pair: (I, I), nx: Dim, ny: Dim,
+                  settings: &LatticeSettings)
+                  -> SiteVector {
+        let origin = SiteVector { x: I(0),
+                                  y: I(0),
+                                  nx,
+                                  ny,
+                                  shift: I(settings.shift(nx) as i32),
+                                  ordering: settings.ordering(nx, ny) };
+        origin.moved(ordered_pair.0, ordered_pair.1)
+    }
+
+    /// The site with the given index, see lattice_index. Fails with
+    /// InvalidArgument unless the index is in 0..nx * ny: an index off the
+    /// lattice comes from a malformed mask or bond list, which taking it
+    /// modulo the number of sites would turn into a wrong site rather than an
+    /// error.
+    pub fn from_index(index: I, nx: Dim, ny: Dim) -> Result<SiteVector> {
+        let n = i64::from((nx * ny).raw_int());
+        if !(0..n).contains(&i64::from(index.raw_int())) {
+            return Err(Error::InvalidArgument("site index"));
+        }
+        let origin = SiteVector::new((I(0), I(0)), nx, ny);
+        Ok(origin.site_at(index.raw_int() as u32))
+    }
+
+    /// This site moved by (dx, dy), by the shift along x for each time it
+    /// crosses the boundary in y. The sums are taken in 64 bits, where they
+    /// cannot overflow.
+    fn moved(&self, dx: I, dy: I) -> SiteVector {
+        let nx = i64::from(self.nx.raw_int());
+        let ny = i64::from(self.ny.raw_int());
+        let y = i64::from(self.y.raw_int()) + i64::from(dy.raw_int());
+        let wraps = y.div_euclid(ny);
+        let x = i64::from(self.x.raw_int()) + i64::from(dx.raw_int())
+                + wraps * i64::from(self.shift.raw_int());
+        SiteVector { x: I(x.rem_euclid(nx) as i32),
+                     y: I((y - wraps * ny) as i32),
+                     ..*self }
     }
 
-    pub fn from_index(index: I, nx: Dim, ny: Dim) -> SiteVector {
-        let x = index % nx;
-        let y = index / nx;
-        SiteVector { x, y, nx, ny }
+    /// Whether the hop by (dx, dy) from this site crosses the boundary only
+    /// along the axes where "periodicity" closes the lattice (see moved)
+    pub fn hop_allowed(&self, dx: i32, dy: i32, periodicity: Periodicity) -> bool {
+        let nx = i64::from(self.nx.raw_int());
+        let ny = i64::from(self.ny.raw_int());
+        let y = i64::from(self.y.raw_int()) + i64::from(dy);
+        let wraps_y = y.div_euclid(ny);
+        let x = i64::from(self.x.raw_int()) + i64::from(dx)
+                + wraps_y * i64::from(self.shift.raw_int());
+        periodicity.allows(x.div_euclid(nx) != 0, wraps_y != 0)
     }
 }
 
+impl fmt::Display for SiteVector {
+    /// The coordinates and, after a #, the lattice index
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "({}, {}) #{}",
+               self.x.raw_int(),
+               self.y.raw_int(),
+               self.lattice_index().raw_int())
+    }
+}
+
+impl SiteVector {
+    /// The sites of the plain nx by ny torus in the order of lattice_index
+    pub fn sites(nx: Dim, ny: Dim) -> Sites {
+        SiteVector::sites_in(nx, ny, &LatticeSettings::default())
+    }
+
+    /// The sites of the nx by ny lattice with "settings" in the order of
+    /// lattice_index
+    pub fn sites_in(nx: Dim, ny: Dim, settings: &LatticeSettings) -> Sites {
+        let origin = SiteVector::new_in((I(0), I(0)), nx, ny, settings);
+        Sites { next: origin.site_at(0),
+                left: (nx * ny).raw_int() as usize }
+    }
+}
+
+/// The sites of a lattice in the order of lattice_index, see SiteVector::sites
+pub struct Sites {
+    next: SiteVector,
+    left: usize
+}
+
+impl Iterator for Sites {
+    type Item = SiteVector;
+
+    fn next(&mut self) -> Option<SiteVector> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let vec = self.next.next_site();
+        Some(mem::replace(&mut self.next, vec))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.left, Some(self.left)) }
+}
+
+impl ExactSizeIterator for Sites {}
+
+/// "coord" moved by "stride" along a periodic axis of "n" sites: the Euclidean
+/// remainder of the sum, in 0..n for strides of either sign and any size. The
+/// sum is taken in 64 bits, where it cannot overflow.
+fn wrap(coord: I, stride: I, n: Dim) -> I {
+    let sum = i64::from(coord.raw_int()) + i64::from(stride.raw_int());
+    I(sum.rem_euclid(i64::from(n.raw_int())) as i32)
+}
+
 // periodic boundary conidtions
 impl SiteVector {
     pub fn xhop(&self, stride: I) -> SiteVector {
-        let mut new_x = self.x + stride;
-        if new_x < I(0) {
-            new_x += self.nx;
+        SiteVector { x: wrap(self.x, stride, self.nx),
+                     ..*self }
+    }
+
+    pub fn yhop(&self, stride: I) -> SiteVector { self.moved(I(0), stride) }
+}
+
+/// The three directions of the bonds of the triangular lattice, as the hops
+/// take them: a1 along x, a2 along -x + y and a3 along -y
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum BondDir {
+    A1 = 1,
+    A2 = 2,
+    A3 = 3
+}
+
+impl BondDir {
+    /// The direction as external callers take it: 1, 2 or 3 along a1, a2 or
+    /// a3 and -1, -2 or -3 against it, for "sign" 1 or -1
+    pub fn code(self, sign: i32) -> i32 { sign * self as i32 }
+}
+
+/// The primitive vectors of the lattice in cartesian coordinates, a1 along the
+/// x axis of the lattice and a2 along its y axis, in units of the lattice
+/// spacing. They place the sites in the plane and so decide which periodic
+/// image of a site is nearest. Which sites are bonded and the phases of the
+/// bonds follow from the connectivity of the triangular lattice, whatever the
+/// shape the vectors give it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LatticeGeometry {
+    pub a1: (f64, f64),
+    pub a2: (f64, f64)
+}
+
+impl Default for LatticeGeometry {
+    /// The triangular lattice, a1 at (1, 0) and a2 at (1/2, √3/2)
+    fn default() -> LatticeGeometry {
+        LatticeGeometry { a1: (1., 0.),
+                          a2: (0.5, 0.75_f64.sqrt()) }
+    }
+}
+
+impl LatticeGeometry {
+    /// Fails with InvalidArgument unless both vectors are finite and they span
+    /// the plane
+    pub fn new(a1: (f64, f64), a2: (f64, f64)) -> Result<LatticeGeometry> {
+        let finite = [a1.0, a1.1, a2.0, a2.1].iter().all(|c| c.is_finite());
+        let cross = a1.0 * a2.1 - a1.1 * a2.0;
+        let norms = a1.0.hypot(a1.1) * a2.0.hypot(a2.1);
+        if !finite || cross.abs() <= 1e-12 * norms {
+            return Err(Error::InvalidArgument("lattice vectors"));
         }
-        new_x %= self.nx;
-        SiteVector { x: new_x, ..*self }
+        Ok(LatticeGeometry { a1, a2 })
+    }
+
+    /// The cartesian coordinates of dx a1 + dy a2
+    pub fn cartesian(&self, dx: i32, dy: i32) -> (f64, f64) {
+        let (dx, dy) = (f64::from(dx), f64::from(dy));
+        (dx * self.a1.0 + dy * self.a2.0, dx * self.a1.1 + dy * self.a2.1)
+    }
+
+    /// The reciprocal vectors b1 and b2, with a_i·b_j = 2π δ_ij
+    pub fn reciprocal(&self) -> ((f64, f64), (f64, f64)) {
+        let (a1, a2) = (self.a1, self.a2);
+        let scale = 2. * PI / (a1.0 * a2.1 - a1.1 * a2.0);
+        ((scale * a2.1, -scale * a2.0), (-scale * a1.1, scale * a1.0))
+    }
+
+    /// The squared length of dx a1 + dy a2
+    fn length_sqr(&self, dx: i32, dy: i32) -> f64 {
+        let (x, y) = self.cartesian(dx, dy);
+        x * x + y * y
+    }
+
+    /// The smallest squared length of x a1 + y a2 for x^2 + y^2 = 1, the
+    /// smaller eigenvalue of the matrix of the scalar products of a1 and a2
+    fn min_stretch(&self) -> f64 {
+        let (a1, a2) = (self.a1, self.a2);
+        let g11 = a1.0 * a1.0 + a1.1 * a1.1;
+        let g12 = a1.0 * a2.0 + a1.1 * a2.1;
+        let g22 = a2.0 * a2.0 + a2.1 * a2.1;
+        (g11 + g22) / 2. - ((g11 - g22) * (g11 - g22) / 4. + g12 * g12).sqrt()
+    }
+}
+
+/// Which axes of the lattice close on themselves. Along an axis that does not
+/// the lattice has open ends: no bond or triangle crosses them and a
+/// translation along it is no symmetry. The default is the torus.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct Periodicity {
+    pub x: bool,
+    pub y: bool
+}
+
+impl Default for Periodicity {
+    fn default() -> Periodicity { Periodicity::TORUS }
+}
+
+impl Periodicity {
+    /// Periodic along both axes
+    pub const TORUS: Periodicity = Periodicity { x: true, y: true };
+
+    /// Whether the lattice is periodic along both axes
+    pub fn is_torus(&self) -> bool { self.x && self.y }
+
+    /// Whether a path that crosses the boundary along x if "crosses_x" and
+    /// along y if "crosses_y" stays within the lattice
+    pub fn allows(&self, crosses_x: bool, crosses_y: bool) -> bool {
+        (self.x || !crosses_x) && (self.y || !crosses_y)
     }
+}
+
+/// The order of the sites of a lattice in the bits of the configurations, which
+/// decides which sites a mask or a contiguous range of bits covers but none of
+/// the physics. The default is RowMajor.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
+pub enum SiteOrdering {
+    /// Site (x, y) at bit x + nx y
+    RowMajor,
+    /// Row by row as RowMajor, the odd rows right to left: site (x, y) at bit
+    /// nx - 1 - x + nx y for odd y, so that consecutive bits are always
+    /// neighbors
+    Snake,
+    /// The site with row-major index i at bit i of the permutation
+    Custom(SitePermutation)
+}
 
-    pub fn yhop(&self, stride: I) -> SiteVector {
-        let mut new_y = self.y + stride;
-        if new_y < I(0) {
-            new_y += self.ny;
+impl Default for SiteOrdering {
+    fn default() -> SiteOrdering { SiteOrdering::RowMajor }
+}
+
+impl SiteOrdering {
+    /// The ordering coded by "ordering": 0 for RowMajor, 1 for Snake and 2 for
+    /// the custom one of "permutation" (see SitePermutation::new), which the
+    /// others ignore
+    pub fn from_raw(ordering: u32, permutation: &[u32]) -> Result<SiteOrdering> {
+        match ordering {
+            0 => Ok(SiteOrdering::RowMajor),
+            1 => Ok(SiteOrdering::Snake),
+            2 => SitePermutation::new(permutation).map(SiteOrdering::Custom),
+            _ => Err(Error::InvalidArgument("site ordering"))
+        }
+    }
+
+    /// Whether the ordering is one of an nx by ny lattice, which for a custom
+    /// one means that it permutes nx * ny sites
+    pub fn fits(&self, nx: Dim, ny: Dim) -> bool {
+        match *self {
+            SiteOrdering::Custom(ref perm) => perm.len() == (nx * ny).raw_int(),
+            _ => true
         }
-        new_y %= self.ny;
-        SiteVector { y: new_y, ..*self }
+    }
+
+    pub fn is_row_major(&self) -> bool { *self == SiteOrdering::RowMajor }
+
+    /// The bit of site (x, y) on a lattice nx sites wide
+    pub fn position(&self, x: u32, y: u32, nx: Dim) -> u32 {
+        let nx = nx.raw_int();
+        match *self {
+            SiteOrdering::RowMajor => x + nx * y,
+            SiteOrdering::Snake if y % 2 == 1 => nx - 1 - x + nx * y,
+            SiteOrdering::Snake => x + nx * y,
+            SiteOrdering::Custom(ref perm) => perm.bit(x + nx * y)
+        }
+    }
+
+    /// The row-major index x + nx y of the site at bit "position" on a lattice
+    /// nx sites wide, the inverse of position
+    pub fn row_major_index(&self, position: u32, nx: Dim) -> u32 {
+        match *self {
+            // snake reverses the odd rows, which undoes itself
+            SiteOrdering::RowMajor | SiteOrdering::Snake => {
+                let (x, y) = (position % nx.raw_int(), position / nx.raw_int());
+                self.position(x, y, nx)
+            }
+            SiteOrdering::Custom(ref perm) => perm.index(position)
+        }
+    }
+
+    /// The bits of the sites of an nx by ny lattice in row-major order
+    pub fn positions(&self, nx: Dim, ny: Dim) -> Vec<u32> {
+        let n = nx.raw_int();
+        (0..(nx * ny).raw_int()).map(|i| self.position(i % n, i / n, nx))
+                                .collect()
     }
 }
 
-// for this specific model
-impl SiteVector {
-    pub fn angle_with(&self, other: &SiteVector) -> f64 {
-        // type casting is necessary or else subtraction might cause overflow
-        let dx = self.x - other.x;
-        let dy = self.y - other.y;
-        if dx == I(0) && dy != I(0) {
+/// A permutation of the sites of a lattice of up to MAX_SITES sites, the bit
+/// of the site with row-major index i at entry i. It is kept inline so that
+/// the ordering can be copied into every SiteVector.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
+pub struct SitePermutation {
+    len:  u32,
+    bits: [u8; MAX_SITES as usize]
+}
+
+impl SitePermutation {
+    /// The permutation that puts the site with row-major index i at bit
+    /// bits[i]. Fails with InvalidArgument unless "bits" holds every one of
+    /// 0..bits.len() once and there are at most MAX_SITES of them.
+    pub fn new(bits: &[u32]) -> Result<SitePermutation> {
+        if bits.len() > MAX_SITES as usize {
+            return Err(Error::InvalidArgument("site ordering"));
+        }
+        let mut seen = [false; MAX_SITES as usize];
+        let mut perm = SitePermutation { len:  bits.len() as u32,
+                                         bits: [0; MAX_SITES as usize] };
+        for (i, &bit) in bits.iter().enumerate() {
+            if bit as usize >= bits.len() || seen[bit as usize] {
+                return Err(Error::InvalidArgument("site ordering"));
+            }
+            seen[bit as usize] = true;
+            perm.bits[i] = bit as u8;
+        }
+        Ok(perm)
+    }
+
+    /// The number of sites permuted
+    pub fn len(&self) -> u32 { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// The bit of the site with row-major index "index"
+    pub fn bit(&self, index: u32) -> u32 { u32::from(self.bits[index as usize]) }
+
+    /// The row-major index of the site at bit "bit"
+    pub fn index(&self, bit: u32) -> u32 {
+        let bits = &self.bits[..self.len as usize];
+        let index = bits.iter().position(|&b| u32::from(b) == bit);
+        index.expect("bit beyond the permutation") as u32
+    }
+}
+
+/// The displacement of a site from another through the nearest of their
+/// periodic images, in units of the lattice vectors along x and y. The wrap
+/// counts are the number of lattice lengths taken off the difference of the
+/// stored coordinates along each axis, nonzero for bonds across the boundary.
+/// On a lattice with a shift (see common::LatticeSettings) each lattice length
+/// taken off along y also adds the shift along x.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Displacement {
+    pub dx:      i32,
+    pub dy:      i32,
+    pub wraps_x: i32,
+    pub wraps_y: i32
+}
+
+impl Displacement {
+    /// The squared length in units of the lattice spacing, the lattice vectors
+    /// along x and y being at 60 degrees
+    pub fn length_sqr(&self) -> i32 {
+        self.dx * self.dx + self.dx * self.dy + self.dy * self.dy
+    }
+
+    /// The displacement in cartesian coordinates, in units of the lattice
+    /// spacing, with the lattice vector along x at (1, 0) and the one along y
+    /// at (1/2, √3/2)
+    pub fn cartesian(&self) -> (f64, f64) {
+        self.cartesian_in(&LatticeGeometry::default())
+    }
+
+    /// The displacement in cartesian coordinates on a lattice of the given
+    /// geometry
+    pub fn cartesian_in(&self, geometry: &LatticeGeometry) -> (f64, f64) {
+        geometry.cartesian(self.dx, self.dy)
+    }
+
+    /// The angle defining the phase of a bond with this displacement: 0 along
+    /// a1, -2π/3 along a3 and 2π/3 along a2 and any other direction
+    pub fn angle(&self) -> f64 {
+        if self.dx == 0 && self.dy != 0 {
             -2. * PI / 3.
-        } else if dx != I(0) && dy == I(0) {
+        } else if self.dx != 0 && self.dy == 0 {
             0.
         } else {
             2. * PI / 3.
         }
     }
+}
+
+impl Displacement {
+    /// The sector of 60 degrees the displacement points into, numbered
+    /// counterclockwise from 0 for those from a1 up to -a3, so that a1, a2 and
+    /// a3 begin the even ones
+    fn sector(&self) -> i32 {
+        let (dx, dy) = (self.dx, self.dy);
+        if dx > 0 && dy >= 0 {
+            0
+        } else if dy > 0 && dx + dy > 0 {
+            1
+        } else if dy > 0 {
+            2
+        } else if dx < 0 && dy <= 0 {
+            3
+        } else if dx + dy < 0 {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn negated(&self) -> Displacement {
+        Displacement { dx:      -self.dx,
+                       dy:      -self.dy,
+                       wraps_x: -self.wraps_x,
+                       wraps_y: -self.wraps_y }
+    }
+}
+
+/// The order of displacements in a shell: those in the even sectors first and
+/// then counterclockwise from a1. Two different displacements of the same
+/// length are never in the same direction, so the order is total on a shell.
+fn shell_order(a: &Displacement, b: &Displacement) -> Ordering {
+    let cross = a.dx * b.dy - a.dy * b.dx;
+    (a.sector() % 2, a.sector()).cmp(&(b.sector() % 2, b.sector()))
+                                .then(0.cmp(&cross))
+}
+
+/// The squared lengths of the displacements between sites of the infinite
+/// lattice up to "max", in increasing order: the one of shell n at n - 1
+fn shell_lengths(max: i32) -> Vec<i32> {
+    let mut lengths = Vec::new();
+    for a in (0..).take_while(|a| a * a <= max) {
+        for b in 0..a + 1 {
+            let length = a * a + a * b + b * b;
+            if 0 < length && length <= max {
+                lengths.push(length);
+            }
+        }
+    }
+    lengths.sort();
+    lengths.dedup();
+    lengths
+}
+
+/// The shells of neighbors of the sites of an nx by ny lattice with a shift,
+/// see SiteVector::neighbors_in_shell, as displacements from a site
+struct Shells {
+    nx:         Dim,
+    ny:         Dim,
+    shift:      I,
+    /// The squared length of shell n at n - 1, up to the farthest shell on
+    /// the lattice
+    length_sqr: Vec<i32>,
+    /// The canonical half of shell n at n - 1, in order
+    half:       Vec<Vec<Displacement>>,
+    /// The rest of shell n at n - 1, in order
+    rest:       Vec<Vec<Displacement>>
+}
+
+impl Shells {
+    fn new(nx: Dim, ny: Dim, shift: I) -> Shells {
+        let origin = SiteVector { x: I(0),
+                                  y: I(0),
+                                  nx,
+                                  ny,
+                                  shift,
+                                  ordering: SiteOrdering::RowMajor };
+        let mut sites = Sites { next: origin.clone(),
+                                left: (nx * ny).raw_int() as usize };
+        sites.next();
+        // the nearest images of the sites from the origin that are in the
+        // canonical halves of their shells, each by the one first in the order
+        // of the shells, together with whether the site is its own opposite
+        let mut halves = Vec::new();
+        for vec in sites {
+            let images = vec.nearest_images(&origin);
+            let image = images.iter().cloned().min_by(shell_order).unwrap();
+            let opposite = images.iter()
+                                 .map(Displacement::negated)
+                                 .min_by(shell_order)
+                                 .unwrap();
+            let own_opposite = origin.moved(-vec.x, -vec.y) == vec;
+            if own_opposite || shell_order(&image, &opposite) == Ordering::Less {
+                halves.push((image, own_opposite));
+            }
+        }
+        let max = halves.iter().map(|h| h.0.length_sqr()).max().unwrap_or(0);
+        let length_sqr = shell_lengths(max);
+        let mut half = vec![Vec::new(); length_sqr.len()];
+        let mut rest = vec![Vec::new(); length_sqr.len()];
+        halves.sort_by(|a, b| shell_order(&a.0, &b.0));
+        for &(image, own_opposite) in halves.iter() {
+            let n = length_sqr.binary_search(&image.length_sqr()).unwrap();
+            half[n].push(image);
+            if !own_opposite {
+                rest[n].push(image.negated());
+            }
+        }
+        Shells { nx,
+                 ny,
+                 shift,
+                 length_sqr,
+                 half,
+                 rest }
+    }
+}
+
+/// Number of lattices whose shells shells keeps
+const SHELLS_CACHED: usize = 8;
+
+static SHELLS: Mutex<Vec<Arc<Shells>>> = Mutex::new(Vec::new());
+
+/// The shells of the nx by ny lattice with the given shift, kept for the last
+/// few lattices asked for as lattice_tables keeps its tables
+fn shells(nx: Dim, ny: Dim, shift: I) -> Arc<Shells> {
+    let mut cache = SHELLS.lock().unwrap();
+    let cached = |s: &Arc<Shells>| s.nx == nx && s.ny == ny && s.shift == shift;
+    if let Some(pos) = cache.iter().position(cached) {
+        // most recently used last
+        let shells = cache.remove(pos);
+        cache.push(shells.clone());
+        return shells;
+    }
+    let shells = Arc::new(Shells::new(nx, ny, shift));
+    if cache.len() == SHELLS_CACHED {
+        cache.remove(0);
+    }
+    cache.push(shells.clone());
+    shells
+}
+
+/// The distances of the shells of the nx by ny lattice with the given shift
+/// with their numbers of sites, see common::shell_distances
+pub fn shell_table(nx: Dim, ny: Dim, shift: u32) -> Vec<(f64, usize)> {
+    let shells = shells(nx, ny, I(shift as i32));
+    shells.length_sqr
+          .iter()
+          .zip(shells.half.iter().zip(shells.rest.iter()))
+          .map(|(&l, (half, rest))| {
+                   (f64::from(l).sqrt(), half.len() + rest.len())
+               })
+          .collect()
+}
+
+/// A shell of neighbors of the sites of a lattice, see common::lattice_shells
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shell {
+    /// The number of the shell, the range of the bonds between the sites in
+    /// the shell of each other (see common::generate_range_bonds)
+    pub range:        I,
+    /// The distance of the sites in the shell through their nearest images,
+    /// in units of the lattice spacing
+    pub distance:     f64,
+    /// The number of sites in the shell of any site
+    pub sites:        usize,
+    /// The displacement of the first site of the shell in the order of
+    /// SiteVector::neighbors_in_shell, the one nearest a1 counterclockwise
+    pub displacement: Displacement
+}
+
+/// The shells of neighbors of the sites of a lattice that hold any sites, in
+/// increasing order of distance, see common::lattice_shells
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShellTable {
+    pub shells: Vec<Shell>
+}
+
+impl ShellTable {
+    /// The shell of range "l", unless it holds no sites on the lattice
+    pub fn range(&self, l: I) -> Option<&Shell> {
+        self.shells.iter().find(|s| s.range == l)
+    }
+}
+
+/// The shells of the nx by ny lattice with the given shift that hold any
+/// sites, see common::lattice_shells
+pub fn shell_list(nx: Dim, ny: Dim, shift: u32) -> ShellTable {
+    let shells = shells(nx, ny, I(shift as i32));
+    let mut list = Vec::new();
+    for (n, &l) in shells.length_sqr.iter().enumerate() {
+        let (half, rest) = (&shells.half[n], &shells.rest[n]);
+        if let Some(&displacement) = half.first() {
+            list.push(Shell { range: I(n as i32 + 1),
+                              distance: f64::from(l).sqrt(),
+                              sites: half.len() + rest.len(),
+                              displacement });
+        }
+    }
+    ShellTable { shells: list }
+}
+
+// for this specific model
+impl SiteVector {
+    /// The shortest displacement of this site from "other" on the torus. Of
+    /// images at the same distance the stored coordinates win, then the eight
+    /// around them, with wraps_x and then wraps_y taken in the order 0, 1, -1,
+    /// and then the ones further out in increasing order of wraps_x and then
+    /// of wraps_y. Those further out are only nearer on a lattice much longer
+    /// than it is wide.
+    pub fn displacement_from(&self, other: &SiteVector) -> Displacement {
+        self.displacement_from_in(other, &LatticeGeometry::default())
+    }
+
+    /// The shortest displacement of this site from "other" on the torus with
+    /// the given geometry, see displacement_from
+    pub fn displacement_from_in(&self, other: &SiteVector,
+                                geometry: &LatticeGeometry)
+                                -> Displacement {
+        self.nearest_images_in(other, geometry)[0]
+    }
+
+    /// All the displacements of this site from "other" through their nearest
+    /// images, in the order displacement_from prefers them
+    pub fn nearest_images(&self, other: &SiteVector) -> Vec<Displacement> {
+        self.nearest_images_in(other, &LatticeGeometry::default())
+    }
+
+    /// All the displacements of this site from "other" through their nearest
+    /// images on the torus with the given geometry. Lengths within a relative
+    /// 1e-9 of each other count as the same, so that the rounding of the
+    /// cartesian coordinates does not decide between images as near.
+    pub fn nearest_images_in(&self, other: &SiteVector,
+                             geometry: &LatticeGeometry)
+                             -> Vec<Displacement> {
+        self.nearest_images_within(other, geometry, Periodicity::TORUS)
+    }
+
+    /// The shortest displacement of this site from "other" with the given
+    /// geometry through the images that "periodicity" allows, those across an
+    /// open end being left out (see displacement_from)
+    pub fn displacement_from_within(&self, other: &SiteVector,
+                                    geometry: &LatticeGeometry,
+                                    periodicity: Periodicity)
+                                    -> Displacement {
+        self.nearest_images_within(other, geometry, periodicity)[0]
+    }
+
+    /// All the displacements of this site from "other" through their nearest
+    /// images that "periodicity" allows, see nearest_images_in. Along an open
+    /// axis only the stored coordinates count.
+    pub fn nearest_images_within(&self, other: &SiteVector,
+                                 geometry: &LatticeGeometry,
+                                 periodicity: Periodicity)
+                                 -> Vec<Displacement> {
+        let (nx, ny) = (self.nx.raw_int() as i32, self.ny.raw_int() as i32);
+        let shift = self.shift.raw_int();
+        let dx = (self.x - other.x).raw_int();
+        let dy = (self.y - other.y).raw_int();
+        let image = |wraps_x: i32, wraps_y: i32| {
+            Displacement { dx: dx - wraps_x * nx + wraps_y * shift,
+                           dy: dy - wraps_y * ny,
+                           wraps_x,
+                           wraps_y }
+        };
+        let length_sqr = |d: &Displacement| geometry.length_sqr(d.dx, d.dy);
+        let consider = |nearest: &mut Vec<Displacement>, d: Displacement| {
+            if !periodicity.allows(d.wraps_x != 0, d.wraps_y != 0) {
+                return;
+            }
+            let (length, shortest) = (length_sqr(&d), length_sqr(&nearest[0]));
+            let tolerance = 1e-9 * shortest;
+            if length < shortest - tolerance {
+                *nearest = vec![d];
+            } else if length <= shortest + tolerance {
+                nearest.push(d);
+            }
+        };
+        let mut nearest = vec![image(0, 0)];
+        for &wraps_x in [0, 1, -1].iter() {
+            for &wraps_y in [0, 1, -1].iter() {
+                if wraps_x != 0 || wraps_y != 0 {
+                    consider(&mut nearest, image(wraps_x, wraps_y));
+                }
+            }
+        }
+        // the squared length is at least min_stretch (dx^2 + dy^2), so an
+        // image as near is less than "reach" away along either axis
+        let longest = length_sqr(&nearest[0]) * (1. + 1e-9);
+        let reach = (longest / geometry.min_stretch()).sqrt() as i32 + 1;
+        let max_y = (dy.abs() + reach) / ny + 1;
+        let max_x = (dx.abs() + reach + max_y * shift) / nx + 1;
+        for wraps_x in -max_x..max_x + 1 {
+            for wraps_y in -max_y..max_y + 1 {
+                if wraps_x.abs() > 1 || wraps_y.abs() > 1 {
+                    consider(&mut nearest, image(wraps_x, wraps_y));
+                }
+            }
+        }
+        nearest
+    }
+
+    /// The position of this site in the cell of the lattice with the given
+    /// geometry, in cartesian coordinates with the first site at the origin
+    pub fn position_in(&self, geometry: &LatticeGeometry) -> (f64, f64) {
+        geometry.cartesian(self.x.raw_int(), self.y.raw_int())
+    }
+
+    /// The shortest displacement from this site to "other" on the torus in
+    /// cartesian coordinates (see Displacement::cartesian), the nearest image
+    /// picked as displacement_from picks it
+    pub fn displacement_to(&self, other: &SiteVector) -> (f64, f64) {
+        other.displacement_from(self).cartesian()
+    }
+
+    /// The shortest displacement from this site to "other" on the torus with
+    /// the given geometry, in cartesian coordinates
+    pub fn displacement_to_in(&self, other: &SiteVector,
+                              geometry: &LatticeGeometry)
+                              -> (f64, f64) {
+        other.displacement_from_in(self, geometry).cartesian_in(geometry)
+    }
+
+    /// The distance between this site and the nearest image of "other", in
+    /// units of the lattice spacing
+    pub fn distance_to(&self, other: &SiteVector) -> f64 {
+        f64::from(other.displacement_from(self).length_sqr()).sqrt()
+    }
+
+    /// The distance between this site and the nearest image of "other" on the
+    /// torus with the given geometry
+    pub fn distance_to_in(&self, other: &SiteVector, geometry: &LatticeGeometry)
+                          -> f64 {
+        let (x, y) = self.displacement_to_in(other, geometry);
+        x.hypot(y)
+    }
+
+    /// The direction of the bond from this site to "other", with 1 for a bond
+    /// along it and -1 for one against it. The direction is that of the
+    /// displacement through the nearest image, as gamma takes it, so a bond
+    /// across the boundary has the direction of the hop that crosses it rather
+    /// than that of the difference of the coordinates. Fails with
+    /// InvalidArgument unless the displacement is a multiple of a1, a2 or a3,
+    /// as those of nearest and third neighbors are.
+    pub fn bond_direction(&self, other: &SiteVector) -> Result<(BondDir, i32)> {
+        let d = other.displacement_from(self);
+        let (dir, steps) = match (d.dx, d.dy) {
+            (0, 0) => return Err(Error::InvalidArgument("bond")),
+            (dx, 0) => (BondDir::A1, dx),
+            (0, dy) => (BondDir::A3, -dy),
+            (dx, dy) if dx + dy == 0 => (BondDir::A2, dy),
+            _ => return Err(Error::InvalidArgument("bond"))
+        };
+        Ok((dir, steps.signum()))
+    }
+
+    pub fn angle_with(&self, other: &SiteVector) -> f64 {
+        self.displacement_from(other).angle()
+    }
+
+    /// The angle of the bond to "other" (see Displacement::angle) through its
+    /// nearest image on the torus with the given geometry
+    pub fn angle_with_in(&self, other: &SiteVector, geometry: &LatticeGeometry)
+                         -> f64 {
+        self.displacement_from_in(other, geometry).angle()
+    }
 
     pub fn a1_hop(&self, stride: I) -> Option<SiteVector> {
         let vec = self.xhop(stride);
@@ -112,7 +857,7 @@ impl SiteVector {
     // this is a bit ugly. Perhaps clean this up a little when you have time
     pub fn b3_hop(&self, stride: I) -> Option<SiteVector> {
         let v = self.b1_hop(-stride);
-        let vec = match v {
+        match v {
             None => None,
             Some(vec) => match vec.b2_hop(-stride) {
                 None => None,
@@ -121,44 +866,887 @@ impl SiteVector {
                     false => Some(vec)
                 }
             }
-        };
-        vec
+        }
     }
 
-    pub fn _neighboring_sites(&self, strides: Vec<I>,
-                              funcs: Vec<fn(&SiteVector, I)
-                                   -> Option<SiteVector>>)
+    /// The sites in shell "shell" around this one: those whose nearest image
+    /// is at the shell-th distance between two sites of the infinite lattice,
+    /// 1, √3, 2, √7, 3 and so on. The shells are numbered on the infinite
+    /// lattice rather than by the distances that occur on the torus, so that a
+    /// shell is at the same distance on every lattice and the first three are
+    /// the bonds of ranges 1 to 3; on a small lattice a shell can be short of
+    /// sites or empty. A site is in one shell only, that of its nearest image.
+    ///
+    /// With "half" only the canonical half of the shell is listed: of the
+    /// opposite displacements d and -d the one whose nearest image points into
+    /// the sectors of 60 degrees after a1, a2 and a3, so that every pair of
+    /// sites in the shell is listed from one of its sites. A site that is its
+    /// own opposite, half the lattice away along both axes, is listed from
+    /// both. The sites come in the order of their displacements
+    /// counterclockwise from a1, the canonical half first and then the
+    /// opposites of the rest in the same order. There are no sites in shell 0
+    /// or in shells beyond the lattice.
+    ///
+    /// Along an axis that "periodicity" leaves open only the images on this
+    /// side of the open ends count (see open_neighbors).
+    pub fn neighbors_in_shell(&self, shell: u32, half: bool,
+                              periodicity: Periodicity)
                               -> Vec<SiteVector> {
+        if !periodicity.is_torus() {
+            return self.open_neighbors(shell, half, periodicity);
+        }
+        let shells = shells(self.nx, self.ny, self.shift);
+        let n = shell as usize;
+        if n == 0 || n > shells.length_sqr.len() {
+            return Vec::new();
+        }
+        let rest = if half { &[][..] } else { &shells.rest[n - 1][..] };
+        shells.half[n - 1].iter()
+                          .chain(rest.iter())
+                          .map(|d| self.xhop(I(d.dx)).yhop(I(d.dy)))
+                          .collect()
+    }
+
+    /// neighbors_in_shell on a lattice with open ends, which has no
+    /// translations to list the shells once for: the sites reached from this
+    /// one by a hop of the length of the shell that crosses no open end,
+    /// unless a shorter such hop reaches them too. With "half" a site is
+    /// listed if one of the hops that reach it is in the canonical half, so
+    /// that a pair reached both ways through a periodic axis is listed from
+    /// both of its sites. The sites come in the order of their hops, the
+    /// canonical half first, each half counterclockwise from a1.
+    fn open_neighbors(&self, shell: u32, half: bool, periodicity: Periodicity)
+                      -> Vec<SiteVector> {
+        if shell == 0 {
+            return Vec::new();
+        }
+        let n = shell as usize;
+        let mut max = 4 * shell as i32;
+        let mut lengths = shell_lengths(max);
+        while lengths.len() < n {
+            max *= 2;
+            lengths = shell_lengths(max);
+        }
+        let length_sqr = lengths[n - 1];
+        // |dx| and |dy| are at most 2 / √3 times the length
+        let reach = (f64::from(length_sqr) * 4. / 3.).sqrt() as i32 + 1;
+        let mut hops = Vec::new();
+        for dx in -reach..reach + 1 {
+            for dy in -reach..reach + 1 {
+                let d = Displacement { dx,
+                                       dy,
+                                       wraps_x: 0,
+                                       wraps_y: 0 };
+                let length = d.length_sqr();
+                if 0 < length && length <= length_sqr {
+                    hops.push(d);
+                }
+            }
+        }
+        hops.sort_by(|a, b| {
+                         a.length_sqr().cmp(&b.length_sqr()).then(shell_order(a, b))
+                     });
+
+        let mut reached = vec![self.clone()];
+        let mut neighbors = Vec::new();
+        for d in hops.iter() {
+            if !self.hop_allowed(d.dx, d.dy, periodicity) {
+                continue;
+            }
+            let site = self.xhop(I(d.dx)).yhop(I(d.dy));
+            if reached.contains(&site) {
+                continue;
+            }
+            if d.length_sqr() < length_sqr {
+                reached.push(site);
+            } else if !half || d.sector() % 2 == 0 {
+                reached.push(site.clone());
+                neighbors.push(site);
+            }
+        }
+        neighbors
+    }
+
+    /// The nearest neighbors, shell 1 (see neighbors_in_shell)
+    pub fn nearest_neighboring_sites(&self, all: bool, periodicity: Periodicity)
+                                     -> Vec<SiteVector> {
+        self.neighbors_in_shell(1, !all, periodicity)
+    }
+
+    /// The second neighbors, shell 2 (see neighbors_in_shell)
+    pub fn second_neighboring_sites(&self, all: bool, periodicity: Periodicity)
+                                    -> Vec<SiteVector> {
+        self.neighbors_in_shell(2, !all, periodicity)
+    }
+
+    /// The third neighbors, shell 3 (see neighbors_in_shell)
+    pub fn third_neighboring_sites(&self, all: bool, periodicity: Periodicity)
+                                   -> Vec<SiteVector> {
+        self.neighbors_in_shell(3, !all, periodicity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CTerm, LatticeSettings, TermKind};
+    use error;
+    use k_term_matrix;
+    use k_term_matrix_geometry;
+    use k_term_matrix_ordered;
+    use k_term_matrix_periodicity;
+    use k_term_matrix_shifted;
+    use ks_term_matrix;
+    use ks_term_matrix_geometry;
+    use ks_term_matrix_ordered;
+    use ks_term_matrix_periodicity;
+    use ks_term_matrix_shifted;
+    use std::ptr::null_mut;
+    use test_support::elements;
+
+    const TORUS: Periodicity = Periodicity::TORUS;
+
+    fn lattices() -> Vec<(Dim, Dim)> {
+        [(1, 1), (1, 5), (5, 1), (2, 8), (8, 2), (4, 3), (6, 6), (7, 9)]
+            .iter()
+            .map(|&(nx, ny)| (Dim(nx), Dim(ny)))
+            .collect()
+    }
+
+    // on the 6 x 3 lattice shifted by 1 a step along y off the top row lands
+    // one site further along x, and every site still has six neighbors
+    #[test]
+    fn shifted_boundary() {
+        let (nx, ny) = (Dim(6), Dim(3));
+        let shifted = LatticeSettings { shift: 1,
+                                        ..LatticeSettings::default() };
+        let site = |x, y| SiteVector::new_in((I(x), I(y)), nx, ny, &shifted);
+        for x in -7..8 {
+            for y in -4..4 {
+                assert_eq!(site(x, y + 3), site(x + 1, y));
+            }
+        }
+        let origin = site(0, 0);
+        assert_eq!(origin.yhop(I(3)), origin.xhop(I(1)));
+        assert_eq!(origin.yhop(I(-3)), origin.xhop(I(-1)));
+        assert_eq!(origin.yhop(I(-1)), site(5, 2));
+        let corner = site(5, 2);
+        assert_eq!(origin.displacement_from(&corner),
+                   Displacement { dx:      0,
+                                  dy:      1,
+                                  wraps_x: -1,
+                                  wraps_y: -1 });
+        let mut bonds = 0;
+        for site in SiteVector::sites_in(nx, ny, &shifted) {
+            let mut neighbors = site.nearest_neighboring_sites(true, TORUS);
+            for neighbor in neighbors.iter() {
+                assert_eq!(site.distance_to(neighbor), 1.);
+            }
+            neighbors.sort();
+            neighbors.dedup();
+            assert_eq!(neighbors.len(), 6);
+            bonds += site.nearest_neighboring_sites(false, TORUS).len();
+        }
+        assert_eq!(bonds, 54);
+        assert_eq!(SiteVector::new((I(0), I(3)), nx, ny),
+                   SiteVector::new((I(0), I(0)), nx, ny));
+    }
+
+    #[test]
+    fn hops_by_whole_lattices() {
+        for &(nx, ny) in lattices().iter() {
+            let (x, y) = (nx.raw_int() as i32, ny.raw_int() as i32);
+            for index in 0..x * y {
+                let vec = SiteVector::from_index(I(index), nx, ny).unwrap();
+                let loops = [x, -x, 2 * x, -2 * x, x * y, -x * y, 3 * x * y];
+                for &stride in loops.iter() {
+                    assert_eq!(vec.xhop(I(stride)), vec);
+                }
+                let loops = [y, -y, 2 * y, -2 * y, x * y, -x * y, 3 * x * y];
+                for &stride in loops.iter() {
+                    assert_eq!(vec.yhop(I(stride)), vec);
+                }
+                // one site on after any number of laps, in either direction
+                for &laps in [0, 1, -1, 2, -2, 5, -5].iter() {
+                    assert_eq!(vec.xhop(I(laps * x + 1)), vec.xhop(I(1)));
+                    assert_eq!(vec.xhop(I(laps * x - 1)), vec.xhop(I(-1)));
+                    assert_eq!(vec.yhop(I(laps * y + 1)), vec.yhop(I(1)));
+                    assert_eq!(vec.yhop(I(laps * y - 1)), vec.yhop(I(-1)));
+                }
+                assert_eq!(vec.xhop(I(-1)).xhop(I(1)), vec);
+                assert_eq!(vec.yhop(I(-1)).yhop(I(1)), vec);
+            }
+        }
+    }
+
+    #[test]
+    fn hops_by_extreme_strides() {
+        let (nx, ny) = (Dim(7), Dim(9));
+        let vec = SiteVector::new((I(3), I(4)), nx, ny);
+        for &stride in [i32::MAX, i32::MIN, i32::MAX - 1, i32::MIN + 1].iter() {
+            let x = (3 + i64::from(stride)).rem_euclid(7) as i32;
+            let y = (4 + i64::from(stride)).rem_euclid(9) as i32;
+            assert_eq!(vec.xhop(I(stride)), SiteVector::new((I(x), I(4)), nx, ny));
+            assert_eq!(vec.yhop(I(stride)), SiteVector::new((I(3), I(y)), nx, ny));
+        }
+    }
+
+    #[test]
+    fn coordinates_wrap() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        assert_eq!(SiteVector::new((I(-1), I(-4)), nx, ny),
+                   SiteVector::new((I(3), I(2)), nx, ny));
+        let mut vec = SiteVector::new((I(0), I(0)), nx, ny);
+        for index in 1..25 {
+            vec = vec.next_site();
+            assert_eq!(vec.lattice_index(), I(index % 12));
+        }
+    }
+
+    #[test]
+    fn sites_in_index_order() {
+        for &(nx, ny) in lattices().iter() {
+            let n = (nx * ny).raw_int() as usize;
+            let mut sites = SiteVector::sites(nx, ny);
+            for index in 0..n {
+                assert_eq!(sites.len(), n - index);
+                let vec = sites.next().unwrap();
+                assert_eq!(vec.lattice_index(), I(index as i32));
+                let expected = SiteVector::from_index(I(index as i32), nx, ny);
+                assert_eq!(vec, expected.unwrap());
+            }
+            assert_eq!(sites.len(), 0);
+            assert!(sites.next().is_none());
+            assert!(sites.next().is_none());
+        }
+    }
+
+    #[test]
+    fn indices_off_the_lattice_are_refused() {
+        for &(nx, ny) in lattices().iter() {
+            let n = (nx * ny).raw_int() as i32;
+            for index in 0..n {
+                let vec = SiteVector::from_index(I(index), nx, ny).unwrap();
+                assert_eq!(vec.lattice_index(), I(index));
+            }
+            for &index in [-1, n, n + 1, 2 * n, i32::MIN, i32::MAX].iter() {
+                match SiteVector::from_index(I(index), nx, ny) {
+                    Err(Error::InvalidArgument("site index")) => (),
+                    other => panic!("{}: {:?}", index, other)
+                }
+            }
+        }
+    }
+
+    // the bits of the sites, walked in order, under every kind of ordering;
+    // the custom one is column-major, site (x, y) at bit y + ny x
+    fn orderings(nx: Dim, ny: Dim) -> Vec<SiteOrdering> {
+        let (w, h) = (nx.raw_int(), ny.raw_int());
+        let column_major: Vec<u32> =
+            (0..w * h).map(|i| i / w + h * (i % w)).collect();
+        let custom = SitePermutation::new(&column_major).unwrap();
+        vec![SiteOrdering::RowMajor,
+             SiteOrdering::Snake,
+             SiteOrdering::Custom(custom)]
+    }
+
+    #[test]
+    fn orderings_permute_the_sites() {
+        for &(nx, ny) in lattices().iter() {
+            let n = (nx * ny).raw_int();
+            for &ordering in orderings(nx, ny).iter() {
+                let mut positions = ordering.positions(nx, ny);
+                for (index, &bit) in positions.iter().enumerate() {
+                    assert_eq!(ordering.row_major_index(bit, nx), index as u32);
+                }
+                positions.sort();
+                assert_eq!(positions, (0..n).collect::<Vec<_>>());
+                let settings = LatticeSettings { ordering,
+                                                 ..LatticeSettings::default() };
+                let mut sites = SiteVector::sites_in(nx, ny, &settings);
+                for index in 0..n as i32 {
+                    let vec = sites.next().unwrap();
+                    assert_eq!(vec.lattice_index(), I(index));
+                    let i = ordering.row_major_index(index as u32, nx) as i32;
+                    let (x, y) = (i % nx.raw_int() as i32, i / nx.raw_int() as i32);
+                    let expected = SiteVector::new_in((I(x), I(y)), nx, ny,
+                                                      &settings);
+                    assert_eq!(vec, expected);
+                    assert_eq!(vec.next_site().lattice_index(),
+                               I((index + 1) % n as i32));
+                }
+            }
+        }
+    }
+
+    // consecutive bits of a snake ordering are nearest neighbors, wherever
+    // the lattice is wide enough to tell
+    #[test]
+    fn snake_steps_to_neighbors() {
+        let (nx, ny) = (Dim(5), Dim(4));
+        let snake = LatticeSettings { ordering: SiteOrdering::Snake,
+                                      ..LatticeSettings::default() };
+        let mut vec = SiteVector::sites_in(nx, ny, &snake).next().unwrap();
+        for _ in 1..20 {
+            let next = vec.next_site();
+            assert_eq!(vec.distance_to(&next), 1.);
+            vec = next;
+        }
+    }
+
+    #[test]
+    fn display_shows_the_lattice_index() {
+        let (nx, ny) = (Dim(5), Dim(4));
+        let site = SiteVector::new((I(1), I(1)), nx, ny);
+        assert_eq!(site.to_string(), "(1, 1) #6");
+        let snake = LatticeSettings { ordering: SiteOrdering::Snake,
+                                      ..LatticeSettings::default() };
+        let site = SiteVector::new_in((I(1), I(1)), nx, ny, &snake);
+        assert_eq!(site.to_string(), "(1, 1) #8");
+    }
+
+    #[test]
+    fn invalid_orderings_are_refused() {
+        for bits in [&[0, 0, 1][..], &[0, 3, 1], &[1, 2]].iter() {
+            match SitePermutation::new(bits) {
+                Err(Error::InvalidArgument("site ordering")) => (),
+                other => panic!("{:?}: {:?}", bits, other)
+            }
+        }
+        let too_many: Vec<u32> = (0..MAX_SITES + 1).collect();
+        assert!(SitePermutation::new(&too_many).is_err());
+        assert_eq!(SiteOrdering::from_raw(0, &[5]).unwrap(),
+                   SiteOrdering::RowMajor);
+        assert_eq!(SiteOrdering::from_raw(1, &[]).unwrap(), SiteOrdering::Snake);
+        let custom = SiteOrdering::from_raw(2, &[2, 0, 1, 3]).unwrap();
+        assert!(custom.fits(Dim(2), Dim(2)) && !custom.fits(Dim(3), Dim(2)));
+        assert!(SiteOrdering::from_raw(2, &[0, 0]).is_err());
+        assert!(SiteOrdering::from_raw(3, &[]).is_err());
+    }
+
+    // random walks of large hops of either sign stay on the lattice and come
+    // back to where they started when retraced
+    #[test]
+    fn random_hops_retrace() {
+        // xorshift64*, as in lanczos::start_vector
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(2685821657736338717)
+        };
+        for &(nx, ny) in lattices().iter() {
+            let n = (nx * ny).raw_int() as i32;
+            for _ in 0..200 {
+                let index = (next() % n as u64) as i32;
+                let start = SiteVector::from_index(I(index), nx, ny).unwrap();
+                // strides in -3N..=3N
+                let span = 6 * n as u64 + 1;
+                let hops = (0..20).map(|_| {
+                                      let along_x = next() % 2 == 0;
+                                      let stride = (next() % span) as i32 - 3 * n;
+                                      (along_x, I(stride))
+                                  })
+                                  .collect::<Vec<_>>();
+                let hop = |vec: &SiteVector, along_x: bool, stride: I| {
+                    if along_x {
+                        vec.xhop(stride)
+                    } else {
+                        vec.yhop(stride)
+                    }
+                };
+                let mut vec = start.clone();
+                for &(along_x, stride) in hops.iter() {
+                    vec = hop(&vec, along_x, stride);
+                    let index = vec.lattice_index().raw_int();
+                    assert!(0 <= index && index < n);
+                }
+                for &(along_x, stride) in hops.iter().rev() {
+                    vec = hop(&vec, along_x, -stride);
+                }
+                assert_eq!(vec, start);
+            }
+        }
+    }
+
+    /// The displacements from the site with index "m" to the periodic images
+    /// of the site with index "n" with wraps up to "wraps" along each axis,
+    /// from the positions of the sites in the plane, the stored coordinates
+    /// first and then the images in the order displacement_from takes them
+    fn images(nx: Dim, ny: Dim, m: i32, n: i32, wraps: i32) -> Vec<(f64, f64)> {
+        let (nx, ny) = (nx.raw_int() as i32, ny.raw_int() as i32);
+        let position = |x: i32, y: i32| {
+            (f64::from(x) + f64::from(y) / 2., f64::from(y) * 0.75_f64.sqrt())
+        };
+        let origin = position(m % nx, m / nx);
+        let mut order = Vec::new();
+        for &a in [0, -1, 1].iter() {
+            for &b in [0, -1, 1].iter() {
+                order.push((a, b));
+            }
+        }
+        for a in -wraps..wraps + 1 {
+            for b in -wraps..wraps + 1 {
+                if a.abs() > 1 || b.abs() > 1 {
+                    order.push((a, b));
+                }
+            }
+        }
+        // the wraps of displacement_from are taken off the displacement, which
+        // puts the image of n at -1 times them
+        order.iter()
+             .map(|&(a, b)| {
+                      let image = position(n % nx + a * nx, n / nx + b * ny);
+                      (image.0 - origin.0, image.1 - origin.1)
+                  })
+             .collect()
+    }
+
+    /// The first of the nearest of "images"
+    fn nearest(images: &[(f64, f64)]) -> (f64, f64) {
+        let norm = |d: &(f64, f64)| d.0 * d.0 + d.1 * d.1;
+        images.iter().fold(images[0], |nearest, d| {
+                               if norm(d) < norm(&nearest) - 1e-9 {
+                                   *d
+                               } else {
+                                   nearest
+                               }
+                           })
+    }
+
+    #[test]
+    fn displacements_match_nearest_images() {
+        let (nx, ny) = (Dim(4), Dim(4));
+        for m in 0..16 {
+            let vec1 = SiteVector::from_index(I(m), nx, ny).unwrap();
+            for n in 0..16 {
+                let vec2 = SiteVector::from_index(I(n), nx, ny).unwrap();
+                let images = images(nx, ny, m, n, 1);
+                let (x, y) = vec1.displacement_to(&vec2);
+                let expected = nearest(&images);
+                let deviation = (x - expected.0).abs().max((y - expected.1).abs());
+                assert!(deviation < 1e-12,
+                        "{} to {}: {:?} rather than {:?}",
+                        m,
+                        n,
+                        (x, y),
+                        expected);
+                let distance = (x * x + y * y).sqrt();
+                assert!((vec1.distance_to(&vec2) - distance).abs() < 1e-12);
+                assert_eq!(vec1.distance_to(&vec2), vec2.distance_to(&vec1));
+            }
+        }
+    }
+
+    // on lattices much longer than wide the nearest image can lie beyond the
+    // eight around the stored coordinates
+    #[test]
+    fn displacements_on_long_lattices() {
+        let mut beyond = 0;
+        for &(nx, ny) in [(2, 12), (12, 2), (1, 9), (3, 16)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let n = (nx * ny).raw_int() as i32;
+            for m in 0..n {
+                let vec1 = SiteVector::from_index(I(m), nx, ny).unwrap();
+                for k in 0..n {
+                    let vec2 = SiteVector::from_index(I(k), nx, ny).unwrap();
+                    let images = images(nx, ny, m, k, 16);
+                    let norm = |d: (f64, f64)| (d.0 * d.0 + d.1 * d.1).sqrt();
+                    let distance = norm(nearest(&images));
+                    assert!((vec1.distance_to(&vec2) - distance).abs() < 1e-12);
+                    if norm(nearest(&images[..9])) > distance + 1e-9 {
+                        beyond += 1;
+                    }
+                }
+            }
+        }
+        assert!(beyond > 0);
+    }
+
+    // the neighbors as they were generated by hops along the lattice vectors,
+    // the hops of every stride in turn
+    fn neighbors_by_hops(vec: &SiteVector, shell: u32, all: bool)
+                         -> Vec<SiteVector> {
+        let (hops, stride) = match shell {
+            1 => ([(1, 0), (-1, 1), (0, -1)], 1),
+            2 => ([(1, 1), (-2, 1), (1, -2)], 1),
+            _ => ([(1, 0), (-1, 1), (0, -1)], 2)
+        };
+        let strides = if all { vec![stride, -stride] } else { vec![stride] };
         let mut neighbors = Vec::new();
         for &stride in strides.iter() {
-            for &func in funcs.iter() {
-                match func(self, stride) {
-                    Some(vec) => neighbors.push(vec),
-                    None => ()
+            for &(dx, dy) in hops.iter() {
+                let n = vec.xhop(I(dx * stride)).yhop(I(dy * stride));
+                if n != *vec {
+                    neighbors.push(n);
                 }
             }
         }
         neighbors
     }
 
-    pub fn nearest_neighboring_sites(&self, all: bool) -> Vec<SiteVector> {
-        let strides = if all { vec![I(1), I(-1)] } else { vec![I(1)] };
-        let funcs: Vec<fn(&SiteVector, I) -> Option<SiteVector>> =
-            vec![SiteVector::a1_hop, SiteVector::a2_hop, SiteVector::a3_hop];
-        SiteVector::_neighboring_sites(&self, strides, funcs)
+    // on lattices large enough for the hops to lead to different sites at
+    // their distance, the first three shells are the neighbors the hops led to
+    #[test]
+    fn first_shells_match_hops() {
+        for &(nx, ny) in [(5, 5), (6, 6), (7, 9), (8, 5), (6, 12)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let n = (nx * ny).raw_int() as i32;
+            for index in 0..n {
+                let vec = SiteVector::from_index(I(index), nx, ny).unwrap();
+                for &all in [false, true].iter() {
+                    assert_eq!(vec.nearest_neighboring_sites(all, TORUS),
+                               neighbors_by_hops(&vec, 1, all));
+                    assert_eq!(vec.second_neighboring_sites(all, TORUS),
+                               neighbors_by_hops(&vec, 2, all));
+                    assert_eq!(vec.third_neighboring_sites(all, TORUS),
+                               neighbors_by_hops(&vec, 3, all));
+                }
+            }
+        }
     }
 
-    pub fn second_neighboring_sites(&self, all: bool) -> Vec<SiteVector> {
-        let strides = if all { vec![I(1), I(-1)] } else { vec![I(1)] };
-        let funcs: Vec<fn(&SiteVector, I) -> Option<SiteVector>> =
-            vec![SiteVector::b1_hop, SiteVector::b2_hop, SiteVector::b3_hop];
-        SiteVector::_neighboring_sites(&self, strides, funcs)
+    #[test]
+    fn shells_of_a_large_lattice() {
+        let shells = shell_table(Dim(12), Dim(12), 0);
+        let lengths = [1, 3, 4, 7, 9, 12, 13, 16];
+        let counts = [6, 6, 6, 12, 6, 6, 12, 6];
+        for n in 0..lengths.len() {
+            assert!((shells[n].0 - f64::from(lengths[n]).sqrt()).abs() < 1e-12);
+            assert_eq!(shells[n].1, counts[n]);
+        }
+        // every site but the first in one shell
+        assert_eq!(shells.iter().map(|s| s.1).sum::<usize>(), 143);
     }
 
-    pub fn third_neighboring_sites(&self, all: bool) -> Vec<SiteVector> {
-        let strides = if all { vec![I(2), I(-2)] } else { vec![I(2)] };
-        let funcs: Vec<fn(&SiteVector, I) -> Option<SiteVector>> =
-            vec![SiteVector::a1_hop, SiteVector::a2_hop, SiteVector::a3_hop];
-        SiteVector::_neighboring_sites(&self, strides, funcs)
+    // a shell holds the sites at its distance, and its canonical halves list
+    // every pair of them
+    #[test]
+    fn shells_hold_the_sites_at_their_distance() {
+        let mut all_lattices = lattices();
+        all_lattices.extend([(3, 3), (2, 4), (4, 2), (4, 4), (3, 16), (6, 5)]
+                                .iter()
+                                .map(|&(nx, ny)| (Dim(nx), Dim(ny))));
+        for &(nx, ny) in all_lattices.iter() {
+            let n = (nx * ny).raw_int() as i32;
+            let sites = (0..n).map(|i| SiteVector::from_index(I(i), nx, ny).unwrap())
+                              .collect::<Vec<_>>();
+            let shells = shell_table(nx, ny, 0);
+            assert_eq!(shells.iter().map(|s| s.1).sum::<usize>(), n as usize - 1);
+            assert!(sites[0].neighbors_in_shell(0, false, TORUS).is_empty());
+            let beyond = shells.len() as u32 + 1;
+            assert!(sites[0].neighbors_in_shell(beyond, false, TORUS).is_empty());
+            for (shell, &(distance, count)) in shells.iter().enumerate() {
+                let shell = shell as u32 + 1;
+                let mut pairs_from_halves = Vec::new();
+                let mut pairs = Vec::new();
+                for vec in sites.iter() {
+                    let mut full = vec.neighbors_in_shell(shell, false, TORUS);
+                    let half = vec.neighbors_in_shell(shell, true, TORUS);
+                    assert_eq!(full[..half.len()], half[..]);
+                    assert_eq!(full.len(), count);
+                    pairs_from_halves.extend(half.iter()
+                                                 .map(|other| pair(vec, other)));
+                    pairs.extend(full.iter().map(|other| pair(vec, other)));
+                    let at_distance = |other: &&SiteVector| {
+                        *other != vec
+                        && (vec.distance_to(other) - distance).abs() < 1e-9
+                    };
+                    let mut expected = sites.iter()
+                                            .filter(at_distance)
+                                            .cloned()
+                                            .collect::<Vec<_>>();
+                    full.sort();
+                    expected.sort();
+                    assert_eq!(full, expected);
+                }
+                pairs_from_halves.sort();
+                pairs_from_halves.dedup();
+                pairs.sort();
+                pairs.dedup();
+                assert_eq!(pairs_from_halves, pairs);
+            }
+        }
+    }
+
+    /// The indices of two sites in increasing order
+    fn pair(a: &SiteVector, b: &SiteVector) -> (I, I) {
+        let (a, b) = (a.lattice_index(), b.lattice_index());
+        if a < b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    #[test]
+    fn geometries_must_span_the_plane() {
+        let nan = f64::NAN;
+        let inf = f64::INFINITY;
+        for &(a1, a2) in [((1., 0.), (2., 0.)),
+                          ((1., 1.), (-3., -3.)),
+                          ((0., 0.), (0., 1.)),
+                          ((1., nan), (0., 1.)),
+                          ((1., 0.), (inf, 1.))]
+                             .iter()
+        {
+            match LatticeGeometry::new(a1, a2) {
+                Err(Error::InvalidArgument("lattice vectors")) => (),
+                other => panic!("{:?} and {:?}: {:?}", a1, a2, other)
+            }
+        }
+        let geometry = LatticeGeometry::new((1., 0.), (0.5, 0.75_f64.sqrt()));
+        assert_eq!(geometry.unwrap(), LatticeGeometry::default());
+    }
+
+    #[test]
+    fn default_geometry_is_triangular() {
+        let geometry = LatticeGeometry::default();
+        for dx in -6..7 {
+            for dy in -6..7 {
+                let d = Displacement { dx,
+                                       dy,
+                                       wraps_x: 0,
+                                       wraps_y: 0 };
+                let (x, y) = (f64::from(dx), f64::from(dy));
+                // the very same floating point numbers as before the geometry
+                assert_eq!(d.cartesian_in(&geometry),
+                           (x + y / 2., y * 0.75_f64.sqrt()));
+                let (x, y) = d.cartesian();
+                let length_sqr = f64::from(d.length_sqr());
+                let deviation = (x * x + y * y - length_sqr).abs();
+                assert!(deviation < 1e-12 * (1. + length_sqr));
+            }
+        }
+    }
+
+    // the nearest images on lattices of other shapes are those nearest in the
+    // plane, however far out
+    #[test]
+    fn nearest_images_follow_the_geometry() {
+        let geometries = [LatticeGeometry::default(),
+                          LatticeGeometry::new((1., 0.), (0., 1.)).unwrap(),
+                          LatticeGeometry::new((1., 0.), (0.5, 2.)).unwrap(),
+                          LatticeGeometry::new((1., 0.), (3.5, 0.8)).unwrap()];
+        for geometry in geometries.iter() {
+            for &(nx, ny) in [(4, 4), (5, 3), (2, 12), (12, 2)].iter() {
+                let (nx, ny) = (Dim(nx), Dim(ny));
+                let (x, y) = (nx.raw_int() as i32, ny.raw_int() as i32);
+                for m in 0..x * y {
+                    let vec1 = SiteVector::from_index(I(m), nx, ny).unwrap();
+                    for n in 0..x * y {
+                        let vec2 = SiteVector::from_index(I(n), nx, ny).unwrap();
+                        let images = vec2.nearest_images_in(&vec1, geometry);
+                        // all images with wraps up to 16 along either axis
+                        let (dx, dy) = (n % x - m % x, n / x - m / x);
+                        let mut lengths = Vec::new();
+                        for wraps_x in -16..17 {
+                            for wraps_y in -16..17 {
+                                let (x, y) = geometry.cartesian(dx - wraps_x * x,
+                                                                dy - wraps_y * y);
+                                lengths.push(x * x + y * y);
+                            }
+                        }
+                        let shortest = lengths.iter().cloned().fold(1e300, f64::min);
+                        let tolerance = 1e-9 * shortest;
+                        let as_near = lengths.iter()
+                                             .filter(|&&l| l <= shortest + tolerance)
+                                             .count();
+                        assert_eq!(images.len(), as_near);
+                        for d in images.iter() {
+                            let (x, y) = d.cartesian_in(geometry);
+                            assert!((x * x + y * y - shortest).abs() <= tolerance);
+                        }
+                        let distance = vec1.distance_to_in(&vec2, geometry);
+                        assert!((distance - shortest.sqrt()).abs() < 1e-9);
+                        assert_eq!(vec1.displacement_to_in(&vec2, geometry),
+                                   images[0].cartesian_in(geometry));
+                    }
+                }
+            }
+        }
+    }
+
+    // every nearest-neighbor bond, those across the boundary included, has the
+    // direction of the hop that makes it even where the difference of the
+    // coordinates points elsewhere
+    #[test]
+    fn bond_directions_across_the_boundary() {
+        let hops = [(BondDir::A1, (1, 0)),
+                    (BondDir::A2, (-1, 1)),
+                    (BondDir::A3, (0, -1))];
+        for &(nx, ny) in [(4, 4), (3, 5)].iter() {
+            let (nx, ny) = (Dim(nx), Dim(ny));
+            let n = (nx * ny).raw_int() as i32;
+            let mut misleading = 0;
+            for index in 0..n {
+                let vec = SiteVector::from_index(I(index), nx, ny).unwrap();
+                for &(dir, (dx, dy)) in hops.iter() {
+                    for &sign in [1, -1].iter() {
+                        let other = vec.xhop(I(sign * dx)).yhop(I(sign * dy));
+                        assert_eq!(vec.bond_direction(&other).unwrap(), (dir, sign));
+                        assert_eq!(other.bond_direction(&vec).unwrap(),
+                                   (dir, -sign));
+                        let naive = ((other.x - vec.x).raw_int(),
+                                     (other.y - vec.y).raw_int());
+                        if naive != (sign * dx, sign * dy) {
+                            misleading += 1;
+                        }
+                    }
+                }
+            }
+            // y bonds along a1 and x along a3 cross the boundary, and x + y - 1
+            // along a2, each taken from both ends
+            let (x, y) = (nx.raw_int() as i32, ny.raw_int() as i32);
+            assert_eq!(misleading, 2 * (y + x + (x + y - 1)));
+        }
+    }
+
+    #[test]
+    fn bond_directions_of_other_displacements() {
+        let (nx, ny) = (Dim(6), Dim(6));
+        let vec = SiteVector::new((I(5), I(0)), nx, ny);
+        // third neighbors lie along the same directions
+        let third = vec.xhop(I(2));
+        assert_eq!(vec.bond_direction(&third).unwrap(), (BondDir::A1, 1));
+        let third = vec.xhop(I(2)).yhop(I(-2));
+        assert_eq!(vec.bond_direction(&third).unwrap(), (BondDir::A2, -1));
+        assert_eq!(BondDir::A2.code(-1), -2);
+        for &(dx, dy) in [(0, 0), (1, 1), (-2, 1), (1, -2), (2, 1)].iter() {
+            let other = vec.xhop(I(dx)).yhop(I(dy));
+            match vec.bond_direction(&other) {
+                Err(Error::InvalidArgument("bond")) => (),
+                other => panic!("{:?}: {:?}", (dx, dy), other)
+            }
+        }
+    }
+
+    #[test]
+    fn geometry_variants() {
+        let ppmm = CTerm { kind:  TermKind::HSsPpmm as u32,
+                           l:     1,
+                           coeff: 1. };
+        let h = 0.75_f64.sqrt();
+        let none = null_mut();
+        unsafe {
+            let triangular = elements(k_term_matrix(4, 4, 1, 2, ppmm, none));
+            let default =
+                k_term_matrix_geometry(4, 4, 1, 2, ppmm, 1., 0., 0.5, h, none);
+            assert_eq!(elements(default), triangular);
+            // four steps along -y shorter than one along x change the nearest
+            // images of the bonds along x, and with them their phases
+            let squashed =
+                k_term_matrix_geometry(4, 4, 1, 2, ppmm, 1., 0., 0.25, 0.01, none);
+            assert!(elements(squashed) != triangular);
+            let mut status = error::SUCCESS;
+            let flat = k_term_matrix_geometry(4, 4, 1, 2, ppmm, 1., 0., 2., 0.,
+                                              &mut status);
+            assert!(flat.is_null());
+            assert_eq!(status, error::ERR_INVALID_ARGUMENT);
+
+            // no phases in the XY term, whatever the shape of the lattice
+            let xy = CTerm { kind:  TermKind::HSsXy as u32,
+                             l:     1,
+                             coeff: 1. };
+            let square =
+                ks_term_matrix_geometry(4, 3, 1, 0, 6, xy, 1., 0., 0., 1., none);
+            assert_eq!(elements(square),
+                       elements(ks_term_matrix(4, 3, 1, 0, 6, xy, none)));
+            let nan = f64::NAN;
+            let invalid =
+                ks_term_matrix_geometry(4, 3, 1, 0, 6, xy, 1., 0., nan, 1., none);
+            assert!(invalid.is_null());
+        }
+    }
+
+    #[test]
+    fn shifted_variants() {
+        let terms = [(TermKind::HSsZ, 1), (TermKind::HSsXy, 2),
+                     (TermKind::HSsPpmm, 1), (TermKind::HSsPmz, 3)];
+        let none = null_mut();
+        unsafe {
+            for &(kind, l) in terms.iter() {
+                let term = CTerm { kind: kind as u32,
+                                   l,
+                                   coeff: 1. };
+                // no shift is the plain torus, element for element
+                let shifted = k_term_matrix_shifted(4, 3, 1, 2, term, 0, none);
+                assert_eq!(elements(shifted),
+                           elements(k_term_matrix(4, 3, 1, 2, term, none)));
+                assert!(k_term_matrix_shifted(4, 3, 1, 2, term, 4, none).is_null());
+            }
+            let xy = CTerm { kind:  TermKind::HSsXy as u32,
+                             l:     1,
+                             coeff: 1. };
+            let plain = elements(ks_term_matrix(6, 3, 2, 1, 9, xy, none));
+            assert_eq!(elements(ks_term_matrix_shifted(6, 3, 2, 1, 9, xy, 0, none)),
+                       plain);
+            assert!(elements(ks_term_matrix_shifted(6, 3, 2, 1, 9, xy, 1, none))
+                    != plain);
+            assert!(ks_term_matrix_shifted(6, 3, 2, 1, 9, xy, 6, none).is_null());
+        }
+    }
+
+    #[test]
+    fn periodicity_variants() {
+        let xy = CTerm { kind:  TermKind::HSsXy as u32,
+                         l:     1,
+                         coeff: 1. };
+        let none = null_mut();
+        unsafe {
+            // periodic both ways is the plain torus, element for element
+            let periodic = k_term_matrix_periodicity(4, 3, 1, 2, xy, 1, 1, none);
+            assert_eq!(elements(periodic),
+                       elements(k_term_matrix(4, 3, 1, 2, xy, none)));
+            let periodic = ks_term_matrix_periodicity(4, 3, 1, 2, 6, xy, 1, 1, none);
+            assert_eq!(elements(periodic),
+                       elements(ks_term_matrix(4, 3, 1, 2, 6, xy, none)));
+            // along an open axis only momentum 0 has states
+            let open_axes = [(1, 0, 0, 1), (0, 2, 1, 0), (1, 1, 0, 0)];
+            for &(kx, ky, px, py) in open_axes.iter() {
+                assert!(k_term_matrix_periodicity(4, 3, kx, ky, xy, px, py, none)
+                            .is_null());
+                assert!(ks_term_matrix_periodicity(4, 3, kx, ky, 6, xy, px, py, none)
+                            .is_null());
+            }
+            let plain = elements(ks_term_matrix(4, 3, 0, 0, 6, xy, none));
+            for &(px, py) in [(0, 1), (1, 0), (0, 0)].iter() {
+                let open =
+                    ks_term_matrix_periodicity(4, 3, 0, 0, 6, xy, px, py, none);
+                assert!(elements(open) != plain);
+            }
+        }
+    }
+
+    #[test]
+    fn ordering_variants() {
+        let xy = CTerm { kind:  TermKind::HSsXy as u32,
+                         l:     1,
+                         coeff: 1. };
+        let none = null_mut();
+        let row_major = ::std::ptr::null();
+        let identity: Vec<u32> = (0..12).collect();
+        let repeated = [0; 12];
+        unsafe {
+            // row-major is the plain lattice, and so is the identity permutation
+            let ordered = k_term_matrix_ordered(4, 3, 1, 2, xy, 0, row_major, none);
+            assert_eq!(elements(ordered),
+                       elements(k_term_matrix(4, 3, 1, 2, xy, none)));
+            let custom = ks_term_matrix_ordered(4, 3, 1, 2, 6, xy, 2,
+                                                identity.as_ptr(), none);
+            assert_eq!(elements(custom),
+                       elements(ks_term_matrix(4, 3, 1, 2, 6, xy, none)));
+            let snake =
+                ks_term_matrix_ordered(4, 3, 1, 2, 6, xy, 1, row_major, none);
+            assert!(!elements(snake).is_empty());
+            // unknown codes, missing or malformed permutations
+            assert!(k_term_matrix_ordered(4, 3, 0, 0, xy, 3, row_major, none)
+                        .is_null());
+            assert!(k_term_matrix_ordered(4, 3, 0, 0, xy, 2, row_major, none)
+                        .is_null());
+            assert!(ks_term_matrix_ordered(4, 3, 0, 0, 6, xy, 2, repeated.as_ptr(),
+                                           none).is_null());
+        }
     }
 }
@@ -0,0 +1,273 @@
+//! Coordinate matrices built within a memory budget. The elements are buffered
+//! in memory up to the budget, and every time the buffer fills up it is
+//! appended to a scratch file and emptied. Once the build is done the caller
+//! drops the basis and the arrays of the result are filled from the file in
+//! one pass, at their final size, so the basis and the full matrix are never
+//! held at the same time:
+//!
+//! ```text
+//! while building: B + T + W + max_bytes
+//! reading back:   24 * nnz + max_bytes
+//! ```
+//!
+//! with B, T and W the basis, orbit table and rows in flight described at
+//! ops::blocks_in_order, and 24 bytes per element of the result. The scratch
+//! file takes 24 bytes per spilled element and is removed when the sink is
+//! dropped.
+use num_complex::Complex;
+use std::{
+    cmp,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering}
+};
+
+use common::{CComplex, OwnedCoordMatrix};
+use error::{Error, Result};
+use ops::{ElementSink, VecSink};
+
+/// Bytes taken by an element in the buffer and in the scratch file
+pub const ELEMENT_BYTES: usize = 24;
+
+// tells apart the scratch files of the sinks of a process
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Collects the elements of a coordinate matrix, keeping at most "max_bytes"
+/// of them in memory and spilling the rest to a scratch file. Failures to
+/// write the file are kept until into_coord_matrix.
+pub struct SpillSink {
+    buffer:  VecSink,
+    cap:     usize,
+    path:    PathBuf,
+    file:    Option<BufWriter<File>>,
+    spilled: usize,
+    error:   Option<Error>
+}
+
+impl SpillSink {
+    /// A sink buffering up to "max_bytes" of elements (at least one) that
+    /// spills into a scratch file in the directory "dir"
+    pub fn new(max_bytes: usize, dir: &Path) -> SpillSink {
+        let cap = cmp::max(max_bytes / ELEMENT_BYTES, 1);
+        let name = format!("spinsys-{}-{}.spill",
+                           process::id(),
+                           SPILLS.fetch_add(1, Ordering::Relaxed));
+        SpillSink { buffer: VecSink::with_capacity(cap),
+                    cap,
+                    path: dir.join(name),
+                    file: None,
+                    spilled: 0,
+                    error: None }
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(BufWriter::new(File::create(&self.path)?));
+        }
+        let f = self.file.as_mut().unwrap();
+        let buffer = &self.buffer;
+        for ((&row, &col), val) in buffer.rows
+                                         .iter()
+                                         .zip(buffer.cols.iter())
+                                         .zip(buffer.data.iter())
+        {
+            f.write_all(&row.to_le_bytes())?;
+            f.write_all(&col.to_le_bytes())?;
+            f.write_all(&val.re.to_bits().to_le_bytes())?;
+            f.write_all(&val.im.to_bits().to_le_bytes())?;
+        }
+        self.spilled += buffer.data.len();
+        Ok(())
+    }
+
+    /// The matrix of dimension "dims" made of all the elements in the order
+    /// they came in. The spilled elements are read back into arrays of the
+    /// final size, followed by the ones still buffered. Fails if the scratch
+    /// file could not be written or read.
+    pub fn into_coord_matrix(mut self, dims: u32)
+                             -> Result<OwnedCoordMatrix<CComplex<f64>>> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let buffer = mem::replace(&mut self.buffer, VecSink::with_capacity(0));
+        match self.file.take() {
+            Some(mut f) => f.flush()?,
+            None => return Ok(buffer.into_coord_matrix(dims))
+        }
+
+        let len = self.spilled + buffer.data.len();
+        let mut sink = VecSink::with_capacity(len);
+        let mut f = BufReader::new(File::open(&self.path)?);
+        let mut u32_buf = [0_u8; 4];
+        let mut u64_buf = [0_u8; 8];
+        for _ in 0..self.spilled {
+            f.read_exact(&mut u32_buf)?;
+            let row = u32::from_le_bytes(u32_buf);
+            f.read_exact(&mut u32_buf)?;
+            let col = u32::from_le_bytes(u32_buf);
+            f.read_exact(&mut u64_buf)?;
+            let re = f64::from_bits(u64::from_le_bytes(u64_buf));
+            f.read_exact(&mut u64_buf)?;
+            let im = f64::from_bits(u64::from_le_bytes(u64_buf));
+            sink.push(row, col, Complex::new(re, im));
+        }
+        sink.rows.extend_from_slice(&buffer.rows);
+        sink.cols.extend_from_slice(&buffer.cols);
+        sink.data.extend_from_slice(&buffer.data);
+        Ok(sink.into_coord_matrix(dims))
+    }
+}
+
+impl ElementSink for SpillSink {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        if self.error.is_some() {
+            return;
+        }
+        self.buffer.push(row, col, val);
+        if self.buffer.data.len() == self.cap {
+            if let Err(e) = self.spill() {
+                self.error = Some(e);
+            }
+            self.buffer.rows.clear();
+            self.buffer.cols.clear();
+            self.buffer.data.clear();
+        }
+    }
+}
+
+impl Drop for SpillSink {
+    fn drop(&mut self) {
+        self.file = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::*;
+    use blochfunc::OrbitTable;
+    use consv;
+    use ops::{self, CountSink, PreparedTerm, BLOCKS_PER_THREAD, ROWS_PER_BLOCK};
+    use pool;
+    use test_support::{lock_pool, reset_thread_peak, thread_allocated, thread_peak};
+    use rayon;
+    use std::env;
+
+    fn bits(mat: &OwnedCoordMatrix<CComplex<f64>>) -> Vec<(u32, u32, u64, u64)> {
+        mat.row
+           .iter()
+           .zip(mat.col.iter())
+           .zip(mat.data.iter())
+           .map(|((&i, &j), d)| (i, j, d.re.to_bits(), d.im.to_bits()))
+           .collect()
+    }
+
+    #[test]
+    fn spilled_build_matches_in_memory() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+        let term = Term::new(TermKind::HSsXy, I(1));
+        let dir = env::temp_dir().join("spinsys_spill_test");
+        fs::create_dir_all(&dir).unwrap();
+        let reference = consv::ks::term_matrix(nx, ny, kx, ky, nup, &term).unwrap();
+
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup).unwrap();
+        let mut sink = SpillSink::new(100 * ELEMENT_BYTES, &dir);
+        ops::term_into(&term, &bfuncs, &mut sink);
+        assert!(sink.spilled > reference.data.len() / 2);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let spilled = sink.into_coord_matrix(bfuncs.nonzero).unwrap();
+        assert_eq!(bits(&spilled), bits(&reference));
+        assert_eq!(spilled.data.capacity(), reference.data.len());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // a budget that holds everything never touches the disk
+        let mat = consv::ks::term_matrix_spilled(nx, ny, kx, ky, nup, &term,
+                                                 1 << 20, &dir)
+            .unwrap();
+        assert_eq!(bits(&mat), bits(&reference));
+        match consv::ks::term_matrix_spilled(nx, ny, kx, ky, nup, &term, 0,
+                                             &dir.join("missing"))
+        {
+            Err(e) => assert_eq!(e.status(), ::error::ERR_IO),
+            Ok(_) => panic!("spilled into a missing directory")
+        }
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    // The bytes the calling thread holds at most while "build" runs, beyond
+    // what it held before. Blocks generated on other threads of a real pool
+    // are not counted, which can only make the figure smaller.
+    fn peak_bytes<F: FnOnce()>(build: F) -> usize {
+        let start = thread_allocated();
+        reset_thread_peak();
+        build();
+        (thread_peak() - start) as usize
+    }
+
+    #[test]
+    fn peak_memory_is_bounded() {
+        let _pool = lock_pool();
+        let (nx, ny) = (Dim(5), Dim(4));
+        let terms = [Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSsXy, I(2))];
+        let bfuncs = consv::ks::bloch_states(nx, ny, K(1), K(0), 10).unwrap();
+        pool::set_threads(1).unwrap();
+        let nnz = {
+            let mut sink = CountSink::default();
+            ops::terms_into(&terms, &bfuncs, &mut sink);
+            sink.count as usize
+        };
+        let matrix = ELEMENT_BYTES * nnz;
+
+        // B + T + W of ops::blocks_in_order, with the basis already built and
+        // some room for the row buffers and the pool's bookkeeping
+        let table = peak_bytes(|| {
+                                   OrbitTable::new(&bfuncs);
+                               });
+        let prepared = terms.iter()
+                            .map(|&t| PreparedTerm::new(t, &bfuncs.tables))
+                            .collect::<Vec<_>>();
+        let batch_rows =
+            BLOCKS_PER_THREAD * pool::install(rayon::current_num_threads)
+            * ROWS_PER_BLOCK;
+        let row_bound = |b| prepared.iter().map(|p| p.row_bound(b)).sum::<usize>();
+        let in_flight = bfuncs.data
+                              .chunks(batch_rows)
+                              .map(|rows| rows.iter().map(&row_bound).sum::<usize>())
+                              .max()
+                              .unwrap()
+                        * ELEMENT_BYTES;
+        let slack = 1 << 16;
+        let bound = table + in_flight + slack;
+
+        let streamed = peak_bytes(|| {
+                                      let mut sink = CountSink::default();
+                                      ops::terms_into(&terms, &bfuncs, &mut sink);
+                                  });
+        let collected = peak_bytes(|| {
+                                       let mut sink = ops::VecSink::with_capacity(0);
+                                       ops::terms_into(&terms, &bfuncs, &mut sink);
+                                   });
+        let budget = matrix / 8;
+        let dir = env::temp_dir();
+        let mut sink = SpillSink::new(budget, &dir);
+        let spilling = peak_bytes(|| ops::terms_into(&terms, &bfuncs, &mut sink));
+        let reading = peak_bytes(|| {
+                                     sink.into_coord_matrix(bfuncs.nonzero).unwrap();
+                                 });
+        pool::set_threads(0).unwrap();
+
+        println!("nnz: {}, table: {}, in flight: {}, streamed: {}, \
+                  collected: {}, spilling: {}, reading back: {}",
+                 nnz, table, in_flight, streamed, collected, spilling, reading);
+        assert!(in_flight < matrix / 4);
+        assert!(streamed <= bound);
+        assert!(collected >= matrix);
+        assert!(spilling <= bound + budget + slack);
+        assert!(reading <= matrix + budget + slack);
+    }
+}
@@ -0,0 +1,182 @@
+//! The spin stiffness from the response of the ground-state energy to a twist
+//! of the boundary conditions. The twisted Hamiltonians of a sweep share one
+//! basis: only the bond phases change with the twist, and each solve starts
+//! from the ground state of the previous twist.
+use num_complex::Complex;
+
+use common::*;
+use error::{Error, Result};
+use lanczos::{self, start_vector};
+use matfree::OpHandle;
+
+/// Weight of the fixed random vector added to the previous ground state to
+/// start the next solve with, so that a level crossing with a state
+/// orthogonal to the previous ground state is not missed
+const ADMIXTURE: f64 = 1e-2;
+
+/// The ground-state energies of a twist sweep and the stiffness estimated
+/// from the first three of them
+#[derive(Clone, Debug)]
+pub struct Stiffness {
+    pub energies:  Vec<f64>,
+    pub stiffness: f64
+}
+
+/// The lowest energy of the sum of "terms" in the (kx, ky, nup) sector under
+/// a twist θ across the boundary in x (see PreparedTerm::twisted), for
+/// "ntheta" >= 3 values of θ evenly spaced from 0 to "theta_max". The
+/// stiffness ρ_s is defined by E(θ) - E(0) = N ρ_s (θ / nx)^2 / 2 and is
+/// estimated from the forward second difference of the first three energies;
+/// fit the energies for a better estimate.
+pub fn spin_stiffness(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term],
+                      ntheta: u32, theta_max: f64, tol: f64, max_iter: u32)
+                      -> Result<Stiffness> {
+    if ntheta < 3 {
+        return Err(Error::InvalidArgument("ntheta"));
+    }
+    if !(theta_max > 0. && theta_max.is_finite()) {
+        return Err(Error::InvalidArgument("theta_max"));
+    }
+    let mut op = OpHandle::ks(nx, ny, kx, ky, nup, terms)?;
+    let noise = start_vector(op.dim() as usize, 1);
+    let mut start = noise.clone();
+    let h = theta_max / (ntheta - 1) as f64;
+    let mut energies = Vec::with_capacity(ntheta as usize);
+    for i in 0..ntheta {
+        op.set_twist(i as f64 * h)?;
+        let (energy, psi) =
+            lanczos::ground_state_from(&op, &start, tol, max_iter, true)?;
+        energies.push(energy);
+        // the eigenvector is always returned when asked for
+        start = psi.unwrap()
+                   .iter()
+                   .zip(noise.iter())
+                   .map(|(&a, &b)| a + b * ADMIXTURE)
+                   .collect::<Vec<Complex<f64>>>();
+    }
+
+    let curvature = (energies[2] - 2. * energies[1] + energies[0]) / (h * h);
+    let (nx, n) = (nx.raw_int() as f64, (nx * ny).raw_int() as f64);
+    Ok(Stiffness { energies,
+                   stiffness: nx * nx / n * curvature })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms() -> [Term; 3] {
+        [Term::new(TermKind::HSsZ, I(1)),
+         Term::new(TermKind::HSsXy, I(1)),
+         Term { kind:  TermKind::HSsXy,
+                l:     I(2),
+                coeff: 0.2 }]
+    }
+
+    fn lowest(op: &OpHandle) -> f64 {
+        lanczos::ground_state(op, 1e-12, 300, false).unwrap().0
+    }
+
+    #[test]
+    fn twisted_operator_is_hermitian() {
+        let mut op = OpHandle::ks(Dim(3), Dim(3), K(1), K(2), 4, &terms()).unwrap();
+        op.set_twist(0.7).unwrap();
+        let n = op.dim() as usize;
+        let mut cols = Vec::new();
+        for j in 0..n {
+            let mut e = vec![Complex::new(0., 0.); n];
+            let mut col = vec![Complex::new(0., 0.); n];
+            e[j] = Complex::new(1., 0.);
+            op.apply(&e, &mut col).unwrap();
+            cols.push(col);
+        }
+        let mut imag = 0.;
+        for (i, row) in cols.iter().enumerate() {
+            for (j, col) in cols.iter().enumerate() {
+                assert!((col[i] - row[j].conj()).norm() < 1e-12);
+                imag += col[i].im.abs();
+            }
+        }
+        // the twist is not a gauge artifact of this sector
+        assert!(imag > 1e-3);
+    }
+
+    #[test]
+    fn full_twist_shifts_momentum() {
+        // a twist of 2π is undone by a gauge transformation that shifts kx by
+        // nup, so the twisted spectrum is that of another sector
+        let (nx, ny, nup) = (Dim(3), Dim(3), 4);
+        for &(kx, ky) in [(0, 0), (1, 2)].iter() {
+            let sweep = spin_stiffness(nx,
+                                       ny,
+                                       K(kx),
+                                       K(ky),
+                                       nup,
+                                       &terms(),
+                                       9,
+                                       2. * PI,
+                                       1e-12,
+                                       300).unwrap();
+            let shifted = OpHandle::ks(nx,
+                                       ny,
+                                       K((kx + nup) % 3),
+                                       K(ky),
+                                       nup,
+                                       &terms()).unwrap();
+            let untwisted =
+                OpHandle::ks(nx, ny, K(kx), K(ky), nup, &terms()).unwrap();
+            assert!((sweep.energies[0] - lowest(&untwisted)).abs() < 1e-10);
+            assert!((sweep.energies[8] - lowest(&shifted)).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn twist_is_even_at_zero_momentum() {
+        // time reversal maps the twist θ at momentum k to -θ at -k
+        let mut op = OpHandle::ks(Dim(3), Dim(4), K(0), K(0), 6, &terms()).unwrap();
+        op.set_twist(0.4).unwrap();
+        let plus = lowest(&op);
+        op.set_twist(-0.4).unwrap();
+        assert!((plus - lowest(&op)).abs() < 1e-10);
+    }
+
+    #[test]
+    fn stiffness_from_second_difference() {
+        let (nx, ny) = (Dim(3), Dim(4));
+        let s = spin_stiffness(nx, ny, K(0), K(0), 6, &terms(), 5, 0.4, 1e-12, 300)
+                .unwrap();
+        let mut op = OpHandle::ks(nx, ny, K(0), K(0), 6, &terms()).unwrap();
+        for (i, &e) in s.energies.iter().enumerate() {
+            op.set_twist(0.1 * i as f64).unwrap();
+            assert!((e - lowest(&op)).abs() < 1e-10);
+        }
+        let e = &s.energies;
+        let expected = 9. / 12. * (e[2] - 2. * e[1] + e[0]) / 0.01;
+        assert!((s.stiffness - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rejects_terms_without_rotation_symmetry() {
+        let mut op = OpHandle::ks(Dim(3),
+                                  Dim(3),
+                                  K(0),
+                                  K(0),
+                                  4,
+                                  &[Term::new(TermKind::HSssChi, I(0))]).unwrap();
+        assert!(op.set_twist(0.1).is_err());
+        assert!(op.set_twist(0.).is_ok());
+        // second neighbors two sites apart along x on a lattice four sites wide
+        let mut op = OpHandle::ks(Dim(4), Dim(3), K(0), K(0), 6, &terms()).unwrap();
+        assert!(op.set_twist(0.1).is_err());
+        assert!(spin_stiffness(Dim(3),
+                               Dim(3),
+                               K(0),
+                               K(0),
+                               4,
+                               &terms(),
+                               2,
+                               1.,
+                               1e-10,
+                               300).is_err());
+    }
+}
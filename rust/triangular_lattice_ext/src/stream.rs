@@ -0,0 +1,147 @@
+//! Builders that hand every matrix element to a callback supplied by the caller
+//! instead of collecting them into a coordinate matrix.
+//!
+//! Ordering guarantee: rows are emitted in increasing order. Within a row the
+//! contributions of all bonds to the same column are merged before the callback
+//! is invoked, so every (row, col) pair is reported exactly once, and the
+//! columns of a row come in increasing order. The callback is only ever
+//! invoked from the calling thread and never after the builder returns.
+//!
+//! Nothing is collected: the elements are generated a few blocks of rows at a
+//! time and handed over as they come, so besides the basis only the orbit
+//! table and the rows in flight are held, whatever the size of the operator.
+//! See ops::blocks_in_order for the formula.
+use libc::c_void;
+use num_complex::Complex;
+
+use common::*;
+use consv;
+use error::{self, Error, Result};
+use ops::{self, ElementSink};
+
+pub type ElementCallback =
+    extern "C" fn(row: u64, col: u64, re: f64, im: f64, ctx: *mut c_void);
+
+struct CallbackSink {
+    cb:  ElementCallback,
+    ctx: *mut c_void
+}
+
+impl ElementSink for CallbackSink {
+    fn push(&mut self, row: u32, col: u32, val: Complex<f64>) {
+        (self.cb)(row as u64, col as u64, val.re, val.im, self.ctx);
+    }
+}
+
+/// Stream the elements of "term" in the (kx, ky, nup) sector into "cb". A panic
+/// during element generation is caught here and reported as an error so it
+/// never unwinds through the caller's frames.
+pub fn ks_term(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, term: Term,
+               cb: Option<ElementCallback>, ctx: *mut c_void)
+               -> Result<()> {
+    let cb = cb.ok_or(Error::InvalidArgument("cb"))?;
+    term.check(nx, ny)?;
+    let mut sink = CallbackSink { cb, ctx };
+    let build = || {
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+        ops::term_into(&term, &bfuncs, &mut sink);
+        Ok(())
+    };
+    error::catch_panic(build)?
+}
+
+/// Stream the sum of "terms" in the (kx, ky, nup) sector, each scaled by its
+/// coefficient, into "cb", merging the contributions of all terms to the same
+/// (row, col) pair. Fails if any of the terms does not conserve total Sz.
+pub fn ks_terms(nx: Dim, ny: Dim, kx: K, ky: K, nup: u32, terms: &[Term],
+                cb: Option<ElementCallback>, ctx: *mut c_void)
+                -> Result<()> {
+    let cb = cb.ok_or(Error::InvalidArgument("cb"))?;
+    if let Some(term) = terms.iter().find(|t| !t.kind.conserves_sz()) {
+        return Err(Error::InvalidTerm(term.kind as u32));
+    }
+    for term in terms.iter() {
+        term.check(nx, ny)?;
+    }
+    let mut sink = CallbackSink { cb, ctx };
+    let build = || {
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+        ops::terms_into(terms, &bfuncs, &mut sink);
+        Ok(())
+    };
+    error::catch_panic(build)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    extern "C" fn collect(row: u64, col: u64, re: f64, im: f64, ctx: *mut c_void) {
+        let v = unsafe { &mut *(ctx as *mut Vec<(u64, u64, f64, f64)>) };
+        v.push((row, col, re, im));
+    }
+
+    #[test]
+    fn stream_matches_coord_matrix() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+        let term = Term { kind:  TermKind::HSsXy,
+                          l:     I(1),
+                          coeff: 1. };
+        let mut streamed: Vec<(u64, u64, f64, f64)> = Vec::new();
+        let ctx = &mut streamed as *mut Vec<(u64, u64, f64, f64)> as *mut c_void;
+        ks_term(nx, ny, kx, ky, nup, term, Some(collect), ctx).unwrap();
+
+        let mat = consv::ks::h_ss_xy(nx, ny, kx, ky, nup,
+                                     BondRange::NearestNeighbor)
+                      .unwrap();
+        let (data, col, row) = (&mat.data, &mat.col, &mat.row);
+        assert_eq!(streamed.len(), data.len());
+        for (k, &(r, c, re, im)) in streamed.iter().enumerate() {
+            assert_eq!((r, c), (row[k] as u64, col[k] as u64));
+            assert_eq!((re, im), (data[k].re, data[k].im));
+        }
+        assert!(streamed.windows(2).all(|w| w[0].0 <= w[1].0));
+    }
+
+    #[test]
+    fn stream_sums_terms() {
+        let (nx, ny, kx, ky, nup) = (Dim(4), Dim(3), K(1), K(0), 6);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term { kind:  TermKind::HSsXy,
+                            l:     I(1),
+                            coeff: 0.5 }];
+        let mut streamed: Vec<(u64, u64, f64, f64)> = Vec::new();
+        let ctx = &mut streamed as *mut Vec<(u64, u64, f64, f64)> as *mut c_void;
+        ks_terms(nx, ny, kx, ky, nup, &terms, Some(collect), ctx).unwrap();
+
+        let mut expected = ::std::collections::BTreeMap::new();
+        for term in terms.iter() {
+            let mat = consv::ks::term_matrix(nx, ny, kx, ky, nup, term).unwrap();
+            let positions = mat.row.iter().zip(mat.col.iter());
+            for ((&r, &c), d) in positions.zip(mat.data.iter()) {
+                let e = expected.entry((r as u64, c as u64)).or_insert((0., 0.));
+                e.0 += d.re;
+                e.1 += d.im;
+            }
+        }
+        let expected = expected.into_iter()
+                               .map(|((r, c), (re, im))| (r, c, re, im))
+                               .collect::<Vec<_>>();
+        assert_eq!(streamed, expected);
+
+        let res = ks_terms(nx, ny, kx, ky, nup, &[Term::new(TermKind::HSsPmz, I(1))],
+                           Some(collect), ctx);
+        assert_eq!(res.unwrap_err().status(), ::error::ERR_INVALID_TERM);
+    }
+
+    #[test]
+    fn stream_rejects_null_callback() {
+        let term = Term { kind:  TermKind::HSsZ,
+                          l:     I(1),
+                          coeff: 1. };
+        let res =
+            ks_term(Dim(3), Dim(3), K(0), K(0), 4, term, None, ptr::null_mut());
+        assert_eq!(res.unwrap_err().status(), ::error::ERR_INVALID_ARGUMENT);
+    }
+}
@@ -0,0 +1,328 @@
+//! The ground state of a whole cluster, found by solving every momentum sector
+//! (and optionally every magnetization sector) and comparing, and the matrices
+//! of all momentum sectors for full-spectrum studies. The sectors are
+//! independent and are solved or built in parallel on the pool of the pool
+//! module, each one with the same functions a single sector is built with.
+//! These keep no mutable state between calls other than the caches of the
+//! lattice tables and the bases, which sit behind locks, so any number of
+//! sectors can be built at once. The settings of the lattice (see
+//! common::LatticeSettings) are handed to every sector as an argument, and the
+//! sectors are labelled under Convention::Plus.
+use rayon::prelude::*;
+use std::f64;
+
+use blochfunc::Convention;
+use common::*;
+use consv;
+use error::Result;
+use lanczos;
+use matfree::OpHandle;
+use pool;
+
+/// The lowest energies of a sweep, at index kx + ky * nx, and the sector
+/// holding the lowest of them. Empty sectors have energy +inf.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    pub energies: Vec<f64>,
+    pub best:     (K, K, u32)
+}
+
+/// The lowest energy of the sum of "terms" in each of "sectors", +inf for
+/// empty ones, solved in parallel
+fn sector_minima(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                 sectors: &[(K, K, u32)], terms: &[Term], tol: f64, max_iter: u32)
+                 -> Result<Vec<f64>> {
+    pool::install(|| {
+        sectors.par_iter()
+               .map(|&(kx, ky, nup)| {
+                   let op = OpHandle::ks_in(nx, ny, settings, Convention::Plus, kx,
+                                            ky, nup, terms)?;
+                   if op.dim() == 0 {
+                       return Ok(f64::INFINITY);
+                   }
+                   let (energy, _) =
+                       lanczos::ground_state(&op, tol, max_iter, false)?;
+                   Ok(energy)
+               })
+               .collect()
+    })
+}
+
+/// The lowest energy of the sum of "terms" in every momentum sector of the
+/// lattice with "settings" with "nup" up spins, or the lowest over all numbers
+/// of up spins if "nup" is None. Ties go to the sector with the smallest index
+/// and then the fewest up spins.
+pub fn ground_state_sweep(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                          nup: Option<u32>, terms: &[Term], tol: f64,
+                          max_iter: u32)
+                          -> Result<Sweep> {
+    check_lattice(nx, ny)?;
+    let n = nx.raw_int() * ny.raw_int();
+    let nups = match nup {
+        Some(nup) => vec![nup],
+        None => (0..=n).collect()
+    };
+    let mut sectors = Vec::new();
+    for ky in 0..ny.raw_int() {
+        for kx in 0..nx.raw_int() {
+            for &nup in nups.iter() {
+                sectors.push((K(kx), K(ky), nup));
+            }
+        }
+    }
+
+    let lowest = sector_minima(nx, ny, settings, &sectors, terms, tol, max_iter)?;
+
+    let mut energies = vec![f64::INFINITY; n as usize];
+    let mut best = (sectors[0], f64::INFINITY);
+    for (&(kx, ky, nup), &e) in sectors.iter().zip(lowest.iter()) {
+        let i = (kx.raw_int() + ky.raw_int() * nx.raw_int()) as usize;
+        energies[i] = energies[i].min(e);
+        if e < best.1 {
+            best = ((kx, ky, nup), e);
+        }
+    }
+    Ok(Sweep { energies,
+               best: best.0 })
+}
+
+/// The lowest energy of the sum of "terms" over all momenta of the lattice with
+/// "settings" for each number of up spins from 0 to nx * ny. A Zeeman term
+/// commutes with everything else and only shifts each of these by
+/// -h (nup - N / 2), so it should be left out of "terms"; the magnetization
+/// curve follows from the Legendre transform of the result.
+pub fn magnetization_curve(nx: Dim, ny: Dim, settings: &LatticeSettings,
+                           terms: &[Term], tol: f64, max_iter: u32)
+                           -> Result<Vec<f64>> {
+    check_lattice(nx, ny)?;
+    let n = nx.raw_int() * ny.raw_int();
+    let mut sectors = Vec::new();
+    for nup in 0..=n {
+        for ky in 0..ny.raw_int() {
+            for kx in 0..nx.raw_int() {
+                sectors.push((K(kx), K(ky), nup));
+            }
+        }
+    }
+    let lowest = sector_minima(nx, ny, settings, &sectors, terms, tol, max_iter)?;
+    let mut energies = vec![f64::INFINITY; n as usize + 1];
+    for (&(_, _, nup), &e) in sectors.iter().zip(lowest.iter()) {
+        energies[nup as usize] = energies[nup as usize].min(e);
+    }
+    Ok(energies)
+}
+
+/// Build each of "terms" in every momentum sector of the lattice with
+/// "settings" with "nup" up spins, as consv::ks::terms_matrices_in does for a
+/// single sector. The sectors are built in parallel and their matrices
+/// returned at index kx + ky * nx. Fails if any of the terms does not conserve
+/// total Sz.
+pub fn build_all_sectors(nx: Dim, ny: Dim, settings: &LatticeSettings, nup: u32,
+                         terms: &[Term])
+                         -> Result<Vec<Vec<OwnedCoordMatrix<CComplex<f64>>>>> {
+    check_lattice(nx, ny)?;
+    let mut sectors = Vec::new();
+    for ky in 0..ny.raw_int() {
+        for kx in 0..nx.raw_int() {
+            sectors.push((K(kx), K(ky)));
+        }
+    }
+    pool::install(|| {
+        sectors.par_iter()
+               .map(|&(kx, ky)| {
+                   consv::ks::terms_matrices_in(nx, ny, settings, Convention::Plus,
+                                                kx, ky, nup, terms)
+               })
+               .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::lock_pool;
+
+    fn torus() -> LatticeSettings { LatticeSettings::default() }
+
+    fn terms() -> [Term; 3] {
+        [Term::new(TermKind::HSsZ, I(1)),
+         Term::new(TermKind::HSsXy, I(1)),
+         Term { kind:  TermKind::HSsXy,
+                l:     I(2),
+                coeff: 0.2 }]
+    }
+
+    // the worker threads build the sectors on the lattice of the settings, a
+    // shifted one as well as the torus
+    #[test]
+    fn sweep_matches_single_sectors_4x3() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let shifted = LatticeSettings { shift: 1,
+                                        ..torus() };
+        for settings in [torus(), shifted].iter() {
+            let sweep =
+                ground_state_sweep(nx, ny, settings, Some(6), &terms(), 1e-10, 300)
+                    .unwrap();
+            let mut lowest = f64::INFINITY;
+            for ky in 0..3 {
+                for kx in 0..4 {
+                    let op = OpHandle::ks_in(nx, ny, settings, Convention::Plus,
+                                             K(kx), K(ky), 6, &terms()).unwrap();
+                    let (e, _) =
+                        lanczos::ground_state(&op, 1e-10, 300, false).unwrap();
+                    let i = (kx + ky * 4) as usize;
+                    assert!((sweep.energies[i] - e).abs() < 1e-8);
+                    lowest = lowest.min(e);
+                }
+            }
+            let (kx, ky, nup) = sweep.best;
+            assert_eq!(nup, 6);
+            let i = (kx.raw_int() + ky.raw_int() * 4) as usize;
+            assert_eq!(sweep.energies[i], lowest);
+        }
+    }
+
+    #[test]
+    fn sweep_over_all_nup() {
+        let (nx, ny) = (Dim(3), Dim(2));
+        let sweep = |nup| {
+            ground_state_sweep(nx, ny, &torus(), nup, &terms(), 1e-10, 300).unwrap()
+        };
+        let all = sweep(None);
+        for nup in 0..7 {
+            let sweep = sweep(Some(nup));
+            for (a, e) in all.energies.iter().zip(sweep.energies.iter()) {
+                assert!(a <= e);
+            }
+        }
+        // the Heisenberg ground state of an even cluster is a singlet
+        assert_eq!(all.best.2, 3);
+        assert!(all.energies.iter().all(|e| e.is_finite()));
+    }
+
+    #[test]
+    fn magnetization_curve_4x3() {
+        let (nx, ny) = (Dim(4), Dim(3));
+        let curve =
+            magnetization_curve(nx, ny, &torus(), &terms(), 1e-10, 300).unwrap();
+        assert_eq!(curve.len(), 13);
+        // the fully polarized states only feel the 36 Ising bonds
+        assert!((curve[0] - 9.).abs() < 1e-10);
+        for nup in 0..13 {
+            // spin inversion
+            assert!((curve[nup] - curve[12 - nup]).abs() < 1e-8);
+        }
+        for nup in 1..12 {
+            assert!(curve[nup + 1] - 2. * curve[nup] + curve[nup - 1] > -1e-8);
+        }
+        let mut lowest = f64::INFINITY;
+        for ky in 0..3 {
+            for kx in 0..4 {
+                let op = OpHandle::ks(nx, ny, K(kx), K(ky), 5, &terms()).unwrap();
+                let (e, _) = lanczos::ground_state(&op, 1e-10, 300, false).unwrap();
+                lowest = lowest.min(e);
+            }
+        }
+        assert!((curve[5] - lowest).abs() < 1e-8);
+    }
+
+    fn triplets(mat: &OwnedCoordMatrix<CComplex<f64>>)
+                -> Vec<(u32, u32, u64, u64)> {
+        mat.row
+           .iter()
+           .zip(mat.col.iter())
+           .zip(mat.data.iter())
+           .map(|((&i, &j), c)| (i, j, c.re.to_bits(), c.im.to_bits()))
+           .collect()
+    }
+
+    #[test]
+    fn all_sectors_match_single_sectors_4x3() {
+        let _pool = lock_pool();
+        let (nx, ny, nup) = (Dim(4), Dim(3), 6);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSssChi, I(0))];
+        let mut serial = Vec::new();
+        for ky in 0..3 {
+            for kx in 0..4 {
+                let mats =
+                    consv::ks::terms_matrices(nx, ny, K(kx), K(ky), nup, &terms)
+                        .unwrap();
+                serial.push(mats.iter().map(triplets).collect::<Vec<_>>());
+            }
+        }
+        for &threads in [1, 4].iter() {
+            pool::set_threads(threads).unwrap();
+            let all = build_all_sectors(nx, ny, &torus(), nup, &terms).unwrap();
+            let all = all.iter()
+                         .map(|mats| mats.iter().map(triplets).collect::<Vec<_>>())
+                         .collect::<Vec<_>>();
+            assert_eq!(all, serial);
+        }
+        pool::set_threads(0).unwrap();
+
+        let c_terms = terms.iter()
+                           .map(|t| {
+                                    CTerm { kind:  t.kind as u32,
+                                            l:     t.l.raw_int() as u32,
+                                            coeff: t.coeff }
+                                })
+                           .collect::<Vec<_>>();
+        let mut out = vec![::std::ptr::null_mut(); 12 * 3];
+        let status = unsafe {
+            ::ks_build_all_sectors(4, 3, nup, c_terms.as_ptr(), 3, out.as_mut_ptr())
+        };
+        assert_eq!(status, ::error::SUCCESS);
+        for (k, &mat) in out.iter().enumerate() {
+            let matrix = unsafe { &::handle::live(mat).unwrap().matrix };
+            assert_eq!(triplets(matrix), serial[k / 3][k % 3]);
+            unsafe { ::coord_matrix_free(mat) };
+        }
+
+        let ppmm = [Term::new(TermKind::HSsPpmm, I(1))];
+        assert!(build_all_sectors(nx, ny, &torus(), nup, &ppmm).is_err());
+    }
+
+    /// Run with --release --ignored --nocapture to time the matrices of all 20
+    /// momentum sectors of the 5x4 cluster at half filling, built one sector
+    /// after the other and all at once
+    #[test]
+    #[ignore]
+    fn all_sectors_scaling() {
+        use rayon;
+        use std::time::Instant;
+        let (nx, ny, nup) = (Dim(5), Dim(4), 10);
+        let terms = [Term::new(TermKind::HSsZ, I(1)),
+                     Term::new(TermKind::HSsXy, I(1)),
+                     Term::new(TermKind::HSssChi, I(0))];
+
+        let start = Instant::now();
+        let mut nnz = 0;
+        for ky in 0..4 {
+            for kx in 0..5 {
+                let mats =
+                    consv::ks::terms_matrices(nx, ny, K(kx), K(ky), nup, &terms)
+                        .unwrap();
+                nnz += mats.iter().map(|m| m.data.len()).sum::<usize>();
+            }
+        }
+        let serial = start.elapsed();
+
+        let start = Instant::now();
+        let all = build_all_sectors(nx, ny, &torus(), nup, &terms).unwrap();
+        let parallel = start.elapsed();
+
+        let total = all.iter()
+                       .flat_map(|mats| mats.iter())
+                       .map(|m| m.data.len())
+                       .sum::<usize>();
+        assert_eq!(total, nnz);
+        println!("{} elements on {} threads, one sector at a time: {:?}, all \
+                  sectors at once: {:?}",
+                 nnz,
+                 pool::install(rayon::current_num_threads),
+                 serial,
+                 parallel);
+    }
+}
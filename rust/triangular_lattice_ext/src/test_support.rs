@@ -0,0 +1,206 @@
+//! Helpers shared by the tests of several modules: an allocator that counts
+//! the bytes every thread holds, and those of a pool of threads together, a
+//! progress callback that collects its
+//! reports, bonds laid out from the periodic images of the sites or written
+//! out by hand, a check of the hermiticity of the exported builders, the
+//! elements of the matrices they hand out behind a handle and a lock for the
+//! tests that change the thread pool.
+use libc::{c_char, c_void};
+use num_complex::Complex;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    f64::consts::PI,
+    ffi::CStr,
+    sync::{
+        atomic::{AtomicIsize, Ordering},
+        Mutex, MutexGuard
+    }
+};
+
+use common::{CComplex, CoordMatrix, Dim};
+use handle::CoordMatrixHandle;
+
+/// Keeps track of the bytes allocated and not yet freed by every thread,
+/// so tests can check a build releases everything it allocates, of their
+/// high-water mark and of the number of allocations made
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = Cell::new(0);
+    static PEAK: Cell<isize> = Cell::new(0);
+    static ALLOCATIONS: Cell<usize> = Cell::new(0);
+    // whether the thread counts towards tracked_allocated
+    static TRACKED: Cell<bool> = Cell::new(false);
+}
+
+static TRACKED_ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+
+fn record(bytes: isize) {
+    // the counters are gone while the thread is shutting down
+    let _ = ALLOCATED.try_with(|a| {
+                         a.set(a.get() + bytes);
+                         let _ = PEAK.try_with(|p| p.set(p.get().max(a.get())));
+                     });
+    if TRACKED.try_with(|t| t.get()).unwrap_or(false) {
+        TRACKED_ALLOCATED.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    // reallocations go through alloc and are counted as well
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size() as isize);
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Bytes allocated minus bytes freed by the current thread
+pub fn thread_allocated() -> isize { ALLOCATED.with(|a| a.get()) }
+
+/// The largest value thread_allocated has taken since the last call to
+/// reset_thread_peak
+pub fn thread_peak() -> isize { PEAK.with(|p| p.get()) }
+
+/// Start tracking the high-water mark of thread_allocated from its current
+/// value
+pub fn reset_thread_peak() { PEAK.with(|p| p.set(thread_allocated())) }
+
+/// The number of allocations made by the current thread
+pub fn thread_allocations() -> usize { ALLOCATIONS.with(|n| n.get()) }
+
+/// A pool of "threads" threads whose allocations are summed up by
+/// tracked_allocated, wherever they are freed, together with those of the
+/// calling thread. The parallel work of a build installed on it runs on it
+/// alone (with the pool of the pool module left at its default, see
+/// lock_pool), so nothing of the tests running alongside is counted. Only one
+/// test uses it, since the sum is shared by all such pools.
+pub fn tracked_pool(threads: usize) -> ThreadPool {
+    TRACKED.with(|t| t.set(true));
+    ThreadPoolBuilder::new().num_threads(threads)
+                            .start_handler(|_| TRACKED.with(|t| t.set(true)))
+                            .build()
+                            .unwrap()
+}
+
+/// Bytes allocated minus bytes freed by the threads of tracked_pool
+pub fn tracked_allocated() -> isize { TRACKED_ALLOCATED.load(Ordering::Relaxed) }
+
+/// The reports collect gets through its context pointer
+pub type Reports = Vec<(f64, String)>;
+
+/// A progress callback that appends every report to the Reports at "ctx"
+pub extern "C" fn collect(fraction: f64, phase: *const c_char, ctx: *mut c_void) {
+    let v = unsafe { &mut *(ctx as *mut Reports) };
+    let phase = unsafe { CStr::from_ptr(phase) };
+    v.push((fraction, phase.to_str().unwrap().to_string()));
+}
+
+/// The squared length and the phase of the bond between the sites with
+/// indices "m" and "n" from their positions in the plane: the periodic
+/// images of site m are laid out explicitly and the bond runs to the
+/// nearest one. Along the axes of the nearest neighbor bonds the phase is
+/// e^(-2iθ) with θ the angle of the bond, and off them, as for the second
+/// neighbors, it is that of a2.
+pub fn bond_from_images(nx: Dim, ny: Dim, m: usize, n: usize)
+                        -> (f64, Complex<f64>) {
+    let (nx, ny) = (nx.raw_int() as i32, ny.raw_int() as i32);
+    let position = |x: i32, y: i32| {
+        (f64::from(x) + f64::from(y) / 2., f64::from(y) * 0.75f64.sqrt())
+    };
+    let (x1, y1) = (m as i32 % nx, m as i32 / nx);
+    let (x2, y2) = (n as i32 % nx, n as i32 / nx);
+    let origin = position(x2, y2);
+    let mut nearest = (f64::INFINITY, 0.);
+    for a in -1..2 {
+        for b in -1..2 {
+            let image = position(x1 + a * nx, y1 + b * ny);
+            let (bx, by) = (image.0 - origin.0, image.1 - origin.1);
+            let length_sqr = bx * bx + by * by;
+            if length_sqr < nearest.0 - 1e-9 {
+                nearest = (length_sqr, by.atan2(bx));
+            }
+        }
+    }
+    let (length_sqr, mut theta) = nearest;
+    // the axes are 60 degrees apart
+    let sixth = PI / 3.;
+    if ((theta / sixth).round() * sixth - theta).abs() > 1e-9 {
+        let a2 = position(-1, 1);
+        theta = a2.1.atan2(a2.0);
+    }
+    (length_sqr, Complex::from_polar(&1.0, &(-2. * theta)))
+}
+
+/// The nearest neighbor bonds of the 2x4 and 4x2 lattices as pairs of site
+/// indices, written out by hand from the hops along a1 = (1, 0),
+/// a2 = (-1, 1) and a3 = (0, -1) with every pair of sites bonded once.
+/// Along the axis two sites wide, the hops both ways lead to the same
+/// site, which leaves 20 bonds rather than 3 per site.
+pub fn two_wide_bonds(nx: u32, ny: u32) -> Vec<(usize, usize)> {
+    match (nx, ny) {
+        (2, 4) => vec![// a1, once across the two sites of each row
+                       (0, 1), (2, 3), (4, 5), (6, 7),
+                       // a2
+                       (0, 3), (1, 2), (2, 5), (3, 4), (4, 7), (5, 6),
+                       (1, 6), (0, 7),
+                       // a3
+                       (0, 6), (1, 7), (0, 2), (1, 3), (2, 4), (3, 5),
+                       (4, 6), (5, 7)],
+        (4, 2) => vec![// a1
+                       (0, 1), (1, 2), (2, 3), (0, 3), (4, 5), (5, 6),
+                       (6, 7), (4, 7),
+                       // a2
+                       (0, 7), (1, 4), (2, 5), (3, 6), (3, 4), (0, 5),
+                       (1, 6), (2, 7),
+                       // a3, once across the two rows
+                       (0, 4), (1, 5), (2, 6), (3, 7)],
+        _ => panic!("no bonds written out for {}x{}", nx, ny)
+    }
+}
+
+/// Check a matrix from one of the builders with check_hermiticity and
+/// release it
+pub fn assert_hermitian(mat: CoordMatrix<CComplex<f64>>, what: &str) {
+    let mut status = 1;
+    let asymmetry = unsafe { ::check_hermiticity(&mat, &mut status) };
+    unsafe { ::request_free(mat) };
+    assert_eq!(status, 0, "{}", what);
+    assert!(asymmetry < 1e-12, "{}: {:e}", what, asymmetry);
+}
+
+/// The elements of the matrix behind "handle" as (row, col, re, im), which
+/// releases it
+pub fn elements(handle: *mut CoordMatrixHandle) -> Vec<(u32, u32, f64, f64)> {
+    unsafe {
+        let mat = ::handle::live(handle).expect("a live handle");
+        let nnz = ::coord_matrix_nnz(handle) as usize;
+        let col = ::std::slice::from_raw_parts(::coord_matrix_col(handle), nnz);
+        let row = ::std::slice::from_raw_parts(::coord_matrix_row(handle), nnz);
+        let elements = (0..nnz).map(|k| {
+                                   let z = mat.matrix.data[k];
+                                   (row[k], col[k], z.re, z.im)
+                               })
+                               .collect::<Vec<_>>();
+        ::coord_matrix_free(handle);
+        elements
+    }
+}
+
+/// Held by every test that calls pool::set_threads, so that none of them
+/// runs on a pool another one has set up in the meantime. A test failing with
+/// the lock held does not fail the ones that take it after.
+pub fn lock_pool() -> MutexGuard<'static, ()> {
+    static POOL_LOCK: Mutex<()> = Mutex::new(());
+    POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
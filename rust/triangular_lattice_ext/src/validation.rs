@@ -0,0 +1,227 @@
+//! End-to-end cross-checks of the builders against each other on small
+//! lattices, for the tests and, with the "validation" feature, as a smoke test
+//! of a build (see ks_validate_sector_decomposition).
+//!
+//! The k sector does not use the conservation of Sz, so the spectrum of an
+//! operator that conserves it is the union of its spectra in the ks sectors of
+//! the same momentum over all nup. Both sides are diagonalized densely, which
+//! takes a cluster small enough for the k sector to fit in MAX_DIM states.
+use num_complex::Complex;
+
+use blochfunc::BlochFuncSet;
+use common::*;
+use consv;
+use error::{Error, Result};
+use lanczos::tridiagonal_eigh;
+use ops::{self, DenseSink};
+
+/// The largest deviation between matching eigenvalues that
+/// sector_decomposition accepts
+pub const SPECTRUM_TOL: f64 = 1e-10;
+
+/// The largest k sector sector_decomposition diagonalizes
+pub const MAX_DIM: u32 = 1024;
+
+/// The eigenvalues of the hermitian n x n matrix "a" (row-major), reduced to
+/// a tridiagonal matrix by Householder reflections first, since the Jacobi
+/// sweeps of lanczos::hermitian_eigvals take too long on the whole space of a
+/// lattice. The off-diagonal elements come out complex and only their
+/// magnitudes matter for the eigenvalues.
+pub fn tridiagonal_eigvals(mut a: Vec<Complex<f64>>, n: usize) -> Result<Vec<f64>> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let mut offdiag = Vec::with_capacity(n - 1);
+    for k in 0..n - 1 {
+        let mut v = (k + 1..n).map(|i| a[i * n + k]).collect::<Vec<_>>();
+        let norm = v.iter().map(|x| x.norm_sqr()).sum::<f64>().sqrt();
+        offdiag.push(norm);
+        if norm == 0. {
+            continue;
+        }
+        // the reflection I - 2vv' takes the column below the diagonal to a
+        // multiple of its first unit vector
+        let phase = if v[0].norm() == 0. {
+            Complex::new(1., 0.)
+        } else {
+            v[0] / v[0].norm()
+        };
+        v[0] += phase * norm;
+        let vnorm = v.iter().map(|x| x.norm_sqr()).sum::<f64>().sqrt();
+        for x in v.iter_mut() {
+            *x /= vnorm;
+        }
+        // a - vw' - wv' with w = 2(av - (v'av)v) on the rows and the columns
+        // past k, while column and row k are left with the element kept in
+        // offdiag
+        let m = n - k - 1;
+        let at = |i: usize, j: usize| (k + 1 + i) * n + k + 1 + j;
+        let p = (0..m).map(|i| {
+                          (0..m).map(|j| a[at(i, j)] * v[j])
+                                .fold(Complex::new(0., 0.), |acc, x| acc + x)
+                      })
+                      .collect::<Vec<_>>();
+        let vp = v.iter().zip(p.iter()).map(|(x, y)| x.conj() * y)
+                  .fold(Complex::new(0., 0.), |acc, x| acc + x);
+        let w = p.iter().zip(v.iter()).map(|(&y, &x)| (y - vp * x) * 2.)
+                 .collect::<Vec<_>>();
+        for i in 0..m {
+            for j in 0..m {
+                a[at(i, j)] -= v[i] * w[j].conj() + w[i] * v[j].conj();
+            }
+            a[(k + 1 + i) * n + k] = Complex::new(0., 0.);
+            a[k * n + k + 1 + i] = Complex::new(0., 0.);
+        }
+    }
+    let diag = (0..n).map(|i| a[i * n + i].re).collect::<Vec<f64>>();
+    Ok(tridiagonal_eigh(&diag, &offdiag)?.0)
+}
+
+/// The operator sector_decomposition compares: the XXZ model on the bonds of
+/// range "l", with different weights on its z and xy parts, plus the chiral
+/// term, which makes the matrices complex away from the real momenta
+pub fn sector_terms(l: I) -> Vec<Term> {
+    vec![Term { kind:  TermKind::HSsZ,
+                l,
+                coeff: 0.6 },
+         Term { kind:  TermKind::HSsXy,
+                l,
+                coeff: 1. },
+         Term { kind:  TermKind::HSssChi,
+                l:     I(0),
+                coeff: 0.3 }]
+}
+
+/// The eigenvalues of the sum of "terms" on "bfuncs" in ascending order
+fn spectrum(bfuncs: &BlochFuncSet, terms: &[Term]) -> Result<Vec<f64>> {
+    let dims = bfuncs.nonzero as usize;
+    let mut sink = DenseSink::new(dims);
+    ops::terms_into(terms, bfuncs, &mut sink);
+    let a = sink.data
+                .iter()
+                .map(|c| Complex::new(c.re, c.im))
+                .collect();
+    let mut evals = tridiagonal_eigvals(a, dims)?;
+    evals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Ok(evals)
+}
+
+/// The largest difference between the spectrum of "k_terms" in the (kx, ky)
+/// sector and the union of the spectra of "ks_terms" in the (kx, ky, nup)
+/// sectors over all nup. The two are the same operator unless a test wants
+/// them to differ. Fails with Error::Inconsistent if the dimensions of the
+/// two sides differ.
+fn decomposition_deviation(nx: Dim, ny: Dim, kx: K, ky: K, k_terms: &[Term],
+                           ks_terms: &[Term])
+                           -> Result<f64> {
+    let bfuncs = consv::k::bloch_states(nx, ny, kx, ky)?;
+    if bfuncs.nonzero > MAX_DIM {
+        return Err(Error::TooLarge(bfuncs.nonzero));
+    }
+    let whole = spectrum(&bfuncs, k_terms)?;
+    let mut union = Vec::with_capacity(whole.len());
+    for nup in 0..(nx * ny).raw_int() + 1 {
+        let bfuncs = consv::ks::bloch_states(nx, ny, kx, ky, nup)?;
+        union.extend(spectrum(&bfuncs, ks_terms)?);
+    }
+    union.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    if union.len() != whole.len() {
+        return Err(Error::Inconsistent(format!("the (kx, ky) = ({}, {}) sector \
+                                                has {} states, its ks sectors \
+                                                {} together",
+                                               kx.raw_int(),
+                                               ky.raw_int(),
+                                               whole.len(),
+                                               union.len())));
+    }
+    Ok(whole.iter()
+            .zip(union.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f64::max))
+}
+
+/// Check that the spectrum of sector_terms(l) in the (kx, ky) sector of the nx
+/// by ny lattice is the union of its spectra in the (kx, ky, nup) sectors to
+/// SPECTRUM_TOL, returning the largest deviation. Fails with
+/// Error::Inconsistent, whose message gives the deviation, if it is not, and
+/// with Error::TooLarge if the k sector has more than MAX_DIM states.
+pub fn sector_decomposition(nx: Dim, ny: Dim, kx: K, ky: K, l: I) -> Result<f64> {
+    check_sector(nx, ny, kx, ky, None)?;
+    let terms = sector_terms(l);
+    for term in terms.iter() {
+        term.check(nx, ny)?;
+    }
+    let deviation = decomposition_deviation(nx, ny, kx, ky, &terms, &terms)?;
+    if deviation > SPECTRUM_TOL {
+        return Err(Error::Inconsistent(format!("the spectrum of the (kx, ky) = \
+                                                ({}, {}) sector differs from \
+                                                those of its ks sectors by up \
+                                                to {:e}",
+                                               kx.raw_int(),
+                                               ky.raw_int(),
+                                               deviation)));
+    }
+    Ok(deviation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sector_decomposition_holds() {
+        // every sector of the smaller clusters, and a few of 4x3, whose k
+        // sectors take a while to diagonalize
+        let mut sectors = vec![(4, 3, 1, 0, 1), (4, 3, 1, 2, 2)];
+        for &(nx, ny) in [(3, 3), (2, 4)].iter() {
+            for kx in 0..nx {
+                for ky in 0..ny {
+                    sectors.extend((1..4).map(|l| (nx, ny, kx, ky, l)));
+                }
+            }
+        }
+        for &(nx, ny, kx, ky, l) in sectors.iter() {
+            let deviation =
+                sector_decomposition(Dim(nx), Dim(ny), K(kx), K(ky), I(l)).unwrap();
+            assert!(deviation <= SPECTRUM_TOL);
+        }
+    }
+
+    // a sign flipped on one side, as a convention bug would, is caught
+    #[test]
+    fn sector_decomposition_catches_a_flipped_term() {
+        let (nx, ny, kx, ky) = (Dim(3), Dim(3), K(1), K(2));
+        let terms = sector_terms(I(1));
+        let mut flipped = terms.clone();
+        flipped[0].coeff = -flipped[0].coeff;
+        let deviation =
+            decomposition_deviation(nx, ny, kx, ky, &terms, &flipped).unwrap();
+        assert!(deviation > 1e-3);
+    }
+
+    #[test]
+    fn sector_decomposition_refuses_large_sectors() {
+        match sector_decomposition(Dim(4), Dim(4), K(0), K(0), I(1)) {
+            Err(Error::TooLarge(dims)) => assert!(dims > MAX_DIM),
+            other => panic!("{:?}", other)
+        }
+        match sector_decomposition(Dim(3), Dim(3), K(3), K(0), I(1)) {
+            Err(Error::InvalidArgument("kx")) => (),
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn validate_sector_decomposition_ffi() {
+        use std::ffi::CStr;
+        use error;
+        assert_eq!(::ks_validate_sector_decomposition(3, 3, 1, 1, 1),
+                   error::SUCCESS);
+        assert_eq!(::ks_validate_sector_decomposition(4, 4, 0, 0, 1),
+                   error::ERR_TOO_LARGE);
+        let msg = unsafe { CStr::from_ptr(::spinsys_last_error()) };
+        assert!(msg.to_str().unwrap().contains("too large"));
+        assert_eq!(::ks_validate_sector_decomposition(3, 3, 0, 3, 1),
+                   error::ERR_INVALID_ARGUMENT);
+    }
+}